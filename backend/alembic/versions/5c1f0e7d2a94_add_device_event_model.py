@@ -0,0 +1,42 @@
+"""Add DeviceEvent model
+
+Revision ID: 5c1f0e7d2a94
+Revises: 28af5958361e
+Create Date: 2026-10-15 10:12:31.402118
+
+"""
+from typing import Sequence, Union
+
+from alembic import op
+import sqlalchemy as sa
+
+
+# revision identifiers, used by Alembic.
+revision: str = '5c1f0e7d2a94'
+down_revision: Union[str, Sequence[str], None] = '28af5958361e'
+branch_labels: Union[str, Sequence[str], None] = None
+depends_on: Union[str, Sequence[str], None] = None
+
+
+def upgrade() -> None:
+    """Upgrade schema."""
+    op.create_table('device_events',
+    sa.Column('id', sa.Integer(), nullable=False),
+    sa.Column('device_id', sa.String(), nullable=True),
+    sa.Column('received_at', sa.DateTime(), nullable=True),
+    sa.Column('event_type', sa.String(), nullable=True),
+    sa.Column('payload', sa.String(), nullable=True),
+    sa.ForeignKeyConstraint(['device_id'], ['devices.id'], ),
+    sa.PrimaryKeyConstraint('id')
+    )
+    op.create_index(op.f('ix_device_events_id'), 'device_events', ['id'], unique=False)
+    op.create_index(op.f('ix_device_events_device_id'), 'device_events', ['device_id'], unique=False)
+    op.create_index(op.f('ix_device_events_event_type'), 'device_events', ['event_type'], unique=False)
+
+
+def downgrade() -> None:
+    """Downgrade schema."""
+    op.drop_index(op.f('ix_device_events_event_type'), table_name='device_events')
+    op.drop_index(op.f('ix_device_events_device_id'), table_name='device_events')
+    op.drop_index(op.f('ix_device_events_id'), table_name='device_events')
+    op.drop_table('device_events')
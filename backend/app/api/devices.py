@@ -1,13 +1,16 @@
 import logging
-from fastapi import APIRouter, Depends, HTTPException, Header
+from fastapi import APIRouter, Depends, HTTPException, Header, Request
 from sqlalchemy.orm import Session
 import datetime
+import hashlib
 import json
+import os
+import re
 from typing import List, Optional, Dict, Any
 import uuid
 from uuid import UUID
 
-from .. import models
+from .. import integrity, models
 from ..database import get_db
 
 # Pydantic models for request/response
@@ -59,6 +62,16 @@ class RegisterResponse(BaseModel):
     desired_sample_interval_secs: int
     desired_upload_interval_secs: int
     desired_heartbeat_interval_secs: int
+    # Request Content-Encodings the backend can decompress (see GzipRequestMiddleware)
+    supported_encodings: List[str] = ["gzip"]
+
+class BulkRegisterPayload(BaseModel):
+    devices: List[RegisterPayload]
+
+class BulkRegisterResult(BaseModel):
+    boot_id: uuid.UUID
+    registration: Optional[RegisterResponse] = None # None if this device was rejected
+    error: Optional[str] = None
 
 class HeartbeatPayload(BaseModel):
     device_id: str
@@ -90,14 +103,18 @@ class DeviceEnvironmentPayload(BaseModel):
     
 class MeasurementPayload(BaseModel):
     timestamp: datetime.datetime
-    temp: float
-    humidity: float
-    battery: float
+    temp: Optional[float] = None
+    humidity: Optional[float] = None
+    battery: Optional[float] = None
     sequence_number: int
-    firmware_version: str
+    firmware_version: Optional[str] = None
     latitude: Optional[float] = None
     longitude: Optional[float] = None
     speed: Optional[float] = None
+    keyframe: Optional[bool] = None # Sent by devices with field cadences; a keyframe carries every field
+
+# Fields a device cadence may leave out of a measurement; absent ones take the last value received
+CADENCE_FIELDS = ("temp", "humidity", "battery", "firmware_version", "latitude", "longitude", "speed")
 
 class IngestPayload(BaseModel):
     device_id: str
@@ -141,66 +158,92 @@ class DeviceShadowResponseGeneric(BaseModel):
     desired: Dict[str, Any]
     reported: Dict[str, Any]
 
+class SyncResponse(BaseModel):
+    desired_state: DesiredStateResponse
+    shadow: DeviceShadowResponseGeneric
+
 class TokenRotationResponse(BaseModel):
     new_auth_token: uuid.UUID
 
 # --- API Endpoints ---
 
-@router.post("/register", response_model=RegisterResponse)
-def register_device(payload: RegisterPayload, db: Session = Depends(get_db)):
-    existing_device = db.query(models.Device).filter(models.Device.id == str(payload.boot_id)).first()
+# Most devices one bulk registration may carry; the fleet runner sends 500 at a time
+MAX_BULK_REGISTRATION = 1000
+
+def _registration(device: models.Device) -> RegisterResponse:
+    return RegisterResponse(
+        device_id=UUID(device.id),
+        auth_token=UUID(device.auth_token),
+        desired_sample_interval_secs=device.desired_sample_interval_secs,
+        desired_upload_interval_secs=device.desired_upload_interval_secs,
+        desired_heartbeat_interval_secs=device.desired_heartbeat_interval_secs,
+    )
+
+def _register(boot_id: uuid.UUID, db: Session) -> models.Device:
+    """Returns the device registered for boot_id, adding a new one to the session if there is none."""
+    existing_device = db.query(models.Device).filter(models.Device.id == str(boot_id)).first()
     if existing_device:
         logger.info(
             "Device already registered, returning existing credentials", 
-            extra={"boot_id": payload.boot_id, "device_id": existing_device.id}
+            extra={"boot_id": boot_id, "device_id": existing_device.id}
         )
         if existing_device.auth_token:
-            return RegisterResponse(
-                device_id=UUID(existing_device.id),
-                auth_token=UUID(existing_device.auth_token),
-                desired_sample_interval_secs=existing_device.desired_sample_interval_secs,
-                desired_upload_interval_secs=existing_device.desired_upload_interval_secs,
-                desired_heartbeat_interval_secs=existing_device.desired_heartbeat_interval_secs,
-            )
-        else:
-            logger.error("Device ID exists but no auth token during re-registration attempt", extra={"boot_id": payload.boot_id})
-            raise HTTPException(status_code=400, detail="Device ID exists but no auth token. Malformed registration.")
-
-    new_device_id = uuid.uuid4()
-    new_auth_token = uuid.uuid4()
+            return existing_device
+        logger.error("Device ID exists but no auth token during re-registration attempt", extra={"boot_id": boot_id})
+        raise HTTPException(status_code=400, detail="Device ID exists but no auth token. Malformed registration.")
 
     new_device = models.Device(
-        id=str(new_device_id),
-        auth_token=str(new_auth_token),
+        id=str(uuid.uuid4()),
+        auth_token=str(uuid.uuid4()),
         lifecycle_state="new",
         registered_at=datetime.datetime.utcnow(),
         desired_state=json.dumps({}),  # Initialize generic desired state
         reported_state=json.dumps({}), # Initialize generic reported state
     )
     db.add(new_device)
-    db.commit()
-    db.refresh(new_device)
-
+    db.flush() # Column defaults, such as the desired intervals, are filled in
     logger.info(
         "New device registered successfully", 
-        extra={"device_id": new_device_id, "lifecycle_state": new_device.lifecycle_state}
+        extra={"device_id": new_device.id, "lifecycle_state": new_device.lifecycle_state}
     )
-    return RegisterResponse(
-        device_id=new_device_id,
-        auth_token=new_auth_token,
-        desired_sample_interval_secs=new_device.desired_sample_interval_secs,
-        desired_upload_interval_secs=new_device.desired_upload_interval_secs,
-        desired_heartbeat_interval_secs=new_device.desired_heartbeat_interval_secs,
+    return new_device
+
+@router.post("/register", response_model=RegisterResponse)
+def register_device(payload: RegisterPayload, db: Session = Depends(get_db)):
+    device = _register(payload.boot_id, db)
+    db.commit()
+    db.refresh(device)
+    return _registration(device)
+
+@router.post("/register/bulk", response_model=List[BulkRegisterResult])
+def register_devices_bulk(payload: BulkRegisterPayload, db: Session = Depends(get_db)):
+    """Registers many devices at once, as a factory pre-provisions them. Each device gets
+    the credentials /register would have given it; one that cannot be registered is
+    answered with an error and does not fail the others."""
+    if len(payload.devices) > MAX_BULK_REGISTRATION:
+        raise HTTPException(status_code=413, detail=f"At most {MAX_BULK_REGISTRATION} devices per bulk registration")
+
+    results = []
+    seen = set()
+    for device in payload.devices:
+        if device.boot_id in seen:
+            results.append(BulkRegisterResult(boot_id=device.boot_id, error="boot_id appears more than once in the request"))
+            continue
+        seen.add(device.boot_id)
+        try:
+            registered = _register(device.boot_id, db)
+        except HTTPException as e:
+            results.append(BulkRegisterResult(boot_id=device.boot_id, error=e.detail))
+            continue
+        results.append(BulkRegisterResult(boot_id=device.boot_id, registration=_registration(registered)))
+    db.commit()
+    logger.info(
+        "Devices registered in bulk",
+        extra={"devices": len(payload.devices), "registered": sum(1 for result in results if result.registration)}
     )
+    return results
 
-@router.post("/heartbeat", response_model=DesiredStateResponse)
-def heartbeat(
-    payload: HeartbeatPayload, 
-    authenticated_device: models.Device = Depends(authenticate_device), 
-    db: Session = Depends(get_db)
-):
-    device = authenticated_device 
-    
+def _record_heartbeat(payload: HeartbeatPayload, device: models.Device, db: Session) -> DesiredStateResponse:
     device.current_version = payload.firmware_version
     device.last_seen = datetime.datetime.utcnow()
     device.status = "online"
@@ -227,6 +270,24 @@ def heartbeat(
         desired_heartbeat_interval_secs=device.desired_heartbeat_interval_secs,
     )
 
+@router.post("/heartbeat", response_model=DesiredStateResponse)
+def heartbeat(
+    payload: HeartbeatPayload, 
+    authenticated_device: models.Device = Depends(authenticate_device), 
+    db: Session = Depends(get_db)
+):
+    return _record_heartbeat(payload, authenticated_device, db)
+
+# Heartbeat and shadow fetch in one round trip, for devices running with combined_sync
+@router.post("/sync", response_model=SyncResponse)
+def sync(
+    payload: HeartbeatPayload,
+    authenticated_device: models.Device = Depends(authenticate_device),
+    db: Session = Depends(get_db)
+):
+    desired_state = _record_heartbeat(payload, authenticated_device, db)
+    return SyncResponse(desired_state=desired_state, shadow=_shadow(authenticated_device))
+
 @router.post("/{device_id}/state", response_model=DeviceOutput)
 def update_device_state(
     device_id: str, 
@@ -274,28 +335,40 @@ def update_device_environment(
     )
     return DeviceOutput.from_orm(device)
 
-@router.post("/ingest", status_code=204)
-def ingest(payload: IngestPayload, authenticated_device: models.Device = Depends(authenticate_device), db: Session = Depends(get_db)):
+async def raw_body(request: Request) -> bytes:
+    return await request.body()
+
+@router.post("/ingest")
+def ingest(
+    payload: IngestPayload,
+    body: bytes = Depends(raw_body), # Hashed as sent; see integrity
+    authenticated_device: models.Device = Depends(authenticate_device),
+    db: Session = Depends(get_db)
+):
     if authenticated_device.id != payload.device_id:
         logger.error("Forbidden: Attempt to ingest data for another device", extra={"requester_device_id": authenticated_device.id, "payload_device_id": payload.device_id})
         raise HTTPException(status_code=403, detail="Forbidden: Cannot ingest data for another device")
 
     device = authenticated_device
 
+    last_stored = (
+        db.query(models.Measurement)
+        .filter(models.Measurement.device_id == device.id)
+        .order_by(models.Measurement.timestamp.desc())
+        .first()
+    )
+    last_known = {field: getattr(last_stored, field) for field in CADENCE_FIELDS} if last_stored else {}
+
     new_measurements = []
-    for m in payload.measurements:
+    for m in sorted(payload.measurements, key=lambda m: m.timestamp):
+        values = {field: getattr(m, field) if field in m.model_fields_set else last_known.get(field) for field in CADENCE_FIELDS}
+        last_known.update(values)
         new_measurements.append(
             models.Measurement(
                 device_id=device.id,
                 timestamp=m.timestamp,
-                temp=m.temp,
-                humidity=m.humidity,
-                battery=m.battery,
                 sequence_number=m.sequence_number,
-                firmware_version=m.firmware_version,
-                latitude=m.latitude,
-                longitude=m.longitude,
-                speed=m.speed,
+                **values,
             )
         )
     db.add_all(new_measurements)
@@ -305,6 +378,15 @@ def ingest(payload: IngestPayload, authenticated_device: models.Device = Depends
         extra={"device_id": device.id, "measurement_count": len(new_measurements)}
     )
 
+    response = {"message": f"Ingested {len(new_measurements)} measurements."}
+    # Devices that hash their measurements check these against what they sent
+    accepted = integrity.accepted_hashes(integrity.parse(body).get("measurements", []))
+    if accepted is not None:
+        response["accepted_hashes"] = accepted
+        if len(accepted) < len(payload.measurements):
+            logger.warning("Measurements did not match their content hash", extra={"device_id": device.id, "accepted": len(accepted), "measurement_count": len(payload.measurements)})
+    return response
+
 @router.post("/{device_id}/errors", status_code=204)
 def report_error(
     device_id: str, 
@@ -332,6 +414,69 @@ def report_error(
         extra={"device_id": device.id, "firmware_version": error.firmware_version, "error_code": error.error_code}
     )
 
+class DeviceEventPayload(BaseModel):
+    event_type: str
+    model_config = {"extra": "allow"} # Fields differ by event type and are stored as sent
+
+@router.post("/{device_id}/events", status_code=204)
+def report_event(
+    device_id: str,
+    payload: DeviceEventPayload,
+    authenticated_device: models.Device = Depends(authenticate_device),
+    db: Session = Depends(get_db)
+):
+    if authenticated_device.id != device_id:
+        logger.error("Forbidden: Attempt to report events for another device", extra={"requester_device_id": authenticated_device.id, "target_device_id": device_id})
+        raise HTTPException(status_code=403, detail="Forbidden: Cannot report events for another device")
+
+    event = models.DeviceEvent(
+        device_id=device_id,
+        received_at=datetime.datetime.utcnow(),
+        event_type=payload.event_type,
+        payload=payload.model_dump_json(),
+    )
+    db.add(event)
+    db.commit()
+    logger.warning("Device event reported", extra={"device_id": device_id, "event_type": payload.event_type})
+
+class UploadedFileResponse(BaseModel):
+    file_id: str
+    sha256: str
+    bytes: int
+    purpose: str
+
+# Files uploaded by devices, e.g. data-subject export archives; one directory per device and purpose
+UPLOAD_DIR = os.environ.get("DEVICE_UPLOAD_DIR", "uploads")
+
+@router.post("/{device_id}/files", response_model=UploadedFileResponse)
+async def upload_device_file(
+    device_id: str,
+    purpose: str,
+    name: str,
+    request: Request,
+    x_content_sha256: Optional[str] = Header(None, alias="X-Content-SHA256"),
+    authenticated_device: models.Device = Depends(authenticate_device),
+):
+    if authenticated_device.id != device_id:
+        logger.error("Forbidden: Attempt to upload a file for another device", extra={"requester_device_id": authenticated_device.id, "target_device_id": device_id})
+        raise HTTPException(status_code=403, detail="Forbidden: Cannot upload files for another device")
+    if not re.fullmatch(r"[A-Za-z0-9_-]+", purpose) or not re.fullmatch(r"[A-Za-z0-9._-]+", name) or name.startswith("."):
+        raise HTTPException(status_code=400, detail="Invalid purpose or file name")
+
+    body = await request.body()
+    sha256 = hashlib.sha256(body).hexdigest()
+    if x_content_sha256 is not None and x_content_sha256.lower() != sha256:
+        logger.warning("Uploaded file does not match its checksum", extra={"device_id": device_id, "purpose": purpose, "file_name": name})
+        raise HTTPException(status_code=422, detail="Body does not match X-Content-SHA256")
+
+    file_id = f"{uuid.uuid4()}-{name}"
+    directory = os.path.join(UPLOAD_DIR, device_id, purpose)
+    os.makedirs(directory, exist_ok=True)
+    with open(os.path.join(directory, file_id), "wb") as file:
+        file.write(body)
+    logger.info("Device file uploaded", extra={"device_id": device_id, "purpose": purpose, "file_id": file_id, "bytes": len(body)})
+    return UploadedFileResponse(file_id=file_id, sha256=sha256, bytes=len(body), purpose=purpose)
+
 # --- Generic Device Shadow Endpoints ---
 
 @router.get("/{device_id}/shadow", response_model=DeviceShadowResponseGeneric)
@@ -343,9 +488,12 @@ def get_generic_device_shadow(
     if authenticated_device.id != device_id:
         logger.error("Forbidden: Attempt to access another device's shadow", extra={"requester_device_id": authenticated_device.id, "target_device_id": device_id})
         raise HTTPException(status_code=403, detail="Forbidden: Cannot access another device's shadow")
-    
-    device = authenticated_device
 
+    shadow = _shadow(authenticated_device)
+    logger.info("Device shadow fetched", extra={"device_id": authenticated_device.id})
+    return shadow
+
+def _shadow(device: models.Device) -> DeviceShadowResponseGeneric:
     desired_state_json = {}
     if device.desired_state:
         try:
@@ -368,7 +516,6 @@ def get_generic_device_shadow(
             )
             pass
 
-    logger.info("Device shadow fetched", extra={"device_id": device.id})
     return DeviceShadowResponseGeneric(
         desired=desired_state_json,
         reported=reported_state_json,
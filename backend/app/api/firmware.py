@@ -1,7 +1,11 @@
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
 from fastapi import APIRouter, Depends, HTTPException, Response
 from sqlalchemy.orm import Session
 from typing import Optional, List
+import datetime
+import json
 import logging
+import os
 
 from .. import models
 from ..database import get_db
@@ -18,6 +22,44 @@ class FirmwareResponse(BaseModel):
     rollout_phase: str
     target_percent: int
     rollout_status: str # Added rollout_status to response
+    signature: Optional[str] = None
+    nonce: Optional[str] = None # Echo of the device's request nonce, for replay protection
+    issued_at: datetime.datetime
+    metadata_signature: Optional[str] = None # Hex ed25519 signature over metadata_message
+
+def signing_key() -> Optional[Ed25519PrivateKey]:
+    """The key metadata is signed with: OTA_SIGNING_KEY, a hex ed25519 seed whose public
+    key devices have as OTA_PUBLIC_KEY. Without it metadata goes out unsigned."""
+    seed = os.environ.get("OTA_SIGNING_KEY", "").strip()
+    if not seed:
+        return None
+    return Ed25519PrivateKey.from_private_bytes(bytes.fromhex(seed))
+
+def metadata_message(version: str, checksum: str, url: str, nonce: Optional[str], issued_at: datetime.datetime) -> bytes:
+    """What metadata_signature covers; the device rebuilds it in ota::metadata_message."""
+    fields = ["vf-ota-metadata/1", version, checksum, url, nonce, int(issued_at.timestamp())]
+    return json.dumps(fields, separators=(",", ":"), ensure_ascii=False).encode()
+
+def firmware_response(firmware: models.Firmware, nonce: Optional[str]) -> FirmwareResponse:
+    # Whole seconds, as the signature covers them
+    issued_at = datetime.datetime.now(datetime.timezone.utc).replace(microsecond=0)
+    key = signing_key()
+    metadata_signature = None
+    if key is not None:
+        message = metadata_message(firmware.version, firmware.checksum, firmware.url, nonce, issued_at)
+        metadata_signature = key.sign(message).hex()
+    return FirmwareResponse(
+        version=firmware.version,
+        checksum=firmware.checksum,
+        url=firmware.url,
+        rollout_phase=firmware.rollout_phase,
+        target_percent=firmware.target_percent,
+        rollout_status=firmware.rollout_status,
+        signature=firmware.signature,
+        nonce=nonce,
+        issued_at=issued_at,
+        metadata_signature=metadata_signature,
+    )
 
 class FirmwareUpdateTargetPercentPayload(BaseModel):
     target_percent: int
@@ -28,16 +70,24 @@ class FirmwareRollbackPayload(BaseModel):
 router = APIRouter()
 
 @router.get("/latest")
-def get_latest_firmware(device_id: str, db: Session = Depends(get_db)):
+def get_latest_firmware(
+    device_id: str,
+    nonce: Optional[str] = None,
+    current_version: Optional[str] = None,
+    db: Session = Depends(get_db)
+):
     device = db.query(models.Device).filter(models.Device.id == device_id).first()
     if not device:
         logger.warning("Device not found for latest firmware check", extra={"device_id": device_id})
         return Response(status_code=204)
+
+    # Prefer the version the device reports with this request over the last heartbeat's
+    current_version = current_version or device.current_version
         
     # 1. Direct assignment
     if device.desired_version:
         firmware = db.query(models.Firmware).filter(models.Firmware.version == device.desired_version).first()
-        if firmware and firmware.version != device.current_version:
+        if firmware and firmware.version != current_version:
             # Check if rollout is active
             if firmware.rollout_status != "active":
                 logger.info(
@@ -50,14 +100,7 @@ def get_latest_firmware(device_id: str, db: Session = Depends(get_db)):
                 "Serving directly assigned desired firmware",
                 extra={"device_id": device_id, "firmware_version": firmware.version}
             )
-            return FirmwareResponse(
-                version=firmware.version,
-                checksum=firmware.checksum,
-                url=firmware.url,
-                rollout_phase=firmware.rollout_phase,
-                target_percent=firmware.target_percent,
-                rollout_status=firmware.rollout_status
-            )
+            return firmware_response(firmware, nonce)
 
     # 2. Segment-based filtering
     fw_query = db.query(models.Firmware).filter(models.Firmware.rollout_status == "active") # Only consider active rollouts
@@ -72,10 +115,10 @@ def get_latest_firmware(device_id: str, db: Session = Depends(get_db)):
 
     latest_firmware = fw_query.order_by(models.Firmware.created_at.desc()).first()
 
-    if not latest_firmware or latest_firmware.version == device.current_version:
+    if not latest_firmware or latest_firmware.version == current_version:
         logger.info(
             "No new active firmware or device is up to date",
-            extra={"device_id": device_id, "current_version": device.current_version, "latest_active_firmware": latest_firmware.version if latest_firmware else "N/A"}
+            extra={"device_id": device_id, "current_version": current_version, "latest_active_firmware": latest_firmware.version if latest_firmware else "N/A"}
         )
         return Response(status_code=204)
 
@@ -93,14 +136,7 @@ def get_latest_firmware(device_id: str, db: Session = Depends(get_db)):
             "Serving latest active firmware based on rollout percentage",
             extra={"device_id": device_id, "firmware_version": latest_firmware.version, "target_percent": latest_firmware.target_percent, "rollout_bucket": device.rollout_bucket}
         )
-        return FirmwareResponse(
-            version=latest_firmware.version,
-            checksum=latest_firmware.checksum,
-            url=latest_firmware.url,
-            rollout_phase=latest_firmware.rollout_phase,
-            target_percent=latest_firmware.target_percent,
-            rollout_status=latest_firmware.rollout_status
-        )
+        return firmware_response(latest_firmware, nonce)
 
     # 5. Default: No applicable update
     logger.info("No applicable firmware update found for device", extra={"device_id": device_id})
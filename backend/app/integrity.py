@@ -0,0 +1,38 @@
+"""Content hashes of measurements, computed the way devices compute them, so an ingest
+can echo back the hashes of what it stored and the device can check nothing changed on
+the way. The canonical form is the measurement's JSON as sent, minus the unhashed
+fields, with object keys sorted at every level and no whitespace. Numbers keep the
+digits the device wrote; re-printing them could change the hash."""
+import hashlib
+import json
+
+# Not part of the content: the hash itself, and the marker set on copies re-sent by a replay
+UNHASHED_FIELDS = ("content_hash", "replay")
+
+class _Number(str):
+    """A JSON number as it was written."""
+
+def parse(body: bytes):
+    return json.loads(body, parse_float=_Number, parse_int=_Number)
+
+def canonical_json(value) -> str:
+    if isinstance(value, dict):
+        return "{" + ",".join(json.dumps(name, ensure_ascii=False) + ":" + canonical_json(value[name]) for name in sorted(value)) + "}"
+    if isinstance(value, list):
+        return "[" + ",".join(canonical_json(item) for item in value) + "]"
+    if isinstance(value, _Number):
+        return str(value)
+    return json.dumps(value, ensure_ascii=False)
+
+def content_hash(measurement: dict) -> str:
+    content = {name: value for name, value in measurement.items() if name not in UNHASHED_FIELDS}
+    return hashlib.sha256(canonical_json(content).encode()).hexdigest()
+
+def accepted_hashes(measurements: list):
+    """The hashes of the measurements whose content matches the hash they were sent
+    with, in order. None if none was sent with a hash, so devices that do not hash see
+    no change."""
+    sent = [measurement for measurement in measurements if isinstance(measurement.get("content_hash"), str)]
+    if not sent:
+        return None
+    return [measurement["content_hash"] for measurement in sent if content_hash(measurement) == measurement["content_hash"].lower()]
@@ -1,7 +1,9 @@
+import gzip
 import logging
 from pythonjsonlogger import jsonlogger
 
 from fastapi import FastAPI
+from fastapi.responses import JSONResponse
 from fastapi.staticfiles import StaticFiles
 
 from . import models
@@ -28,7 +30,47 @@ if not logger.handlers: # Avoid adding handlers multiple times if reloaded
 
     logger.setLevel(logging.DEBUG)
 
+class GzipRequestMiddleware:
+    """Decompresses request bodies sent with Content-Encoding: gzip, as devices do for ingest."""
+
+    def __init__(self, app):
+        self.app = app
+
+    async def __call__(self, scope, receive, send):
+        headers = dict(scope.get("headers", [])) if scope["type"] == "http" else {}
+        if headers.get(b"content-encoding", b"").lower() != b"gzip":
+            await self.app(scope, receive, send)
+            return
+
+        body = b""
+        more_body = True
+        while more_body:
+            message = await receive()
+            body += message.get("body", b"")
+            more_body = message.get("more_body", False)
+        try:
+            body = gzip.decompress(body)
+        except (OSError, EOFError):
+            await JSONResponse({"detail": "Invalid gzip body"}, status_code=400)(scope, receive, send)
+            return
+
+        scope = dict(scope)
+        scope["headers"] = [
+            (name, value) for name, value in scope["headers"] if name not in (b"content-encoding", b"content-length")
+        ] + [(b"content-length", str(len(body)).encode())]
+        delivered = False
+
+        async def receive_decompressed():
+            nonlocal delivered
+            if delivered:
+                return await receive()
+            delivered = True
+            return {"type": "http.request", "body": body, "more_body": False}
+
+        await self.app(scope, receive_decompressed, send)
+
 app = FastAPI(title="Virtual Fleet Backend")
+app.add_middleware(GzipRequestMiddleware)
 
 # Mount static files
 app.mount("/static", StaticFiles(directory="app/ui/static"), name="static")
@@ -35,6 +35,7 @@ class Device(Base):
 
     measurements = relationship("Measurement", back_populates="device")
     errors = relationship("DeviceError", back_populates="device")
+    events = relationship("DeviceEvent", back_populates="device")
     metrics = relationship("Metric", backref="device_rel") # Using backref for simplicity
     alerts = relationship("Alert", backref="device_rel") # Using backref for simplicity
 
@@ -84,6 +85,17 @@ class DeviceError(Base):
 
     device = relationship("Device", back_populates="errors")
 
+class DeviceEvent(Base):
+    __tablename__ = "device_events"
+
+    id = Column(Integer, primary_key=True, index=True)
+    device_id = Column(String, ForeignKey("devices.id"), index=True)
+    received_at = Column(DateTime, default=datetime.datetime.utcnow)
+    event_type = Column(String, index=True) # e.g. geofence_breach, geofence_reentry, integrity_mismatch
+    payload = Column(String) # The event as the device sent it, JSON
+
+    device = relationship("Device", back_populates="events")
+
 class FleetSetting(Base):
     __tablename__ = "fleet_settings"
 
@@ -1,12 +1,20 @@
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
 from fastapi.testclient import TestClient
 from sqlalchemy import create_engine
 from sqlalchemy.orm import sessionmaker
+import datetime
+import gzip
+import hashlib
+import json
 import pytest
 import time
+import uuid
 
 from ..main import app
 from ..database import Base, get_db
 from .. import models
+from ..api import devices, firmware
+from .. import integrity
 
 # Use an in-memory SQLite database for testing
 SQLALCHEMY_DATABASE_URL = "sqlite:///./test.db"
@@ -95,6 +103,37 @@ def test_ingest_data_for_known_device():
     assert response.status_code == 200
     assert response.json()["message"] == "Ingested 1 measurements."
 
+def test_ingest_accepts_gzipped_body():
+    client.post(
+        "/api/devices/heartbeat",
+        json={
+            "device_id": "test-device-gzip",
+            "firmware_version": "1.0.0",
+            "reported_sample_interval_secs": 10,
+            "reported_upload_interval_secs": 60,
+            "reported_heartbeat_interval_secs": 30
+        },
+    )
+    body = json.dumps({
+        "device_id": "test-device-gzip",
+        "measurements": [
+            {
+                "timestamp": "2026-01-08T12:00:00Z",
+                "temp": 25.5,
+                "humidity": 60.1,
+                "battery": 0.95,
+                "sequence_number": 1
+            }
+        ]
+    }).encode()
+    response = client.post(
+        "/api/devices/ingest",
+        content=gzip.compress(body),
+        headers={"Content-Type": "application/json", "Content-Encoding": "gzip"},
+    )
+    assert response.status_code == 200
+    assert response.json()["message"] == "Ingested 1 measurements."
+
 def test_ingest_data_for_unknown_device():
     response = client.post(
         "/api/devices/ingest",
@@ -227,6 +266,12 @@ def test_blue_green_rollout():
     assert response_green.status_code == 200
     assert response_green.json()["version"] == "2.0.0-green"
 
+    # The request nonce is echoed back with an issue timestamp
+    response_nonce = client.get("/api/firmware/latest?device_id=green-device&nonce=abc123&current_version=1.0.0")
+    assert response_nonce.status_code == 200
+    assert response_nonce.json()["nonce"] == "abc123"
+    assert response_nonce.json()["issued_at"]
+
 def test_segment_rollout():
     # Register devices in different segments
     client.post("/api/devices/heartbeat", json={
@@ -282,3 +327,192 @@ def test_segment_rollout():
     response_eu_new = client.get("/api/firmware/latest?device_id=eu-device-rev2")
     assert response_eu_new.status_code == 200
     assert response_eu_new.json()["version"] == "3.0-universal"
+
+def add_active_device(device_id, auth_token):
+    db = TestingSessionLocal()
+    db.add(models.Device(id=device_id, auth_token=auth_token, lifecycle_state="active"))
+    db.commit()
+    db.close()
+
+def test_upload_device_file_rejects_a_checksum_mismatch(tmp_path, monkeypatch):
+    monkeypatch.setattr(devices, "UPLOAD_DIR", str(tmp_path))
+    add_active_device("upload-device", "upload-token")
+    body = b"export archive"
+
+    response = client.post(
+        "/api/devices/upload-device/files?purpose=export&name=archive.zip",
+        content=body,
+        headers={"X-Auth-Token": "upload-token", "X-Content-SHA256": hashlib.sha256(b"something else").hexdigest()},
+    )
+    assert response.status_code == 422
+    assert not (tmp_path / "upload-device").exists()
+
+    response = client.post(
+        "/api/devices/upload-device/files?purpose=export&name=archive.zip",
+        content=body,
+        headers={"X-Auth-Token": "upload-token", "X-Content-SHA256": hashlib.sha256(body).hexdigest()},
+    )
+    assert response.status_code == 200
+    stored = tmp_path / "upload-device" / "export" / response.json()["file_id"]
+    assert stored.read_bytes() == body
+
+def test_upload_device_file_for_another_device_is_forbidden(tmp_path, monkeypatch):
+    monkeypatch.setattr(devices, "UPLOAD_DIR", str(tmp_path))
+    add_active_device("uploader", "uploader-token")
+    add_active_device("other-device", "other-token")
+
+    response = client.post(
+        "/api/devices/other-device/files?purpose=export&name=archive.zip",
+        content=b"export archive",
+        headers={"X-Auth-Token": "uploader-token"},
+    )
+    assert response.status_code == 403
+    assert not (tmp_path / "other-device").exists()
+
+def test_device_events_are_stored_as_sent():
+    add_active_device("tracker", "tracker-token")
+    breach = {"event_type": "geofence_breach", "timestamp": "2026-01-08T12:00:00Z", "latitude": 52.53, "longitude": 13.405}
+
+    response = client.post("/api/devices/tracker/events", json=breach, headers={"X-Auth-Token": "tracker-token"})
+    assert response.status_code == 204
+    db = TestingSessionLocal()
+    events = db.query(models.DeviceEvent).filter(models.DeviceEvent.device_id == "tracker").all()
+    assert [(event.event_type, json.loads(event.payload)) for event in events] == [("geofence_breach", breach)]
+    db.close()
+
+    # Every event names its type
+    response = client.post("/api/devices/tracker/events", json={"latitude": 52.53}, headers={"X-Auth-Token": "tracker-token"})
+    assert response.status_code == 422
+
+def test_device_events_for_another_device_are_forbidden():
+    add_active_device("tracker", "tracker-token")
+    add_active_device("other-tracker", "other-token")
+
+    response = client.post("/api/devices/other-tracker/events", json={"event_type": "geofence_breach"}, headers={"X-Auth-Token": "tracker-token"})
+    assert response.status_code == 403
+    db = TestingSessionLocal()
+    assert db.query(models.DeviceEvent).count() == 0
+    db.close()
+
+def test_bulk_registration_registers_each_device():
+    boot_ids = [str(uuid.uuid4()) for _ in range(3)]
+    response = client.post("/api/devices/register/bulk", json={"devices": [{"boot_id": boot_id, "features": {}} for boot_id in boot_ids]})
+    assert response.status_code == 200
+    results = response.json()
+    assert [result["boot_id"] for result in results] == boot_ids
+    assert all(result["error"] is None for result in results)
+
+    # Each device got the credentials /register would have given it
+    db = TestingSessionLocal()
+    for result in results:
+        registration = result["registration"]
+        device = db.query(models.Device).filter(models.Device.id == registration["device_id"]).one()
+        assert device.auth_token == registration["auth_token"]
+        assert device.lifecycle_state == "new"
+        assert registration["desired_sample_interval_secs"] == 10
+    db.close()
+
+def test_bulk_registration_rejects_single_devices_without_failing_the_rest():
+    boot_id = str(uuid.uuid4())
+    response = client.post("/api/devices/register/bulk", json={"devices": [{"boot_id": boot_id}, {"boot_id": boot_id}]})
+    assert response.status_code == 200
+    first, repeated = response.json()
+    assert first["registration"] is not None
+    assert repeated["registration"] is None and "more than once" in repeated["error"]
+
+    too_many = [{"boot_id": str(uuid.uuid4())} for _ in range(devices.MAX_BULK_REGISTRATION + 1)]
+    assert client.post("/api/devices/register/bulk", json={"devices": too_many}).status_code == 413
+
+# As a device sends it: f32 values widened to f64, and a hash the device computed, which
+# its test the_content_hash_of_a_fixed_measurement_matches_the_backend checks as well
+HASHED_MEASUREMENT = r'''{"battery":0.8700000047683716,"content_hash":"9ce2046835c03ac2338e80fc08dff8c5d03a781573adcb1a33590b114f5dd38e","device_flags":["spike"],"extra":{"can":{"label":"\u00e9\u0001","rpm":1800}},"firmware_version":"1.0.0","humidity":48.5,"latitude":52.52000045776367,"longitude":13.404999732971191,"sequence_number":7,"speed":null,"temp":21.299999237060547,"timestamp":"2026-01-08T12:00:00Z"}'''
+
+def test_ingest_echoes_the_hashes_of_what_it_stored():
+    add_active_device("hashing-device", "hashing-token")
+    tampered = HASHED_MEASUREMENT.replace('"temp":21.299999237060547', '"temp":25.0').replace('"sequence_number":7', '"sequence_number":8')
+    body = '{"device_id":"hashing-device","measurements":[' + HASHED_MEASUREMENT + "," + tampered + "]}"
+
+    response = client.post("/api/devices/ingest", content=body, headers={"Content-Type": "application/json", "X-Auth-Token": "hashing-token"})
+    assert response.status_code == 200
+    # The tampered copy carries the original's hash, which its content no longer matches
+    assert response.json()["accepted_hashes"] == ["9ce2046835c03ac2338e80fc08dff8c5d03a781573adcb1a33590b114f5dd38e"]
+    assert integrity.content_hash(integrity.parse(HASHED_MEASUREMENT.encode())) == "9ce2046835c03ac2338e80fc08dff8c5d03a781573adcb1a33590b114f5dd38e"
+
+def test_ingest_without_hashes_echoes_none():
+    add_active_device("plain-device", "plain-token")
+    response = client.post(
+        "/api/devices/ingest",
+        json={"device_id": "plain-device", "measurements": [{"timestamp": "2026-01-08T12:00:00Z", "temp": 25.5, "sequence_number": 1}]},
+        headers={"X-Auth-Token": "plain-token"},
+    )
+    assert response.status_code == 200
+    assert "accepted_hashes" not in response.json()
+
+def test_sync_records_the_heartbeat_and_returns_the_desired_state_and_shadow():
+    add_active_device("syncing-device", "sync-token")
+    db = TestingSessionLocal()
+    device = db.query(models.Device).filter(models.Device.id == "syncing-device").one()
+    device.desired_state = json.dumps({"chaos_flags": {"random_error": True}})
+    device.desired_upload_interval_secs = 120
+    db.commit()
+    db.close()
+
+    response = client.post(
+        "/api/devices/sync",
+        json={
+            "device_id": "syncing-device",
+            "firmware_version": "1.2.0",
+            "reported_sample_interval_secs": 10,
+            "reported_upload_interval_secs": 60,
+            "reported_heartbeat_interval_secs": 30,
+        },
+        headers={"X-Auth-Token": "sync-token"},
+    )
+    assert response.status_code == 200
+    synced = response.json()
+    assert synced["desired_state"]["desired_upload_interval_secs"] == 120
+    assert synced["shadow"] == {"desired": {"chaos_flags": {"random_error": True}}, "reported": {}}
+
+    # The heartbeat half was recorded as /heartbeat would have
+    db = TestingSessionLocal()
+    device = db.query(models.Device).filter(models.Device.id == "syncing-device").one()
+    assert (device.current_version, device.status) == ("1.2.0", "online")
+    db.close()
+
+def test_latest_firmware_metadata_is_signed_with_the_nonce(monkeypatch):
+    monkeypatch.setenv("OTA_SIGNING_KEY", "07" * 32)
+    db = TestingSessionLocal()
+    db.add(models.Device(id="signed-device", current_version="1.0.0", desired_version="1.2.0", lifecycle_state="active"))
+    db.add(models.Firmware(version="1.2.0", checksum="sha256:abc", url="/firmware/1.2.0.bin"))
+    db.commit()
+    db.close()
+
+    response = client.get("/api/firmware/latest?device_id=signed-device&nonce=n-1&current_version=1.0.0")
+    assert response.status_code == 200
+    metadata = response.json()
+    issued_at = datetime.datetime.fromisoformat(metadata["issued_at"].replace("Z", "+00:00"))
+    message = firmware.metadata_message(metadata["version"], metadata["checksum"], metadata["url"], metadata["nonce"], issued_at)
+    public_key = Ed25519PrivateKey.from_private_bytes(bytes.fromhex("07" * 32)).public_key()
+    public_key.verify(bytes.fromhex(metadata["metadata_signature"]), message)
+
+    # The device's SIGNED_METADATA fixture in ota_tests.rs, signed here
+    fixture = firmware.metadata_message(
+        "1.2.0", "sha256:abc", "https://fleet.example/firmware/1.2.0.bin", "n-1",
+        datetime.datetime(2026, 1, 8, 12, 0, 0, tzinfo=datetime.timezone.utc),
+    )
+    assert firmware.signing_key().sign(fixture).hex() == (
+        "18c4e73a722b2ccc800588658ff4f44b9a592f108491b670cba8484668ec14275fcb8bbe2e50b2b6f1fda1757d9c0921fc6477571a272b0bf9ba18b5af15ac02"
+    )
+
+def test_latest_firmware_metadata_is_unsigned_without_a_key(monkeypatch):
+    monkeypatch.delenv("OTA_SIGNING_KEY", raising=False)
+    db = TestingSessionLocal()
+    db.add(models.Device(id="unsigned-device", current_version="1.0.0", desired_version="1.2.0", lifecycle_state="active"))
+    db.add(models.Firmware(version="1.2.0", checksum="sha256:abc", url="/firmware/1.2.0.bin"))
+    db.commit()
+    db.close()
+
+    response = client.get("/api/firmware/latest?device_id=unsigned-device&nonce=n-1")
+    assert response.status_code == 200
+    assert response.json()["nonce"] == "n-1"
+    assert response.json()["metadata_signature"] is None
@@ -0,0 +1,15 @@
+use crate::types::IngestFeedback;
+
+/// Computes the sample interval suggested by backend ingest feedback, clamped to
+/// the configured bounds. Returns `None` when the feedback carries no suggestion
+/// or the clamped value equals the current interval.
+pub fn apply_ingest_feedback(current_secs: u64, feedback: &IngestFeedback, min_secs: u64, max_secs: u64) -> Option<u64> {
+    let suggested = feedback.suggested_sample_interval_secs?;
+    let (lo, hi) = if min_secs <= max_secs { (min_secs, max_secs) } else { (max_secs, min_secs) };
+    let new_secs = suggested.clamp(lo.max(1), hi.max(1));
+    if new_secs == current_secs {
+        None
+    } else {
+        Some(new_secs)
+    }
+}
@@ -0,0 +1,127 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::types::Measurement;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldDetectorConfig {
+    #[serde(default = "default_window")]
+    pub window: u32, // EWMA span in samples; also the warm-up before flags fire
+    #[serde(default = "default_threshold")]
+    pub threshold: f64, // Flag when |z| exceeds this many standard deviations
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SelfDetectionConfig {
+    #[serde(default)]
+    pub fields: BTreeMap<String, FieldDetectorConfig>,
+    // Samples further apart than this restart the field's statistics
+    #[serde(default = "default_max_gap_secs")]
+    pub max_gap_secs: i64,
+}
+
+fn default_window() -> u32 {
+    20
+}
+
+fn default_threshold() -> f64 {
+    3.0
+}
+
+fn default_max_gap_secs() -> i64 {
+    600
+}
+
+#[derive(Debug, Clone, Default)]
+struct Ewma {
+    mean: f64,
+    variance: f64,
+    count: u32,
+    last_at: Option<DateTime<Utc>>,
+}
+
+/// Rolling EWMA-band anomaly detector mirroring the backend's per-field logic.
+/// Each evaluation is O(1) per configured field.
+#[derive(Debug, Clone)]
+pub struct SelfDetector {
+    config: SelfDetectionConfig,
+    state: BTreeMap<String, Ewma>,
+    counts: BTreeMap<String, u64>,
+}
+
+fn field_value(measurement: &Measurement, field: &str) -> Option<f64> {
+    let value = match field {
+        "temp" => Some(measurement.temp),
+        "humidity" => Some(measurement.humidity),
+        "battery" => Some(measurement.battery),
+        "latitude" => measurement.latitude,
+        "longitude" => measurement.longitude,
+        "speed" => measurement.speed,
+        _ => None,
+    };
+    value.map(f64::from)
+}
+
+impl SelfDetector {
+    pub fn new(config: SelfDetectionConfig) -> Self {
+        SelfDetector { config, state: BTreeMap::new(), counts: BTreeMap::new() }
+    }
+
+    /// Swaps in new parameters, keeping statistics for fields whose window is unchanged.
+    pub fn reconfigure(&mut self, config: SelfDetectionConfig) {
+        self.state.retain(|field, _| {
+            config.fields.get(field).map(|c| c.window) == self.config.fields.get(field).map(|c| c.window)
+        });
+        self.config = config;
+    }
+
+    /// Detection counts per field since boot, for heartbeat summaries.
+    pub fn counts(&self) -> &BTreeMap<String, u64> {
+        &self.counts
+    }
+
+    /// Updates the statistics with a new measurement and returns the flags it raised,
+    /// e.g. `["temp_anomaly"]`.
+    pub fn evaluate(&mut self, measurement: &Measurement) -> Vec<String> {
+        let mut flags = Vec::new();
+        for (field, params) in &self.config.fields {
+            let Some(value) = field_value(measurement, field) else {
+                continue;
+            };
+            let state = self.state.entry(field.clone()).or_default();
+
+            let gap_exceeded = state
+                .last_at
+                .is_some_and(|last| (measurement.timestamp - last).num_seconds() > self.config.max_gap_secs);
+            if gap_exceeded {
+                *state = Ewma::default();
+            }
+            state.last_at = Some(measurement.timestamp);
+
+            if state.count == 0 {
+                state.mean = value;
+                state.variance = 0.0;
+                state.count = 1;
+                continue;
+            }
+
+            let std_dev = state.variance.sqrt();
+            if state.count >= params.window && std_dev > f64::EPSILON {
+                let z = (value - state.mean) / std_dev;
+                if z.abs() > params.threshold {
+                    flags.push(format!("{}_anomaly", field));
+                    *self.counts.entry(field.clone()).or_insert(0) += 1;
+                }
+            }
+
+            let alpha = 2.0 / (f64::from(params.window) + 1.0);
+            let diff = value - state.mean;
+            let increment = alpha * diff;
+            state.mean += increment;
+            state.variance = (1.0 - alpha) * (state.variance + diff * increment);
+            state.count = state.count.saturating_add(1);
+        }
+        flags
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{error, info};
+
+
+pub const AUDIT_LOG_FILE: &str = "audit.log";
+
+/// Which control-plane channel requested an action.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditSource {
+    Shadow,
+    Heartbeat,
+    IngestFeedback,
+    Ota,
+    Startup,
+    Sampler,
+    DebugSession, // Settings elevated for a debug session, or restored when it ended
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub sequence: u64,
+    pub timestamp: DateTime<Utc>,
+    pub device_id: String,
+    pub source: AuditSource,
+    pub action: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+/// Append-only, sequenced audit trail of control-plane actions, kept separate from
+/// operational logs. Each record is one JSON line; sequence numbers continue across restarts.
+pub struct AuditLog {
+    path: PathBuf,
+    device_id: String,
+    next_sequence: u64,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, device_id: &str) -> Result<Self> {
+        let next_sequence = match fs::read_to_string(path) {
+            Ok(contents) => contents
+                .lines()
+                .rev()
+                .find_map(|line| serde_json::from_str::<AuditRecord>(line).ok())
+                .map_or(0, |last| last.sequence + 1),
+            Err(_) => 0,
+        };
+        Ok(AuditLog { path: path.to_path_buf(), device_id: device_id.to_string(), next_sequence })
+    }
+
+    /// Appends a record. Failures are logged rather than propagated so auditing never
+    /// blocks applying the action itself.
+    pub fn record(&mut self, source: AuditSource, action: &str, old: Value, new: Value) {
+        let record = AuditRecord {
+            sequence: self.next_sequence,
+            timestamp: Utc::now(),
+            device_id: self.device_id.clone(),
+            source,
+            action: action.to_string(),
+            old,
+            new,
+        };
+        match self.append(&record) {
+            Ok(()) => {
+                self.next_sequence += 1;
+                info!(target: "audit", sequence = record.sequence, source = ?record.source, action = %record.action, old = %record.old, new = %record.new, "Control-plane action applied");
+            }
+            Err(e) => {
+                error!(device_id = %self.device_id, error = %e, action = action, "Failed to write audit record");
+            }
+        }
+    }
+
+    /// Records written within `from..=to`, oldest first. Unreadable lines are skipped.
+    pub fn records_between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<AuditRecord>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(contents
+            .lines()
+            .filter_map(|line| serde_json::from_str::<AuditRecord>(line).ok())
+            .filter(|record| (from..=to).contains(&record.timestamp))
+            .collect())
+    }
+
+    fn append(&self, record: &AuditRecord) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use anyhow::{Context, Result};
+use reqwest::{Client, StatusCode};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::features::Features;
+use crate::init;
+use crate::net;
+
+/// Identity issued by the backend after the config was loaded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub device_id: String,
+    pub auth_token: String,
+}
+
+/// Credentials refreshed at runtime, shared by every clone of the config so the
+/// main loop and background tasks all pick up a new token at once.
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    inner: Arc<Mutex<SessionState>>,
+}
+
+#[derive(Debug, Default)]
+struct SessionState {
+    refreshed: Option<Credentials>,
+    persist_to: Option<PathBuf>, // Config file rewritten after a refresh; None keeps it in memory
+}
+
+impl Session {
+    pub fn persisting_to(path: PathBuf) -> Self {
+        Session { inner: Arc::new(Mutex::new(SessionState { refreshed: None, persist_to: Some(path) })) }
+    }
+
+    pub fn refreshed(&self) -> Option<Credentials> {
+        self.inner.lock().unwrap().refreshed.clone()
+    }
+}
+
+/// The backend answers 401 for unknown or rotated tokens and 403 for devices it will not serve.
+pub fn is_rejected(status: StatusCode) -> bool {
+    status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN
+}
+
+/// Token to send: the refreshed one if there is one, else the one from the config file.
+pub fn current_token(config: &Config) -> Result<String> {
+    match config.session.refreshed() {
+        Some(credentials) => Ok(credentials.auth_token),
+        None => config.auth_token.clone().ok_or_else(|| anyhow::anyhow!("Auth token not found")),
+    }
+}
+
+/// Replaces a rejected token by registering again, using the device id as boot id: the
+/// backend answers a known device with the credentials it now holds for it. A device the
+/// backend no longer knows is registered afresh; its new identity is persisted but only
+/// taken on after a restart. Returns the token to retry with.
+pub async fn refresh(client: &Client, config: &Config, rejected: &str) -> Result<String> {
+    let current = current_token(config)?;
+    if current != rejected {
+        return Ok(current); // Another request refreshed it in the meantime
+    }
+
+    let boot_id = uuid::Uuid::parse_str(&config.device_id).unwrap_or_else(|_| uuid::Uuid::new_v4());
+    let features = Features::resolve(&config.features);
+    let response = net::register_device(client, &config.backend_url, boot_id, config.device_name.clone(), features.report(), None, &config.compression.accept_encoding())
+        .await
+        .context("failed to refresh the rejected auth token")?;
+    let credentials = Credentials { device_id: response.device_id.to_string(), auth_token: response.auth_token.to_string() };
+    if credentials.device_id == config.device_id {
+        info!(device_id = %config.device_id, "Refreshed auth token after it was rejected");
+    } else {
+        warn!(device_id = %config.device_id, new_device_id = %credentials.device_id, "Backend no longer knows this device; registered a new identity, taken on at the next restart");
+    }
+
+    let persist_to = {
+        let mut state = config.session.inner.lock().unwrap();
+        state.refreshed = Some(credentials.clone());
+        state.persist_to.clone()
+    };
+    if let Some(path) = persist_to {
+        let mut persisted = config.clone();
+        if credentials.device_id != config.device_id {
+            init::adopt_identity(&mut persisted, &response);
+        }
+        if let Err(e) = persisted.save_to(&path) {
+            error!(device_id = %config.device_id, error = %e, "Failed to persist refreshed auth token");
+        }
+    }
+    Ok(credentials.auth_token)
+}
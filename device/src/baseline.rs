@@ -0,0 +1,254 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::simulate::SensorProfile;
+use crate::types::Measurement;
+
+/// Background failure rates of a device, each per opportunity for the fault to happen.
+/// Every profile has one, so a simulated fleet can be made no more reliable than a real
+/// one; it is off unless `background_faults` turns it on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FaultBaseline {
+    pub gps_dropout: f64, // Chance a sample with a fix loses it
+    pub dead_zone: f64, // Chance an upload tick finds no cellular coverage
+    pub upload_timeout: f64, // Chance an upload tick with coverage times out
+    pub restarts_per_day: f64, // Unplanned restarts, such as watchdog resets and brownouts
+    pub low_battery_below: f32, // Charge fraction below which sampling is throttled; 0 never throttles
+    pub low_battery_throttle: u32, // While throttled, one sample tick in this many is taken
+}
+
+impl FaultBaseline {
+    /// No background faults at all.
+    pub const NONE: FaultBaseline = FaultBaseline {
+        gps_dropout: 0.0,
+        dead_zone: 0.0,
+        upload_timeout: 0.0,
+        restarts_per_day: 0.0,
+        low_battery_below: 0.0,
+        low_battery_throttle: 1,
+    };
+
+    /// Real-world-inspired defaults per profile:
+    /// - asset_tracker: about 3% of fixes are lost to tunnels, urban canyons and parking
+    ///   structures, and 2% of upload attempts fall in rural cellular dead zones. It runs
+    ///   off the vehicle, so it is never throttled for battery.
+    /// - environmental_node: a fixed outdoor station with marginal but stable coverage
+    ///   (0.5% dead zone), on a primary cell it spares below 20% charge by sampling at a
+    ///   quarter of the rate.
+    /// - industrial_meter: mains powered with good coverage, but brownouts on the shop
+    ///   floor restart it about once every three weeks.
+    /// - reefer_trailer: on the road like the asset tracker, with the same fix and
+    ///   coverage losses; the trailer's steel box costs it nothing extra.
+    ///
+    /// Every profile times out on 1% of uploads and restarts spontaneously now and then,
+    /// in line with field reports for consumer-grade cellular modules.
+    pub fn for_profile(profile: SensorProfile) -> Self {
+        match profile {
+            SensorProfile::AssetTracker => FaultBaseline {
+                gps_dropout: 0.03,
+                dead_zone: 0.02,
+                upload_timeout: 0.01,
+                restarts_per_day: 0.02,
+                ..FaultBaseline::NONE
+            },
+            SensorProfile::EnvironmentalNode => FaultBaseline {
+                dead_zone: 0.005,
+                upload_timeout: 0.01,
+                restarts_per_day: 0.01,
+                low_battery_below: 0.2,
+                low_battery_throttle: 4,
+                ..FaultBaseline::NONE
+            },
+            SensorProfile::IndustrialMeter => FaultBaseline {
+                dead_zone: 0.001,
+                upload_timeout: 0.01,
+                restarts_per_day: 0.05,
+                ..FaultBaseline::NONE
+            },
+            SensorProfile::ReeferTrailer => FaultBaseline {
+                gps_dropout: 0.03,
+                dead_zone: 0.02,
+                upload_timeout: 0.01,
+                restarts_per_day: 0.02,
+                ..FaultBaseline::NONE
+            },
+        }
+    }
+
+    /// The baseline a device runs with: its profile's defaults with `overrides` applied
+    /// when `enabled`, otherwise none.
+    pub fn effective(profile: SensorProfile, overrides: &BaselineOverrides, enabled: bool) -> Self {
+        if !enabled {
+            return FaultBaseline::NONE;
+        }
+        let defaults = FaultBaseline::for_profile(profile);
+        FaultBaseline {
+            gps_dropout: overrides.gps_dropout.unwrap_or(defaults.gps_dropout),
+            dead_zone: overrides.dead_zone.unwrap_or(defaults.dead_zone),
+            upload_timeout: overrides.upload_timeout.unwrap_or(defaults.upload_timeout),
+            restarts_per_day: overrides.restarts_per_day.unwrap_or(defaults.restarts_per_day),
+            low_battery_below: overrides.low_battery_below.unwrap_or(defaults.low_battery_below),
+            low_battery_throttle: overrides.low_battery_throttle.unwrap_or(defaults.low_battery_throttle),
+        }
+    }
+
+    /// Why the baseline cannot be simulated, if it cannot.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, probability) in [("gps_dropout", self.gps_dropout), ("dead_zone", self.dead_zone), ("upload_timeout", self.upload_timeout)] {
+            if !(0.0..=1.0).contains(&probability) {
+                return Err(format!("{} must be between 0 and 1, got {}", name, probability));
+            }
+        }
+        if !(self.restarts_per_day >= 0.0 && self.restarts_per_day.is_finite()) {
+            return Err(format!("restarts_per_day must be a non-negative number, got {}", self.restarts_per_day));
+        }
+        if !(0.0..=1.0).contains(&self.low_battery_below) {
+            return Err(format!("low_battery_below must be between 0 and 1, got {}", self.low_battery_below));
+        }
+        if self.low_battery_throttle == 0 {
+            return Err("low_battery_throttle must be at least 1".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Rates replacing a profile's defaults; unset ones keep the default.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BaselineOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gps_dropout: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dead_zone: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_timeout: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restarts_per_day: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub low_battery_below: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub low_battery_throttle: Option<u32>,
+}
+
+/// Why an upload tick failed on the baseline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadFault {
+    DeadZone,
+    Timeout,
+}
+
+impl UploadFault {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            UploadFault::DeadZone => "no cellular coverage",
+            UploadFault::Timeout => "upload timed out",
+        }
+    }
+}
+
+/// Faults injected since boot, reported next to the rates they were drawn at.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InjectedFaults {
+    pub gps_dropouts: u64,
+    pub dead_zones: u64,
+    pub upload_timeouts: u64,
+    pub throttled_samples: u64,
+}
+
+/// Decides which baseline faults happen. Draws only for faults with a nonzero rate.
+#[derive(Debug, Clone)]
+pub struct BaselineFaults {
+    profile: SensorProfile,
+    enabled: bool,
+    baseline: FaultBaseline,
+    rng: StdRng,
+    skipped_ticks: u32, // Sample ticks skipped since the last one taken while throttled
+    injected: InjectedFaults,
+}
+
+impl BaselineFaults {
+    /// Draws from `seed`, or from entropy without one.
+    pub fn new(profile: SensorProfile, overrides: &BaselineOverrides, enabled: bool, seed: Option<u64>) -> Self {
+        BaselineFaults {
+            profile,
+            enabled,
+            baseline: FaultBaseline::effective(profile, overrides, enabled),
+            rng: seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64),
+            skipped_ticks: 0,
+            injected: InjectedFaults::default(),
+        }
+    }
+
+    pub fn baseline(&self) -> &FaultBaseline {
+        &self.baseline
+    }
+
+    // The device reports these through report(); tests read them directly
+    #[cfg(test)]
+    pub fn injected(&self) -> InjectedFaults {
+        self.injected
+    }
+
+    // Invalid rates inject nothing rather than panicking; validation reports them
+    fn draw(&mut self, probability: f64) -> bool {
+        probability > 0.0 && probability <= 1.0 && self.rng.gen_bool(probability)
+    }
+
+    /// Drops the fix from a sample that has one. Returns true if it did.
+    pub fn gps_dropout(&mut self, measurement: &mut Measurement) -> bool {
+        if measurement.latitude.is_none() || !self.draw(self.baseline.gps_dropout) {
+            return false;
+        }
+        (measurement.latitude, measurement.longitude, measurement.speed, measurement.heading) = (None, None, None, None);
+        self.injected.gps_dropouts += 1;
+        true
+    }
+
+    /// Whether this upload tick fails, and why.
+    pub fn upload_fault(&mut self) -> Option<UploadFault> {
+        if self.draw(self.baseline.dead_zone) {
+            self.injected.dead_zones += 1;
+            return Some(UploadFault::DeadZone);
+        }
+        if self.draw(self.baseline.upload_timeout) {
+            self.injected.upload_timeouts += 1;
+            return Some(UploadFault::Timeout);
+        }
+        None
+    }
+
+    /// Whether the device restarts within the `elapsed_secs` since the last check;
+    /// restarts arrive as a Poisson process at restarts_per_day.
+    pub fn restart(&mut self, elapsed_secs: f64) -> bool {
+        let probability = 1.0 - (-self.baseline.restarts_per_day * elapsed_secs.max(0.0) / 86_400.0).exp();
+        self.draw(probability)
+    }
+
+    /// Whether this sample tick takes a measurement at `battery_level`. Below
+    /// low_battery_below only one tick in low_battery_throttle does.
+    pub fn admit(&mut self, battery_level: f32) -> bool {
+        if battery_level >= self.baseline.low_battery_below || self.baseline.low_battery_throttle <= 1 {
+            self.skipped_ticks = 0;
+            return true;
+        }
+        if self.skipped_ticks + 1 >= self.baseline.low_battery_throttle {
+            self.skipped_ticks = 0;
+            return true;
+        }
+        self.skipped_ticks += 1;
+        self.injected.throttled_samples += 1;
+        false
+    }
+
+    /// The baseline in effect and what it injected, for analysts to subtract.
+    pub fn report(&self) -> Value {
+        json!({
+            "profile": self.profile,
+            "enabled": self.enabled,
+            "rates": self.baseline,
+            "injected": self.injected,
+        })
+    }
+}
@@ -0,0 +1,242 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::stats::StatsByEndpoint;
+use crate::storage;
+use crate::types::Measurement;
+
+const STATE_KEY: &str = "battery_drain";
+const MODEL_STATE_KEY: &str = "battery";
+
+/// At or below this level the device is put on charge.
+pub const CHARGE_BELOW: f32 = 0.1;
+
+// Charge fraction each radio-heavy request costs on top of the per-sample drain
+const UPLOAD_COST: f32 = 0.0005; // Per ingest request
+const OTA_DOWNLOAD_COST: f32 = 0.01; // Per firmware download
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatteryPhase {
+    Draining,
+    Charging, // Climbs back to full at `charge_rate`, then drains again
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct ModelState {
+    level: f32,
+    phase: BatteryPhase,
+    last_at: Option<DateTime<Utc>>, // Of the last sample, to charge by elapsed time
+}
+
+/// The device's battery: drains a little with every sample, upload and firmware download,
+/// and once down to CHARGE_BELOW charges back to full over time before draining again.
+/// The level and phase are checkpointed, so a restart continues the cycle.
+#[derive(Debug, Clone)]
+pub struct Battery {
+    drain_rate: f32, // Charge fraction per sample
+    charge_rate: f32, // Charge fraction per hour on charge
+    state: ModelState,
+    charged_requests: (u64, u64), // Ingest and firmware download attempts already paid for this boot
+}
+
+impl Battery {
+    pub fn load(drain_rate: f32, charge_rate: f32, conn: &Connection) -> Result<Self> {
+        let state = match storage::load_state(conn, MODEL_STATE_KEY)? {
+            Some(value) => serde_json::from_value(value)?,
+            None => ModelState { level: 1.0, phase: BatteryPhase::Draining, last_at: None },
+        };
+        Ok(Battery { drain_rate, charge_rate, state, charged_requests: (0, 0) })
+    }
+
+    pub fn checkpoint(&self, conn: &Connection) -> Result<()> {
+        Ok(storage::save_state(conn, MODEL_STATE_KEY, &serde_json::to_value(&self.state)?)?)
+    }
+
+    pub fn level(&self) -> f32 {
+        self.state.level
+    }
+
+    /// Advances the battery to a sample taken at `now` and stamps the level on it. The
+    /// per-sample drain is multiplied by `drain_factor`; requests in `stats` not yet paid
+    /// for are charged too. Returns the new phase when this sample changed it.
+    pub fn apply(&mut self, measurement: &mut Measurement, now: DateTime<Utc>, stats: &StatsByEndpoint, drain_factor: f32) -> Option<BatteryPhase> {
+        let attempts = |endpoint: &str| stats.get(endpoint).map_or(0, |endpoint| endpoint.attempts);
+        let requests = (attempts("ingest"), attempts("firmware_download"));
+        let cost = requests.0.saturating_sub(self.charged_requests.0) as f32 * UPLOAD_COST
+            + requests.1.saturating_sub(self.charged_requests.1) as f32 * OTA_DOWNLOAD_COST;
+        self.charged_requests = requests;
+
+        let state = &mut self.state;
+        let hours = state.last_at.map_or(0.0, |last_at| (now - last_at).num_milliseconds().max(0) as f32 / 3_600_000.0);
+        state.last_at = Some(now);
+        let previous = state.phase;
+        match state.phase {
+            BatteryPhase::Draining => {
+                state.level = (state.level - self.drain_rate * drain_factor - cost).max(0.0);
+                if state.level <= CHARGE_BELOW {
+                    state.phase = BatteryPhase::Charging;
+                }
+            }
+            BatteryPhase::Charging => {
+                state.level = (state.level + self.charge_rate * hours - cost).clamp(0.0, 1.0);
+                if state.level >= 1.0 {
+                    state.phase = BatteryPhase::Draining;
+                }
+            }
+        }
+        measurement.battery = state.level;
+        (state.phase != previous).then_some(state.phase)
+    }
+
+    pub fn report(&self) -> Value {
+        json!({
+            "level": self.state.level,
+            "phase": self.state.phase,
+            "drain_rate": self.drain_rate,
+            "charge_rate": self.charge_rate,
+        })
+    }
+}
+
+/// Parses the `battery_drain_factor` chaos flag: how many times faster than configured
+/// the battery drains per sample.
+pub fn drain_factor(flag: &Value) -> Result<f32, String> {
+    match flag.as_f64() {
+        Some(factor) if factor >= 1.0 && factor.is_finite() => Ok(factor as f32),
+        _ => Err("expected a number of at least 1".to_string()),
+    }
+}
+
+/// Settings of the `battery_drain` chaos flag: `true` for the defaults, or e.g.
+/// `{"start": 0.9, "rate": 0.002, "low_threshold": 0.2, "power_save_factor": 4}`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatteryDrainConfig {
+    pub start: f32, // Charge fraction draining starts from
+    pub rate: f32, // Charge fraction lost per measurement
+    pub low_threshold: f32, // At or below this the device saves power
+    pub power_save_factor: u32, // While saving power, one sample tick in this many is taken
+}
+
+impl Default for BatteryDrainConfig {
+    fn default() -> Self {
+        BatteryDrainConfig { start: 1.0, rate: 0.001, low_threshold: 0.15, power_save_factor: 4 }
+    }
+}
+
+/// Parses the `battery_drain` chaos flag; `false` turns draining off.
+pub fn drain_config(flag: &Value) -> Result<Option<BatteryDrainConfig>, String> {
+    let config = match flag {
+        Value::Bool(false) => return Ok(None),
+        Value::Bool(true) => BatteryDrainConfig::default(),
+        Value::Object(_) => serde_json::from_value(flag.clone()).map_err(|e| e.to_string())?,
+        _ => return Err("expected true, false or an object".to_string()),
+    };
+    if !(0.0..=1.0).contains(&config.start) || !(0.0..=1.0).contains(&config.low_threshold) {
+        return Err("start and low_threshold must be between 0 and 1".to_string());
+    }
+    if !(config.rate >= 0.0 && config.rate <= 1.0) {
+        return Err("rate must be between 0 and 1".to_string());
+    }
+    if config.power_save_factor == 0 {
+        return Err("power_save_factor must be at least 1".to_string());
+    }
+    Ok(Some(config))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct DrainState {
+    level: f32,
+    config: BatteryDrainConfig, // Settings the level drained under; new settings start over
+    #[serde(default)]
+    skipped_ticks: u32, // Sample ticks skipped since the last one taken while saving power
+}
+
+/// A discharging cell overriding the Battery model's reading while the
+/// `battery_drain` chaos flag is set. The level only ever goes down, and is
+/// checkpointed so a restart continues where it left off.
+#[derive(Debug, Clone, Default)]
+pub struct BatteryDrain {
+    state: Option<DrainState>,
+}
+
+impl BatteryDrain {
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let state = match storage::load_state(conn, STATE_KEY)? {
+            Some(value) => serde_json::from_value(value)?,
+            None => None,
+        };
+        Ok(BatteryDrain { state })
+    }
+
+    pub fn checkpoint(&self, conn: &Connection) -> Result<()> {
+        Ok(storage::save_state(conn, STATE_KEY, &serde_json::to_value(&self.state)?)?)
+    }
+
+    /// Follows the chaos flag. Unchanged settings keep the current level, so the flag
+    /// staying in the shadow does not recharge the cell; a malformed flag is ignored.
+    /// Returns true if draining started, stopped or was reconfigured.
+    pub fn set_flag(&mut self, flag: Option<&Value>) -> bool {
+        let config = match flag.map(drain_config) {
+            Some(Ok(config)) => config,
+            Some(Err(_)) => return false,
+            None => None,
+        };
+        if config == self.state.as_ref().map(|state| state.config) {
+            return false;
+        }
+        self.state = config.map(|config| DrainState { level: config.start, config, skipped_ticks: 0 });
+        true
+    }
+
+    pub fn level(&self) -> Option<f32> {
+        self.state.as_ref().map(|state| state.level)
+    }
+
+    pub fn power_saving(&self) -> bool {
+        self.state.as_ref().is_some_and(|state| state.level <= state.config.low_threshold)
+    }
+
+    /// Whether this sample tick takes a measurement. While saving power only one
+    /// tick in `power_save_factor` does.
+    pub fn admit(&mut self) -> bool {
+        let power_saving = self.power_saving();
+        let Some(state) = self.state.as_mut().filter(|_| power_saving) else {
+            return true;
+        };
+        if state.skipped_ticks + 1 >= state.config.power_save_factor {
+            state.skipped_ticks = 0;
+            return true;
+        }
+        state.skipped_ticks += 1;
+        false
+    }
+
+    /// Drains one measurement's worth and stamps the level on the measurement.
+    /// Returns true when this measurement took the cell into power saving.
+    pub fn apply(&mut self, measurement: &mut Measurement) -> bool {
+        let was_saving = self.power_saving();
+        let Some(state) = self.state.as_mut() else {
+            return false;
+        };
+        measurement.battery = state.level;
+        state.level = (state.level - state.config.rate).max(0.0);
+        !was_saving && self.power_saving()
+    }
+
+    pub fn report(&self) -> Value {
+        match &self.state {
+            Some(state) => json!({
+                "draining": true,
+                "level": state.level,
+                "power_saving": self.power_saving(),
+                "power_save_factor": state.config.power_save_factor,
+            }),
+            None => json!({"draining": false}),
+        }
+    }
+}
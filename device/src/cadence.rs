@@ -0,0 +1,132 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use crate::types::Measurement;
+
+/// Fields a cadence can leave out. `timestamp` and `sequence_number` identify the sample and
+/// always go; the backend fills an absent field with the last value it received for it.
+pub const FIELDS: &[&str] = &["temp", "humidity", "battery", "latitude", "longitude", "speed", "firmware_version"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldCadence {
+    #[serde(default = "default_every")]
+    pub every: u32, // Send at least every Nth sample; 0 sends only on change and in keyframes
+    #[serde(default)]
+    pub delta: Option<f64>, // Numbers: also send once the value moved this far from the last one sent
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CadenceConfig {
+    #[serde(default)]
+    pub fields: BTreeMap<String, FieldCadence>, // Fields not listed are sent with every sample
+    #[serde(default = "default_keyframe_interval_secs")]
+    pub keyframe_interval_secs: u64, // Every field goes in the first sample after this much time
+}
+
+fn default_every() -> u32 {
+    1
+}
+
+fn default_keyframe_interval_secs() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone)]
+struct FieldState {
+    last_sent: Value,
+    skipped: u32, // Samples since the field was last sent
+}
+
+/// Decides per sample which fields go on the wire. Values are compared with the last value
+/// *sent*, not the previous sample, so a slow drift still crosses its delta eventually.
+#[derive(Debug, Clone, Default)]
+pub struct CadenceEngine {
+    config: Option<CadenceConfig>,
+    last_keyframe: Option<DateTime<Utc>>,
+    state: BTreeMap<String, FieldState>,
+    keyframes: u64,
+    omitted_values: u64,
+}
+
+impl CadenceEngine {
+    pub fn new(config: Option<CadenceConfig>) -> Self {
+        CadenceEngine { config, ..Default::default() }
+    }
+
+    /// Swaps in new cadences. The next sample is a keyframe, so the backend never holds a
+    /// value older than the change.
+    pub fn reconfigure(&mut self, config: Option<CadenceConfig>) {
+        self.config = config;
+        self.last_keyframe = None;
+        self.state.clear();
+    }
+
+    /// Marks the measurement as a keyframe or lists the fields to leave out of its upload.
+    /// The stored row keeps every value.
+    pub fn apply(&mut self, measurement: &mut Measurement) {
+        let Some(config) = self.config.as_ref() else {
+            return;
+        };
+        let keyframe = self.last_keyframe.is_none_or(|last| {
+            (measurement.timestamp - last).num_seconds() >= config.keyframe_interval_secs as i64
+        });
+        let values = serde_json::to_value(&*measurement).unwrap_or_default();
+        let mut omitted = Vec::new();
+        for &field in FIELDS {
+            let value = values.get(field).cloned().unwrap_or(Value::Null);
+            let send = keyframe || match (config.fields.get(field), self.state.get(field)) {
+                (None, _) | (_, None) => true,
+                (Some(cadence), Some(state)) => {
+                    (cadence.every > 0 && state.skipped + 1 >= cadence.every) || changed(&state.last_sent, &value, cadence.delta)
+                }
+            };
+            if send {
+                self.state.insert(field.to_string(), FieldState { last_sent: value, skipped: 0 });
+            } else {
+                if let Some(state) = self.state.get_mut(field) {
+                    state.skipped += 1;
+                }
+                omitted.push(field.to_string());
+            }
+        }
+        if keyframe {
+            self.last_keyframe = Some(measurement.timestamp);
+            self.keyframes += 1;
+        }
+        self.omitted_values += omitted.len() as u64;
+        measurement.keyframe = Some(keyframe);
+        measurement.omitted = omitted;
+    }
+
+    /// Active cadences and counters since boot, for the reported shadow.
+    pub fn report(&self) -> Value {
+        json!({
+            "config": self.config,
+            "keyframes": self.keyframes,
+            "omitted_values": self.omitted_values,
+            "last_keyframe": self.last_keyframe,
+        })
+    }
+}
+
+// Numbers change once they move by `delta`; without one only the every-Nth cadence sends them.
+// Anything else, including a value appearing or disappearing, changes on any difference.
+fn changed(last_sent: &Value, value: &Value, delta: Option<f64>) -> bool {
+    match (last_sent.as_f64(), value.as_f64()) {
+        (Some(last), Some(current)) => delta.is_some_and(|delta| (current - last).abs() >= delta),
+        _ => last_sent != value,
+    }
+}
+
+/// Drops each measurement's omitted fields from an ingest payload built from `measurements`.
+pub fn strip_omitted(measurements: &[Measurement], payload: &mut Value) {
+    if let Some(Value::Array(rows)) = payload.get_mut("measurements") {
+        for (row, measurement) in rows.iter_mut().zip(measurements) {
+            if let Value::Object(fields) = row {
+                fields.retain(|name, _| !measurement.omitted.contains(name));
+            }
+        }
+    }
+}
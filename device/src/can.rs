@@ -0,0 +1,243 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::str::FromStr;
+
+/// One signal of a simplified DBC definition. The line
+///
+/// ```text
+/// SG_ EngineSpeed : 16 (0.25,0) [0|8000] "rpm" 10
+/// ```
+///
+/// is a 16-bit unsigned raw value decoded as `raw * 0.25 + 0` rpm, plausible between 0 and
+/// 8000, sent every 10 ms.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signal {
+    pub name: String,
+    pub bits: u32, // Width of the raw value, 1 to 64
+    pub scale: f64,
+    pub offset: f64,
+    pub min: f64, // Engineering units
+    pub max: f64,
+    pub unit: String,
+    pub cycle_ms: u64,
+}
+
+impl Signal {
+    fn raw_max(&self) -> u64 {
+        u64::MAX >> (64 - self.bits)
+    }
+
+    /// Raw frame value for an engineering value, rounded to the signal's resolution and
+    /// clamped to what the raw width can carry.
+    pub fn encode(&self, value: f64) -> u64 {
+        let raw = ((value - self.offset) / self.scale).round();
+        raw.clamp(0.0, self.raw_max() as f64) as u64
+    }
+
+    pub fn decode(&self, raw: u64) -> f64 {
+        raw as f64 * self.scale + self.offset
+    }
+
+    /// The raw value in Intel (little-endian) byte order, as many bytes as its width needs.
+    pub fn frame_bytes(&self, raw: u64) -> Vec<u8> {
+        raw.to_le_bytes()[..self.bits.div_ceil(8) as usize].to_vec()
+    }
+}
+
+/// The signals a simulated vehicle puts on its bus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignalSet {
+    pub signals: Vec<Signal>,
+}
+
+impl SignalSet {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid signal definition {}", path.display()))
+    }
+
+    /// Reads `SG_` lines. Blank lines, `#` comments and the `VERSION` and `BO_` lines of
+    /// a full DBC file are skipped, so a DBC reduced to this signal syntax still loads.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut signals: Vec<Signal> = Vec::new();
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("VERSION") || line.starts_with("BO_ ") {
+                continue;
+            }
+            let Some(rest) = line.strip_prefix("SG_ ") else {
+                bail!("line {}: expected an SG_ signal, found {:?}", index + 1, line);
+            };
+            let signal = parse_signal(rest).with_context(|| format!("line {}", index + 1))?;
+            if signals.iter().any(|existing| existing.name == signal.name) {
+                bail!("line {}: signal {} is defined twice", index + 1, signal.name);
+            }
+            signals.push(signal);
+        }
+        if signals.is_empty() {
+            bail!("no SG_ signals defined");
+        }
+        Ok(SignalSet { signals })
+    }
+}
+
+fn parse_signal(text: &str) -> Result<Signal> {
+    let (name, rest) = text.split_once(':').context("expected `name : bits`")?;
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        bail!("invalid signal name {:?}", name);
+    }
+    let rest = rest.trim_start();
+    let bits_end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    let bits: u32 = number(&rest[..bits_end], "raw width")?;
+    let (factors, rest) = enclosed(&rest[bits_end..], '(', ')')?;
+    let (scale, offset) = factors.split_once(',').context("expected (scale,offset)")?;
+    let (range, rest) = enclosed(rest, '[', ']')?;
+    let (min, max) = range.split_once('|').context("expected [min|max]")?;
+    let (unit, rest) = enclosed(rest, '"', '"')?;
+    let signal = Signal {
+        name: name.to_string(),
+        bits,
+        scale: number(scale, "scale")?,
+        offset: number(offset, "offset")?,
+        min: number(min, "minimum")?,
+        max: number(max, "maximum")?,
+        unit: unit.to_string(),
+        cycle_ms: number(rest, "cycle time")?,
+    };
+
+    if !(1..=64).contains(&signal.bits) {
+        bail!("{}: raw width must be 1 to 64 bits, got {}", signal.name, signal.bits);
+    }
+    if !(signal.scale.is_finite() && signal.scale > 0.0 && signal.offset.is_finite()) {
+        bail!("{}: scale must be positive and offset finite", signal.name);
+    }
+    if !(signal.min.is_finite() && signal.max.is_finite()) || signal.min >= signal.max {
+        bail!("{}: minimum {} must be below maximum {}", signal.name, signal.min, signal.max);
+    }
+    let half_step = signal.scale / 2.0;
+    if signal.min < signal.decode(0) - half_step || signal.max > signal.decode(signal.raw_max()) + half_step {
+        bail!("{}: [{}|{}] does not fit {} raw bits at scale {} and offset {}", signal.name, signal.min, signal.max, signal.bits, signal.scale, signal.offset);
+    }
+    if signal.cycle_ms == 0 {
+        bail!("{}: cycle time must be at least 1 ms", signal.name);
+    }
+    Ok(signal)
+}
+
+// `(inner) rest` after optional leading whitespace
+fn enclosed(text: &str, open: char, close: char) -> Result<(&str, &str)> {
+    let inner = text.trim_start().strip_prefix(open).with_context(|| format!("expected '{}'", open))?;
+    inner.split_once(close).with_context(|| format!("expected a closing '{}'", close))
+}
+
+fn number<T: FromStr>(raw: &str, what: &str) -> Result<T>
+where
+    T::Err: Display,
+{
+    raw.trim().parse().map_err(|e| anyhow!("invalid {} {:?}: {}", what, raw.trim(), e))
+}
+
+/// Frames a signal sends at most per sample, so a long gap between samples does not
+/// simulate hours of traffic at once.
+pub const MAX_FRAMES_PER_SAMPLE: i64 = 10_000;
+
+#[derive(Debug, Clone)]
+struct SignalState {
+    signal: Signal,
+    value: f64, // Engineering value the random walk is at
+    last_raw: u64,
+    next_frame: Option<DateTime<Utc>>, // None before the first sample
+}
+
+/// Where one signal's simulation is, for fleet snapshots.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CanSignalState {
+    pub name: String,
+    pub value: f64,
+    pub last_raw: u64,
+    pub next_frame: Option<DateTime<Utc>>,
+}
+
+/// Simulated bus traffic for a signal set. Every signal sends a frame each cycle time,
+/// on its own schedule; a sample summarizes the frames sent since the previous one.
+#[derive(Debug, Clone)]
+pub struct CanBus {
+    signals: Vec<SignalState>,
+    raw_frames: bool, // Also report the hex bytes of each signal's last frame
+}
+
+impl CanBus {
+    pub fn new(set: SignalSet, raw_frames: bool) -> Self {
+        let signals = set.signals.into_iter()
+            .map(|signal| SignalState { value: (signal.min + signal.max) / 2.0, last_raw: 0, next_frame: None, signal })
+            .collect();
+        CanBus { signals, raw_frames }
+    }
+
+    pub fn state(&self) -> Vec<CanSignalState> {
+        self.signals.iter()
+            .map(|state| CanSignalState { name: state.signal.name.clone(), value: state.value, last_raw: state.last_raw, next_frame: state.next_frame })
+            .collect()
+    }
+
+    /// Continues each signal from a snapshot taken `shift` ago. Signals the snapshot does
+    /// not know, because the definitions changed, start over.
+    pub fn restore(&mut self, saved: &[CanSignalState], shift: Duration) {
+        for state in &mut self.signals {
+            if let Some(saved) = saved.iter().find(|saved| saved.name == state.signal.name) {
+                (state.value, state.last_raw) = (saved.value, saved.last_raw);
+                state.next_frame = saved.next_frame.map(|at| at + shift);
+            }
+        }
+    }
+
+    /// Sends every frame due up to `now` and returns the decoded values for a
+    /// measurement's extra map. A signal that sent at most one frame reports its latest
+    /// value, as a receiver holding the last frame would; one that sent several reports
+    /// `{last, min, max, frames}`. With raw frames on, `<name>_raw` carries the last frame.
+    /// Signal values wander by draws from `rng`.
+    pub fn sample(&mut self, now: DateTime<Utc>, rng: &mut impl Rng) -> BTreeMap<String, Value> {
+        let mut values = BTreeMap::new();
+        for state in &mut self.signals {
+            let cycle = Duration::milliseconds(state.signal.cycle_ms as i64);
+            let mut next = state.next_frame.unwrap_or(now);
+            let due = if next > now { 0 } else { (now - next).num_milliseconds() / cycle.num_milliseconds() + 1 };
+            if due > MAX_FRAMES_PER_SAMPLE {
+                next = now - cycle * (MAX_FRAMES_PER_SAMPLE - 1) as i32;
+            }
+
+            let mut summary: Option<(f64, f64, u64)> = None; // min, max, frames
+            while next <= now {
+                let span = state.signal.max - state.signal.min;
+                state.value = (state.value + (rng.gen::<f64>() * 2.0 - 1.0) * span * 0.01).clamp(state.signal.min, state.signal.max);
+                state.last_raw = state.signal.encode(state.value);
+                let decoded = state.signal.decode(state.last_raw);
+                summary = Some(match summary {
+                    None => (decoded, decoded, 1),
+                    Some((min, max, frames)) => (min.min(decoded), max.max(decoded), frames + 1),
+                });
+                next += cycle;
+            }
+            state.next_frame = Some(next);
+
+            let last = state.signal.decode(state.last_raw);
+            let value = match summary {
+                Some((min, max, frames)) if frames > 1 => json!({"last": last, "min": min, "max": max, "frames": frames}),
+                _ => json!(last),
+            };
+            values.insert(state.signal.name.clone(), value);
+            if self.raw_frames {
+                let hex: String = state.signal.frame_bytes(state.last_raw).iter().map(|byte| format!("{:02x}", byte)).collect();
+                values.insert(format!("{}_raw", state.signal.name), json!(hex));
+            }
+        }
+        values
+    }
+}
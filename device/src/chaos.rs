@@ -0,0 +1,71 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::corruption::CorruptionMode;
+use crate::snapshot;
+use crate::types::{ChaosFlags, ChaosTarget};
+
+/// Makes the device's random chaos decisions from its flags. It owns the generator they
+/// are drawn from, so a snapshot can capture it and a restored device decides the same.
+#[derive(Debug, Clone)]
+pub struct ChaosEngine {
+    rng: StdRng,
+}
+
+impl ChaosEngine {
+    /// Draws from `seed`, or from entropy without one.
+    pub fn new(seed: Option<u64>) -> Self {
+        let rng = seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+        ChaosEngine { rng }
+    }
+
+    /// Whether to fail this run of `target`. Only draws while random_error covers it.
+    pub fn inject_error(&mut self, flags: Option<&ChaosFlags>, target: ChaosTarget) -> bool {
+        let Some(probability) = flags.and_then(|flags| flags.error_probability_for(target)) else {
+            return false;
+        };
+        // Parsed flags are in range; anything else injects nothing rather than panicking
+        (0.0..=1.0).contains(&probability) && self.rng.gen_bool(probability)
+    }
+
+    /// Whether to send an upload batch a second time. Only draws while duplicate_probability is set.
+    pub fn duplicate_batch(&mut self, flags: Option<&ChaosFlags>) -> bool {
+        let probability = flags.map_or(0.0, |flags| flags.duplicate_probability);
+        probability > 0.0 && probability <= 1.0 && self.rng.gen_bool(probability)
+    }
+
+    /// Order to send a batch of `len` measurements in while shuffle_batch is on, as
+    /// positions in the batch; None otherwise.
+    pub fn batch_order(&mut self, flags: Option<&ChaosFlags>, len: usize) -> Option<Vec<usize>> {
+        if !flags.is_some_and(|flags| flags.shuffle_batch) {
+            return None;
+        }
+        let mut order: Vec<usize> = (0..len).collect();
+        order.shuffle(&mut self.rng);
+        Some(order)
+    }
+
+    /// How to mangle an upload batch while corrupt_payload is set, with a random mode
+    /// already drawn; None to send it intact. Only draws while the flag is set.
+    pub fn corrupt_payload(&mut self, flags: Option<&ChaosFlags>) -> Option<CorruptionMode> {
+        let corrupt = flags.and_then(|flags| flags.corrupt_payload.as_ref())?;
+        let corrupted = corrupt.probability > 0.0 && corrupt.probability <= 1.0 && self.rng.gen_bool(corrupt.probability);
+        corrupted.then(|| corrupt.mode.resolve(&mut self.rng))
+    }
+
+    /// Whether the device crashes within the `elapsed_secs` since the last check. Only
+    /// draws while crash is set.
+    pub fn crash(&mut self, flags: Option<&ChaosFlags>, elapsed_secs: f64) -> bool {
+        let Some(crash) = flags.and_then(|flags| flags.crash.as_ref()) else {
+            return false;
+        };
+        let probability = crash.probability(elapsed_secs);
+        probability > 0.0 && probability <= 1.0 && self.rng.gen_bool(probability)
+    }
+
+    /// Seed the engine continues from, recorded in a snapshot; see snapshot::reseed.
+    pub fn reseed(&mut self) -> u64 {
+        snapshot::reseed(&mut self.rng)
+    }
+}
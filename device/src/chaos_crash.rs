@@ -0,0 +1,44 @@
+use serde::{Deserialize, Serialize};
+
+/// Exit code of a lone device that crashes on the `crash` flag; Docker's restart policy
+/// starts it again.
+pub const EXIT_CODE: i32 = 70;
+
+/// The `crash` chaos flag, e.g. `{"probability_per_hour": 0.5}` or
+/// `{"mean_uptime_secs": 600, "dirty": true}`: the device exits at a random moment, as a
+/// crashing process would. A clean crash first saves what a shutdown saves; a dirty one
+/// saves nothing, not even the crash count, to exercise the recovery paths: the process
+/// exits on the spot. Fleet members share one process, so they always crash clean.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ChaosCrash {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probability_per_hour: Option<f64>, // Chance of crashing within any hour of uptime
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mean_uptime_secs: Option<f64>, // Average time between crashes
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dirty: bool,
+}
+
+impl ChaosCrash {
+    /// Why the flag cannot be acted on, if it cannot: exactly one of the two rates is set.
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.probability_per_hour, self.mean_uptime_secs) {
+            (Some(probability), None) if (0.0..=1.0).contains(&probability) => Ok(()),
+            (Some(probability), None) => Err(format!("probability_per_hour {} is not between 0 and 1", probability)),
+            (None, Some(mean)) if mean > 0.0 && mean.is_finite() => Ok(()),
+            (None, Some(mean)) => Err(format!("mean_uptime_secs must be a positive number, got {}", mean)),
+            _ => Err("set one of probability_per_hour and mean_uptime_secs".to_string()),
+        }
+    }
+
+    /// Chance of crashing within `elapsed_secs`; crashes arrive as a Poisson process.
+    pub fn probability(&self, elapsed_secs: f64) -> f64 {
+        let elapsed_secs = elapsed_secs.max(0.0);
+        match (self.probability_per_hour, self.mean_uptime_secs) {
+            (Some(probability), _) => 1.0 - (1.0 - probability.clamp(0.0, 1.0)).powf(elapsed_secs / 3600.0),
+            (None, Some(mean)) if mean > 0.0 => 1.0 - (-elapsed_secs / mean).exp(),
+            _ => 0.0,
+        }
+    }
+}
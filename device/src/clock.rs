@@ -0,0 +1,22 @@
+use chrono::{DateTime, Duration, Utc};
+use tokio::time::Instant;
+
+/// A device's notion of true time. It starts at the wall clock, or where a restored
+/// snapshot left off, and runs on tokio's clock from there: a restored device continues
+/// from the snapshot's instant instead of jumping to the present, and with tokio's time
+/// paused, as in tests, it moves only with the device's timers, so runs repeat exactly.
+#[derive(Debug, Clone, Copy)]
+pub struct Clock {
+    origin: DateTime<Utc>,
+    started: Instant,
+}
+
+impl Clock {
+    pub fn starting_at(origin: DateTime<Utc>) -> Self {
+        Clock { origin, started: Instant::now() }
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.origin + Duration::milliseconds(self.started.elapsed().as_millis() as i64)
+    }
+}
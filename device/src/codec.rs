@@ -0,0 +1,193 @@
+use anyhow::{bail, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+/// A Content-Encoding the device can produce and read.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[serde(alias = "identity")]
+    None,
+    Gzip,
+    Zstd,
+    #[serde(rename = "br", alias = "brotli")]
+    Brotli,
+}
+
+impl Codec {
+    /// Token used in Content-Encoding and Accept-Encoding headers.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Codec::None => "identity",
+            Codec::Gzip => "gzip",
+            Codec::Zstd => "zstd",
+            Codec::Brotli => "br",
+        }
+    }
+
+    pub fn encode(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Codec::Zstd => Ok(zstd::encode_all(data, 0)?),
+            Codec::Brotli => {
+                let mut encoded = Vec::new();
+                let mut encoder = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+                encoder.write_all(data)?;
+                drop(encoder);
+                Ok(encoded)
+            }
+        }
+    }
+
+    pub fn decode(self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        match self {
+            Codec::None => decoded.extend_from_slice(data),
+            Codec::Gzip => {
+                GzDecoder::new(data).read_to_end(&mut decoded)?;
+            }
+            Codec::Zstd => decoded = zstd::decode_all(data)?,
+            Codec::Brotli => {
+                brotli::Decompressor::new(data, 4096).read_to_end(&mut decoded)?;
+            }
+        }
+        Ok(decoded)
+    }
+}
+
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self> {
+        match raw.trim().to_ascii_lowercase().as_str() {
+            "none" | "identity" => Ok(Codec::None),
+            "gzip" | "x-gzip" => Ok(Codec::Gzip),
+            "zstd" => Ok(Codec::Zstd),
+            "br" | "brotli" => Ok(Codec::Brotli),
+            other => bail!("Unknown content encoding {:?}", other),
+        }
+    }
+}
+
+/// Codec for one class of request: a fixed codec, or `auto` for the one negotiated at registration.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CodecSetting {
+    Auto,
+    #[serde(untagged)]
+    Fixed(Codec),
+}
+
+impl CodecSetting {
+    pub fn resolve(self, negotiated: Option<Codec>) -> Codec {
+        match self {
+            CodecSetting::Auto => negotiated.unwrap_or(FALLBACK),
+            CodecSetting::Fixed(codec) => codec,
+        }
+    }
+}
+
+/// Used when nothing was negotiated, e.g. the backend advertised no encodings. Every
+/// backend this device has talked to accepts gzip uploads.
+pub const FALLBACK: Codec = Codec::Gzip;
+
+/// Request compression per endpoint class.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CompressionConfig {
+    #[serde(default = "default_ingest")]
+    pub ingest: CodecSetting,
+    #[serde(default = "default_small")]
+    pub heartbeat: CodecSetting,
+    #[serde(default = "default_small")]
+    pub shadow: CodecSetting, // Shadow reports
+    #[serde(default = "default_min_bytes")]
+    pub min_bytes: usize, // Bodies smaller than this are sent uncompressed
+    #[serde(default = "default_preference")]
+    pub preference: Vec<Codec>, // Best first; decides the negotiated codec
+}
+
+fn default_ingest() -> CodecSetting {
+    CodecSetting::Auto
+}
+
+fn default_small() -> CodecSetting {
+    CodecSetting::Fixed(Codec::None)
+}
+
+fn default_min_bytes() -> usize {
+    512
+}
+
+fn default_preference() -> Vec<Codec> {
+    vec![Codec::Zstd, Codec::Brotli, Codec::Gzip]
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            ingest: default_ingest(),
+            heartbeat: default_small(),
+            shadow: default_small(),
+            min_bytes: default_min_bytes(),
+            preference: default_preference(),
+        }
+    }
+}
+
+/// Classes of request body that can be compressed differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EndpointClass {
+    Ingest,
+    Heartbeat,
+    Shadow,
+}
+
+impl CompressionConfig {
+    pub fn setting(&self, class: EndpointClass) -> CodecSetting {
+        match class {
+            EndpointClass::Ingest => self.ingest,
+            EndpointClass::Heartbeat => self.heartbeat,
+            EndpointClass::Shadow => self.shadow,
+        }
+    }
+
+    /// Accept-Encoding value listing the device's codecs, best first.
+    pub fn accept_encoding(&self) -> String {
+        let mut tokens: Vec<&str> = self.preference.iter().filter(|codec| **codec != Codec::None).map(|codec| codec.as_str()).collect();
+        tokens.push(Codec::None.as_str());
+        tokens.dedup();
+        tokens.join(", ")
+    }
+}
+
+/// Picks the device's most preferred codec among those the backend advertises. Unknown
+/// tokens are ignored. None when the backend advertises nothing, so the fallback applies.
+pub fn negotiate(preference: &[Codec], advertised: &[String]) -> Option<Codec> {
+    let advertised: Vec<Codec> = advertised.iter().filter_map(|token| token.parse().ok()).collect();
+    if advertised.is_empty() {
+        return None;
+    }
+    Some(preference.iter().copied().find(|codec| advertised.contains(codec)).unwrap_or(Codec::None))
+}
+
+/// Encodes a body with `codec` unless it is below `min_bytes` or would not shrink.
+/// Returns the codec actually applied alongside the bytes to send.
+pub fn encode_body(codec: Codec, min_bytes: usize, body: Vec<u8>) -> Result<(Codec, Vec<u8>)> {
+    if codec == Codec::None || body.len() < min_bytes {
+        return Ok((Codec::None, body));
+    }
+    let encoded = codec.encode(&body)?;
+    if encoded.len() >= body.len() {
+        return Ok((Codec::None, body));
+    }
+    Ok((codec, encoded))
+}
@@ -1,86 +1,1061 @@
-use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::env;
 use std::fs;
-use std::io::{self, Write};
+use std::io::Write;
 use uuid::Uuid;
-use serde_json::Value; // Import Value for chaos_flags
-use std::path::PathBuf; // Import PathBuf
+use std::path::{Path, PathBuf}; // Import PathBuf
+use std::collections::{BTreeMap, HashMap};
+use tracing::{info, warn};
+
+use crate::anomaly::SelfDetectionConfig;
+use crate::auth::Session;
+use crate::baseline::BaselineOverrides;
+use crate::cadence::CadenceConfig;
+use crate::geo::GeoBucketConfig;
+use crate::geofence::Geofence;
+use crate::codec::{Codec, CompressionConfig};
+use crate::cost::CostConfig;
+use crate::debug_session::DebugSession;
+use crate::degradation::DegradationConfig;
+use crate::external::{self, Backpressure, ExternalSourceConfig};
+use crate::features::FeatureValue;
+use crate::firmware::FirmwareBehavior;
+use crate::maintenance::MaintenanceState;
+use crate::offline::OfflineWindow;
+use crate::network::{NetworkProfile, NetworkType, RoamingConfig};
+use crate::push::KeepaliveConfig;
+use crate::reefer::ReeferConfig;
+use crate::residency::RegionDrainPolicy;
+use crate::response_capture::CaptureConfig;
+use crate::route::Waypoint;
+use crate::shadow_report::ShadowRejectionPolicy;
+use crate::shed::{ShedConfig, ShedPolicy};
+use crate::simulate::SensorProfile;
+use crate::storage;
+use crate::txn::TxnOutcome;
+use crate::types::ChaosFlags;
+use crate::validation;
+
+pub const CONFIG_FILE: &str = "device_config.json";
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Config {
     pub device_id: String,
+    #[serde(default)]
+    pub device_name: Option<String>, // Friendly label; see naming::display_name
     pub auth_token: Option<String>,
     pub backend_url: String,
     pub sample_interval_secs: u64,
+    #[serde(default = "default_min_sample_interval_secs")]
+    pub min_sample_interval_secs: u64, // Lower bound for backend-suggested sample intervals
+    #[serde(default = "default_max_sample_interval_secs")]
+    pub max_sample_interval_secs: u64, // Upper bound for backend-suggested sample intervals
     pub upload_interval_secs: u64,
+    #[serde(default = "default_upload_batch_size")]
+    pub upload_batch_size: u32, // Maximum measurements per ingest request
+    #[serde(default = "default_upload_max_in_flight")]
+    pub upload_max_in_flight: u32, // Concurrent batches when draining a large backlog
+    #[serde(default = "default_upload_drain_threshold")]
+    pub upload_drain_threshold: u64, // Backlog above which concurrent drains start
+    #[serde(default = "default_max_stored_measurements")]
+    pub max_stored_measurements: u64, // Oldest rows are evicted past this; 0 for no cap
+    #[serde(default)]
+    pub max_storage_age_secs: u64, // Rows taken longer ago than this are evicted, checked each minute; 0 for no limit
+    #[serde(default = "default_storage_cache_kib")]
+    pub storage_cache_kib: u64, // Memory budget for the local database's page cache; 0 keeps SQLite's default
+    #[serde(default = "default_storage_busy_timeout_ms")]
+    pub storage_busy_timeout_ms: u64, // How long a database statement waits on a lock before failing
+    #[serde(default = "default_data_dir")]
+    pub data_dir: PathBuf, // Holds everything the device writes but its config; created at startup
+    #[serde(default = "default_compress_uploads")]
+    pub compress_uploads: bool, // Compress ingest bodies; turn off for backends that cannot decompress them
+    #[serde(default)]
+    pub compression: CompressionConfig, // Request codec per endpoint class
+    #[serde(default)]
+    pub negotiated_encoding: Option<Codec>, // Best codec both sides support, chosen at registration
+    #[serde(default)]
+    pub reconnect_replay_secs: u64, // Recent measurements re-sent after an outage; 0 turns replay off
+    #[serde(default = "default_reconnect_replay_max_rows")]
+    pub reconnect_replay_max_rows: u32,
     pub heartbeat_interval_secs: u64,
+    #[serde(default = "default_retry_max_attempts")]
+    pub retry_max_attempts: u32, // Attempts per backend request, including the first
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub retry_base_delay_ms: u64, // First backoff delay; doubles with each retry
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64, // Bound on the final upload and offline report when stopping
+    #[serde(default = "default_http_connect_timeout_ms")]
+    pub http_connect_timeout_ms: u64, // Bound on DNS and connecting to the backend; 0 waits indefinitely
+    #[serde(default = "default_http_request_timeout_secs")]
+    pub http_request_timeout_secs: u64, // Bound on a whole backend request, firmware downloads aside; 0 waits indefinitely
+    #[serde(default = "default_http_pool_idle_timeout_secs")]
+    pub http_pool_idle_timeout_secs: u64, // Idle pooled connections are closed after this; 0 keeps them
+    #[serde(default = "default_http_tcp_keepalive_secs")]
+    pub http_tcp_keepalive_secs: u64, // TCP keepalive probe interval; 0 turns keepalive off
+    #[serde(default)]
+    pub tls_client_cert_path: Option<PathBuf>, // PEM certificate presented to the backend for mutual TLS
+    #[serde(default)]
+    pub tls_client_key_path: Option<PathBuf>, // PEM private key of the client certificate
+    #[serde(default)]
+    pub tls_ca_bundle_path: Option<PathBuf>, // PEM CA certificates trusted besides the system's, for self-signed backends
+    #[serde(default)]
+    pub error_body_capture: CaptureConfig, // Bodies of failed backend responses, logged and kept for the crash snapshot
     pub ota_check_interval_secs: u64,
+    #[serde(default = "default_ota_metadata_freshness_secs")]
+    pub ota_metadata_freshness_secs: u64, // Maximum age of firmware metadata before it is rejected as stale
+    #[serde(default)]
+    pub ota_public_key: Option<String>, // Hex ed25519 key firmware images and their metadata must be signed with; unsigned ones are accepted without one
+    #[serde(default = "default_ota_trial_heartbeats")]
+    pub ota_trial_heartbeats: u32, // Successful heartbeats that confirm new firmware
+    #[serde(default = "default_ota_trial_window_secs")]
+    pub ota_trial_window_secs: u64, // Time new firmware has to send them before it is rolled back
+    #[serde(default = "default_ota_max_trial_boots")]
+    pub ota_max_trial_boots: u32, // Boots of unconfirmed firmware before it is rolled back
+    #[serde(default = "default_ota_trial_require_ingest")]
+    pub ota_trial_require_ingest: bool, // New firmware must also complete an ingest before it is confirmed
+    #[serde(default = "default_ota_apply_ms")]
+    pub ota_apply_ms: u64, // Simulated time applying an update takes, during which the device is dark
+    #[serde(default = "default_ota_apply_overdue_factor")]
+    pub ota_apply_overdue_factor: f32, // Times over the announced estimate an apply may run before a progress heartbeat
     pub region: Option<String>,
+    #[serde(default)]
+    pub region_endpoints: BTreeMap<String, String>, // Region to region-local data endpoint
+    #[serde(default)]
+    pub strict_residency: bool, // Hold data instead of falling back to backend_url for unmapped regions
+    #[serde(default)]
+    pub residency_drain: RegionDrainPolicy, // Handling of pending data when the region changes
     pub hardware_rev: Option<String>,
+    #[serde(default)]
+    pub timezone: Option<String>, // IANA name; when set, measurements also carry local time
     pub desired_shadow_state: Option<serde_json::Value>,
     pub reported_shadow_state: Option<serde_json::Value>,
-    pub chaos_flags: Option<Value>, // New field for chaos flags
+    #[serde(default, deserialize_with = "lenient_chaos_flags")]
+    pub chaos_flags: Option<ChaosFlags>, // As last adopted from the desired shadow
+    #[serde(default)]
+    pub maintenance: Option<MaintenanceState>, // Persisted so a restart restores an un-expired maintenance window
+    #[serde(default)]
+    pub offline_window: Option<OfflineWindow>, // Persisted so a restart mid-window stays offline for the rest of it
+    #[serde(default)]
+    pub debug_session: Option<DebugSession>, // Persisted so a restart resumes the session or restores what it replaced
+    #[serde(default)]
+    pub external_source: Option<ExternalSourceConfig>, // Co-simulator feed replacing the synthetic model
+    #[serde(default)]
+    pub last_config_txn: Option<TxnOutcome>, // Outcome of the most recent desired config transaction
+    #[serde(default)]
+    pub chaos_crashes: u32, // Clean crashes on the crash chaos flag, reported in heartbeats
+    #[serde(default)]
+    pub self_detection: Option<SelfDetectionConfig>, // On-device anomaly detection parameters
+    #[serde(default)]
+    pub cadence: Option<CadenceConfig>, // Per-field upload cadences; every field in every sample when unset
+    #[serde(default)]
+    pub firmware_behaviors: BTreeMap<String, FirmwareBehavior>, // Simulated behavior per firmware version
+    #[serde(default)]
+    pub features: BTreeMap<String, FeatureValue>, // Experimental behavior overrides, resolved by features::Features
+    #[serde(default)]
+    pub shed: ShedConfig, // Sample shedding when the upload backlog grows
+    #[serde(default)]
+    pub degradation: Option<DegradationConfig>, // Simulated sensor wear-out
+    #[serde(default)]
+    pub otel_endpoint: Option<String>, // OTLP/HTTP collector base URL; OTEL_* variables also apply
+    #[serde(default)]
+    pub network: Option<NetworkType>, // Simulated network the device is on; None sends requests unconditioned
+    #[serde(default)]
+    pub network_roaming: Option<RoamingConfig>,
+    #[serde(default)]
+    pub network_profiles: BTreeMap<NetworkType, NetworkProfile>, // Overrides for the bundled profiles
+    #[serde(default)]
+    pub push_keepalive: KeepaliveConfig, // Pings on the desired-state push channel
+    #[serde(default)]
+    pub cost_model: Option<CostConfig>, // Unit prices for running cost estimates
+    #[serde(default)]
+    pub sensor_profile: SensorProfile, // Simulated hardware: which fields are sampled and their ranges
+    #[serde(default)]
+    pub simulation_seed: Option<u64>, // Same seed, same readings; see simulate::device_seed
+    #[serde(default)]
+    pub can_signals: Option<String>, // DBC-like signal definition for the vehicle's simulated CAN bus, see can::SignalSet
+    #[serde(default)]
+    pub can_raw_frames: bool, // Also send each signal's last raw frame, hex encoded
+    #[serde(default)]
+    pub route_file: Option<String>, // GPX track or GeoJSON LineString a tracker drives along, see route::Route
+    #[serde(default)]
+    pub route_reverse: bool, // Turn around at the end of the route instead of starting over
+    #[serde(default)]
+    pub route_waypoints: Vec<Waypoint>, // Route given inline, followed when there is no route_file
+    #[serde(default)]
+    pub route_stop_at_end: bool, // Park at the last waypoint instead of starting over or turning around
+    #[serde(default = "default_battery_drain_rate")]
+    pub battery_drain_rate: f32, // Charge fraction each sample costs, see battery::Battery
+    #[serde(default = "default_charge_rate")]
+    pub charge_rate: f32, // Charge fraction regained per hour once the battery is on charge
+    #[serde(default)]
+    pub shadow_rejection_policy: ShadowRejectionPolicy, // Reaction to reported keys the backend refuses
+    #[serde(default)]
+    pub health_port: Option<u16>, // Local port of the /healthz and /config endpoints; fleet members add their index
+    #[serde(default)]
+    pub geo_buckets: Option<GeoBucketConfig>, // Geohash bucketing of GPS fixes; off when unset
+    #[serde(default)]
+    pub geofence: Option<Geofence>, // Area the asset may not leave without a breach event; off when unset
+    #[serde(default)]
+    pub reefer: Option<ReeferConfig>, // Cargo thermal model and delivery stops of the reefer_trailer profile; defaults when unset
+    #[serde(default)]
+    pub fault_baseline: BaselineOverrides, // Rates replacing the profile's background faults, see baseline::FaultBaseline
+    #[serde(default)]
+    pub background_faults: bool, // Simulate the profile's background faults; off, so devices fail only when asked to
+    #[serde(default)]
+    pub combined_sync: bool, // Heartbeats also bring the shadow back, see net::sync; no separate shadow fetches
+    #[serde(default = "default_interval_jitter")]
+    pub interval_jitter: f32, // Share of each timer period it may randomly differ by, see jitter::jittered
+    #[serde(skip)]
+    pub session: Session, // Credentials refreshed after the backend rejected a token
+}
+
+/// Context on an error caused by a setting the device cannot start with, so it is
+/// reported as error::DeviceError::Config.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("invalid {0}")]
+pub struct InvalidSetting(pub &'static str);
+
+/// Why the device has no config to run with.
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    /// A setting marked InvalidSetting, and what was wrong with it.
+    #[error("{setting}")]
+    Invalid { setting: InvalidSetting, #[source] source: Option<Box<dyn std::error::Error + Send + Sync>> },
+    #[error("failed to read {}", .path.display())]
+    Read { path: PathBuf, #[source] source: std::io::Error },
+    #[error("invalid config file {}", .path.display())]
+    Parse { path: PathBuf, #[source] source: serde_json::Error },
+    #[error("failed to serialize the config")]
+    Serialize(#[source] serde_json::Error),
+    #[error("failed to write {}", .path.display())]
+    Write { path: PathBuf, #[source] source: std::io::Error },
 }
 
 impl Config {
-    pub fn from_env() -> Result<Self> {
-        let device_id = env::var("DEVICE_ID").unwrap_or_else(|_| Uuid::new_v4().to_string());
-        let auth_token = env::var("AUTH_TOKEN").ok();
-        let backend_url = env::var("BACKEND_URL").unwrap_or_else(|_| "http://localhost:8000".to_string());
-        
-        let sample_interval_secs = get_env_var_u64("SAMPLE_INTERVAL_SECS", 10);
-        let upload_interval_secs = get_env_var_u64("UPLOAD_INTERVAL_SECS", 60);
-        let heartbeat_interval_secs = get_env_var_u64("HEARTBEAT_INTERVAL_SECS", 30);
-        let ota_check_interval_secs = get_env_var_u64("OTA_CHECK_INTERVAL_SECS", 300);
-
-        let region = env::var("REGION").ok();
-        let hardware_rev = env::var("HARDWARE_REV").ok();
-
-        Ok(Config {
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let (config, report) = Self::from_env_vars(&env::vars().collect());
+        if strict_mode_enabled() {
+            report.log();
+        }
+        Ok(config)
+    }
+
+    // Builds a config from the given environment, recording where each value came from
+    pub fn from_env_vars(vars: &HashMap<String, String>) -> (Self, ConfigReport) {
+        let mut env = EnvReader { vars, report: ConfigReport::default() };
+
+        let device_id = env.string_or_else("DEVICE_ID", || Uuid::new_v4().to_string());
+        let device_name = env.optional_string("DEVICE_NAME");
+        let auth_token = env.optional_string("AUTH_TOKEN");
+        let backend_url = env.string_or_else("BACKEND_URL", || "http://localhost:8000".to_string());
+
+        let sample_interval_secs = env.u64("SAMPLE_INTERVAL_SECS", 10);
+        let min_sample_interval_secs = env.u64("MIN_SAMPLE_INTERVAL_SECS", default_min_sample_interval_secs());
+        let max_sample_interval_secs = env.u64("MAX_SAMPLE_INTERVAL_SECS", default_max_sample_interval_secs());
+        let upload_interval_secs = env.u64("UPLOAD_INTERVAL_SECS", 60);
+        let upload_batch_size = env.u64("UPLOAD_BATCH_SIZE", default_upload_batch_size() as u64) as u32;
+        let upload_max_in_flight = env.u64("UPLOAD_MAX_IN_FLIGHT", default_upload_max_in_flight() as u64) as u32;
+        let upload_drain_threshold = env.u64("UPLOAD_DRAIN_THRESHOLD", default_upload_drain_threshold());
+        let max_stored_measurements = env.u64("MAX_STORED_MEASUREMENTS", default_max_stored_measurements());
+        let max_storage_age_secs = env.u64("MAX_STORAGE_AGE_SECS", 0);
+        let storage_cache_kib = env.u64("STORAGE_CACHE_KIB", default_storage_cache_kib());
+        let storage_busy_timeout_ms = env.u64("STORAGE_BUSY_TIMEOUT_MS", default_storage_busy_timeout_ms());
+        let data_dir = env.optional_string("DATA_DIR").map_or_else(default_data_dir, PathBuf::from);
+        let compress_uploads = env.bool("COMPRESS_UPLOADS", default_compress_uploads());
+        let compression = env.compression();
+        let cadence = env.cadence();
+        let reconnect_replay_secs = env.u64("RECONNECT_REPLAY_SECS", 0);
+        let reconnect_replay_max_rows = env.u64("RECONNECT_REPLAY_MAX_ROWS", default_reconnect_replay_max_rows() as u64) as u32;
+        let heartbeat_interval_secs = env.u64("HEARTBEAT_INTERVAL_SECS", 30);
+        let retry_max_attempts = env.u64("RETRY_MAX_ATTEMPTS", default_retry_max_attempts() as u64) as u32;
+        let retry_base_delay_ms = env.u64("RETRY_BASE_DELAY_MS", default_retry_base_delay_ms());
+        let shutdown_timeout_secs = env.u64("SHUTDOWN_TIMEOUT_SECS", default_shutdown_timeout_secs());
+        let http_connect_timeout_ms = env.u64("HTTP_CONNECT_TIMEOUT_MS", default_http_connect_timeout_ms());
+        let http_request_timeout_secs = env.u64("HTTP_REQUEST_TIMEOUT_SECS", default_http_request_timeout_secs());
+        let http_pool_idle_timeout_secs = env.u64("HTTP_POOL_IDLE_TIMEOUT_SECS", default_http_pool_idle_timeout_secs());
+        let http_tcp_keepalive_secs = env.u64("HTTP_TCP_KEEPALIVE_SECS", default_http_tcp_keepalive_secs());
+        let tls_client_cert_path = env.optional_string("TLS_CLIENT_CERT_PATH").map(PathBuf::from);
+        let tls_client_key_path = env.optional_string("TLS_CLIENT_KEY_PATH").map(PathBuf::from);
+        let tls_ca_bundle_path = env.optional_string("TLS_CA_BUNDLE_PATH").map(PathBuf::from);
+        let error_body_capture = env.error_body_capture();
+        let ota_check_interval_secs = env.u64("OTA_CHECK_INTERVAL_SECS", 300);
+        let ota_metadata_freshness_secs = env.u64("OTA_METADATA_FRESHNESS_SECS", default_ota_metadata_freshness_secs());
+        let ota_public_key = env.optional_string("OTA_PUBLIC_KEY");
+        let ota_trial_heartbeats = env.u64("OTA_TRIAL_HEARTBEATS", default_ota_trial_heartbeats() as u64) as u32;
+        let ota_trial_window_secs = env.u64("OTA_TRIAL_WINDOW_SECS", default_ota_trial_window_secs());
+        let ota_max_trial_boots = env.u64("OTA_MAX_TRIAL_BOOTS", default_ota_max_trial_boots() as u64) as u32;
+        let ota_trial_require_ingest = env.bool("OTA_TRIAL_REQUIRE_INGEST", default_ota_trial_require_ingest());
+        let ota_apply_ms = env.u64("OTA_APPLY_MS", default_ota_apply_ms());
+        let ota_apply_overdue_factor = env.f32("OTA_APPLY_OVERDUE_FACTOR", default_ota_apply_overdue_factor());
+
+        let region = env.optional_string("REGION");
+        let region_endpoints = env.region_endpoints();
+        let strict_residency = env.bool("STRICT_RESIDENCY", false);
+        let residency_drain = match env.optional_string("RESIDENCY_DRAIN").as_deref() {
+            Some("flush_to_old") | None => RegionDrainPolicy::FlushToOld,
+            Some("hold_for_new") => RegionDrainPolicy::HoldForNew,
+            Some(other) => {
+                env.report.warnings.push(format!("Unknown RESIDENCY_DRAIN {:?}, using flush_to_old", other));
+                RegionDrainPolicy::FlushToOld
+            }
+        };
+        let hardware_rev = env.optional_string("HARDWARE_REV");
+        let timezone = env.optional_string("TIMEZONE");
+        let external_source = env.external_source();
+        let firmware_behaviors = env.firmware_behaviors();
+        let features = env.features();
+        let shed = env.shed();
+        let degradation = env.degradation();
+        let otel_endpoint = env.optional_string("OTEL_ENDPOINT");
+        let network = env.network();
+        let network_roaming = env.network_roaming();
+        let network_profiles = env.network_profiles();
+        let push_keepalive = env.push_keepalive();
+        let cost_model = env.cost_model();
+        let sensor_profile = env.sensor_profile();
+        let simulation_seed = env.optional_u64("SIMULATION_SEED");
+        let can_signals = env.optional_string("CAN_SIGNALS");
+        let can_raw_frames = env.bool("CAN_RAW_FRAMES", false);
+        let route_file = env.optional_string("ROUTE_FILE");
+        let route_reverse = env.bool("ROUTE_REVERSE", false);
+        let route_waypoints = env.route_waypoints();
+        let route_stop_at_end = env.bool("ROUTE_STOP_AT_END", false);
+        let battery_drain_rate = env.f32("BATTERY_DRAIN_RATE", default_battery_drain_rate());
+        let charge_rate = env.f32("CHARGE_RATE", default_charge_rate());
+        let shadow_rejection_policy = env.shadow_rejection_policy();
+        let health_port = env.optional_u16("HEALTH_PORT");
+        let geo_buckets = env.geo_buckets();
+        let geofence = env.geofence();
+        let reefer = env.reefer();
+        let fault_baseline = env.fault_baseline();
+        let background_faults = env.bool("BACKGROUND_FAULTS", false);
+        let combined_sync = env.bool("COMBINED_SYNC", false);
+        let interval_jitter = env.f32("INTERVAL_JITTER", default_interval_jitter());
+
+        let mut report = env.report;
+        for key in unrecognized_env_vars(vars) {
+            report.warnings.push(format!("Unrecognized environment variable {}", key));
+        }
+
+        let config = Config {
             device_id,
+            device_name,
             auth_token,
             backend_url,
             sample_interval_secs,
+            min_sample_interval_secs,
+            max_sample_interval_secs,
             upload_interval_secs,
+            upload_batch_size,
+            upload_max_in_flight,
+            upload_drain_threshold,
+            max_stored_measurements,
+            max_storage_age_secs,
+            storage_cache_kib,
+            storage_busy_timeout_ms,
+            data_dir,
+            compress_uploads,
+            compression,
+            negotiated_encoding: None,
+            reconnect_replay_secs,
+            reconnect_replay_max_rows,
             heartbeat_interval_secs,
+            retry_max_attempts,
+            retry_base_delay_ms,
+            shutdown_timeout_secs,
+            http_connect_timeout_ms,
+            http_request_timeout_secs,
+            http_pool_idle_timeout_secs,
+            http_tcp_keepalive_secs,
+            tls_client_cert_path,
+            tls_client_key_path,
+            tls_ca_bundle_path,
+            error_body_capture,
             ota_check_interval_secs,
+            ota_metadata_freshness_secs,
+            ota_public_key,
+            ota_trial_heartbeats,
+            ota_trial_window_secs,
+            ota_max_trial_boots,
+            ota_trial_require_ingest,
+            ota_apply_ms,
+            ota_apply_overdue_factor,
             region,
+            region_endpoints,
+            strict_residency,
+            residency_drain,
             hardware_rev,
+            timezone,
             desired_shadow_state: None, // Initialize to None
             reported_shadow_state: None, // Initialize to None
             chaos_flags: None, // Initialize chaos_flags to None
-        })
+            maintenance: None,
+            offline_window: None,
+            debug_session: None,
+            external_source,
+            last_config_txn: None,
+            chaos_crashes: 0,
+            self_detection: None,
+            cadence,
+            firmware_behaviors,
+            features,
+            shed,
+            degradation,
+            otel_endpoint,
+            network,
+            network_roaming,
+            network_profiles,
+            push_keepalive,
+            cost_model,
+            sensor_profile,
+            simulation_seed,
+            can_signals,
+            can_raw_frames,
+            route_file,
+            route_reverse,
+            route_waypoints,
+            route_stop_at_end,
+            battery_drain_rate,
+            charge_rate,
+            shadow_rejection_policy,
+            health_port,
+            geo_buckets,
+            geofence,
+            reefer,
+            fault_baseline,
+            background_faults,
+            combined_sync,
+            interval_jitter,
+            session: Session::default(),
+        };
+        (config, report)
     }
 
-    fn get_config_file_path() -> PathBuf {
-        let config_dir = env::var("CONFIG_DIR").unwrap_or_else(|_| ".".to_string());
-        PathBuf::from(config_dir).join("device_config.json")
+    /// Everything wrong with this config, as the problems validation::check_config
+    /// finds: intervals, URLs, paths that must be readable or writable, and so on.
+    pub fn validate(&self) -> validation::Report {
+        validation::check_config(self)
     }
 
-    pub fn load_from_file() -> Result<Self> {
-        let config_file_path = Self::get_config_file_path();
-        let contents = fs::read_to_string(&config_file_path)?;
-        let config: Config = serde_json::from_str(&contents)?;
-        Ok(config)
+    pub fn load_from(config_file_path: &Path) -> Result<Self, ConfigError> {
+        let path = || config_file_path.to_path_buf();
+        let contents = fs::read_to_string(config_file_path).map_err(|source| ConfigError::Read { path: path(), source })?;
+        serde_json::from_str(&contents).map_err(|source| ConfigError::Parse { path: path(), source })
     }
 
-    pub fn save_to_file(&self) -> Result<()> {
-        let config_file_path = Self::get_config_file_path();
+    pub fn save_to(&self, config_file_path: &Path) -> Result<(), ConfigError> {
+        let written = |source| ConfigError::Write { path: config_file_path.to_path_buf(), source };
         // Ensure the directory exists
         if let Some(parent) = config_file_path.parent() {
-            fs::create_dir_all(parent)?;
+            fs::create_dir_all(parent).map_err(written)?;
+        }
+        // Credentials refreshed since loading replace the ones the config was loaded with
+        let contents = match self.session.refreshed() {
+            Some(credentials) => serde_json::to_string_pretty(&Config { device_id: credentials.device_id, auth_token: Some(credentials.auth_token), ..self.clone() }),
+            None => serde_json::to_string_pretty(self),
+        }
+        .map_err(ConfigError::Serialize)?;
+        // Written beside the target and renamed over it, so a crash mid-write leaves the
+        // old config or the new one, never a truncated file
+        let staged = config_file_path.with_extension("json.tmp");
+        let mut file = fs::File::create(&staged).map_err(written)?;
+        file.write_all(contents.as_bytes()).map_err(written)?;
+        file.sync_all().map_err(written)?;
+        fs::rename(&staged, config_file_path).map_err(written)?;
+        // The rename itself is durable once the directory is
+        #[cfg(unix)]
+        {
+            let parent = config_file_path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+            fs::File::open(parent).and_then(|parent| parent.sync_all()).map_err(written)?;
         }
-        let contents = serde_json::to_string_pretty(self)?;
-        let mut file = fs::File::create(&config_file_path)?;
-        file.write_all(contents.as_bytes())?;
         Ok(())
     }
 }
 
-fn get_env_var_u64(key: &str, default: u64) -> u64 {
-    env::var(key)
-        .ok()
-        .and_then(|val| val.parse().ok())
-        .unwrap_or(default)
+// Resolves a file name inside CONFIG_DIR, where the device keeps its persisted state
+pub fn config_dir_file(file_name: &str) -> PathBuf {
+    let config_dir = env::var("CONFIG_DIR").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(config_dir).join(file_name)
+}
+
+fn default_min_sample_interval_secs() -> u64 {
+    1
+}
+
+fn default_max_sample_interval_secs() -> u64 {
+    3600
+}
+
+fn default_upload_max_in_flight() -> u32 {
+    1
+}
+
+fn default_upload_drain_threshold() -> u64 {
+    1000
+}
+
+fn default_max_stored_measurements() -> u64 {
+    100_000
+}
+
+fn default_storage_cache_kib() -> u64 {
+    storage::DEFAULT_CACHE_KIB
+}
+
+fn default_storage_busy_timeout_ms() -> u64 {
+    storage::DEFAULT_BUSY_TIMEOUT_MS
+}
+
+fn default_data_dir() -> PathBuf {
+    PathBuf::from(".")
+}
+
+fn default_battery_drain_rate() -> f32 {
+    0.0005
+}
+
+fn default_charge_rate() -> f32 {
+    0.5
+}
+
+fn default_interval_jitter() -> f32 {
+    0.1
+}
+
+fn default_compress_uploads() -> bool {
+    true
+}
+
+fn default_reconnect_replay_max_rows() -> u32 {
+    100
+}
+
+fn default_retry_max_attempts() -> u32 {
+    3
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    500
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    10
+}
+
+fn default_http_connect_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_http_request_timeout_secs() -> u64 {
+    30
+}
+
+fn default_http_pool_idle_timeout_secs() -> u64 {
+    90
+}
+
+fn default_http_tcp_keepalive_secs() -> u64 {
+    60
+}
+
+fn default_ota_metadata_freshness_secs() -> u64 {
+    300
+}
+
+fn default_ota_trial_heartbeats() -> u32 {
+    1
+}
+
+fn default_ota_trial_window_secs() -> u64 {
+    600
+}
+
+fn default_ota_max_trial_boots() -> u32 {
+    3
+}
+
+fn default_ota_trial_require_ingest() -> bool {
+    true
+}
+
+fn default_ota_apply_ms() -> u64 {
+    3000
+}
+
+fn default_ota_apply_overdue_factor() -> f32 {
+    2.0
+}
+
+fn default_upload_batch_size() -> u32 {
+    100
+}
+
+// Chaos flags a config file holds in a shape this firmware cannot parse are dropped
+// rather than failing the whole load
+fn lenient_chaos_flags<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<ChaosFlags>, D::Error> {
+    let value = Option::<serde_json::Value>::deserialize(deserializer)?;
+    Ok(value.filter(|value| !value.is_null()).and_then(|value| match ChaosFlags::parse(&value) {
+        Ok(flags) => Some(flags),
+        Err(e) => {
+            warn!(error = %e, "Ignoring unparseable chaos_flags in the config file");
+            None
+        }
+    }))
+}
+
+// Environment variable names understood by from_env, also accepted with a VF_ prefix
+const KNOWN_ENV_VARS: &[&str] = &[
+    "DEVICE_ID",
+    "DEVICE_NAME",
+    "AUTH_TOKEN",
+    "BACKEND_URL",
+    "SAMPLE_INTERVAL_SECS",
+    "MIN_SAMPLE_INTERVAL_SECS",
+    "MAX_SAMPLE_INTERVAL_SECS",
+    "UPLOAD_INTERVAL_SECS",
+    "UPLOAD_BATCH_SIZE",
+    "UPLOAD_MAX_IN_FLIGHT",
+    "UPLOAD_DRAIN_THRESHOLD",
+    "MAX_STORED_MEASUREMENTS",
+    "MAX_STORAGE_AGE_SECS",
+    "STORAGE_CACHE_KIB",
+    "STORAGE_BUSY_TIMEOUT_MS",
+    "DATA_DIR",
+    "COMPRESS_UPLOADS",
+    "COMPRESSION",
+    "CADENCE",
+    "RECONNECT_REPLAY_SECS",
+    "RECONNECT_REPLAY_MAX_ROWS",
+    "HEARTBEAT_INTERVAL_SECS",
+    "RETRY_MAX_ATTEMPTS",
+    "RETRY_BASE_DELAY_MS",
+    "SHUTDOWN_TIMEOUT_SECS",
+    "HTTP_CONNECT_TIMEOUT_MS",
+    "HTTP_REQUEST_TIMEOUT_SECS",
+    "HTTP_POOL_IDLE_TIMEOUT_SECS",
+    "HTTP_TCP_KEEPALIVE_SECS",
+    "TLS_CLIENT_CERT_PATH",
+    "TLS_CLIENT_KEY_PATH",
+    "TLS_CA_BUNDLE_PATH",
+    "ERROR_BODY_LIMIT_BYTES",
+    "ERROR_BODY_REDACT_PATTERNS",
+    "OTA_CHECK_INTERVAL_SECS",
+    "OTA_METADATA_FRESHNESS_SECS",
+    "OTA_PUBLIC_KEY",
+    "OTA_TRIAL_HEARTBEATS",
+    "OTA_TRIAL_WINDOW_SECS",
+    "OTA_MAX_TRIAL_BOOTS",
+    "OTA_TRIAL_REQUIRE_INGEST",
+    "OTA_APPLY_MS",
+    "OTA_APPLY_OVERDUE_FACTOR",
+    "REGION",
+    "REGION_ENDPOINTS",
+    "STRICT_RESIDENCY",
+    "RESIDENCY_DRAIN",
+    "HARDWARE_REV",
+    "TIMEZONE",
+    "EXTERNAL_SOURCE",
+    "EXTERNAL_STALL_TIMEOUT_SECS",
+    "EXTERNAL_BACKPRESSURE",
+    "EXTERNAL_FIELD_MAP",
+    "FIRMWARE_BEHAVIORS",
+    "FEATURES",
+    "SHED_POLICY",
+    "SHED_HIGH_WATER",
+    "SHED_LOW_WATER",
+    "DEGRADATION",
+    "OTEL_ENDPOINT",
+    "NETWORK",
+    "NETWORK_ROAMING",
+    "NETWORK_PROFILES",
+    "PUSH_PING_INTERVAL_SECS",
+    "PUSH_PONG_TIMEOUT_SECS",
+    "COST_MODEL",
+    "SENSOR_PROFILE",
+    "SIMULATION_SEED",
+    "CAN_SIGNALS",
+    "CAN_RAW_FRAMES",
+    "ROUTE_FILE",
+    "ROUTE_REVERSE",
+    "ROUTE_WAYPOINTS",
+    "ROUTE_STOP_AT_END",
+    "BATTERY_DRAIN_RATE",
+    "CHARGE_RATE",
+    "SHADOW_REJECTION_POLICY",
+    "HEALTH_PORT",
+    "GEO_BUCKETS",
+    "GEOFENCE",
+    "REEFER",
+    "FAULT_BASELINE",
+    "BACKGROUND_FAULTS",
+    "COMBINED_SYNC",
+    "INTERVAL_JITTER",
+    "PROVISIONING_TOKEN", // Read by device init only
+    "CONFIG_DIR",
+    "STRICT_CONFIG",
+    "NUM_DEVICES",
+    "SNAPSHOT_DIR", // Read by the fleet runner only
+    "FLEET_CONTROL_PORT", // Read by the fleet runner only
+    "FLEET_CONTROL_ADDRESS", // Read by the fleet runner only
+    "FLEET_CONTROL_TOKEN", // Read by the fleet runner only
+    "FLEET_ENVIRONMENTS", // Read by the fleet runner only
+];
+
+const ENV_PREFIX: &str = "VF_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    Env,
+    Default,
+    File,
+}
+
+// Where each config value came from, plus any misconfiguration found while loading
+#[derive(Debug, Clone, Default)]
+pub struct ConfigReport {
+    pub sources: Vec<(String, ConfigSource)>,
+    pub warnings: Vec<String>,
+}
+
+impl ConfigReport {
+    pub fn from_file() -> Self {
+        let sources = KNOWN_ENV_VARS
+            .iter()
+            .filter(|key| !matches!(**key, "CONFIG_DIR" | "STRICT_CONFIG" | "NUM_DEVICES") && !key.starts_with("EXTERNAL_"))
+            .map(|key| (key.to_string(), ConfigSource::File))
+            .collect();
+        ConfigReport { sources, warnings: Vec::new() }
+    }
+
+    pub fn log(&self) {
+        for (key, source) in &self.sources {
+            info!(key = %key, source = ?source, "Config value source");
+        }
+        for warning in &self.warnings {
+            warn!("{}", warning);
+        }
+    }
+}
+
+// Strict mode surfaces config sources and unrecognized variables at startup
+pub fn strict_mode_enabled() -> bool {
+    let vars: HashMap<String, String> = env::vars().collect();
+    lookup(&vars, "STRICT_CONFIG").is_some_and(|val| val == "1" || val.eq_ignore_ascii_case("true"))
+}
+
+// VF_-prefixed variables that do not correspond to any known setting, e.g. a typo
+pub fn unrecognized_env_vars(vars: &HashMap<String, String>) -> Vec<String> {
+    let mut unknown: Vec<String> = vars
+        .keys()
+        .filter(|key| {
+            key.strip_prefix(ENV_PREFIX)
+                .is_some_and(|name| !KNOWN_ENV_VARS.contains(&name))
+        })
+        .cloned()
+        .collect();
+    unknown.sort();
+    unknown
+}
+
+fn lookup<'a>(vars: &'a HashMap<String, String>, key: &str) -> Option<&'a String> {
+    vars.get(&format!("{}{}", ENV_PREFIX, key)).or_else(|| vars.get(key))
+}
+
+struct EnvReader<'a> {
+    vars: &'a HashMap<String, String>,
+    report: ConfigReport,
+}
+
+impl EnvReader<'_> {
+    fn record(&mut self, key: &str, source: ConfigSource) {
+        self.report.sources.push((key.to_string(), source));
+    }
+
+    fn optional_string(&mut self, key: &str) -> Option<String> {
+        let value = lookup(self.vars, key).cloned();
+        self.record(key, if value.is_some() { ConfigSource::Env } else { ConfigSource::Default });
+        value
+    }
+
+    fn string_or_else(&mut self, key: &str, default: impl FnOnce() -> String) -> String {
+        self.optional_string(key).unwrap_or_else(default)
+    }
+
+    fn external_source(&mut self) -> Option<ExternalSourceConfig> {
+        let kind = match external::parse_source_kind(&self.optional_string("EXTERNAL_SOURCE")?) {
+            Ok(kind) => kind,
+            Err(e) => {
+                self.report.warnings.push(e.to_string());
+                return None;
+            }
+        };
+        let stall_timeout_secs = self.u64("EXTERNAL_STALL_TIMEOUT_SECS", 30);
+        let backpressure = match self.optional_string("EXTERNAL_BACKPRESSURE").as_deref() {
+            Some("buffer") => Backpressure::Buffer,
+            Some("latest") | None => Backpressure::Latest,
+            Some(other) => {
+                self.report.warnings.push(format!("Unknown EXTERNAL_BACKPRESSURE {:?}, using latest", other));
+                Backpressure::Latest
+            }
+        };
+        let field_map = self.optional_string("EXTERNAL_FIELD_MAP").map(|raw| external::parse_field_map(&raw)).unwrap_or_default();
+        Some(ExternalSourceConfig { kind, stall_timeout_secs, backpressure, buffer_size: 100, field_map })
+    }
+
+    // JSON object mapping firmware version to behavior overrides
+    fn firmware_behaviors(&mut self) -> BTreeMap<String, FirmwareBehavior> {
+        let Some(raw) = self.optional_string("FIRMWARE_BEHAVIORS") else {
+            return BTreeMap::new();
+        };
+        serde_json::from_str(&raw).unwrap_or_else(|e| {
+            self.report.warnings.push(format!("Invalid FIRMWARE_BEHAVIORS: {}", e));
+            BTreeMap::new()
+        })
+    }
+
+    // JSON object of feature overrides; names and values are validated when resolved
+    fn features(&mut self) -> BTreeMap<String, FeatureValue> {
+        let Some(raw) = self.optional_string("FEATURES") else {
+            return BTreeMap::new();
+        };
+        serde_json::from_str(&raw).unwrap_or_else(|e| {
+            self.report.warnings.push(format!("Invalid FEATURES: {}", e));
+            BTreeMap::new()
+        })
+    }
+
+    // JSON sensor degradation settings, see degradation::DegradationConfig
+    fn degradation(&mut self) -> Option<DegradationConfig> {
+        let raw = self.optional_string("DEGRADATION")?;
+        serde_json::from_str(&raw).map_err(|e| self.report.warnings.push(format!("Invalid DEGRADATION: {}", e))).ok()
+    }
+
+    fn network(&mut self) -> Option<NetworkType> {
+        let raw = self.optional_string("NETWORK")?;
+        raw.parse().map_err(|e: anyhow::Error| self.report.warnings.push(e.to_string())).ok()
+    }
+
+    // JSON roaming schedule and region map, see network::RoamingConfig
+    fn network_roaming(&mut self) -> Option<RoamingConfig> {
+        let raw = self.optional_string("NETWORK_ROAMING")?;
+        serde_json::from_str(&raw).map_err(|e| self.report.warnings.push(format!("Invalid NETWORK_ROAMING: {}", e))).ok()
+    }
+
+    // JSON object mapping network type to a full replacement profile
+    fn network_profiles(&mut self) -> BTreeMap<NetworkType, NetworkProfile> {
+        let Some(raw) = self.optional_string("NETWORK_PROFILES") else {
+            return BTreeMap::new();
+        };
+        serde_json::from_str(&raw).unwrap_or_else(|e| {
+            self.report.warnings.push(format!("Invalid NETWORK_PROFILES: {}", e));
+            BTreeMap::new()
+        })
+    }
+
+    fn shed(&mut self) -> ShedConfig {
+        let defaults = ShedConfig::default();
+        let policy = match self.optional_string("SHED_POLICY").as_deref() {
+            Some("decimate") | None => ShedPolicy::Decimate,
+            Some("aggregate") => ShedPolicy::Aggregate,
+            Some("evict") => ShedPolicy::Evict,
+            Some(other) => {
+                self.report.warnings.push(format!("Unknown SHED_POLICY {:?}, using decimate", other));
+                ShedPolicy::Decimate
+            }
+        };
+        let high_water = self.u64("SHED_HIGH_WATER", defaults.high_water);
+        let low_water = self.u64("SHED_LOW_WATER", defaults.low_water);
+        ShedConfig { policy, high_water, low_water, ..defaults }
+    }
+
+    fn cost_model(&mut self) -> Option<CostConfig> {
+        let raw = self.optional_string("COST_MODEL")?;
+        serde_json::from_str(&raw).map_err(|e| self.report.warnings.push(format!("Invalid COST_MODEL: {}", e))).ok()
+    }
+
+    // JSON actions by rejection code, see shadow_report::ShadowRejectionPolicy
+    fn shadow_rejection_policy(&mut self) -> ShadowRejectionPolicy {
+        let Some(raw) = self.optional_string("SHADOW_REJECTION_POLICY") else {
+            return ShadowRejectionPolicy::default();
+        };
+        serde_json::from_str(&raw).unwrap_or_else(|e| {
+            self.report.warnings.push(format!("Invalid SHADOW_REJECTION_POLICY: {}", e));
+            ShadowRejectionPolicy::default()
+        })
+    }
+
+    fn sensor_profile(&mut self) -> SensorProfile {
+        let Some(raw) = self.optional_string("SENSOR_PROFILE") else {
+            return SensorProfile::default();
+        };
+        raw.parse().map_err(|e: anyhow::Error| self.report.warnings.push(e.to_string())).unwrap_or_default()
+    }
+
+    // JSON codec settings per endpoint class, see codec::CompressionConfig
+    fn compression(&mut self) -> CompressionConfig {
+        let Some(raw) = self.optional_string("COMPRESSION") else {
+            return CompressionConfig::default();
+        };
+        serde_json::from_str(&raw).unwrap_or_else(|e| {
+            self.report.warnings.push(format!("Invalid COMPRESSION: {}", e));
+            CompressionConfig::default()
+        })
+    }
+
+    fn cadence(&mut self) -> Option<CadenceConfig> {
+        let raw = self.optional_string("CADENCE")?;
+        serde_json::from_str(&raw)
+            .map_err(|e| self.report.warnings.push(format!("Invalid CADENCE: {}", e)))
+            .ok()
+    }
+
+    fn geo_buckets(&mut self) -> Option<GeoBucketConfig> {
+        let raw = self.optional_string("GEO_BUCKETS")?;
+        serde_json::from_str(&raw)
+            .map_err(|e| self.report.warnings.push(format!("Invalid GEO_BUCKETS: {}", e)))
+            .ok()
+    }
+
+    // JSON object, e.g. {"shape": "circle", "lat": …, "lon": …, "radius_m": …}
+    fn geofence(&mut self) -> Option<Geofence> {
+        let raw = self.optional_string("GEOFENCE")?;
+        serde_json::from_str(&raw)
+            .map_err(|e| self.report.warnings.push(format!("Invalid GEOFENCE: {}", e)))
+            .ok()
+    }
+
+    // JSON object, e.g. {"setpoint_c": 4, "stops": [{"distance_m": …, "dwell_secs": …}]}
+    fn reefer(&mut self) -> Option<ReeferConfig> {
+        let raw = self.optional_string("REEFER")?;
+        serde_json::from_str(&raw)
+            .map_err(|e| self.report.warnings.push(format!("Invalid REEFER: {}", e)))
+            .ok()
+    }
+
+    // JSON object of rates, e.g. {"gps_dropout": 0.1}; see baseline::BaselineOverrides
+    fn fault_baseline(&mut self) -> BaselineOverrides {
+        let Some(raw) = self.optional_string("FAULT_BASELINE") else {
+            return BaselineOverrides::default();
+        };
+        serde_json::from_str(&raw).unwrap_or_else(|e| {
+            self.report.warnings.push(format!("Invalid FAULT_BASELINE: {}", e));
+            BaselineOverrides::default()
+        })
+    }
+
+    // JSON array of {"lat": …, "lon": …} objects, in driving order
+    fn route_waypoints(&mut self) -> Vec<Waypoint> {
+        let Some(raw) = self.optional_string("ROUTE_WAYPOINTS") else {
+            return Vec::new();
+        };
+        serde_json::from_str(&raw).unwrap_or_else(|e| {
+            self.report.warnings.push(format!("Invalid ROUTE_WAYPOINTS: {}", e));
+            Vec::new()
+        })
+    }
+
+    // ERROR_BODY_REDACT_PATTERNS is a JSON array of regexes, replacing the default ones
+    fn error_body_capture(&mut self) -> CaptureConfig {
+        let defaults = CaptureConfig::default();
+        let limit_bytes = self.u64("ERROR_BODY_LIMIT_BYTES", defaults.limit_bytes as u64) as usize;
+        let redact_patterns = match self.optional_string("ERROR_BODY_REDACT_PATTERNS") {
+            Some(raw) => serde_json::from_str(&raw).unwrap_or_else(|e| {
+                self.report.warnings.push(format!("Invalid ERROR_BODY_REDACT_PATTERNS: {}", e));
+                defaults.redact_patterns
+            }),
+            None => defaults.redact_patterns,
+        };
+        CaptureConfig { limit_bytes, redact_patterns }
+    }
+
+    fn push_keepalive(&mut self) -> KeepaliveConfig {
+        let defaults = KeepaliveConfig::default();
+        KeepaliveConfig {
+            ping_interval_secs: self.u64("PUSH_PING_INTERVAL_SECS", defaults.ping_interval_secs),
+            pong_timeout_secs: self.u64("PUSH_PONG_TIMEOUT_SECS", defaults.pong_timeout_secs),
+        }
+    }
+
+    // JSON object mapping region to data endpoint
+    fn region_endpoints(&mut self) -> BTreeMap<String, String> {
+        let Some(raw) = self.optional_string("REGION_ENDPOINTS") else {
+            return BTreeMap::new();
+        };
+        serde_json::from_str(&raw).unwrap_or_else(|e| {
+            self.report.warnings.push(format!("Invalid REGION_ENDPOINTS: {}", e));
+            BTreeMap::new()
+        })
+    }
+
+    fn bool(&mut self, key: &str, default: bool) -> bool {
+        match lookup(self.vars, key).map(|raw| raw.to_ascii_lowercase()) {
+            Some(raw) => match raw.as_str() {
+                "1" | "true" | "yes" => {
+                    self.record(key, ConfigSource::Env);
+                    true
+                }
+                "0" | "false" | "no" => {
+                    self.record(key, ConfigSource::Env);
+                    false
+                }
+                _ => {
+                    self.report.warnings.push(format!("Invalid value {:?} for {}, using default {}", raw, key, default));
+                    self.record(key, ConfigSource::Default);
+                    default
+                }
+            },
+            None => {
+                self.record(key, ConfigSource::Default);
+                default
+            }
+        }
+    }
+
+    fn optional_u64(&mut self, key: &str) -> Option<u64> {
+        let raw = self.optional_string(key)?;
+        raw.parse().map_err(|_| self.report.warnings.push(format!("Invalid value {:?} for {}, ignoring it", raw, key))).ok()
+    }
+
+    fn optional_u16(&mut self, key: &str) -> Option<u16> {
+        let raw = self.optional_string(key)?;
+        raw.parse().map_err(|_| self.report.warnings.push(format!("Invalid value {:?} for {}, ignoring it", raw, key))).ok()
+    }
+
+    fn f32(&mut self, key: &str, default: f32) -> f32 {
+        match lookup(self.vars, key).map(|raw| (raw.parse::<f32>(), raw)) {
+            Some((Ok(val), _)) if val.is_finite() => {
+                self.record(key, ConfigSource::Env);
+                val
+            }
+            Some((_, raw)) => {
+                self.report.warnings.push(format!("Invalid value {:?} for {}, using default {}", raw, key, default));
+                self.record(key, ConfigSource::Default);
+                default
+            }
+            None => {
+                self.record(key, ConfigSource::Default);
+                default
+            }
+        }
+    }
+
+    fn u64(&mut self, key: &str, default: u64) -> u64 {
+        match lookup(self.vars, key) {
+            Some(raw) => match raw.parse() {
+                Ok(val) => {
+                    self.record(key, ConfigSource::Env);
+                    val
+                }
+                Err(_) => {
+                    self.report.warnings.push(format!("Invalid value {:?} for {}, using default {}", raw, key, default));
+                    self.record(key, ConfigSource::Default);
+                    default
+                }
+            },
+            None => {
+                self.record(key, ConfigSource::Default);
+                default
+            }
+        }
+    }
 }
\ No newline at end of file
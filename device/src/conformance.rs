@@ -0,0 +1,428 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use reqwest::{Client, StatusCode};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::codec::{Codec, CodecSetting};
+use crate::config::Config;
+use crate::init;
+use crate::net::{self, NetError};
+use crate::network::NetworkType;
+use crate::ota::OtaPhase;
+use crate::ota_history::{AttemptOutcome, OtaAttempt};
+use crate::residency::DataTarget;
+use crate::stats::ApiStats;
+use crate::types::{Heartbeat, Measurement, ReportedShadowState, RollbackReport};
+
+// Heartbeats sent at once to find out how the backend throttles
+const DEFAULT_BURST: usize = 20;
+// Firmware version the suite's device claims to run
+const FIRMWARE_VERSION: &str = "1.0.0";
+// Newer than anything a backend publishes, so it is never offered an update
+const NEWEST_VERSION: &str = "999999.0.0";
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Pass,
+    Skipped, // The backend gave the check nothing to look at, e.g. no firmware published
+    Fail,
+}
+
+/// One expectation of the device, with what the backend answered.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub detail: String,
+    pub observed: Value, // Response body, or status and captured body of a refusal
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>, observed: Value) -> Self {
+        Check { name, outcome: Outcome::Pass, detail: detail.into(), observed }
+    }
+
+    fn fail(name: &'static str, detail: impl Into<String>, observed: Value) -> Self {
+        Check { name, outcome: Outcome::Fail, detail: detail.into(), observed }
+    }
+
+    fn skipped(name: &'static str, detail: impl Into<String>, observed: Value) -> Self {
+        Check { name, outcome: Outcome::Skipped, detail: detail.into(), observed }
+    }
+
+    // A request that did not go through
+    fn error(name: &'static str, error: &anyhow::Error) -> Self {
+        Check::fail(name, format!("{:#}", error), observed_error(error))
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CapabilityReport {
+    pub capability: &'static str,
+    pub outcome: Outcome, // The worst of its checks
+    pub checks: Vec<Check>,
+}
+
+impl CapabilityReport {
+    fn new(capability: &'static str, checks: Vec<Check>) -> Self {
+        let outcome = checks.iter().map(|check| check.outcome).max().unwrap_or(Outcome::Skipped);
+        CapabilityReport { capability, outcome, checks }
+    }
+}
+
+/// Result of `device conformance` against one backend.
+#[derive(Serialize, Debug, Clone)]
+pub struct ConformanceReport {
+    pub backend_url: String,
+    pub started_at: DateTime<Utc>,
+    pub device_id: Option<String>, // Registered for the run; None if registration failed
+    pub passed: bool, // No check failed; skipped ones do not count against it
+    pub capabilities: Vec<CapabilityReport>,
+}
+
+/// Options for `device conformance --backend-url URL [--provisioning-token TOKEN] [--burst N]`.
+#[derive(Debug, Default)]
+pub struct ConformanceArgs {
+    pub backend_url: Option<String>,
+    pub provisioning_token: Option<String>,
+    pub burst: Option<usize>,
+}
+
+impl ConformanceArgs {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut parsed = ConformanceArgs::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().cloned().ok_or_else(|| format!("{} needs a value", arg));
+            match arg.as_str() {
+                "--backend-url" => parsed.backend_url = Some(value()?),
+                "--provisioning-token" => parsed.provisioning_token = Some(value()?),
+                "--burst" => parsed.burst = Some(value()?.parse().map_err(|e| format!("--burst {}", e))?),
+                other => return Err(format!("unknown argument {}", other)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+// Status, correlation id and body of a refusal; the error text of anything else
+fn observed_error(error: &anyhow::Error) -> Value {
+    if let Some(NetError::Status { status, correlation_id, body, .. }) = error.downcast_ref::<NetError>() {
+        return json!({"status": status.as_u16(), "correlation_id": correlation_id, "body": body});
+    }
+    match error.downcast_ref::<NetError>().and_then(NetError::status) {
+        Some(status) => json!({"status": status.as_u16()}),
+        None => json!({"error": format!("{:#}", error)}),
+    }
+}
+
+fn to_value(value: &impl Serialize) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+/// Registers a throwaway device with the backend and checks each capability the device
+/// relies on through the same code paths the device uses. Nothing is written to disk.
+pub async fn run_suite(client: &Client, backend_url: &str, provisioning_token: Option<String>, burst: usize) -> ConformanceReport {
+    let started_at = Utc::now();
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), backend_url.to_string()),
+        ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()), // Each answer as the backend gave it
+    ]);
+    let mut config = Config::from_env_vars(&env).0;
+    let stats = ApiStats::default();
+
+    let registration = match init::register(client, &mut config, provisioning_token).await {
+        Ok(()) => Check::pass("register", "registered", json!({"device_id": config.device_id, "negotiated_encoding": config.negotiated_encoding})),
+        Err(e) => Check::error("register", &e),
+    };
+    let registered = registration.outcome == Outcome::Pass;
+    let mut capabilities = vec![CapabilityReport::new("registration", vec![registration])];
+    if registered {
+        capabilities.push(CapabilityReport::new("heartbeat", heartbeat_checks(client, &config, &stats).await));
+        capabilities.push(CapabilityReport::new("ingest", ingest_checks(client, &config, &stats).await));
+        capabilities.push(CapabilityReport::new("shadow", shadow_checks(client, &config, &stats).await));
+        capabilities.push(CapabilityReport::new("firmware", firmware_checks(client, &config, &stats).await));
+        capabilities.push(CapabilityReport::new("error_handling", error_handling_checks(client, &config, burst).await));
+    } else {
+        for capability in ["heartbeat", "ingest", "shadow", "firmware", "error_handling"] {
+            let check = Check::skipped("registered", "the device could not register", Value::Null);
+            capabilities.push(CapabilityReport::new(capability, vec![check]));
+        }
+    }
+
+    ConformanceReport {
+        backend_url: backend_url.to_string(),
+        started_at,
+        device_id: registered.then(|| config.device_id.clone()),
+        passed: capabilities.iter().all(|capability| capability.outcome != Outcome::Fail),
+        capabilities,
+    }
+}
+
+// A heartbeat with every optional field the device may send
+fn full_heartbeat(config: &Config) -> Heartbeat {
+    let now = Utc::now();
+    let mut heartbeat = net::heartbeat_body(config, FIRMWARE_VERSION, 10, 60, 30);
+    heartbeat.region = Some("eu-west".to_string());
+    heartbeat.hardware_rev = Some("rev-b".to_string());
+    heartbeat.maintenance = true;
+    heartbeat.maintenance_expires_at = Some(now + chrono::Duration::hours(1));
+    heartbeat.anomaly_counts = Some(BTreeMap::from([("temp_spike".to_string(), 2)]));
+    heartbeat.api_stats = Some(ApiStats::default().report());
+    heartbeat.features = Some(json!({"compression": true}));
+    heartbeat.shed_samples = Some(3);
+    heartbeat.decimation_factor = Some(2);
+    heartbeat.network = Some(NetworkType::Lte);
+    heartbeat.rollback = Some(RollbackReport {
+        failed_version: "1.1.0".to_string(),
+        version: FIRMWARE_VERSION.to_string(),
+        slot: "b".to_string(),
+        reason: "boot_limit".to_string(),
+        boot_count: 3,
+        heartbeats: 0,
+        ingested: false,
+        at: now,
+    });
+    heartbeat.uptime_secs = Some(120);
+    heartbeat.pending_measurements = Some(42);
+    heartbeat.consecutive_upload_failures = Some(1);
+    heartbeat.last_upload_error = Some("ingest answered HTTP 503".to_string());
+    heartbeat.battery_level = Some(0.5);
+    heartbeat.free_disk_bytes = Some(1 << 30);
+    heartbeat.dropped_measurements = Some(0);
+    heartbeat.ota_update = Some(OtaAttempt {
+        version: FIRMWARE_VERSION.to_string(),
+        from_version: "0.9.0".to_string(),
+        started_at: now - chrono::Duration::minutes(5),
+        image_bytes: Some(1 << 20),
+        bytes_downloaded: 1 << 20,
+        resumed: false,
+        retries: 0,
+        download_ms: 4000,
+        verify_ms: 50,
+        apply_ms: 300,
+        network: Some(NetworkType::Lte),
+        outcome: AttemptOutcome::Confirmed,
+        error: None,
+    });
+    heartbeat.ota_phase = Some(OtaPhase::Overdue { version: "1.2.0".to_string(), elapsed_ms: 7000, expected_downtime_ms: 3000 });
+    heartbeat.device_time = Some(now);
+    heartbeat.chaos_crashes = Some(1);
+    heartbeat
+}
+
+async fn heartbeat_checks(client: &Client, config: &Config, stats: &ApiStats) -> Vec<Check> {
+    let minimal = net::heartbeat_body(config, FIRMWARE_VERSION, 10, 60, 30);
+    let mut checks = Vec::new();
+    for (name, body) in [("minimal", minimal), ("all_fields", full_heartbeat(config))] {
+        checks.push(match net::send_heartbeat(client, config, stats, &body).await {
+            Ok(desired) if desired.desired_sample_interval_secs == 0 || desired.desired_upload_interval_secs == 0 || desired.desired_heartbeat_interval_secs == 0 => {
+                Check::fail(name, "desired intervals must be positive", to_value(&desired))
+            }
+            Ok(desired) => Check::pass(name, "desired state received", to_value(&desired)),
+            Err(e) => Check::error(name, &e),
+        });
+    }
+    checks
+}
+
+fn measurement(sequence_number: u32) -> Measurement {
+    Measurement {
+        timestamp: Utc::now(),
+        temp: 21.5,
+        humidity: 40.0,
+        battery: 0.9,
+        sequence_number,
+        latitude: None,
+        longitude: None,
+        speed: None,
+        heading: None,
+        geohash: None,
+        map_tile: None,
+        firmware_version: None,
+        maintenance: None,
+        device_flags: None,
+        local_timestamp: None,
+        utc_offset_minutes: None,
+        aggregate_count: None,
+        region: None,
+        network: None,
+        replay: None,
+        keyframe: None,
+        extra: None,
+        content_hash: None,
+        omitted: Vec::new(),
+    }
+}
+
+async fn ingest_checks(client: &Client, config: &Config, stats: &ApiStats) -> Vec<Check> {
+    let optional_fields_absent = measurement(0);
+
+    let mut unicode = measurement(1);
+    unicode.firmware_version = Some("1.0.0-β".to_string());
+    unicode.device_flags = Some(vec!["température_élevée".to_string()]);
+    unicode.local_timestamp = Some("2026-03-29T02:30:00+02:00".to_string());
+    unicode.extra = Some(BTreeMap::from([("label".to_string(), json!("Kühlhaus Nord ❄ 冷库 🚚"))]));
+
+    let mut boundary_floats = measurement(2);
+    boundary_floats.temp = f32::MAX;
+    boundary_floats.humidity = f32::MIN_POSITIVE;
+    boundary_floats.battery = -0.0;
+    boundary_floats.latitude = Some(90.0);
+    boundary_floats.longitude = Some(-180.0);
+    boundary_floats.speed = Some(0.0);
+    boundary_floats.heading = Some(359.99);
+
+    let target = DataTarget { region: None, endpoint: config.backend_url.clone() };
+    let mut checks = Vec::new();
+    for (name, sample) in [("optional_fields_absent", optional_fields_absent), ("unicode", unicode), ("boundary_floats", boundary_floats)] {
+        checks.push(match net::send_ingest(client, config, stats, &target, &[sample], None).await {
+            Ok(feedback) => Check::pass(name, "accepted", to_value(&feedback)),
+            Err(e) => Check::error(name, &e),
+        });
+    }
+    checks
+}
+
+async fn report(client: &Client, config: &Config, stats: &ApiStats, state: Value) -> Result<()> {
+    net::report_device_shadow(client, config, stats, ReportedShadowState { state }).await
+}
+
+async fn fetch_reported(client: &Client, config: &Config, stats: &ApiStats) -> Result<Value> {
+    Ok(net::fetch_device_shadow(client, config, stats).await?.reported.unwrap_or(Value::Null))
+}
+
+// Reports each patch in turn, or all at once, then reads back what the backend kept
+async fn patch_and_fetch(client: &Client, config: &Config, stats: &ApiStats, patches: Vec<Value>, concurrent: bool) -> Result<Value> {
+    if concurrent {
+        join_all(patches.into_iter().map(|patch| report(client, config, stats, patch))).await.into_iter().collect::<Result<Vec<_>>>()?;
+    } else {
+        for patch in patches {
+            report(client, config, stats, patch).await?;
+        }
+    }
+    fetch_reported(client, config, stats).await
+}
+
+fn expect_reported(name: &'static str, result: Result<Value>, expected: &[(&str, Value)], failure: &str) -> Check {
+    match result {
+        Ok(reported) => {
+            let missing: Vec<&str> = expected.iter().filter(|(key, value)| reported.get(*key) != Some(value)).map(|(key, _)| *key).collect();
+            if missing.is_empty() {
+                Check::pass(name, "reported state kept", reported)
+            } else {
+                Check::fail(name, format!("{}: {} not as reported", failure, missing.join(", ")), reported)
+            }
+        }
+        Err(e) => Check::error(name, &e),
+    }
+}
+
+// The device reports only the sections that changed (see reported::ReportedState), so
+// the backend must merge each patch into what it holds rather than replace it.
+async fn shadow_checks(client: &Client, config: &Config, stats: &ApiStats) -> Vec<Check> {
+    let marker = json!({"run": uuid::Uuid::new_v4().to_string()});
+    let round_trip = patch_and_fetch(client, config, stats, vec![json!({"conformance": marker})], false).await;
+    let partial = patch_and_fetch(client, config, stats, vec![json!({"conformance_a": 1}), json!({"conformance_b": 2})], false).await;
+    let last_write = patch_and_fetch(client, config, stats, vec![json!({"conformance_a": 3})], false).await;
+    // Two patches built on the same reported version, as after a reconnect
+    let concurrent = patch_and_fetch(client, config, stats, vec![json!({"conformance_c": 1}), json!({"conformance_d": 1})], true).await;
+    vec![
+        expect_reported("round_trip", round_trip, &[("conformance", marker.clone())], "the reported state did not read back"),
+        expect_reported("partial_patch_merges", partial, &[("conformance", marker), ("conformance_a", json!(1)), ("conformance_b", json!(2))], "a partial patch replaced the reported state"),
+        expect_reported("last_write_wins", last_write, &[("conformance_a", json!(3)), ("conformance_b", json!(2))], "a later patch of the same key did not replace it"),
+        expect_reported("concurrent_patches", concurrent, &[("conformance_c", json!(1)), ("conformance_d", json!(1))], "a concurrent patch was lost"),
+    ]
+}
+
+async fn firmware_checks(client: &Client, config: &Config, stats: &ApiStats) -> Vec<Check> {
+    let no_update = match net::fetch_latest_firmware(client, config, stats, NEWEST_VERSION, &uuid::Uuid::new_v4().to_string()).await {
+        Ok(None) => Check::pass("no_update", "no firmware offered", json!({"status": StatusCode::NO_CONTENT.as_u16()})),
+        Ok(Some(firmware)) => Check::fail("no_update", format!("offered {} to a device running {}", firmware.version, NEWEST_VERSION), to_value(&firmware)),
+        Err(e) => Check::error("no_update", &e),
+    };
+
+    let nonce = uuid::Uuid::new_v4().to_string();
+    let metadata = match net::fetch_latest_firmware(client, config, stats, "0.0.0", &nonce).await {
+        Ok(None) => Check::skipped("metadata", "the backend publishes no firmware", Value::Null),
+        Ok(Some(firmware)) => {
+            let mut problems = Vec::new();
+            if firmware.version.is_empty() {
+                problems.push("version is empty".to_string());
+            }
+            if firmware.checksum.len() != 64 || !firmware.checksum.chars().all(|c| c.is_ascii_hexdigit()) {
+                problems.push("checksum is not a hex SHA-256 digest".to_string());
+            }
+            if reqwest::Url::parse(&firmware.url).is_err() {
+                problems.push(format!("url {} does not parse", firmware.url));
+            }
+            if firmware.nonce.as_ref().is_some_and(|echoed| echoed != &nonce) {
+                problems.push("nonce echo does not match the request".to_string());
+            }
+            if problems.is_empty() {
+                Check::pass("metadata", format!("offered {}", firmware.version), to_value(&firmware))
+            } else {
+                Check::fail("metadata", problems.join("; "), to_value(&firmware))
+            }
+        }
+        Err(e) => Check::error("metadata", &e),
+    };
+    vec![no_update, metadata]
+}
+
+async fn error_handling_checks(client: &Client, config: &Config, burst: usize) -> Vec<Check> {
+    let mut gzip_config = config.clone();
+    gzip_config.compression.heartbeat = CodecSetting::Fixed(Codec::Gzip);
+    gzip_config.compression.min_bytes = 0;
+    let gzip = match net::send_heartbeat(client, &gzip_config, &ApiStats::default(), &full_heartbeat(config)).await {
+        Ok(desired) => Check::pass("gzip_body", "gzip request body accepted", to_value(&desired)),
+        Err(e) => Check::error("gzip_body", &e),
+    };
+
+    // A fresh counter, so only the burst's refusals are looked at
+    let stats = ApiStats::default();
+    let body = net::heartbeat_body(config, FIRMWARE_VERSION, 10, 60, 30);
+    join_all((0..burst).map(|_| net::send_heartbeat(client, config, &stats, &body))).await;
+    let throttled: Vec<_> = stats.failed_responses().into_iter()
+        .filter(|response| response.status == StatusCode::TOO_MANY_REQUESTS.as_u16() || response.status == StatusCode::SERVICE_UNAVAILABLE.as_u16())
+        .collect();
+    let observed = json!(throttled.iter().map(|response| json!({"status": response.status, "retry_after": response.retry_after})).collect::<Vec<_>>());
+    let retry_after = if throttled.is_empty() {
+        Check::skipped("retry_after", format!("{} heartbeats at once were not throttled", burst), observed)
+    } else if throttled.iter().all(|response| response.retry_after.as_deref().is_some_and(valid_retry_after)) {
+        Check::pass("retry_after", "throttled responses say when to retry", observed)
+    } else {
+        Check::fail("retry_after", "a 429 or 503 came without a valid Retry-After", observed)
+    };
+    vec![gzip, retry_after]
+}
+
+// Delay seconds or an HTTP date
+fn valid_retry_after(value: &str) -> bool {
+    value.trim().parse::<u64>().is_ok() || DateTime::parse_from_rfc2822(value.trim()).is_ok()
+}
+
+/// Entry point for `device conformance`: prints the report as JSON and exits non-zero
+/// if any check failed.
+pub async fn run(args: &[String]) -> ! {
+    let vars: HashMap<String, String> = std::env::vars().collect();
+    let args = match ConformanceArgs::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\nusage: device conformance [--backend-url URL] [--provisioning-token TOKEN] [--burst N]", e);
+            std::process::exit(2);
+        }
+    };
+    let Some(backend_url) = args.backend_url.or_else(|| vars.get("BACKEND_URL").cloned()) else {
+        eprintln!("device conformance needs --backend-url or BACKEND_URL");
+        std::process::exit(2);
+    };
+    let provisioning_token = args.provisioning_token.or_else(|| vars.get("VF_PROVISIONING_TOKEN").or_else(|| vars.get("PROVISIONING_TOKEN")).cloned());
+    let report = run_suite(&Client::new(), backend_url.trim_end_matches('/'), provisioning_token, args.burst.unwrap_or(DEFAULT_BURST)).await;
+    println!("{}", serde_json::to_string_pretty(&report).expect("report serializes"));
+    std::process::exit(if report.passed { 0 } else { 1 });
+}
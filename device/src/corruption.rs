@@ -0,0 +1,76 @@
+use rand::seq::SliceRandom;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// The `corrupt_payload` chaos flag, e.g. `{"probability": 0.1, "mode": "truncate"}`:
+/// ingest bodies sent mangled, to see how the backend refuses them. A corrupted batch
+/// is never counted as uploaded; its rows stay stored and go again, intact, on a later
+/// tick.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CorruptPayload {
+    pub probability: f64, // Chance of each upload batch being corrupted
+    #[serde(default)]
+    pub mode: CorruptionMode,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CorruptionMode {
+    Truncate, // The JSON cut off mid-document
+    Type, // A string where a number is expected
+    MissingField, // A required field dropped
+    Absurd, // A reading no sensor takes
+    #[default]
+    Random, // One of the others, drawn for each corrupted batch
+}
+
+const CONCRETE_MODES: [CorruptionMode; 4] = [CorruptionMode::Truncate, CorruptionMode::Type, CorruptionMode::MissingField, CorruptionMode::Absurd];
+
+// Temperature set by the absurd mode
+const ABSURD_TEMP: f64 = 9999.0;
+
+impl CorruptionMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CorruptionMode::Truncate => "truncate",
+            CorruptionMode::Type => "type",
+            CorruptionMode::MissingField => "missing_field",
+            CorruptionMode::Absurd => "absurd",
+            CorruptionMode::Random => "random",
+        }
+    }
+
+    /// This mode, or for Random one of the others drawn from `rng`.
+    pub fn resolve(self, rng: &mut impl Rng) -> CorruptionMode {
+        match self {
+            CorruptionMode::Random => *CONCRETE_MODES.choose(rng).expect("modes are not empty"),
+            mode => mode,
+        }
+    }
+}
+
+/// `payload`, an ingest body as net::ingest_body builds it, serialized and mangled per
+/// `mode`. Only the first measurement is touched; Random sends the payload as it is, so
+/// resolve the mode first.
+pub fn corrupt(payload: &Value, mode: CorruptionMode) -> Vec<u8> {
+    let mut payload = payload.clone();
+    let first = payload.get_mut("measurements").and_then(|measurements| measurements.get_mut(0)).and_then(Value::as_object_mut);
+    match (mode, first) {
+        (CorruptionMode::Type, Some(measurement)) => {
+            measurement.insert("temp".to_string(), json!("warm"));
+        }
+        (CorruptionMode::MissingField, Some(measurement)) => {
+            measurement.remove("timestamp");
+        }
+        (CorruptionMode::Absurd, Some(measurement)) => {
+            measurement.insert("temp".to_string(), json!(ABSURD_TEMP));
+        }
+        _ => {}
+    }
+    let mut body = serde_json::to_vec(&payload).expect("a JSON value serializes");
+    if mode == CorruptionMode::Truncate {
+        body.truncate(body.len() / 2);
+    }
+    body
+}
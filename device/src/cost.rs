@@ -0,0 +1,208 @@
+use anyhow::Result;
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use crate::stats::StatsByEndpoint;
+use crate::storage;
+
+const STATE_KEY: &str = "cost_model";
+const FIRMWARE_DOWNLOAD_ENDPOINT: &str = "firmware_download";
+
+/// Unit prices for estimating what a device costs to run. All prices are in `currency`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CostConfig {
+    #[serde(default = "default_currency")]
+    pub currency: String, // ISO 4217 code, only used for formatting
+    #[serde(default)]
+    pub per_mb_uploaded: f64, // Per 1,000,000 bytes sent to the backend
+    #[serde(default)]
+    pub per_request: f64,
+    #[serde(default)]
+    pub per_firmware_download: f64,
+    #[serde(default)]
+    pub per_measurement_day: f64, // Per measurement held in local storage for a day
+}
+
+fn default_currency() -> String {
+    "USD".to_string()
+}
+
+/// Billable usage in one calendar month (UTC).
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Usage {
+    pub bytes_uploaded: u64,
+    pub requests: u64,
+    pub firmware_downloads: u64,
+    pub measurement_secs: u64, // Stored measurements times seconds held
+}
+
+/// Estimated cost per category, in millionths of the currency unit.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Costs {
+    pub upload: u64,
+    pub requests: u64,
+    pub firmware_downloads: u64,
+    pub storage: u64,
+    pub total: u64,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.bytes_uploaded += other.bytes_uploaded;
+        self.requests += other.requests;
+        self.firmware_downloads += other.firmware_downloads;
+        self.measurement_secs += other.measurement_secs;
+    }
+
+    /// Prices the usage. Usage is what is persisted, so a price change re-prices past months too.
+    pub fn costs(&self, prices: &CostConfig) -> Costs {
+        let micros = |quantity: f64, price: f64| (quantity * price * 1_000_000.0).round().max(0.0) as u64;
+        let upload = micros(self.bytes_uploaded as f64 / 1_000_000.0, prices.per_mb_uploaded);
+        let requests = micros(self.requests as f64, prices.per_request);
+        let firmware_downloads = micros(self.firmware_downloads as f64, prices.per_firmware_download);
+        let storage = micros(self.measurement_secs as f64 / 86_400.0, prices.per_measurement_day);
+        Costs { upload, requests, firmware_downloads, storage, total: upload + requests + firmware_downloads + storage }
+    }
+}
+
+// Cumulative counters already charged to some month
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+struct Charged {
+    bytes_uploaded: u64,
+    requests: u64,
+    firmware_downloads: u64,
+}
+
+impl Charged {
+    fn from_stats(stats: &StatsByEndpoint) -> Self {
+        Charged {
+            bytes_uploaded: stats.values().map(|endpoint| endpoint.bytes_sent).sum(),
+            requests: stats.values().map(|endpoint| endpoint.attempts).sum(),
+            firmware_downloads: stats.get(FIRMWARE_DOWNLOAD_ENDPOINT).map_or(0, |endpoint| endpoint.successes),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+struct CostState {
+    months: BTreeMap<String, Usage>, // Keyed by YYYY-MM
+    charged: Charged,
+    last_accrual: Option<DateTime<Utc>>,
+}
+
+/// Accumulates billable usage per calendar month from the API counters and the local
+/// backlog. Checkpointed into the device_state store alongside the API statistics.
+#[derive(Debug)]
+pub struct CostModel {
+    config: CostConfig,
+    state: CostState,
+}
+
+fn month_key(at: DateTime<Utc>) -> String {
+    at.format("%Y-%m").to_string()
+}
+
+fn next_month_start(at: DateTime<Utc>) -> DateTime<Utc> {
+    let (year, month) = if at.month() == 12 { (at.year() + 1, 1) } else { (at.year(), at.month() + 1) };
+    Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).unwrap()
+}
+
+impl CostModel {
+    /// Restores accumulated usage. Without saved state, traffic already in `stats` is
+    /// treated as charged, so enabling the model does not bill for history.
+    pub fn load(config: CostConfig, conn: &Connection, stats: &StatsByEndpoint) -> Result<Self> {
+        let state = match storage::load_state(conn, STATE_KEY)? {
+            Some(value) => serde_json::from_value(value)?,
+            None => CostState { charged: Charged::from_stats(stats), ..Default::default() },
+        };
+        Ok(CostModel { config, state })
+    }
+
+    pub fn checkpoint(&self, conn: &Connection) -> Result<()> {
+        Ok(storage::save_state(conn, STATE_KEY, &serde_json::to_value(&self.state)?)?)
+    }
+
+    /// Charges traffic since the last accrual to the month of `now`, and storage for
+    /// `pending` measurements held since then, split at month boundaries. Time comes
+    /// from the caller, so a simulated clock works the same as the wall clock.
+    pub fn accrue(&mut self, now: DateTime<Utc>, stats: &StatsByEndpoint, pending: u64) {
+        let current = Charged::from_stats(stats);
+        let charged = &self.state.charged;
+        let traffic = Usage {
+            // Counters restart lower if a crash lost increments since their last checkpoint
+            bytes_uploaded: current.bytes_uploaded.saturating_sub(charged.bytes_uploaded),
+            requests: current.requests.saturating_sub(charged.requests),
+            firmware_downloads: current.firmware_downloads.saturating_sub(charged.firmware_downloads),
+            measurement_secs: 0,
+        };
+        self.state.months.entry(month_key(now)).or_default().add(&traffic);
+        self.state.charged = current;
+
+        let mut from = self.state.last_accrual.unwrap_or(now);
+        while from < now {
+            let until = next_month_start(from).min(now);
+            let held_secs = (until - from).num_seconds().max(0) as u64;
+            self.state.months.entry(month_key(from)).or_default().measurement_secs += pending * held_secs;
+            from = until;
+        }
+        self.state.last_accrual = Some(now.max(from));
+    }
+
+    /// Sum over every month recorded.
+    pub fn total_usage(&self) -> Usage {
+        let mut total = Usage::default();
+        for usage in self.state.months.values() {
+            total.add(usage);
+        }
+        total
+    }
+
+    fn priced(&self, usage: &Usage) -> Value {
+        let costs = usage.costs(&self.config);
+        let format = |micros| format_amount(micros, &self.config.currency);
+        json!({
+            "usage": usage,
+            "cost_micros": costs,
+            "cost": {
+                "upload": format(costs.upload),
+                "requests": format(costs.requests),
+                "firmware_downloads": format(costs.firmware_downloads),
+                "storage": format(costs.storage),
+                "total": format(costs.total),
+            },
+        })
+    }
+
+    pub fn report(&self) -> Value {
+        let months: BTreeMap<&String, Value> = self.state.months.iter().map(|(month, usage)| (month, self.priced(usage))).collect();
+        json!({
+            "currency": self.config.currency,
+            "months": months,
+            "total": self.priced(&self.total_usage()),
+        })
+    }
+}
+
+/// Formats millionths of a currency unit rounded to cents, e.g. `$1,234.57`. Codes
+/// without a known symbol are written after the amount.
+pub fn format_amount(micros: u64, currency: &str) -> String {
+    let cents = (micros + 5_000) / 10_000;
+    let units = (cents / 100).to_string();
+    let mut grouped = String::new();
+    for (index, digit) in units.chars().enumerate() {
+        if index > 0 && (units.len() - index).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    let amount = format!("{}.{:02}", grouped, cents % 100);
+    match currency.to_ascii_uppercase().as_str() {
+        "USD" => format!("${}", amount),
+        "EUR" => format!("€{}", amount),
+        "GBP" => format!("£{}", amount),
+        code => format!("{} {}", amount, code),
+    }
+}
@@ -0,0 +1,324 @@
+use anyhow::{Context, Result};
+use reqwest::Client;
+use serde_json::Value;
+use std::fmt::{self, Write as _};
+use std::io::Write as _;
+use std::panic::PanicHookInfo;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use tracing::field::{Field, Visit};
+use tracing::{info, warn, Event, Subscriber};
+use tracing_subscriber::layer::{Context as LayerContext, Layer};
+
+use crate::config::Config;
+use crate::export;
+use crate::net;
+use crate::stats::ApiStats;
+use crate::types::DeviceErrorPayload;
+
+pub const SNAPSHOT_SCHEMA_VERSION: u32 = 1;
+const SNAPSHOT_PREFIX: &str = "crash-snapshot-";
+const EVENT_SLOTS: usize = 100;
+const EVENT_BYTES: usize = 240; // Longer log lines are cut off
+const STATE_BYTES: usize = 32 * 1024;
+// Worst case for the written file: every event needs escaping, plus the panic message
+const SNAPSHOT_BYTES: usize = STATE_BYTES + EVENT_SLOTS * (EVENT_BYTES * 6 + 64) + 8 * 1024;
+
+// Everything the panic hook touches is allocated up front; the hook only copies into it
+struct Recorder {
+    events: Mutex<EventRing>,
+    state: Mutex<StateSlot>,
+    output: Mutex<Vec<u8>>,
+    target: OnceLock<Mutex<Target>>,
+}
+
+struct EventSlot {
+    at_ms: i64,
+    level: &'static str,
+    len: usize,
+    text: [u8; EVENT_BYTES],
+}
+
+struct EventRing {
+    slots: Box<[EventSlot]>,
+    next: usize,
+    filled: usize,
+}
+
+struct StateSlot {
+    json: Vec<u8>,
+    updated_at_ms: i64,
+}
+
+struct Target {
+    device_id: String,
+    path: String, // Snapshot directory with a trailing separator; the file name is appended in place
+    dir_len: usize,
+}
+
+static RECORDER: OnceLock<Recorder> = OnceLock::new();
+
+fn recorder() -> &'static Recorder {
+    RECORDER.get_or_init(|| Recorder {
+        events: Mutex::new(EventRing {
+            slots: (0..EVENT_SLOTS).map(|_| EventSlot { at_ms: 0, level: "", len: 0, text: [0; EVENT_BYTES] }).collect(),
+            next: 0,
+            filled: 0,
+        }),
+        state: Mutex::new(StateSlot { json: Vec::with_capacity(STATE_BYTES), updated_at_ms: 0 }),
+        output: Mutex::new(Vec::with_capacity(SNAPSHOT_BYTES)),
+        target: OnceLock::new(),
+    })
+}
+
+// A panic elsewhere must not stop the snapshot, so poisoned locks are used as they are
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn now_ms() -> i64 {
+    chrono::Utc::now().timestamp_millis()
+}
+
+/// Writes into a fixed-size buffer, dropping whatever does not fit.
+struct Bounded<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl fmt::Write for Bounded<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut take = s.len().min(self.buf.len() - self.len);
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.buf[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        Ok(())
+    }
+}
+
+/// Appends to a vector without growing it past the capacity it was created with.
+struct Capped<'a>(&'a mut Vec<u8>);
+
+impl std::io::Write for Capped<'_> {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        if self.0.len() + data.len() > self.0.capacity() {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        self.0.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+struct EventVisitor<'a>(Bounded<'a>);
+
+impl Visit for EventVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{:?} ", value);
+        } else {
+            let _ = write!(self.0, "{}={:?} ", field.name(), value);
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            let _ = write!(self.0, "{} ", value);
+        } else {
+            let _ = write!(self.0, "{}={} ", field.name(), value);
+        }
+    }
+}
+
+/// Tracing layer keeping the last `EVENT_SLOTS` log events for the crash snapshot.
+pub struct EventLayer;
+
+impl<S: Subscriber> Layer<S> for EventLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: LayerContext<'_, S>) {
+        let mut ring = lock(&recorder().events);
+        let index = ring.next;
+        let slot = &mut ring.slots[index];
+        let mut visitor = EventVisitor(Bounded { buf: &mut slot.text, len: 0 });
+        event.record(&mut visitor);
+        let len = visitor.0.len;
+        slot.len = len;
+        slot.at_ms = now_ms();
+        slot.level = event.metadata().level().as_str();
+        ring.next = (index + 1) % EVENT_SLOTS;
+        ring.filled = (ring.filled + 1).min(EVENT_SLOTS);
+    }
+}
+
+/// Replaces the state written into a crash snapshot: metrics, counters, state machines
+/// and backlog. Rendered here, outside the panic hook; state too large to fit is
+/// recorded as truncated.
+pub fn update_state(state: &Value) {
+    let mut slot = lock(&recorder().state);
+    slot.json.clear();
+    if serde_json::to_writer(Capped(&mut slot.json), state).is_err() {
+        slot.json.clear();
+        slot.json.extend_from_slice(br#"{"truncated":true}"#);
+    }
+    slot.updated_at_ms = now_ms();
+}
+
+/// Writes a snapshot into `dir` whenever a thread panics, then runs the previous hook.
+pub fn install(dir: &Path, device_id: &str) -> Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    let mut path = dir.to_string_lossy().into_owned();
+    path.push(std::path::MAIN_SEPARATOR);
+    let dir_len = path.len();
+    path.reserve(SNAPSHOT_PREFIX.len() + 32);
+    let target = Target { device_id: device_id.to_string(), path, dir_len };
+    let recorder = recorder();
+    match recorder.target.get() {
+        Some(installed) => *lock(installed) = target,
+        None => {
+            let _ = recorder.target.set(Mutex::new(target));
+            let previous = std::panic::take_hook();
+            std::panic::set_hook(Box::new(move |info| {
+                if let Some(target) = recorder.target.get() {
+                    recorder.write_snapshot(&mut lock(target), info);
+                }
+                previous(info);
+            }));
+        }
+    }
+    Ok(())
+}
+
+impl Recorder {
+    fn write_snapshot(&self, target: &mut Target, info: &PanicHookInfo) {
+        let at_ms = now_ms();
+        let mut output = lock(&self.output);
+        output.clear();
+        let _ = self.render(&mut Capped(&mut output), target, info, at_ms);
+        target.path.truncate(target.dir_len);
+        let _ = write!(target.path, "{}{}.json", SNAPSHOT_PREFIX, at_ms);
+        let _ = std::fs::write(&target.path, &*output);
+    }
+
+    // Hand-written so nothing is allocated; a snapshot that outgrows the buffer is cut
+    // short and still uploaded
+    fn render(&self, out: &mut Capped, target: &Target, info: &PanicHookInfo, at_ms: i64) -> std::io::Result<()> {
+        write!(out, "{{\"schema_version\":{},\"device_id\":", SNAPSHOT_SCHEMA_VERSION)?;
+        write_json_str(out, &target.device_id)?;
+        write!(out, ",\"panicked_at_ms\":{},\"thread\":", at_ms)?;
+        write_json_str(out, std::thread::current().name().unwrap_or("unnamed"))?;
+        out.write_all(b",\"message\":")?;
+        let payload = info.payload();
+        let message = payload.downcast_ref::<&str>().copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("non-string panic payload");
+        write_json_str(out, message)?;
+        match info.location() {
+            Some(location) => {
+                out.write_all(b",\"location\":{\"file\":")?;
+                write_json_str(out, location.file())?;
+                write!(out, ",\"line\":{},\"column\":{}}}", location.line(), location.column())?;
+            }
+            None => out.write_all(b",\"location\":null")?,
+        }
+
+        // try_lock: the panicking thread may be the one holding these
+        match self.state.try_lock() {
+            Ok(state) if !state.json.is_empty() => {
+                write!(out, ",\"state_updated_at_ms\":{},\"state\":", state.updated_at_ms)?;
+                out.write_all(&state.json)?;
+            }
+            _ => out.write_all(b",\"state_updated_at_ms\":null,\"state\":null")?,
+        }
+        out.write_all(b",\"events\":[")?;
+        if let Ok(ring) = self.events.try_lock() {
+            let oldest = (ring.next + EVENT_SLOTS - ring.filled) % EVENT_SLOTS;
+            for i in 0..ring.filled {
+                let slot = &ring.slots[(oldest + i) % EVENT_SLOTS];
+                if i > 0 {
+                    out.write_all(b",")?;
+                }
+                write!(out, "{{\"at_ms\":{},\"level\":\"{}\",\"text\":", slot.at_ms, slot.level)?;
+                write_json_str(out, std::str::from_utf8(&slot.text[..slot.len]).unwrap_or("").trim_end())?;
+                out.write_all(b"}")?;
+            }
+        }
+        out.write_all(b"]}")
+    }
+}
+
+fn write_json_str(out: &mut Capped, value: &str) -> std::io::Result<()> {
+    out.write_all(b"\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => out.write_all(b"\\\"")?,
+            '\\' => out.write_all(b"\\\\")?,
+            '\n' => out.write_all(b"\\n")?,
+            '\r' => out.write_all(b"\\r")?,
+            '\t' => out.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+            c => out.write_all(c.encode_utf8(&mut [0; 4]).as_bytes())?,
+        }
+    }
+    out.write_all(b"\"")
+}
+
+/// Snapshots left by earlier runs, oldest first.
+pub fn pending_snapshots(dir: &Path) -> Result<Vec<PathBuf>> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Ok(Vec::new());
+    };
+    let mut snapshots = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        if name.starts_with(SNAPSHOT_PREFIX) && name.ends_with(".json") {
+            snapshots.push(path);
+        }
+    }
+    snapshots.sort();
+    Ok(snapshots)
+}
+
+/// Uploads each snapshot left by a crash and reports the crash with the uploaded file
+/// attached, then moves the snapshot to `archived/`. A snapshot that fails to upload
+/// stays for the next start. Returns how many were reported.
+pub async fn report_pending(client: &Client, config: &Config, stats: &ApiStats, dir: &Path, firmware_version: &str) -> Result<usize> {
+    let mut reported = 0;
+    for path in pending_snapshots(dir)? {
+        let body = std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let uploaded = match net::upload_file(client, config, stats, "crash_report", &file_name, &body, &export::sha256_hex(&body)).await {
+            Ok(uploaded) => uploaded,
+            Err(e) => {
+                warn!(device_id = %config.device_id, file = %file_name, error = %e, "Failed to upload crash snapshot, keeping it for the next start");
+                continue;
+            }
+        };
+        // A snapshot cut short by the buffer is still uploaded above; the report says what it could
+        let snapshot: Value = serde_json::from_slice(&body).unwrap_or_default();
+        let error = DeviceErrorPayload {
+            firmware_version: firmware_version.to_string(),
+            error_code: "panic".to_string(),
+            error_message: format!(
+                "{} at {}:{} (snapshot file {})",
+                snapshot["message"].as_str().unwrap_or("unreadable snapshot"),
+                snapshot["location"]["file"].as_str().unwrap_or("?"),
+                snapshot["location"]["line"],
+                uploaded.file_id,
+            ),
+        };
+        if let Err(e) = net::report_device_error(client, config, stats, &error).await {
+            warn!(device_id = %config.device_id, file_id = %uploaded.file_id, error = %e, "Uploaded crash snapshot but failed to report the crash");
+        }
+        let archived = dir.join("archived");
+        std::fs::create_dir_all(&archived)?;
+        std::fs::rename(&path, archived.join(&file_name))?;
+        info!(device_id = %config.device_id, file = %file_name, file_id = %uploaded.file_id, "Reported crash from a previous run");
+        reported += 1;
+    }
+    Ok(reported)
+}
@@ -0,0 +1,431 @@
+use chrono::{DateTime, Duration, Utc};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{self, Write as _};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::field::{Field, Visit};
+use tracing::{debug, info_span, warn, Event, Instrument, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::config::Config;
+use crate::net;
+use crate::stats::ApiStats;
+
+pub const DEBUG_SAMPLE_INTERVAL_SECS: u64 = 1;
+pub const DEBUG_HEARTBEAT_INTERVAL_SECS: u64 = 10;
+pub const MAX_DURATION_SECS: u64 = 4 * 60 * 60;
+pub const QUEUE_EVENTS: usize = 1000; // Events waiting to be shipped; more are dropped
+const BATCH_EVENTS: usize = 100;
+const BATCH_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+const MESSAGE_BYTES: usize = 2048; // Longer log lines are cut off
+const STREAM_SPAN: &str = "debug_stream";
+
+/// What a debug session elevates.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum DebugScope {
+    Logs, // Debug-level log events shipped to the backend
+    Sampling, // One-second sampling
+    Heartbeat, // Frequent heartbeats carrying full telemetry
+    Audit, // Every backend request and response logged
+}
+
+impl DebugScope {
+    pub const ALL: [DebugScope; 4] = [DebugScope::Logs, DebugScope::Sampling, DebugScope::Heartbeat, DebugScope::Audit];
+
+    // The setting the scope elevates, if any
+    fn setting(self) -> Option<&'static str> {
+        match self {
+            DebugScope::Sampling => Some("sample_interval_secs"),
+            DebugScope::Heartbeat => Some("heartbeat_interval_secs"),
+            DebugScope::Logs | DebugScope::Audit => None,
+        }
+    }
+
+    // Value of the setting during the session; it is never made less frequent
+    fn elevated_value(self, current: u64) -> u64 {
+        match self {
+            DebugScope::Sampling => current.min(DEBUG_SAMPLE_INTERVAL_SECS),
+            _ => current.min(DEBUG_HEARTBEAT_INTERVAL_SECS),
+        }
+    }
+}
+
+/// The desired shadow's `debug_session` command, e.g.
+/// `{"duration_secs": 900, "scopes": ["logs", "sampling"]}`. Without scopes, all apply.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DebugSessionRequest {
+    pub duration_secs: u64,
+    #[serde(default = "all_scopes")]
+    pub scopes: BTreeSet<DebugScope>,
+    #[serde(default)]
+    pub id: Option<String>, // Lets the backend repeat a request with the same parameters
+}
+
+fn all_scopes() -> BTreeSet<DebugScope> {
+    DebugScope::ALL.into_iter().collect()
+}
+
+pub fn parse_request(raw: &Value) -> Result<DebugSessionRequest, String> {
+    let request = serde_json::from_value::<DebugSessionRequest>(raw.clone()).map_err(|e| e.to_string())?;
+    if !(1..=MAX_DURATION_SECS).contains(&request.duration_secs) {
+        return Err(format!("duration_secs must be between 1 and {}", MAX_DURATION_SECS));
+    }
+    if request.scopes.is_empty() {
+        return Err("scopes must not be empty".to_string());
+    }
+    Ok(request)
+}
+
+/// A time-boxed debug session, persisted in the config so a restart either resumes it
+/// or, if it expired while the device was down, restores the settings it replaced.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DebugSession {
+    pub started_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub scopes: BTreeSet<DebugScope>,
+    pub saved: BTreeMap<String, u64>, // Settings in force before the session, restored when it ends
+    #[serde(default)]
+    pub ended_at: Option<DateTime<Utc>>,
+    // The desired document that produced this session, so a request left in the shadow
+    // after the session ended does not start it again on every shadow check.
+    pub request: Value,
+}
+
+impl DebugSession {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.ended_at.is_none() && now < self.expires_at
+    }
+
+    /// Time left until the session ends, zero once it is due.
+    pub fn remaining(&self, now: DateTime<Utc>) -> std::time::Duration {
+        (self.expires_at - now).to_std().unwrap_or_default()
+    }
+
+    /// Settings the session holds at elevated values.
+    pub fn elevated(&self, current: &BTreeMap<String, u64>) -> BTreeMap<String, u64> {
+        self.scopes.iter()
+            .filter_map(|scope| {
+                let key = scope.setting()?;
+                let current = self.saved.get(key).or_else(|| current.get(key)).copied()?;
+                Some((key.to_string(), scope.elevated_value(current)))
+            })
+            .collect()
+    }
+
+    pub fn to_reported(&self, now: DateTime<Utc>) -> Value {
+        json!({
+            "active": self.is_active(now),
+            "scopes": self.scopes,
+            "started_at": self.started_at,
+            "expires_at": self.expires_at,
+            "ended_at": self.ended_at,
+        })
+    }
+}
+
+pub fn covers(state: Option<&DebugSession>, scope: DebugScope, now: DateTime<Utc>) -> bool {
+    state.is_some_and(|session| session.is_active(now) && session.scopes.contains(&scope))
+}
+
+/// Starts or extends a session for the desired `debug_session` command. A request
+/// arriving during a session extends it to the later expiry and adds its scopes; the
+/// settings saved when the session first started are kept. Returns the elevated
+/// settings to put in force, or None if the request was already handled.
+pub fn request(
+    state: &mut Option<DebugSession>,
+    raw: &Value,
+    current: &BTreeMap<String, u64>,
+    now: DateTime<Utc>,
+) -> Result<Option<BTreeMap<String, u64>>, String> {
+    if state.as_ref().is_some_and(|session| &session.request == raw) {
+        return Ok(None);
+    }
+    let request = parse_request(raw)?;
+    let expires_at = now + Duration::seconds(request.duration_secs as i64);
+    // A session not yet ended still holds the elevated settings, even if it is just due
+    let mut session = match state.take().filter(|session| session.ended_at.is_none()) {
+        Some(mut session) => {
+            session.expires_at = session.expires_at.max(expires_at);
+            session.scopes.extend(request.scopes);
+            session.request = raw.clone();
+            session
+        }
+        None => DebugSession {
+            started_at: now,
+            expires_at,
+            scopes: request.scopes,
+            saved: BTreeMap::new(),
+            ended_at: None,
+            request: raw.clone(),
+        },
+    };
+    // Only settings elevated for the first time are saved; the rest already hold their elevated value
+    for scope in &session.scopes {
+        if let Some((key, value)) = scope.setting().and_then(|key| current.get(key).map(|value| (key, *value))) {
+            session.saved.entry(key.to_string()).or_insert(value);
+        }
+    }
+    let elevated = session.elevated(current);
+    *state = Some(session);
+    Ok(Some(elevated))
+}
+
+/// Ends a session that is due, returning every setting it replaced. Also used at
+/// startup, for a session that expired while the device was down.
+pub fn end_if_due(state: &mut Option<DebugSession>, now: DateTime<Utc>) -> Option<BTreeMap<String, u64>> {
+    let session = state.as_mut().filter(|session| session.ended_at.is_none() && now >= session.expires_at)?;
+    session.ended_at = Some(now);
+    Some(std::mem::take(&mut session.saved))
+}
+
+/// Keeps a change to a setting the session holds for when it ends, instead of putting
+/// it in force. Returns true if the change was kept back.
+pub fn defer(state: &mut Option<DebugSession>, key: &str, value: u64, now: DateTime<Utc>) -> bool {
+    match state.as_mut().filter(|session| session.is_active(now)) {
+        Some(session) => match session.saved.get_mut(key) {
+            Some(saved) => {
+                *saved = value;
+                true
+            }
+            None => false,
+        },
+        None => false,
+    }
+}
+
+/// A log event shipped to the backend during a session.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DebugEvent {
+    pub at: DateTime<Utc>,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+#[derive(Debug, Default)]
+struct StreamCounters {
+    queued: AtomicU64,
+    dropped: AtomicU64, // Queue full, the batch failed to send, or the device was offline
+    sent: AtomicU64,
+}
+
+impl StreamCounters {
+    fn report(&self) -> Value {
+        json!({
+            "queued": self.queued.load(Ordering::Relaxed),
+            "sent": self.sent.load(Ordering::Relaxed),
+            "dropped": self.dropped.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Bounded queue of one device's debug events. Offering never waits: when the queue
+/// is full the event is dropped and counted, so a slow backend cannot stall the device.
+#[derive(Debug, Clone)]
+pub struct DebugSink {
+    sender: mpsc::Sender<DebugEvent>,
+    counters: Arc<StreamCounters>,
+}
+
+impl DebugSink {
+    pub fn new(capacity: usize) -> (Self, mpsc::Receiver<DebugEvent>) {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        (DebugSink { sender, counters: Arc::default() }, receiver)
+    }
+
+    /// Queues an event, returning false if it was dropped.
+    pub fn offer(&self, event: DebugEvent) -> bool {
+        match self.sender.try_send(event) {
+            Ok(()) => {
+                self.counters.queued.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Err(_) => {
+                self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    // The device reports its counters through DebugStream; tests read a bare sink's
+    #[cfg(test)]
+    pub fn report(&self) -> Value {
+        self.counters.report()
+    }
+}
+
+// Sinks of devices with a logs session, keyed by device_id; events are routed by their device_id field
+static SINKS: LazyLock<Mutex<HashMap<String, DebugSink>>> = LazyLock::new(Mutex::default);
+static ACTIVE_SINKS: AtomicUsize = AtomicUsize::new(0); // Lets the layer skip the lock while no session streams logs
+
+fn sinks() -> std::sync::MutexGuard<'static, HashMap<String, DebugSink>> {
+    SINKS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Ships a device's debug events to `/api/devices/{id}/debug` in batches while its
+/// session lasts. Failed batches are dropped rather than retried, as are batches due
+/// while the device is offline. The only sender is the registered sink, so unregistering
+/// it lets the task drain and finish.
+pub struct DebugStream {
+    device_id: String,
+    counters: Arc<StreamCounters>,
+    offline: Arc<AtomicBool>,
+    task: JoinHandle<()>,
+}
+
+impl DebugStream {
+    pub fn spawn(client: Client, config: Config, stats: ApiStats) -> Self {
+        let (sink, mut receiver) = DebugSink::new(QUEUE_EVENTS);
+        let counters = sink.counters.clone();
+        let task_counters = counters.clone();
+        let offline = Arc::new(AtomicBool::new(false));
+        let task_offline = offline.clone();
+        let device_id = config.device_id.clone();
+        let task = tokio::spawn(async move {
+            while let Some(first) = receiver.recv().await {
+                let mut batch = vec![first];
+                let deadline = tokio::time::Instant::now() + BATCH_DELAY;
+                while batch.len() < BATCH_EVENTS {
+                    match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                        Ok(Some(event)) => batch.push(event),
+                        _ => break,
+                    }
+                }
+                if task_offline.load(Ordering::Relaxed) {
+                    task_counters.dropped.fetch_add(batch.len() as u64, Ordering::Relaxed);
+                    continue;
+                }
+                match net::send_debug_events(&client, &config, &stats, &batch).await {
+                    Ok(()) => task_counters.sent.fetch_add(batch.len() as u64, Ordering::Relaxed),
+                    Err(e) => {
+                        warn!(device_id = %config.device_id, error = %e, events = batch.len(), "Dropped debug events the backend did not accept");
+                        task_counters.dropped.fetch_add(batch.len() as u64, Ordering::Relaxed)
+                    }
+                };
+            }
+        }.instrument(info_span!(STREAM_SPAN)));
+        if sinks().insert(device_id.clone(), sink).is_none() {
+            ACTIVE_SINKS.fetch_add(1, Ordering::Relaxed);
+        }
+        DebugStream { device_id, counters, offline, task }
+    }
+
+    /// Follows the device's offline window: no batch is sent while it lasts.
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::Relaxed);
+    }
+
+    pub fn report(&self) -> Value {
+        self.counters.report()
+    }
+
+    fn unregister(&self) {
+        if sinks().remove(&self.device_id).is_some() {
+            ACTIVE_SINKS.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Stops capturing and waits up to `timeout` for queued events to go out.
+    pub async fn close(mut self, timeout: std::time::Duration) {
+        self.unregister();
+        if tokio::time::timeout(timeout, &mut self.task).await.is_err() {
+            debug!(device_id = %self.device_id, "Timed out shipping debug events");
+        }
+    }
+}
+
+impl Drop for DebugStream {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+struct EventVisitor {
+    device_id: Option<String>,
+    message: String,
+}
+
+impl EventVisitor {
+    fn append(&mut self, field: &Field, value: fmt::Arguments) {
+        if self.message.len() >= MESSAGE_BYTES {
+            return;
+        }
+        let _ = match field.name() {
+            "message" => write!(self.message, "{} ", value),
+            "auth_token" => write!(self.message, "auth_token=<redacted> "), // Debug logs leave the device
+            name => write!(self.message, "{}={} ", name, value),
+        };
+        if self.message.len() > MESSAGE_BYTES {
+            let mut end = MESSAGE_BYTES;
+            while !self.message.is_char_boundary(end) {
+                end -= 1;
+            }
+            self.message.truncate(end);
+        }
+    }
+}
+
+impl Visit for EventVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "device_id" {
+            self.device_id = Some(format!("{:?}", value));
+        } else {
+            self.append(field, format_args!("{:?}", value));
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "device_id" {
+            self.device_id = Some(value.to_string());
+        } else {
+            self.append(field, format_args!("{}", value));
+        }
+    }
+}
+
+/// Tracing layer feeding debug-level events into the sink of the device they name, for
+/// devices in a session with the logs scope. Events of the stream itself are skipped.
+pub struct EventLayer;
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for EventLayer {
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if ACTIVE_SINKS.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        if ctx.event_scope(event).is_some_and(|mut scope| scope.any(|span| span.name() == STREAM_SPAN)) {
+            return;
+        }
+        let mut visitor = EventVisitor { device_id: None, message: String::new() };
+        event.record(&mut visitor);
+        let Some(device_id) = visitor.device_id else {
+            return;
+        };
+        let Some(sink) = sinks().get(&device_id).cloned() else {
+            return;
+        };
+        let metadata = event.metadata();
+        sink.offer(DebugEvent {
+            at: Utc::now(),
+            level: metadata.level().as_str().to_string(),
+            target: metadata.target().to_string(),
+            message: visitor.message.trim_end().to_string(),
+        });
+    }
+}
+
+/// The reported `debug_session` section.
+pub fn report(state: Option<&DebugSession>, stream: Option<&DebugStream>, now: DateTime<Utc>) -> Value {
+    let mut reported = state.map_or_else(|| json!({"active": false}), |session| session.to_reported(now));
+    if let Some(stream) = stream {
+        reported["stream"] = stream.report();
+    }
+    reported
+}
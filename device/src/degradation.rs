@@ -0,0 +1,268 @@
+use anyhow::Result;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+use crate::storage;
+use crate::types::Measurement;
+
+const STATE_KEY: &str = "sensor_degradation";
+pub const SENSORS: &[&str] = &["temp", "humidity", "gps"];
+
+/// Distribution sensor lifetimes are drawn from when a sensor is installed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Lifetime {
+    Fixed { hours: f64 },
+    Uniform { min_hours: f64, max_hours: f64 },
+    Weibull { scale_hours: f64, shape: f64 }, // The usual wear-out model; shape > 1 means failures cluster late
+}
+
+impl Default for Lifetime {
+    fn default() -> Self {
+        Lifetime::Weibull { scale_hours: 6.0 * 7.0 * 24.0, shape: 3.0 }
+    }
+}
+
+impl Lifetime {
+    fn sample_secs(&self, rng: &mut StdRng) -> f64 {
+        let hours = match *self {
+            Lifetime::Fixed { hours } => hours,
+            Lifetime::Uniform { min_hours, max_hours } => rng.gen_range(min_hours..=max_hours.max(min_hours)),
+            Lifetime::Weibull { scale_hours, shape } => {
+                let u: f64 = rng.gen();
+                scale_hours * (-(1.0 - u).ln()).powf(1.0 / shape)
+            }
+        };
+        hours.max(0.0) * 3600.0
+    }
+}
+
+/// How wear (age over lifetime) maps to degradation.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Curve {
+    Linear,
+    #[default]
+    Exponential, // Barely noticeable for most of the lifetime, then rapid decline
+}
+
+impl Curve {
+    fn degradation(self, wear: f64) -> f64 {
+        let wear = wear.clamp(0.0, 1.0);
+        match self {
+            Curve::Linear => wear,
+            Curve::Exponential => (4.0 * wear).exp_m1() / 4.0_f64.exp_m1(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SensorDegradationConfig {
+    #[serde(default)]
+    pub lifetime: Lifetime,
+    #[serde(default)]
+    pub curve: Curve,
+    #[serde(default = "default_max_noise")]
+    pub max_noise: f64, // Extra noise amplitude at end of life, in the sensor's units
+    #[serde(default = "default_max_bias")]
+    pub max_bias: f64, // Drift at end of life, in the sensor's units
+    #[serde(default = "default_max_dropout")]
+    pub max_dropout: f64, // Probability a reading drops out at end of life
+}
+
+fn default_max_noise() -> f64 {
+    2.0
+}
+
+fn default_max_bias() -> f64 {
+    1.5
+}
+
+fn default_max_dropout() -> f64 {
+    0.3
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct DegradationConfig {
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default)]
+    pub sensors: BTreeMap<String, SensorDegradationConfig>, // Keyed by temp, humidity or gps
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct SensorState {
+    age_secs: f64,
+    lifetime_secs: f64,
+    bias_sign: f64,
+    failed: bool,
+    last_value: Option<f32>, // Reading a failed temp or humidity sensor is stuck at
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct PersistedState {
+    sensors: BTreeMap<String, SensorState>,
+    installs: u64, // Sensors installed so far; varies the lifetime draw on each replacement
+    last_replace_id: Option<String>,
+}
+
+/// Per-sensor wear model. Progress advances with simulated time, not wall time, and
+/// is checkpointed into the device_state store so it carries across restarts.
+#[derive(Clone)]
+pub struct Degradation {
+    config: DegradationConfig,
+    state: PersistedState,
+    rng: StdRng,
+}
+
+impl Degradation {
+    pub fn new(config: DegradationConfig) -> Self {
+        let rng = StdRng::seed_from_u64(config.seed);
+        let mut degradation = Degradation { config, state: PersistedState::default(), rng };
+        degradation.install_missing();
+        degradation
+    }
+
+    pub fn load(config: DegradationConfig, conn: &Connection) -> Result<Self> {
+        let mut degradation = Self::new(config);
+        if let Some(value) = storage::load_state(conn, STATE_KEY)? {
+            degradation.state = serde_json::from_value(value)?;
+            degradation.install_missing();
+        }
+        Ok(degradation)
+    }
+
+    pub fn checkpoint(&self, conn: &Connection) -> Result<()> {
+        Ok(storage::save_state(conn, STATE_KEY, &serde_json::to_value(&self.state)?)?)
+    }
+
+    // Fresh sensors for configured ones without state; the lifetime draw depends only on seed, sensor and install count
+    fn install_missing(&mut self) {
+        let names: Vec<String> = self.config.sensors.keys().filter(|name| !self.state.sensors.contains_key(*name)).cloned().collect();
+        for name in names {
+            self.install(&name);
+        }
+    }
+
+    fn install(&mut self, name: &str) {
+        let Some(sensor) = self.config.sensors.get(name) else { return };
+        let sensor_seed = name.bytes().fold(self.config.seed ^ self.state.installs.wrapping_mul(0x9e37_79b9_7f4a_7c15), |acc, b| acc.rotate_left(8) ^ b as u64);
+        let mut rng = StdRng::seed_from_u64(sensor_seed);
+        let state = SensorState {
+            age_secs: 0.0,
+            lifetime_secs: sensor.lifetime.sample_secs(&mut rng),
+            bias_sign: if rng.gen_bool(0.5) { 1.0 } else { -1.0 },
+            failed: false,
+            last_value: None,
+        };
+        self.state.installs += 1;
+        self.state.sensors.insert(name.to_string(), state);
+    }
+
+    fn degradation_of(&self, name: &str) -> f64 {
+        let (Some(sensor), Some(state)) = (self.config.sensors.get(name), self.state.sensors.get(name)) else {
+            return 0.0;
+        };
+        if state.lifetime_secs <= 0.0 {
+            return 1.0;
+        }
+        sensor.curve.degradation(state.age_secs / state.lifetime_secs)
+    }
+
+    /// 1.0 for a new sensor, falling towards 0.0 with wear; exactly 0.0 once failed.
+    pub fn health(&self, name: &str) -> Option<f64> {
+        let state = self.state.sensors.get(name)?;
+        Some(if state.failed { 0.0 } else { 1.0 - self.degradation_of(name) })
+    }
+
+    pub fn health_report(&self) -> Value {
+        let scores: BTreeMap<&String, f64> = self.state.sensors.keys()
+            .filter_map(|name| Some((name, self.health(name)?)))
+            .collect();
+        json!(scores)
+    }
+
+    /// Ages every sensor by `elapsed_secs`. Returns sensors that failed during this step.
+    pub fn advance(&mut self, elapsed_secs: f64) -> Vec<String> {
+        let mut failed = Vec::new();
+        for (name, state) in self.state.sensors.iter_mut() {
+            if state.failed {
+                continue;
+            }
+            state.age_secs += elapsed_secs;
+            if state.age_secs >= state.lifetime_secs {
+                state.failed = true;
+                failed.push(name.clone());
+            }
+        }
+        failed
+    }
+
+    /// Applies noise, bias and dropouts to a fresh measurement. A failed GPS emits no
+    /// position; failed temp or humidity sensors are stuck at their last reading and
+    /// flagged as faulted, since the ingest API requires both fields.
+    pub fn apply(&mut self, measurement: &mut Measurement) {
+        let mut faulted = Vec::new();
+        for name in SENSORS {
+            let Some(sensor) = self.config.sensors.get(*name) else { continue };
+            let degradation = self.degradation_of(name);
+            let noise = sensor.max_noise * degradation * self.rng.gen_range(-1.0..=1.0);
+            let dropout = self.rng.gen_bool((sensor.max_dropout * degradation).clamp(0.0, 1.0));
+            let state = self.state.sensors.get_mut(*name).expect("configured sensors are installed");
+            let offset = (sensor.max_bias * degradation * state.bias_sign + noise) as f32;
+
+            let reading = match *name {
+                "temp" => Some(&mut measurement.temp),
+                "humidity" => Some(&mut measurement.humidity),
+                _ => None,
+            };
+            match reading {
+                Some(value) => {
+                    if state.failed || dropout {
+                        *value = state.last_value.unwrap_or(*value);
+                    } else {
+                        *value += offset;
+                        state.last_value = Some(*value);
+                    }
+                }
+                None if state.failed || dropout => {
+                    measurement.latitude = None;
+                    measurement.longitude = None;
+                    measurement.speed = None;
+                    measurement.heading = None;
+                }
+                None => {}
+            }
+            if state.failed {
+                faulted.push(format!("{}_faulted", name));
+            }
+        }
+        if !faulted.is_empty() {
+            measurement.device_flags.get_or_insert_with(Vec::new).extend(faulted);
+        }
+    }
+
+    /// Handles a `replace_sensor` shadow command, `{"id": ..., "sensors": [...]}`.
+    /// Each id is applied once so the command can stay in the desired state.
+    /// Returns the sensors that were replaced.
+    pub fn replace(&mut self, command: &Value) -> Result<Vec<String>> {
+        let id = command.get("id").and_then(Value::as_str).ok_or_else(|| anyhow::anyhow!("replace_sensor needs a string id"))?;
+        if self.state.last_replace_id.as_deref() == Some(id) {
+            return Ok(Vec::new());
+        }
+        let sensors = command.get("sensors").and_then(Value::as_array).ok_or_else(|| anyhow::anyhow!("replace_sensor needs a sensors list"))?;
+        let mut replaced = Vec::new();
+        for name in sensors.iter().filter_map(Value::as_str) {
+            if self.config.sensors.contains_key(name) {
+                self.install(name);
+                replaced.push(name.to_string());
+            }
+        }
+        self.state.last_replace_id = Some(id.to_string());
+        Ok(replaced)
+    }
+}
@@ -0,0 +1,482 @@
+use chrono::{DateTime, Utc};
+use rusqlite::Connection;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use tracing::error;
+
+use crate::anomaly::SelfDetectionConfig;
+use crate::audit::{AuditLog, AuditSource};
+use crate::cadence::CadenceConfig;
+use crate::config::Config;
+use crate::degradation::Degradation;
+use crate::export::SubjectExporter;
+use crate::features::{ApplyMode, Features};
+use crate::fleet::DevicePaths;
+use crate::geo::GeoBucketConfig;
+use crate::network::NetworkType;
+use crate::types::ChaosFlags;
+use crate::{debug_session, maintenance, naming, offline, residency, txn, validation};
+
+const INTERVALS: [&str; 3] = ["sample_interval_secs", "upload_interval_secs", "heartbeat_interval_secs"];
+
+/// What applying a desired document did, or would do, per key. A dry run reports it
+/// under `dry_run_result`.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Outcome {
+    pub apply: BTreeMap<String, Value>, // Keys that changed, with their new value
+    pub clamp: BTreeMap<String, Value>, // Keys that changed to an adjusted value, with that value
+    pub defer: BTreeMap<String, Value>, // Keys held until a debug session ends or the next boot
+    pub reject: BTreeMap<String, String>, // Keys ignored, with why
+    pub unchanged: BTreeSet<String>, // Keys already in force
+    pub restart: BTreeSet<String>, // Components restarted or rebuilt
+}
+
+impl Outcome {
+    pub fn is_empty(&self) -> bool {
+        *self == Outcome::default()
+    }
+
+    fn changed(&mut self, key: &str, value: Value, restarts: &str) {
+        self.apply.insert(key.to_string(), value);
+        self.restart.insert(restarts.to_string());
+    }
+}
+
+/// The device state a desired document changes.
+pub struct Target<'a> {
+    pub config: &'a mut Config,
+    pub features: &'a mut Features,
+    pub sample_interval_secs: &'a mut u64, // Intervals in force, which a debug session may have elevated
+    pub upload_interval_secs: &'a mut u64,
+    pub heartbeat_interval_secs: &'a mut u64,
+    pub degradation: Option<&'a mut Degradation>,
+    pub subject_exporter: &'a mut SubjectExporter,
+}
+
+impl Target<'_> {
+    fn interval(&mut self, key: &str) -> &mut u64 {
+        match key {
+            "sample_interval_secs" => self.sample_interval_secs,
+            "upload_interval_secs" => self.upload_interval_secs,
+            _ => self.heartbeat_interval_secs,
+        }
+    }
+}
+
+/// Where applied changes are kept: the device's store, audit log and files.
+pub struct Storage<'a> {
+    pub conn: &'a Connection,
+    pub audit_log: &'a mut AuditLog,
+    pub paths: &'a DevicePaths,
+}
+
+/// A running component that a change reconfigures or restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+    DebugStream,
+    SelfDetector,
+    CadenceEngine,
+    GeoBuckets,
+    Shedder,
+}
+
+impl Component {
+    fn name(self) -> &'static str {
+        match self {
+            Component::DebugStream => "debug_stream",
+            Component::SelfDetector => "self_detector",
+            Component::CadenceEngine => "cadence_engine",
+            Component::GeoBuckets => "geo_buckets",
+            Component::Shedder => "shedder",
+        }
+    }
+}
+
+/// What applying a document does beyond changing its target. The shadow check puts
+/// changes in force; a dry run has no effects, so the same steps run on copies of the
+/// device's state change nothing real.
+pub trait Effects {
+    /// The store changes are recorded and persisted to; None keeps them in memory.
+    fn storage(&mut self) -> Option<Storage<'_>>;
+    /// Re-arms the timer of an interval now in force.
+    fn interval(&mut self, key: &str, secs: u64);
+    /// Puts the chaos flags now in `config` in force.
+    fn chaos_flags(&mut self, config: &Config);
+    /// Reconfigures or restarts `component` from `config`.
+    fn restart(&mut self, component: Component, config: &Config);
+}
+
+/// Validates and applies a desired document to `target`, key by key, and reports the
+/// outcome. The shadow check and a dry run both go through here; only `effects` differ.
+pub fn apply(target: &mut Target, desired: &Value, effects: &mut impl Effects, now: DateTime<Utc>) -> Outcome {
+    let mut outcome = Outcome::default();
+    chaos_flags(target.config, desired, effects, &mut outcome, now);
+    maintenance(target.config, desired, effects, &mut outcome, now);
+    debug_session(target, desired, effects, &mut outcome, now);
+    for key in INTERVALS {
+        let Some(secs) = desired.get(key).and_then(Value::as_u64) else {
+            continue;
+        };
+        match validation::interval(target.config, key, secs) {
+            Ok(()) => control_interval(target, effects, &mut outcome, key, key, secs, now),
+            Err(e) => {
+                outcome.reject.insert(key.to_string(), e);
+            }
+        }
+    }
+    components(target.config, desired, effects, &mut outcome);
+    device_name(target.config, desired, effects, &mut outcome);
+    region(target.config, desired, effects, &mut outcome);
+    network(target.config, desired, effects, &mut outcome);
+    replace_sensor(target, desired, effects, &mut outcome);
+    features(target, desired, effects, &mut outcome);
+    export_subject_data(target, desired, effects, &mut outcome);
+    config_txn(target, desired, effects, &mut outcome, now);
+    outcome
+}
+
+fn audit(effects: &mut impl Effects, source: AuditSource, key: &str, old: Value, new: Value) {
+    if let Some(storage) = effects.storage() {
+        storage.audit_log.record(source, key, old, new);
+    }
+}
+
+fn restart(effects: &mut impl Effects, outcome: &mut Outcome, component: Component, config: &Config) {
+    effects.restart(component, config);
+    outcome.restart.insert(component.name().to_string());
+}
+
+// Chaos flags are replaced wholesale, cleared when absent, and kept when unparseable
+fn chaos_flags(config: &mut Config, desired: &Value, effects: &mut impl Effects, outcome: &mut Outcome, now: DateTime<Utc>) {
+    let previous = config.chaos_flags.clone();
+    match desired.get("chaos_flags").filter(|value| !value.is_null()).map(ChaosFlags::parse).transpose() {
+        Ok(chaos_flags) => config.chaos_flags = chaos_flags,
+        Err(e) => {
+            outcome.reject.insert("chaos_flags".to_string(), e.to_string());
+        }
+    }
+    if config.chaos_flags != previous {
+        audit(effects, AuditSource::Shadow, "chaos_flags", json!(previous), json!(config.chaos_flags));
+        outcome.apply.insert("chaos_flags".to_string(), json!(config.chaos_flags));
+    }
+    effects.chaos_flags(config);
+
+    let was_offline = config.offline_window.clone();
+    config.offline_window = offline::reconcile(config.offline_window.take(), config.chaos_flags.as_ref().and_then(|chaos| chaos.get("offline")), now);
+    if config.offline_window != was_offline {
+        audit(effects, AuditSource::Shadow, "offline_window", json!(was_offline), json!(config.offline_window));
+        outcome.apply.insert("offline_window".to_string(), json!(config.offline_window));
+    }
+}
+
+// Maintenance also ends when the document no longer asks for it
+fn maintenance(config: &mut Config, desired: &Value, effects: &mut impl Effects, outcome: &mut Outcome, now: DateTime<Utc>) {
+    let was_active = maintenance::is_active(config.maintenance.as_ref(), now);
+    config.maintenance = maintenance::reconcile(config.maintenance.take(), desired.get("maintenance"), now);
+    let is_active = maintenance::is_active(config.maintenance.as_ref(), now);
+    if was_active != is_active {
+        audit(effects, AuditSource::Shadow, "maintenance", json!(was_active), json!(is_active));
+        outcome.apply.insert("maintenance".to_string(), json!(is_active));
+    } else if desired.get("maintenance").is_some() {
+        outcome.unchanged.insert("maintenance".to_string());
+    }
+}
+
+fn debug_session(target: &mut Target, desired: &Value, effects: &mut impl Effects, outcome: &mut Outcome, now: DateTime<Utc>) {
+    let Some(raw) = desired.get("debug_session") else {
+        return;
+    };
+    let current = BTreeMap::from([
+        ("sample_interval_secs".to_string(), *target.sample_interval_secs),
+        ("heartbeat_interval_secs".to_string(), *target.heartbeat_interval_secs),
+    ]);
+    match debug_session::request(&mut target.config.debug_session, raw, &current, now) {
+        Ok(Some(elevated)) => {
+            audit(effects, AuditSource::Shadow, "debug_session", json!(null), raw.clone());
+            outcome.apply.insert("debug_session".to_string(), raw.clone());
+            for (key, secs) in elevated {
+                if put_interval(target, effects, AuditSource::DebugSession, &key, secs) {
+                    outcome.restart.insert(timer(&key));
+                }
+            }
+            restart(effects, outcome, Component::DebugStream, target.config);
+        }
+        Ok(None) => {
+            outcome.unchanged.insert("debug_session".to_string());
+        }
+        Err(e) => {
+            outcome.reject.insert("debug_session".to_string(), e);
+        }
+    }
+}
+
+// Puts an interval in force and re-arms its timer. Returns true if it changed.
+fn put_interval(target: &mut Target, effects: &mut impl Effects, source: AuditSource, key: &str, secs: u64) -> bool {
+    let current = target.interval(key);
+    if *current == secs {
+        return false;
+    }
+    let previous = std::mem::replace(current, secs);
+    audit(effects, source, key, json!(previous), json!(secs));
+    effects.interval(key, secs);
+    true
+}
+
+// An interval from the control plane, reported under `label`. While a debug session
+// holds the setting, it is kept for when the session ends instead.
+fn control_interval(target: &mut Target, effects: &mut impl Effects, outcome: &mut Outcome, label: &str, key: &str, secs: u64, now: DateTime<Utc>) {
+    if debug_session::defer(&mut target.config.debug_session, key, secs, now) {
+        outcome.defer.insert(label.to_string(), json!(secs));
+    } else if put_interval(target, effects, AuditSource::Shadow, key, secs) {
+        outcome.changed(label, json!(secs), &timer(key));
+    } else {
+        outcome.unchanged.insert(label.to_string());
+    }
+}
+
+// Self-detection, field cadences and geohash bucketing, each run by its own component
+fn components(config: &mut Config, desired: &Value, effects: &mut impl Effects, outcome: &mut Outcome) {
+    if let Some(raw) = desired.get("self_detection") {
+        match serde_json::from_value::<SelfDetectionConfig>(raw.clone()) {
+            Ok(detection) if config.self_detection.as_ref() != Some(&detection) => {
+                audit(effects, AuditSource::Shadow, "self_detection", json!(config.self_detection), raw.clone());
+                config.self_detection = Some(detection);
+                outcome.apply.insert("self_detection".to_string(), raw.clone());
+                restart(effects, outcome, Component::SelfDetector, config);
+            }
+            Ok(_) => {
+                outcome.unchanged.insert("self_detection".to_string());
+            }
+            Err(e) => {
+                outcome.reject.insert("self_detection".to_string(), e.to_string());
+            }
+        }
+    }
+
+    if let Some(raw) = desired.get("cadence") {
+        let desired_cadence = serde_json::from_value::<CadenceConfig>(raw.clone())
+            .map_err(|e| e.to_string())
+            .and_then(|desired_cadence| validation::cadence(&desired_cadence).map(|()| desired_cadence));
+        match desired_cadence {
+            Ok(desired_cadence) if config.cadence.as_ref() != Some(&desired_cadence) => {
+                audit(effects, AuditSource::Shadow, "cadence", json!(config.cadence), raw.clone());
+                config.cadence = Some(desired_cadence);
+                outcome.apply.insert("cadence".to_string(), raw.clone());
+                restart(effects, outcome, Component::CadenceEngine, config);
+            }
+            Ok(_) => {
+                outcome.unchanged.insert("cadence".to_string());
+            }
+            Err(e) => {
+                outcome.reject.insert("cadence".to_string(), e);
+            }
+        }
+    }
+
+    if let Some(raw) = desired.get("geo_buckets") {
+        let desired_buckets = match raw {
+            Value::Null => Ok(None),
+            raw => serde_json::from_value::<GeoBucketConfig>(raw.clone())
+                .map_err(|e| e.to_string())
+                .and_then(|settings| validation::geo_buckets(&settings).map(|()| Some(settings))),
+        };
+        match desired_buckets {
+            Ok(desired_buckets) if config.geo_buckets != desired_buckets => {
+                audit(effects, AuditSource::Shadow, "geo_buckets", json!(config.geo_buckets), raw.clone());
+                config.geo_buckets = desired_buckets;
+                outcome.apply.insert("geo_buckets".to_string(), raw.clone());
+                restart(effects, outcome, Component::GeoBuckets, config);
+            }
+            Ok(_) => {
+                outcome.unchanged.insert("geo_buckets".to_string());
+            }
+            Err(e) => {
+                outcome.reject.insert("geo_buckets".to_string(), e);
+            }
+        }
+    }
+}
+
+fn device_name(config: &mut Config, desired: &Value, effects: &mut impl Effects, outcome: &mut Outcome) {
+    let Some(raw) = desired.get("device_name") else {
+        return;
+    };
+    match naming::rename(config, raw) {
+        Ok(Some(previous)) => {
+            audit(effects, AuditSource::Shadow, "device_name", json!(previous), json!(config.device_name));
+            // Surrounding whitespace is trimmed off
+            if raw.as_str() == config.device_name.as_deref() {
+                outcome.apply.insert("device_name".to_string(), raw.clone());
+            } else {
+                outcome.clamp.insert("device_name".to_string(), json!(config.device_name));
+            }
+        }
+        Ok(None) => {
+            outcome.unchanged.insert("device_name".to_string());
+        }
+        Err(e) => {
+            outcome.reject.insert("device_name".to_string(), e.to_string());
+        }
+    }
+}
+
+fn region(config: &mut Config, desired: &Value, effects: &mut impl Effects, outcome: &mut Outcome) {
+    let Some(new_region) = desired.get("region").and_then(Value::as_str) else {
+        return;
+    };
+    if config.region.as_deref() == Some(new_region) {
+        outcome.unchanged.insert("region".to_string());
+        return;
+    }
+    let changed = {
+        let storage = effects.storage();
+        residency::change_region(storage.as_ref().map(|storage| storage.conn), config, new_region)
+    };
+    match changed {
+        Ok(previous) => {
+            audit(effects, AuditSource::Shadow, "region", json!(previous), json!(new_region));
+            // Applied even when unmapped; measurements are then held until it is mapped
+            outcome.changed("region", json!(new_region), "residency");
+            if let Err(e) = residency::target_for(config, Some(new_region)) {
+                outcome.reject.insert("region_endpoint".to_string(), e.to_string());
+            }
+        }
+        Err(e) => {
+            outcome.reject.insert("region".to_string(), format!("{:#}", e));
+        }
+    }
+}
+
+fn network(config: &mut Config, desired: &Value, effects: &mut impl Effects, outcome: &mut Outcome) {
+    let Some(raw) = desired.get("network").and_then(Value::as_str) else {
+        return;
+    };
+    match raw.parse::<NetworkType>() {
+        Ok(next) if config.network == Some(next) => {
+            outcome.unchanged.insert("network".to_string());
+        }
+        Ok(next) => {
+            audit(effects, AuditSource::Shadow, "network", json!(config.network), json!(next));
+            config.network = Some(next);
+            outcome.apply.insert("network".to_string(), json!(next));
+            restart(effects, outcome, Component::Shedder, config);
+        }
+        Err(e) => {
+            outcome.reject.insert("network".to_string(), e.to_string());
+        }
+    }
+}
+
+fn replace_sensor(target: &mut Target, desired: &Value, effects: &mut impl Effects, outcome: &mut Outcome) {
+    let Some(command) = desired.get("replace_sensor") else {
+        return;
+    };
+    let Some(model) = target.degradation.as_deref_mut() else {
+        outcome.reject.insert("replace_sensor".to_string(), "sensor degradation is not simulated".to_string());
+        return;
+    };
+    match model.replace(command) {
+        Ok(replaced) if !replaced.is_empty() => {
+            audit(effects, AuditSource::Shadow, "replace_sensor", json!(null), json!(replaced));
+            outcome.apply.insert("replace_sensor".to_string(), json!(replaced));
+            if let Some(storage) = effects.storage() {
+                if let Err(e) = model.checkpoint(storage.conn) {
+                    error!(device_id = %target.config.device_id, error = %e, "Failed to checkpoint sensor degradation");
+                }
+            }
+        }
+        Ok(_) => {
+            outcome.unchanged.insert("replace_sensor".to_string());
+        }
+        Err(e) => {
+            outcome.reject.insert("replace_sensor".to_string(), e.to_string());
+        }
+    }
+}
+
+fn features(target: &mut Target, desired: &Value, effects: &mut impl Effects, outcome: &mut Outcome) {
+    let Some(desired_features) = desired.get("features") else {
+        return;
+    };
+    for change in target.features.apply_desired(&mut target.config.features, desired_features) {
+        let key = format!("feature.{}", change.name);
+        audit(effects, AuditSource::Shadow, &key, json!(change.old), json!(change.new));
+        match change.apply {
+            ApplyMode::Immediate => {
+                outcome.apply.insert(key, json!(change.new));
+            }
+            ApplyMode::Restart => {
+                outcome.defer.insert(key, json!(change.new));
+                outcome.restart.insert("device".to_string());
+            }
+        }
+    }
+    if !target.features.warnings().is_empty() {
+        outcome.reject.insert("features".to_string(), target.features.warnings().join("; "));
+    }
+}
+
+fn export_subject_data(target: &mut Target, desired: &Value, effects: &mut impl Effects, outcome: &mut Outcome) {
+    let Some(command) = desired.get("export_subject_data") else {
+        return;
+    };
+    let accepted = {
+        let storage = effects.storage();
+        target.subject_exporter.request(command, storage.map(|storage| (storage.conn, storage.audit_log)))
+    };
+    match accepted {
+        Ok(true) => {
+            outcome.apply.insert("export_subject_data".to_string(), command.clone());
+        }
+        Ok(false) => {
+            outcome.unchanged.insert("export_subject_data".to_string());
+        }
+        Err(e) => {
+            outcome.reject.insert("export_subject_data".to_string(), format!("{:#}", e));
+        }
+    }
+}
+
+// Transactional changes: validated together, applied all-or-nothing
+fn config_txn(target: &mut Target, desired: &Value, effects: &mut impl Effects, outcome: &mut Outcome, now: DateTime<Utc>) {
+    let Some(txn_value) = desired.get("config_txn") else {
+        return;
+    };
+    let applied = {
+        let storage = effects.storage();
+        let files = storage.as_ref().map(|storage| (storage.paths.staged_txn.as_path(), storage.paths.config.as_path()));
+        txn::apply(target.config, txn_value, files)
+    };
+    match applied {
+        Ok(Some(txn_outcome)) => {
+            audit(effects, AuditSource::Shadow, "config_txn", json!(null), json!(txn_outcome));
+            for (key, reason) in &txn_outcome.errors {
+                outcome.reject.insert(format!("config_txn.{}", key), reason.clone());
+            }
+            for key in &txn_outcome.applied {
+                let label = format!("config_txn.{}", key);
+                match key.as_str() {
+                    "sample_interval_secs" => control_interval(target, effects, outcome, &label, key, target.config.sample_interval_secs, now),
+                    "upload_interval_secs" => control_interval(target, effects, outcome, &label, key, target.config.upload_interval_secs, now),
+                    "heartbeat_interval_secs" => control_interval(target, effects, outcome, &label, key, target.config.heartbeat_interval_secs, now),
+                    "upload_batch_size" => {
+                        outcome.apply.insert(label, json!(target.config.upload_batch_size));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(None) => {
+            outcome.unchanged.insert("config_txn".to_string());
+        }
+        Err(e) => {
+            outcome.reject.insert("config_txn".to_string(), format!("{:#}", e));
+        }
+    }
+}
+
+// sample_interval_secs is timed by sample_timer
+fn timer(key: &str) -> String {
+    format!("{}_timer", key.trim_end_matches("_interval_secs"))
+}
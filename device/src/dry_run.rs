@@ -0,0 +1,63 @@
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::degradation::Degradation;
+use crate::desired::{self, Component, Effects, Outcome, Storage, Target};
+use crate::export::SubjectExporter;
+use crate::features::Features;
+
+/// Whether a desired document only asks what it would do: `"dry_run": true`.
+pub fn requested(desired: &Value) -> bool {
+    desired.get("dry_run").and_then(Value::as_bool).unwrap_or(false)
+}
+
+/// The device state a desired document is previewed against. Only borrowed, so working
+/// out a preview cannot change the device.
+#[derive(Clone, Copy)]
+pub struct Runtime<'a> {
+    pub config: &'a Config,
+    pub features: &'a Features,
+    pub sample_interval_secs: u64, // Intervals in force, which a debug session may have elevated
+    pub upload_interval_secs: u64,
+    pub heartbeat_interval_secs: u64,
+    pub degradation: Option<&'a Degradation>,
+    pub subject_exporter: &'a SubjectExporter,
+}
+
+// A dry run's effects: none. Nothing is recorded, persisted, re-armed or restarted.
+struct Sandbox;
+
+impl Effects for Sandbox {
+    fn storage(&mut self) -> Option<Storage<'_>> {
+        None
+    }
+
+    fn interval(&mut self, _key: &str, _secs: u64) {}
+
+    fn chaos_flags(&mut self, _config: &Config) {}
+
+    fn restart(&mut self, _component: Component, _config: &Config) {}
+}
+
+/// Applies a desired document the way the shadow check does, through the same steps,
+/// but to copies of the device's state and without effects, and reports the outcome
+/// per key, `replace_sensor` and `export_subject_data` included.
+pub fn preview(runtime: Runtime, desired: &Value, now: DateTime<Utc>) -> Outcome {
+    let mut config = runtime.config.clone();
+    let mut features = runtime.features.clone();
+    let (mut sample_interval_secs, mut upload_interval_secs, mut heartbeat_interval_secs) =
+        (runtime.sample_interval_secs, runtime.upload_interval_secs, runtime.heartbeat_interval_secs);
+    let mut degradation = runtime.degradation.cloned();
+    let mut subject_exporter = runtime.subject_exporter.clone();
+    let mut target = Target {
+        config: &mut config,
+        features: &mut features,
+        sample_interval_secs: &mut sample_interval_secs,
+        upload_interval_secs: &mut upload_interval_secs,
+        heartbeat_interval_secs: &mut heartbeat_interval_secs,
+        degradation: degradation.as_mut(),
+        subject_exporter: &mut subject_exporter,
+    };
+    desired::apply(&mut target, desired, &mut Sandbox, now)
+}
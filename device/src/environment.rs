@@ -0,0 +1,184 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use crate::config::InvalidSetting;
+
+/// A place several devices share, such as a cold room or a reefer truck, and how its air
+/// temperature behaves. Members are given by fleet index.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EnvironmentConfig {
+    pub setpoint_c: f32, // Held while the cooling works and the doors are shut
+    #[serde(default = "default_ambient_c")]
+    pub ambient_c: f32, // Outside air, which the inside drifts towards without cooling
+    #[serde(default = "default_cooling_tau_secs")]
+    pub cooling_tau_secs: f64, // Time constant of the pull back down to the setpoint
+    #[serde(default = "default_warming_tau_secs")]
+    pub warming_tau_secs: f64, // Time constant of the drift to ambient after a cooling failure
+    #[serde(default = "default_door_tau_secs")]
+    pub door_tau_secs: f64, // Time constant of the warming while a door is open
+    #[serde(default)]
+    pub members: Vec<usize>,
+}
+
+fn default_ambient_c() -> f32 {
+    22.0
+}
+
+fn default_cooling_tau_secs() -> f64 {
+    600.0
+}
+
+fn default_warming_tau_secs() -> f64 {
+    1800.0
+}
+
+fn default_door_tau_secs() -> f64 {
+    120.0
+}
+
+/// Something that happens to a whole environment, and so to every device in it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EnvironmentEvent {
+    CoolingFailure,
+    CoolingRestored,
+    DoorOpen { secs: u64 },
+}
+
+/// Where an environment stands, as reported by the fleet control API.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EnvironmentState {
+    pub temp_c: f32,
+    pub cooling_failed: bool,
+    pub door_open_until: Option<DateTime<Utc>>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// One environment's air temperature, relaxing towards the setpoint or, with the cooling
+/// failed or a door open, towards ambient. Devices sampling at their own times all move it
+/// forward; a sample older than the last update reads the current temperature.
+#[derive(Debug)]
+pub struct Environment {
+    config: EnvironmentConfig,
+    state: EnvironmentState,
+}
+
+impl Environment {
+    pub fn new(config: EnvironmentConfig, now: DateTime<Utc>) -> Self {
+        let state = EnvironmentState { temp_c: config.setpoint_c, cooling_failed: false, door_open_until: None, updated_at: now };
+        Environment { config, state }
+    }
+
+    /// Moves the model forward to `now` and returns the air temperature then.
+    pub fn temperature_at(&mut self, now: DateTime<Utc>) -> f32 {
+        // A door closing part way through splits the step, as the target changes there
+        if let Some(closes) = self.state.door_open_until.filter(|closes| *closes <= now) {
+            self.relax(closes);
+            self.state.door_open_until = None;
+        }
+        self.relax(now);
+        self.state.temp_c
+    }
+
+    pub fn apply(&mut self, event: &EnvironmentEvent, now: DateTime<Utc>) -> EnvironmentState {
+        self.temperature_at(now);
+        match event {
+            EnvironmentEvent::CoolingFailure => self.state.cooling_failed = true,
+            EnvironmentEvent::CoolingRestored => self.state.cooling_failed = false,
+            EnvironmentEvent::DoorOpen { secs } => {
+                let closes = now + chrono::Duration::seconds(*secs as i64);
+                self.state.door_open_until = Some(self.state.door_open_until.map_or(closes, |open| open.max(closes)));
+            }
+        }
+        self.state.clone()
+    }
+
+    pub fn state(&self) -> &EnvironmentState {
+        &self.state
+    }
+
+    fn relax(&mut self, until: DateTime<Utc>) {
+        let elapsed_secs = (until - self.state.updated_at).num_milliseconds() as f64 / 1000.0;
+        if elapsed_secs <= 0.0 {
+            return;
+        }
+        let (target, tau) = if self.state.door_open_until.is_some() {
+            (self.config.ambient_c, self.config.door_tau_secs)
+        } else if self.state.cooling_failed {
+            (self.config.ambient_c, self.config.warming_tau_secs)
+        } else {
+            (self.config.setpoint_c, self.config.cooling_tau_secs)
+        };
+        let remaining = (-elapsed_secs / tau.max(1.0)).exp() as f32;
+        self.state.temp_c = target + (self.state.temp_c - target) * remaining;
+        self.state.updated_at = until;
+    }
+}
+
+/// A member's handle on the one model instance of its environment.
+#[derive(Debug, Clone)]
+pub struct SharedEnvironment {
+    pub name: String,
+    model: Arc<Mutex<Environment>>,
+}
+
+impl SharedEnvironment {
+    pub fn temperature_at(&self, now: DateTime<Utc>) -> f32 {
+        self.model.lock().unwrap().temperature_at(now)
+    }
+}
+
+/// Every named environment of a fleet, shared by its member devices and the runner.
+#[derive(Debug, Clone, Default)]
+pub struct Environments {
+    models: Arc<BTreeMap<String, Arc<Mutex<Environment>>>>,
+    members: Arc<HashMap<usize, String>>,
+}
+
+impl Environments {
+    /// A device can only be in one place, so each fleet index belongs to one environment at most.
+    pub fn new(configs: BTreeMap<String, EnvironmentConfig>, now: DateTime<Utc>) -> Result<Self> {
+        let mut members = HashMap::new();
+        for (name, config) in &configs {
+            for index in &config.members {
+                if let Some(other) = members.insert(*index, name.clone()) {
+                    bail!("fleet device {} is in both {} and {}", index, other, name);
+                }
+            }
+        }
+        let models = configs.into_iter().map(|(name, config)| (name, Arc::new(Mutex::new(Environment::new(config, now))))).collect();
+        Ok(Environments { models: Arc::new(models), members: Arc::new(members) })
+    }
+
+    /// Environments from FLEET_ENVIRONMENTS, a JSON object of EnvironmentConfig by name.
+    pub fn from_env_vars(vars: &HashMap<String, String>, now: DateTime<Utc>) -> Result<Self> {
+        let Some(raw) = vars.get("FLEET_ENVIRONMENTS") else {
+            return Ok(Environments::default());
+        };
+        let configs = serde_json::from_str(raw).context(InvalidSetting("FLEET_ENVIRONMENTS"))?;
+        Environments::new(configs, now).context(InvalidSetting("FLEET_ENVIRONMENTS"))
+    }
+
+    /// The environment the fleet device with `index` is in, if any.
+    pub fn for_member(&self, index: usize) -> Option<SharedEnvironment> {
+        let name = self.members.get(&index)?;
+        Some(SharedEnvironment { name: name.clone(), model: self.models[name].clone() })
+    }
+
+    /// Applies an event to the named environment; None if there is no such environment.
+    pub fn inject(&self, name: &str, event: &EnvironmentEvent, now: DateTime<Utc>) -> Option<EnvironmentState> {
+        Some(self.models.get(name)?.lock().unwrap().apply(event, now))
+    }
+
+    pub fn report(&self, now: DateTime<Utc>) -> BTreeMap<String, EnvironmentState> {
+        self.models.iter().map(|(name, model)| {
+            let mut model = model.lock().unwrap();
+            model.temperature_at(now);
+            (name.clone(), model.state().clone())
+        }).collect()
+    }
+}
@@ -0,0 +1,212 @@
+use reqwest::StatusCode;
+use std::error::Error;
+use std::fmt;
+
+use crate::config::{ConfigError, InvalidSetting};
+use crate::net::NetError;
+use crate::ota::{ChecksumMismatch, MetadataRejection, OtaError, SignatureRejection};
+use crate::residency::ResidencyError;
+use crate::shutdown::SignalsUnavailable;
+use crate::slots::SlotFault;
+use crate::storage::StorageError;
+
+/// Why a device stopped on an error, sorted by what whoever runs it can do about it:
+/// retry, alert or give up. Each variant wraps its module's error as its source, with
+/// the messages of what the device was doing when it failed, outermost first, as
+/// `context`. Devices are sorted where their run ends, in lib's stopped_on_error.
+#[derive(Debug, thiserror::Error)]
+pub enum DeviceError {
+    /// A setting the device cannot start with, see config::InvalidSetting, or a config
+    /// file it cannot read or write. Not retryable: the config has to change first.
+    #[error("configuration invalid")]
+    Config { context: Vec<String>, #[source] source: ConfigError },
+    /// The measurement database or the data directory cannot be used. Retryable only
+    /// when SQLite found the database busy or locked by another connection.
+    #[error("local storage unusable")]
+    Storage { context: Vec<String>, #[source] source: StorageError },
+    /// The backend could not be reached or refused a request. Retryable unless it
+    /// refused with a 4xx other than 408 and 429, as when it rejects a registration,
+    /// answered with a body that is not what the endpoint returns, or the data has no
+    /// endpoint it may be sent to.
+    #[error("backend request failed")]
+    Net { context: Vec<String>, #[source] source: NetError },
+    /// A firmware image or its metadata failed verification, no slot can be booted, or
+    /// the OTA state cannot be kept. Retryable only for a checksum mismatch, which a
+    /// fresh download may not repeat.
+    #[error("firmware update failed")]
+    Ota { context: Vec<String>, #[source] source: OtaError },
+    /// The process cannot listen for the signals that stop it cleanly. Not retryable.
+    #[error("shutdown handling unavailable")]
+    Shutdown { context: Vec<String>, #[source] source: SignalsUnavailable },
+    /// Anything without a typed cause. Not retryable, as nothing is known about it.
+    #[error("device failed")]
+    Other(#[source] Box<dyn Error + Send + Sync>),
+}
+
+impl From<ConfigError> for DeviceError {
+    fn from(source: ConfigError) -> Self {
+        DeviceError::Config { context: Vec::new(), source }
+    }
+}
+
+impl From<StorageError> for DeviceError {
+    fn from(source: StorageError) -> Self {
+        DeviceError::Storage { context: Vec::new(), source }
+    }
+}
+
+impl From<NetError> for DeviceError {
+    fn from(source: NetError) -> Self {
+        DeviceError::Net { context: Vec::new(), source }
+    }
+}
+
+impl From<OtaError> for DeviceError {
+    fn from(source: OtaError) -> Self {
+        DeviceError::Ota { context: Vec::new(), source }
+    }
+}
+
+impl From<SignalsUnavailable> for DeviceError {
+    fn from(source: SignalsUnavailable) -> Self {
+        DeviceError::Shutdown { context: Vec::new(), source }
+    }
+}
+
+/// Sorts an error from the modules that still return anyhow by the typed error in its
+/// chain. The links above it become the context; without one it is Other.
+impl From<anyhow::Error> for DeviceError {
+    fn from(error: anyhow::Error) -> Self {
+        match sort(error) {
+            Ok(unsorted) => DeviceError::Other(unsorted.into()),
+            Err(sorted) => sorted,
+        }
+    }
+}
+
+// Passes on an error without a typed cause; returns the sorted error as Err, so each
+// step can end the sorting with `?`
+fn sort(error: anyhow::Error) -> Result<anyhow::Error, DeviceError> {
+    // Marked settings win over whatever failed on them
+    let error = invalid_setting(error)?;
+    let error = take(error, |context, source: ConfigError| DeviceError::Config { context, source })?;
+    let error = take(error, |context, source: SignalsUnavailable| DeviceError::Shutdown { context, source })?;
+    let error = take(error, |context, source: OtaError| DeviceError::Ota { context, source })?;
+    let error = take(error, |context, source: SlotFault| DeviceError::Ota { context, source: source.into() })?;
+    let error = take(error, |context, source: ChecksumMismatch| DeviceError::Ota { context, source: source.into() })?;
+    let error = take(error, |context, source: SignatureRejection| DeviceError::Ota { context, source: source.into() })?;
+    let error = take(error, |context, source: MetadataRejection| DeviceError::Ota { context, source: source.into() })?;
+    let error = take(error, |context, source: NetError| DeviceError::Net { context, source })?;
+    let error = take(error, |context, source: ResidencyError| DeviceError::Net { context, source: source.into() })?;
+    let error = take(error, |context, source: reqwest::Error| {
+        let endpoint = source.url().map_or_else(|| "the backend".to_string(), |url| url.path().to_string());
+        DeviceError::Net { context, source: NetError::Transport { endpoint, source } }
+    })?;
+    let error = take(error, |context, source: StorageError| DeviceError::Storage { context, source })?;
+    let error = take(error, |context, source: rusqlite::Error| DeviceError::Storage { context, source: source.into() })?;
+    take(error, |context, source: std::io::Error| DeviceError::Storage { context, source: source.into() })
+}
+
+// Takes the first link of type E out of `error`, unless it is only reachable as the
+// source of another link
+fn take<E>(error: anyhow::Error, sorted: impl FnOnce(Vec<String>, E) -> DeviceError) -> Result<anyhow::Error, DeviceError>
+where
+    E: Error + Send + Sync + 'static,
+{
+    let Some(at) = error.chain().position(|link| link.is::<E>()) else {
+        return Ok(error);
+    };
+    let context = error.chain().take(at).map(ToString::to_string).collect();
+    match error.downcast::<E>() {
+        Ok(source) => Err(sorted(context, source)),
+        Err(error) => Ok(error),
+    }
+}
+
+// The marker is context on what failed, which taking the marker would drop, so what
+// failed is kept as the messages of its chain. Added as context the marker is wrapped,
+// so its link is found by its message.
+fn invalid_setting(error: anyhow::Error) -> Result<anyhow::Error, DeviceError> {
+    let Some(setting) = error.downcast_ref::<InvalidSetting>().cloned() else {
+        return Ok(error);
+    };
+    let marker = setting.to_string();
+    let at = error.chain().position(|link| link.is::<InvalidSetting>() || link.to_string() == marker).unwrap_or_default();
+    let links: Vec<String> = error.chain().map(ToString::to_string).collect();
+    let source = links[at + 1..].iter().rev().fold(None, |source, message| Some(Box::new(Link { message: message.clone(), source })));
+    Err(DeviceError::Config {
+        context: links[..at].to_vec(),
+        source: ConfigError::Invalid { setting, source: source.map(|link| link as Box<dyn Error + Send + Sync>) },
+    })
+}
+
+// One link of an error chain, kept as its message
+#[derive(Debug)]
+struct Link {
+    message: String,
+    source: Option<Box<Link>>,
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for Link {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref().map(|link| link as &(dyn Error + 'static))
+    }
+}
+
+impl DeviceError {
+    /// Name of the variant, for logs and exit reports.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DeviceError::Config { .. } => "config",
+            DeviceError::Storage { .. } => "storage",
+            DeviceError::Net { .. } => "net",
+            DeviceError::Ota { .. } => "ota",
+            DeviceError::Shutdown { .. } => "shutdown",
+            DeviceError::Other(_) => "other",
+        }
+    }
+
+    /// What the device was doing when it failed, outermost first.
+    pub fn context(&self) -> &[String] {
+        match self {
+            DeviceError::Config { context, .. }
+            | DeviceError::Storage { context, .. }
+            | DeviceError::Net { context, .. }
+            | DeviceError::Ota { context, .. }
+            | DeviceError::Shutdown { context, .. } => context,
+            DeviceError::Other(_) => &[],
+        }
+    }
+
+    /// The context, the error and its sources on one line, as `{:#}` prints an anyhow
+    /// error.
+    pub fn report(&self) -> String {
+        let mut links = self.context().to_vec();
+        let mut link: Option<&(dyn Error + 'static)> = Some(self);
+        while let Some(error) = link {
+            links.push(error.to_string());
+            link = error.source();
+        }
+        links.join(": ")
+    }
+
+    /// Whether running the device again, unchanged, may succeed; see each variant.
+    pub fn retryable(&self) -> bool {
+        match self {
+            DeviceError::Storage { source, .. } => source.is_busy(),
+            DeviceError::Net { source: NetError::Residency(_) | NetError::Decode(_), .. } => false,
+            // Without a status the request never got an answer
+            DeviceError::Net { source, .. } => source
+                .status()
+                .is_none_or(|status| !status.is_client_error() || matches!(status, StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS)),
+            DeviceError::Ota { source, .. } => matches!(source, OtaError::Checksum(_)),
+            DeviceError::Config { .. } | DeviceError::Shutdown { .. } | DeviceError::Other(_) => false,
+        }
+    }
+}
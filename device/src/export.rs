@@ -0,0 +1,307 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::PathBuf;
+use tracing::{error, info, warn};
+
+use crate::audit::{AuditLog, AuditSource};
+use crate::auth;
+use crate::config::Config;
+use crate::net;
+use crate::stats::ApiStats;
+use crate::storage;
+
+const STATE_KEY: &str = "subject_export";
+/// Purpose tag the archive is uploaded under.
+pub const PURPOSE: &str = "compliance";
+// Finished export ids remembered so a desired command left in the shadow is not run twice
+const MAX_FINISHED: usize = 50;
+
+/// Data-subject export requested through the desired shadow as `export_subject_data`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportRequest {
+    pub id: String,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    #[serde(default)]
+    pub delete: bool, // Irreversibly delete the exported measurements once the archive is uploaded
+}
+
+/// What went into an archive: enough to check it and to delete exactly what it holds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArchiveSummary {
+    pub file_name: String,
+    pub sha256: String, // Of the whole archive
+    pub bytes: u64,
+    pub measurement_ids: Vec<i64>,
+    pub audit_sequences: Vec<u64>,
+}
+
+/// Where an export stands. Each step is persisted before the next starts, so a restart
+/// picks up where the previous run stopped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+pub enum ExportPhase {
+    Requested,
+    Packaged { archive: ArchiveSummary },
+    Uploaded { archive: ArchiveSummary, file_id: String },
+    Completed { archive: ArchiveSummary, file_id: String, deleted: bool },
+    Failed { error: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportJob {
+    pub request: ExportRequest,
+    #[serde(flatten)]
+    pub phase: ExportPhase,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ExportJob {
+    fn finished(&self) -> bool {
+        matches!(self.phase, ExportPhase::Completed { .. } | ExportPhase::Failed { .. })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+struct ExportState {
+    job: Option<ExportJob>,
+    finished: Vec<String>, // Oldest first
+}
+
+/// Runs data-subject exports: packages every locally held measurement and audit entry in
+/// the requested range as a signed archive, uploads it and, when asked to, deletes the
+/// exported measurements. Every step is written to the audit log, so a partial failure
+/// leaves a record of exactly what was exported and deleted. The audit log itself is
+/// exported but never deleted, as it holds that record.
+#[derive(Clone)]
+pub struct SubjectExporter {
+    state: ExportState,
+    archive_dir: PathBuf,
+}
+
+impl SubjectExporter {
+    pub fn load(conn: &Connection, archive_dir: PathBuf) -> Result<Self> {
+        let state = match storage::load_state(conn, STATE_KEY)? {
+            Some(value) => serde_json::from_value(value)?,
+            None => ExportState::default(),
+        };
+        Ok(SubjectExporter { state, archive_dir })
+    }
+
+    pub fn job(&self) -> Option<&ExportJob> {
+        self.state.job.as_ref()
+    }
+
+    /// Accepts a desired `export_subject_data` command, recording and checkpointing it
+    /// in `storage` when given. Returns false for an export that has already been
+    /// accepted; only one export runs at a time.
+    pub fn request(&mut self, raw: &Value, storage: Option<(&Connection, &mut AuditLog)>) -> Result<bool> {
+        let request: ExportRequest = serde_json::from_value(raw.clone()).context("invalid export_subject_data command")?;
+        if self.state.finished.contains(&request.id) || self.job().is_some_and(|job| job.request.id == request.id) {
+            return Ok(false);
+        }
+        if let Some(job) = self.job().filter(|job| !job.finished()) {
+            bail!("export {} is still in progress", job.request.id);
+        }
+        if request.from > request.to {
+            bail!("export range starts after it ends ({} > {})", request.from, request.to);
+        }
+        let recorded = json!(request);
+        self.state.job = Some(ExportJob { request, phase: ExportPhase::Requested, updated_at: Utc::now() });
+        if let Some((conn, audit_log)) = storage {
+            audit_log.record(AuditSource::Shadow, "subject_export_requested", json!(null), recorded);
+            self.checkpoint(conn)?;
+        }
+        Ok(true)
+    }
+
+    /// Takes the current export as far as it goes. A failed upload is retried at the next call.
+    pub async fn advance(&mut self, client: &Client, config: &Config, stats: &ApiStats, conn: &mut Connection, audit_log: &mut AuditLog) -> Result<()> {
+        while let Some(job) = self.state.job.clone().filter(|job| !job.finished()) {
+            let id = job.request.id.clone();
+            let next = match job.phase {
+                ExportPhase::Requested => match self.package(config, conn, audit_log, &job.request) {
+                    Ok(archive) => {
+                        audit_log.record(AuditSource::Shadow, "subject_export_packaged", json!(null), json!({
+                            "id": id,
+                            "sha256": archive.sha256,
+                            "bytes": archive.bytes,
+                            "measurements": archive.measurement_ids.len(),
+                            "audit_entries": archive.audit_sequences.len(),
+                        }));
+                        ExportPhase::Packaged { archive }
+                    }
+                    Err(e) => {
+                        error!(device_id = %config.device_id, export_id = %id, error = %e, "Failed to package subject data export");
+                        ExportPhase::Failed { error: format!("packaging failed: {:#}", e) }
+                    }
+                },
+                ExportPhase::Packaged { archive } => {
+                    let path = self.archive_dir.join(&archive.file_name);
+                    let bytes = match fs::read(&path) {
+                        Ok(bytes) if sha256_hex(&bytes) == archive.sha256 => bytes,
+                        _ => {
+                            // Nothing has been deleted yet, so the range can be packaged again
+                            warn!(device_id = %config.device_id, export_id = %id, path = %path.display(), "Export archive missing or changed; packaging it again");
+                            audit_log.record(AuditSource::Shadow, "subject_export_repackaging", json!({"id": id, "sha256": archive.sha256}), json!(null));
+                            self.set_phase(conn, ExportPhase::Requested)?;
+                            continue;
+                        }
+                    };
+                    match net::upload_file(client, config, stats, PURPOSE, &archive.file_name, &bytes, &archive.sha256).await {
+                        Ok(uploaded) => {
+                            audit_log.record(AuditSource::Shadow, "subject_export_uploaded", json!(null), json!({
+                                "id": id,
+                                "sha256": archive.sha256,
+                                "file_id": uploaded.file_id,
+                                "purpose": PURPOSE,
+                            }));
+                            ExportPhase::Uploaded { archive, file_id: uploaded.file_id }
+                        }
+                        Err(e) => {
+                            warn!(device_id = %config.device_id, export_id = %id, error = %e, "Failed to upload subject data export; retrying later");
+                            audit_log.record(AuditSource::Shadow, "subject_export_upload_failed", json!(null), json!({"id": id, "sha256": archive.sha256, "error": format!("{:#}", e)}));
+                            return Ok(());
+                        }
+                    }
+                }
+                ExportPhase::Uploaded { archive, file_id } => {
+                    if job.request.delete {
+                        // Deleting by id is idempotent, so a crash here just repeats it
+                        let removed = storage::delete_measurements(conn, &archive.measurement_ids)?;
+                        audit_log.record(AuditSource::Shadow, "subject_data_deleted", json!(null), json!({
+                            "id": id,
+                            "from": job.request.from,
+                            "to": job.request.to,
+                            "archive_sha256": archive.sha256,
+                            "file_id": file_id,
+                            "measurement_ids": archive.measurement_ids,
+                            "removed_now": removed,
+                            "retained": ["audit_log"],
+                        }));
+                        info!(device_id = %config.device_id, export_id = %id, measurements = archive.measurement_ids.len(), removed, "Deleted exported subject data");
+                    }
+                    if let Err(e) = fs::remove_file(self.archive_dir.join(&archive.file_name)) {
+                        if e.kind() != std::io::ErrorKind::NotFound {
+                            warn!(device_id = %config.device_id, export_id = %id, error = %e, "Failed to remove uploaded export archive");
+                        }
+                    }
+                    audit_log.record(AuditSource::Shadow, "subject_export_completed", json!(null), json!({"id": id, "file_id": file_id, "deleted": job.request.delete}));
+                    ExportPhase::Completed { archive, file_id, deleted: job.request.delete }
+                }
+                ExportPhase::Completed { .. } | ExportPhase::Failed { .. } => unreachable!("finished jobs are filtered out above"),
+            };
+            if let ExportPhase::Failed { error } = &next {
+                audit_log.record(AuditSource::Shadow, "subject_export_failed", json!(null), json!({"id": id, "error": error}));
+            }
+            self.set_phase(conn, next)?;
+        }
+        Ok(())
+    }
+
+    // Writes the archive into the archive directory and returns what it holds
+    fn package(&self, config: &Config, conn: &Connection, audit_log: &AuditLog, request: &ExportRequest) -> Result<ArchiveSummary> {
+        let measurements = storage::measurements_between(conn, request.from, request.to)?;
+        let audit_records = audit_log.records_between(request.from, request.to)?;
+        let created_at = Utc::now();
+
+        let entries = [
+            ("measurements.json", serde_json::to_vec_pretty(&measurements.iter().map(|row| json!({"id": row.id, "measurement": row.measurement})).collect::<Vec<_>>())?, measurements.len()),
+            ("audit.json", serde_json::to_vec_pretty(&audit_records)?, audit_records.len()),
+        ];
+        let manifest = serde_json::to_vec_pretty(&json!({
+            "export_id": request.id,
+            "device_id": config.device_id,
+            "from": request.from,
+            "to": request.to,
+            "created_at": created_at,
+            "purpose": PURPOSE,
+            "entries": entries.iter().map(|(name, body, records)| json!({
+                "name": name,
+                "sha256": sha256_hex(body),
+                "bytes": body.len(),
+                "records": records,
+            })).collect::<Vec<_>>(),
+        }))?;
+        let signature = sign(&auth::current_token(config)?, &manifest);
+
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (name, body) in entries.iter().map(|(name, body, _)| (*name, body.as_slice())).chain([("manifest.json", manifest.as_slice()), ("manifest.sig", signature.as_bytes())]) {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(body.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(created_at.timestamp().max(0) as u64);
+            header.set_cksum();
+            builder.append_data(&mut header, name, body)?;
+        }
+        let archive = builder.into_inner()?.finish()?;
+
+        let file_name = format!("subject_export_{}.tar.gz", request.id);
+        fs::create_dir_all(&self.archive_dir)?;
+        fs::write(self.archive_dir.join(&file_name), &archive)?;
+        info!(device_id = %config.device_id, export_id = %request.id, measurements = measurements.len(), audit_entries = audit_records.len(), bytes = archive.len(), "Packaged subject data export");
+        Ok(ArchiveSummary {
+            file_name,
+            sha256: sha256_hex(&archive),
+            bytes: archive.len() as u64,
+            measurement_ids: measurements.iter().map(|row| row.id).collect(),
+            audit_sequences: audit_records.iter().map(|record| record.sequence).collect(),
+        })
+    }
+
+    fn set_phase(&mut self, conn: &Connection, phase: ExportPhase) -> Result<()> {
+        if let Some(job) = self.state.job.as_mut() {
+            job.phase = phase;
+            job.updated_at = Utc::now();
+            if job.finished() {
+                self.state.finished.push(job.request.id.clone());
+                let excess = self.state.finished.len().saturating_sub(MAX_FINISHED);
+                self.state.finished.drain(..excess);
+            }
+        }
+        self.checkpoint(conn)
+    }
+
+    fn checkpoint(&self, conn: &Connection) -> Result<()> {
+        Ok(storage::save_state(conn, STATE_KEY, &serde_json::to_value(&self.state)?)?)
+    }
+
+    pub fn report(&self) -> Value {
+        match self.job() {
+            Some(job) => json!({
+                "id": job.request.id,
+                "phase": serde_json::to_value(&job.phase).ok().and_then(|phase| phase.get("phase").cloned()),
+                "file_id": match &job.phase {
+                    ExportPhase::Uploaded { file_id, .. } | ExportPhase::Completed { file_id, .. } => Some(file_id),
+                    _ => None,
+                },
+                "error": match &job.phase {
+                    ExportPhase::Failed { error } => Some(error),
+                    _ => None,
+                },
+                "updated_at": job.updated_at,
+            }),
+            None => json!(null),
+        }
+    }
+}
+
+/// Hex HMAC-SHA256 of `data`, keyed with the device's auth token so the backend can check it.
+pub fn sign(key: &str, data: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -0,0 +1,198 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tracing::{debug, info, warn};
+
+use crate::types::Measurement;
+
+/// Where an external co-simulator delivers NDJSON measurement records.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExternalSourceKind {
+    Stdin,
+    Unix { socket_path: String },
+}
+
+/// How records arriving faster than the sample interval are consumed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Backpressure {
+    #[default]
+    Latest, // Sample the newest record and drop the rest
+    Buffer, // Consume records in order from a bounded queue
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExternalSourceConfig {
+    pub kind: ExternalSourceKind,
+    #[serde(default = "default_stall_timeout_secs")]
+    pub stall_timeout_secs: u64,
+    #[serde(default)]
+    pub backpressure: Backpressure,
+    #[serde(default = "default_buffer_size")]
+    pub buffer_size: usize,
+    // Source record field -> Measurement field. Unmapped record fields are used as-is.
+    #[serde(default)]
+    pub field_map: HashMap<String, String>,
+}
+
+fn default_stall_timeout_secs() -> u64 {
+    30
+}
+
+fn default_buffer_size() -> usize {
+    100
+}
+
+#[derive(Default)]
+struct FeedBuffer {
+    queue: VecDeque<Value>,
+    last_record: Option<Value>,
+    last_received: Option<Instant>,
+}
+
+/// Records read from an external co-simulator, shared between the reader task and the sampler.
+#[derive(Clone)]
+pub struct ExternalFeed {
+    config: ExternalSourceConfig,
+    buffer: Arc<Mutex<FeedBuffer>>,
+}
+
+impl ExternalFeed {
+    /// Starts reading from the configured source in a background task.
+    pub fn spawn(config: ExternalSourceConfig) -> Self {
+        match config.kind.clone() {
+            ExternalSourceKind::Stdin => Self::from_reader(config, tokio::io::stdin()),
+            ExternalSourceKind::Unix { socket_path } => {
+                let feed = ExternalFeed { config, buffer: Arc::default() };
+                let reader = feed.clone();
+                tokio::spawn(async move {
+                    loop {
+                        match tokio::net::UnixStream::connect(&socket_path).await {
+                            Ok(stream) => {
+                                info!(socket_path = %socket_path, "Connected to external measurement source");
+                                reader.read_records(stream).await;
+                            }
+                            Err(e) => debug!(socket_path = %socket_path, error = %e, "External measurement source not reachable"),
+                        }
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                });
+                feed
+            }
+        }
+    }
+
+    /// Builds a feed reading from an arbitrary stream; used for stdin, sockets and tests.
+    pub fn from_reader<R: AsyncRead + Unpin + Send + 'static>(config: ExternalSourceConfig, reader: R) -> Self {
+        let feed = ExternalFeed { config, buffer: Arc::default() };
+        let task_feed = feed.clone();
+        tokio::spawn(async move { task_feed.read_records(reader).await });
+        feed
+    }
+
+    async fn read_records<R: AsyncRead + Unpin>(&self, reader: R) {
+        let mut lines = BufReader::new(reader).lines();
+        loop {
+            match lines.next_line().await {
+                Ok(Some(line)) => {
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    match serde_json::from_str::<Value>(&line) {
+                        Ok(record) => self.push(record),
+                        Err(e) => warn!(error = %e, "Skipping malformed external measurement record"),
+                    }
+                }
+                Ok(None) => {
+                    info!("External measurement source closed");
+                    return;
+                }
+                Err(e) => {
+                    warn!(error = %e, "Failed reading external measurement source");
+                    return;
+                }
+            }
+        }
+    }
+
+    fn push(&self, record: Value) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.last_received = Some(Instant::now());
+        buffer.queue.push_back(record);
+        while buffer.queue.len() > self.config.buffer_size.max(1) {
+            buffer.queue.pop_front();
+        }
+    }
+
+    /// Returns the record to use for the next sample, or `None` when the feed has
+    /// stalled beyond the configured timeout and the synthetic model should be used.
+    pub fn next_record(&self, now: Instant) -> Option<Value> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let stall_timeout = Duration::from_secs(self.config.stall_timeout_secs);
+        let fresh = buffer.last_received.is_some_and(|at| now.saturating_duration_since(at) <= stall_timeout);
+        if !fresh {
+            return None;
+        }
+
+        let next = match self.config.backpressure {
+            Backpressure::Latest => {
+                let newest = buffer.queue.pop_back();
+                buffer.queue.clear();
+                newest
+            }
+            Backpressure::Buffer => buffer.queue.pop_front(),
+        };
+        if let Some(record) = next {
+            buffer.last_record = Some(record);
+        }
+        // Hold the last value when the feed is slower than the sample interval
+        buffer.last_record.clone()
+    }
+
+    /// Overwrites the measurement's sensor fields with values from an external record.
+    /// Timestamp and sequence number stay device-assigned.
+    pub fn apply(&self, record: &Value, measurement: &mut Measurement) {
+        let Some(fields) = record.as_object() else {
+            return;
+        };
+        for (source, value) in fields {
+            let target = self.config.field_map.get(source).map(String::as_str).unwrap_or(source);
+            let Some(number) = value.as_f64().map(|v| v as f32) else {
+                continue;
+            };
+            match target {
+                "temp" => measurement.temp = number,
+                "humidity" => measurement.humidity = number,
+                "battery" => measurement.battery = number,
+                "latitude" => measurement.latitude = Some(number),
+                "longitude" => measurement.longitude = Some(number),
+                "speed" => measurement.speed = Some(number),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Parses the `EXTERNAL_SOURCE` env value: `stdin` or `unix:/path/to.sock`.
+pub fn parse_source_kind(raw: &str) -> Result<ExternalSourceKind> {
+    if raw == "stdin" {
+        Ok(ExternalSourceKind::Stdin)
+    } else if let Some(socket_path) = raw.strip_prefix("unix:") {
+        Ok(ExternalSourceKind::Unix { socket_path: socket_path.to_string() })
+    } else {
+        Err(anyhow::anyhow!("Unknown external source {:?}, expected 'stdin' or 'unix:<path>'", raw))
+    }
+}
+
+/// Parses a field map of the form `source=target,source2=target2`.
+pub fn parse_field_map(raw: &str) -> HashMap<String, String> {
+    raw.split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(source, target)| (source.trim().to_string(), target.trim().to_string()))
+        .collect()
+}
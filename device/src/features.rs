@@ -0,0 +1,186 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// A feature is either on/off or selects one of a fixed set of variants.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum FeatureValue {
+    Bool(bool),
+    Variant(String),
+}
+
+/// How a changed feature takes effect.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApplyMode {
+    Immediate,
+    Restart, // Used once at startup; the new value is persisted and applies after the next boot
+}
+
+struct FeatureSpec {
+    name: &'static str,
+    default: fn() -> FeatureValue,
+    variants: &'static [&'static str], // Empty for boolean features
+    apply: ApplyMode,
+}
+
+const FEATURES: &[FeatureSpec] = &[
+    // Apply the backend's suggested sample interval from ingest responses
+    FeatureSpec { name: "adaptive_sampling", default: || FeatureValue::Bool(true), variants: &[], apply: ApplyMode::Immediate },
+    // Drop fields the backend's measurement schema does not accept
+    FeatureSpec { name: "schema_filter", default: || FeatureValue::Bool(true), variants: &[], apply: ApplyMode::Immediate },
+    // Run the on-device anomaly detector when it is configured
+    FeatureSpec { name: "self_detection", default: || FeatureValue::Bool(true), variants: &[], apply: ApplyMode::Immediate },
+    // Read measurements from the configured co-simulator; the feed is spawned at startup
+    FeatureSpec { name: "external_source", default: || FeatureValue::Bool(true), variants: &[], apply: ApplyMode::Restart },
+    // "full" attaches API statistics and anomaly counts to heartbeats, "minimal" omits them
+    FeatureSpec { name: "heartbeat_telemetry", default: || FeatureValue::Variant("full".to_string()), variants: &["full", "minimal"], apply: ApplyMode::Immediate },
+];
+
+fn spec(name: &str) -> Option<&'static FeatureSpec> {
+    FEATURES.iter().find(|spec| spec.name == name)
+}
+
+// Checks a value against the feature's type and allowed variants
+fn validate(spec: &FeatureSpec, value: &Value) -> Result<FeatureValue, String> {
+    let parsed: FeatureValue = serde_json::from_value(value.clone())
+        .map_err(|_| format!("Feature {} expects a bool or variant name, got {}", spec.name, value))?;
+    match (&parsed, spec.variants.is_empty()) {
+        (FeatureValue::Bool(_), true) => Ok(parsed),
+        (FeatureValue::Variant(variant), false) if spec.variants.contains(&variant.as_str()) => Ok(parsed),
+        _ => Err(format!("Invalid value {} for feature {}", value, spec.name)),
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FeatureChange {
+    pub name: String,
+    pub old: FeatureValue,
+    pub new: FeatureValue,
+    pub apply: ApplyMode,
+}
+
+/// Effective feature set: defaults overlaid with the persisted overrides from the config.
+/// Unknown names and invalid values are skipped and kept as warnings for reporting.
+#[derive(Debug, Clone)]
+pub struct Features {
+    effective: BTreeMap<String, FeatureValue>,
+    pending_restart: BTreeMap<String, FeatureValue>,
+    warnings: Vec<String>,
+}
+
+impl Features {
+    pub fn resolve(overrides: &BTreeMap<String, FeatureValue>) -> Self {
+        let mut features = Features {
+            effective: FEATURES.iter().map(|spec| (spec.name.to_string(), (spec.default)())).collect(),
+            pending_restart: BTreeMap::new(),
+            warnings: Vec::new(),
+        };
+        for (name, value) in overrides {
+            match spec(name) {
+                Some(spec) => match validate(spec, &json!(value)) {
+                    Ok(value) => {
+                        features.effective.insert(name.clone(), value);
+                    }
+                    Err(warning) => features.warnings.push(warning),
+                },
+                None => features.warnings.push(format!("Unknown feature {}", name)),
+            }
+        }
+        features
+    }
+
+    /// Applies a desired `features` object from the shadow. Valid values are written to
+    /// `overrides` for persistence; immediate features take effect now, restart-bound
+    /// ones are held as pending. Warnings are replaced by this update's findings.
+    pub fn apply_desired(&mut self, overrides: &mut BTreeMap<String, FeatureValue>, desired: &Value) -> Vec<FeatureChange> {
+        let mut changes = Vec::new();
+        self.warnings.clear();
+        let Some(desired) = desired.as_object() else {
+            self.warnings.push(format!("Desired features must be an object, got {}", desired));
+            return changes;
+        };
+
+        for (name, raw) in desired {
+            let Some(spec) = spec(name) else {
+                self.warnings.push(format!("Unknown feature {}", name));
+                continue;
+            };
+            let new = match validate(spec, raw) {
+                Ok(value) => value,
+                Err(warning) => {
+                    self.warnings.push(warning);
+                    continue;
+                }
+            };
+            overrides.insert(name.clone(), new.clone());
+
+            let old = self.effective[name].clone();
+            match spec.apply {
+                ApplyMode::Immediate => {
+                    if old != new {
+                        self.effective.insert(name.clone(), new.clone());
+                        changes.push(FeatureChange { name: name.clone(), old, new, apply: spec.apply });
+                    }
+                }
+                ApplyMode::Restart => {
+                    let pending = self.pending_restart.get(name).unwrap_or(&old);
+                    if *pending != new {
+                        changes.push(FeatureChange { name: name.clone(), old: pending.clone(), new: new.clone(), apply: spec.apply });
+                    }
+                    if old == new {
+                        self.pending_restart.remove(name);
+                    } else {
+                        self.pending_restart.insert(name.clone(), new);
+                    }
+                }
+            }
+        }
+        changes
+    }
+
+    fn enabled(&self, name: &str) -> bool {
+        matches!(self.effective.get(name), Some(FeatureValue::Bool(true)))
+    }
+
+    fn variant(&self, name: &str) -> &str {
+        match self.effective.get(name) {
+            Some(FeatureValue::Variant(variant)) => variant,
+            _ => "",
+        }
+    }
+
+    pub fn adaptive_sampling(&self) -> bool {
+        self.enabled("adaptive_sampling")
+    }
+
+    pub fn schema_filter(&self) -> bool {
+        self.enabled("schema_filter")
+    }
+
+    pub fn self_detection(&self) -> bool {
+        self.enabled("self_detection")
+    }
+
+    pub fn external_source(&self) -> bool {
+        self.enabled("external_source")
+    }
+
+    pub fn full_heartbeat_telemetry(&self) -> bool {
+        self.variant("heartbeat_telemetry") == "full"
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Full effective set, plus changes waiting on a restart and any validation warnings.
+    pub fn report(&self) -> Value {
+        json!({
+            "effective": self.effective,
+            "pending_restart": self.pending_restart,
+            "warnings": self.warnings,
+        })
+    }
+}
@@ -0,0 +1,61 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::types::Measurement;
+
+/// Simulated behavior of a firmware version. Lets a rollout show up in the data:
+/// an old version can carry a bug (clock skew, a dropped field) that a newer one fixes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FirmwareBehavior {
+    #[serde(default = "default_noise_scale")]
+    pub noise_scale: f32, // Multiplier on sensor noise; 0 produces flat readings
+    #[serde(default)]
+    pub clock_skew_secs: i64, // Offset applied to measurement timestamps
+    #[serde(default)]
+    pub omitted_fields: Vec<String>, // Optional measurement fields this version fails to emit
+    #[serde(default)]
+    pub capabilities: Vec<String>, // Reported only, for demoing feature rollout
+}
+
+fn default_noise_scale() -> f32 { 1.0 }
+
+impl Default for FirmwareBehavior {
+    fn default() -> Self {
+        FirmwareBehavior {
+            noise_scale: default_noise_scale(),
+            clock_skew_secs: 0,
+            omitted_fields: Vec::new(),
+            capabilities: Vec::new(),
+        }
+    }
+}
+
+impl FirmwareBehavior {
+    // Applies the timestamp and missing-field bugs to a generated measurement
+    pub fn apply(&self, measurement: &mut Measurement) {
+        measurement.timestamp += chrono::Duration::seconds(self.clock_skew_secs);
+        for field in &self.omitted_fields {
+            match field.as_str() {
+                "latitude" => measurement.latitude = None,
+                "longitude" => measurement.longitude = None,
+                "speed" => measurement.speed = None,
+                "firmware_version" => measurement.firmware_version = None,
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Behavior for `version`. Overrides delivered with the firmware metadata take
+/// precedence over the table bundled in the config; unknown versions behave normally.
+pub fn behavior_for(
+    version: &str,
+    installed: &BTreeMap<String, FirmwareBehavior>,
+    bundled: &BTreeMap<String, FirmwareBehavior>,
+) -> FirmwareBehavior {
+    installed
+        .get(version)
+        .or_else(|| bundled.get(version))
+        .cloned()
+        .unwrap_or_default()
+}
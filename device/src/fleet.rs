@@ -0,0 +1,267 @@
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::Client;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::config::{Config, InvalidSetting};
+use crate::features::Features;
+use crate::types::{BulkRegisterResult, RegisterPayload};
+use crate::{audit, config, init, net, ota, schema, storage, txn};
+
+// Devices registered per bulk request
+const BULK_REGISTRATION_BATCH: usize = 500;
+
+/// Where one simulated device keeps its persisted state. A lone device uses the
+/// historical names; device `i` of a fleet suffixes each with `_{i}`, so devices sharing
+/// a process and a CONFIG_DIR never share a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DevicePaths {
+    pub index: Option<usize>, // Position in the fleet; None when running a single device
+    pub config: PathBuf,
+    pub database: PathBuf,
+    pub ota_state: PathBuf,
+    pub firmware_dir: PathBuf,
+    pub audit_log: PathBuf,
+    pub staged_txn: PathBuf,
+    pub schema_cache: PathBuf,
+    pub exports_dir: PathBuf,
+    pub crash_dir: PathBuf,
+    pub restored_runtime: PathBuf, // Left by a snapshot restore for the next boot, see snapshot::take_restored
+    pub suspended: PathBuf, // Left beside the config while the fleet runner has the member suspended
+}
+
+impl DevicePaths {
+    pub fn single() -> Self {
+        Self::resolve(None)
+    }
+
+    pub fn fleet_member(index: usize) -> Self {
+        Self::resolve(Some(index))
+    }
+
+    fn resolve(index: Option<usize>) -> Self {
+        let in_config_dir = |name: &str| config::config_dir_file(&suffixed(name, index));
+        let in_working_dir = |name: &str| Path::new(".").join(suffixed(name, index));
+        DevicePaths {
+            index,
+            config: in_config_dir(config::CONFIG_FILE),
+            database: in_working_dir(storage::DB_FILE),
+            ota_state: in_working_dir(ota::OTA_STATE_FILE),
+            firmware_dir: in_working_dir(ota::FIRMWARE_DIR),
+            audit_log: in_config_dir(audit::AUDIT_LOG_FILE),
+            staged_txn: in_config_dir(txn::STAGED_TXN_FILE),
+            schema_cache: in_config_dir(schema::SCHEMA_CACHE_FILE),
+            exports_dir: in_config_dir("exports"),
+            crash_dir: in_config_dir("crash"),
+            restored_runtime: in_config_dir("restored_runtime.json"),
+            suspended: in_config_dir("suspended"),
+        }
+    }
+
+    /// The same paths with everything the device writes but its config under `data_dir`.
+    /// The default data directory, the working directory, leaves what CONFIG_DIR holds
+    /// where it was.
+    pub fn in_data_dir(self, data_dir: &Path) -> Self {
+        let rebased = |path: PathBuf| path.file_name().map_or_else(|| path.clone(), |name| data_dir.join(name));
+        let paths = DevicePaths {
+            database: rebased(self.database),
+            ota_state: rebased(self.ota_state),
+            firmware_dir: rebased(self.firmware_dir),
+            ..self
+        };
+        if data_dir == Path::new(".") {
+            return paths;
+        }
+        DevicePaths {
+            audit_log: rebased(paths.audit_log),
+            staged_txn: rebased(paths.staged_txn),
+            schema_cache: rebased(paths.schema_cache),
+            exports_dir: rebased(paths.exports_dir),
+            crash_dir: rebased(paths.crash_dir),
+            restored_runtime: rebased(paths.restored_runtime),
+            ..paths
+        }
+    }
+
+    // Every file and directory the device writes besides its config, with the database's journals
+    fn state_files(&self) -> Vec<PathBuf> {
+        let journals = ["-journal", "-wal", "-shm"].map(|suffix| PathBuf::from(format!("{}{}", self.database.display(), suffix)));
+        [&self.database, &self.ota_state, &self.firmware_dir, &self.audit_log, &self.staged_txn, &self.schema_cache, &self.exports_dir, &self.crash_dir, &self.restored_runtime]
+            .into_iter()
+            .cloned()
+            .chain(journals)
+            .collect()
+    }
+
+    /// Moves what the device left at its `previous` paths, from before it had a data
+    /// directory, to these. A file already at its new path is kept, and the old one is
+    /// left alone with a warning, as is one that cannot be moved.
+    pub fn adopt(&self, previous: &DevicePaths) {
+        for (from, to) in previous.state_files().into_iter().zip(self.state_files()) {
+            if from == to || !from.exists() {
+                continue;
+            }
+            if to.exists() {
+                warn!(old = %from.display(), new = %to.display(), "Ignoring state left outside the data directory; the data directory already has its own");
+                continue;
+            }
+            match std::fs::rename(&from, &to) {
+                Ok(()) => info!(old = %from.display(), new = %to.display(), "Moved state into the data directory"),
+                Err(e) => warn!(old = %from.display(), new = %to.display(), error = %e, "Failed to move state into the data directory; the device starts without it"),
+            }
+        }
+    }
+
+    /// The first device also owns what is process-wide: the panic hook and trace export.
+    pub fn owns_process(&self) -> bool {
+        self.index.is_none_or(|index| index == 0)
+    }
+}
+
+// device_config.json becomes device_config_3.json, firmware becomes firmware_3
+fn suffixed(name: &str, index: Option<usize>) -> String {
+    let Some(index) = index else {
+        return name.to_string();
+    };
+    match name.rsplit_once('.') {
+        Some((stem, extension)) => format!("{}_{}.{}", stem, index, extension),
+        None => format!("{}_{}", name, index),
+    }
+}
+
+/// Devices to simulate in this process: `--devices N`, else NUM_DEVICES, else one.
+pub fn fleet_size(args: &[String], vars: &HashMap<String, String>) -> Result<usize> {
+    let raw = match args.iter().position(|arg| arg == "--devices") {
+        Some(at) => Some(args.get(at + 1).context("--devices needs a count")?.as_str()),
+        None => args.iter()
+            .find_map(|arg| arg.strip_prefix("--devices="))
+            .or_else(|| vars.get("NUM_DEVICES").map(String::as_str)),
+    };
+    let Some(raw) = raw else {
+        return Ok(1);
+    };
+    match raw.trim().parse::<usize>() {
+        Ok(0) => bail!("the device count must be at least 1"),
+        Ok(devices) => Ok(devices),
+        Err(e) => bail!("invalid device count {:?}: {}", raw, e),
+    }
+}
+
+/// Port of the fleet runner's control API, from FLEET_CONTROL_PORT; None leaves it off.
+pub fn control_port(vars: &HashMap<String, String>) -> Result<Option<u16>> {
+    vars.get("FLEET_CONTROL_PORT")
+        .map(|raw| raw.trim().parse::<u16>().with_context(|| format!("{:?} is not a port", raw)).context(InvalidSetting("FLEET_CONTROL_PORT")))
+        .transpose()
+}
+
+/// Where the fleet runner serves its control API, and the token it asks for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlApi {
+    pub address: SocketAddr,
+    pub token: Option<String>, // Expected as a bearer token on every request when set
+}
+
+/// The control API from FLEET_CONTROL_PORT, on FLEET_CONTROL_ADDRESS, else loopback, and
+/// guarded by FLEET_CONTROL_TOKEN if set; serving beyond loopback needs the token. None
+/// leaves it off.
+pub fn control_api(vars: &HashMap<String, String>) -> Result<Option<ControlApi>> {
+    let Some(port) = control_port(vars)? else {
+        return Ok(None);
+    };
+    let ip = match vars.get("FLEET_CONTROL_ADDRESS") {
+        Some(raw) => raw.trim().parse::<IpAddr>()
+            .with_context(|| format!("{:?} is not an IP address", raw))
+            .context(InvalidSetting("FLEET_CONTROL_ADDRESS"))?,
+        None => IpAddr::V4(Ipv4Addr::LOCALHOST),
+    };
+    let token = vars.get("FLEET_CONTROL_TOKEN").map(|token| token.trim().to_string()).filter(|token| !token.is_empty());
+    if !ip.is_loopback() && token.is_none() {
+        return Err(anyhow!("serving on {} needs FLEET_CONTROL_TOKEN", ip)).context(InvalidSetting("FLEET_CONTROL_ADDRESS"));
+    }
+    Ok(Some(ControlApi { address: SocketAddr::new(ip, port), token }))
+}
+
+/// The config a device registers with: `base`, with a fleet member's name suffixed by its
+/// index.
+pub fn boot_config(base: &Config, index: Option<usize>) -> Config {
+    let mut config = base.clone();
+    if let Some(index) = index {
+        config.device_name = config.device_name.map(|name| format!("{}-{}", name, index));
+    }
+    config
+}
+
+/// How the fleet's unregistered devices got their identities.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Registration {
+    pub bulk: usize,
+    pub individually: usize, // Rejected in bulk, or the backend has no bulk endpoint
+    pub failed: usize, // Left to register themselves when they boot
+}
+
+/// Registers every member without a config, in bulk requests as a factory would
+/// provision them, and writes each one's config just as its own registration would have.
+/// Devices the bulk endpoint rejects are registered one by one, as is everyone when the
+/// backend has no bulk endpoint.
+pub async fn register_members(client: &Client, base: &Config, members: &[DevicePaths]) -> Result<Registration> {
+    let mut pending = members.iter()
+        .filter(|paths| Config::load_from(&paths.config).is_err())
+        .map(|paths| (paths, boot_config(base, paths.index)));
+    let mut registration = Registration::default();
+    let mut individually = Vec::new();
+    let mut bulk_supported = true;
+    loop {
+        let batch: Vec<_> = pending.by_ref().take(BULK_REGISTRATION_BATCH).collect();
+        if batch.is_empty() {
+            break;
+        }
+        if !bulk_supported {
+            individually.extend(batch);
+            continue;
+        }
+        let boot_ids: Vec<Uuid> = batch.iter().map(|_| Uuid::new_v4()).collect();
+        let payloads = batch.iter().zip(&boot_ids).map(|((_, config), boot_id)| RegisterPayload {
+            boot_id: *boot_id,
+            device_name: config.device_name.clone(),
+            features: Features::resolve(&config.features).report(),
+            provisioning_token: None,
+        }).collect();
+        let Some(results) = net::register_devices_bulk(client, &base.backend_url, payloads, &base.compression.accept_encoding()).await? else {
+            info!("Backend has no bulk registration; registering devices one by one");
+            bulk_supported = false;
+            individually.extend(batch);
+            continue;
+        };
+        let mut results: HashMap<Uuid, BulkRegisterResult> = results.into_iter().map(|result| (result.boot_id, result)).collect();
+        for ((paths, mut config), boot_id) in batch.into_iter().zip(boot_ids) {
+            match results.remove(&boot_id) {
+                Some(BulkRegisterResult { registration: Some(response), .. }) => {
+                    init::adopt_identity(&mut config, &response);
+                    config.save_to(&paths.config)?;
+                    registration.bulk += 1;
+                }
+                rejected => {
+                    let error = rejected.and_then(|result| result.error).unwrap_or_else(|| "no result".to_string());
+                    warn!(index = ?paths.index, error = %error, "Bulk registration rejected device; registering it individually");
+                    individually.push((paths, config));
+                }
+            }
+        }
+    }
+    for (paths, mut config) in individually {
+        match init::register(client, &mut config, None).await {
+            Ok(()) => {
+                config.save_to(&paths.config)?;
+                registration.individually += 1;
+            }
+            Err(e) => {
+                warn!(index = ?paths.index, error = %e, "Failed to register device; it tries again when it boots");
+                registration.failed += 1;
+            }
+        }
+    }
+    Ok(registration)
+}
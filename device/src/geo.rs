@@ -0,0 +1,203 @@
+use anyhow::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::storage;
+use crate::types::Measurement;
+
+const STATE_KEY: &str = "geo_buckets";
+
+const BASE32: &[u8; 32] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+pub const MAX_PRECISION: u8 = 12;
+pub const MAX_ZOOM: u8 = 22;
+
+// Latitude beyond which Web Mercator tiles end
+const MAX_TILE_LATITUDE: f64 = 85.051_128_78;
+
+// Transitions kept for the shadow
+const RECENT_TRANSITIONS: usize = 20;
+
+// Longest gap between fixes credited to a cell; beyond it the device was off or had lost its fix
+const MAX_DWELL_GAP_SECS: i64 = 600;
+
+/// How positions are bucketed. Adjustable through the shadow without a restart.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GeoBucketConfig {
+    #[serde(default = "default_precision")]
+    pub precision: u8, // Geohash characters, 1 to 12; 6 is about 1.2 km by 0.6 km
+    #[serde(default)]
+    pub tile_zoom: Option<u8>, // Also stamp the slippy-map tile at this zoom, 0 to 22
+}
+
+fn default_precision() -> u8 {
+    6
+}
+
+/// A slippy-map tile, as in z/x/y tile URLs.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapTile {
+    pub x: u32,
+    pub y: u32,
+    pub z: u8,
+}
+
+/// Geohash of a position with `precision` characters (at most 12), per the public
+/// geohash algorithm: longitude and latitude bits interleaved, longitude first, in base 32.
+pub fn geohash(latitude: f64, longitude: f64, precision: u8) -> String {
+    let (mut latitudes, mut longitudes) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision as usize);
+    let mut bits = 0;
+    let mut index = 0;
+    let mut even = true;
+    while hash.len() < precision.min(MAX_PRECISION) as usize {
+        let (range, value) = if even { (&mut longitudes, longitude) } else { (&mut latitudes, latitude) };
+        let mid = (range.0 + range.1) / 2.0;
+        index <<= 1;
+        if value >= mid {
+            index |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+        bits += 1;
+        if bits == 5 {
+            hash.push(BASE32[index] as char);
+            bits = 0;
+            index = 0;
+        }
+    }
+    hash
+}
+
+/// The Web Mercator tile holding a position at `zoom`.
+pub fn map_tile(latitude: f64, longitude: f64, zoom: u8) -> MapTile {
+    let tiles = 2f64.powi(zoom as i32);
+    let last = tiles as u32 - 1;
+    let latitude = latitude.clamp(-MAX_TILE_LATITUDE, MAX_TILE_LATITUDE).to_radians();
+    let x = ((longitude + 180.0) / 360.0 * tiles).floor() as u32;
+    let y = ((1.0 - latitude.tan().asinh() / std::f64::consts::PI) / 2.0 * tiles).floor() as u32;
+    MapTile { x: x.min(last), y: y.min(last), z: zoom }
+}
+
+/// The device moved from one geohash cell into another.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CellTransition {
+    pub from: String,
+    pub to: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Time spent in each cell over one UTC day.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DailyDwell {
+    pub day: NaiveDate,
+    pub dwell_secs: BTreeMap<String, i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+struct TrackerState {
+    cell: Option<String>,
+    last_fix_at: Option<DateTime<Utc>>,
+    today: Option<DailyDwell>,
+    yesterday: Option<DailyDwell>,
+    transitions: VecDeque<CellTransition>, // Most recent last
+}
+
+/// Buckets each GPS fix into a geohash cell, and optionally a map tile, on the device
+/// rather than the mapping backend. Notices the device moving into a new cell and adds up
+/// the time spent in each cell per day. Checkpointed, so dwell times survive restarts.
+#[derive(Debug, Clone)]
+pub struct GeoBuckets {
+    config: Option<GeoBucketConfig>,
+    state: TrackerState,
+}
+
+impl GeoBuckets {
+    pub fn load(config: Option<GeoBucketConfig>, conn: &Connection) -> Result<Self> {
+        let state = match storage::load_state(conn, STATE_KEY)? {
+            Some(value) => serde_json::from_value(value)?,
+            None => TrackerState::default(),
+        };
+        Ok(GeoBuckets { config, state })
+    }
+
+    pub fn checkpoint(&self, conn: &Connection) -> Result<()> {
+        Ok(storage::save_state(conn, STATE_KEY, &serde_json::to_value(&self.state)?)?)
+    }
+
+    /// Follows new settings from the shadow. Cells of another size are not comparable, so
+    /// the next fix starts afresh rather than counting as a transition; dwell times so far
+    /// stay under the old cells.
+    pub fn reconfigure(&mut self, config: Option<GeoBucketConfig>) {
+        if self.config.as_ref().map(|config| config.precision) != config.as_ref().map(|config| config.precision) {
+            self.state.cell = None;
+            self.state.last_fix_at = None;
+        }
+        self.config = config;
+    }
+
+    /// Stamps the cell, and tile, of a measurement with a GPS fix and credits the time
+    /// since the previous fix to the cell the device was in. Returns the transition if the
+    /// fix is in another cell than the previous one.
+    pub fn apply(&mut self, measurement: &mut Measurement) -> Option<CellTransition> {
+        let config = self.config.as_ref()?;
+        let (Some(latitude), Some(longitude)) = (measurement.latitude, measurement.longitude) else {
+            return None;
+        };
+        let (latitude, longitude) = (latitude as f64, longitude as f64);
+        let cell = geohash(latitude, longitude, config.precision);
+        measurement.geohash = Some(cell.clone());
+        measurement.map_tile = config.tile_zoom.map(|zoom| map_tile(latitude, longitude, zoom));
+
+        let at = measurement.timestamp;
+        self.credit_dwell(at);
+        self.state.last_fix_at = Some(at);
+        let from = self.state.cell.replace(cell.clone()).filter(|from| *from != cell)?;
+        let transition = CellTransition { from, to: cell, at };
+        self.state.transitions.push_back(transition.clone());
+        if self.state.transitions.len() > RECENT_TRANSITIONS {
+            self.state.transitions.pop_front();
+        }
+        Some(transition)
+    }
+
+    // Credits the time since the last fix to the cell the device was in, on the day of `at`
+    fn credit_dwell(&mut self, at: DateTime<Utc>) {
+        let day = at.date_naive();
+        if self.state.today.as_ref().is_none_or(|today| today.day != day) {
+            let finished = self.state.today.replace(DailyDwell { day, dwell_secs: BTreeMap::new() });
+            if finished.as_ref().is_some_and(|finished| finished.day < day) {
+                self.state.yesterday = finished;
+            }
+        }
+        let (Some(cell), Some(last_fix_at)) = (&self.state.cell, self.state.last_fix_at) else {
+            return;
+        };
+        let gap = (at - last_fix_at).num_seconds();
+        if (0..=MAX_DWELL_GAP_SECS).contains(&gap) {
+            if let Some(today) = self.state.today.as_mut() {
+                *today.dwell_secs.entry(cell.clone()).or_default() += gap;
+            }
+        }
+    }
+
+    pub fn report(&self) -> Value {
+        let Some(config) = &self.config else {
+            return Value::Null;
+        };
+        json!({
+            "precision": config.precision,
+            "tile_zoom": config.tile_zoom,
+            "cell": self.state.cell,
+            "today": self.state.today,
+            "yesterday": self.state.yesterday,
+            "transitions": self.state.transitions,
+        })
+    }
+}
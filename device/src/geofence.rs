@@ -0,0 +1,106 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::route::Waypoint;
+use crate::types::{DeviceEvent, Measurement};
+
+/// The area a tracked asset is allowed in: a circle around a point, or a polygon.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "shape", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Geofence {
+    Circle { lat: f64, lon: f64, radius_m: f64 },
+    Polygon { points: Vec<Waypoint> }, // Corners in order; the last joins back to the first
+}
+
+impl Geofence {
+    pub fn contains(&self, point: Waypoint) -> bool {
+        match self {
+            Geofence::Circle { lat, lon, radius_m } => haversine_m(Waypoint { lat: *lat, lon: *lon }, point) <= *radius_m,
+            Geofence::Polygon { points } => in_polygon(points, point),
+        }
+    }
+
+    /// Why the fence cannot be checked against, if it cannot.
+    pub fn validate(&self) -> Result<(), String> {
+        let in_range = |point: &Waypoint| (-90.0..=90.0).contains(&point.lat) && (-180.0..=180.0).contains(&point.lon);
+        match self {
+            Geofence::Circle { lat, lon, radius_m } => {
+                if !in_range(&Waypoint { lat: *lat, lon: *lon }) {
+                    return Err(format!("center {}, {} is not a position", lat, lon));
+                }
+                if radius_m.is_nan() || *radius_m <= 0.0 {
+                    return Err(format!("radius_m must be positive, got {}", radius_m));
+                }
+            }
+            Geofence::Polygon { points } => {
+                if points.len() < 3 {
+                    return Err(format!("a polygon needs at least 3 points, got {}", points.len()));
+                }
+                if let Some(point) = points.iter().find(|point| !in_range(point)) {
+                    return Err(format!("{}, {} is not a position", point.lat, point.lon));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Great-circle distance between two positions in meters, by the haversine formula.
+pub fn haversine_m(from: Waypoint, to: Waypoint) -> f64 {
+    from.distance_to(to)
+}
+
+// Even-odd ray casting on plain degrees, which is close enough for fences that do not
+// span the antimeridian or a pole
+fn in_polygon(points: &[Waypoint], point: Waypoint) -> bool {
+    let mut inside = false;
+    for (i, a) in points.iter().enumerate() {
+        let b = points[(i + 1) % points.len()];
+        if (a.lat > point.lat) != (b.lat > point.lat) {
+            let crossing_lon = a.lon + (point.lat - a.lat) / (b.lat - a.lat) * (b.lon - a.lon);
+            if point.lon < crossing_lon {
+                inside = !inside;
+            }
+        }
+    }
+    inside
+}
+
+/// Where and when an asset crossed its fence, reported once each time it leaves the fence
+/// and once each time it comes back in.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GeofenceCrossing {
+    pub timestamp: DateTime<Utc>,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// Watches a device's positions for it leaving its fence and coming back.
+#[derive(Debug, Clone)]
+pub struct GeofenceMonitor {
+    fence: Geofence,
+    inside: Option<bool>, // Where the last fix was; None before the first
+}
+
+impl GeofenceMonitor {
+    pub fn new(fence: Geofence) -> Self {
+        GeofenceMonitor { fence, inside: None }
+    }
+
+    /// The event a sample amounts to: a breach if its position is outside the fence and
+    /// the last one was inside, or it is the first fix; a re-entry if it is inside and the
+    /// last one was outside. Samples without a position change nothing.
+    pub fn check(&mut self, measurement: &Measurement) -> Option<DeviceEvent> {
+        let (Some(latitude), Some(longitude)) = (measurement.latitude, measurement.longitude) else {
+            return None;
+        };
+        let (latitude, longitude) = (latitude as f64, longitude as f64);
+        let inside = self.fence.contains(Waypoint { lat: latitude, lon: longitude });
+        let crossing = GeofenceCrossing { timestamp: measurement.timestamp, latitude, longitude };
+        match (self.inside.replace(inside), inside) {
+            (Some(true) | None, false) => Some(DeviceEvent::GeofenceBreach(crossing)),
+            (Some(false), true) => Some(DeviceEvent::GeofenceReentry(crossing)),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::{Context, Result};
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::{Json, Router};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::metrics::DeviceMetrics;
+
+// Config keys whose values never leave the device, matched anywhere in a key's name
+const SECRET_KEY_PARTS: [&str; 3] = ["token", "secret", "password"];
+
+/// What GET /healthz reports, kept current by the device's main loop.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HealthStatus {
+    pub device_id: String,
+    pub firmware_version: String,
+    pub active_slot: String,
+    pub buffered_measurements: u64, // Stored and not yet confirmed by the backend
+    pub last_upload_at: Option<DateTime<Utc>>, // Last upload the backend accepted measurements from
+}
+
+/// State the self-test endpoints serve, shared between the main loop and the server.
+#[derive(Debug, Clone)]
+pub struct HealthState {
+    status: Arc<Mutex<HealthStatus>>,
+    config: Arc<Mutex<Value>>, // Already redacted
+    metrics: DeviceMetrics,
+}
+
+impl HealthState {
+    pub fn new(status: HealthStatus, config: &Config) -> Self {
+        let metrics = DeviceMetrics::new(&status.device_id);
+        metrics.buffered_measurements.set(status.buffered_measurements as i64);
+        HealthState { status: Arc::new(Mutex::new(status)), config: Arc::new(Mutex::new(redacted(config))), metrics }
+    }
+
+    /// Changes the status; the buffered measurements gauge follows it.
+    pub fn update(&self, change: impl FnOnce(&mut HealthStatus)) {
+        let mut status = self.status.lock().unwrap();
+        change(&mut status);
+        self.metrics.buffered_measurements.set(status.buffered_measurements as i64);
+    }
+
+    pub fn metrics(&self) -> &DeviceMetrics {
+        &self.metrics
+    }
+
+    pub fn set_config(&self, config: &Config) {
+        *self.config.lock().unwrap() = redacted(config);
+    }
+
+    pub fn status(&self) -> HealthStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+/// The config as JSON, with credentials replaced by a placeholder.
+pub fn redacted(config: &Config) -> Value {
+    let mut value = serde_json::to_value(config).unwrap_or(Value::Null);
+    redact(&mut value);
+    value
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, field) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if SECRET_KEY_PARTS.iter().any(|part| key.contains(part)) && !field.is_null() {
+                    *field = Value::String("<redacted>".to_string());
+                } else {
+                    redact(field);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+pub fn router(state: HealthState) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/config", get(config))
+        .route("/metrics", get(metrics))
+        .with_state(state)
+}
+
+async fn healthz(State(state): State<HealthState>) -> Json<HealthStatus> {
+    Json(state.status())
+}
+
+async fn config(State(state): State<HealthState>) -> Json<Value> {
+    Json(state.config.lock().unwrap().clone())
+}
+
+async fn metrics(State(state): State<HealthState>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], state.metrics.render())
+}
+
+/// The endpoints' server task; stopped when dropped, so a rebooting device frees its port.
+#[derive(Debug)]
+pub struct HealthServer(JoinHandle<()>);
+
+impl HealthServer {
+    /// Serves the endpoints on `port` of every interface.
+    pub async fn start(port: u16, state: HealthState) -> Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port)).await.with_context(|| format!("Failed to bind health port {}", port))?;
+        Ok(Self::serve(listener, state))
+    }
+
+    pub fn serve(listener: TcpListener, state: HealthState) -> Self {
+        if let Ok(address) = listener.local_addr() {
+            info!(address = %address, "Serving device health endpoints");
+        }
+        HealthServer(tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, router(state)).await {
+                warn!(error = %e, "Device health server stopped");
+            }
+        }))
+    }
+}
+
+impl Drop for HealthServer {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
@@ -0,0 +1,295 @@
+use anyhow::{bail, Context, Result};
+use reqwest::Client;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use tracing::info;
+
+use crate::codec;
+use crate::config::{self, Config, ConfigSource};
+use crate::features::Features;
+use crate::naming;
+use crate::net;
+use crate::network::NetworkType;
+use crate::types::{ChaosFlags, RegisterResponse};
+use crate::validation;
+
+/// Starting points offered by the wizard; individual answers override them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Preset {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub sample_interval_secs: u64,
+    pub upload_interval_secs: u64,
+    pub heartbeat_interval_secs: u64,
+    pub network: Option<NetworkType>,
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset { name: "default", description: "balanced sampling on an unconditioned link", sample_interval_secs: 10, upload_interval_secs: 60, heartbeat_interval_secs: 30, network: None },
+    Preset { name: "low_power", description: "battery sensor on NB-IoT", sample_interval_secs: 300, upload_interval_secs: 3600, heartbeat_interval_secs: 900, network: Some(NetworkType::NbIot) },
+    Preset { name: "cellular", description: "vehicle tracker on LTE", sample_interval_secs: 30, upload_interval_secs: 300, heartbeat_interval_secs: 120, network: Some(NetworkType::Lte) },
+    Preset { name: "high_frequency", description: "mains-powered gateway on wifi", sample_interval_secs: 1, upload_interval_secs: 10, heartbeat_interval_secs: 15, network: Some(NetworkType::Wifi) },
+];
+
+pub fn preset(name: &str) -> Option<&'static Preset> {
+    PRESETS.iter().find(|preset| preset.name == name)
+}
+
+/// Answers given as flags; anything left unset is taken from the environment or asked for.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Answers {
+    pub backend_url: Option<String>,
+    pub provisioning_token: Option<String>,
+    pub profile: Option<String>,
+    pub device_name: Option<String>,
+    pub region: Option<String>,
+    pub sample_interval_secs: Option<u64>,
+    pub upload_interval_secs: Option<u64>,
+    pub heartbeat_interval_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InitArgs {
+    pub non_interactive: bool,
+    pub force: bool, // Replace an existing identity
+    pub config_dir: Option<PathBuf>,
+    pub answers: Answers,
+}
+
+impl InitArgs {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut parsed = InitArgs::default();
+        let answers = &mut parsed.answers;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().cloned().ok_or_else(|| format!("{} needs a value", arg));
+            let mut secs = || value()?.parse::<u64>().map_err(|e| format!("{}: {}", arg, e));
+            match arg.as_str() {
+                "--non-interactive" => parsed.non_interactive = true,
+                "--force" => parsed.force = true,
+                "--config-dir" => parsed.config_dir = Some(PathBuf::from(value()?)),
+                "--backend-url" => answers.backend_url = Some(value()?),
+                "--provisioning-token" => answers.provisioning_token = Some(value()?),
+                "--profile" => answers.profile = Some(value()?),
+                "--device-name" => answers.device_name = Some(value()?),
+                "--region" => answers.region = Some(value()?),
+                "--sample-interval-secs" => answers.sample_interval_secs = Some(secs()?),
+                "--upload-interval-secs" => answers.upload_interval_secs = Some(secs()?),
+                "--heartbeat-interval-secs" => answers.heartbeat_interval_secs = Some(secs()?),
+                other => return Err(format!("unknown argument {}", other)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// What the wizard registered, printed once it is done.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Identity {
+    pub device_id: String,
+    pub device_name: String,
+    pub backend_url: String,
+    pub config_file: PathBuf,
+}
+
+// Asks on `output` and reads one line from `input`; an empty answer takes the default
+struct Prompter<'a, R: BufRead, W: Write> {
+    interactive: bool,
+    input: &'a mut R,
+    output: &'a mut W,
+}
+
+impl<R: BufRead, W: Write> Prompter<'_, R, W> {
+    fn ask(&mut self, question: &str, default: &str) -> Result<String> {
+        if !self.interactive {
+            return Ok(default.to_string());
+        }
+        if default.is_empty() {
+            write!(self.output, "{}: ", question)?;
+        } else {
+            write!(self.output, "{} [{}]: ", question, default)?;
+        }
+        self.output.flush()?;
+        let mut line = String::new();
+        if self.input.read_line(&mut line)? == 0 {
+            bail!("input ended while asking for {}", question);
+        }
+        let answer = line.trim();
+        Ok(if answer.is_empty() { default.to_string() } else { answer.to_string() })
+    }
+
+    // Re-asks until `check` accepts the answer; without a terminal the first failure is final
+    fn ask_valid<T>(&mut self, question: &str, default: &str, check: impl Fn(&str) -> Result<T>) -> Result<T> {
+        loop {
+            match check(&self.ask(question, default)?) {
+                Ok(value) => return Ok(value),
+                Err(e) if self.interactive => writeln!(self.output, "  {:#}", e)?,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// An interval from its flag, else the environment, else a prompt defaulting to the preset
+fn choose_interval(
+    prompter: &mut Prompter<'_, impl BufRead, impl Write>,
+    config: &Config,
+    key: &str,
+    answer: Option<u64>,
+    env: Option<u64>,
+    preset_secs: u64,
+) -> Result<u64> {
+    let check = |secs: u64| validation::interval(config, key, secs).map(|()| secs).map_err(|e| anyhow::anyhow!("{} {}", key, e));
+    match answer.or(env) {
+        Some(secs) => check(secs),
+        None => prompter.ask_valid(key, &preset_secs.to_string(), |answer| {
+            let secs = answer.parse::<u64>().map_err(|e| anyhow::anyhow!("{} {}", key, e))?;
+            check(secs)
+        }),
+    }
+}
+
+/// Checks the backend answers its health endpoint.
+pub async fn check_backend(client: &Client, backend_url: &str) -> Result<()> {
+    validation::url(backend_url).map_err(|e| anyhow::anyhow!("backend URL {}", e))?;
+    let url = format!("{}/health", backend_url.trim_end_matches('/'));
+    client.get(&url).send().await
+        .and_then(|response| response.error_for_status())
+        .with_context(|| format!("backend at {} is not reachable", backend_url))?;
+    Ok(())
+}
+
+/// Registers with the backend and takes on the assigned identity, with empty shadow
+/// and chaos state as a freshly provisioned device starts with.
+pub async fn register(client: &Client, config: &mut Config, provisioning_token: Option<String>) -> Result<()> {
+    let features = Features::resolve(&config.features);
+    let accept_encoding = config.compression.accept_encoding();
+    let response = net::register_device(client, &config.backend_url, uuid::Uuid::new_v4(), config.device_name.clone(), features.report(), provisioning_token, &accept_encoding).await?;
+    adopt_identity(config, &response);
+    Ok(())
+}
+
+/// Takes on the identity in a registration response, starting from empty state.
+pub fn adopt_identity(config: &mut Config, response: &RegisterResponse) {
+    config.negotiated_encoding = codec::negotiate(&config.compression.preference, &response.supported_encodings);
+    info!(device_id = %response.device_id, advertised = ?response.supported_encodings, negotiated = ?config.negotiated_encoding, "Negotiated request encoding");
+    config.device_id = response.device_id.to_string();
+    config.auth_token = Some(response.auth_token.to_string());
+    config.desired_shadow_state = Some(json!({}));
+    config.reported_shadow_state = Some(json!({}));
+    config.chaos_flags = Some(ChaosFlags::default());
+}
+
+/// Runs the wizard: each value comes from its flag, else the environment, else a
+/// prompt (or its default when non-interactive). Registers the device and writes its
+/// config into the config directory. An existing identity is only replaced with `force`.
+pub async fn init(args: &InitArgs, vars: &HashMap<String, String>, input: &mut impl BufRead, output: &mut impl Write) -> Result<Identity> {
+    let config_dir = args.config_dir.clone()
+        .or_else(|| vars.get("CONFIG_DIR").map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("."));
+    let config_file = config_dir.join(config::CONFIG_FILE);
+    if config_file.exists() && !args.force {
+        bail!("{} already holds a device identity; pass --force to replace it", config_file.display());
+    }
+
+    let (mut config, report) = Config::from_env_vars(vars);
+    let from_env = |key: &str| report.sources.iter().any(|(source_key, source)| source_key == key && *source == ConfigSource::Env);
+    let answers = &args.answers;
+    let mut prompter = Prompter { interactive: !args.non_interactive, input, output };
+    let client = net::build_client(&config)?;
+
+    let given_url = answers.backend_url.clone().or_else(|| from_env("BACKEND_URL").then(|| config.backend_url.clone()));
+    config.backend_url = match given_url {
+        Some(url) => {
+            check_backend(&client, &url).await?;
+            url
+        }
+        None => loop {
+            let url = prompter.ask("Backend URL", &config.backend_url)?;
+            match check_backend(&client, &url).await {
+                Ok(()) => break url,
+                Err(e) if prompter.interactive => writeln!(prompter.output, "  {:#}", e)?,
+                Err(e) => return Err(e),
+            }
+        },
+    };
+
+    let provisioning_token = match answers.provisioning_token.clone() {
+        Some(token) => Some(token),
+        None => match vars.get("VF_PROVISIONING_TOKEN").or_else(|| vars.get("PROVISIONING_TOKEN")) {
+            Some(token) => Some(token.clone()),
+            None => Some(prompter.ask("Provisioning token (empty for none)", "")?).filter(|token| !token.is_empty()),
+        },
+    };
+
+    let profile_name = match answers.profile.clone() {
+        Some(name) => name,
+        None => {
+            let choices: Vec<String> = PRESETS.iter().map(|preset| format!("{} ({})", preset.name, preset.description)).collect();
+            prompter.ask(&format!("Profile, one of {}", choices.join(", ")), PRESETS[0].name)?
+        }
+    };
+    let profile = preset(&profile_name).ok_or_else(|| {
+        anyhow::anyhow!("unknown profile {:?}, expected one of {}", profile_name, PRESETS.iter().map(|preset| preset.name).collect::<Vec<_>>().join(", "))
+    })?;
+    if config.network.is_none() {
+        config.network = profile.network;
+    }
+
+    config.device_name = match answers.device_name.clone() {
+        Some(name) => Some(name),
+        None if from_env("DEVICE_NAME") => config.device_name.clone(),
+        None => Some(prompter.ask("Device name (empty to generate one)", "")?).filter(|name| !name.is_empty()),
+    };
+    config.region = match answers.region.clone() {
+        Some(region) => Some(region),
+        None if from_env("REGION") => config.region.clone(),
+        None => Some(prompter.ask("Region (empty for none)", "")?).filter(|region| !region.is_empty()),
+    };
+
+    let env_secs = |key: &str, secs: u64| from_env(key).then_some(secs);
+    config.sample_interval_secs = choose_interval(&mut prompter, &config, "sample_interval_secs", answers.sample_interval_secs,
+        env_secs("SAMPLE_INTERVAL_SECS", config.sample_interval_secs), profile.sample_interval_secs)?;
+    config.upload_interval_secs = choose_interval(&mut prompter, &config, "upload_interval_secs", answers.upload_interval_secs,
+        env_secs("UPLOAD_INTERVAL_SECS", config.upload_interval_secs), profile.upload_interval_secs)?;
+    config.heartbeat_interval_secs = choose_interval(&mut prompter, &config, "heartbeat_interval_secs", answers.heartbeat_interval_secs,
+        env_secs("HEARTBEAT_INTERVAL_SECS", config.heartbeat_interval_secs), profile.heartbeat_interval_secs)?;
+
+    register(&client, &mut config, provisioning_token).await?;
+    fs::create_dir_all(&config_dir)?;
+    config.save_to(&config_file)?;
+    Ok(Identity {
+        device_id: config.device_id.clone(),
+        device_name: naming::display_name(&config),
+        backend_url: config.backend_url.clone(),
+        config_file,
+    })
+}
+
+/// Entry point for `device init`: prints the identity as JSON, or the error. Exits 1
+/// on failure and 2 on bad arguments.
+pub async fn run(args: &[String]) -> ! {
+    let args = match InitArgs::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\nusage: device init [--non-interactive] [--force] [--config-dir DIR] [--backend-url URL] [--provisioning-token TOKEN] [--profile NAME] [--device-name NAME] [--region REGION] [--sample-interval-secs N] [--upload-interval-secs N] [--heartbeat-interval-secs N]", e);
+            std::process::exit(2);
+        }
+    };
+    let stdin = std::io::stdin();
+    let result = init(&args, &std::env::vars().collect(), &mut stdin.lock(), &mut std::io::stderr()).await;
+    match result {
+        Ok(identity) => {
+            println!("{}", serde_json::to_string_pretty(&identity).expect("identity serializes"));
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("device init failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
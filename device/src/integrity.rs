@@ -0,0 +1,123 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::types::{IngestFeedback, Measurement};
+use crate::{export, ota};
+
+// Not part of the content: the hash itself, and the marker set on copies re-sent by a replay
+const UNHASHED_FIELDS: [&str; 2] = ["content_hash", "replay"];
+
+/// SHA-256 of a measurement's canonical serialization, in hex. The canonical form is the
+/// measurement's JSON as uploaded, minus fields its cadence leaves out, with object keys
+/// sorted and no whitespace, so the backend hashing what it stored gets the same value.
+pub fn content_hash(measurement: &Measurement) -> String {
+    let mut value = serde_json::to_value(measurement).unwrap_or(Value::Null);
+    if let Value::Object(fields) = &mut value {
+        fields.retain(|name, _| !UNHASHED_FIELDS.contains(&name.as_str()) && !measurement.omitted.contains(name));
+    }
+    export::sha256_hex(canonical_json(&value).as_bytes())
+}
+
+/// JSON with object keys sorted at every level and no insignificant whitespace.
+pub fn canonical_json(value: &Value) -> String {
+    let mut out = String::new();
+    write_canonical(value, &mut out);
+    out
+}
+
+fn write_canonical(value: &Value, out: &mut String) {
+    match value {
+        Value::Object(fields) => {
+            let mut names: Vec<&String> = fields.keys().collect();
+            names.sort();
+            out.push('{');
+            for (i, name) in names.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&Value::String(name.clone()).to_string());
+                out.push(':');
+                write_canonical(&fields[name], out);
+            }
+            out.push('}');
+        }
+        Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out);
+            }
+            out.push(']');
+        }
+        scalar => out.push_str(&scalar.to_string()),
+    }
+}
+
+/// Root of the binary Merkle tree over content hashes in batch order: each parent is the
+/// SHA-256 of its children's digests concatenated, and an unpaired node moves up a level
+/// as it is. None for an empty batch or a hash that is not hex.
+pub fn merkle_root(hashes: &[&str]) -> Option<String> {
+    let mut level = hashes.iter().map(|hash| ota::decode_hex(hash)).collect::<Option<Vec<_>>>()?;
+    if level.is_empty() {
+        return None;
+    }
+    while level.len() > 1 {
+        level = level.chunks(2).map(|pair| match pair {
+            [left, right] => Sha256::new().chain_update(left).chain_update(right).finalize().to_vec(),
+            [single] => single.clone(),
+            _ => unreachable!("chunks of two"),
+        }).collect();
+    }
+    Some(level[0].iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// What the backend acknowledged of a batch, compared with what was sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verification {
+    Verified,
+    Unsupported, // The backend echoed nothing to check against
+    Mismatch { reason: String, suspect: Vec<usize> }, // Positions in the batch of rows it cannot vouch for
+}
+
+/// Checks the ingest acknowledgement of `batch` against the rows' own hashes: the echoed
+/// hashes must be exactly those sent, and an echoed Merkle root must match the local one.
+/// Rows stored without a hash are not covered.
+pub fn verify(batch: &[Measurement], feedback: Option<&IngestFeedback>) -> Verification {
+    let Some(feedback) = feedback.filter(|feedback| feedback.accepted_hashes.is_some() || feedback.merkle_root.is_some()) else {
+        return Verification::Unsupported;
+    };
+    let sent: Vec<(usize, &str)> = batch.iter().enumerate()
+        .filter_map(|(i, measurement)| measurement.content_hash.as_deref().map(|hash| (i, hash)))
+        .collect();
+    let everything = || sent.iter().map(|(i, _)| *i).collect::<Vec<_>>();
+
+    if let Some(accepted) = &feedback.accepted_hashes {
+        let echoed = |hash: &str| accepted.iter().any(|echoed| echoed.eq_ignore_ascii_case(hash));
+        let missing: Vec<usize> = sent.iter().filter(|(_, hash)| !echoed(hash)).map(|(i, _)| *i).collect();
+        if !missing.is_empty() {
+            return Verification::Mismatch { reason: format!("{} of {} hashes were not echoed back", missing.len(), sent.len()), suspect: missing };
+        }
+        if let Some(unknown) = accepted.iter().find(|echoed| !sent.iter().any(|(_, hash)| hash.eq_ignore_ascii_case(echoed))) {
+            return Verification::Mismatch { reason: format!("the backend echoed unknown hash {}", unknown), suspect: everything() };
+        }
+    }
+    if let Some(root) = &feedback.merkle_root {
+        let local = merkle_root(&sent.iter().map(|(_, hash)| *hash).collect::<Vec<_>>());
+        if !local.as_deref().is_some_and(|local| local.eq_ignore_ascii_case(root)) {
+            return Verification::Mismatch { reason: format!("batch Merkle root {} does not match {}", root, local.unwrap_or_default()), suspect: everything() };
+        }
+    }
+    Verification::Verified
+}
+
+/// Raised when the backend's acknowledgement of a batch does not match what was sent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct IntegrityMismatch {
+    pub timestamp: DateTime<Utc>,
+    pub reason: String,
+    pub sequence_numbers: Vec<u32>, // Of the rows the acknowledgement does not vouch for
+}
@@ -0,0 +1,75 @@
+use rand::Rng;
+use std::time::Duration;
+use tokio::time::{self, Instant};
+
+// Largest spread accepted; past it a period could come out near zero
+pub const MAX_FRACTION: f32 = 0.5;
+
+/// `period` moved by a uniform random share of up to ±`fraction` of it, so devices
+/// started together drift apart instead of ticking in step and hitting the backend at
+/// the same moments.
+pub fn jittered(period: Duration, fraction: f32, rng: &mut impl Rng) -> Duration {
+    let fraction = f64::from(fraction.clamp(0.0, MAX_FRACTION));
+    if fraction == 0.0 {
+        return period;
+    }
+    period.mul_f64(rng.gen_range(1.0 - fraction..=1.0 + fraction))
+}
+
+/// Wait before the first tick of a timer: a uniform random share of up to `fraction` of
+/// `period`, so a fleet started together does not send its first requests at once.
+pub fn first_tick_offset(period: Duration, fraction: f32, rng: &mut impl Rng) -> Duration {
+    let fraction = f64::from(fraction.clamp(0.0, MAX_FRACTION));
+    if fraction == 0.0 {
+        return Duration::ZERO;
+    }
+    period.mul_f64(rng.gen_range(0.0..=fraction))
+}
+
+/// A timer in place of `time::Interval` whose every tick is jittered: each one comes a
+/// freshly drawn jittered period after the last, so devices never fall back into step.
+/// Ticks missed while the loop was busy are not made up; the next one is a jittered
+/// period from when the late one completed.
+#[derive(Debug)]
+pub struct Interval {
+    period: Duration,
+    fraction: f32,
+    next: Instant,
+}
+
+/// An Interval of `secs` whose first tick comes after first_tick_offset.
+pub fn interval(secs: u64, fraction: f32) -> Interval {
+    let period = Duration::from_secs(secs);
+    let offset = first_tick_offset(period, fraction, &mut rand::thread_rng());
+    Interval { period, fraction, next: Instant::now() + offset }
+}
+
+impl Interval {
+    // The period ticks are jittered around; the device only tracks it in seconds, tests
+    // read it here
+    #[cfg(test)]
+    pub fn period(&self) -> Duration {
+        self.period
+    }
+
+    /// Completes at the next tick. Cancel safe, like `time::Interval::tick`: the next
+    /// tick is only drawn once this one has completed.
+    pub async fn tick(&mut self) -> Instant {
+        time::sleep_until(self.next).await;
+        let now = Instant::now();
+        let drawn = jittered(self.period, self.fraction, &mut rand::thread_rng());
+        // Counted from when the tick was due, unless the loop fell a whole period behind
+        self.next = if self.next + drawn > now { self.next + drawn } else { now + drawn };
+        now
+    }
+
+    /// Moves the next tick to a jittered period from now.
+    pub fn reset(&mut self) {
+        self.next = Instant::now() + jittered(self.period, self.fraction, &mut rand::thread_rng());
+    }
+
+    /// Makes the next tick complete at once.
+    pub fn reset_immediately(&mut self) {
+        self.next = Instant::now();
+    }
+}
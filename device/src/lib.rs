@@ -0,0 +1,1608 @@
+use anyhow::{Context, Result};
+use futures::FutureExt;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time;
+use serde_json::{json, Value};
+use tracing_subscriber::{fmt, prelude::*, filter};
+use tracing::{debug, info, info_span, error, warn, Instrument};
+use chrono::{DateTime, Utc};
+
+mod adaptive;
+mod anomaly;
+mod audit;
+mod auth;
+mod baseline;
+mod battery;
+mod cadence;
+mod can;
+mod chaos;
+mod chaos_crash;
+mod clock;
+mod codec;
+mod config;
+mod conformance;
+mod corruption;
+mod cost;
+mod crash;
+mod debug_session;
+mod degradation;
+mod desired;
+mod dry_run;
+mod environment;
+mod error;
+mod export;
+mod external;
+mod features;
+mod firmware;
+mod fleet;
+mod geo;
+mod geofence;
+mod health;
+mod init;
+mod integrity;
+mod jitter;
+mod localtime;
+mod maintenance;
+mod metrics;
+mod naming;
+mod net;
+mod network;
+mod offline;
+mod outbox;
+mod ota;
+mod ota_history;
+mod push;
+mod replay;
+mod reefer;
+mod reported;
+mod residency;
+mod response_capture;
+mod route;
+mod runner;
+mod schema;
+mod shadow_report;
+mod shed;
+mod shutdown;
+mod simulate;
+mod slots;
+mod snapshot;
+mod stats;
+mod storage;
+mod telemetry;
+mod txn;
+mod types;
+mod upload;
+mod validation;
+
+#[cfg(test)]
+mod tests;
+
+use audit::{AuditLog, AuditSource};
+use config::Config;
+use debug_session::{DebugScope, DebugSession, DebugStream};
+use ota::OtaState;
+use types::{ChaosFlags, ChaosTarget};
+
+pub use chaos_crash::EXIT_CODE as CRASH_EXIT_CODE;
+pub use config::{ConfigError, InvalidSetting};
+pub use error::DeviceError;
+pub use net::NetError;
+pub use ota::{ChecksumMismatch, MetadataRejection, OtaError, SignatureRejection};
+pub use residency::ResidencyError;
+pub use shutdown::SignalsUnavailable;
+pub use slots::SlotFault;
+pub use storage::StorageError;
+pub use telemetry::TelemetryHandle;
+
+/// Runs the offline command `args` name, `validate`, `init`, `conformance` or `restore`,
+/// which exits the process once done; returns if they name none.
+pub async fn run_command(args: &[String]) {
+    match args.first().map(String::as_str) {
+        Some("validate" | "--validate") => validation::run(&args[1..]),
+        Some("init") => init::run(&args[1..]).await,
+        Some("conformance") => conformance::run(&args[1..]).await,
+        Some("restore") => snapshot::run(&args[1..]),
+        _ => {}
+    }
+}
+
+/// Sets up JSON logging, filtered by RUST_LOG, for the process. Trace export is switched
+/// on through the handle once a device knows its identity.
+pub fn init_tracing() -> TelemetryHandle {
+    let (telemetry_layer, telemetry_handle) = telemetry::layer();
+    tracing_subscriber::registry()
+        .with(telemetry_layer)
+        .with(fmt::layer().json().with_filter(filter::EnvFilter::from_default_env())) // Allows setting log level via RUST_LOG env var
+        .with(crash::EventLayer.with_filter(filter::LevelFilter::INFO)) // Recent events for crash snapshots
+        .with(debug_session::EventLayer.with_filter(filter::LevelFilter::DEBUG)) // Debug logs of devices in a debug session
+        .init();
+    telemetry_handle
+}
+
+/// Runs the device, or the fleet `--devices` or NUM_DEVICES in `args` asks for, until it
+/// stops. A lone device also returns when it reboots or crashes, for whatever runs it to
+/// start it again; a fleet restarts its members in place and returns once all have
+/// stopped. Traces are flushed unless the device crashed. Whether an error is worth
+/// retrying is documented on DeviceError's variants.
+pub async fn run(args: &[String], telemetry_handle: TelemetryHandle) -> Result<DeviceExit, DeviceError> {
+    let devices = fleet::fleet_size(args, &std::env::vars().collect()).context(config::InvalidSetting("--devices or NUM_DEVICES"))?;
+    // SIGUSR1 or the control API pauses every device and snapshots the fleet; the control
+    // API restores a running fleet in place, `device restore` a stopped one
+    let snapshots = Arc::new(snapshot::Controller::default());
+    let snapshot_dir = std::env::var("SNAPSHOT_DIR").unwrap_or_else(|_| snapshot::DEFAULT_SNAPSHOT_DIR.to_string());
+    if devices == 1 {
+        let mut pause_requests = snapshots.port(None);
+        snapshots.clone().listen(snapshot_dir.into());
+        // A lone device has no fleet runner; only signals stop it
+        let (_, mut stop_requests) = mpsc::channel(1);
+        let span = info_span!("device", device_id = tracing::field::Empty);
+        let exit = run_device(&fleet::DevicePaths::single(), None, Some(&telemetry_handle), &mut pause_requests, &mut stop_requests).instrument(span).await.map_err(stopped_on_error)?;
+        if !matches!(exit, DeviceExit::Crash) {
+            telemetry::shutdown();
+        }
+        return Ok(exit);
+    }
+
+    info!(devices, "Simulating a fleet in one process");
+    let members: Vec<_> = (0..devices).map(fleet::DevicePaths::fleet_member).collect();
+    // Identities are handed out up front, rather than each device registering as it boots
+    let base_config = Config::from_env()?;
+    let environments = environment::Environments::from_env_vars(&std::env::vars().collect(), Utc::now())?;
+    match fleet::register_members(&net::build_client(&base_config)?, &base_config, &members).await {
+        Ok(registration) if registration != fleet::Registration::default() => info!(?registration, "Registered fleet devices"),
+        Ok(_) => {}
+        Err(e) => warn!(error = %e, "Fleet registration failed; devices register themselves as they boot"),
+    }
+    // Trace export belongs to the process; the first device sets it up on its first boot
+    let telemetry_handle = Arc::new(Mutex::new(Some(telemetry_handle)));
+    // Snapshot ports outlive a device being stopped and started again
+    let environments_for_members = environments.clone();
+    let ports = snapshots.clone();
+    let launch: runner::Launch = Arc::new(move |paths: fleet::DevicePaths, mut stop_requests: mpsc::Receiver<shutdown::StopMode>| {
+        let (telemetry_handle, ports) = (telemetry_handle.clone(), ports.clone());
+        let environment = environments_for_members.for_member(paths.index.unwrap_or_default());
+        async move {
+            let first_boot_telemetry = telemetry_handle.lock().unwrap().take();
+            let mut pause_requests = ports.acquire(paths.index);
+            let stopped = supervise(&paths, environment, first_boot_telemetry, &mut pause_requests, &mut stop_requests).await;
+            ports.release(paths.index, pause_requests);
+            stopped
+        }
+        .boxed()
+    });
+    let fleet = runner::FleetRunner::launch(members, Arc::new(fleet::DevicePaths::fleet_member), launch)
+        .with_environments(environments)
+        .with_snapshots(snapshots.clone(), snapshot_dir.clone().into());
+    snapshots.listen(snapshot_dir.into());
+    if let Some(control_api) = fleet::control_api(&std::env::vars().collect())? {
+        let mut shutdown_signals = shutdown::ShutdownSignals::install()?;
+        runner::serve(&control_api, fleet.clone()).await?;
+        // Devices stopped through the API may be started again, so only a signal ends the run
+        shutdown_signals.recv().await;
+    }
+    fleet.stopped().await;
+    telemetry::shutdown();
+    Ok(DeviceExit::Shutdown)
+}
+
+/// Runs a fleet member through its reboots until it stops.
+async fn supervise(
+    paths: &fleet::DevicePaths,
+    environment: Option<environment::SharedEnvironment>,
+    mut first_boot_telemetry: Option<telemetry::TelemetryHandle>,
+    pause_requests: &mut mpsc::Receiver<snapshot::PauseRequest>,
+    stop_requests: &mut mpsc::Receiver<shutdown::StopMode>,
+) -> Result<(), DeviceError> {
+    let index = paths.index.unwrap_or_default();
+    loop {
+        let telemetry_handle = first_boot_telemetry.take();
+        let boot = run_device(paths, environment.clone(), telemetry_handle.as_ref(), pause_requests, stop_requests)
+            .instrument(info_span!("device", index, device_id = tracing::field::Empty));
+        match boot.await.map_err(stopped_on_error)? {
+            DeviceExit::Reboot => info!(index, "Rebooting simulated device"),
+            // Exiting would take the whole fleet down, so a crashed member is restarted in place
+            DeviceExit::Crash => warn!(index, "Restarting crashed simulated device"),
+            DeviceExit::Shutdown => return Ok(()),
+        }
+    }
+}
+
+/// Sorts the error a device stopped on, see error::DeviceError, and logs what can be done
+/// about it.
+fn stopped_on_error(error: anyhow::Error) -> DeviceError {
+    let error = DeviceError::from(error);
+    error!(kind = error.kind(), retryable = error.retryable(), error = %error.report(), "Device stopped on an error");
+    error
+}
+
+/// Why a device's run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceExit {
+    Reboot, // New or rolled-back firmware; the device starts again from its persisted state
+    Crash, // On the crash chaos flag; a lone device exits with chaos_crash::EXIT_CODE
+    Shutdown,
+}
+
+/// Runs one device until it reboots or is stopped. Everything it persists lives under
+/// `paths`, so several can run side by side in one process. Devices in a shared
+/// `environment` sample its air temperature.
+async fn run_device(
+    paths: &fleet::DevicePaths,
+    environment: Option<environment::SharedEnvironment>,
+    telemetry_handle: Option<&telemetry::TelemetryHandle>,
+    pause_requests: &mut mpsc::Receiver<snapshot::PauseRequest>,
+    stop_requests: &mut mpsc::Receiver<shutdown::StopMode>,
+) -> Result<DeviceExit> {
+    let mut config = match Config::load_from(&paths.config) {
+        Ok(mut conf) => {
+            info!(device_id = %conf.device_id, "Loaded config from file: {:?}", conf);
+            if config::strict_mode_enabled() {
+                let mut report = config::ConfigReport::from_file();
+                for key in config::unrecognized_env_vars(&std::env::vars().collect()) {
+                    report.warnings.push(format!("Unrecognized environment variable {}", key));
+                }
+                report.log();
+            }
+            // Initialize shadow states from config if they exist
+            if conf.desired_shadow_state.is_none() {
+                conf.desired_shadow_state = Some(json!({}));
+            }
+            if conf.reported_shadow_state.is_none() {
+                conf.reported_shadow_state = Some(json!({}));
+            }
+            conf
+        },
+        Err(e) => {
+            error!(error = %e, "Could not load config from file. Attempting to register device.");
+            let mut boot_config = fleet::boot_config(&Config::from_env()?, paths.index); // Get initial config from env (especially backend_url)
+            
+            // Shadow and chaos state start out empty upon registration
+            init::register(&net::build_client(&boot_config)?, &mut boot_config, None).await?;
+            boot_config.save_to(&paths.config)?;
+            info!(device_id = %boot_config.device_id, "Device registered and config saved.");
+            boot_config
+        }
+    };
+
+    // Everything the device writes but its config lives under the configured data directory
+    let data_paths = paths.clone().in_data_dir(&config.data_dir);
+    std::fs::create_dir_all(&data_paths.firmware_dir).with_context(|| format!("Failed to create data directory {}", config.data_dir.display()))?;
+    data_paths.adopt(paths);
+    let paths = &data_paths;
+
+    // First boot after a snapshot restore: the device continues where the snapshot left it
+    let restored = snapshot::take_restored(&paths.restored_runtime)?;
+    // Everything below reads this rather than the wall clock, so a restored device resumes at the snapshot's instant
+    let clock = clock::Clock::starting_at(restored.as_ref().map_or_else(Utc::now, |runtime| runtime.taken_at));
+
+    // Roll forward a config transaction interrupted by a crash before starting with the config
+    if let Some(outcome) = txn::recover(&mut config, &paths.staged_txn, &paths.config)? {
+        info!(device_id = %config.device_id, ?outcome, "Completed interrupted config transaction");
+    }
+    // A token refreshed after a 401 or 403 is written back to the config file
+    config.session = auth::Session::persisting_to(paths.config.clone());
+    tracing::Span::current().record("device_id", config.device_id.as_str());
+    // A panic from here on leaves a snapshot of the device's state for the next start to report
+    if paths.owns_process() {
+        if let Err(e) = crash::install(&paths.crash_dir, &config.device_id) {
+            warn!(device_id = %config.device_id, error = %e, "Crash snapshots disabled");
+        }
+    }
+
+    info!(device_id = %config.device_id, device_name = %naming::display_name(&config), "Device starting with config: {:?}", config);
+
+    // Same checks as the validate command; the device still starts and falls back where it can
+    for finding in config.validate().findings {
+        match finding.severity {
+            validation::Severity::Error => error!(device_id = %config.device_id, key = %finding.key, "Invalid config: {}", finding.message),
+            validation::Severity::Warning => warn!(device_id = %config.device_id, key = %finding.key, "Config warning: {}", finding.message),
+        }
+    }
+
+    let mut conn = storage::init_with(&paths.database, config.storage_cache_kib, config.storage_busy_timeout_ms)?;
+    info!(device_id = %config.device_id, "Initialized local database.");
+
+    // Per-endpoint API counters; cumulative totals continue from the last checkpoint
+    let api_stats = stats::ApiStats::load(&conn)?;
+
+    // Reported paths the backend refused; blocks continue from the last checkpoint
+    let mut shadow_guard = shadow_report::ShadowReportGuard::load(config.shadow_rejection_policy.clone(), &conn)?;
+
+    // Simulated sensor wear; progress continues from the last checkpoint
+    let mut degradation = config.degradation.clone()
+        .map(|settings| degradation::Degradation::load(settings, &conn))
+        .transpose()?;
+
+    // Running cost estimate; usage continues from the last checkpoint
+    let mut cost_model = config.cost_model.clone()
+        .map(|settings| cost::CostModel::load(settings, &conn, &api_stats.cumulative()))
+        .transpose()?;
+
+    let mut ota_state = OtaState::load_from(&paths.ota_state)?;
+    info!(device_id = %config.device_id, "Loaded OTA state: {:?}", ota_state);
+    // Firmware from before slot directories moves into them
+    if ota::migrate_slots(&mut ota_state, &paths.firmware_dir)? {
+        ota_state.save_to(&paths.ota_state)?;
+    }
+
+    // --- CHAOS: Fail boot confirmation of freshly installed firmware ---
+    let fail_boot_confirmation = matches!(
+        config.chaos_flags.as_ref().and_then(|chaos| chaos.get("fail_boot_confirmation")),
+        Some(Value::Bool(true))
+    );
+    // --- END CHAOS ---
+    let mut audit_log = AuditLog::open(&paths.audit_log, &config.device_id)?;
+
+    // Data-subject exports; one interrupted by a restart resumes at the next shadow check
+    let mut subject_exporter = export::SubjectExporter::load(&conn, paths.exports_dir.clone())?;
+
+    // Unconfirmed firmware is on trial: it must send enough heartbeats within the window, over a bounded number of boots
+    let trial_policy = ota::TrialPolicy::from_config(&config);
+    let trial_version = ota_state.current_version.clone();
+    let pending_trial = ota_state.pending_confirmation;
+    let rebooted = ota_state.finish_reboot();
+    if ota::handle_trial_boot(&mut ota_state, &trial_policy, fail_boot_confirmation, clock.now()) {
+        audit_log.record(AuditSource::Startup, "ota_rollback", json!(trial_version), json!(ota_state.current_version));
+    }
+    // Only a slot whose image still matches its manifest boots
+    let boot = ota::boot_slot(&mut ota_state, clock.now());
+    if let (Some(fault), Some(manifest)) = (&boot.fault, &boot.manifest) {
+        audit_log.record(
+            AuditSource::Startup,
+            "ota_slot_fallback",
+            json!({ "slot": fault.slot(), "error": fault.to_string() }),
+            json!({ "slot": manifest.slot, "version": manifest.version }),
+        );
+    }
+    if pending_trial || rebooted || boot.fault.is_some() {
+        // Persist the boot count before anything can crash this boot
+        ota_state.save_to(&paths.ota_state)?;
+    }
+
+    // Simulated behavior of the running firmware: the booted slot's overlay, else by version
+    let firmware_behavior = boot.manifest.as_ref()
+        .and_then(|manifest| manifest.behavior.clone())
+        .unwrap_or_else(|| ota_state.behavior(&config.firmware_behaviors));
+    let seed = simulate::device_seed(config.simulation_seed, paths.index);
+    let mut simulator = simulate::Simulator::new(config.sensor_profile, seed, simulate::Position::default());
+    match config.can_signals.as_deref().filter(|_| config.sensor_profile.has_can_bus()) {
+        Some(path) => match can::SignalSet::load(std::path::Path::new(path)) {
+            Ok(signals) => {
+                info!(device_id = %config.device_id, path, signals = signals.signals.len(), raw_frames = config.can_raw_frames, "Simulating CAN bus signals");
+                simulator = simulator.with_can_bus(can::CanBus::new(signals, config.can_raw_frames));
+            }
+            Err(e) => error!(device_id = %config.device_id, error = %format!("{:#}", e), "Failed to load CAN signals; sampling without them"),
+        },
+        None if config.can_signals.is_some() => {
+            warn!(device_id = %config.device_id, profile = config.sensor_profile.as_str(), "CAN signals are only simulated for the vehicle profiles");
+        }
+        None => {}
+    }
+    if let Some(environment) = environment {
+        info!(device_id = %config.device_id, environment = %environment.name, "Sampling a shared environment");
+        simulator = simulator.with_environment(environment);
+    }
+    if config.sensor_profile == simulate::SensorProfile::ReeferTrailer {
+        let reefer_config = config.reefer.clone().unwrap_or_default();
+        info!(device_id = %config.device_id, setpoint_c = reefer_config.setpoint_c, stops = reefer_config.stops.len(), "Simulating a reefer trailer");
+        simulator = simulator.with_reefer(reefer::Reefer::new(reefer_config, clock.now()));
+    }
+    match route::configured(&config).filter(|_| config.sensor_profile.bounds().gps) {
+        Some(Ok(route)) => {
+            let end = route::RouteEnd::from_config(&config);
+            info!(device_id = %config.device_id, path = ?config.route_file, waypoints = route.waypoints().len(), length_m = route.length_m(), ?end, "Following route");
+            simulator = simulator.with_route(route::RouteFollower::new(route, end));
+        }
+        Some(Err(e)) => error!(device_id = %config.device_id, error = %format!("{:#}", e), "Failed to load route; using a random walk"),
+        None if config.route_file.is_some() || !config.route_waypoints.is_empty() => {
+            warn!(device_id = %config.device_id, profile = config.sensor_profile.as_str(), "Routes are only followed by profiles with GPS");
+        }
+        None => {}
+    }
+    if simulator.set_clock_skew(config.chaos_flags.as_ref().and_then(|chaos| chaos.get("clock_skew_secs"))) {
+        warn!(device_id = %config.device_id, chaos_type = "clock_skew_secs", skew_secs = simulator.clock_skew().num_seconds(), "Injecting clock skew into measurement timestamps");
+    }
+    if simulator.set_clock_drift(config.chaos_flags.as_ref().and_then(|chaos| chaos.get("clock_drift_secs_per_hour")), clock.now()) {
+        warn!(device_id = %config.device_id, chaos_type = "clock_drift_secs_per_hour", report = %simulator.clock_report(clock.now()), "Injecting clock drift into measurement timestamps");
+    }
+    if let Some(runtime) = &restored {
+        info!(device_id = %config.device_id, taken_at = %runtime.taken_at, sequence_number = runtime.simulator.sequence_number, "Resuming from fleet snapshot");
+        simulator.restore(&runtime.simulator, clock.now() - runtime.taken_at);
+    }
+    // Battery charge and charging cycle; both continue from the last checkpoint
+    let mut battery_model = battery::Battery::load(config.battery_drain_rate, config.charge_rate, &conn)?;
+    // Simulated discharging cell; the level continues from the last checkpoint
+    let mut battery_drain = battery::BatteryDrain::load(&conn)?;
+    // Geohash cells of GPS fixes; dwell times continue from the last checkpoint
+    let mut geo_buckets = geo::GeoBuckets::load(config.geo_buckets.clone(), &conn)?;
+    let mut geofence_monitor = config.geofence.clone().filter(|_| config.sensor_profile.bounds().gps).map(geofence::GeofenceMonitor::new);
+    if battery_drain.set_flag(config.chaos_flags.as_ref().and_then(|chaos| chaos.get("battery_drain"))) {
+        warn!(device_id = %config.device_id, chaos_type = "battery_drain", level = ?battery_drain.level(), "Battery drain changed");
+    }
+    info!(device_id = %config.device_id, version = %ota_state.current_version, behavior = ?firmware_behavior, sensor_profile = config.sensor_profile.as_str(), seed, "Applied firmware behavior");
+
+    // Optional OTLP trace export, identified by device and running firmware
+    if let Some(telemetry_handle) = telemetry_handle {
+        match telemetry::init(telemetry_handle, &config, &ota_state.current_version) {
+            Ok(true) => info!(device_id = %config.device_id, "Exporting traces over OTLP"),
+            Ok(false) => {}
+            Err(e) => warn!(device_id = %config.device_id, error = %e, "Trace export disabled, failed to set up OTLP exporter"),
+        }
+    }
+
+    // Experimental behaviors, resolved from defaults and persisted overrides
+    let mut features = features::Features::resolve(&config.features);
+    for warning in features.warnings() {
+        warn!(device_id = %config.device_id, warning = %warning, "Ignoring feature override");
+    }
+    info!(device_id = %config.device_id, features = %features.report(), "Resolved feature set");
+
+    // Data residency: with strict residency and no endpoint for this region, data is held locally
+    if let Err(e) = residency::target_for(&config, config.region.as_deref()) {
+        error!(device_id = %config.device_id, error = %e, "Residency misconfigured, holding measurements until the region is mapped");
+    }
+
+    // Optional local-time emission; timestamps stay UTC internally
+    let timezone = config.timezone.as_deref().and_then(|name| match localtime::parse_timezone(name) {
+        Ok(tz) => Some(tz),
+        Err(e) => {
+            warn!(device_id = %config.device_id, error = %e, "Ignoring timezone, emitting UTC only");
+            None
+        }
+    });
+
+    let mut upload_metrics = upload::UploadMetrics::default();
+    let mut analyze_after_drain = storage::AnalyzeAfterDrain::default();
+
+    // Degrades the sample rate instead of growing the backlog without bound
+    let mut shedder = shed::Shedder::new(config.shed.clone());
+    let mut dropped_measurements: u64 = 0; // Evicted by the storage limits, until the next heartbeat reports them
+
+    // Simulated network conditions; roaming switches networks by region or on a schedule
+    let mut roaming = config.network_roaming.clone().map(network::Roaming::new);
+    if let Some(next) = roaming.as_mut().and_then(|r| r.advance(0, config.region.as_deref())) {
+        config.network = Some(next);
+    }
+    shedder.set_min_aggregation(network::active_profile(&config).map_or(1, |profile| profile.min_aggregation));
+
+    // Optional co-simulator feed; the synthetic model remains the fallback
+    let external_feed = config.external_source.clone()
+        .filter(|_| features.external_source())
+        .map(external::ExternalFeed::spawn);
+    let mut external_stalled = false;
+
+    // Optional on-device anomaly detection, mirroring the backend's logic
+    let mut self_detector = config.self_detection.clone().map(anomaly::SelfDetector::new);
+    // Per-field upload cadences; unset sends every field in every sample
+    let mut cadence_engine = cadence::CadenceEngine::new(config.cadence.clone());
+
+    let client = net::build_client(&config)?;
+    // OTA status reaches the shadow as it changes; a failed update is re-reported after restart
+    let ota_reporter = ota::OtaStatusReporter::spawn(client.clone(), config.clone(), api_stats.clone());
+    let _ = ota_reporter.sender().send(ota_state.ota_status.clone());
+    match crash::report_pending(&client, &config, &api_stats, &paths.crash_dir, &ota_state.current_version).await {
+        Ok(0) => {}
+        Ok(reported) => info!(device_id = %config.device_id, reported, "Reported crashes from previous runs"),
+        Err(e) => warn!(device_id = %config.device_id, error = %e, "Failed to report crashes from previous runs"),
+    }
+    // Owned by this device, so the device task can move between threads
+    let mut chaos = chaos::ChaosEngine::new(restored.as_ref().map(|runtime| runtime.chaos_seed));
+    // Background faults of the profile; drawn from entropy, so a seeded device does not restart at the same tick every boot
+    let mut baseline_faults = baseline::BaselineFaults::new(config.sensor_profile, &config.fault_baseline, config.background_faults, None);
+    info!(device_id = %config.device_id, enabled = config.background_faults, rates = ?baseline_faults.baseline(), "Simulating background faults");
+
+    let mut sample_interval_secs = restored.as_ref().map_or(config.sample_interval_secs, |runtime| runtime.sample_interval_secs);
+    let mut upload_interval_secs = restored.as_ref().map_or(config.upload_interval_secs, |runtime| runtime.upload_interval_secs);
+    let mut heartbeat_interval_secs = restored.as_ref().map_or(config.heartbeat_interval_secs, |runtime| runtime.heartbeat_interval_secs);
+    let shadow_check_interval_secs = 60; // How often to check for shadow updates
+    let schema_refresh_interval_secs = 24 * 60 * 60; // Refresh the measurement schema daily
+    let stats_checkpoint_interval_secs = 60; // Debounces persisting API statistics
+
+    // Every tick is jittered, the first included, so a fleet started together spreads its
+    // requests out and stays spread
+    let jitter = config.interval_jitter;
+    let mut sample_interval = jitter::interval(sample_interval_secs, jitter);
+    let mut upload_interval = jitter::interval(upload_interval_secs, jitter);
+    let mut heartbeat_interval = jitter::interval(heartbeat_interval_secs, jitter);
+    let mut ota_check_interval = jitter::interval(config.ota_check_interval_secs, jitter);
+    let mut shadow_check_interval = jitter::interval(shadow_check_interval_secs, jitter);
+    let mut synced_shadow: Option<types::DeviceShadow> = None; // Received with a combined heartbeat, not yet applied
+    // Off for the rest of the run once the backend turns out to have no sync endpoint
+    let mut combined_sync = config.combined_sync;
+    // While the combined sync fails the shadow is fetched on its own, so desired state
+    // still arrives through whichever request gets through
+    let mut sync_failing = false;
+    let mut schema_refresh_interval = jitter::interval(schema_refresh_interval_secs, jitter);
+    let mut stats_checkpoint_interval = jitter::interval(stats_checkpoint_interval_secs, jitter);
+    let mut push_keepalive_interval = time::interval(Duration::from_secs(1)); // Granularity of push keepalive checks
+    let mut storage_age_interval = time::interval(Duration::from_secs(60)); // Granularity of the storage age limit
+    let crash_check_secs = 1; // Granularity of chaos crashes
+    let mut crash_check_interval = time::interval(Duration::from_secs(crash_check_secs));
+    if ota_state.last_update.is_some() {
+        // The first thing out after an update is the heartbeat reporting it
+        heartbeat_interval.reset_immediately();
+        upload_interval.reset();
+        shadow_check_interval.reset();
+        ota_check_interval.reset();
+        schema_refresh_interval.reset();
+    }
+
+    // Device events that failed to send come back here to be queued in the outbox
+    let (failed_events, mut failed_event_queue) = mpsc::unbounded_channel();
+
+    // A debug session resumes after a restart; one that expired while the device was down is ended now
+    let mut debug_stream = None;
+    if let Some(restored) = debug_session::end_if_due(&mut config.debug_session, clock.now()) {
+        info!(device_id = %config.device_id, ?restored, "Debug session expired while the device was down; restoring settings");
+        apply_debug_settings(&mut audit_log, jitter, &restored, (&mut sample_interval_secs, &mut sample_interval), (&mut heartbeat_interval_secs, &mut heartbeat_interval));
+        if let Err(e) = config.save_to(&paths.config) {
+            error!(device_id = %config.device_id, error = %e, "Failed to save config after ending debug session");
+        }
+    } else if let Some(session) = config.debug_session.as_ref().filter(|session| session.is_active(clock.now())) {
+        info!(device_id = %config.device_id, scopes = ?session.scopes, expires_at = %session.expires_at, "Resuming debug session");
+        let elevated = session.elevated(&interval_settings(sample_interval_secs, heartbeat_interval_secs));
+        apply_debug_settings(&mut audit_log, jitter, &elevated, (&mut sample_interval_secs, &mut sample_interval), (&mut heartbeat_interval_secs, &mut heartbeat_interval));
+        if session.scopes.contains(&DebugScope::Logs) {
+            debug_stream = Some(DebugStream::spawn(client.clone(), config.clone(), api_stats.clone()));
+        }
+    }
+
+    // Desired state reaches the device over a simulated push connection, subject to carrier NAT
+    let started_at = time::Instant::now();
+    let mut push_channel = push::PushChannel::new(config.push_keepalive.clone(), push::nat_idle_timeout(&config));
+    let mut reconnect_replay = replay::ReconnectReplay::new(config.reconnect_replay_secs, config.reconnect_replay_max_rows);
+
+    // Last known backend measurement schema; None means every field is sent
+    let schema_cache_path = paths.schema_cache.clone();
+    let mut measurement_schema = schema::load_cached(&schema_cache_path);
+    let mut schema_rejected_values: u64 = 0;
+
+    // Reported shadow sections, each owned by the subsystem that publishes it
+    let reported_state = reported::ReportedState::new(config.reported_shadow_state.as_ref(), reported::MAX_DOCUMENT_BYTES);
+    let sections = reported::Sections::register(&reported_state)?;
+
+    let mut shutdown_signals = shutdown::ShutdownSignals::install()?;
+
+    // Self-test endpoints for operators; fleet members listen on consecutive ports
+    let health = health::HealthState::new(health::HealthStatus {
+        device_id: config.device_id.clone(),
+        firmware_version: ota_state.current_version.clone(),
+        active_slot: ota_state.active_slot.clone(),
+        buffered_measurements: storage::pending_count(&conn).unwrap_or(0),
+        last_upload_at: None,
+    }, &config);
+    let _health_server = match config.health_port.map(|port| u16::try_from(port as usize + paths.index.unwrap_or(0))) {
+        Some(Ok(port)) => health::HealthServer::start(port, health.clone()).await
+            .map_err(|e| warn!(device_id = %config.device_id, error = %e, "Health endpoints disabled"))
+            .ok(),
+        Some(Err(_)) => {
+            warn!(device_id = %config.device_id, "Health endpoints disabled, no port left for this fleet member");
+            None
+        }
+        None => None,
+    };
+
+    loop {
+        let debug_session_remaining = config.debug_session.as_ref()
+            .filter(|session| session.ended_at.is_none())
+            .map(|session| session.remaining(clock.now()));
+        let offline_remaining = offline::remaining(config.offline_window.as_ref(), clock.now());
+        if let Some(stream) = &debug_stream {
+            stream.set_offline(offline_remaining.is_some());
+        }
+        tokio::select! {
+            _ = sample_interval.tick() => {
+                // --- CHAOS: Power saving on a drained battery skips sample ticks ---
+                if !battery_drain.admit() {
+                    continue;
+                }
+                // --- BASELINE: A battery near end of life samples less often ---
+                if !baseline_faults.admit(battery_model.level()) {
+                    continue;
+                }
+                let mut measurement = simulator.generate_measurement_at(clock.now(), ota_state.current_version.clone(), &firmware_behavior); // Pass firmware_version
+                health.metrics().measurements_generated.inc();
+                for breach in simulator.take_breaches() {
+                    warn!(device_id = %config.device_id, kind = breach.kind.flag(), cargo_c = breach.peak_c, limit_c = breach.limit_c, "Cargo left the cold-chain band");
+                    report_event(&client, &config, &api_stats, &conn, &failed_events, types::DeviceEvent::ColdChainBreach(breach), clock.now());
+                }
+                if let Some(feed) = &external_feed {
+                    match feed.next_record(std::time::Instant::now()) {
+                        Some(record) => {
+                            feed.apply(&record, &mut measurement);
+                            if external_stalled {
+                                info!(device_id = %config.device_id, "External measurement source recovered");
+                                external_stalled = false;
+                            }
+                        }
+                        None => {
+                            if !external_stalled {
+                                warn!(device_id = %config.device_id, "External measurement source stalled, falling back to synthetic model");
+                                external_stalled = true;
+                            }
+                        }
+                    }
+                }
+                // --- CHAOS: Accelerated battery drain ---
+                let drain_factor = config.chaos_flags.as_ref()
+                    .and_then(|chaos| chaos.get("battery_drain_factor"))
+                    .and_then(|flag| battery::drain_factor(flag).ok())
+                    .unwrap_or(1.0);
+                match battery_model.apply(&mut measurement, clock.now(), &api_stats.since_boot(), drain_factor) {
+                    Some(battery::BatteryPhase::Charging) => warn!(device_id = %config.device_id, level = battery_model.level(), "Battery low, charging"),
+                    Some(battery::BatteryPhase::Draining) => info!(device_id = %config.device_id, level = battery_model.level(), "Battery charged"),
+                    None => {}
+                }
+                if battery_drain.apply(&mut measurement) {
+                    warn!(device_id = %config.device_id, chaos_type = "battery_drain", level = ?battery_drain.level(), "Battery low, reducing sample frequency to save power");
+                }
+                health.metrics().battery_level.set(measurement.battery as f64);
+                if let Some(tz) = timezone {
+                    // --- CHAOS: Stale UTC offset around DST transitions ---
+                    let broken_dst = matches!(
+                        config.chaos_flags.as_ref().and_then(|chaos| chaos.get("broken_dst")),
+                        Some(Value::Bool(true))
+                    );
+                    localtime::stamp(&mut measurement, tz, broken_dst);
+                }
+                // --- BASELINE: Lost GPS fix ---
+                if baseline_faults.gps_dropout(&mut measurement) {
+                    debug!(device_id = %config.device_id, fault = "gps_dropout", "Sample lost its GPS fix");
+                }
+                if let Some(transition) = geo_buckets.apply(&mut measurement) {
+                    info!(device_id = %config.device_id, from = %transition.from, to = %transition.to, "Moved into another geohash cell");
+                }
+                if let Some(event) = geofence_monitor.as_mut().and_then(|monitor| monitor.check(&measurement)) {
+                    match &event {
+                        types::DeviceEvent::GeofenceReentry(crossing) => info!(device_id = %config.device_id, latitude = crossing.latitude, longitude = crossing.longitude, "Back inside the geofence"),
+                        _ => warn!(device_id = %config.device_id, ?event, "Left the geofence"),
+                    }
+                    report_event(&client, &config, &api_stats, &conn, &failed_events, event, clock.now());
+                }
+                if let Some(model) = degradation.as_mut() {
+                    for sensor in model.advance(sample_interval_secs as f64) {
+                        warn!(device_id = %config.device_id, sensor = %sensor, "Sensor reached end of life");
+                        audit_log.record(AuditSource::Sampler, "sensor_failed", json!(sensor), json!(model.health_report()));
+                    }
+                    model.apply(&mut measurement);
+                }
+                measurement.region = config.region.clone();
+                if let Some(next) = roaming.as_mut().and_then(|r| r.advance(sample_interval_secs, config.region.as_deref())) {
+                    apply_network_change(&mut audit_log, AuditSource::Sampler, &mut config, &mut shedder, next);
+                }
+                measurement.network = config.network;
+                if maintenance::is_active(config.maintenance.as_ref(), clock.now()) {
+                    measurement.maintenance = Some(true);
+                }
+                if let Some(detector) = self_detector.as_mut().filter(|_| features.self_detection()) {
+                    let flags = detector.evaluate(&measurement);
+                    if !flags.is_empty() {
+                        info!(device_id = %config.device_id, ?flags, "Self-detection flagged measurement");
+                        measurement.device_flags.get_or_insert_with(Vec::new).extend(flags);
+                    }
+                }
+                info!(device_id = %config.device_id, "Generated measurement: {:?}", measurement);
+                if let Some(active_schema) = measurement_schema.as_ref().filter(|_| features.schema_filter()) {
+                    let rejected = schema::count_rejections(active_schema, &measurement);
+                    if rejected > 0 {
+                        schema_rejected_values += rejected;
+                        warn!(device_id = %config.device_id, rejected, total = schema_rejected_values, "Measurement has values the backend schema would reject");
+                    }
+                }
+                let backlog = match storage::pending_count(&conn) {
+                    Ok(backlog) => {
+                        if let Some(change) = shedder.update(backlog) {
+                            warn!(device_id = %config.device_id, active = change.active, backlog = change.backlog, decimation_factor = change.decimation_factor, "Sample shed mode changed");
+                            audit_log.record(AuditSource::Sampler, "shed_mode", json!(!change.active), json!(shedder.report()));
+                        }
+                        Some(backlog)
+                    }
+                    Err(e) => {
+                        error!(device_id = %config.device_id, error = %e, "Failed to count pending measurements");
+                        None
+                    }
+                };
+                // The panic hook is process-wide, so in a fleet it snapshots the first device
+                if paths.owns_process() {
+                    crash::update_state(&json!({
+                        "metrics": {"api_stats": api_stats.report(), "upload": upload_metrics.report()},
+                        "failed_responses": api_stats.failed_responses(),
+                        "runtime": {
+                            "uptime_secs": started_at.elapsed().as_secs(),
+                            "sequence_number": measurement.sequence_number,
+                            "schema_rejected_values": schema_rejected_values,
+                            "sample_interval_secs": sample_interval_secs,
+                        },
+                        "state": {
+                            "ota_status": ota_state.ota_status,
+                            "push": push_channel.report(),
+                            "shed": shedder.report(),
+                            "replay": reconnect_replay.report(),
+                            "subject_export": subject_exporter.report(),
+                        },
+                        "backlog": {"pending": backlog, "max_stored": config.max_stored_measurements},
+                    }));
+                }
+                let Some(mut measurement) = shedder.admit(measurement) else {
+                    continue;
+                };
+                cadence_engine.apply(&mut measurement);
+                // Hashed last, over exactly what is stored and sent
+                measurement.content_hash = Some(integrity::content_hash(&measurement));
+                match storage::append_measurement(&conn, &measurement, config.max_stored_measurements) { // No await here
+                    Ok(evicted) => {
+                        dropped_measurements += evicted as u64;
+                        health.metrics().measurements_dropped.inc_by(evicted as u64);
+                        if let Some(backlog) = backlog {
+                            health.update(|status| status.buffered_measurements = (backlog + 1).saturating_sub(evicted as u64));
+                        }
+                    }
+                    Err(e) => error!(device_id = %config.device_id, error = %e, "Failed to store measurement"),
+                }
+                reconnect_replay.remember(&measurement);
+            }
+            _ = upload_interval.tick() => {
+                if offline::is_active(config.offline_window.as_ref(), clock.now()) {
+                    debug!(device_id = %config.device_id, chaos_type = "offline", "Offline window, holding measurements");
+                    continue;
+                }
+                info!(device_id = %config.device_id, "Attempting to upload measurements...");
+
+                // --- CHAOS: Random Error ---
+                if chaos.inject_error(config.chaos_flags.as_ref(), ChaosTarget::Ingest) {
+                    warn!(device_id = %config.device_id, chaos_type = "random_error", "Simulated network error during upload");
+                    health.metrics().upload_failures.inc();
+                    upload_metrics.record_failure("simulated network error");
+                    // Skip actual upload, measurements remain in local DB
+                    continue;
+                }
+                // --- END CHAOS ---
+                // --- BASELINE: Cellular dead zone or upload timeout ---
+                if let Some(fault) = baseline_faults.upload_fault() {
+                    warn!(device_id = %config.device_id, fault = fault.as_str(), "Upload failed on the fault baseline");
+                    health.metrics().upload_failures.inc();
+                    upload_metrics.record_failure(fault.as_str());
+                    if fault == baseline::UploadFault::DeadZone {
+                        reconnect_replay.observe(false);
+                    }
+                    continue;
+                }
+
+                let active_schema = measurement_schema.as_ref().filter(|_| features.schema_filter());
+                // Recent rows go out first after an outage so dashboards repaint before the backlog drains
+                upload::send_replay(&client, &config, &api_stats, &mut reconnect_replay, active_schema, clock.now())
+                    .instrument(info_span!("reconnect_replay", device_id = %config.device_id))
+                    .await;
+                let cycle = upload::drain_once_with_chaos(&client, &config, &api_stats, &mut conn, active_schema, &mut chaos)
+                    .instrument(info_span!("upload_cycle", device_id = %config.device_id));
+                match cycle.await {
+                    Ok(mut round) => {
+                        upload_metrics.record(&round);
+                        for mut mismatch in round.integrity_mismatches.drain(..) {
+                            mismatch.timestamp = simulator.device_time(mismatch.timestamp);
+                            audit_log.record(AuditSource::IngestFeedback, "integrity_mismatch", Value::Null, json!(mismatch));
+                            report_event(&client, &config, &api_stats, &conn, &failed_events, types::DeviceEvent::IntegrityMismatch(mismatch), clock.now());
+                        }
+                        health.metrics().measurements_uploaded.inc_by(round.uploaded() as u64);
+                        health.metrics().upload_failures.inc_by(round.batches.iter().filter(|batch| !batch.uploaded).count() as u64);
+                        if let Err(e) = analyze_after_drain.record(&conn, round.uploaded() as u64) {
+                            warn!(device_id = %config.device_id, error = %e, "Failed to refresh query planner statistics");
+                        }
+                        if !round.batches.is_empty() {
+                            reconnect_replay.observe(round.uploaded() > 0);
+                        }
+                        if let Ok(backlog) = storage::pending_count(&conn) {
+                            health.update(|status| status.buffered_measurements = backlog);
+                        }
+                        if round.uploaded() > 0 {
+                            health.update(|status| status.last_upload_at = Some(clock.now()));
+                            // Back in contact: the heartbeat tick flushes what was queued meanwhile
+                            if read_outbox(&config, &conn).is_some_and(|pending| !pending.is_empty()) {
+                                heartbeat_interval.reset_immediately();
+                            }
+                        }
+                        health.set_config(&config);
+                        if round.batches.is_empty() {
+                            info!(device_id = %config.device_id, "No measurements to upload");
+                        } else {
+                            info!(device_id = %config.device_id, count = round.uploaded(), batches = round.batches.len(), "Measurements ingested");
+                        }
+                        if ota_state.pending_confirmation && round.uploaded() > 0 {
+                            let confirmed = ota_state.record_trial_ingest(&trial_policy);
+                            if let Err(e) = ota_state.save_to(&paths.ota_state) {
+                                error!(device_id = %config.device_id, error = %e, "Failed to save OTA trial state");
+                            } else if confirmed {
+                                info!(device_id = %config.device_id, version = %ota_state.current_version, "Confirmed boot of new firmware");
+                            }
+                        }
+                        // Closed-loop adaptive sampling: apply backend suggestion within configured bounds
+                        if let Some(feedback) = round.feedback.filter(|_| features.adaptive_sampling()) {
+                            if let Some(new_val) = adaptive::apply_ingest_feedback(sample_interval_secs, &feedback, config.min_sample_interval_secs, config.max_sample_interval_secs) {
+                                apply_control_interval(&mut audit_log, jitter, &mut config.debug_session, AuditSource::IngestFeedback, "sample_interval_secs", &mut sample_interval_secs, &mut sample_interval, new_val, clock.now());
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        error!(device_id = %config.device_id, error = %e, "Failed to get measurements from local DB");
+                        health.metrics().upload_failures.inc();
+                        upload_metrics.record_failure(&e.to_string());
+                    }
+                }
+            }
+            _ = heartbeat_interval.tick() => {
+                let trial_version = ota_state.current_version.clone();
+                if ota::check_trial(&mut ota_state, &trial_policy, clock.now()) {
+                    audit_log.record(AuditSource::Ota, "ota_rollback", json!(trial_version), json!(ota_state.current_version));
+                    if let Err(e) = ota_state.save_to(&paths.ota_state) {
+                        error!(device_id = %config.device_id, error = %e, "Failed to save rolled back OTA state");
+                    }
+                    // Reboot into the previous slot
+                    return Ok(DeviceExit::Reboot);
+                }
+                // --- BASELINE: Unplanned restart ---
+                if baseline_faults.restart(heartbeat_interval_secs as f64) {
+                    warn!(device_id = %config.device_id, fault = "restart", "Restarting unexpectedly on the fault baseline");
+                    checkpoint_models(&conn, &config, &api_stats, degradation.as_ref(), &battery_model, &battery_drain, &geo_buckets, cost_model.as_mut(), clock.now());
+                    return Ok(DeviceExit::Reboot);
+                }
+                if offline::is_active(config.offline_window.as_ref(), clock.now()) {
+                    debug!(device_id = %config.device_id, chaos_type = "offline", "Offline window, skipping heartbeat");
+                    continue;
+                }
+                info!(device_id = %config.device_id, "Sending heartbeat");
+                
+                // --- CHAOS: Random Error ---
+                if chaos.inject_error(config.chaos_flags.as_ref(), ChaosTarget::Heartbeat) {
+                    warn!(device_id = %config.device_id, chaos_type = "random_error", "Simulated network error during heartbeat");
+                    // Skip actual heartbeat
+                    continue;
+                }
+                // --- END CHAOS ---
+
+                // Whatever was queued while offline goes first; the backend's answer to a
+                // queued heartbeat is applied like a live one, then the live one's on top
+                let mut desired_states = Vec::new();
+                if let Some(pending) = read_outbox(&config, &conn).filter(|pending| !pending.is_empty()) {
+                    let mut flushed = pending.send(&client, &config, &api_stats, &mut shadow_guard).await;
+                    desired_states.extend(flushed.take_desired_state());
+                    settle_outbox(&config, &conn, &mut shadow_guard, flushed);
+                }
+
+                let mut heartbeat = net::heartbeat_body(&config, &ota_state.current_version, sample_interval_secs, upload_interval_secs, heartbeat_interval_secs);
+                if features.full_heartbeat_telemetry() || debug_session::covers(config.debug_session.as_ref(), DebugScope::Heartbeat, clock.now()) {
+                    heartbeat.anomaly_counts = self_detector.as_ref().map(|d| d.counts().clone());
+                    heartbeat.api_stats = Some(api_stats.report());
+                }
+                heartbeat.features = Some(features.report());
+                heartbeat.shed_samples = Some(shedder.shed_samples());
+                heartbeat.decimation_factor = Some(shedder.decimation_factor());
+                heartbeat.rollback = ota_state.last_rollback.clone();
+                heartbeat.uptime_secs = Some(started_at.elapsed().as_secs());
+                heartbeat.pending_measurements = storage::pending_count(&conn).ok();
+                heartbeat.consecutive_upload_failures = Some(upload_metrics.consecutive_failures());
+                heartbeat.last_upload_error = upload_metrics.last_error().map(str::to_string);
+                heartbeat.battery_level = Some(battery_model.level());
+                heartbeat.free_disk_bytes = storage::free_disk_bytes(&config.data_dir).ok();
+                heartbeat.ota_update = ota_state.last_update.clone();
+                heartbeat.device_time = Some(simulator.device_time(clock.now()));
+                heartbeat.dropped_measurements = (dropped_measurements > 0).then_some(dropped_measurements);
+                let heartbeat_span = info_span!("heartbeat", device_id = %config.device_id);
+                let synced = if combined_sync {
+                    net::sync(&client, &config, &api_stats, &heartbeat).instrument(heartbeat_span.clone()).await
+                } else {
+                    Ok(None)
+                };
+                let sent = match synced {
+                    Ok(Some(synced)) => {
+                        // The shadow that came back is handled by the shadow branch, right away
+                        synced_shadow = Some(synced.shadow);
+                        shadow_check_interval.reset_immediately();
+                        Ok(synced.desired_state)
+                    }
+                    Ok(None) => {
+                        if combined_sync {
+                            warn!(device_id = %config.device_id, "Backend has no sync endpoint; sending heartbeats and fetching the shadow separately");
+                            combined_sync = false;
+                        }
+                        net::send_heartbeat(&client, &config, &api_stats, &heartbeat).instrument(heartbeat_span).await
+                    }
+                    Err(e) => Err(e),
+                };
+                sync_failing = combined_sync && sent.is_err();
+                match sent {
+                    Ok(desired_state) => {
+                        info!(device_id = %config.device_id, ?desired_state, "Received desired state in heartbeat response");
+                        health.metrics().heartbeats_sent.inc();
+                        reconnect_replay.observe(true);
+                        // This heartbeat supersedes one queued while offline that failed to flush
+                        if let Err(e) = outbox::clear(&conn, outbox::OutboxKind::Heartbeat) {
+                            error!(device_id = %config.device_id, error = %e, "Failed to clear queued heartbeat");
+                        }
+                        dropped_measurements -= heartbeat.dropped_measurements.unwrap_or(0);
+                        if heartbeat.rollback.is_some() {
+                            info!(device_id = %config.device_id, "Reported firmware rollback");
+                            ota_state.last_rollback = None;
+                        }
+                        if heartbeat.ota_update.is_some() {
+                            info!(device_id = %config.device_id, "Reported firmware update");
+                            ota_state.last_update = None;
+                        }
+                        if ota_state.pending_confirmation || heartbeat.rollback.is_some() || heartbeat.ota_update.is_some() {
+                            let confirmed = ota_state.record_trial_heartbeat(&trial_policy);
+                            if let Err(e) = ota_state.save_to(&paths.ota_state) {
+                                error!(device_id = %config.device_id, error = %e, "Failed to save OTA trial state");
+                            } else if confirmed {
+                                info!(device_id = %config.device_id, version = %ota_state.current_version, "Confirmed boot of new firmware");
+                            }
+                        }
+                        desired_states.push(desired_state);
+                    }
+                    Err(e) => {
+                        error!(device_id = %config.device_id, error = %e, "Failed to send heartbeat");
+                        reconnect_replay.observe(false);
+                        if let Err(e) = outbox::queue_heartbeat(&conn, &heartbeat, clock.now()) {
+                            error!(device_id = %config.device_id, error = %e, "Failed to queue heartbeat for later");
+                        }
+                    }
+                }
+                for desired_state in desired_states {
+                    // These interval updates are also reflected in the shadow, but handled here for immediate effect
+                    apply_control_interval(&mut audit_log, jitter, &mut config.debug_session, AuditSource::Heartbeat, "sample_interval_secs", &mut sample_interval_secs, &mut sample_interval, desired_state.desired_sample_interval_secs, clock.now());
+                    apply_control_interval(&mut audit_log, jitter, &mut config.debug_session, AuditSource::Heartbeat, "upload_interval_secs", &mut upload_interval_secs, &mut upload_interval, desired_state.desired_upload_interval_secs, clock.now());
+                    apply_control_interval(&mut audit_log, jitter, &mut config.debug_session, AuditSource::Heartbeat, "heartbeat_interval_secs", &mut heartbeat_interval_secs, &mut heartbeat_interval, desired_state.desired_heartbeat_interval_secs, clock.now());
+                    // Note: desired_version is not handled here, but in the ota module.
+                }
+            }
+            _ = ota_check_interval.tick() => {
+                if offline::is_active(config.offline_window.as_ref(), clock.now()) {
+                    debug!(device_id = %config.device_id, chaos_type = "offline", "Offline window, skipping OTA check");
+                    continue;
+                }
+                info!(device_id = %config.device_id, "Checking for OTA update");
+                health.metrics().ota_checks.inc();
+                // --- CHAOS: Random Error ---
+                if chaos.inject_error(config.chaos_flags.as_ref(), ChaosTarget::Ota) {
+                    warn!(device_id = %config.device_id, chaos_type = "random_error", "Simulated network error during OTA check");
+                    continue;
+                }
+                // --- END CHAOS ---
+                // Sent, with the apply phase announced, before going dark to apply an update
+                let mut heartbeat = net::heartbeat_body(&config, &ota_state.current_version, sample_interval_secs, upload_interval_secs, heartbeat_interval_secs);
+                heartbeat.uptime_secs = Some(started_at.elapsed().as_secs());
+                heartbeat.device_time = Some(simulator.device_time(clock.now()));
+                // Maintenance holds back only the apply and reboot; the update is still fetched
+                let hold_apply = maintenance::is_active(config.maintenance.as_ref(), clock.now());
+                let check = ota::check_for_update(&client, &config, &api_stats, paths, &mut ota_state, &mut audit_log, ota_reporter.sender(), &heartbeat, hold_apply)
+                    .instrument(info_span!("ota_check", device_id = %config.device_id));
+                match check.await {
+                    Ok(true) => {
+                        // Simulate reboot: a lone device exits and Docker restarts the container
+                        ota_reporter.flush(Duration::from_secs(5)).await;
+                        return Ok(DeviceExit::Reboot);
+                    }
+                    Ok(false) => info!(device_id = %config.device_id, "OTA check completed"),
+                    Err(e) => error!(device_id = %config.device_id, error = %e, "OTA check failed"),
+                }
+            }
+            _ = storage_age_interval.tick(), if config.max_storage_age_secs > 0 => {
+                match storage::evict_expired(&conn, config.max_storage_age_secs, clock.now()) {
+                    Ok(evicted) => {
+                        dropped_measurements += evicted as u64;
+                        health.metrics().measurements_dropped.inc_by(evicted as u64);
+                    }
+                    Err(e) => error!(device_id = %config.device_id, error = %e, "Failed to evict expired measurements"),
+                }
+            }
+            Some(event) = failed_event_queue.recv() => {
+                if let Err(e) = outbox::queue_event(&conn, &event, clock.now()) {
+                    error!(device_id = %config.device_id, error = %e, ?event, "Failed to queue device event for later");
+                }
+            }
+            _ = stats_checkpoint_interval.tick() => {
+                checkpoint_models(&conn, &config, &api_stats, degradation.as_ref(), &battery_model, &battery_drain, &geo_buckets, cost_model.as_mut(), clock.now());
+            }
+            Some(pause) = pause_requests.recv() => {
+                // Between branches everything is consistent; persist it all, then capture
+                checkpoint_models(&conn, &config, &api_stats, degradation.as_ref(), &battery_model, &battery_drain, &geo_buckets, cost_model.as_mut(), clock.now());
+                config.reported_shadow_state = Some(reported_state.document());
+                let runtime = snapshot::DeviceRuntime {
+                    taken_at: clock.now(),
+                    simulator: simulator.snapshot(),
+                    chaos_seed: chaos.reseed(),
+                    sample_interval_secs,
+                    upload_interval_secs,
+                    heartbeat_interval_secs,
+                };
+                let captured = config.save_to(&paths.config).map_err(anyhow::Error::from)
+                    .and_then(|_| Ok(ota_state.save_to(&paths.ota_state)?))
+                    .and_then(|_| snapshot::write_device(&pause.dir, paths, &conn, &runtime))
+                    .map(|_| snapshot::ManifestEntry { index: paths.index, device_id: config.device_id.clone() })
+                    .map_err(|e| format!("{:#}", e));
+                info!(device_id = %config.device_id, dir = %pause.dir.display(), captured = captured.is_ok(), "Paused for fleet snapshot");
+                pause.finish(captured).await;
+            }
+            _ = schema_refresh_interval.tick() => {
+                if offline::is_active(config.offline_window.as_ref(), clock.now()) {
+                    continue;
+                }
+                match net::fetch_measurement_schema(&client, &config, &api_stats).await {
+                    Ok(fetched) => {
+                        if let Err(e) = schema::save_cached(&schema_cache_path, fetched.as_ref()) {
+                            error!(device_id = %config.device_id, error = %e, "Failed to persist measurement schema");
+                        }
+                        measurement_schema = fetched;
+                    }
+                    Err(e) => {
+                        // Keep honoring the last known schema while the backend is unreachable
+                        error!(device_id = %config.device_id, error = %e, "Failed to fetch measurement schema");
+                    }
+                }
+            }
+            _ = push_keepalive_interval.tick() => {
+                if offline::is_active(config.offline_window.as_ref(), clock.now()) {
+                    continue;
+                }
+                push_channel.set_nat_idle_timeout(push::nat_idle_timeout(&config));
+                if push_channel.poll(started_at.elapsed().as_secs()) == push::KeepaliveAction::Reconnect {
+                    warn!(device_id = %config.device_id, "Missed push keepalive pong, reconnecting");
+                    push_channel.reconnect(started_at.elapsed().as_secs());
+                    // Handoff: fetch the full desired state now instead of at the next shadow check
+                    shadow_check_interval.reset_immediately();
+                }
+            }
+            _ = crash_check_interval.tick() => {
+                // --- CHAOS: Crash ---
+                if !chaos.crash(config.chaos_flags.as_ref(), crash_check_secs as f64) {
+                    continue;
+                }
+                let mut dirty = config.chaos_flags.as_ref().and_then(|chaos| chaos.crash.as_ref()).is_some_and(|crash| crash.dirty);
+                if dirty && paths.index.is_some() {
+                    // Exiting would take the whole fleet down with it
+                    warn!(device_id = %config.device_id, chaos_type = "crash", "Fleet members cannot crash dirty; crashing clean instead");
+                    dirty = false;
+                }
+                if !dirty {
+                    // Everything a shutdown would keep, so only the restart itself is tested
+                    config.chaos_crashes += 1;
+                    checkpoint_models(&conn, &config, &api_stats, degradation.as_ref(), &battery_model, &battery_drain, &geo_buckets, cost_model.as_mut(), clock.now());
+                    if let Err(e) = storage::sync(&conn) {
+                        error!(device_id = %config.device_id, error = %e, "Failed to sync the measurement database before crashing");
+                    }
+                    config.reported_shadow_state = Some(reported_state.document());
+                    if let Err(e) = config.save_to(&paths.config) {
+                        error!(device_id = %config.device_id, error = %e, "Failed to save config before crashing");
+                    }
+                    if let Err(e) = ota_state.save_to(&paths.ota_state) {
+                        error!(device_id = %config.device_id, error = %e, "Failed to save OTA state before crashing");
+                    }
+                }
+                error!(
+                    device_id = %config.device_id,
+                    event = "chaos_crash",
+                    chaos_type = "crash",
+                    dirty,
+                    crashes = config.chaos_crashes,
+                    uptime_secs = started_at.elapsed().as_secs(),
+                    "Crashing on the crash chaos flag"
+                );
+                if dirty {
+                    // Unwinding would close the database cleanly, so the process ends right here
+                    std::process::exit(chaos_crash::EXIT_CODE);
+                }
+                return Ok(DeviceExit::Crash);
+            }
+            _ = shadow_check_interval.tick() => {
+                if offline::is_active(config.offline_window.as_ref(), clock.now()) {
+                    debug!(device_id = %config.device_id, chaos_type = "offline", "Offline window, skipping shadow check");
+                    continue;
+                }
+                let shadow_span = info_span!("shadow_sync", device_id = %config.device_id);
+                let fetched = match synced_shadow.take() {
+                    Some(shadow) => Ok(shadow), // Came back with the last heartbeat
+                    None if combined_sync && !sync_failing => continue, // Arrives with the next heartbeat
+                    None => {
+                        info!(device_id = %config.device_id, "Checking device shadow...");
+                        // --- CHAOS: Random Error ---
+                        if chaos.inject_error(config.chaos_flags.as_ref(), ChaosTarget::Shadow) {
+                            warn!(device_id = %config.device_id, chaos_type = "random_error", "Simulated network error during shadow sync");
+                            continue;
+                        }
+                        // --- END CHAOS ---
+                        net::fetch_device_shadow(&client, &config, &api_stats).instrument(shadow_span.clone()).await
+                    }
+                };
+                match fetched {
+                    Ok(shadow) => {
+                        let desired = shadow.desired.and_then(|desired| push_channel.receive(started_at.elapsed().as_secs(), desired));
+                        // A dry run reports what the document would do; it applies when it comes again without the marker
+                        if let Some(desired) = desired.as_ref().filter(|desired| dry_run::requested(desired)) {
+                            let runtime = dry_run::Runtime {
+                                config: &config,
+                                features: &features,
+                                sample_interval_secs,
+                                upload_interval_secs,
+                                heartbeat_interval_secs,
+                                degradation: degradation.as_ref(),
+                                subject_exporter: &subject_exporter,
+                            };
+                            let result = dry_run::preview(runtime, desired, clock.now());
+                            let version = push::desired_version(desired);
+                            info!(device_id = %config.device_id, version = %version, ?result, "Previewed desired shadow state without applying it");
+                            sections.dry_run_result.set(json!({ version: result }));
+                            if let Err(e) = reported_state.report(&client, &config, &api_stats, &mut shadow_guard).instrument(shadow_span.clone()).await {
+                                error!(device_id = %config.device_id, error = %format!("{:#}", e), "Failed to report dry run result");
+                            }
+                            if let Err(e) = shadow_guard.checkpoint(&conn) {
+                                error!(device_id = %config.device_id, error = %e, "Failed to checkpoint shadow report rejections");
+                            }
+                        } else if let Some(desired) = desired {
+                            info!(device_id = %config.device_id, ?desired, "Received desired shadow state");
+
+                            // Confined to a block: the effects borrow the connection, which cannot be held across an await
+                            let outcome = {
+                                let mut target = desired::Target {
+                                    config: &mut config,
+                                    features: &mut features,
+                                    sample_interval_secs: &mut sample_interval_secs,
+                                    upload_interval_secs: &mut upload_interval_secs,
+                                    heartbeat_interval_secs: &mut heartbeat_interval_secs,
+                                    degradation: degradation.as_mut(),
+                                    subject_exporter: &mut subject_exporter,
+                                };
+                                let mut effects = LiveEffects {
+                                    conn: &conn,
+                                    audit_log: &mut audit_log,
+                                    paths,
+                                    jitter,
+                                    sample_timer: &mut sample_interval,
+                                    upload_timer: &mut upload_interval,
+                                    heartbeat_timer: &mut heartbeat_interval,
+                                    simulator: &mut simulator,
+                                    battery_drain: &mut battery_drain,
+                                    self_detector: &mut self_detector,
+                                    cadence_engine: &mut cadence_engine,
+                                    geo_buckets: &mut geo_buckets,
+                                    shedder: &mut shedder,
+                                    debug_stream: &mut debug_stream,
+                                    client: &client,
+                                    api_stats: &api_stats,
+                                    clock,
+                                };
+                                desired::apply(&mut target, &desired, &mut effects, clock.now())
+                            };
+                            if !outcome.is_empty() {
+                                info!(device_id = %config.device_id, ?outcome, "Applied desired shadow state");
+                            }
+                            for (key, reason) in &outcome.reject {
+                                warn!(device_id = %config.device_id, key = %key, error = %reason, "Ignoring desired setting");
+                            }
+                            if let Some(window) = config.offline_window.as_ref().filter(|window| outcome.apply.contains_key("offline_window") && window.is_active(clock.now())) {
+                                warn!(device_id = %config.device_id, chaos_type = "offline", until = %window.until, "Going offline; sampling continues but nothing is sent");
+                            }
+
+                            if let Err(e) = subject_exporter.advance(&client, &config, &api_stats, &mut conn, &mut audit_log).await {
+                                error!(device_id = %config.device_id, error = %e, "Subject data export stalled");
+                            }
+
+                            // Each subsystem publishes its section; only the ones that changed are reported
+                            sections.device_name.set(json!(naming::display_name(&config)));
+                            sections.sample_interval_secs.set(json!(sample_interval_secs));
+                            sections.upload_interval_secs.set(json!(upload_interval_secs));
+                            sections.heartbeat_interval_secs.set(json!(heartbeat_interval_secs));
+                            sections.clock.set(simulator.clock_report(clock.now()));
+                            sections.chaos_flags.set(config.chaos_flags.as_ref().map_or_else(|| json!({}), |chaos_flags| json!(chaos_flags)));
+                            sections.random_error.set(json!(config.chaos_flags.as_ref().and_then(ChaosFlags::random_error)));
+                            sections.schema_rejected_values.set(json!(schema_rejected_values));
+                            if external_feed.is_some() {
+                                sections.external_source_stalled.set(json!(external_stalled));
+                            }
+                            sections.api_stats.publish(&api_stats);
+                            sections.firmware_behavior.set(json!(firmware_behavior));
+                            sections.features.publish(&features);
+                            sections.shed.publish(&shedder);
+                            sections.cadence.publish(&cadence_engine);
+                            sections.upload.publish(&upload_metrics);
+                            sections.residency.set(residency::report(&config));
+                            sections.network.set(network::report(&config));
+                            sections.push.publish(&push_channel);
+                            sections.replay.publish(&reconnect_replay);
+                            sections.ota_status.set(json!(ota_state.ota_status));
+                            sections.ota_history.set(ota_state.history_report(clock.now()));
+                            sections.subject_export.publish(&subject_exporter);
+                            sections.battery.publish(&battery_drain);
+                            sections.power.publish(&battery_model);
+                            sections.fault_baseline.publish(&baseline_faults);
+                            sections.geo.publish(&geo_buckets);
+                            if let Some(model) = &cost_model {
+                                sections.costs.publish(model);
+                            }
+                            if let Some(model) = &degradation {
+                                sections.sensor_health.publish(model);
+                            }
+                            if let Some(reefer) = simulator.reefer() {
+                                sections.cold_chain.publish(reefer);
+                            }
+                            if let Some(outcome) = &config.last_config_txn {
+                                sections.config_txn.set(json!({ outcome.id.clone(): outcome }));
+                            }
+                            sections.debug_session.set(debug_session::report(config.debug_session.as_ref(), debug_stream.as_ref(), clock.now()));
+                            sections.connection.set(json!("online"));
+                            sections.maintenance.set(config.maintenance.as_ref()
+                                .map(|m| m.to_reported(clock.now()))
+                                .unwrap_or_else(|| json!({"active": false})));
+                            sections.offline.set(config.offline_window.as_ref()
+                                .map(|window| window.to_reported(clock.now()))
+                                .unwrap_or_else(|| json!({"active": false})));
+
+                            // Persist reported shadow state to config
+                            config.reported_shadow_state = Some(reported_state.document());
+                            if let Err(e) = config.save_to(&paths.config) {
+                                error!(device_id = %config.device_id, error = %e, "Failed to save config with reported shadow state");
+                            }
+
+                            // Report the changed sections back to backend
+                            match reported_state.report(&client, &config, &api_stats, &mut shadow_guard).instrument(shadow_span.clone()).await {
+                                Ok(true) => {
+                                    info!(device_id = %config.device_id, "Reported current shadow state");
+                                    if let Err(e) = outbox::clear(&conn, outbox::OutboxKind::ShadowReport) {
+                                        error!(device_id = %config.device_id, error = %e, "Failed to clear queued shadow report");
+                                    }
+                                }
+                                Ok(false) => debug!(device_id = %config.device_id, "Reported shadow state unchanged; nothing to report"),
+                                Err(e) => {
+                                    error!(device_id = %config.device_id, error = %format!("{:#}", e), "Failed to report shadow state");
+                                    // Refused reports cannot succeed as they are; the rest go out once the backend is back
+                                    if e.downcast_ref::<shadow_report::ShadowRejected>().is_none() {
+                                        if let Err(e) = outbox::queue_shadow_report(&conn, &reported_state.document(), clock.now()) {
+                                            error!(device_id = %config.device_id, error = %e, "Failed to queue shadow report for later");
+                                        }
+                                    }
+                                }
+                            }
+                            if let Err(e) = shadow_guard.checkpoint(&conn) {
+                                error!(device_id = %config.device_id, error = %e, "Failed to checkpoint shadow report rejections");
+                            }
+                        } else {
+                            info!(device_id = %config.device_id, "No desired shadow state received");
+                        }
+                    }
+                    Err(e) => {
+                        error!(device_id = %config.device_id, error = %e, "Failed to fetch device shadow");
+                    }
+                }
+            }
+            _ = time::sleep(debug_session_remaining.unwrap_or_default()), if debug_session_remaining.is_some() => {
+                if let Some(restored) = debug_session::end_if_due(&mut config.debug_session, clock.now()) {
+                    info!(device_id = %config.device_id, ?restored, "Debug session ended; restoring settings");
+                    apply_debug_settings(&mut audit_log, jitter, &restored, (&mut sample_interval_secs, &mut sample_interval), (&mut heartbeat_interval_secs, &mut heartbeat_interval));
+                    if let Some(stream) = debug_stream.take() {
+                        stream.close(Duration::from_secs(2)).await;
+                    }
+                    if let Err(e) = config.save_to(&paths.config) {
+                        error!(device_id = %config.device_id, error = %e, "Failed to save config after ending debug session");
+                    }
+                }
+            }
+            _ = time::sleep(offline_remaining.unwrap_or_default()), if offline_remaining.is_some() => {
+                // Back online: drain the backlog and check in right away
+                info!(device_id = %config.device_id, chaos_type = "offline", backlog = storage::pending_count(&conn).unwrap_or(0), "Offline window ended; flushing stored measurements");
+                upload_interval.reset_immediately();
+                heartbeat_interval.reset_immediately();
+            }
+            stop = shutdown::next_stop(&mut shutdown_signals, stop_requests) => {
+                match stop {
+                    shutdown::StopReason::Signal(signal) => {
+                        info!(device_id = %config.device_id, signal, timeout_secs = config.shutdown_timeout_secs, "Shutting down; flushing pending measurements");
+                    }
+                    shutdown::StopReason::Suspend(mode) => {
+                        info!(device_id = %config.device_id, ?mode, timeout_secs = config.shutdown_timeout_secs, "Suspending for the fleet runner");
+                    }
+                }
+                if stop == shutdown::StopReason::Suspend(shutdown::StopMode::Immediate) {
+                    // Nothing is sent; the backlog waits for the next start
+                    sections.connection.set(json!(stop.connection()));
+                } else {
+                    // The final report carries the whole document, not just what changed
+                    let active_schema = measurement_schema.as_ref().filter(|_| features.schema_filter());
+                    let mut document = reported_state.document();
+                    let report = shutdown::FinalReport { state: &mut document, connection: stop.connection(), guard: &mut shadow_guard };
+                    shutdown::flush(&client, &config, &api_stats, &mut conn, active_schema, report)
+                        .instrument(info_span!("shutdown_flush", device_id = %config.device_id))
+                        .await;
+                    sections.connection.set(document["connection"].take());
+                    sections.last_shutdown.set(document["last_shutdown"].take());
+                }
+
+                config.reported_shadow_state = Some(reported_state.document());
+                if let Err(e) = config.save_to(&paths.config) {
+                    error!(device_id = %config.device_id, error = %e, "Failed to save config on shutdown");
+                }
+                if let Err(e) = ota_state.save_to(&paths.ota_state) {
+                    error!(device_id = %config.device_id, error = %e, "Failed to save OTA state on shutdown");
+                }
+                if let Err(e) = api_stats.checkpoint(&conn) {
+                    error!(device_id = %config.device_id, error = %e, "Failed to checkpoint API statistics on shutdown");
+                }
+                if let Err(e) = battery_model.checkpoint(&conn) {
+                    error!(device_id = %config.device_id, error = %e, "Failed to checkpoint battery on shutdown");
+                }
+                if let Err(e) = battery_drain.checkpoint(&conn) {
+                    error!(device_id = %config.device_id, error = %e, "Failed to checkpoint battery drain on shutdown");
+                }
+                if let Err(e) = geo_buckets.checkpoint(&conn) {
+                    error!(device_id = %config.device_id, error = %e, "Failed to checkpoint geohash dwell times on shutdown");
+                }
+                // Close explicitly so a failed final write is logged rather than lost on drop
+                if let Err((_, e)) = conn.close() {
+                    error!(device_id = %config.device_id, error = %e, "Failed to close the measurement database on shutdown");
+                }
+                if let Some(stream) = debug_stream.take() {
+                    stream.close(Duration::from_secs(1)).await;
+                }
+                ota_reporter.flush(Duration::from_secs(1)).await;
+                return Ok(DeviceExit::Shutdown);
+            }
+        }
+    }
+}
+
+/// What applying a desired document from the shadow check does to the running device:
+/// records and persists the change, re-arms timers and reconfigures the components.
+struct LiveEffects<'a> {
+    conn: &'a rusqlite::Connection,
+    audit_log: &'a mut AuditLog,
+    paths: &'a fleet::DevicePaths,
+    jitter: f32,
+    sample_timer: &'a mut jitter::Interval,
+    upload_timer: &'a mut jitter::Interval,
+    heartbeat_timer: &'a mut jitter::Interval,
+    simulator: &'a mut simulate::Simulator,
+    battery_drain: &'a mut battery::BatteryDrain,
+    self_detector: &'a mut Option<anomaly::SelfDetector>,
+    cadence_engine: &'a mut cadence::CadenceEngine,
+    geo_buckets: &'a mut geo::GeoBuckets,
+    shedder: &'a mut shed::Shedder,
+    debug_stream: &'a mut Option<DebugStream>,
+    client: &'a reqwest::Client,
+    api_stats: &'a stats::ApiStats,
+    clock: clock::Clock,
+}
+
+impl desired::Effects for LiveEffects<'_> {
+    fn storage(&mut self) -> Option<desired::Storage<'_>> {
+        Some(desired::Storage { conn: self.conn, audit_log: self.audit_log, paths: self.paths })
+    }
+
+    fn interval(&mut self, key: &str, secs: u64) {
+        let timer = match key {
+            "sample_interval_secs" => &mut *self.sample_timer,
+            "upload_interval_secs" => &mut *self.upload_timer,
+            _ => &mut *self.heartbeat_timer,
+        };
+        *timer = jitter::interval(secs, self.jitter);
+        info!(key = key, new_interval = secs, "Control plane updated interval");
+    }
+
+    fn chaos_flags(&mut self, config: &Config) {
+        let flag = |name: &str| config.chaos_flags.as_ref().and_then(|chaos| chaos.get(name));
+        if self.simulator.set_clock_skew(flag("clock_skew_secs")) {
+            warn!(device_id = %config.device_id, chaos_type = "clock_skew_secs", skew_secs = self.simulator.clock_skew().num_seconds(), "Injecting clock skew into measurement timestamps");
+        }
+        if self.simulator.set_clock_drift(flag("clock_drift_secs_per_hour"), self.clock.now()) {
+            warn!(device_id = %config.device_id, chaos_type = "clock_drift_secs_per_hour", report = %self.simulator.clock_report(self.clock.now()), "Injecting clock drift into measurement timestamps");
+        }
+        if self.battery_drain.set_flag(flag("battery_drain")) {
+            warn!(device_id = %config.device_id, chaos_type = "battery_drain", level = ?self.battery_drain.level(), "Battery drain changed");
+            if let Err(e) = self.battery_drain.checkpoint(self.conn) {
+                error!(device_id = %config.device_id, error = %e, "Failed to checkpoint battery drain");
+            }
+        }
+    }
+
+    fn restart(&mut self, component: desired::Component, config: &Config) {
+        match component {
+            desired::Component::DebugStream => {
+                if self.debug_stream.is_none() && debug_session::covers(config.debug_session.as_ref(), DebugScope::Logs, self.clock.now()) {
+                    *self.debug_stream = Some(DebugStream::spawn(self.client.clone(), config.clone(), self.api_stats.clone()));
+                }
+            }
+            desired::Component::SelfDetector => {
+                if let Some(detection) = config.self_detection.clone() {
+                    match self.self_detector.as_mut() {
+                        Some(detector) => detector.reconfigure(detection),
+                        None => *self.self_detector = Some(anomaly::SelfDetector::new(detection)),
+                    }
+                }
+            }
+            desired::Component::CadenceEngine => self.cadence_engine.reconfigure(config.cadence.clone()),
+            desired::Component::GeoBuckets => self.geo_buckets.reconfigure(config.geo_buckets.clone()),
+            desired::Component::Shedder => {
+                self.shedder.set_min_aggregation(network::active_profile(config).map_or(1, |profile| profile.min_aggregation));
+            }
+        }
+    }
+}
+
+/// Applies an interval change requested by a control-plane source: re-arms the timer
+/// and records the old and new values in the audit log. Returns true if it changed.
+fn apply_interval_change(
+    audit_log: &mut AuditLog,
+    jitter: f32,
+    source: AuditSource,
+    key: &str,
+    current_secs: &mut u64,
+    timer: &mut jitter::Interval,
+    new_secs: u64,
+) -> bool {
+    if new_secs == *current_secs {
+        return false;
+    }
+    audit_log.record(source, key, json!(*current_secs), json!(new_secs));
+    *current_secs = new_secs;
+    *timer = jitter::interval(new_secs, jitter);
+    info!(source = ?source, key = key, new_interval = new_secs, "Control plane updated interval");
+    true
+}
+
+/// Applies an interval change from the control plane. While a debug session holds the
+/// setting, the change is kept for when the session ends instead. Returns true if it changed.
+#[allow(clippy::too_many_arguments)]
+fn apply_control_interval(
+    audit_log: &mut AuditLog,
+    jitter: f32,
+    debug_session: &mut Option<DebugSession>,
+    source: AuditSource,
+    key: &str,
+    current_secs: &mut u64,
+    timer: &mut jitter::Interval,
+    new_secs: u64,
+    now: DateTime<Utc>,
+) -> bool {
+    if debug_session::defer(debug_session, key, new_secs, now) {
+        debug!(source = ?source, key = key, new_interval = new_secs, "Interval held by debug session; applies when it ends");
+        return false;
+    }
+    apply_interval_change(audit_log, jitter, source, key, current_secs, timer, new_secs)
+}
+
+// The interval settings a debug session may elevate, as currently in force
+fn interval_settings(sample_interval_secs: u64, heartbeat_interval_secs: u64) -> BTreeMap<String, u64> {
+    BTreeMap::from([
+        ("sample_interval_secs".to_string(), sample_interval_secs),
+        ("heartbeat_interval_secs".to_string(), heartbeat_interval_secs),
+    ])
+}
+
+/// Puts interval settings elevated or restored by a debug session in force.
+fn apply_debug_settings(
+    audit_log: &mut AuditLog,
+    jitter: f32,
+    settings: &BTreeMap<String, u64>,
+    sample: (&mut u64, &mut jitter::Interval),
+    heartbeat: (&mut u64, &mut jitter::Interval),
+) {
+    let (sample_secs, sample_timer) = sample;
+    let (heartbeat_secs, heartbeat_timer) = heartbeat;
+    for (key, secs) in settings {
+        match key.as_str() {
+            "sample_interval_secs" => apply_interval_change(audit_log, jitter, AuditSource::DebugSession, key, sample_secs, sample_timer, *secs),
+            "heartbeat_interval_secs" => apply_interval_change(audit_log, jitter, AuditSource::DebugSession, key, heartbeat_secs, heartbeat_timer, *secs),
+            _ => false,
+        };
+    }
+}
+
+/// Persists the models that otherwise only checkpoint periodically. Failures are logged;
+/// the next checkpoint tries again.
+#[allow(clippy::too_many_arguments)]
+fn checkpoint_models(
+    conn: &rusqlite::Connection,
+    config: &Config,
+    api_stats: &stats::ApiStats,
+    degradation: Option<&degradation::Degradation>,
+    battery_model: &battery::Battery,
+    battery_drain: &battery::BatteryDrain,
+    geo_buckets: &geo::GeoBuckets,
+    cost_model: Option<&mut cost::CostModel>,
+    now: DateTime<Utc>,
+) {
+    if let Err(e) = api_stats.checkpoint(conn) {
+        error!(device_id = %config.device_id, error = %e, "Failed to checkpoint API statistics");
+    }
+    if let Some(Err(e)) = degradation.map(|model| model.checkpoint(conn)) {
+        error!(device_id = %config.device_id, error = %e, "Failed to checkpoint sensor degradation");
+    }
+    if let Err(e) = battery_model.checkpoint(conn) {
+        error!(device_id = %config.device_id, error = %e, "Failed to checkpoint battery");
+    }
+    if let Err(e) = battery_drain.checkpoint(conn) {
+        error!(device_id = %config.device_id, error = %e, "Failed to checkpoint battery drain");
+    }
+    if let Err(e) = geo_buckets.checkpoint(conn) {
+        error!(device_id = %config.device_id, error = %e, "Failed to checkpoint geohash dwell times");
+    }
+    if let Some(model) = cost_model {
+        match storage::pending_count(conn) {
+            Ok(pending) => model.accrue(now, &api_stats.cumulative(), pending),
+            Err(e) => error!(device_id = %config.device_id, error = %e, "Failed to count stored measurements for cost model"),
+        }
+        if let Err(e) = model.checkpoint(conn) {
+            error!(device_id = %config.device_id, error = %e, "Failed to checkpoint cost model");
+        }
+    }
+}
+
+/// Sends a device event off the sample path, so a slow backend does not hold sampling
+/// up. One that fails to send comes back on `failed` to be queued in the outbox, like
+/// one raised during an offline window; the heartbeat tick flushes them.
+fn report_event(
+    client: &reqwest::Client,
+    config: &Config,
+    api_stats: &stats::ApiStats,
+    conn: &rusqlite::Connection,
+    failed: &mpsc::UnboundedSender<types::DeviceEvent>,
+    event: types::DeviceEvent,
+    now: DateTime<Utc>,
+) {
+    if offline::is_active(config.offline_window.as_ref(), now) {
+        debug!(device_id = %config.device_id, chaos_type = "offline", ?event, "Offline window, queueing device event");
+        if let Err(e) = outbox::queue_event(conn, &event, now) {
+            error!(device_id = %config.device_id, error = %e, ?event, "Failed to queue device event for later");
+        }
+        return;
+    }
+    let (client, config, api_stats, failed) = (client.clone(), config.clone(), api_stats.clone(), failed.clone());
+    tokio::spawn(async move {
+        if let Err(e) = net::send_event(&client, &config, &api_stats, &event).await {
+            warn!(device_id = %config.device_id, error = %format!("{:#}", e), ?event, "Failed to report device event; queueing it for later");
+            let _ = failed.send(event);
+        }
+    }.in_current_span());
+}
+
+/// Reads what was queued while the backend was unreachable, for sending on the next
+/// heartbeat tick.
+fn read_outbox(config: &Config, conn: &rusqlite::Connection) -> Option<outbox::Pending> {
+    match outbox::pending(conn) {
+        Ok(pending) => Some(pending),
+        Err(e) => {
+            error!(device_id = %config.device_id, error = %e, "Failed to read queued messages");
+            None
+        }
+    }
+}
+
+/// Drops what a flush sent from the outbox. What failed to send stays queued for the
+/// next time.
+fn settle_outbox(config: &Config, conn: &rusqlite::Connection, shadow_guard: &mut shadow_report::ShadowReportGuard, flushed: outbox::Flushed) {
+    match flushed.settle(conn) {
+        Ok(0) => {}
+        Ok(sent) => info!(device_id = %config.device_id, sent, "Flushed messages queued while offline"),
+        Err(e) => warn!(device_id = %config.device_id, error = %format!("{:#}", e), "Failed to flush queued messages; keeping them for later"),
+    }
+    if let Err(e) = shadow_guard.checkpoint(conn) {
+        error!(device_id = %config.device_id, error = %e, "Failed to checkpoint shadow report rejections");
+    }
+}
+
+/// Moves the device onto another simulated network and adopts that network's
+/// forced aggregation. Returns true if it changed.
+fn apply_network_change(
+    audit_log: &mut AuditLog,
+    source: AuditSource,
+    config: &mut Config,
+    shedder: &mut shed::Shedder,
+    next: network::NetworkType,
+) -> bool {
+    if config.network == Some(next) {
+        return false;
+    }
+    audit_log.record(source, "network", json!(config.network), json!(next));
+    info!(device_id = %config.device_id, source = ?source, previous = ?config.network, network = %next, "Switched simulated network");
+    config.network = Some(next);
+    shedder.set_min_aggregation(network::active_profile(config).map_or(1, |profile| profile.min_aggregation));
+    true
+}
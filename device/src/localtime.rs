@@ -0,0 +1,28 @@
+use chrono::{DateTime, Duration, FixedOffset, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use crate::types::Measurement;
+
+pub fn parse_timezone(name: &str) -> anyhow::Result<Tz> {
+    name.parse().map_err(|_| anyhow::anyhow!("Unknown IANA timezone {:?}", name))
+}
+
+/// UTC offset in effect at `utc`. With `broken_dst` the device reproduces the classic
+/// stale-offset bug: the offset is taken from an hour earlier, so for the hour after a
+/// transition local time runs on the old offset. Spring-forward then emits the
+/// non-existent hour and skips the next one; fall-back repeats an hour.
+pub fn offset_at(tz: Tz, utc: DateTime<Utc>, broken_dst: bool) -> FixedOffset {
+    let lookup_at = if broken_dst { utc - Duration::hours(1) } else { utc };
+    tz.offset_from_utc_datetime(&lookup_at.naive_utc()).fix()
+}
+
+pub fn local_time(tz: Tz, utc: DateTime<Utc>, broken_dst: bool) -> DateTime<FixedOffset> {
+    utc.with_timezone(&offset_at(tz, utc, broken_dst))
+}
+
+// The measurement timestamp stays UTC; the local fields are added alongside
+pub fn stamp(measurement: &mut Measurement, tz: Tz, broken_dst: bool) {
+    let local = local_time(tz, measurement.timestamp, broken_dst);
+    measurement.local_timestamp = Some(local.to_rfc3339());
+    measurement.utc_offset_minutes = Some(local.offset().local_minus_utc() / 60);
+}
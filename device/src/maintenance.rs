@@ -0,0 +1,69 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// Maintenance mode as requested through the desired shadow's `maintenance` key,
+/// e.g. `{"enabled": true, "duration_secs": 900}` or `{"enabled": true, "expires_at": "..."}`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MaintenanceState {
+    pub enabled_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    // The desired document that produced this state, so a stale request left in the
+    // shadow after expiry does not re-arm the mode on every shadow check.
+    pub request: Value,
+}
+
+impl MaintenanceState {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+
+    pub fn to_reported(&self, now: DateTime<Utc>) -> Value {
+        json!({
+            "active": self.is_active(now),
+            "enabled_at": self.enabled_at,
+            "expires_at": self.expires_at,
+        })
+    }
+}
+
+pub fn is_active(state: Option<&MaintenanceState>, now: DateTime<Utc>) -> bool {
+    state.is_some_and(|state| state.is_active(now))
+}
+
+/// Reconciles the persisted maintenance state with the desired shadow request.
+pub fn reconcile(current: Option<MaintenanceState>, desired: Option<&Value>, now: DateTime<Utc>) -> Option<MaintenanceState> {
+    let request = desired?;
+    let enabled = match request {
+        Value::Bool(enabled) => *enabled,
+        Value::Object(fields) => fields.get("enabled").and_then(Value::as_bool).unwrap_or(false),
+        _ => false,
+    };
+    if !enabled {
+        return None;
+    }
+
+    if let Some(current) = current {
+        if &current.request == request {
+            return Some(current);
+        }
+    }
+
+    let expires_at = request
+        .get("expires_at")
+        .and_then(Value::as_str)
+        .and_then(|raw| DateTime::parse_from_rfc3339(raw).ok())
+        .map(|expires_at| expires_at.with_timezone(&Utc))
+        .or_else(|| {
+            request
+                .get("duration_secs")
+                .and_then(Value::as_i64)
+                .map(|secs| now + Duration::seconds(secs))
+        });
+
+    Some(MaintenanceState {
+        enabled_at: now,
+        expires_at,
+        request: request.clone(),
+    })
+}
@@ -0,0 +1,67 @@
+use prometheus::{Encoder, Gauge, IntCounter, IntGauge, Registry, TextEncoder};
+use std::collections::HashMap;
+use std::fmt;
+
+/// Prometheus metrics of one device, served with its health endpoints at /metrics. Each
+/// device has a registry of its own, labelled with its id, so fleet members sharing a
+/// process never share a counter.
+#[derive(Clone)]
+pub struct DeviceMetrics {
+    registry: Registry,
+    pub measurements_generated: IntCounter,
+    pub measurements_uploaded: IntCounter, // Accepted by the backend
+    pub upload_failures: IntCounter, // Failed batches, and upload ticks lost to injected errors
+    pub measurements_dropped: IntCounter, // Evicted by the storage limits before upload
+    pub heartbeats_sent: IntCounter,
+    pub ota_checks: IntCounter,
+    pub buffered_measurements: IntGauge, // Stored and not yet confirmed by the backend
+    pub battery_level: Gauge, // Charge from 0 to 1, as last sampled
+}
+
+impl DeviceMetrics {
+    pub fn new(device_id: &str) -> Self {
+        let labels = HashMap::from([("device_id".to_string(), device_id.to_string())]);
+        let registry = Registry::new_custom(Some("device".to_string()), Some(labels)).expect("metric namespace is valid");
+        let counter = |name: &str, help: &str| {
+            let counter = IntCounter::new(name, help).expect("metric name is valid");
+            registry.register(Box::new(counter.clone())).expect("metric is registered once");
+            counter
+        };
+        let measurements_generated = counter("measurements_generated_total", "Measurements sampled");
+        let measurements_uploaded = counter("measurements_uploaded_total", "Measurements the backend accepted");
+        let upload_failures = counter("upload_failures_total", "Failed measurement uploads");
+        let measurements_dropped = counter("measurements_dropped_total", "Measurements evicted by the storage limits");
+        let heartbeats_sent = counter("heartbeats_sent_total", "Heartbeats the backend answered");
+        let ota_checks = counter("ota_checks_total", "Firmware update checks");
+        let buffered_measurements = IntGauge::new("buffered_measurements", "Measurements stored and not yet uploaded").expect("metric name is valid");
+        registry.register(Box::new(buffered_measurements.clone())).expect("metric is registered once");
+        let battery_level = Gauge::new("battery_level", "Battery charge, from 0 to 1").expect("metric name is valid");
+        registry.register(Box::new(battery_level.clone())).expect("metric is registered once");
+        DeviceMetrics {
+            registry,
+            measurements_generated,
+            measurements_uploaded,
+            upload_failures,
+            measurements_dropped,
+            heartbeats_sent,
+            ota_checks,
+            buffered_measurements,
+            battery_level,
+        }
+    }
+
+    /// Every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            return format!("# failed to encode metrics: {}\n", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for DeviceMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DeviceMetrics").finish_non_exhaustive()
+    }
+}
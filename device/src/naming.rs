@@ -0,0 +1,53 @@
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::config::Config;
+
+const ADJECTIVES: &[&str] = &[
+    "amber", "brisk", "calm", "dapper", "eager", "fuzzy", "gentle", "hardy",
+    "icy", "jolly", "keen", "lively", "mellow", "nimble", "olive", "plucky",
+    "quiet", "rusty", "sunny", "tidy", "upbeat", "vivid", "witty", "young",
+    "zesty", "bold", "crisp", "dusty", "frosty", "golden", "hazy", "misty",
+];
+
+const ANIMALS: &[&str] = &[
+    "badger", "bison", "crane", "dingo", "eagle", "ferret", "gecko", "heron",
+    "ibis", "jackal", "koala", "lemur", "marmot", "newt", "otter", "panda",
+    "quail", "raven", "salmon", "tapir", "urchin", "vole", "walrus", "yak",
+    "zebra", "beaver", "cobra", "donkey", "finch", "gopher", "hare", "moose",
+];
+
+const MAX_NAME_LEN: usize = 64;
+
+// FNV-1a; unlike std's hasher its output is stable across builds
+fn stable_hash(input: &str) -> u64 {
+    input.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Deterministic adjective-animal name, suffixed with the start of the id to keep
+/// collisions readable apart. The same device_id always yields the same name.
+pub fn generated_name(device_id: &str) -> String {
+    let hash = stable_hash(device_id);
+    let adjective = ADJECTIVES[(hash % ADJECTIVES.len() as u64) as usize];
+    let animal = ANIMALS[((hash >> 32) % ANIMALS.len() as u64) as usize];
+    let suffix: String = device_id.chars().filter(char::is_ascii_alphanumeric).take(4).collect();
+    format!("{}-{}-{}", adjective, animal, suffix)
+}
+
+/// Human-friendly label for dashboards. Display only: never used for authentication
+/// or as a storage key, which stay on device_id.
+pub fn display_name(config: &Config) -> String {
+    config.device_name.clone().unwrap_or_else(|| generated_name(&config.device_id))
+}
+
+/// Applies a desired `device_name` from the shadow. Returns the previous display name
+/// if the name changed; the caller persists the config.
+pub fn rename(config: &mut Config, desired: &Value) -> Result<Option<String>> {
+    let name = desired.as_str().map(str::trim).ok_or_else(|| anyhow::anyhow!("device_name must be a string, got {}", desired))?;
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        anyhow::bail!("device_name must be 1 to {} characters, got {:?}", MAX_NAME_LEN, name);
+    }
+    let previous = display_name(config);
+    config.device_name = Some(name.to_string());
+    Ok((previous != name).then_some(previous))
+}
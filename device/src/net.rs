@@ -1,139 +1,832 @@
-use anyhow::Result;
-use reqwest::Client;
-use tracing::{info, debug, error};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use rand::Rng;
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_RANGE, CONTENT_TYPE, RANGE};
+use reqwest::{Certificate, Client, Identity, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tracing::{info, debug, error, warn};
 
+use crate::auth;
+use crate::cadence;
+use crate::codec::{self, Codec, EndpointClass};
 use crate::config::Config;
-use crate::types::{FirmwareMetadata, Heartbeat, IngestPayload, DesiredState, RegisterPayload, RegisterResponse, DeviceShadow, ReportedShadowState}; 
+use crate::debug_session::{self, DebugEvent, DebugScope};
+use crate::maintenance;
+use crate::naming;
+use crate::network;
+use crate::residency::{DataTarget, ResidencyError};
+use crate::response_capture;
+use crate::schema;
+use crate::shadow_report::ShadowRejected;
+use crate::stats::ApiStats;
+use crate::telemetry;
+use crate::types::{DeviceErrorPayload, DeviceEvent, FirmwareMetadata, Heartbeat, IngestPayload, IngestFeedback, DesiredState, RegisterPayload, RegisterResponse, BulkRegisterPayload, BulkRegisterResult, DeviceShadow, ReportedShadowState, MeasurementSchema, ShadowRejections, SyncResponse, UploadedFile};
 use uuid::Uuid; 
 
-pub async fn register_device(client: &Client, backend_url: &str, boot_id: Uuid) -> Result<RegisterResponse> {
+// Sends a request and records it in the per-endpoint API statistics.
+// Non-2xx responses are returned to the caller but counted as failures by status code.
+// On a simulated network the request is delayed, and possibly lost, per its profile.
+async fn send_recorded(config: &Config, stats: &ApiStats, endpoint: &str, request: RequestBuilder) -> Result<Response> {
+    let (client, request) = request.build_split();
+    let mut request = request.map_err(transport(endpoint))?;
+    telemetry::inject_trace_context(request.headers_mut());
+    let bytes_sent = request.body().and_then(|body| body.as_bytes()).map_or(0, |body| body.len() as u64);
+    stats.record_attempt(endpoint, bytes_sent);
+    let audited = debug_session::covers(config.debug_session.as_ref(), DebugScope::Audit, Utc::now());
+    if audited {
+        audit_request(config, endpoint, &request);
+    }
+
+    let link = network::active_profile(config);
+    if let Some(profile) = &link {
+        let transit = network::transit(profile, bytes_sent);
+        tokio::time::sleep(transit.delay).await;
+        if transit.lost {
+            stats.record_failure(endpoint, "packet_loss");
+            let network = config.network.map_or("unknown", |network| network.as_str());
+            return Err(NetError::Lost { endpoint: endpoint.to_string(), network }.into());
+        }
+    }
+
+    match client.execute(request).await {
+        Ok(response) => {
+            if let Some(profile) = &link {
+                tokio::time::sleep(network::transfer_delay(profile, response.content_length().unwrap_or(0))).await;
+            }
+            if audited {
+                debug!(device_id = %config.device_id, endpoint, status = %response.status(), headers = ?response.headers(), bytes = ?response.content_length(), "Debug audit: response");
+            }
+            if response.status().is_success() {
+                stats.record_success(endpoint, response.content_length().unwrap_or(0));
+            } else {
+                stats.record_failure(endpoint, response.status().as_str());
+            }
+            Ok(response)
+        }
+        Err(e) => {
+            if audited {
+                debug!(device_id = %config.device_id, endpoint, error = %e, "Debug audit: no response");
+            }
+            stats.record_failure(endpoint, transport_error_code(&e));
+            Err(transport(endpoint)(e).into())
+        }
+    }
+}
+
+// Logs a request for a debug session with the audit scope. Credentials are left out and
+// only uncompressed bodies are logged as text.
+fn audit_request(config: &Config, endpoint: &str, request: &reqwest::Request) {
+    let body = request.body().and_then(|body| body.as_bytes()).unwrap_or_default();
+    let text = match request.headers().get(CONTENT_ENCODING) {
+        None => String::from_utf8_lossy(body).into_owned(),
+        Some(encoding) => format!("<{} bytes, {:?}>", body.len(), encoding),
+    };
+    debug!(device_id = %config.device_id, endpoint, method = %request.method(), url = %request.url(), body = %text, "Debug audit: request");
+}
+
+// Holds a request back by the `latency_ms` chaos flag, to simulate a slow link
+async fn inject_chaos_latency(config: &Config, endpoint: &str) {
+    if let Some(delay) = network::chaos_latency(config) {
+        warn!(device_id = %config.device_id, chaos_type = "latency_ms", endpoint, delay_ms = delay.as_millis() as u64, "Injecting network latency");
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// Upper bound on a single backoff delay, however many attempts are configured
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Bound on a firmware download, which streams far longer than the per-request timeout
+// allows; an interrupted download resumes at the next OTA check
+const FIRMWARE_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// Bytes streamed between records of a firmware download's progress, so a process
+/// killed mid-download loses at most this much of it.
+pub const RESUME_CHECKPOINT_BYTES: u64 = 1024 * 1024;
+
+/// The HTTP client a device talks to the backend with, tuned per the config. Every
+/// request is bounded, so slow DNS or a hung socket fails that request rather than
+/// stalling the main loop, and the next tick tries again. With a client certificate
+/// configured, every request presents it for mutual TLS.
+pub fn build_client(config: &Config) -> Result<Client> {
+    let seconds = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+    let mut builder = Client::builder()
+        .pool_idle_timeout(seconds(config.http_pool_idle_timeout_secs))
+        .tcp_keepalive(seconds(config.http_tcp_keepalive_secs));
+    if config.http_connect_timeout_ms > 0 {
+        builder = builder.connect_timeout(Duration::from_millis(config.http_connect_timeout_ms));
+    }
+    if let Some(timeout) = seconds(config.http_request_timeout_secs) {
+        builder = builder.timeout(timeout);
+    }
+    // Client identities are only loaded by the rustls backend
+    if config.tls_ca_bundle_path.is_some() || config.tls_client_cert_path.is_some() {
+        builder = builder.use_rustls_tls();
+    }
+    if let Some(path) = &config.tls_ca_bundle_path {
+        let pem = std::fs::read(path).with_context(|| format!("Failed to read CA bundle {}", path.display()))?;
+        for certificate in Certificate::from_pem_bundle(&pem).with_context(|| format!("Invalid CA bundle {}", path.display()))? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    match (&config.tls_client_cert_path, &config.tls_client_key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            let mut pem = std::fs::read(cert_path).with_context(|| format!("Failed to read client certificate {}", cert_path.display()))?;
+            pem.push(b'\n');
+            pem.extend(std::fs::read(key_path).with_context(|| format!("Failed to read client key {}", key_path.display()))?);
+            builder = builder.identity(Identity::from_pem(&pem).context("Invalid client certificate or key")?);
+        }
+        (None, None) => {}
+        _ => anyhow::bail!("tls_client_cert_path and tls_client_key_path must be set together"),
+    }
+    builder.build().context("Failed to build the HTTP client")
+}
+
+/// Delay before retry number `retry` (0 for the first retry): exponential in the retry
+/// count, capped at MAX_RETRY_DELAY, with the upper half scaled by `jitter` in [0, 1].
+pub fn backoff_delay(base_delay: Duration, retry: u32, jitter: f64) -> Duration {
+    let exponential = base_delay.saturating_mul(2u32.saturating_pow(retry)).min(MAX_RETRY_DELAY);
+    exponential / 2 + exponential.mul_f64(jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+/// Runs `f` up to `max_attempts` times, backing off between attempts. Connection
+/// errors and 5xx responses are retried; any other response, including 4xx, is
+/// returned straight away. After the last attempt its outcome is returned as is.
+pub async fn with_retry<F, Fut>(max_attempts: u32, base_delay: Duration, mut f: F) -> Result<Response>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<Response>>,
+{
+    let max_attempts = max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        let outcome = f().await;
+        let retryable = match &outcome {
+            Ok(response) => response.status().is_server_error(),
+            Err(_) => true,
+        };
+        if !retryable || attempt >= max_attempts {
+            return outcome;
+        }
+        let delay = backoff_delay(base_delay, attempt - 1, rand::thread_rng().gen::<f64>());
+        match &outcome {
+            Ok(response) => warn!(attempt, status = %response.status(), delay_ms = delay.as_millis() as u64, "Request failed, retrying"),
+            Err(e) => warn!(attempt, error = %e, delay_ms = delay.as_millis() as u64, "Request failed, retrying"),
+        }
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+// Sends a request built fresh for each attempt, retried per the config
+async fn send_with_retry(config: &Config, stats: &ApiStats, endpoint: &str, request: impl Fn() -> RequestBuilder) -> Result<Response> {
+    let base_delay = Duration::from_millis(config.retry_base_delay_ms);
+    with_retry(config.retry_max_attempts, base_delay, || send_recorded(config, stats, endpoint, request())).await
+}
+
+/// How often a request may be sent before its outcome is returned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Attempts {
+    Retried, // Per the retry settings in the config
+    Once,
+}
+
+// Sends a request carrying the device's auth token. When the backend rejects the token,
+// it is refreshed (see auth::refresh) and the request is sent once more with the new one.
+async fn send_authenticated(client: &Client, config: &Config, stats: &ApiStats, endpoint: &str, attempts: Attempts, request: impl Fn(&str) -> RequestBuilder) -> Result<Response> {
+    let request = &request;
+    let send = |auth_token: String| async move {
+        debug!(device_id = %config.device_id, endpoint, auth_token = %auth_token, "Sending request with auth token"); // Debug log
+        match attempts {
+            Attempts::Retried => send_with_retry(config, stats, endpoint, || request(&auth_token)).await,
+            Attempts::Once => send_recorded(config, stats, endpoint, request(&auth_token)).await,
+        }
+    };
+    let auth_token = auth::current_token(config)?;
+    let response = send(auth_token.clone()).await?;
+    if !auth::is_rejected(response.status()) {
+        return Ok(response);
+    }
+    warn!(device_id = %config.device_id, endpoint, status = %response.status(), "Backend rejected the auth token; refreshing it");
+    let auth_token = auth::refresh(client, config, &auth_token).await?;
+    send(auth_token).await
+}
+
+/// Why a request to the backend failed. Calls into the backend also fail on auth,
+/// encoding and shadow policy errors, which keep their own types.
+#[derive(Debug, thiserror::Error)]
+pub enum NetError {
+    /// A backend answer with a 4xx or 5xx status, with what was captured of its body.
+    #[error("{endpoint} answered HTTP {status}{}", body_suffix(.body))]
+    Status { endpoint: String, status: StatusCode, correlation_id: Option<String>, body: String },
+    /// No answer: the request could not be built, connected or completed.
+    #[error("request to {endpoint} failed")]
+    Transport { endpoint: String, #[source] source: reqwest::Error },
+    /// Dropped by the simulated network profile before it was sent.
+    #[error("request to {endpoint} lost on simulated {network} network")]
+    Lost { endpoint: String, network: &'static str },
+    /// The answer's body broke off or could not be read.
+    #[error("response body unreadable")]
+    Body(#[source] reqwest::Error),
+    /// The answer's body is not the JSON the endpoint returns.
+    #[error("response body malformed: {0}")]
+    Decode(#[from] serde_json::Error),
+    /// The data has no endpoint it may be sent to.
+    #[error(transparent)]
+    Residency(#[from] ResidencyError),
+}
+
+impl NetError {
+    /// The status the backend answered with, if it answered.
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            NetError::Status { status, .. } => Some(*status),
+            NetError::Transport { source, .. } => source.status(),
+            _ => None,
+        }
+    }
+}
+
+fn transport(endpoint: &str) -> impl FnOnce(reqwest::Error) -> NetError + '_ {
+    move |source| NetError::Transport { endpoint: endpoint.to_string(), source }
+}
+
+fn body_suffix(body: &str) -> String {
+    if body.is_empty() { String::new() } else { format!(": {}", body) }
+}
+
+// Passes anything but a 4xx or 5xx response through. Those are turned into
+// NetError::Status with their body captured per the config (see response_capture),
+// logged with the backend's correlation id and kept for the crash snapshot.
+async fn check_status(config: &Config, stats: &ApiStats, endpoint: &str, response: Response) -> Result<Response> {
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return Ok(response);
+    }
+    let captured = response_capture::capture(&config.error_body_capture, endpoint, response).await;
+    warn!(device_id = %config.device_id, endpoint, status = %status, correlation_id = captured.correlation_id.as_deref().unwrap_or("none"), body = %captured.body, "Backend refused the request");
+    let error = NetError::Status { endpoint: endpoint.to_string(), status, correlation_id: captured.correlation_id.clone(), body: captured.body.clone() };
+    stats.record_failed_response(captured);
+    Err(error.into())
+}
+
+fn transport_error_code(error: &reqwest::Error) -> &'static str {
+    if error.is_timeout() {
+        "timeout"
+    } else if error.is_connect() {
+        "connect"
+    } else if error.is_request() {
+        "request"
+    } else {
+        "other"
+    }
+}
+
+pub async fn register_device(
+    client: &Client,
+    backend_url: &str,
+    boot_id: Uuid,
+    device_name: Option<String>,
+    features: Value,
+    provisioning_token: Option<String>,
+    accept_encoding: &str,
+) -> Result<RegisterResponse> {
     let url = format!("{}/api/devices/register", backend_url);
-    let body = RegisterPayload { boot_id };
+    let body = RegisterPayload { boot_id, device_name, features, provisioning_token };
     
     info!(boot_id = %boot_id, "Attempting to register device");
-    let response = client.post(&url).json(&body).send().await?.error_for_status()?;
-    let register_response = response.json::<RegisterResponse>().await?;
+    // The backend answers with the request encodings it accepts
+    let response = client.post(&url).header(ACCEPT_ENCODING, accept_encoding).json(&body).send().await
+        .and_then(Response::error_for_status)
+        .map_err(transport("register"))?;
+    let register_response = read_json::<RegisterResponse>(response).await?;
     info!(device_id = %register_response.device_id, "Device registered successfully");
     Ok(register_response)
 }
 
-pub async fn send_heartbeat(
-    client: &Client, 
-    config: &Config, 
+/// Registers many devices in one request, as a factory provisions a batch. Returns None
+/// if the backend has no bulk endpoint, so the devices must register one by one.
+pub async fn register_devices_bulk(client: &Client, backend_url: &str, devices: Vec<RegisterPayload>, accept_encoding: &str) -> Result<Option<Vec<BulkRegisterResult>>> {
+    let url = format!("{}/api/devices/register/bulk", backend_url);
+    let count = devices.len();
+    info!(devices = count, "Attempting to register devices in bulk");
+    let response = client.post(&url).header(ACCEPT_ENCODING, accept_encoding).json(&BulkRegisterPayload { devices }).send().await.map_err(transport("register_bulk"))?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let results = read_json::<Vec<BulkRegisterResult>>(response.error_for_status().map_err(transport("register_bulk"))?).await?;
+    info!(devices = count, registered = results.iter().filter(|result| result.registration.is_some()).count(), "Bulk registration answered");
+    Ok(Some(results))
+}
+
+// Builds the heartbeat body from the device's current state; callers attach optional telemetry
+pub fn heartbeat_body(
+    config: &Config,
     firmware_version: &str,
     sample_interval: u64,
     upload_interval: u64,
     heartbeat_interval: u64,
-) -> Result<DesiredState> {
-    let url = format!("{}/api/devices/heartbeat", config.backend_url);
-    let body = Heartbeat {
+) -> Heartbeat {
+    Heartbeat {
         device_id: config.device_id.clone(),
+        device_name: naming::display_name(config),
         firmware_version: firmware_version.to_string(),
         reported_sample_interval_secs: sample_interval,
         reported_upload_interval_secs: upload_interval,
         reported_heartbeat_interval_secs: heartbeat_interval,
         region: config.region.clone(),
         hardware_rev: config.hardware_rev.clone(),
-    };
+        maintenance: maintenance::is_active(config.maintenance.as_ref(), Utc::now()),
+        maintenance_expires_at: config.maintenance.as_ref().and_then(|m| m.expires_at),
+        anomaly_counts: None,
+        api_stats: None,
+        features: None,
+        shed_samples: None,
+        decimation_factor: None,
+        network: config.network,
+        rollback: None,
+        uptime_secs: None,
+        pending_measurements: None,
+        consecutive_upload_failures: None,
+        last_upload_error: None,
+        battery_level: None,
+        free_disk_bytes: None,
+        dropped_measurements: None,
+        device_time: None,
+        ota_update: None,
+        ota_phase: None,
+        chaos_crashes: (config.chaos_crashes > 0).then_some(config.chaos_crashes),
+    }
+}
 
-    let auth_token = config.auth_token.as_ref().ok_or_else(|| anyhow::anyhow!("Auth token not found"))?;
-    debug!(device_id = %config.device_id, auth_token = %auth_token, "Sending heartbeat with auth token"); // Debug log
+pub async fn send_heartbeat(client: &Client, config: &Config, stats: &ApiStats, body: &Heartbeat) -> Result<DesiredState> {
+    let url = format!("{}/api/devices/heartbeat", config.backend_url);
 
     debug!(device_id = %config.device_id, "Sending heartbeat");
-    let desired_state = client.post(&url)
-        .header("X-Auth-Token", auth_token) // Changed header name
-        .json(&body)
-        .send().await?.error_for_status()?.json::<DesiredState>().await?;
+    inject_chaos_latency(config, "heartbeat").await;
+    let (encoding, encoded) = encode_json(config, EndpointClass::Heartbeat, body)?;
+    let request = |auth_token: &str| with_body(client.post(&url)
+        .header("X-Auth-Token", auth_token), encoding, &encoded); // Changed header name
+    let response = send_authenticated(client, config, stats, "heartbeat", Attempts::Retried, request).await?;
+    let desired_state = read_json::<DesiredState>(check_status(config, stats, "heartbeat", response).await?).await?;
     info!(device_id = %config.device_id, "Heartbeat sent successfully, desired state received.");
     Ok(desired_state)
 }
 
-pub async fn send_ingest(client: &Client, config: &Config, measurements: &[crate::types::Measurement]) -> Result<()> {
-    if measurements.is_empty() {
-        debug!(device_id = %config.device_id, "No measurements to ingest");
-        return Ok(());
+/// Sends the heartbeat and receives the desired state and the full device shadow in the
+/// same round trip; used instead of separate heartbeats and shadow fetches when
+/// `combined_sync` is on. Returns None if the backend has no sync endpoint, so the
+/// heartbeat must go out on its own.
+pub async fn sync(client: &Client, config: &Config, stats: &ApiStats, body: &Heartbeat) -> Result<Option<SyncResponse>> {
+    let url = format!("{}/api/devices/sync", config.backend_url);
+
+    debug!(device_id = %config.device_id, "Sending heartbeat with shadow sync");
+    inject_chaos_latency(config, "sync").await;
+    let (encoding, encoded) = encode_json(config, EndpointClass::Heartbeat, body)?;
+    let request = |auth_token: &str| with_body(client.post(&url)
+        .header("X-Auth-Token", auth_token)
+        .header(ACCEPT_ENCODING, config.compression.accept_encoding()), encoding, &encoded);
+    let response = send_authenticated(client, config, stats, "sync", Attempts::Retried, request).await?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
     }
+    let synced = read_json::<SyncResponse>(check_status(config, stats, "sync", response).await?).await?;
+    info!(device_id = %config.device_id, "Heartbeat sent successfully, desired state and shadow received.");
+    Ok(Some(synced))
+}
+
+/// Serializes a request body and compresses it with the codec configured for `class`.
+/// Returns the encoding actually applied, which is none for small bodies.
+pub fn encode_json(config: &Config, class: EndpointClass, body: &impl Serialize) -> Result<(Codec, Vec<u8>)> {
+    let compression = &config.compression;
+    let codec = if class == EndpointClass::Ingest && !config.compress_uploads {
+        Codec::None
+    } else {
+        compression.setting(class).resolve(config.negotiated_encoding)
+    };
+    codec::encode_body(codec, compression.min_bytes, serde_json::to_vec(body)?)
+}
+
+// Attaches an encoded JSON body and its Content-Encoding
+fn with_body(request: RequestBuilder, encoding: Codec, body: &[u8]) -> RequestBuilder {
+    let request = request.header(CONTENT_TYPE, "application/json").body(body.to_vec());
+    match encoding {
+        Codec::None => request,
+        codec => request.header(CONTENT_ENCODING, codec.as_str()),
+    }
+}
+
+// Reads a response body, decoding it per its Content-Encoding
+async fn read_body(response: Response) -> Result<Vec<u8>> {
+    let encoding = response.headers().get(CONTENT_ENCODING)
+        .map(|value| value.to_str().map_err(anyhow::Error::from).and_then(str::parse::<Codec>))
+        .transpose()?
+        .unwrap_or(Codec::None);
+    let body = response.bytes().await.map_err(NetError::Body)?;
+    encoding.decode(&body)
+}
 
-    let url = format!("{}/api/devices/ingest", config.backend_url);
-    let body = IngestPayload {
+async fn read_json<T: DeserializeOwned>(response: Response) -> Result<T> {
+    Ok(serde_json::from_slice(&read_body(response).await?).map_err(NetError::Decode)?)
+}
+
+/// The ingest payload for `measurements`, with the fields cadence thinning omits and
+/// those the measurement schema does not accept left out.
+pub fn ingest_body(config: &Config, target: &DataTarget, measurements: &[crate::types::Measurement], measurement_schema: Option<&MeasurementSchema>) -> Result<Value> {
+    let mut body = serde_json::to_value(IngestPayload {
         device_id: config.device_id.clone(),
         measurements: measurements.to_vec(),
-    };
+        region: target.region.clone(),
+        data_endpoint: Some(target.endpoint.clone()),
+    })?;
+    cadence::strip_omitted(measurements, &mut body);
+    if let Some(measurement_schema) = measurement_schema {
+        schema::filter_payload(measurement_schema, &mut body);
+    }
+    Ok(body)
+}
 
-    let auth_token = config.auth_token.as_ref().ok_or_else(|| anyhow::anyhow!("Auth token not found"))?;
-    debug!(device_id = %config.device_id, auth_token = %auth_token, "Sending ingest with auth token"); // Debug log
+/// Sends `body` to the ingest endpoint as it is: once, uncompressed and whatever it
+/// holds. Returns the status the backend answered with; a refusal is not an error here.
+/// For chaos that sends bodies the backend should refuse, see corruption::corrupt.
+pub async fn send_raw_ingest(client: &Client, config: &Config, stats: &ApiStats, target: &DataTarget, body: &[u8]) -> Result<StatusCode> {
+    let url = format!("{}/api/devices/ingest", target.endpoint);
+    let request = |auth_token: &str| with_body(client.post(&url)
+        .header("X-Auth-Token", auth_token), Codec::None, body);
+    let response = send_authenticated(client, config, stats, "ingest", Attempts::Once, request).await?;
+    let status = response.status();
+    if status.is_client_error() || status.is_server_error() {
+        let captured = response_capture::capture(&config.error_body_capture, "ingest", response).await;
+        debug!(device_id = %config.device_id, status = %status, body = %captured.body, "Backend refused a raw ingest body");
+    }
+    Ok(status)
+}
 
-    client.post(&url)
-        .header("X-Auth-Token", auth_token) // Changed header name
-        .json(&body)
-        .send().await?.error_for_status()?;
+pub async fn send_ingest(
+    client: &Client,
+    config: &Config,
+    stats: &ApiStats,
+    target: &DataTarget,
+    measurements: &[crate::types::Measurement],
+    measurement_schema: Option<&MeasurementSchema>,
+) -> Result<Option<IngestFeedback>> {
+    if measurements.is_empty() {
+        debug!(device_id = %config.device_id, "No measurements to ingest");
+        return Ok(None);
+    }
+
+    let url = format!("{}/api/devices/ingest", target.endpoint);
+    let body = ingest_body(config, target, measurements, measurement_schema)?;
+
+    inject_chaos_latency(config, "ingest").await;
+    // Bodies that would grow go as-is, so they still fit the payload limit
+    let (encoding, encoded) = encode_json(config, EndpointClass::Ingest, &body)?;
+    let request = |auth_token: &str| with_body(client.post(&url)
+        .header("X-Auth-Token", auth_token), encoding, &encoded); // Changed header name
+    let response = send_authenticated(client, config, stats, "ingest", Attempts::Retried, request).await?;
+    let response = check_status(config, stats, "ingest", response).await?;
     info!(device_id = %config.device_id, count = measurements.len(), "Ingested measurements.");
-    Ok(())
+
+    // The backend may optionally attach sampling feedback; a 204 or unparseable body means none.
+    let text = response.text().await.map_err(NetError::Body)?;
+    if text.is_empty() {
+        return Ok(None);
+    }
+    match serde_json::from_str::<IngestFeedback>(&text) {
+        Ok(feedback) => {
+            debug!(device_id = %config.device_id, ?feedback, "Received ingest feedback");
+            Ok(Some(feedback))
+        }
+        Err(e) => {
+            debug!(device_id = %config.device_id, error = %e, "Ignoring unparseable ingest response body");
+            Ok(None)
+        }
+    }
 }
 
-pub async fn fetch_latest_firmware(client: &Client, config: &Config) -> Result<Option<FirmwareMetadata>> {
-    let url = format!("{}/api/firmware/latest?device_id={}", config.backend_url, config.device_id);
-    
-    let auth_token = config.auth_token.as_ref().ok_or_else(|| anyhow::anyhow!("Auth token not found"))?;
-    debug!(device_id = %config.device_id, auth_token = %auth_token, "Fetching latest firmware with auth token"); // Debug log
+pub async fn fetch_measurement_schema(client: &Client, config: &Config, stats: &ApiStats) -> Result<Option<MeasurementSchema>> {
+    let url = format!("{}/api/schema/measurements", config.backend_url);
+
+    debug!(device_id = %config.device_id, "Fetching measurement schema");
+    let request = |auth_token: &str| client.get(&url)
+        .header("X-Auth-Token", auth_token);
+    let response = send_authenticated(client, config, stats, "schema", Attempts::Once, request).await?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        info!(device_id = %config.device_id, "Backend publishes no measurement schema; sending all fields.");
+        return Ok(None);
+    }
+
+    let measurement_schema = check_status(config, stats, "schema", response).await?.json::<MeasurementSchema>().await?;
+    info!(device_id = %config.device_id, accepted_fields = ?measurement_schema.accepted_fields, "Fetched measurement schema");
+    Ok(Some(measurement_schema))
+}
+
+pub async fn fetch_latest_firmware(
+    client: &Client,
+    config: &Config,
+    stats: &ApiStats,
+    current_version: &str,
+    nonce: &str,
+) -> Result<Option<FirmwareMetadata>> {
+    let url = format!("{}/api/firmware/latest", config.backend_url);
 
     debug!(device_id = %config.device_id, "Fetching latest firmware");
-    let response = client.get(&url)
+    let request = |auth_token: &str| client.get(&url)
+        .query(&[("device_id", config.device_id.as_str()), ("current_version", current_version), ("nonce", nonce)])
         .header("X-Auth-Token", auth_token) // Changed header name
-        .send().await?;
+        .header(ACCEPT_ENCODING, config.compression.accept_encoding());
+    let response = send_authenticated(client, config, stats, "firmware_latest", Attempts::Once, request).await?;
     
     if response.status() == reqwest::StatusCode::NO_CONTENT {
         info!(device_id = %config.device_id, "No new firmware available.");
         return Ok(None);
     }
     
-    let text = response.text().await?;
-    if text.is_empty() {
+    let body = read_body(response).await?;
+    if body.is_empty() {
         error!(device_id = %config.device_id, "Firmware response body was empty but status was not 204.");
         return Ok(None);
     }
 
-    let firmware: FirmwareMetadata = serde_json::from_str(&text)?;
+    let firmware: FirmwareMetadata = serde_json::from_slice(&body).map_err(NetError::Decode)?;
     info!(device_id = %config.device_id, version = %firmware.version, "Fetched new firmware metadata");
     Ok(Some(firmware))
 }
 
-pub async fn download_firmware(client: &Client, config: &Config, firmware_url: &str) -> Result<Vec<u8>> {
-    info!(device_id = %config.device_id, url = %firmware_url, "Downloading firmware");
-    let auth_token = config.auth_token.as_ref().ok_or_else(|| anyhow::anyhow!("Auth token not found"))?;
-    debug!(device_id = %config.device_id, auth_token = %auth_token, "Downloading firmware with auth token"); // Debug log
+pub async fn report_device_error(client: &Client, config: &Config, stats: &ApiStats, error: &DeviceErrorPayload) -> Result<()> {
+    let url = format!("{}/api/devices/{}/errors", config.backend_url, config.device_id);
+    let request = |auth_token: &str| client.post(&url)
+        .header("X-Auth-Token", auth_token)
+        .json(error);
+    let response = send_authenticated(client, config, stats, "errors", Attempts::Once, request).await?;
+    check_status(config, stats, "errors", response).await?;
+    info!(device_id = %config.device_id, error_code = %error.error_code, "Reported device error.");
+    Ok(())
+}
 
-    let response = client.get(firmware_url)
-        .header("X-Auth-Token", auth_token) // Changed header name
-        .send().await?;
-    let bytes = response.error_for_status()?.bytes().await?.to_vec();
-    info!(device_id = %config.device_id, bytes = bytes.len(), "Firmware downloaded successfully");
-    Ok(bytes)
+/// Reports a device event, such as a geofence breach, as soon as it happens.
+pub async fn send_event(client: &Client, config: &Config, stats: &ApiStats, event: &DeviceEvent) -> Result<()> {
+    let url = format!("{}/api/devices/{}/events", config.backend_url, config.device_id);
+    let request = |auth_token: &str| client.post(&url)
+        .header("X-Auth-Token", auth_token)
+        .json(event);
+    let response = send_authenticated(client, config, stats, "events", Attempts::Retried, request).await?;
+    check_status(config, stats, "events", response).await?;
+    info!(device_id = %config.device_id, ?event, "Reported device event.");
+    Ok(())
 }
 
-pub async fn fetch_device_shadow(client: &Client, config: &Config) -> Result<DeviceShadow> {
-    let url = format!("{}/api/devices/{}/shadow", config.backend_url, config.device_id);
-    let auth_token = config.auth_token.as_ref().ok_or_else(|| anyhow::anyhow!("Auth token not found"))?;
-    debug!(device_id = %config.device_id, auth_token = %auth_token, "Fetching device shadow with auth token"); // Debug log
+/// Uploads a file under a purpose tag, e.g. `compliance`. The backend checks the body
+/// against `sha256` before storing it.
+pub async fn upload_file(client: &Client, config: &Config, stats: &ApiStats, purpose: &str, file_name: &str, body: &[u8], sha256: &str) -> Result<UploadedFile> {
+    let url = format!("{}/api/devices/{}/files", config.backend_url, config.device_id);
+    let request = |auth_token: &str| client.post(&url)
+        .query(&[("purpose", purpose), ("name", file_name)])
+        .header("X-Auth-Token", auth_token)
+        .header("X-Content-SHA256", sha256)
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .body(body.to_vec());
+    let response = send_authenticated(client, config, stats, "file_upload", Attempts::Retried, request).await?;
+    let response = check_status(config, stats, "file_upload", response).await?;
+    let uploaded = response.json::<UploadedFile>().await.map_err(NetError::Body)?;
+    info!(device_id = %config.device_id, purpose, file_name, file_id = %uploaded.file_id, bytes = body.len(), "Uploaded file");
+    Ok(uploaded)
+}
+
+/// A firmware image on disk, not yet checked against its metadata.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DownloadedFirmware {
+    pub path: PathBuf,
+    pub digest: [u8; 32], // SHA-256 of the image, computed as it streamed in
+    pub bytes: u64,
+    pub resumed_from: u64, // Bytes kept from an interrupted attempt; 0 if downloaded in one go
+}
 
+impl DownloadedFirmware {
+    pub fn sha256_hex(&self) -> String {
+        self.digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// How far an interrupted download got, kept next to its partial file so the next
+/// attempt asks only for the rest.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PartialDownload {
+    pub url: String,
+    pub offset: u64, // Bytes of the partial file known to be on disk
+}
+
+pub fn resume_path(destination: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.resume", destination.display()))
+}
+
+/// Bytes the next download of `firmware_url` to `destination` would keep from an
+/// interrupted one.
+pub fn resume_offset(destination: &Path, firmware_url: &str) -> u64 {
+    resumable_offset(destination, &resume_path(destination), firmware_url)
+}
+
+/// Streams a firmware image into the file at `destination` and calls `on_progress` with
+/// the bytes received so far and the total size when the backend sends one. The image is
+/// never held in memory whole.
+///
+/// A download cut off midway keeps what it received, and the next one for the same URL
+/// asks for the rest with a Range request. Progress is recorded as the image streams in,
+/// so this holds as well when the process dies mid-download. A server that answers with the whole image
+/// instead of 206 Partial Content starts it over. A download that fails before receiving
+/// anything, or that the server refuses, leaves no file behind.
+pub async fn download_firmware(
+    client: &Client,
+    config: &Config,
+    stats: &ApiStats,
+    firmware_url: &str,
+    destination: &Path,
+    mut on_progress: impl FnMut(u64, Option<u64>),
+) -> Result<DownloadedFirmware> {
+    let resume_path = resume_path(destination);
+    let offset = resumable_offset(destination, &resume_path, firmware_url);
+    if offset > 0 {
+        info!(device_id = %config.device_id, url = %firmware_url, offset, "Resuming firmware download");
+    } else {
+        info!(device_id = %config.device_id, url = %firmware_url, destination = %destination.display(), "Downloading firmware");
+    }
+    let request = |auth_token: &str| {
+        let request = client.get(firmware_url).header("X-Auth-Token", auth_token).timeout(FIRMWARE_DOWNLOAD_TIMEOUT); // Changed header name
+        if offset > 0 { request.header(RANGE, format!("bytes={}-", offset)) } else { request }
+    };
+    let response = send_authenticated(client, config, stats, "firmware_download", Attempts::Once, request).await?;
+    let response = match check_status(config, stats, "firmware_download", response).await {
+        Ok(response) => response,
+        Err(e) => {
+            // Refused, perhaps because the image changed under the partial one; start over next time
+            discard_partial(destination, &resume_path).await;
+            return Err(e);
+        }
+    };
+    let resumed_from = if offset > 0 && response.status() == StatusCode::PARTIAL_CONTENT && range_start(&response) == Some(offset) {
+        offset
+    } else {
+        if offset > 0 {
+            warn!(device_id = %config.device_id, offset, status = %response.status(), "Server did not resume the firmware download; starting over");
+        }
+        0
+    };
+    let mut written = resumed_from;
+    let checkpoint = (resume_path.as_path(), firmware_url);
+    match stream_to_file(response, destination, resumed_from, checkpoint, &mut written, &mut on_progress).await {
+        Ok(digest) => {
+            let _ = tokio::fs::remove_file(&resume_path).await;
+            info!(device_id = %config.device_id, bytes = written, resumed_from, "Firmware downloaded successfully");
+            Ok(DownloadedFirmware { path: destination.to_path_buf(), digest, bytes: written, resumed_from })
+        }
+        Err(e) => {
+            if written > 0 {
+                match record_progress(&resume_path, firmware_url, written).await {
+                    Ok(()) => warn!(device_id = %config.device_id, offset = written, error = %e, "Firmware download interrupted; the next attempt resumes from here"),
+                    Err(write_error) => {
+                        warn!(device_id = %config.device_id, error = %write_error, "Failed to record firmware download progress");
+                        discard_partial(destination, &resume_path).await;
+                    }
+                }
+            } else {
+                discard_partial(destination, &resume_path).await;
+            }
+            Err(e)
+        }
+    }
+}
+
+// Bytes to keep from an earlier attempt at the same URL, if its partial file still holds them
+fn resumable_offset(destination: &Path, resume_path: &Path, firmware_url: &str) -> u64 {
+    let Some(partial) = std::fs::read(resume_path).ok().and_then(|raw| serde_json::from_slice::<PartialDownload>(&raw).ok()) else {
+        return 0;
+    };
+    let on_disk = std::fs::metadata(destination).map_or(0, |metadata| metadata.len());
+    if partial.url == firmware_url && on_disk >= partial.offset { partial.offset } else { 0 }
+}
+
+// Notes that the first `offset` bytes of the download of `firmware_url` are on disk
+async fn record_progress(resume_path: &Path, firmware_url: &str, offset: u64) -> Result<()> {
+    let partial = PartialDownload { url: firmware_url.to_string(), offset };
+    tokio::fs::write(resume_path, serde_json::to_vec(&partial)?).await?;
+    Ok(())
+}
+
+async fn discard_partial(destination: &Path, resume_path: &Path) {
+    let _ = tokio::fs::remove_file(destination).await;
+    let _ = tokio::fs::remove_file(resume_path).await;
+}
+
+// First byte of a 206 response, from `Content-Range: bytes 100-199/200`
+fn range_start(response: &Response) -> Option<u64> {
+    let range = response.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    range.strip_prefix("bytes ")?.split('-').next()?.trim().parse().ok()
+}
+
+// Appends the body to the first `offset` bytes of `destination`, counting what is on disk
+// in `written` so an interrupted download knows where to resume, and recording it at
+// `checkpoint`, the resume file and URL, every RESUME_CHECKPOINT_BYTES in case the
+// process dies. Returns the SHA-256 of the whole image.
+async fn stream_to_file(
+    response: Response,
+    destination: &Path,
+    offset: u64,
+    (resume_path, firmware_url): (&Path, &str),
+    written: &mut u64,
+    on_progress: &mut impl FnMut(u64, Option<u64>),
+) -> Result<[u8; 32]> {
+    let total_bytes = response.content_length().map(|length| offset + length);
+    if let Some(parent) = destination.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut hasher = Sha256::new();
+    let mut file = if offset > 0 {
+        let mut kept = tokio::fs::File::open(destination).await?.take(offset);
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = kept.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+        let file = tokio::fs::OpenOptions::new().append(true).open(destination).await?;
+        file.set_len(offset).await?; // Drops whatever followed the last recorded byte
+        file
+    } else {
+        tokio::fs::File::create(destination).await?
+    };
+    *written = offset;
+    let mut recorded = offset;
+    let mut chunks = response.bytes_stream();
+    let streamed: Result<()> = async {
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.map_err(NetError::Body)?;
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+            *written += chunk.len() as u64;
+            on_progress(*written, total_bytes);
+            if *written - recorded >= RESUME_CHECKPOINT_BYTES {
+                // Flushed first, so the recorded bytes are with the OS should the process die
+                file.flush().await?;
+                record_progress(resume_path, firmware_url, *written).await?;
+                recorded = *written;
+            }
+        }
+        Ok(())
+    }.await;
+    // Also after an interruption, so the bytes counted are on disk when resuming
+    file.flush().await?;
+    file.sync_all().await?;
+    streamed?;
+    Ok(hasher.finalize().into())
+}
+
+/// Ships a batch of debug session log events. Sent once; the caller drops a batch that fails.
+pub async fn send_debug_events(client: &Client, config: &Config, stats: &ApiStats, events: &[DebugEvent]) -> Result<()> {
+    let url = format!("{}/api/devices/{}/debug", config.backend_url, config.device_id);
+    let request = |auth_token: &str| client.post(&url)
+        .header("X-Auth-Token", auth_token)
+        .json(&serde_json::json!({ "events": events }));
+    let response = send_authenticated(client, config, stats, "debug", Attempts::Once, request).await?;
+    check_status(config, stats, "debug", response).await?;
+    Ok(())
+}
+
+pub async fn fetch_device_shadow(client: &Client, config: &Config, stats: &ApiStats) -> Result<DeviceShadow> {
+    let url = format!("{}/api/devices/{}/shadow", config.backend_url, config.device_id);
     debug!(device_id = %config.device_id, "Fetching device shadow");
-    let shadow = client.get(&url)
+    let request = |auth_token: &str| client.get(&url)
         .header("X-Auth-Token", auth_token) // Changed header name
-        .send().await?.error_for_status()?.json::<DeviceShadow>().await?;
+        .header(ACCEPT_ENCODING, config.compression.accept_encoding());
+    let response = send_authenticated(client, config, stats, "shadow_fetch", Attempts::Retried, request).await?;
+    let shadow = read_json::<DeviceShadow>(check_status(config, stats, "shadow_fetch", response).await?).await?;
     debug!(device_id = %config.device_id, ?shadow, "Fetched device shadow");
     Ok(shadow)
 }
 
-pub async fn report_device_shadow(client: &Client, config: &Config, reported_state: ReportedShadowState) -> Result<()> {
+pub async fn report_device_shadow(client: &Client, config: &Config, stats: &ApiStats, reported_state: ReportedShadowState) -> Result<()> {
     let url = format!("{}/api/devices/{}/shadow", config.backend_url, config.device_id);
-    let auth_token = config.auth_token.as_ref().ok_or_else(|| anyhow::anyhow!("Auth token not found"))?;
-    debug!(device_id = %config.device_id, auth_token = %auth_token, "Reporting device shadow state with auth token"); // Debug log
-
     debug!(device_id = %config.device_id, ?reported_state, "Reporting device shadow state");
-    client.patch(&url)
-        .header("X-Auth-Token", auth_token) // Changed header name
-        .json(&reported_state)
-        .send().await?.error_for_status()?;
+    let (encoding, encoded) = encode_json(config, EndpointClass::Shadow, &reported_state)?;
+    let request = |auth_token: &str| with_body(client.patch(&url)
+        .header("X-Auth-Token", auth_token), encoding, &encoded); // Changed header name
+    let response = send_authenticated(client, config, stats, "shadow_report", Attempts::Retried, request).await?;
+    // Refused by the backend's policy rather than failed; retrying as is cannot succeed
+    if response.status() == StatusCode::UNPROCESSABLE_ENTITY {
+        let body = response.bytes().await.map_err(NetError::Body)?;
+        let rejections = serde_json::from_slice::<ShadowRejections>(&body).map(ShadowRejections::into_vec).unwrap_or_default();
+        return Err(ShadowRejected { rejections }.into());
+    }
+    check_status(config, stats, "shadow_report", response).await?;
     info!(device_id = %config.device_id, "Reported device shadow state.");
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,233 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::types::{IngestPayload, Measurement};
+
+/// Simulated mobile network generation the device is attached to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum NetworkType {
+    #[serde(rename = "2g")]
+    TwoG,
+    #[serde(rename = "3g")]
+    ThreeG,
+    #[serde(rename = "lte")]
+    Lte,
+    #[serde(rename = "nbiot")]
+    NbIot,
+    #[serde(rename = "wifi")]
+    Wifi,
+}
+
+impl NetworkType {
+    pub const ALL: [NetworkType; 5] = [NetworkType::TwoG, NetworkType::ThreeG, NetworkType::Lte, NetworkType::NbIot, NetworkType::Wifi];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NetworkType::TwoG => "2g",
+            NetworkType::ThreeG => "3g",
+            NetworkType::Lte => "lte",
+            NetworkType::NbIot => "nbiot",
+            NetworkType::Wifi => "wifi",
+        }
+    }
+
+    /// Bundled link characteristics for this network generation.
+    pub fn bundled_profile(self) -> NetworkProfile {
+        let (latency_ms, jitter_ms, bandwidth_bytes_per_sec, loss, max_payload_bytes, min_aggregation, nat_idle_timeout_secs) = match self {
+            NetworkType::TwoG => (600, 300, 5_000, 0.05, Some(4_096), 1, Some(120)),
+            NetworkType::ThreeG => (150, 60, 48_000, 0.02, Some(16_384), 1, Some(300)),
+            NetworkType::Lte => (50, 20, 1_250_000, 0.005, Some(65_536), 1, Some(300)),
+            NetworkType::NbIot => (1_500, 1_000, 3_000, 0.02, Some(1_024), 6, Some(60)),
+            NetworkType::Wifi => (10, 5, 5_000_000, 0.001, None, 1, None),
+        };
+        NetworkProfile { latency_ms, jitter_ms, bandwidth_bytes_per_sec, loss, max_payload_bytes, min_aggregation, nat_idle_timeout_secs }
+    }
+}
+
+impl fmt::Display for NetworkType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for NetworkType {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        NetworkType::ALL.into_iter()
+            .find(|network| network.as_str().eq_ignore_ascii_case(raw))
+            .ok_or_else(|| anyhow::anyhow!("unknown network type {:?}, expected one of 2g, 3g, lte, nbiot, wifi", raw))
+    }
+}
+
+/// Link characteristics applied to every backend request while on a network.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NetworkProfile {
+    pub latency_ms: u64,
+    #[serde(default)]
+    pub jitter_ms: u64, // Uniform extra latency on top of latency_ms
+    pub bandwidth_bytes_per_sec: u64,
+    #[serde(default)]
+    pub loss: f64, // Probability a request is lost in transit
+    #[serde(default)]
+    pub max_payload_bytes: Option<u64>, // Largest ingest body; batches are split to fit
+    #[serde(default = "default_min_aggregation")]
+    pub min_aggregation: u32, // Samples averaged into each stored measurement, even without a backlog
+    #[serde(default)]
+    pub nat_idle_timeout_secs: Option<u64>, // Carrier NAT silently drops push connections idle this long
+}
+
+fn default_min_aggregation() -> u32 {
+    1
+}
+
+/// Switches networks as the device moves: by the region it is in, or on a repeating schedule.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct RoamingConfig {
+    #[serde(default)]
+    pub schedule: Vec<RoamStep>, // Cycled through in order
+    #[serde(default)]
+    pub by_region: BTreeMap<String, NetworkType>, // Takes precedence over the schedule in mapped regions
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoamStep {
+    pub network: NetworkType,
+    pub duration_secs: u64,
+}
+
+/// Profile for the device's current network, with any configured overrides.
+/// None when no network is simulated.
+pub fn active_profile(config: &Config) -> Option<NetworkProfile> {
+    let network = config.network?;
+    Some(config.network_profiles.get(&network).cloned().unwrap_or_else(|| network.bundled_profile()))
+}
+
+/// What happens to one request on the link.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transit {
+    pub delay: Duration,
+    pub lost: bool,
+}
+
+/// Latency plus serialization delay for `bytes`, and whether the request is lost.
+pub fn transit(profile: &NetworkProfile, bytes: u64) -> Transit {
+    let mut rng = rand::thread_rng();
+    let jitter = if profile.jitter_ms > 0 { rng.gen_range(0..=profile.jitter_ms) } else { 0 };
+    Transit {
+        delay: Duration::from_millis(profile.latency_ms + jitter) + transfer_delay(profile, bytes),
+        lost: rng.gen_bool(profile.loss.clamp(0.0, 1.0)),
+    }
+}
+
+/// Delay range of the `latency_ms` chaos flag: a fixed number of milliseconds, or
+/// `{"min": 100, "max": 2000}` for a uniform pick per request.
+pub fn latency_range(flag: &Value) -> Result<(u64, u64), String> {
+    match flag {
+        Value::Object(range) => {
+            let bound = |key: &str| range.get(key).map(|value| value.as_u64().ok_or_else(|| format!("{} must be a whole number of milliseconds", key))).transpose();
+            let min = bound("min")?.unwrap_or(0);
+            let max = bound("max")?.ok_or("expected a max")?;
+            if min > max {
+                return Err(format!("min {} exceeds max {}", min, max));
+            }
+            Ok((min, max))
+        }
+        _ => flag.as_u64().map(|millis| (millis, millis)).ok_or_else(|| "expected milliseconds or {\"min\", \"max\"}".to_string()),
+    }
+}
+
+/// Extra delay the `latency_ms` chaos flag puts before each upload and heartbeat. None
+/// when the flag is unset, zero or malformed.
+pub fn chaos_latency(config: &Config) -> Option<Duration> {
+    let flag = config.chaos_flags.as_ref()?.get("latency_ms")?;
+    let (min, max) = latency_range(flag).ok()?;
+    let millis = rand::thread_rng().gen_range(min..=max);
+    (millis > 0).then(|| Duration::from_millis(millis))
+}
+
+/// Time to move `bytes` over the link at its bandwidth.
+pub fn transfer_delay(profile: &NetworkProfile, bytes: u64) -> Duration {
+    Duration::from_millis(bytes.saturating_mul(1000) / profile.bandwidth_bytes_per_sec.max(1))
+}
+
+/// Splits a batch so each ingest body stays within `max_payload_bytes`. A single
+/// measurement larger than the limit is still sent on its own.
+pub fn split_to_fit<'a>(batch: &'a [Measurement], device_id: &str, max_payload_bytes: Option<u64>) -> Vec<&'a [Measurement]> {
+    let Some(limit) = max_payload_bytes else {
+        return vec![batch];
+    };
+    let envelope = IngestPayload { device_id: device_id.to_string(), measurements: Vec::new(), region: batch.first().and_then(|m| m.region.clone()), data_endpoint: None };
+    // Room for the data endpoint, which is only known once the batch is routed
+    let overhead = serde_json::to_vec(&envelope).map_or(0, |body| body.len() as u64) + 256;
+
+    let mut chunks = Vec::new();
+    let (mut start, mut size) = (0, overhead);
+    for (index, measurement) in batch.iter().enumerate() {
+        // Sized as sent: the ingest body goes through serde_json::Value, which widens f32 readings
+        let bytes = serde_json::to_value(measurement).map_or(0, |value| value.to_string().len() as u64) + 1;
+        if index > start && size + bytes > limit {
+            chunks.push(&batch[start..index]);
+            (start, size) = (index, overhead);
+        }
+        size += bytes;
+    }
+    if start < batch.len() {
+        chunks.push(&batch[start..]);
+    }
+    chunks
+}
+
+/// Tracks roaming between networks in simulated time.
+#[derive(Debug)]
+pub struct Roaming {
+    config: RoamingConfig,
+    elapsed_secs: u64,
+    current: Option<NetworkType>,
+}
+
+impl Roaming {
+    pub fn new(config: RoamingConfig) -> Self {
+        Roaming { config, elapsed_secs: 0, current: None }
+    }
+
+    fn scheduled(&self) -> Option<NetworkType> {
+        let cycle: u64 = self.config.schedule.iter().map(|step| step.duration_secs).sum();
+        if cycle == 0 {
+            return None;
+        }
+        let mut offset = self.elapsed_secs % cycle;
+        for step in &self.config.schedule {
+            if offset < step.duration_secs {
+                return Some(step.network);
+            }
+            offset -= step.duration_secs;
+        }
+        None
+    }
+
+    /// Advances by `elapsed_secs` in `region`. Returns the network to switch to when
+    /// the roaming target changes; a network set another way is left alone until then.
+    pub fn advance(&mut self, elapsed_secs: u64, region: Option<&str>) -> Option<NetworkType> {
+        self.elapsed_secs += elapsed_secs;
+        let target = region.and_then(|region| self.config.by_region.get(region).copied()).or_else(|| self.scheduled());
+        if target == self.current {
+            return None;
+        }
+        self.current = target;
+        target
+    }
+}
+
+pub fn report(config: &Config) -> Value {
+    json!({
+        "network": config.network,
+        "profile": active_profile(config),
+    })
+}
@@ -0,0 +1,66 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// A window without any network activity, as requested through the `offline` chaos flag,
+/// e.g. `{"duration_secs": 600}` or `{"until": "..."}`. Measurements are still sampled
+/// and stored; uploads, heartbeats, shadow checks, OTA checks, schema refreshes, push
+/// keepalives and device events wait until it ends. Debug log batches are dropped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OfflineWindow {
+    pub started_at: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+    // The flag that opened the window, so one left in the shadow after the window ends
+    // does not open it again on every shadow check.
+    pub request: Value,
+}
+
+impl OfflineWindow {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        now < self.until
+    }
+
+    pub fn to_reported(&self, now: DateTime<Utc>) -> Value {
+        json!({
+            "active": self.is_active(now),
+            "started_at": self.started_at,
+            "until": self.until,
+        })
+    }
+}
+
+pub fn is_active(window: Option<&OfflineWindow>, now: DateTime<Utc>) -> bool {
+    window.is_some_and(|window| window.is_active(now))
+}
+
+/// Time left in the window; None once it has ended.
+pub fn remaining(window: Option<&OfflineWindow>, now: DateTime<Utc>) -> Option<std::time::Duration> {
+    window.filter(|window| window.is_active(now)).and_then(|window| (window.until - now).to_std().ok())
+}
+
+/// When the window the flag asks for ends.
+pub fn parse_request(request: &Value, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    match (request.get("duration_secs"), request.get("until")) {
+        (Some(_), Some(_)) => Err("expected duration_secs or until, not both".to_string()),
+        (Some(duration), None) => match duration.as_i64() {
+            Some(secs) if secs > 0 => Ok(now + Duration::seconds(secs)),
+            _ => Err(format!("duration_secs must be a positive number of seconds, got {}", duration)),
+        },
+        (None, Some(until)) => until.as_str()
+            .and_then(|raw| DateTime::parse_from_rfc3339(raw).ok())
+            .map(|until| until.with_timezone(&Utc))
+            .ok_or_else(|| format!("until must be an RFC 3339 timestamp, got {}", until)),
+        (None, None) => Err("expected duration_secs or until".to_string()),
+    }
+}
+
+/// Reconciles the persisted window with the `offline` chaos flag. A flag that cannot be
+/// parsed opens no window.
+pub fn reconcile(current: Option<OfflineWindow>, request: Option<&Value>, now: DateTime<Utc>) -> Option<OfflineWindow> {
+    let request = request?;
+    if let Some(current) = current.filter(|current| &current.request == request) {
+        return Some(current);
+    }
+    let until = parse_request(request, now).ok()?;
+    Some(OfflineWindow { started_at: now, until, request: request.clone() })
+}
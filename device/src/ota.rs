@@ -1,53 +1,819 @@
 use anyhow::Result;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, VerifyingKey};
 use reqwest::Client;
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
-use tracing::{info, error, debug}; // Add debug import
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, info_span, error, warn, Instrument};
 
+use crate::audit::{AuditLog, AuditSource};
 use crate::config::Config;
-use crate::net;
+use crate::firmware::{self, FirmwareBehavior};
+use crate::fleet::DevicePaths;
+use crate::net::{self, DownloadedFirmware};
+use crate::ota_history::{self, AttemptOutcome, OtaAttempt};
+use crate::slots::{self, SlotFault, SlotManifest};
+use crate::stats::ApiStats;
+use crate::types::{DeviceErrorPayload, FirmwareMetadata, Heartbeat, ReportedShadowState, RollbackReport};
+use uuid::Uuid;
 
-const OTA_STATE_PATH: &str = "./ota_state.json";
-const FIRMWARE_DIR: &str = "./firmware";
+pub const OTA_STATE_FILE: &str = "ota_state.json";
+pub const FIRMWARE_DIR: &str = "firmware";
+
+// Most recent attempts listed in the shadow
+const REPORTED_ATTEMPTS: usize = 5;
+// Least time between progress heartbeats of an overdue apply, however small the estimate
+const MIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct OtaState {
     pub current_version: String,
     pub active_slot: String,
+    #[serde(default)]
+    pub previous_version: Option<String>, // Last known-good version, restored on rollback
+    #[serde(default)]
+    pub previous_slot: Option<String>,
+    #[serde(default)]
+    pub pending_confirmation: bool, // New firmware booted but not yet confirmed healthy
+    #[serde(default)]
+    pub boot_count: u32, // Boots of the pending firmware so far; catches crash loops
+    #[serde(default)]
+    pub trial_heartbeats: u32, // Successful heartbeats sent by the pending firmware
+    #[serde(default)]
+    pub trial_ingested: bool, // Pending firmware has completed an ingest
+    #[serde(default)]
+    pub trial_started_at: Option<DateTime<Utc>>, // First boot of the pending firmware
+    #[serde(default)]
+    pub installed_behaviors: BTreeMap<String, FirmwareBehavior>, // Behavior overrides delivered with installed versions
+    #[serde(default)]
+    pub last_rollback: Option<RollbackReport>, // Reported in the next successful heartbeat, then cleared
+    #[serde(default)]
+    pub last_update: Option<OtaAttempt>, // The install the running firmware came from; reported in the next successful heartbeat, then cleared
+    #[serde(default)]
+    pub ota_status: OtaStatus, // Last update outcome, so a failure stays visible across restarts
+    #[serde(default)]
+    pub manifests: BTreeMap<String, PathBuf>, // By slot, the manifest of the firmware it holds; see slots::SlotManifest
+    #[serde(default)]
+    pub history: Vec<OtaAttempt>, // Update attempts, oldest first, up to ota_history::MAX_ATTEMPTS
+    #[serde(default)]
+    pub staged: Option<StagedUpdate>, // Downloaded and verified during maintenance, applied once it ends
+}
+
+/// An update downloaded and verified while maintenance held its apply back. The first
+/// OTA check after the window ends installs it without fetching it again.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StagedUpdate {
+    pub metadata: FirmwareMetadata,
+    pub download: DownloadedFirmware,
+    pub attempt: OtaAttempt, // Download and verify timings so far; recorded once applied
+}
+
+/// Where an update stands, reported in the shadow under `ota_status`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum OtaStatus {
+    #[default]
+    Idle,
+    Downloading { version: String, bytes: u64, total_bytes: Option<u64>, percent: Option<u8> },
+    Verifying { version: String },
+    Installing { version: String },
+    Rebooting { version: String },
+    Staged { version: String }, // Verified, waiting for maintenance to end before applying
+    Failed { version: String, error: String },
+}
+
+/// What the heartbeats around an update's apply phase announce, under `ota_phase`. The
+/// device sends one heartbeat before applying, with the downtime to expect, and nothing
+/// else while the apply holds its loop: no ingest, no shadow polling. Should the apply
+/// run `ota_apply_overdue_factor` times over the estimate, a progress heartbeat goes out,
+/// and again each time that long passes. A failed apply is announced right away; a
+/// successful one by the first heartbeat after the reboot, carrying `ota_update`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "phase", rename_all = "snake_case")]
+pub enum OtaPhase {
+    Applying { version: String, expected_downtime_ms: u64 },
+    Overdue { version: String, elapsed_ms: u64, expected_downtime_ms: u64 },
+    Failed { version: String, error: String },
+}
+
+pub type OtaStatusSender = mpsc::UnboundedSender<OtaStatus>;
+
+/// Publishes OTA status transitions to the shadow from a background task, so a slow
+/// shadow update never holds up the download. Only the newest queued status is sent.
+pub struct OtaStatusReporter {
+    sender: OtaStatusSender,
+    task: JoinHandle<()>,
+}
+
+impl OtaStatusReporter {
+    pub fn spawn(client: Client, config: Config, stats: ApiStats) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<OtaStatus>();
+        let task = tokio::spawn(async move {
+            while let Some(mut status) = receiver.recv().await {
+                while let Ok(newer) = receiver.try_recv() {
+                    status = newer;
+                }
+                let reported = ReportedShadowState { state: json!({ "ota_status": status }) };
+                match net::report_device_shadow(&client, &config, &stats, reported).await {
+                    Ok(()) => debug!(device_id = %config.device_id, ?status, "Reported OTA status"),
+                    Err(e) => warn!(device_id = %config.device_id, error = %e, ?status, "Failed to report OTA status"),
+                }
+            }
+        });
+        OtaStatusReporter { sender, task }
+    }
+
+    pub fn sender(&self) -> &OtaStatusSender {
+        &self.sender
+    }
+
+    /// Waits up to `timeout` for queued statuses to go out, e.g. before rebooting.
+    pub async fn flush(self, timeout: Duration) {
+        drop(self.sender);
+        if tokio::time::timeout(timeout, self.task).await.is_err() {
+            warn!("Timed out reporting OTA status");
+        }
+    }
 }
 
 impl OtaState {
-    pub fn load() -> Result<Self> {
-        if Path::new(OTA_STATE_PATH).exists() {
-            let file_content = fs::read_to_string(OTA_STATE_PATH)?;
-            let state: OtaState = serde_json::from_str(&file_content)?;
-            info!(path = OTA_STATE_PATH, ?state, "Loaded OTA state from file");
+    pub fn load_from(path: &Path) -> Result<Self, OtaError> {
+        if path.exists() {
+            let file_content = fs::read_to_string(path).map_err(OtaError::io("read", path))?;
+            let state: OtaState = serde_json::from_str(&file_content).map_err(|source| OtaError::State { path: path.to_path_buf(), source })?;
+            info!(path = %path.display(), ?state, "Loaded OTA state from file");
             Ok(state)
         } else {
             // Default state if none exists
             let default_state = OtaState {
                 current_version: env!("CARGO_PKG_VERSION").to_string(),
                 active_slot: "A".to_string(),
+                previous_version: None,
+                previous_slot: None,
+                pending_confirmation: false,
+                boot_count: 0,
+                trial_heartbeats: 0,
+                trial_ingested: false,
+                trial_started_at: None,
+                installed_behaviors: BTreeMap::new(),
+                last_rollback: None,
+                last_update: None,
+                ota_status: OtaStatus::Idle,
+                manifests: BTreeMap::new(),
+                history: Vec::new(),
+                staged: None,
             };
-            info!(path = OTA_STATE_PATH, ?default_state, "No OTA state file found, using default");
+            info!(path = %path.display(), ?default_state, "No OTA state file found, using default");
             Ok(default_state)
         }
     }
 
-    pub fn save(&self) -> Result<()> {
-        let file_content = serde_json::to_string_pretty(self)?;
-        fs::write(OTA_STATE_PATH, file_content)?;
-        info!(path = OTA_STATE_PATH, ?self, "OTA state saved to file");
+    pub fn save_to(&self, path: &Path) -> Result<(), OtaError> {
+        let file_content = serde_json::to_string_pretty(self).map_err(OtaError::Serialize)?;
+        fs::write(path, file_content).map_err(OtaError::io("write", path))?;
+        info!(path = %path.display(), ?self, "OTA state saved to file");
+        Ok(())
+    }
+
+    // Switches to the other slot with new firmware, keeping the current one as the rollback target
+    pub fn begin_trial(&mut self, new_version: String) {
+        self.previous_version = Some(std::mem::replace(&mut self.current_version, new_version));
+        self.previous_slot = Some(self.active_slot.clone());
+        self.active_slot = slots::other(&self.active_slot).to_string();
+        self.pending_confirmation = true;
+        self.reset_trial();
+    }
+
+    fn reset_trial(&mut self) {
+        self.boot_count = 0;
+        self.trial_heartbeats = 0;
+        self.trial_ingested = false;
+        self.trial_started_at = None;
+    }
+
+    // Counts a boot of pending firmware; the trial window starts at the first one
+    pub fn begin_boot(&mut self, now: DateTime<Utc>) {
+        if self.pending_confirmation {
+            self.boot_count += 1;
+            self.trial_started_at.get_or_insert(now);
+        }
+    }
+
+    // Counts a successful heartbeat of pending firmware. Returns true if that completes the health check and confirms the boot.
+    pub fn record_trial_heartbeat(&mut self, policy: &TrialPolicy) -> bool {
+        if !self.pending_confirmation {
+            return false;
+        }
+        self.trial_heartbeats += 1;
+        self.confirm_if_healthy(policy)
+    }
+
+    // Notes a completed ingest by pending firmware. Returns true if that completes the health check and confirms the boot.
+    pub fn record_trial_ingest(&mut self, policy: &TrialPolicy) -> bool {
+        if !self.pending_confirmation {
+            return false;
+        }
+        self.trial_ingested = true;
+        self.confirm_if_healthy(policy)
+    }
+
+    fn confirm_if_healthy(&mut self, policy: &TrialPolicy) -> bool {
+        if self.trial_heartbeats >= policy.heartbeats && (self.trial_ingested || !policy.require_ingest) {
+            self.confirm_boot();
+            return true;
+        }
+        false
+    }
+
+    // Why the pending firmware has failed its trial, if it has
+    pub fn trial_failure(&self, policy: &TrialPolicy, now: DateTime<Utc>) -> Option<&'static str> {
+        if !self.pending_confirmation {
+            return None;
+        }
+        if self.boot_count > policy.max_boots {
+            return Some("boot_limit");
+        }
+        let started_at = self.trial_started_at?;
+        ((now - started_at).num_seconds() > policy.window_secs as i64).then_some("health_check_window")
+    }
+
+    // Records a status transition and pushes it out. The receiver may be gone, e.g. in tests.
+    fn set_status(&mut self, status: OtaStatus, sender: &OtaStatusSender) {
+        self.ota_status = status.clone();
+        let _ = sender.send(status);
+    }
+
+    // A reboot into new firmware has completed. Returns true if the status changed.
+    pub fn finish_reboot(&mut self) -> bool {
+        if matches!(self.ota_status, OtaStatus::Rebooting { .. }) {
+            self.ota_status = OtaStatus::Idle;
+            return true;
+        }
+        false
+    }
+
+    // Simulated behavior of the running version; follows current_version through updates and rollbacks
+    pub fn behavior(&self, bundled: &BTreeMap<String, FirmwareBehavior>) -> FirmwareBehavior {
+        firmware::behavior_for(&self.current_version, &self.installed_behaviors, bundled)
+    }
+
+    // Marks the running firmware as known-good
+    pub fn confirm_boot(&mut self) {
+        if self.pending_confirmation {
+            ota_history::settle(&mut self.history, &self.current_version, AttemptOutcome::Confirmed);
+        }
+        self.pending_confirmation = false;
+        self.reset_trial();
+    }
+
+    fn record_attempt(&mut self, attempt: OtaAttempt) {
+        info!(
+            version = %attempt.version,
+            outcome = ?attempt.outcome,
+            bytes_downloaded = attempt.bytes_downloaded,
+            download_ms = attempt.download_ms,
+            verify_ms = attempt.verify_ms,
+            apply_ms = attempt.apply_ms,
+            retries = attempt.retries,
+            "Recorded OTA attempt"
+        );
+        if attempt.outcome == AttemptOutcome::Installed {
+            self.last_update = Some(attempt.clone());
+        }
+        ota_history::record(&mut self.history, attempt);
+    }
+
+    /// Recent attempts and daily totals, reported in the shadow under `ota_history`.
+    pub fn history_report(&self, now: DateTime<Utc>) -> Value {
+        json!({
+            "recent": self.history.iter().rev().take(REPORTED_ATTEMPTS).collect::<Vec<_>>(),
+            "daily": ota_history::daily_summary(&self.history, now),
+        })
+    }
+
+    // Reverts to the previous version and slot. Returns false if there is nothing to revert to.
+    pub fn rollback(&mut self) -> bool {
+        self.reset_trial();
+        let (Some(previous_version), Some(previous_slot)) = (self.previous_version.take(), self.previous_slot.take()) else {
+            self.pending_confirmation = false;
+            return false;
+        };
+        self.current_version = previous_version;
+        self.active_slot = previous_slot;
+        self.pending_confirmation = false;
+        true
+    }
+}
+
+/// How pending firmware proves itself: `heartbeats` successful heartbeats, and an
+/// ingest if `require_ingest` is set, within `window_secs` of its first boot, over no
+/// more than `max_boots` boots.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrialPolicy {
+    pub heartbeats: u32,
+    pub window_secs: u64,
+    pub max_boots: u32,
+    pub require_ingest: bool,
+}
+
+impl TrialPolicy {
+    pub fn from_config(config: &Config) -> Self {
+        TrialPolicy {
+            heartbeats: config.ota_trial_heartbeats,
+            window_secs: config.ota_trial_window_secs,
+            max_boots: config.ota_max_trial_boots,
+            require_ingest: config.ota_trial_require_ingest,
+        }
+    }
+}
+
+/// Runs the boot-confirmation gate for firmware that has not been confirmed yet: counts
+/// the boot and rolls back if the trial has already failed. With the
+/// `fail_boot_confirmation` chaos flag set, confirmation deterministically fails and
+/// the device reverts to the previous slot. Returns true on rollback.
+pub fn handle_trial_boot(state: &mut OtaState, policy: &TrialPolicy, fail_boot_confirmation: bool, now: DateTime<Utc>) -> bool {
+    if !state.pending_confirmation {
+        return false;
+    }
+    state.begin_boot(now);
+    if fail_boot_confirmation {
+        warn!(version = %state.current_version, chaos_type = "fail_boot_confirmation", "Injecting boot confirmation failure");
+        return roll_back_trial(state, "fail_boot_confirmation", now);
+    }
+    check_trial(state, policy, now)
+}
+
+/// Rolls back pending firmware whose trial has failed while running. Returns true on rollback.
+pub fn check_trial(state: &mut OtaState, policy: &TrialPolicy, now: DateTime<Utc>) -> bool {
+    match state.trial_failure(policy, now) {
+        Some(reason) => roll_back_trial(state, reason, now),
+        None => false,
+    }
+}
+
+fn roll_back_trial(state: &mut OtaState, reason: &str, now: DateTime<Utc>) -> bool {
+    let failed_version = state.current_version.clone();
+    let (boot_count, heartbeats, ingested) = (state.boot_count, state.trial_heartbeats, state.trial_ingested);
+    if state.rollback() {
+        ota_history::settle(&mut state.history, &failed_version, AttemptOutcome::RolledBack);
+        warn!(failed_version = %failed_version, version = %state.current_version, slot = %state.active_slot, reason, boot_count, heartbeats, ingested, "Rolled back to previous firmware");
+        state.ota_status = OtaStatus::Failed { version: failed_version.clone(), error: format!("rolled back: {}", reason) };
+        state.last_rollback = Some(RollbackReport {
+            failed_version,
+            version: state.current_version.clone(),
+            slot: state.active_slot.clone(),
+            reason: reason.to_string(),
+            boot_count,
+            heartbeats,
+            ingested,
+            at: now,
+        });
+        true
+    } else {
+        error!(version = %state.current_version, reason, "Firmware trial failed but no previous firmware to roll back to");
+        false
+    }
+}
+
+/// Why an update could not be verified or installed, or the OTA state kept.
+#[derive(Debug, thiserror::Error)]
+pub enum OtaError {
+    #[error(transparent)]
+    Metadata(#[from] MetadataRejection),
+    #[error(transparent)]
+    Signature(#[from] SignatureRejection),
+    #[error(transparent)]
+    Checksum(#[from] ChecksumMismatch),
+    #[error(transparent)]
+    Slot(#[from] SlotFault),
+    #[error("failed to {action} {}", .path.display())]
+    Io { action: &'static str, path: PathBuf, #[source] source: std::io::Error },
+    #[error("invalid OTA state file {}", .path.display())]
+    State { path: PathBuf, #[source] source: serde_json::Error },
+    #[error("failed to serialize OTA state or a slot manifest")]
+    Serialize(#[source] serde_json::Error),
+}
+
+impl OtaError {
+    /// Maps an io::Error from doing `action` to the file or directory at `path`.
+    pub fn io(action: &'static str, path: &Path) -> impl FnOnce(std::io::Error) -> OtaError {
+        let path = path.to_path_buf();
+        move |source| OtaError::Io { action, path, source }
+    }
+}
+
+/// Why firmware metadata was refused. Each variant maps to a distinct OTA error code.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum MetadataRejection {
+    #[error("firmware metadata is not signed")]
+    Unsigned,
+    #[error("firmware metadata signature does not verify")]
+    BadSignature,
+    #[error("firmware metadata carries no nonce")]
+    MissingNonce,
+    #[error("firmware metadata nonce does not match the request")]
+    NonceMismatch,
+    #[error("firmware metadata carries no issued_at timestamp")]
+    MissingTimestamp,
+    #[error("firmware metadata is {age_secs}s old, freshness window is {max_age_secs}s")]
+    Stale { age_secs: i64, max_age_secs: u64 },
+}
+
+impl MetadataRejection {
+    pub fn code(&self) -> &'static str {
+        match self {
+            MetadataRejection::Unsigned | MetadataRejection::BadSignature => "OTA_METADATA_SIGNATURE",
+            MetadataRejection::MissingNonce | MetadataRejection::NonceMismatch => "OTA_METADATA_REPLAY",
+            MetadataRejection::MissingTimestamp | MetadataRejection::Stale { .. } => "OTA_METADATA_STALE",
+        }
+    }
+}
+
+/// What the backend signs firmware metadata over: the fields the device acts on, with the
+/// echoed nonce and the issue time in whole seconds, as a compact JSON array.
+pub fn metadata_message(metadata: &FirmwareMetadata) -> Vec<u8> {
+    let issued_at = metadata.issued_at.map(|issued_at| issued_at.timestamp());
+    serde_json::to_vec(&("vf-ota-metadata/1", &metadata.version, &metadata.checksum, &metadata.url, &metadata.nonce, issued_at))
+        .expect("a tuple of strings and numbers always serializes")
+}
+
+/// Replay protection for firmware metadata: the response must echo the nonce sent with
+/// the request and be issued within the freshness window. With a `key`, the metadata
+/// signature is checked first, so the nonce and timestamp checked are the backend's;
+/// without one they are checked as they arrived. Timestamps slightly in the future are
+/// tolerated within the same window to allow for clock drift.
+pub fn verify_metadata(
+    metadata: &FirmwareMetadata,
+    expected_nonce: &str,
+    now: DateTime<Utc>,
+    max_age_secs: u64,
+    key: Option<&VerifyingKey>,
+) -> std::result::Result<(), MetadataRejection> {
+    if let Some(key) = key {
+        match verify_signature(&metadata_message(metadata), metadata.metadata_signature.as_deref(), key) {
+            Ok(()) => {}
+            Err(SignatureRejection::Missing) => return Err(MetadataRejection::Unsigned),
+            Err(_) => return Err(MetadataRejection::BadSignature),
+        }
+    }
+    match metadata.nonce.as_deref() {
+        None => return Err(MetadataRejection::MissingNonce),
+        Some(nonce) if nonce != expected_nonce => return Err(MetadataRejection::NonceMismatch),
+        Some(_) => {}
+    }
+    let issued_at = metadata.issued_at.ok_or(MetadataRejection::MissingTimestamp)?;
+    let age_secs = (now - issued_at).num_seconds();
+    if age_secs.unsigned_abs() > max_age_secs {
+        return Err(MetadataRejection::Stale { age_secs, max_age_secs });
+    }
+    Ok(())
+}
+
+/// Downloaded image does not hash to the checksum the firmware metadata advertised.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("firmware checksum mismatch: expected {expected}, got {actual}")]
+pub struct ChecksumMismatch {
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Compares the SHA-256 hex digest of an image with the advertised checksum, either bare
+/// hex or prefixed with `sha256:`.
+pub fn verify_checksum(actual: &str, expected: &str) -> std::result::Result<(), ChecksumMismatch> {
+    let expected_hex = expected.trim();
+    let expected_hex = match expected_hex.split_once(':') {
+        Some((algorithm, hex)) if algorithm.eq_ignore_ascii_case("sha256") => hex,
+        _ => expected_hex,
+    };
+    if actual.eq_ignore_ascii_case(expected_hex) {
         Ok(())
+    } else {
+        Err(ChecksumMismatch { expected: expected.to_string(), actual: actual.to_string() })
+    }
+}
+
+/// Why a downloaded image's signature was refused.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum SignatureRejection {
+    #[error("firmware metadata carries no signature")]
+    Missing,
+    #[error("firmware signature is not 64 hex-encoded bytes")]
+    Malformed,
+    #[error("firmware signature does not match the image")]
+    Invalid,
+}
+
+/// Parses a hex-encoded ed25519 public key, as given in `ota_public_key`.
+pub fn public_key(hex: &str) -> std::result::Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = decode_hex(hex).and_then(|bytes| bytes.try_into().ok()).ok_or("expected 32 hex-encoded bytes")?;
+    VerifyingKey::from_bytes(&bytes).map_err(|_| "not a valid ed25519 public key".to_string())
+}
+
+/// Checks the hex-encoded ed25519 `signature` over `data`: an image's SHA-256 digest, or
+/// the metadata message. Unlike the checksum, which only catches corruption, this proves
+/// the image came from the holder of the signing key.
+pub fn verify_signature(data: &[u8], signature: Option<&str>, key: &VerifyingKey) -> std::result::Result<(), SignatureRejection> {
+    let signature = signature.ok_or(SignatureRejection::Missing)?;
+    let bytes: [u8; 64] = decode_hex(signature).and_then(|bytes| bytes.try_into().ok()).ok_or(SignatureRejection::Malformed)?;
+    key.verify_strict(data, &Signature::from_bytes(&bytes)).map_err(|_| SignatureRejection::Invalid)
+}
+
+/// Bytes of a hex string; None unless it is an even number of hex digits.
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.trim();
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
     }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
 }
 
-pub async fn check_for_update(client: &Client, config: &Config, current_state: &mut OtaState) -> Result<()> {
-    info!(device_id = %config.device_id, current_version = %current_state.current_version, "Checking for firmware updates");
-    
-    match net::fetch_latest_firmware(client, config).await {
+/// Verifies the downloaded image and moves it into the inactive slot under `firmware_dir`,
+/// then switches to that slot on trial. Returns the slot's new manifest. On a checksum
+/// mismatch nothing is moved, so the inactive slot keeps the rollback target, and the state
+/// is left as it was. Saving the state is left to the caller.
+pub fn install_firmware(state: &mut OtaState, metadata: &FirmwareMetadata, download: &DownloadedFirmware, firmware_dir: &Path) -> Result<PathBuf, OtaError> {
+    verify_checksum(&download.sha256_hex(), &metadata.checksum)?;
+    let target = slots::other(&state.active_slot);
+    let manifest = slots::install_file(firmware_dir, target, &metadata.version, &download.path, download.sha256_hex(), metadata.behavior.clone())?;
+    state.manifests.insert(target.to_string(), manifest.clone());
+    if let Some(behavior) = &metadata.behavior {
+        state.installed_behaviors.insert(metadata.version.clone(), behavior.clone());
+    }
+    state.begin_trial(metadata.version.clone());
+    Ok(manifest)
+}
+
+/// Moves firmware from the flat `firmware_{version}.bin` layout into slot directories
+/// with manifests, taking behavior overlays from `installed_behaviors`. An active slot
+/// without an image gets the factory image. Returns true if the state changed.
+pub fn migrate_slots(state: &mut OtaState, firmware_dir: &Path) -> Result<bool, OtaError> {
+    if !state.manifests.is_empty() {
+        return Ok(false);
+    }
+    let installed = [
+        Some((state.active_slot.clone(), state.current_version.clone())),
+        state.previous_slot.clone().zip(state.previous_version.clone()),
+    ];
+    for (slot, version) in installed.into_iter().flatten() {
+        let flat = firmware_dir.join(format!("firmware_{}.bin", version));
+        let behavior = state.installed_behaviors.get(&version).cloned();
+        let manifest = if flat.exists() {
+            let checksum = slots::file_sha256(&flat).map_err(OtaError::io("read", &flat))?;
+            slots::install_file(firmware_dir, &slot, &version, &flat, checksum, behavior)?
+        } else if slot == state.active_slot {
+            slots::install(firmware_dir, &slot, &version, &slots::factory_image(&version), behavior)?
+        } else {
+            warn!(slot = %slot, version = %version, "No image of the previous firmware to migrate; its slot stays empty");
+            continue;
+        };
+        info!(slot = %slot, version = %version, manifest = %manifest.display(), "Migrated firmware into its slot");
+        state.manifests.insert(slot, manifest);
+    }
+    Ok(true)
+}
+
+/// Which firmware a boot runs, per the slot checks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BootSlot {
+    pub manifest: Option<SlotManifest>, // Verified manifest of the slot booted; None if neither slot verified
+    pub fault: Option<SlotFault>, // Why the slot that was active could not be booted
+}
+
+/// Verifies the active slot's image as the bootloader would. A slot that fails falls back
+/// to the other one if that verifies, which then runs as known-good firmware: the failure
+/// is recorded as a rollback for the next heartbeat and as the OTA status.
+pub fn boot_slot(state: &mut OtaState, now: DateTime<Utc>) -> BootSlot {
+    let active = state.active_slot.clone();
+    let fault = match slots::verify(&active, state.manifests.get(&active).map(PathBuf::as_path)) {
+        Ok(manifest) => return BootSlot { manifest: Some(manifest), fault: None },
+        Err(fault) => fault,
+    };
+    let fallback = slots::other(&active);
+    let manifest = match slots::verify(fallback, state.manifests.get(fallback).map(PathBuf::as_path)) {
+        Ok(manifest) => manifest,
+        Err(other_fault) => {
+            error!(slot = %active, error = %fault, fallback_error = %other_fault, "No bootable firmware slot; running on as is");
+            return BootSlot { manifest: None, fault: Some(fault) };
+        }
+    };
+    let failed_version = std::mem::replace(&mut state.current_version, manifest.version.clone());
+    let (boot_count, heartbeats, ingested) = (state.boot_count, state.trial_heartbeats, state.trial_ingested);
+    warn!(failed_version = %failed_version, slot = %active, error = %fault, version = %manifest.version, fallback_slot = fallback, "Active firmware slot failed verification, booting the other one");
+    state.active_slot = fallback.to_string();
+    // The failed slot is no rollback target
+    state.previous_version = None;
+    state.previous_slot = None;
+    state.pending_confirmation = false;
+    state.reset_trial();
+    state.ota_status = OtaStatus::Failed { version: failed_version.clone(), error: fault.to_string() };
+    ota_history::settle(&mut state.history, &failed_version, AttemptOutcome::RolledBack);
+    state.last_rollback = Some(RollbackReport {
+        failed_version,
+        version: manifest.version.clone(),
+        slot: state.active_slot.clone(),
+        reason: "slot_verification".to_string(),
+        boot_count,
+        heartbeats,
+        ingested,
+        at: now,
+    });
+    BootSlot { manifest: Some(manifest), fault: Some(fault) }
+}
+
+// Deletes a download that will not be installed
+fn discard(download: &DownloadedFirmware) {
+    if let Err(e) = fs::remove_file(&download.path).or_else(|e| if e.kind() == std::io::ErrorKind::NotFound { Ok(()) } else { Err(e) }) {
+        warn!(path = %download.path.display(), error = %e, "Failed to delete rejected firmware download");
+    }
+}
+
+// Persists a failed update so it is still visible after a restart
+fn fail_update(state: &mut OtaState, state_path: &Path, status: &OtaStatusSender, version: &str, error: String) {
+    state.set_status(OtaStatus::Failed { version: version.to_string(), error }, status);
+    if let Err(e) = state.save_to(state_path) {
+        error!(error = %e, "Failed to save OTA status");
+    }
+}
+
+// Progress granularity: every 10% with a known size, otherwise every 64 KiB
+fn progress_step(bytes: u64, total_bytes: Option<u64>) -> (Option<u8>, u64) {
+    match total_bytes.filter(|total| *total > 0) {
+        Some(total) => {
+            let percent = (bytes.saturating_mul(100) / total).min(100) as u8;
+            (Some(percent), percent as u64 / 10)
+        }
+        None => (None, bytes / 65_536),
+    }
+}
+
+/// Downtime to announce before applying an update: the configured apply duration, or
+/// the mean of past applies if they took longer.
+pub fn apply_estimate(configured_ms: u64, history: &[OtaAttempt]) -> u64 {
+    let applied: Vec<u64> = history.iter().map(|attempt| attempt.apply_ms).filter(|ms| *ms > 0).collect();
+    if applied.is_empty() {
+        return configured_ms;
+    }
+    configured_ms.max(applied.iter().sum::<u64>() / applied.len() as u64)
+}
+
+// Sends `heartbeat` announcing `phase`. A failure is only logged; the update goes on.
+async fn announce(client: &Client, config: &Config, stats: &ApiStats, heartbeat: &Heartbeat, phase: OtaPhase) {
+    let mut heartbeat = heartbeat.clone();
+    heartbeat.ota_phase = Some(phase);
+    if let Err(e) = net::send_heartbeat(client, config, stats, &heartbeat).await {
+        warn!(device_id = %config.device_id, error = %e, "Failed to announce OTA phase");
+    }
+}
+
+// The simulated apply: ota_apply_ms, plus any hang the `ota_apply_hang_ms` chaos flag
+// asks for, with progress heartbeats once it runs over the estimate
+async fn simulate_apply(client: &Client, config: &Config, stats: &ApiStats, heartbeat: &Heartbeat, version: &str, expected_downtime_ms: u64) {
+    let hang_ms = config.chaos_flags.as_ref().and_then(|flags| flags.get("ota_apply_hang_ms")).and_then(Value::as_u64).unwrap_or(0);
+    if hang_ms > 0 {
+        warn!(device_id = %config.device_id, chaos_type = "ota_apply_hang_ms", hang_ms, "Injecting a hang into the firmware apply");
+    }
+    let overdue_after = Duration::from_millis(expected_downtime_ms).mul_f32(config.ota_apply_overdue_factor.max(1.0)).max(MIN_PROGRESS_INTERVAL);
+    let started = Instant::now();
+    let applied = tokio::time::sleep(Duration::from_millis(config.ota_apply_ms.saturating_add(hang_ms)));
+    tokio::pin!(applied);
+    loop {
+        tokio::select! {
+            _ = &mut applied => return,
+            _ = tokio::time::sleep(overdue_after) => {
+                let elapsed_ms = started.elapsed().as_millis() as u64;
+                warn!(device_id = %config.device_id, version, elapsed_ms, expected_downtime_ms, "Firmware apply is taking longer than announced");
+                announce(client, config, stats, heartbeat, OtaPhase::Overdue { version: version.to_string(), elapsed_ms, expected_downtime_ms }).await;
+            }
+        }
+    }
+}
+
+// Installs a verified download into the inactive slot, announcing the apply phase around
+// it; true once the device must reboot into the new firmware
+#[allow(clippy::too_many_arguments)]
+async fn apply_update(
+    client: &Client,
+    config: &Config,
+    stats: &ApiStats,
+    paths: &DevicePaths,
+    current_state: &mut OtaState,
+    audit_log: &mut AuditLog,
+    status: &OtaStatusSender,
+    heartbeat: &Heartbeat,
+    firmware_metadata: &FirmwareMetadata,
+    download: &DownloadedFirmware,
+    mut attempt: OtaAttempt,
+) -> Result<bool> {
+    let version = firmware_metadata.version.clone();
+    let install_span = info_span!("ota_install", version = %version);
+    current_state.set_status(OtaStatus::Installing { version: version.clone() }, status);
+
+    let previous_version = current_state.current_version.clone();
+    let expected_downtime_ms = apply_estimate(config.ota_apply_ms, &current_state.history);
+    info!(device_id = %config.device_id, %version, expected_downtime_ms, "Applying firmware");
+    let installing = Instant::now();
+    let applying = async {
+        announce(client, config, stats, heartbeat, OtaPhase::Applying { version: version.clone(), expected_downtime_ms }).await;
+        simulate_apply(client, config, stats, heartbeat, &version, expected_downtime_ms).await;
+    };
+    applying.instrument(install_span.clone()).await;
+    let installed = install_span.in_scope(|| install_firmware(current_state, firmware_metadata, download, &paths.firmware_dir));
+    let manifest = match installed {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            error!(device_id = %config.device_id, error = %e, "Failed to install firmware");
+            discard(download);
+            attempt.apply_ms = installing.elapsed().as_millis() as u64;
+            attempt.outcome = AttemptOutcome::InstallFailed;
+            attempt.error = Some(e.to_string());
+            current_state.record_attempt(attempt);
+            fail_update(current_state, &paths.ota_state, status, &version, e.to_string());
+            announce(client, config, stats, heartbeat, OtaPhase::Failed { version: version.clone(), error: e.to_string() }).await;
+            return Err(e.into());
+        }
+    };
+    let _install = install_span.enter();
+    attempt.apply_ms = installing.elapsed().as_millis() as u64;
+    attempt.outcome = AttemptOutcome::Installed;
+    current_state.record_attempt(attempt);
+    info!(device_id = %config.device_id, slot = %current_state.active_slot, manifest = %manifest.display(), "Firmware saved.");
+
+    current_state.set_status(OtaStatus::Rebooting { version }, status);
+    current_state.save_to(&paths.ota_state)?;
+    audit_log.record(AuditSource::Ota, "ota_apply", json!(previous_version), json!(current_state.current_version));
+
+    info!(device_id = %config.device_id, new_version = %current_state.current_version, "Switched to new firmware version. Rebooting...");
+    Ok(true)
+}
+
+/// Returns true once new firmware is installed and the device must reboot into it.
+/// Status transitions go to `status` as they happen; the final one is persisted. The
+/// apply phase is announced with `heartbeat`, the device's current one; see OtaPhase.
+/// With `hold_apply`, during maintenance, an update is still downloaded and verified
+/// but only staged; the first check without it applies the staged update.
+#[allow(clippy::too_many_arguments)]
+pub async fn check_for_update(
+    client: &Client,
+    config: &Config,
+    stats: &ApiStats,
+    paths: &DevicePaths,
+    current_state: &mut OtaState,
+    audit_log: &mut AuditLog,
+    status: &OtaStatusSender,
+    heartbeat: &Heartbeat,
+    hold_apply: bool,
+) -> Result<bool> {
+    info!(device_id = %config.device_id, current_version = %current_state.current_version, hold_apply, "Checking for firmware updates");
+
+    if !hold_apply {
+        if let Some(staged) = current_state.staged.take() {
+            if staged.download.path.exists() {
+                info!(device_id = %config.device_id, version = %staged.metadata.version, "Applying firmware held back during maintenance");
+                return apply_update(client, config, stats, paths, current_state, audit_log, status, heartbeat, &staged.metadata, &staged.download, staged.attempt).await;
+            }
+            warn!(device_id = %config.device_id, version = %staged.metadata.version, "Held firmware download is gone, checking for updates again");
+            current_state.save_to(&paths.ota_state)?;
+        }
+    }
+
+    let key = config.ota_public_key.as_deref().map(public_key).transpose().map_err(|e| anyhow::anyhow!("Invalid ota_public_key: {}", e))?;
+    let nonce = Uuid::new_v4().to_string();
+    let metadata = net::fetch_latest_firmware(client, config, stats, &current_state.current_version, &nonce)
+        .instrument(info_span!("ota_metadata"));
+    match metadata.await {
         Ok(Some(firmware_metadata)) => {
+            if let Err(rejection) = verify_metadata(&firmware_metadata, &nonce, Utc::now(), config.ota_metadata_freshness_secs, key.as_ref()) {
+                warn!(
+                    device_id = %config.device_id,
+                    offered_version = %firmware_metadata.version,
+                    error_code = rejection.code(),
+                    reason = %rejection,
+                    "Rejected firmware metadata"
+                );
+                audit_log.record(
+                    AuditSource::Ota,
+                    "ota_metadata_rejected",
+                    json!(current_state.current_version),
+                    json!({ "offered_version": firmware_metadata.version, "error_code": rejection.code() }),
+                );
+                fail_update(current_state, &paths.ota_state, status, &firmware_metadata.version, rejection.to_string());
+                let error = DeviceErrorPayload {
+                    firmware_version: current_state.current_version.clone(),
+                    error_code: rejection.code().to_string(),
+                    error_message: format!("{} (offered version {})", rejection, firmware_metadata.version),
+                };
+                if let Err(e) = net::report_device_error(client, config, stats, &error).await {
+                    error!(device_id = %config.device_id, error = %e, "Failed to report firmware metadata rejection");
+                }
+                return Ok(false);
+            }
+
             if firmware_metadata.version != current_state.current_version {
                 info!(
                     device_id = %config.device_id, 
@@ -55,31 +821,117 @@ pub async fn check_for_update(client: &Client, config: &Config, current_state: &
                     new_version = %firmware_metadata.version, 
                     "New firmware version available"
                 );
-                
-                // In a real device, you'd download to the inactive slot.
-                // Here, we just download it to a firmware directory.
-                match net::download_firmware(client, config, &firmware_metadata.url).await { // Pass config to download_firmware
-                    Ok(firmware_data) => {
-                        debug!(device_id = %config.device_id, "Checksum verification would happen here.");
-
-                        // Create firmware directory if it doesn't exist
-                        fs::create_dir_all(FIRMWARE_DIR)?;
-                        let file_path = Path::new(FIRMWARE_DIR).join(format!("firmware_{}.bin", firmware_metadata.version));
-                        fs::write(&file_path, firmware_data)?; // Pass reference to file_path
-                        info!(device_id = %config.device_id, file_path = %file_path.display(), "Firmware saved.");
-
-                        // "Switch" to the new version
-                        current_state.current_version = firmware_metadata.version;
-                        current_state.active_slot = if current_state.active_slot == "A" { "B" } else { "A" }.to_string();
-                        current_state.save()?;
-                        
-                        info!(device_id = %config.device_id, new_version = %current_state.current_version, "Switched to new firmware version. Rebooting...");
-
-                        // Simulate reboot by exiting. Docker will restart the container.
-                        std::process::exit(0);
+                let version = firmware_metadata.version.clone();
+                if current_state.staged.as_ref().is_some_and(|staged| staged.metadata.version == version) {
+                    info!(device_id = %config.device_id, %version, "Firmware already downloaded, held until maintenance ends");
+                    return Ok(false);
+                }
+
+                // Streamed to a temporary file next to the slots, and moved into the inactive
+                // one only once verified
+                current_state.set_status(OtaStatus::Downloading { version: version.clone(), bytes: 0, total_bytes: None, percent: None }, status);
+                let destination = paths.firmware_dir.join(format!("download_{}.part", firmware_metadata.version));
+                let resume_offset = net::resume_offset(&destination, &firmware_metadata.url);
+                let mut attempt = OtaAttempt {
+                    version: version.clone(),
+                    from_version: current_state.current_version.clone(),
+                    started_at: Utc::now(),
+                    image_bytes: None,
+                    bytes_downloaded: 0,
+                    resumed: resume_offset > 0,
+                    retries: ota_history::retries(&current_state.history, &version),
+                    download_ms: 0,
+                    verify_ms: 0,
+                    apply_ms: 0,
+                    network: config.network,
+                    outcome: AttemptOutcome::DownloadFailed,
+                    error: None,
+                };
+                let started = Instant::now();
+                let mut last_step = None;
+                let mut received = (resume_offset, None);
+                let on_progress = |bytes: u64, total_bytes: Option<u64>| {
+                    received = (bytes, total_bytes);
+                    let (percent, step) = progress_step(bytes, total_bytes);
+                    if last_step != Some(step) {
+                        last_step = Some(step);
+                        let _ = status.send(OtaStatus::Downloading { version: version.clone(), bytes, total_bytes, percent });
+                    }
+                };
+                let download = net::download_firmware(client, config, stats, &firmware_metadata.url, &destination, on_progress)
+                    .instrument(info_span!("ota_download", version = %firmware_metadata.version));
+                match download.await {
+                    Ok(download) => {
+                        attempt.download_ms = started.elapsed().as_millis() as u64;
+                        attempt.image_bytes = Some(download.bytes);
+                        attempt.bytes_downloaded = download.bytes - download.resumed_from;
+                        attempt.resumed = download.resumed_from > 0;
+                        let install_span = info_span!("ota_install", version = %firmware_metadata.version);
+                        let _install = install_span.enter();
+                        // A bad image is deleted and leaves the state untouched; the error surfaces so the next OTA tick retries
+                        current_state.set_status(OtaStatus::Verifying { version: version.clone() }, status);
+                        let verifying = Instant::now();
+                        attempt.outcome = AttemptOutcome::Rejected;
+                        if let Err(mismatch) = verify_checksum(&download.sha256_hex(), &firmware_metadata.checksum) {
+                            error!(
+                                device_id = %config.device_id,
+                                version = %firmware_metadata.version,
+                                expected = %mismatch.expected,
+                                actual = %mismatch.actual,
+                                "Firmware checksum mismatch, not installing"
+                            );
+                            discard(&download);
+                            attempt.verify_ms = verifying.elapsed().as_millis() as u64;
+                            attempt.error = Some(mismatch.to_string());
+                            current_state.record_attempt(attempt);
+                            fail_update(current_state, &paths.ota_state, status, &version, mismatch.to_string());
+                            return Err(mismatch.into());
+                        }
+                        if let Some(key) = &key {
+                            // Signed over the image's digest, so the download is not read again
+                            if let Err(rejection) = verify_signature(&download.digest, firmware_metadata.signature.as_deref(), key) {
+                                error!(
+                                    device_id = %config.device_id,
+                                    version = %firmware_metadata.version,
+                                    reason = %rejection,
+                                    "Firmware signature verification failed, not installing"
+                                );
+                                audit_log.record(
+                                    AuditSource::Ota,
+                                    "ota_signature_rejected",
+                                    json!(current_state.current_version),
+                                    json!({ "offered_version": firmware_metadata.version, "reason": rejection.to_string() }),
+                                );
+                                discard(&download);
+                                attempt.verify_ms = verifying.elapsed().as_millis() as u64;
+                                attempt.error = Some(rejection.to_string());
+                                current_state.record_attempt(attempt);
+                                fail_update(current_state, &paths.ota_state, status, &version, rejection.to_string());
+                                return Err(rejection.into());
+                            }
+                        }
+                        attempt.verify_ms = verifying.elapsed().as_millis() as u64;
+                        drop(_install);
+                        if hold_apply {
+                            info!(device_id = %config.device_id, %version, "Maintenance mode active, holding verified firmware until the window ends");
+                            let staged = StagedUpdate { metadata: firmware_metadata, download, attempt };
+                            if let Some(superseded) = current_state.staged.replace(staged) {
+                                discard(&superseded.download);
+                            }
+                            current_state.set_status(OtaStatus::Staged { version }, status);
+                            current_state.save_to(&paths.ota_state)?;
+                            return Ok(false);
+                        }
+                        return apply_update(client, config, stats, paths, current_state, audit_log, status, heartbeat, &firmware_metadata, &download, attempt).await;
                     },
                     Err(e) => {
                         error!(device_id = %config.device_id, error = %e, "Failed to download new firmware");
+                        attempt.download_ms = started.elapsed().as_millis() as u64;
+                        attempt.bytes_downloaded = received.0.saturating_sub(resume_offset);
+                        attempt.image_bytes = received.1;
+                        attempt.error = Some(e.to_string());
+                        current_state.record_attempt(attempt);
+                        fail_update(current_state, &paths.ota_state, status, &version, format!("download failed: {}", e));
                     }
                 }
             } else {
@@ -94,5 +946,5 @@ pub async fn check_for_update(client: &Client, config: &Config, current_state: &
         }
     }
 
-    Ok(())
+    Ok(false)
 }
\ No newline at end of file
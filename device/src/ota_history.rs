@@ -0,0 +1,130 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::network::NetworkType;
+
+// Attempts kept in the OTA state
+pub const MAX_ATTEMPTS: usize = 50;
+
+// Days the daily summary covers, today included
+const SUMMARY_DAYS: usize = 7;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AttemptOutcome {
+    DownloadFailed,
+    Rejected, // Checksum or signature did not verify
+    InstallFailed,
+    Installed, // Rebooting into it, or running it on trial
+    Confirmed, // Passed its trial
+    RolledBack,
+}
+
+impl AttemptOutcome {
+    pub fn failed(self) -> bool {
+        matches!(self, AttemptOutcome::DownloadFailed | AttemptOutcome::Rejected | AttemptOutcome::InstallFailed | AttemptOutcome::RolledBack)
+    }
+
+    // Whether the whole image reached the device
+    fn downloaded(self) -> bool {
+        self != AttemptOutcome::DownloadFailed
+    }
+}
+
+/// One attempt at updating to a version, from the start of its download to its outcome.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OtaAttempt {
+    pub version: String,
+    pub from_version: String,
+    pub started_at: DateTime<Utc>,
+    pub image_bytes: Option<u64>, // Size of the full image, once known
+    pub bytes_downloaded: u64, // Transferred by this attempt; less than the image when it resumed a partial one
+    pub resumed: bool,
+    pub retries: u32, // Earlier failed attempts at the same version
+    pub download_ms: u64,
+    pub verify_ms: u64,
+    pub apply_ms: u64,
+    pub network: Option<NetworkType>, // Simulated network in effect
+    pub outcome: AttemptOutcome,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+/// OTA activity over one UTC day.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct DailyOtaSummary {
+    pub day: NaiveDate,
+    pub attempts: u32,
+    pub failed: u32,
+    pub bytes_downloaded: u64,
+    pub download_ms: u64,
+}
+
+/// Earlier failed attempts at `version`, which a new attempt counts as retries.
+pub fn retries(history: &[OtaAttempt], version: &str) -> u32 {
+    history.iter().filter(|attempt| attempt.version == version && attempt.outcome.failed()).count() as u32
+}
+
+/// Appends an attempt, dropping the oldest past MAX_ATTEMPTS.
+pub fn record(history: &mut Vec<OtaAttempt>, attempt: OtaAttempt) {
+    history.push(attempt);
+    if history.len() > MAX_ATTEMPTS {
+        history.drain(..history.len() - MAX_ATTEMPTS);
+    }
+}
+
+/// Settles the latest install of `version` once its trial ends.
+pub fn settle(history: &mut [OtaAttempt], version: &str, outcome: AttemptOutcome) {
+    if let Some(attempt) = history.iter_mut().rev().find(|attempt| attempt.version == version && attempt.outcome == AttemptOutcome::Installed) {
+        attempt.outcome = outcome;
+    }
+}
+
+/// Totals per day for the last SUMMARY_DAYS days up to `now`, oldest first.
+pub fn daily_summary(history: &[OtaAttempt], now: DateTime<Utc>) -> Vec<DailyOtaSummary> {
+    let today = now.date_naive();
+    let mut days: BTreeMap<NaiveDate, DailyOtaSummary> = BTreeMap::new();
+    for attempt in history {
+        let day = attempt.started_at.date_naive();
+        if day > today || (today - day).num_days() >= SUMMARY_DAYS as i64 {
+            continue;
+        }
+        let summary = days.entry(day).or_insert_with(|| DailyOtaSummary { day, ..Default::default() });
+        summary.attempts += 1;
+        summary.failed += attempt.outcome.failed() as u32;
+        summary.bytes_downloaded += attempt.bytes_downloaded;
+        summary.download_ms += attempt.download_ms;
+    }
+    days.into_values().collect()
+}
+
+/// Where a fleet's rollout stands, from each device's running version and OTA history.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct RolloutProgress {
+    pub devices_per_version: BTreeMap<String, usize>,
+    pub median_download_ms: BTreeMap<String, u64>, // Over complete downloads, by target version
+    pub failed_attempts: BTreeMap<String, u32>, // By target version
+}
+
+/// Rollout progress over `devices`, each given as its running version and OTA history.
+pub fn rollout<'a>(devices: impl IntoIterator<Item = (&'a str, &'a [OtaAttempt])>) -> RolloutProgress {
+    let mut progress = RolloutProgress::default();
+    let mut download_ms: BTreeMap<String, Vec<u64>> = BTreeMap::new();
+    for (current_version, history) in devices {
+        *progress.devices_per_version.entry(current_version.to_string()).or_default() += 1;
+        for attempt in history {
+            if attempt.outcome.downloaded() {
+                download_ms.entry(attempt.version.clone()).or_default().push(attempt.download_ms);
+            }
+            if attempt.outcome.failed() {
+                *progress.failed_attempts.entry(attempt.version.clone()).or_default() += 1;
+            }
+        }
+    }
+    progress.median_download_ms = download_ms.into_iter().map(|(version, mut durations)| {
+        durations.sort_unstable();
+        (version, durations[durations.len() / 2])
+    }).collect();
+    progress
+}
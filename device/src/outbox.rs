@@ -0,0 +1,184 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use reqwest::{Client, StatusCode};
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::net::{self, NetError};
+use crate::shadow_report::{self, ShadowRejected, ShadowReportGuard};
+use crate::stats::ApiStats;
+use crate::storage;
+use crate::types::{DesiredState, DeviceEvent, Heartbeat};
+
+// Most device events kept while the backend is unreachable
+pub const MAX_QUEUED_EVENTS: usize = 1_000;
+
+/// Messages kept in SQLite while the backend is unreachable. Each kind holds only its
+/// latest message: a newer heartbeat supersedes an older one, and the reported state
+/// is cumulative, so the last report carries the final reconciliation. Device events
+/// are kept apart, every one of them, since none supersedes another; see queue_event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutboxKind {
+    Heartbeat,
+    ShadowReport,
+}
+
+impl OutboxKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OutboxKind::Heartbeat => "heartbeat",
+            OutboxKind::ShadowReport => "shadow_report",
+        }
+    }
+}
+
+pub fn queue_heartbeat(conn: &Connection, heartbeat: &Heartbeat, now: DateTime<Utc>) -> Result<()> {
+    Ok(storage::queue_outbox(conn, OutboxKind::Heartbeat.as_str(), &json!(heartbeat), now)?)
+}
+
+pub fn queue_shadow_report(conn: &Connection, state: &Value, now: DateTime<Utc>) -> Result<()> {
+    Ok(storage::queue_outbox(conn, OutboxKind::ShadowReport.as_str(), state, now)?)
+}
+
+/// Keeps a device event that could not be sent, for the next flush. Up to
+/// MAX_QUEUED_EVENTS wait; past that the oldest are dropped.
+pub fn queue_event(conn: &Connection, event: &DeviceEvent, now: DateTime<Utc>) -> Result<()> {
+    let dropped = storage::queue_event(conn, &json!(event), now, MAX_QUEUED_EVENTS)?;
+    if dropped > 0 {
+        warn!(dropped, "Event outbox full; dropped the oldest queued events");
+    }
+    Ok(())
+}
+
+/// Drops a waiting message that one sent since has made stale.
+pub fn clear(conn: &Connection, kind: OutboxKind) -> Result<()> {
+    Ok(storage::clear_outbox(conn, kind.as_str())?)
+}
+
+/// What the outbox held when a flush began, read out first so that nothing borrows the
+/// database while it is sent.
+#[derive(Debug, Default)]
+pub struct Pending {
+    heartbeat: Option<(Value, DateTime<Utc>)>,
+    shadow_report: Option<(Value, DateTime<Utc>)>,
+    events: Vec<(i64, Value, DateTime<Utc>)>,
+}
+
+/// How a flush went: the kinds to drop from the outbox, the desired state that answered
+/// a flushed heartbeat, and the error that stopped it.
+#[derive(Debug)]
+pub struct Flushed {
+    sent: usize,
+    done: Vec<OutboxKind>,
+    done_events: Vec<i64>,
+    desired_state: Option<DesiredState>,
+    error: Option<anyhow::Error>,
+}
+
+pub fn pending(conn: &Connection) -> Result<Pending> {
+    Ok(Pending {
+        heartbeat: storage::outbox_entry(conn, OutboxKind::Heartbeat.as_str())?,
+        shadow_report: storage::outbox_entry(conn, OutboxKind::ShadowReport.as_str())?,
+        events: storage::queued_events(conn)?,
+    })
+}
+
+impl Pending {
+    pub fn is_empty(&self) -> bool {
+        self.heartbeat.is_none() && self.shadow_report.is_none() && self.events.is_empty()
+    }
+
+    /// Sends whatever is waiting, events last and oldest first. Sent messages are done;
+    /// one that fails stays for the next flush, except a shadow report or event the
+    /// backend refused, which cannot succeed as is. The desired state answering a flushed heartbeat is kept in Flushed, to be
+    /// applied like a live heartbeat's.
+    pub async fn send(self, client: &Client, config: &Config, stats: &ApiStats, guard: &mut ShadowReportGuard) -> Flushed {
+        let mut flushed = Flushed { sent: 0, done: Vec::new(), done_events: Vec::new(), desired_state: None, error: None };
+        if let Some((payload, queued_at)) = self.heartbeat {
+            match serde_json::from_value::<Heartbeat>(payload) {
+                Ok(heartbeat) => match net::send_heartbeat(client, config, stats, &heartbeat).await {
+                    Ok(desired_state) => {
+                        info!(device_id = %config.device_id, %queued_at, "Flushed heartbeat queued while offline");
+                        flushed.sent += 1;
+                        flushed.desired_state = Some(desired_state);
+                    }
+                    Err(e) => {
+                        flushed.error = Some(e);
+                        return flushed;
+                    }
+                },
+                Err(e) => warn!(device_id = %config.device_id, error = %e, "Dropping unreadable queued heartbeat"),
+            }
+            flushed.done.push(OutboxKind::Heartbeat);
+        }
+        if let Some((state, queued_at)) = self.shadow_report {
+            match shadow_report::report(client, config, stats, guard, &state).await {
+                Ok(()) => {
+                    info!(device_id = %config.device_id, %queued_at, "Flushed shadow report queued while offline");
+                    flushed.sent += 1;
+                }
+                Err(e) if e.downcast_ref::<ShadowRejected>().is_some() => {
+                    warn!(device_id = %config.device_id, error = %format!("{:#}", e), "Dropping queued shadow report the backend refused");
+                }
+                Err(e) => {
+                    flushed.error = Some(e);
+                    return flushed;
+                }
+            }
+            flushed.done.push(OutboxKind::ShadowReport);
+        }
+        for (id, payload, queued_at) in self.events {
+            match serde_json::from_value::<DeviceEvent>(payload) {
+                Ok(event) => match net::send_event(client, config, stats, &event).await {
+                    Ok(()) => {
+                        info!(device_id = %config.device_id, %queued_at, ?event, "Flushed device event queued while offline");
+                        flushed.sent += 1;
+                    }
+                    Err(e) if refused(&e) => {
+                        warn!(device_id = %config.device_id, error = %format!("{:#}", e), ?event, "Dropping queued device event the backend refused");
+                    }
+                    Err(e) => {
+                        flushed.error = Some(e);
+                        return flushed;
+                    }
+                },
+                Err(e) => warn!(device_id = %config.device_id, error = %e, "Dropping unreadable queued device event"),
+            }
+            flushed.done_events.push(id);
+        }
+        flushed
+    }
+}
+
+// The backend found the event itself wrong; sending it again will not help. Anything
+// else, auth or a missing endpoint included, may pass later
+fn refused(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<NetError>() {
+        Some(NetError::Status { status, .. }) => matches!(*status, StatusCode::BAD_REQUEST | StatusCode::PAYLOAD_TOO_LARGE | StatusCode::UNPROCESSABLE_ENTITY),
+        _ => false,
+    }
+}
+
+impl Flushed {
+    /// What the backend answered a flushed heartbeat with, if one went out.
+    pub fn take_desired_state(&mut self) -> Option<DesiredState> {
+        self.desired_state.take()
+    }
+
+    /// Drops what was sent from the outbox. Returns the number sent, or the error that
+    /// stopped the flush.
+    pub fn settle(self, conn: &Connection) -> Result<usize> {
+        for kind in self.done {
+            clear(conn, kind)?;
+        }
+        for id in self.done_events {
+            storage::remove_event(conn, id)?;
+        }
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(self.sent),
+        }
+    }
+}
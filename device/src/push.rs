@@ -0,0 +1,182 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::{dry_run, network};
+
+/// Application-level keepalive on the push channel. Carrier NAT drops idle mappings
+/// without telling either end, so a ping now and then is the only way to notice.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct KeepaliveConfig {
+    #[serde(default = "default_ping_interval_secs")]
+    pub ping_interval_secs: u64, // Idle time before a ping is sent; 0 turns keepalive off
+    #[serde(default = "default_pong_timeout_secs")]
+    pub pong_timeout_secs: u64, // Missing pong after this long marks the connection dead
+}
+
+fn default_ping_interval_secs() -> u64 {
+    30
+}
+
+fn default_pong_timeout_secs() -> u64 {
+    10
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig { ping_interval_secs: default_ping_interval_secs(), pong_timeout_secs: default_pong_timeout_secs() }
+    }
+}
+
+/// NAT idle timeout in effect: the `nat_idle_timeout_secs` chaos flag, else the
+/// current network profile's. None when idle connections are never dropped.
+pub fn nat_idle_timeout(config: &Config) -> Option<u64> {
+    config.chaos_flags.as_ref()
+        .and_then(|chaos| chaos.get("nat_idle_timeout_secs"))
+        .and_then(Value::as_u64)
+        .or_else(|| network::active_profile(config).and_then(|profile| profile.nat_idle_timeout_secs))
+}
+
+/// What the keepalive wants done on this tick.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepaliveAction {
+    Idle,
+    Ping,
+    Reconnect, // A pong was missed; reconnect and hand off from the anchored version
+}
+
+/// Version of a desired document: its own `version` field when the backend sets one,
+/// otherwise a digest of the document.
+pub fn desired_version(desired: &Value) -> String {
+    match desired.get("version") {
+        Some(Value::String(version)) => version.clone(),
+        Some(Value::Number(version)) => version.to_string(),
+        _ => Sha256::digest(desired.to_string().as_bytes()).iter().take(8).map(|byte| format!("{:02x}", byte)).collect(),
+    }
+}
+
+/// Simulated long-lived push connection for desired-state changes. Times are seconds
+/// since the device started. Behind NAT with `nat_idle_timeout_secs`, an idle connection
+/// dies silently: deliveries stop arriving but nothing errors, and only a missed pong
+/// reveals it.
+#[derive(Debug)]
+pub struct PushChannel {
+    keepalive: KeepaliveConfig,
+    nat_idle_timeout_secs: Option<u64>,
+    last_activity: u64,
+    nat_dropped: bool,
+    ping_sent_at: Option<u64>, // Outstanding ping over a dead connection
+    delivered: Option<Value>, // Last desired document that reached the device
+    anchor: Option<String>, // Version of `delivered`, the handoff point on reconnect
+    handoff_pending: bool,
+    lost_version: Option<String>,
+    lost_deliveries: u64,
+    missed_pongs: u64,
+    reconnects: u64,
+}
+
+impl PushChannel {
+    pub fn new(keepalive: KeepaliveConfig, nat_idle_timeout_secs: Option<u64>) -> Self {
+        PushChannel {
+            keepalive,
+            nat_idle_timeout_secs,
+            last_activity: 0,
+            nat_dropped: false,
+            ping_sent_at: None,
+            delivered: None,
+            anchor: None,
+            handoff_pending: true, // The first connect fetches the full state
+            lost_version: None,
+            lost_deliveries: 0,
+            missed_pongs: 0,
+            reconnects: 0,
+        }
+    }
+
+    pub fn set_nat_idle_timeout(&mut self, nat_idle_timeout_secs: Option<u64>) {
+        self.nat_idle_timeout_secs = nat_idle_timeout_secs;
+    }
+
+    // Latches the NAT drop once the connection has been idle past the timeout
+    fn expire(&mut self, now: u64) {
+        if let Some(timeout) = self.nat_idle_timeout_secs {
+            if now.saturating_sub(self.last_activity) >= timeout {
+                self.nat_dropped = true;
+            }
+        }
+    }
+
+    /// Offers the backend's current desired document at `now` and returns the one the
+    /// device holds afterwards. A new version only gets through on a live connection or
+    /// during a handoff; over a NAT-dropped connection it is lost without an error.
+    pub fn receive(&mut self, now: u64, desired: Value) -> Option<Value> {
+        let mut version = desired_version(&desired);
+        // The dry run of a version and its real apply are separate deliveries
+        if dry_run::requested(&desired) {
+            version.push_str("+dry_run");
+        }
+        self.expire(now);
+        if self.handoff_pending {
+            self.handoff_pending = false;
+            self.last_activity = now;
+        } else if self.anchor.as_deref() == Some(version.as_str()) {
+            return self.delivered.clone();
+        } else if self.nat_dropped {
+            // Counted for the report only; the device itself sees nothing
+            if self.lost_version.as_deref() != Some(version.as_str()) {
+                self.lost_deliveries += 1;
+                self.lost_version = Some(version);
+            }
+            return self.delivered.clone();
+        } else {
+            self.last_activity = now;
+        }
+        self.anchor = Some(version);
+        self.delivered = Some(desired);
+        self.delivered.clone()
+    }
+
+    /// Runs the keepalive. On a live connection a ping is answered straight away and
+    /// refreshes the NAT mapping; on a dropped one the pong never comes.
+    pub fn poll(&mut self, now: u64) -> KeepaliveAction {
+        if let Some(sent_at) = self.ping_sent_at {
+            if now.saturating_sub(sent_at) >= self.keepalive.pong_timeout_secs {
+                self.missed_pongs += 1;
+                return KeepaliveAction::Reconnect;
+            }
+            return KeepaliveAction::Idle;
+        }
+        if self.keepalive.ping_interval_secs == 0 || now.saturating_sub(self.last_activity) < self.keepalive.ping_interval_secs {
+            return KeepaliveAction::Idle;
+        }
+        self.expire(now);
+        if self.nat_dropped {
+            self.ping_sent_at = Some(now);
+        } else {
+            self.last_activity = now;
+        }
+        KeepaliveAction::Ping
+    }
+
+    /// Opens a fresh connection. The next `receive` is the handoff: anything newer
+    /// than the anchored version is applied even though its push was lost.
+    pub fn reconnect(&mut self, now: u64) {
+        self.reconnects += 1;
+        self.last_activity = now;
+        self.nat_dropped = false;
+        self.ping_sent_at = None;
+        self.handoff_pending = true;
+    }
+
+    pub fn report(&self) -> Value {
+        json!({
+            "anchor": self.anchor,
+            "nat_idle_timeout_secs": self.nat_idle_timeout_secs,
+            "ping_interval_secs": self.keepalive.ping_interval_secs,
+            "lost_deliveries": self.lost_deliveries,
+            "missed_pongs": self.missed_pongs,
+            "reconnects": self.reconnects,
+        })
+    }
+}
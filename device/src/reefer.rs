@@ -0,0 +1,403 @@
+use chrono::{DateTime, Duration, NaiveDate, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::f64::consts::TAU;
+
+// Longest step the thermal model takes at once; the thermostat, door and engine only
+// change state between steps
+const STEP_SECS: f64 = 10.0;
+// Hour of the day, UTC, at which the outside air is warmest
+const WARMEST_HOUR: f64 = 15.0;
+// Days of cold-chain summaries kept for the reported shadow
+const SUMMARY_DAYS: usize = 7;
+
+/// A delivery stop, `distance_m` into the trip, where the truck stands for `dwell_secs`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DeliveryStop {
+    pub distance_m: f64,
+    pub dwell_secs: u64,
+}
+
+/// The refrigerated trailer of the reefer_trailer profile, the `reefer` config section:
+/// how its cargo warms and cools, and the stops of its trip. At each stop the door
+/// opens on arrival and the engine, which powers the reefer unit, idles for a while and
+/// is then switched off until the truck leaves.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReeferConfig {
+    pub setpoint_c: f32, // Cargo temperature the reefer holds
+    pub hysteresis_c: f32, // The unit starts this far above the setpoint and stops as far below
+    pub supply_c: f32, // Air the running unit blows into the trailer
+    pub min_c: f32, // Cold-chain band; cargo outside it is a breach
+    pub max_c: f32,
+    pub ambient_mean_c: f32, // Outside air over the day
+    pub ambient_swing_c: f32, // Half the day's range, warmest at 15:00 UTC
+    pub cooling_tau_secs: f64, // Time constant of the pull towards supply_c while cooling
+    pub insulation_tau_secs: f64, // Time constant of the drift towards ambient with the door shut
+    pub door_tau_secs: f64, // Time constant of the drift towards ambient with the door open
+    pub door_open_secs: u64, // Door left open on arriving at a stop
+    pub engine_idle_secs: u64, // Engine left running on arriving at a stop
+    pub cruise_kmh: f32, // Speed between stops
+    pub stops: Vec<DeliveryStop>, // In trip order
+}
+
+impl Default for ReeferConfig {
+    fn default() -> Self {
+        ReeferConfig {
+            setpoint_c: 4.0,
+            hysteresis_c: 1.0,
+            supply_c: -2.0,
+            min_c: 2.0,
+            max_c: 8.0,
+            ambient_mean_c: 20.0,
+            ambient_swing_c: 6.0,
+            cooling_tau_secs: 900.0,
+            insulation_tau_secs: 7200.0,
+            door_tau_secs: 900.0,
+            door_open_secs: 300,
+            engine_idle_secs: 600,
+            cruise_kmh: 60.0,
+            stops: Vec::new(),
+        }
+    }
+}
+
+impl ReeferConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_c >= self.max_c {
+            return Err(format!("min_c must be below max_c ({} >= {})", self.min_c, self.max_c));
+        }
+        if !(self.min_c..=self.max_c).contains(&self.setpoint_c) {
+            return Err(format!("setpoint_c {} is outside the band {} to {}", self.setpoint_c, self.min_c, self.max_c));
+        }
+        if self.supply_c >= self.setpoint_c - self.hysteresis_c {
+            return Err("supply_c must be below the setpoint less the hysteresis, or the unit never stops".to_string());
+        }
+        if self.hysteresis_c < 0.0 || self.ambient_swing_c < 0.0 {
+            return Err("hysteresis_c and ambient_swing_c must not be negative".to_string());
+        }
+        if [self.cooling_tau_secs, self.insulation_tau_secs, self.door_tau_secs].iter().any(|tau| *tau <= 0.0) {
+            return Err("time constants must be positive".to_string());
+        }
+        if self.cruise_kmh <= 0.0 {
+            return Err("cruise_kmh must be positive".to_string());
+        }
+        if self.stops.iter().any(|stop| stop.distance_m < 0.0) || self.stops.windows(2).any(|pair| pair[0].distance_m >= pair[1].distance_m) {
+            return Err("stops must be at increasing, non-negative distances".to_string());
+        }
+        Ok(())
+    }
+
+    /// Outside air at `at`, following the time of day.
+    pub fn ambient_at(&self, at: DateTime<Utc>) -> f32 {
+        let hour = at.num_seconds_from_midnight() as f64 / 3600.0;
+        self.ambient_mean_c + self.ambient_swing_c * (TAU * (hour - WARMEST_HOUR) / 24.0).cos() as f32
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnitState {
+    Cooling,
+    Idle, // Powered, with the cargo cold enough
+    Unpowered, // Engine off
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BreachKind {
+    High,
+    Low,
+}
+
+impl BreachKind {
+    /// Device flag carried by samples taken during the breach.
+    pub fn flag(self) -> &'static str {
+        match self {
+            BreachKind::High => "cargo_temp_high",
+            BreachKind::Low => "cargo_temp_low",
+        }
+    }
+}
+
+/// A spell of cargo temperature outside the cold-chain band, sent as an event when it
+/// starts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ColdChainBreach {
+    pub kind: BreachKind,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub peak_c: f32, // Furthest outside the band so far
+    pub limit_c: f32, // The bound that was crossed
+}
+
+/// The cold chain over one UTC day.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DailyColdChain {
+    pub day: NaiveDate,
+    pub min_c: f32,
+    pub max_c: f32,
+    pub breaches: u32, // Started that day
+    pub breach_secs: u64,
+    pub cooling_secs: u64, // Reefer unit running, for its duty cycle
+    pub door_openings: u32,
+}
+
+/// What the truck is doing at a moment of its trip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VehicleState {
+    pub engine_on: bool,
+    pub door_open: bool,
+}
+
+/// The trailer as one sample sees it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReeferSample {
+    pub cargo_c: f32,
+    pub ambient_c: f32,
+    pub unit: UnitState,
+    pub vehicle: VehicleState,
+    pub breach: Option<BreachKind>,
+}
+
+impl ReeferSample {
+    /// Fields added to the sample's extra map.
+    pub fn fields(&self) -> BTreeMap<String, Value> {
+        BTreeMap::from([
+            ("reefer".to_string(), json!(self.unit)),
+            ("door".to_string(), json!(if self.vehicle.door_open { "open" } else { "closed" })),
+            ("engine_on".to_string(), json!(self.vehicle.engine_on)),
+            ("ambient_c".to_string(), json!(self.ambient_c)),
+        ])
+    }
+}
+
+/// Everything the model changes as it runs, for fleet snapshots.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReeferState {
+    pub cargo_c: f32,
+    pub cooling: bool, // The thermostat's call, whether or not the unit has power
+    pub updated_at: DateTime<Utc>,
+    pub odometer_m: f64,
+    pub next_stop: usize, // Index into the configured stops
+    pub arrived_at: Option<DateTime<Utc>>, // Standing at next_stop since
+    pub breach: Option<ColdChainBreach>, // Ongoing
+    pub last_breach: Option<ColdChainBreach>,
+    pub days: Vec<DailyColdChain>, // Oldest first
+}
+
+/// A reefer trailer's cargo temperature, moved forward by the samples of the device
+/// riding in it, and the trip that drives it.
+#[derive(Debug, Clone)]
+pub struct Reefer {
+    config: ReeferConfig,
+    state: ReeferState,
+    started: Vec<ColdChainBreach>, // Breaches not yet alerted on
+}
+
+impl Reefer {
+    /// Cargo loaded at the setpoint at `now`, at the start of the trip.
+    pub fn new(config: ReeferConfig, now: DateTime<Utc>) -> Self {
+        let state = ReeferState {
+            cargo_c: config.setpoint_c,
+            cooling: false,
+            updated_at: now,
+            odometer_m: 0.0,
+            next_stop: 0,
+            arrived_at: None,
+            breach: None,
+            last_breach: None,
+            days: Vec::new(),
+        };
+        Reefer { config, state, started: Vec::new() }
+    }
+
+    pub fn config(&self) -> &ReeferConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> &ReeferState {
+        &self.state
+    }
+
+    /// Continues from a snapshot taken `shift` ago.
+    pub fn restore(&mut self, state: &ReeferState, shift: Duration) {
+        self.state = state.clone();
+        self.state.updated_at += shift;
+        self.state.arrived_at = state.arrived_at.map(|at| at + shift);
+        if let Some(breach) = self.state.breach.as_mut() {
+            breach.started_at += shift;
+        }
+    }
+
+    /// Whether the truck is standing at a stop.
+    pub fn stopped(&self) -> bool {
+        self.state.arrived_at.is_some()
+    }
+
+    /// The truck's state at `at`, from where the trip stands.
+    pub fn vehicle_at(&self, at: DateTime<Utc>) -> VehicleState {
+        let (Some(arrived), Some(stop)) = (self.state.arrived_at, self.config.stops.get(self.state.next_stop)) else {
+            return VehicleState { engine_on: true, door_open: false };
+        };
+        let since = (at - arrived).num_milliseconds() as f64 / 1000.0;
+        if since >= stop.dwell_secs as f64 {
+            return VehicleState { engine_on: true, door_open: false };
+        }
+        VehicleState { engine_on: since < self.config.engine_idle_secs as f64, door_open: since < self.config.door_open_secs as f64 }
+    }
+
+    /// Drives on for `elapsed_secs` at cruising speed, up to `now`, and returns the
+    /// meters covered. The truck halts at the next stop on reaching it and leaves once
+    /// its dwell is over. Call after advance, so the time before `now` is modelled with
+    /// the trip as it was.
+    pub fn drive(&mut self, elapsed_secs: f64, now: DateTime<Utc>) -> f64 {
+        if let Some(arrived) = self.state.arrived_at {
+            let dwell = self.config.stops.get(self.state.next_stop).map_or(0, |stop| stop.dwell_secs);
+            if now < arrived + Duration::seconds(dwell as i64) {
+                return 0.0;
+            }
+            self.state.arrived_at = None;
+            self.state.next_stop += 1;
+        }
+        let meters = self.config.cruise_kmh as f64 / 3.6 * elapsed_secs;
+        match self.config.stops.get(self.state.next_stop) {
+            Some(stop) if self.state.odometer_m + meters >= stop.distance_m => {
+                let driven = (stop.distance_m - self.state.odometer_m).max(0.0);
+                self.state.odometer_m = stop.distance_m;
+                self.state.arrived_at = Some(now);
+                if stop.dwell_secs > 0 && self.config.door_open_secs > 0 {
+                    self.day(now).door_openings += 1;
+                }
+                driven
+            }
+            _ => {
+                self.state.odometer_m += meters;
+                meters
+            }
+        }
+    }
+
+    /// Moves the cargo temperature forward to `now` and returns it. Samples older than
+    /// the last update read the current temperature.
+    pub fn advance(&mut self, now: DateTime<Utc>) -> f32 {
+        loop {
+            let remaining = (now - self.state.updated_at).num_milliseconds() as f64 / 1000.0;
+            if remaining <= 0.0 {
+                break;
+            }
+            self.step(remaining.min(STEP_SECS));
+        }
+        self.state.cargo_c
+    }
+
+    /// The trailer at `now`, which advance has reached.
+    pub fn sample(&self, now: DateTime<Utc>) -> ReeferSample {
+        let vehicle = self.vehicle_at(now);
+        ReeferSample {
+            cargo_c: self.state.cargo_c,
+            ambient_c: self.config.ambient_at(now),
+            unit: self.unit(vehicle),
+            vehicle,
+            breach: self.state.breach.as_ref().map(|breach| breach.kind),
+        }
+    }
+
+    /// Breaches that started since the last call, to alert on.
+    pub fn take_breaches(&mut self) -> Vec<ColdChainBreach> {
+        std::mem::take(&mut self.started)
+    }
+
+    /// The cold chain for the reported shadow: where it stands and the last few days.
+    pub fn report(&self) -> Value {
+        json!({
+            "cargo_c": self.state.cargo_c,
+            "unit": self.unit(self.vehicle_at(self.state.updated_at)),
+            "band": {"min_c": self.config.min_c, "max_c": self.config.max_c},
+            "breach": self.state.breach,
+            "last_breach": self.state.last_breach,
+            "daily": self.state.days,
+        })
+    }
+
+    fn unit(&self, vehicle: VehicleState) -> UnitState {
+        match (vehicle.engine_on, self.state.cooling) {
+            (false, _) => UnitState::Unpowered,
+            (true, true) => UnitState::Cooling,
+            (true, false) => UnitState::Idle,
+        }
+    }
+
+    // One step of `secs` from updated_at, with the trip and thermostat as they stand at its start
+    fn step(&mut self, secs: f64) {
+        let at = self.state.updated_at;
+        let vehicle = self.vehicle_at(at);
+        let ambient = self.config.ambient_at(at);
+        let cargo = self.state.cargo_c;
+        if cargo > self.config.setpoint_c + self.config.hysteresis_c {
+            self.state.cooling = true;
+        } else if cargo < self.config.setpoint_c - self.config.hysteresis_c {
+            self.state.cooling = false;
+        }
+        let unit = self.unit(vehicle);
+        let (target, tau) = if vehicle.door_open {
+            (ambient, self.config.door_tau_secs)
+        } else if unit == UnitState::Cooling {
+            (self.config.supply_c, self.config.cooling_tau_secs)
+        } else {
+            (ambient, self.config.insulation_tau_secs)
+        };
+        let cargo = target + (cargo - target) * (-secs / tau).exp() as f32;
+        self.state.cargo_c = cargo;
+        let end = at + Duration::milliseconds((secs * 1000.0) as i64);
+        self.state.updated_at = end;
+
+        let kind = if cargo > self.config.max_c {
+            Some(BreachKind::High)
+        } else if cargo < self.config.min_c {
+            Some(BreachKind::Low)
+        } else {
+            None
+        };
+        let breached = self.state.breach.as_ref().map(|breach| breach.kind);
+        if let Some(breach) = self.state.breach.as_mut().filter(|breach| Some(breach.kind) == kind) {
+            breach.peak_c = if breach.kind == BreachKind::High { breach.peak_c.max(cargo) } else { breach.peak_c.min(cargo) };
+        } else if breached != kind {
+            if let Some(mut ended) = self.state.breach.take() {
+                ended.ended_at = Some(end);
+                self.state.last_breach = Some(ended);
+            }
+            if let Some(kind) = kind {
+                let limit_c = if kind == BreachKind::High { self.config.max_c } else { self.config.min_c };
+                let breach = ColdChainBreach { kind, started_at: end, ended_at: None, peak_c: cargo, limit_c };
+                self.started.push(breach.clone());
+                self.state.breach = Some(breach);
+                self.day(end).breaches += 1;
+            }
+        }
+
+        let breach_secs = if breached.is_some() || kind.is_some() { secs } else { 0.0 };
+        let day = self.day(at);
+        day.min_c = day.min_c.min(cargo);
+        day.max_c = day.max_c.max(cargo);
+        day.breach_secs += breach_secs.round() as u64;
+        if unit == UnitState::Cooling {
+            day.cooling_secs += secs.round() as u64;
+        }
+    }
+
+    // The summary of the UTC day of `at`, started if need be
+    fn day(&mut self, at: DateTime<Utc>) -> &mut DailyColdChain {
+        let date = at.date_naive();
+        if self.state.days.last().is_none_or(|day| day.day != date) {
+            let cargo = self.state.cargo_c;
+            self.state.days.push(DailyColdChain { day: date, min_c: cargo, max_c: cargo, breaches: 0, breach_secs: 0, cooling_secs: 0, door_openings: 0 });
+            if self.state.days.len() > SUMMARY_DAYS {
+                self.state.days.remove(0);
+            }
+        }
+        self.state.days.last_mut().expect("a day was just added")
+    }
+}
@@ -0,0 +1,94 @@
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+
+use crate::types::Measurement;
+
+/// Re-sends the most recent measurements when the device comes back online, so
+/// dashboards on a push-first backend repaint without waiting for gap-fill. Rows are
+/// taken from an in-memory ring, since uploaded rows are deleted from local storage.
+/// Replayed rows keep their timestamp and sequence number, so the backend can drop
+/// the copies it already has.
+#[derive(Debug)]
+pub struct ReconnectReplay {
+    window_secs: u64, // 0 turns replay off
+    max_rows: usize,
+    recent: VecDeque<Measurement>, // Oldest first
+    online: bool,
+    pending: bool, // Came back online but the replay has not been sent yet
+    replays: u64,
+    replayed_rows: u64,
+}
+
+impl ReconnectReplay {
+    pub fn new(window_secs: u64, max_rows: u32) -> Self {
+        ReconnectReplay {
+            window_secs,
+            max_rows: max_rows as usize,
+            recent: VecDeque::new(),
+            online: true,
+            pending: false,
+            replays: 0,
+            replayed_rows: 0,
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.window_secs > 0 && self.max_rows > 0
+    }
+
+    /// Keeps a sampled measurement for a later replay, trimmed to the window and row limit.
+    pub fn remember(&mut self, measurement: &Measurement) {
+        if !self.enabled() {
+            return;
+        }
+        self.recent.push_back(measurement.clone());
+        let cutoff = measurement.timestamp - Duration::seconds(self.window_secs as i64);
+        while self.recent.len() > self.max_rows || self.recent.front().is_some_and(|oldest| oldest.timestamp < cutoff) {
+            self.recent.pop_front();
+        }
+    }
+
+    /// Records the outcome of a backend request. An Offline to Online transition schedules a replay.
+    pub fn observe(&mut self, online: bool) {
+        if online && !self.online && self.enabled() {
+            self.pending = true;
+        }
+        self.online = online;
+    }
+
+    /// A replay goes out ahead of the backlog once the device is back online. While
+    /// offline, the replay itself is the first attempt to reach the backend.
+    pub fn due(&self) -> bool {
+        self.enabled() && (self.pending || !self.online) && !self.recent.is_empty()
+    }
+
+    /// Measurements sampled within the window before `now`, flagged as a replay. They go
+    /// out whole: the backend may be missing the rows a cadence-thinned sample relies on.
+    pub fn batch(&self, now: DateTime<Utc>) -> Vec<Measurement> {
+        let cutoff = now - Duration::seconds(self.window_secs as i64);
+        self.recent.iter()
+            .filter(|measurement| measurement.timestamp >= cutoff)
+            .map(|measurement| Measurement { replay: Some(true), omitted: Vec::new(), ..measurement.clone() })
+            .collect()
+    }
+
+    /// Marks the replay as delivered, which also means the device is online.
+    pub fn delivered(&mut self, rows: usize) {
+        self.online = true;
+        self.pending = false;
+        self.replays += 1;
+        self.replayed_rows += rows as u64;
+    }
+
+    pub fn report(&self) -> Value {
+        json!({
+            "window_secs": self.window_secs,
+            "max_rows": self.max_rows,
+            "online": self.online,
+            "buffered_rows": self.recent.len(),
+            "replays": self.replays,
+            "replayed_rows": self.replayed_rows,
+        })
+    }
+}
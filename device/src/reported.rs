@@ -0,0 +1,397 @@
+use anyhow::{bail, Result};
+use reqwest::Client;
+use serde_json::{json, Map, Value};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::baseline::BaselineFaults;
+use crate::battery::{Battery, BatteryDrain};
+use crate::cadence::CadenceEngine;
+use crate::config::Config;
+use crate::cost::CostModel;
+use crate::degradation::Degradation;
+use crate::export::SubjectExporter;
+use crate::features::Features;
+use crate::geo::GeoBuckets;
+use crate::push::PushChannel;
+use crate::reefer::Reefer;
+use crate::replay::ReconnectReplay;
+use crate::shadow_report::{self, ShadowReportGuard};
+use crate::shed::Shedder;
+use crate::stats::ApiStats;
+use crate::upload::UploadMetrics;
+
+/// Largest reported document the device sends; the lowest priority sections are pruned to fit.
+pub const MAX_DOCUMENT_BYTES: usize = 64 * 1024;
+
+// Unless a section is registered with a limit of its own
+const DEFAULT_SECTION_BYTES: usize = 4 * 1024;
+
+/// A subsystem's part of the reported shadow.
+pub trait SectionProvider {
+    fn section(&self) -> Value;
+
+    /// Whether the section may have changed since it was last published. Providers that
+    /// cannot tell say yes; an unchanged value is still not reported again.
+    fn dirty(&self) -> bool {
+        true
+    }
+}
+
+impl<F: Fn() -> Value> SectionProvider for F {
+    fn section(&self) -> Value {
+        self()
+    }
+}
+
+/// Which sections give way first when the document is over its limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low, // Diagnostics the backend can do without for a while
+    Normal,
+    High, // What operators act on: identity, intervals, connection, updates
+}
+
+#[derive(Debug)]
+struct Section {
+    name: &'static str,
+    priority: Priority,
+    max_bytes: usize,
+    value: Option<Value>, // None until first published
+    version: u64, // Bumped on every change
+    reported: u64, // Latest version the backend acknowledged
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    sections: Vec<Section>, // In registration order
+    restored: Map<String, Value>, // Reported before the restart, for sections yet to register
+}
+
+/// The reported shadow document, composed of named sections each owned by one
+/// subsystem. Sections are registered once at startup; the returned writer is the only
+/// way to change one, so subsystems cannot clobber each other's keys. Each section
+/// carries a version, and only sections changed since the backend last acknowledged them
+/// are reported.
+#[derive(Debug, Clone)]
+pub struct ReportedState {
+    inner: Arc<Mutex<Inner>>,
+    max_bytes: usize,
+}
+
+/// The sections changed since they were last reported, as a patch, and the versions
+/// it carries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pending {
+    pub patch: Value,
+    versions: Vec<(usize, u64)>,
+}
+
+impl ReportedState {
+    /// Seeded with the document persisted before a restart. Restored sections keep
+    /// their values until their owners publish, and are reported once again, since
+    /// the last report before the restart may not have made it.
+    pub fn new(previous: Option<&Value>, max_bytes: usize) -> Self {
+        let restored = previous.and_then(Value::as_object).cloned().unwrap_or_default();
+        ReportedState { inner: Arc::new(Mutex::new(Inner { sections: Vec::new(), restored })), max_bytes }
+    }
+
+    // A writer that panicked mid-update leaves a consistent section; keep going
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Registers the section `name`; `max_bytes` of None takes the default limit.
+    /// Fails if a section of that name exists, since it already has an owner.
+    pub fn register(&self, name: &'static str, priority: Priority, max_bytes: Option<usize>) -> Result<SectionWriter> {
+        let mut inner = self.lock();
+        if inner.sections.iter().any(|section| section.name == name) {
+            bail!("reported shadow section {} is already registered", name);
+        }
+        let value = inner.restored.remove(name);
+        let version = u64::from(value.is_some());
+        inner.sections.push(Section { name, priority, max_bytes: max_bytes.unwrap_or(DEFAULT_SECTION_BYTES), value, version, reported: 0 });
+        Ok(SectionWriter { state: Arc::clone(&self.inner), index: inner.sections.len() - 1 })
+    }
+
+    /// Section values by name, with any pruned to fit the document limit.
+    fn composed(&self, inner: &Inner) -> Vec<Option<Value>> {
+        let mut values: Vec<Option<Value>> = inner.sections.iter().map(|section| section.value.clone()).collect();
+        let mut total: usize = values.iter().flatten().map(byte_len).sum();
+        let mut order: Vec<usize> = (0..values.len()).filter(|&index| values[index].is_some()).collect();
+        // Lowest priority first, and the largest first within a priority
+        order.sort_by_key(|&index| (inner.sections[index].priority, std::cmp::Reverse(values[index].as_ref().map_or(0, byte_len))));
+        for index in order {
+            if total <= self.max_bytes {
+                break;
+            }
+            let bytes = values[index].as_ref().map_or(0, byte_len);
+            let marker = truncated(bytes);
+            if byte_len(&marker) >= bytes {
+                continue;
+            }
+            total = total - bytes + byte_len(&marker);
+            values[index] = Some(marker);
+        }
+        values
+    }
+
+    /// The whole reported document.
+    pub fn document(&self) -> Value {
+        let inner = self.lock();
+        let values = self.composed(&inner);
+        let document: Map<String, Value> = inner.sections.iter().zip(values)
+            .filter_map(|(section, value)| Some((section.name.to_string(), value?)))
+            .collect();
+        Value::Object(document)
+    }
+
+    /// The sections to report, or None if the backend has them all.
+    pub fn pending(&self) -> Option<Pending> {
+        let inner = self.lock();
+        let values = self.composed(&inner);
+        let mut patch = Map::new();
+        let mut versions = Vec::new();
+        for (index, (section, value)) in inner.sections.iter().zip(values).enumerate() {
+            if section.version > section.reported {
+                patch.insert(section.name.to_string(), value.unwrap_or(Value::Null));
+                versions.push((index, section.version));
+            }
+        }
+        if versions.is_empty() {
+            return None;
+        }
+        Some(Pending { patch: Value::Object(patch), versions })
+    }
+
+    /// Records that the backend has `pending`. Sections changed since it was taken stay pending.
+    pub fn mark_reported(&self, pending: &Pending) {
+        let mut inner = self.lock();
+        for &(index, version) in &pending.versions {
+            let section = &mut inner.sections[index];
+            section.reported = section.reported.max(version);
+        }
+    }
+
+    /// Reports the changed sections. Returns false if nothing had changed, so nothing was sent.
+    pub async fn report(&self, client: &Client, config: &Config, stats: &ApiStats, guard: &mut ShadowReportGuard) -> Result<bool> {
+        let Some(pending) = self.pending() else {
+            return Ok(false);
+        };
+        shadow_report::report(client, config, stats, guard, &pending.patch).await?;
+        self.mark_reported(&pending);
+        Ok(true)
+    }
+}
+
+/// The one handle that changes a section. It cannot be cloned; a subsystem that hands
+/// it on gives up its section.
+#[derive(Debug)]
+pub struct SectionWriter {
+    state: Arc<Mutex<Inner>>,
+    index: usize,
+}
+
+impl SectionWriter {
+    /// Sets the section, limited to its size. Returns true if that changed it.
+    pub fn set(&self, value: Value) -> bool {
+        let mut inner = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let section = &mut inner.sections[self.index];
+        let bytes = byte_len(&value);
+        let value = if bytes > section.max_bytes { truncated(bytes) } else { value };
+        if section.value.as_ref() == Some(&value) {
+            return false;
+        }
+        section.value = Some(value);
+        section.version += 1;
+        true
+    }
+
+    /// Sets the section from its provider, if the provider says it may have changed.
+    pub fn publish(&self, provider: &impl SectionProvider) -> bool {
+        provider.dirty() && self.set(provider.section())
+    }
+}
+
+fn byte_len(value: &Value) -> usize {
+    serde_json::to_vec(value).map_or(0, |bytes| bytes.len())
+}
+
+// Stands in for a section over its limit, so the backend knows it exists
+fn truncated(bytes: usize) -> Value {
+    json!({"truncated": true, "bytes": bytes})
+}
+
+/// Every section of the reported shadow, registered at startup.
+#[derive(Debug)]
+pub struct Sections {
+    pub device_name: SectionWriter,
+    pub sample_interval_secs: SectionWriter,
+    pub upload_interval_secs: SectionWriter,
+    pub heartbeat_interval_secs: SectionWriter,
+    pub connection: SectionWriter,
+    pub last_shutdown: SectionWriter,
+    pub ota_status: SectionWriter,
+    pub maintenance: SectionWriter,
+    pub offline: SectionWriter,
+    pub config_txn: SectionWriter,
+    pub dry_run_result: SectionWriter,
+    pub chaos_flags: SectionWriter,
+    pub clock: SectionWriter,
+    pub random_error: SectionWriter,
+    pub schema_rejected_values: SectionWriter,
+    pub external_source_stalled: SectionWriter,
+    pub firmware_behavior: SectionWriter,
+    pub features: SectionWriter,
+    pub shed: SectionWriter,
+    pub cadence: SectionWriter,
+    pub residency: SectionWriter,
+    pub network: SectionWriter,
+    pub battery: SectionWriter,
+    pub power: SectionWriter,
+    pub fault_baseline: SectionWriter,
+    pub sensor_health: SectionWriter,
+    pub cold_chain: SectionWriter,
+    pub api_stats: SectionWriter,
+    pub upload: SectionWriter,
+    pub push: SectionWriter,
+    pub replay: SectionWriter,
+    pub ota_history: SectionWriter,
+    pub subject_export: SectionWriter,
+    pub geo: SectionWriter,
+    pub costs: SectionWriter,
+    pub debug_session: SectionWriter,
+}
+
+impl Sections {
+    pub fn register(state: &ReportedState) -> Result<Self> {
+        use Priority::*;
+        Ok(Sections {
+            device_name: state.register("device_name", High, None)?,
+            sample_interval_secs: state.register("sample_interval_secs", High, None)?,
+            upload_interval_secs: state.register("upload_interval_secs", High, None)?,
+            heartbeat_interval_secs: state.register("heartbeat_interval_secs", High, None)?,
+            connection: state.register("connection", High, None)?,
+            last_shutdown: state.register("last_shutdown", High, None)?,
+            ota_status: state.register("ota_status", High, None)?,
+            maintenance: state.register("maintenance", High, None)?,
+            offline: state.register("offline", High, None)?,
+            config_txn: state.register("config_txn", High, None)?,
+            dry_run_result: state.register("dry_run_result", High, Some(16 * 1024))?,
+            chaos_flags: state.register("chaos_flags", Normal, None)?,
+            clock: state.register("clock", Normal, None)?,
+            random_error: state.register("random_error", Normal, None)?,
+            schema_rejected_values: state.register("schema_rejected_values", Normal, None)?,
+            external_source_stalled: state.register("external_source_stalled", Normal, None)?,
+            firmware_behavior: state.register("firmware_behavior", Normal, None)?,
+            features: state.register("features", Normal, Some(8 * 1024))?,
+            shed: state.register("shed", Normal, None)?,
+            cadence: state.register("cadence", Normal, None)?,
+            residency: state.register("residency", Normal, None)?,
+            network: state.register("network", Normal, None)?,
+            battery: state.register("battery", Normal, None)?,
+            power: state.register("power", Normal, None)?,
+            fault_baseline: state.register("fault_baseline", Normal, None)?,
+            sensor_health: state.register("sensor_health", Normal, None)?,
+            cold_chain: state.register("cold_chain", Normal, Some(8 * 1024))?,
+            api_stats: state.register("api_stats", Low, Some(8 * 1024))?,
+            upload: state.register("upload", Low, None)?,
+            push: state.register("push", Low, None)?,
+            replay: state.register("replay", Low, None)?,
+            ota_history: state.register("ota_history", Low, Some(16 * 1024))?,
+            subject_export: state.register("subject_export", Low, None)?,
+            geo: state.register("geo", Low, Some(8 * 1024))?,
+            costs: state.register("costs", Low, None)?,
+            debug_session: state.register("debug_session", Low, None)?,
+        })
+    }
+}
+
+impl SectionProvider for ApiStats {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
+
+impl SectionProvider for Features {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
+
+impl SectionProvider for Shedder {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
+
+impl SectionProvider for CadenceEngine {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
+
+impl SectionProvider for UploadMetrics {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
+
+impl SectionProvider for PushChannel {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
+
+impl SectionProvider for ReconnectReplay {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
+
+impl SectionProvider for SubjectExporter {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
+
+impl SectionProvider for BatteryDrain {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
+
+impl SectionProvider for Battery {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
+
+impl SectionProvider for BaselineFaults {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
+
+impl SectionProvider for GeoBuckets {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
+
+impl SectionProvider for CostModel {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
+
+impl SectionProvider for Degradation {
+    fn section(&self) -> Value {
+        self.health_report()
+    }
+}
+
+impl SectionProvider for Reefer {
+    fn section(&self) -> Value {
+        self.report()
+    }
+}
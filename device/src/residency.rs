@@ -0,0 +1,70 @@
+use anyhow::Result;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::config::Config;
+use crate::storage;
+
+/// What happens to measurements collected in the old region when the region changes.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RegionDrainPolicy {
+    #[default]
+    FlushToOld, // Pending rows keep their region and are still sent to its endpoint
+    HoldForNew, // Pending rows are re-tagged and sent to the new region's endpoint
+}
+
+/// Where a batch of measurements is sent. Uploads carry both fields so audits can
+/// check that data stayed in its region.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DataTarget {
+    pub region: Option<String>,
+    pub endpoint: String,
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum ResidencyError {
+    #[error("no data endpoint mapped for region {0:?} and strict residency is enabled")]
+    Unmapped(Option<String>),
+}
+
+/// Data endpoint for measurements collected in `region`. Without a region map every
+/// region uses backend_url; with one, unmapped regions fall back to it unless
+/// strict residency is on. Control-plane calls always use backend_url.
+pub fn target_for(config: &Config, region: Option<&str>) -> std::result::Result<DataTarget, ResidencyError> {
+    let mapped = region.and_then(|region| config.region_endpoints.get(region));
+    let endpoint = match mapped {
+        Some(endpoint) => endpoint.clone(),
+        None if config.strict_residency && !config.region_endpoints.is_empty() => {
+            return Err(ResidencyError::Unmapped(region.map(str::to_string)));
+        }
+        None => config.backend_url.clone(),
+    };
+    Ok(DataTarget { region: region.map(str::to_string), endpoint })
+}
+
+/// Switches the device to `new_region`, handling pending rows in `conn`, when given,
+/// per the drain policy. Returns the previous region.
+pub fn change_region(conn: Option<&Connection>, config: &mut Config, new_region: &str) -> Result<Option<String>> {
+    let previous = config.region.replace(new_region.to_string());
+    if let Some(conn) = conn.filter(|_| config.residency_drain == RegionDrainPolicy::HoldForNew) {
+        storage::retag_region(conn, previous.as_deref(), new_region)?;
+    }
+    Ok(previous)
+}
+
+pub fn report(config: &Config) -> Value {
+    let (status, endpoint) = match target_for(config, config.region.as_deref()) {
+        Ok(_) if config.region_endpoints.is_empty() => ("unrestricted", None),
+        Ok(target) => ("ok", Some(target.endpoint)),
+        Err(_) => ("misconfigured", None),
+    };
+    json!({
+        "status": status,
+        "region": config.region,
+        "endpoint": endpoint,
+        "strict": config.strict_residency,
+        "drain_policy": config.residency_drain,
+    })
+}
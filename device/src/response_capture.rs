@@ -0,0 +1,119 @@
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use reqwest::header::{CONTENT_TYPE, RETRY_AFTER};
+use reqwest::Response;
+use serde::{Deserialize, Serialize};
+
+// Failed responses kept for the crash snapshot, newest last
+pub const MAX_CAPTURED: usize = 5;
+
+// Headers a backend may echo its request id in, checked in order
+const CORRELATION_HEADERS: [&str; 2] = ["x-request-id", "x-correlation-id"];
+
+/// Capture of response bodies the backend sends with non-2xx statuses, which would
+/// otherwise be thrown away with the response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CaptureConfig {
+    #[serde(default = "default_limit_bytes")]
+    pub limit_bytes: usize, // Body bytes read and kept; 0 turns capture off
+    #[serde(default = "default_redact_patterns")]
+    pub redact_patterns: Vec<String>, // Regexes whose matches are replaced before a body is logged or kept
+}
+
+fn default_limit_bytes() -> usize {
+    2048
+}
+
+// Credentials assigned in JSON, form or header style: `"auth_token": "…"`, `password=…`
+fn default_redact_patterns() -> Vec<String> {
+    vec![r#"(?i)"?[\w-]*(token|secret|password)[\w-]*"?\s*[:=]\s*"?[^"\s,&}]+"?"#.to_string()]
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        CaptureConfig { limit_bytes: default_limit_bytes(), redact_patterns: default_redact_patterns() }
+    }
+}
+
+/// A non-2xx response as far as it is worth keeping: text bodies truncated and
+/// redacted, anything else only summarized.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CapturedResponse {
+    pub at: DateTime<Utc>,
+    pub endpoint: String,
+    pub status: u16,
+    pub correlation_id: Option<String>, // The backend's request id, to find its side of the failure
+    pub content_type: Option<String>,
+    pub body: String,
+    pub truncated: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<String>, // Raw Retry-After header, as a throttled or unavailable backend sends it
+}
+
+/// Reads up to `limit_bytes` of a failed response's body and captures it.
+pub async fn capture(config: &CaptureConfig, endpoint: &str, mut response: Response) -> CapturedResponse {
+    let status = response.status().as_u16();
+    let correlation_id = CORRELATION_HEADERS.iter()
+        .find_map(|name| response.headers().get(*name))
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let content_type = response.headers().get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).map(str::to_string);
+    let retry_after = response.headers().get(RETRY_AFTER).and_then(|value| value.to_str().ok()).map(str::to_string);
+
+    // A huge body is never read past the limit; the rest is dropped with the connection
+    let mut body = Vec::new();
+    let mut truncated = false;
+    if config.limit_bytes > 0 {
+        while let Ok(Some(chunk)) = response.chunk().await {
+            body.extend_from_slice(&chunk);
+            if body.len() > config.limit_bytes {
+                body.truncate(config.limit_bytes);
+                truncated = true;
+                break;
+            }
+        }
+    }
+
+    let body = summarize(config, content_type.as_deref(), &body, truncated);
+    CapturedResponse { at: Utc::now(), endpoint: endpoint.to_string(), status, correlation_id, content_type, body, truncated, retry_after }
+}
+
+/// Text to keep for a body: redacted text for textual content types, or a summary of
+/// its size and type for binary ones. Without a content type, valid UTF-8 counts as text.
+pub fn summarize(config: &CaptureConfig, content_type: Option<&str>, body: &[u8], truncated: bool) -> String {
+    if body.is_empty() {
+        return String::new();
+    }
+    let size = format!("{}{} bytes", body.len(), if truncated { "+" } else { "" });
+    let text = match content_type {
+        Some(content_type) if !is_text(content_type) => None,
+        // Truncation may have split a character; the cut-off part is dropped
+        _ => match std::str::from_utf8(body) {
+            Ok(text) => Some(text),
+            Err(e) if truncated && e.error_len().is_none() => std::str::from_utf8(&body[..e.valid_up_to()]).ok(),
+            Err(_) => None,
+        },
+    };
+    match text {
+        Some(text) => redact(&config.redact_patterns, text),
+        None => format!("<{} of {}>", size, content_type.unwrap_or("binary data")),
+    }
+}
+
+fn is_text(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("/json")
+        || essence.ends_with("+json")
+        || essence.ends_with("/xml")
+        || essence.ends_with("+xml")
+        || essence == "application/x-www-form-urlencoded"
+}
+
+/// Replaces every match of the patterns with `<redacted>`. Patterns that do not compile
+/// are skipped; config validation reports them.
+pub fn redact(patterns: &[String], text: &str) -> String {
+    patterns.iter()
+        .filter_map(|pattern| Regex::new(pattern).ok())
+        .fold(text.to_string(), |text, pattern| pattern.replace_all(&text, "<redacted>").into_owned())
+}
@@ -0,0 +1,263 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+
+use crate::config::Config;
+
+const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+/// A point on the ground, in degrees.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Waypoint {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl Waypoint {
+    /// Great-circle distance in meters.
+    pub fn distance_to(self, other: Waypoint) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.lon - self.lon).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_M * a.sqrt().asin()
+    }
+
+    /// Initial bearing towards `other`, in degrees clockwise from north.
+    pub fn bearing_to(self, other: Waypoint) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let dlon = (other.lon - self.lon).to_radians();
+        let y = dlon.sin() * lat2.cos();
+        let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * dlon.cos();
+        y.atan2(x).to_degrees().rem_euclid(360.0)
+    }
+}
+
+/// Where a device is along its route and which way it faces.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RoutePoint {
+    pub lat: f64,
+    pub lon: f64,
+    pub heading: f64, // Degrees clockwise from north
+}
+
+/// A polyline read from a GPX track or a GeoJSON LineString.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    waypoints: Vec<Waypoint>,
+    cumulative_m: Vec<f64>, // Distance from the first waypoint to each one
+}
+
+impl Route {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Route::parse(&text).with_context(|| format!("Invalid route in {}", path.display()))
+    }
+
+    /// Parses a GPX document (`<trkpt>` or `<rtept>` elements) or GeoJSON (a LineString,
+    /// or the first one in a Feature or FeatureCollection), told apart by their first character.
+    pub fn parse(text: &str) -> Result<Self> {
+        let waypoints = match text.trim_start().chars().next() {
+            Some('<') => parse_gpx(text)?,
+            Some('{') => parse_geojson(text)?,
+            _ => bail!("expected a GPX or GeoJSON document"),
+        };
+        Route::from_waypoints(waypoints)
+    }
+
+    /// Repeated consecutive waypoints are dropped, as they have no heading.
+    pub fn from_waypoints(mut waypoints: Vec<Waypoint>) -> Result<Self> {
+        if let Some(bad) = waypoints.iter().find(|w| !(-90.0..=90.0).contains(&w.lat) || !(-180.0..=180.0).contains(&w.lon)) {
+            bail!("waypoint {},{} is not a valid latitude and longitude", bad.lat, bad.lon);
+        }
+        waypoints.dedup();
+        if waypoints.len() < 2 {
+            bail!("a route needs at least two distinct waypoints");
+        }
+        let mut cumulative_m = vec![0.0];
+        for pair in waypoints.windows(2) {
+            cumulative_m.push(cumulative_m.last().unwrap() + pair[0].distance_to(pair[1]));
+        }
+        Ok(Route { waypoints, cumulative_m })
+    }
+
+    pub fn waypoints(&self) -> &[Waypoint] {
+        &self.waypoints
+    }
+
+    pub fn length_m(&self) -> f64 {
+        *self.cumulative_m.last().unwrap()
+    }
+
+    /// The same route with a straight leg back to the start, unless it already ends there.
+    pub fn closed(&self) -> Route {
+        let mut waypoints = self.waypoints.clone();
+        waypoints.push(waypoints[0]);
+        Route::from_waypoints(waypoints).expect("closing a valid route keeps it valid")
+    }
+
+    /// The point `distance_m` along the route, clamped to its ends, interpolated between
+    /// the waypoints either side and heading towards the next one.
+    pub fn at(&self, distance_m: f64) -> RoutePoint {
+        let distance_m = distance_m.clamp(0.0, self.length_m());
+        let segment = self.cumulative_m.partition_point(|&start| start <= distance_m).clamp(1, self.waypoints.len() - 1) - 1;
+        let (from, to) = (self.waypoints[segment], self.waypoints[segment + 1]);
+        let fraction = (distance_m - self.cumulative_m[segment]) / (self.cumulative_m[segment + 1] - self.cumulative_m[segment]);
+        RoutePoint {
+            lat: from.lat + (to.lat - from.lat) * fraction,
+            lon: from.lon + (to.lon - from.lon) * fraction,
+            heading: from.bearing_to(to),
+        }
+    }
+}
+
+/// The route a config asks for: its route_file, else its inline route_waypoints. None
+/// when it sets neither.
+pub fn configured(config: &Config) -> Option<Result<Route>> {
+    match &config.route_file {
+        Some(path) => Some(Route::load(Path::new(path))),
+        None if !config.route_waypoints.is_empty() => Some(Route::from_waypoints(config.route_waypoints.clone()).context("Invalid route_waypoints")),
+        None => None,
+    }
+}
+
+fn parse_gpx(text: &str) -> Result<Vec<Waypoint>> {
+    let mut waypoints = Vec::new();
+    for tag in ["<trkpt", "<rtept"] {
+        for (offset, _) in text.match_indices(tag) {
+            let rest = &text[offset + tag.len()..];
+            let element = &rest[..rest.find('>').context("unterminated GPX element")?];
+            let line = text[..offset].matches('\n').count() + 1;
+            let coordinate = |name: &str| -> Result<f64> {
+                let raw = xml_attribute(element, name).with_context(|| format!("line {}: {} without a {} attribute", line, tag, name))?;
+                raw.parse().with_context(|| format!("line {}: {} {:?} is not a number", line, name, raw))
+            };
+            waypoints.push(Waypoint { lat: coordinate("lat")?, lon: coordinate("lon")? });
+        }
+        // A track wins over a planned route in the same file
+        if !waypoints.is_empty() {
+            break;
+        }
+    }
+    Ok(waypoints)
+}
+
+fn xml_attribute<'a>(element: &'a str, name: &str) -> Option<&'a str> {
+    let mut rest = element;
+    while let Some(at) = rest.find(name) {
+        let whole_name = rest[..at].ends_with(char::is_whitespace);
+        rest = &rest[at + name.len()..];
+        let Some(value) = rest.trim_start().strip_prefix('=').map(str::trim_start).filter(|_| whole_name) else {
+            continue;
+        };
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let value = &value[1..];
+        return value.find(quote).map(|end| &value[..end]);
+    }
+    None
+}
+
+fn parse_geojson(text: &str) -> Result<Vec<Waypoint>> {
+    let document: Value = serde_json::from_str(text).context("invalid JSON")?;
+    let line_string = find_line_string(&document).context("no LineString geometry found")?;
+    let coordinates = line_string.get("coordinates").and_then(Value::as_array).context("LineString without coordinates")?;
+    coordinates.iter().enumerate().map(|(i, position)| {
+        // GeoJSON positions are longitude first
+        match position.as_array().map(|pair| (pair.first().and_then(Value::as_f64), pair.get(1).and_then(Value::as_f64))) {
+            Some((Some(lon), Some(lat))) => Ok(Waypoint { lat, lon }),
+            _ => bail!("coordinate {} is not a [longitude, latitude] pair", i),
+        }
+    }).collect()
+}
+
+fn find_line_string(value: &Value) -> Option<&Value> {
+    match value.get("type").and_then(Value::as_str)? {
+        "LineString" => Some(value),
+        "Feature" => find_line_string(value.get("geometry")?),
+        "FeatureCollection" => value.get("features")?.as_array()?.iter().find_map(find_line_string),
+        _ => None,
+    }
+}
+
+/// What a device does on reaching the end of its route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteEnd {
+    Loop, // Starts over, along a closing leg if the route does not end where it starts
+    Reverse, // Turns around
+    Stop, // Parks at the last waypoint
+}
+
+impl RouteEnd {
+    pub fn from_config(config: &Config) -> Self {
+        if config.route_stop_at_end {
+            RouteEnd::Stop
+        } else if config.route_reverse {
+            RouteEnd::Reverse
+        } else {
+            RouteEnd::Loop
+        }
+    }
+}
+
+/// A device travelling a route, and what it does at the end; see RouteEnd.
+#[derive(Debug, Clone)]
+pub struct RouteFollower {
+    route: Route,
+    end: RouteEnd,
+    travelled_m: f64, // Total distance covered, including earlier laps
+}
+
+impl RouteFollower {
+    pub fn new(route: Route, end: RouteEnd) -> Self {
+        let route = if end == RouteEnd::Loop { route.closed() } else { route };
+        RouteFollower { route, end, travelled_m: 0.0 }
+    }
+
+    /// Starts `fraction` of the way along the route, so devices sharing one spread out.
+    /// A route that stops at its end is always driven from the start.
+    pub fn starting_at(mut self, fraction: f64) -> Self {
+        if self.end != RouteEnd::Stop {
+            self.travelled_m = self.route.length_m() * fraction.clamp(0.0, 1.0);
+        }
+        self
+    }
+
+    /// Whether the device has parked at the end of a route that stops there.
+    pub fn finished(&self) -> bool {
+        self.end == RouteEnd::Stop && self.travelled_m >= self.route.length_m()
+    }
+
+    pub fn travelled_m(&self) -> f64 {
+        self.travelled_m
+    }
+
+    pub fn set_travelled_m(&mut self, travelled_m: f64) {
+        self.travelled_m = travelled_m;
+    }
+
+    pub fn position(&self) -> RoutePoint {
+        let length = self.route.length_m();
+        match self.end {
+            RouteEnd::Loop => return self.route.at(self.travelled_m.rem_euclid(length)),
+            RouteEnd::Stop => return self.route.at(self.travelled_m),
+            RouteEnd::Reverse => {}
+        }
+        // Out and back: the second half of each round trip runs the route backwards
+        let along = self.travelled_m.rem_euclid(2.0 * length);
+        if along <= length {
+            return self.route.at(along);
+        }
+        let point = self.route.at(2.0 * length - along);
+        RoutePoint { heading: (point.heading + 180.0).rem_euclid(360.0), ..point }
+    }
+
+    /// Moves `meters` further along and returns the new position.
+    pub fn advance(&mut self, meters: f64) -> RoutePoint {
+        self.travelled_m += meters.max(0.0);
+        if self.end == RouteEnd::Stop {
+            self.travelled_m = self.travelled_m.min(self.route.length_m());
+        }
+        self.position()
+    }
+}
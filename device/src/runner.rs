@@ -0,0 +1,528 @@
+use anyhow::{bail, Context, Result};
+use axum::body::Bytes;
+use axum::extract::{Path, Request, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::Response;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::Utc;
+use futures::future::{self, BoxFuture};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::environment::{EnvironmentEvent, EnvironmentState, Environments};
+use crate::error::DeviceError;
+use crate::fleet::{ControlApi, DevicePaths};
+use crate::ota::OtaState;
+use crate::ota_history::{self, RolloutProgress};
+use crate::shutdown::StopMode;
+use crate::snapshot::{self, Manifest};
+
+/// Runs one fleet member through its reboots until it stops, which it does when asked on
+/// the receiver. Ok means the device went down cleanly, with its state kept.
+pub type Launch = Arc<dyn Fn(DevicePaths, mpsc::Receiver<StopMode>) -> BoxFuture<'static, Result<(), DeviceError>> + Send + Sync>;
+
+/// Paths of the fleet member with an index, for devices added by scaling up.
+pub type MemberPaths = Arc<dyn Fn(usize) -> DevicePaths + Send + Sync>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MemberStatus {
+    Running,
+    Suspended, // Stopped with its state kept; starting it resumes the same device
+    Failed, // Stopped by an error; starting it tries again
+}
+
+/// Which running devices a scale-down stops.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(tag = "by", rename_all = "snake_case")]
+pub enum ScaleDownSelection {
+    #[default]
+    NewestFirst, // Highest fleet index first
+    Region { region: String }, // Only devices configured for the region, newest first
+    Explicit { devices: Vec<String> }, // Device ids or fleet indexes, in this order
+}
+
+/// Body of POST /fleet/scale.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ScaleRequest {
+    pub target: usize, // Running devices wanted
+    #[serde(default)]
+    pub selection: ScaleDownSelection,
+    #[serde(default)]
+    pub mode: StopMode,
+}
+
+/// Body of POST /fleet/devices/{id}/stop; may be left out.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StopRequest {
+    #[serde(default)]
+    pub mode: StopMode,
+}
+
+/// Body of POST /fleet/restore.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RestoreRequest {
+    pub snapshot: String, // Directory name under the snapshot root, as POST /fleet/snapshot gave it
+}
+
+/// Response of POST /fleet/snapshot.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotTaken {
+    pub snapshot: String,
+    pub manifest: Manifest,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MemberSummary {
+    pub index: usize,
+    pub device_id: Option<String>, // None until the device has registered
+    pub region: Option<String>,
+    pub status: MemberStatus,
+    pub error: Option<String>, // Why a failed device stopped
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FleetSummary {
+    pub running: usize,
+    pub suspended: usize,
+    pub failed: usize,
+    pub devices: Vec<MemberSummary>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Identity {
+    device_id: String,
+    region: Option<String>,
+}
+
+struct Member {
+    paths: DevicePaths,
+    status: MemberStatus,
+    identity: Option<Identity>, // Read from the device's config once it has one
+    error: Option<String>,
+    stop: Option<mpsc::Sender<StopMode>>, // While running
+    task: Option<JoinHandle<()>>,
+    suspending: bool, // Asked to stop by the runner, rather than going down with the process
+}
+
+impl Member {
+    // Not started yet
+    fn new(paths: DevicePaths) -> Self {
+        Member { paths, status: MemberStatus::Suspended, identity: None, error: None, stop: None, task: None, suspending: false }
+    }
+
+    fn identity(&mut self) -> Option<&Identity> {
+        if self.identity.is_none() {
+            self.identity = Config::load_from(&self.paths.config).ok().map(|config| Identity { device_id: config.device_id, region: config.region });
+        }
+        self.identity.as_ref()
+    }
+
+    fn summary(&mut self, index: usize) -> MemberSummary {
+        let identity = self.identity().cloned();
+        MemberSummary {
+            index,
+            device_id: identity.as_ref().map(|identity| identity.device_id.clone()),
+            region: identity.and_then(|identity| identity.region),
+            status: self.status,
+            error: self.error.clone(),
+        }
+    }
+}
+
+/// The fleet's devices and what each is doing. A device is stopped through its stop
+/// channel, which lets it drain its backlog and report itself suspended, and keeps its
+/// state directory, so starting it again resumes it with the same identity and backlog.
+/// A suspended device leaves a marker beside its config, so it stays suspended when the
+/// runner is restarted.
+#[derive(Clone)]
+pub struct FleetRunner {
+    members: Arc<Mutex<Vec<Member>>>,
+    member_paths: MemberPaths,
+    launch: Launch,
+    running: Arc<watch::Sender<usize>>,
+    environments: Environments, // Shared by co-located members; events are injected here
+    snapshots: Option<(Arc<snapshot::Controller>, PathBuf)>, // And the directory snapshots go under
+}
+
+impl FleetRunner {
+    /// Starts every one of `members` but those suspended when the runner last stopped.
+    pub fn launch(members: Vec<DevicePaths>, member_paths: MemberPaths, launch: Launch) -> Self {
+        let runner = FleetRunner {
+            members: Arc::new(Mutex::new(Vec::new())),
+            member_paths,
+            launch,
+            running: Arc::new(watch::channel(0).0),
+            environments: Environments::default(),
+            snapshots: None,
+        };
+        let mut joined = runner.members.lock().unwrap();
+        for (index, paths) in members.into_iter().enumerate() {
+            let suspended = paths.suspended.exists();
+            joined.push(Member::new(paths));
+            if suspended {
+                info!(index, "Fleet device stays suspended until started again");
+            } else {
+                runner.start_locked(&mut joined, index);
+            }
+        }
+        drop(joined);
+        runner
+    }
+
+    /// Serves `environments` on the control API. Members get their handle on them when
+    /// launched, not from the runner.
+    pub fn with_environments(mut self, environments: Environments) -> Self {
+        self.environments = environments;
+        self
+    }
+
+    /// Snapshots the fleet through `controller`, which the members' pause ports belong
+    /// to, into directories under `root`.
+    pub fn with_snapshots(mut self, controller: Arc<snapshot::Controller>, root: PathBuf) -> Self {
+        self.snapshots = Some((controller, root));
+        self
+    }
+
+    /// Pauses every running device, snapshots the fleet into a new directory under the
+    /// snapshot root and resumes them.
+    pub async fn snapshot(&self) -> Result<(PathBuf, Manifest)> {
+        let (controller, root) = self.snapshots.as_ref().context("fleet snapshots are not set up")?;
+        controller.take_under(root).await
+    }
+
+    /// Puts the fleet back as the snapshot at `dir` left it: the running devices are
+    /// stopped, every captured device is restored in place and those that were running
+    /// are started again, continuing from the snapshot.
+    pub async fn restore(&self, dir: &std::path::Path) -> Result<Manifest> {
+        let manifest = snapshot::manifest(dir)?;
+        if manifest.devices.iter().any(|entry| entry.index.is_none()) {
+            bail!("snapshot is of a single device, not of a fleet");
+        }
+        let running: Vec<usize> = {
+            let members = self.members.lock().unwrap();
+            (0..members.len()).filter(|&index| members[index].status == MemberStatus::Running).collect()
+        };
+        info!(dir = %dir.display(), devices = manifest.devices.len(), stopping = running.len(), "Restoring the fleet from a snapshot");
+        future::join_all(running.iter().map(|&index| self.stop(index, StopMode::Immediate))).await;
+        let paths: Vec<DevicePaths> = self.members.lock().unwrap().iter().map(|member| member.paths.clone()).collect();
+        let restored = snapshot::restore_devices(dir, &manifest, |index| {
+            let index = index.unwrap_or_default();
+            paths.get(index).cloned().unwrap_or_else(|| (self.member_paths)(index))
+        });
+        // Restored or not, the devices that were running go on running
+        let mut members = self.members.lock().unwrap();
+        for &index in &running {
+            self.start_locked(&mut members, index);
+        }
+        restored?;
+        Ok(manifest)
+    }
+
+    /// The member with this device id, or else this fleet index.
+    pub fn find(&self, id: &str) -> Option<usize> {
+        let mut members = self.members.lock().unwrap();
+        find(&mut members, id)
+    }
+
+    pub fn summary(&self) -> FleetSummary {
+        let mut members = self.members.lock().unwrap();
+        let devices: Vec<MemberSummary> = members.iter_mut().enumerate().map(|(index, member)| member.summary(index)).collect();
+        let count = |status: MemberStatus| devices.iter().filter(|device| device.status == status).count();
+        FleetSummary {
+            running: count(MemberStatus::Running),
+            suspended: count(MemberStatus::Suspended),
+            failed: count(MemberStatus::Failed),
+            devices,
+        }
+    }
+
+    /// Rollout progress across the fleet, from the OTA state each registered device keeps.
+    pub fn rollout(&self) -> RolloutProgress {
+        let paths: Vec<DevicePaths> = self.members.lock().unwrap().iter().map(|member| member.paths.clone()).collect();
+        let states: Vec<OtaState> = paths.into_iter().filter_map(|paths| {
+            let paths = paths.clone().in_data_dir(&Config::load_from(&paths.config).ok()?.data_dir);
+            if !paths.ota_state.exists() {
+                return None;
+            }
+            OtaState::load_from(&paths.ota_state).map_err(|e| warn!(path = %paths.ota_state.display(), error = %e, "Skipping unreadable OTA state")).ok()
+        }).collect();
+        ota_history::rollout(states.iter().map(|state| (state.current_version.as_str(), state.history.as_slice())))
+    }
+
+    /// Starts a suspended or failed device; a running one is left alone.
+    pub fn start(&self, index: usize) -> MemberSummary {
+        let mut members = self.members.lock().unwrap();
+        self.start_locked(&mut members, index);
+        members[index].summary(index)
+    }
+
+    /// Stops a device and waits until it is down.
+    pub async fn stop(&self, index: usize, mode: StopMode) -> MemberSummary {
+        let (stop, task) = {
+            let mut members = self.members.lock().unwrap();
+            let member = &mut members[index];
+            member.suspending = member.stop.is_some();
+            (member.stop.take(), member.task.take())
+        };
+        if let Some(stop) = stop {
+            // A device between boots picks the request up when it starts again
+            let _ = stop.send(mode).await;
+        }
+        if let Some(task) = task {
+            let _ = task.await;
+        }
+        let mut members = self.members.lock().unwrap();
+        members[index].summary(index)
+    }
+
+    /// Starts or stops devices until `target` are running. Suspended and failed devices
+    /// are started again, lowest index first, before new ones join the fleet; a scale-down
+    /// stops the devices `selection` picks first, then the newest of the rest, all at once.
+    pub async fn scale(&self, target: usize, selection: &ScaleDownSelection, mode: StopMode) -> FleetSummary {
+        let stopping = {
+            let mut members = self.members.lock().unwrap();
+            let running: Vec<usize> = (0..members.len()).filter(|&index| members[index].status == MemberStatus::Running).collect();
+            if target >= running.len() {
+                let mut missing = target - running.len();
+                for index in 0..members.len() {
+                    if missing == 0 {
+                        break;
+                    }
+                    if members[index].status != MemberStatus::Running {
+                        self.start_locked(&mut members, index);
+                        missing -= 1;
+                    }
+                }
+                for _ in 0..missing {
+                    let index = members.len();
+                    members.push(Member::new((self.member_paths)(index)));
+                    self.start_locked(&mut members, index);
+                }
+                Vec::new()
+            } else {
+                select(&mut members, &running, running.len() - target, selection)
+            }
+        };
+        info!(target, stopping = stopping.len(), "Scaling the fleet");
+        future::join_all(stopping.into_iter().map(|index| self.stop(index, mode))).await;
+        self.summary()
+    }
+
+    /// Waits until no device is running.
+    pub async fn stopped(&self) {
+        let _ = self.running.subscribe().wait_for(|running| *running == 0).await;
+    }
+
+    fn start_locked(&self, members: &mut [Member], index: usize) {
+        let member = &mut members[index];
+        if member.status == MemberStatus::Running {
+            return;
+        }
+        match std::fs::remove_file(&member.paths.suspended) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => warn!(index, error = %e, "Failed to clear the fleet device's suspended marker; it stays suspended after a restart"),
+            _ => {}
+        }
+        let (stop, stop_requests) = mpsc::channel(1);
+        let device = (self.launch)(member.paths.clone(), stop_requests);
+        let runner = self.clone();
+        member.task = Some(tokio::spawn(async move {
+            let stopped = device.await;
+            runner.finished(index, stopped);
+        }));
+        member.stop = Some(stop);
+        member.status = MemberStatus::Running;
+        member.error = None;
+        self.count_running(members);
+    }
+
+    fn finished(&self, index: usize, stopped: Result<(), DeviceError>) {
+        let mut members = self.members.lock().unwrap();
+        let member = &mut members[index];
+        member.stop = None;
+        // Registering or roaming while it ran may have changed it
+        member.identity = None;
+        let suspending = std::mem::take(&mut member.suspending);
+        match stopped {
+            Ok(()) => {
+                info!(index, "Fleet device stopped; its state is kept for a restart");
+                member.status = MemberStatus::Suspended;
+                if suspending {
+                    if let Err(e) = std::fs::write(&member.paths.suspended, Utc::now().to_rfc3339()) {
+                        warn!(index, error = %e, "Failed to mark the fleet device suspended; it starts again with the runner");
+                    }
+                }
+            }
+            Err(e) => {
+                error!(index, kind = e.kind(), error = %e.report(), "Fleet device failed");
+                member.status = MemberStatus::Failed;
+                member.error = Some(e.report());
+            }
+        }
+        self.count_running(&members);
+    }
+
+    fn count_running(&self, members: &[Member]) {
+        self.running.send_replace(members.iter().filter(|member| member.status == MemberStatus::Running).count());
+    }
+}
+
+fn find(members: &mut [Member], id: &str) -> Option<usize> {
+    members.iter_mut()
+        .position(|member| member.identity().is_some_and(|identity| identity.device_id == id))
+        .or_else(|| id.parse::<usize>().ok().filter(|&index| index < members.len()))
+}
+
+// The running devices a scale-down by `excess` stops
+fn select(members: &mut [Member], running: &[usize], excess: usize, selection: &ScaleDownSelection) -> Vec<usize> {
+    let candidates: Vec<usize> = match selection {
+        ScaleDownSelection::NewestFirst => running.iter().rev().copied().collect(),
+        ScaleDownSelection::Region { region } => running.iter().rev().copied()
+            .filter(|&index| members[index].identity().is_some_and(|identity| identity.region.as_ref() == Some(region)))
+            .collect(),
+        ScaleDownSelection::Explicit { devices } => devices.iter()
+            .filter_map(|id| find(members, id))
+            .filter(|index| running.contains(index))
+            .collect(),
+    };
+    // The newest devices the selection left out make up the rest, so the target is reached
+    let candidates = candidates.into_iter().chain(running.iter().rev().copied());
+    let mut chosen = Vec::new();
+    for index in candidates {
+        if chosen.len() == excess {
+            break;
+        }
+        if !chosen.contains(&index) {
+            chosen.push(index);
+        }
+    }
+    chosen
+}
+
+type ApiError = (StatusCode, String);
+
+// An absent body stands for the request's defaults
+fn body<T: DeserializeOwned + Default>(raw: &[u8]) -> Result<T, ApiError> {
+    if raw.iter().all(u8::is_ascii_whitespace) {
+        return Ok(T::default());
+    }
+    serde_json::from_slice(raw).map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+fn member(runner: &FleetRunner, id: &str) -> Result<usize, ApiError> {
+    runner.find(id).ok_or_else(|| (StatusCode::NOT_FOUND, format!("no fleet device {}", id)))
+}
+
+/// The control API; with a `token`, every request has to bring it as a bearer token.
+pub fn router(runner: FleetRunner, token: Option<String>) -> Router {
+    let router = Router::new()
+        .route("/fleet", get(summary))
+        .route("/fleet/rollout", get(rollout))
+        .route("/fleet/scale", post(scale))
+        .route("/fleet/devices/:id/stop", post(stop))
+        .route("/fleet/devices/:id/start", post(start))
+        .route("/fleet/snapshot", post(take_snapshot))
+        .route("/fleet/restore", post(restore))
+        .route("/fleet/environments", get(environments))
+        .route("/fleet/environments/:name/events", post(inject))
+        .with_state(runner);
+    match token {
+        Some(token) => router.route_layer(middleware::from_fn_with_state(Arc::<str>::from(token), authorize)),
+        None => router,
+    }
+}
+
+async fn authorize(State(token): State<Arc<str>>, headers: HeaderMap, request: Request, next: Next) -> Result<Response, ApiError> {
+    let presented = headers.get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if presented != Some(&*token) {
+        return Err((StatusCode::UNAUTHORIZED, "missing or wrong fleet control token".to_string()));
+    }
+    Ok(next.run(request).await)
+}
+
+async fn summary(State(runner): State<FleetRunner>) -> Json<FleetSummary> {
+    Json(runner.summary())
+}
+
+async fn rollout(State(runner): State<FleetRunner>) -> Json<RolloutProgress> {
+    Json(runner.rollout())
+}
+
+async fn scale(State(runner): State<FleetRunner>, Json(request): Json<ScaleRequest>) -> Json<FleetSummary> {
+    Json(runner.scale(request.target, &request.selection, request.mode).await)
+}
+
+async fn stop(State(runner): State<FleetRunner>, Path(id): Path<String>, raw: Bytes) -> Result<Json<MemberSummary>, ApiError> {
+    let index = member(&runner, &id)?;
+    let request: StopRequest = body(&raw)?;
+    Ok(Json(runner.stop(index, request.mode).await))
+}
+
+async fn start(State(runner): State<FleetRunner>, Path(id): Path<String>) -> Result<Json<MemberSummary>, ApiError> {
+    let index = member(&runner, &id)?;
+    Ok(Json(runner.start(index)))
+}
+
+async fn take_snapshot(State(runner): State<FleetRunner>) -> Result<Json<SnapshotTaken>, ApiError> {
+    let (dir, manifest) = runner.snapshot().await.map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+    let snapshot = dir.file_name().unwrap_or_default().to_string_lossy().into_owned();
+    Ok(Json(SnapshotTaken { snapshot, manifest }))
+}
+
+async fn restore(State(runner): State<FleetRunner>, Json(request): Json<RestoreRequest>) -> Result<Json<Manifest>, ApiError> {
+    let Some((_, root)) = &runner.snapshots else {
+        return Err((StatusCode::NOT_FOUND, "fleet snapshots are not set up".to_string()));
+    };
+    // Only snapshots under the root can be named, not any directory on the host
+    let name = std::path::Path::new(&request.snapshot);
+    if !matches!(name.components().collect::<Vec<_>>().as_slice(), [Component::Normal(_)]) {
+        return Err((StatusCode::BAD_REQUEST, format!("{} is not a snapshot name", request.snapshot)));
+    }
+    let dir = root.join(name);
+    snapshot::manifest(&dir).map_err(|e| (StatusCode::BAD_REQUEST, format!("{:#}", e)))?;
+    runner.restore(&dir).await.map(Json).map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))
+}
+
+async fn environments(State(runner): State<FleetRunner>) -> Json<BTreeMap<String, EnvironmentState>> {
+    Json(runner.environments.report(Utc::now()))
+}
+
+async fn inject(State(runner): State<FleetRunner>, Path(name): Path<String>, Json(event): Json<EnvironmentEvent>) -> Result<Json<EnvironmentState>, ApiError> {
+    info!(environment = %name, ?event, "Injecting environment event");
+    runner.environments.inject(&name, &event, Utc::now())
+        .map(Json)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("no environment {}", name)))
+}
+
+/// Serves the control API as `api` says, until the process exits.
+pub async fn serve(api: &ControlApi, runner: FleetRunner) -> Result<()> {
+    let listener = TcpListener::bind(api.address).await.with_context(|| format!("Failed to bind the fleet control API to {}", api.address))?;
+    serve_on(listener, runner, api.token.clone());
+    Ok(())
+}
+
+pub fn serve_on(listener: TcpListener, runner: FleetRunner, token: Option<String>) -> JoinHandle<()> {
+    if let Ok(address) = listener.local_addr() {
+        info!(address = %address, "Serving the fleet control API");
+    }
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router(runner, token)).await {
+            warn!(error = %e, "Fleet control API stopped");
+        }
+    })
+}
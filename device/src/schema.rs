@@ -0,0 +1,82 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::types::{Measurement, MeasurementSchema};
+
+pub const SCHEMA_CACHE_FILE: &str = "measurement_schema.json";
+
+/// Loads the last known schema so offline boots still honor it.
+pub fn load_cached(path: &Path) -> Option<MeasurementSchema> {
+    let contents = fs::read_to_string(path).ok()?;
+    match serde_json::from_str(&contents) {
+        Ok(schema) => {
+            info!(path = %path.display(), "Loaded cached measurement schema");
+            Some(schema)
+        }
+        Err(e) => {
+            warn!(path = %path.display(), error = %e, "Ignoring unreadable measurement schema cache");
+            None
+        }
+    }
+}
+
+/// Persists the schema, or removes the cache when the backend publishes none.
+pub fn save_cached(path: &Path, schema: Option<&MeasurementSchema>) -> Result<()> {
+    match schema {
+        Some(schema) => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(path, serde_json::to_string_pretty(schema)?)?;
+        }
+        None => {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Counts the values in a locally generated measurement that the schema would
+/// reject: missing required fields and numbers outside the published ranges.
+pub fn count_rejections(schema: &MeasurementSchema, measurement: &Measurement) -> u64 {
+    let Ok(Value::Object(fields)) = serde_json::to_value(measurement) else {
+        return 0;
+    };
+
+    let mut rejected = 0;
+    for name in &schema.required_fields {
+        if fields.get(name).is_none_or(Value::is_null) {
+            rejected += 1;
+        }
+    }
+    for (name, range) in &schema.ranges {
+        if let Some(value) = fields.get(name).and_then(Value::as_f64) {
+            let below = range.min.is_some_and(|min| value < min);
+            let above = range.max.is_some_and(|max| value > max);
+            if below || above {
+                rejected += 1;
+            }
+        }
+    }
+    rejected
+}
+
+/// Strips measurement fields the schema does not accept from a serialized
+/// ingest payload. An empty accepted list means every field is accepted.
+pub fn filter_payload(schema: &MeasurementSchema, payload: &mut Value) {
+    if schema.accepted_fields.is_empty() {
+        return;
+    }
+    if let Some(Value::Array(measurements)) = payload.get_mut("measurements") {
+        for measurement in measurements {
+            if let Value::Object(fields) = measurement {
+                fields.retain(|name, _| schema.accepted_fields.iter().any(|accepted| accepted == name));
+            }
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::net;
+use crate::stats::ApiStats;
+use crate::storage;
+use crate::types::{ReportedShadowState, ShadowRejection};
+
+const STATE_KEY: &str = "shadow_report_rejections";
+
+// Reports of one state: the original and a sanitized remainder per round of rejections
+const MAX_REPORT_ROUNDS: usize = 3;
+
+/// The backend refused a reported shadow patch with 422. An empty list means the body
+/// named no keys, so nothing can be fixed.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+#[error("backend rejected the reported shadow patch ({} rejection(s))", rejections.len())]
+pub struct ShadowRejected {
+    pub rejections: Vec<ShadowRejection>,
+}
+
+/// What to do with a key the backend rejected.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionAction {
+    Drop, // Leave the key out of the retried patch
+    Sanitize, // Report the key as null, so the backend learns it has no valid value
+}
+
+/// How the device reacts to rejected reported keys, by rejection code.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShadowRejectionPolicy {
+    pub actions: BTreeMap<String, RejectionAction>, // Codes not listed are dropped
+    pub max_rejections: u32, // Rejections of one path before it is no longer reported
+}
+
+impl Default for ShadowRejectionPolicy {
+    fn default() -> Self {
+        ShadowRejectionPolicy { actions: BTreeMap::new(), max_rejections: 3 }
+    }
+}
+
+impl ShadowRejectionPolicy {
+    pub fn action(&self, code: &str) -> RejectionAction {
+        self.actions.get(code).copied().unwrap_or(RejectionAction::Drop)
+    }
+}
+
+/// Rejection history of one reported path.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PathRejections {
+    pub code: String, // Of the latest rejection
+    pub message: String,
+    pub count: u32,
+    pub blocked: bool, // Rejected too often; left out of every report
+    pub last_at: DateTime<Utc>,
+}
+
+/// Keeps reported shadow patches the backend refuses from being retried unchanged.
+/// Rejected keys are dropped or sanitized for an immediate retry; a path rejected
+/// `max_rejections` times is no longer reported at all and is listed under
+/// `shadow_report_errors` instead. The history is checkpointed, so blocks survive restarts.
+#[derive(Debug, Clone, Default)]
+pub struct ShadowReportGuard {
+    policy: ShadowRejectionPolicy,
+    paths: BTreeMap<String, PathRejections>, // By JSON pointer
+    dirty: bool,
+}
+
+impl ShadowReportGuard {
+    pub fn load(policy: ShadowRejectionPolicy, conn: &Connection) -> Result<Self> {
+        let paths = match storage::load_state(conn, STATE_KEY)? {
+            Some(value) => serde_json::from_value(value)?,
+            None => BTreeMap::new(),
+        };
+        Ok(ShadowReportGuard { policy, paths, dirty: false })
+    }
+
+    /// Saves the history if it changed since the last checkpoint. Returns true if it saved.
+    pub fn checkpoint(&mut self, conn: &Connection) -> Result<bool> {
+        if !self.dirty {
+            return Ok(false);
+        }
+        storage::save_state(conn, STATE_KEY, &serde_json::to_value(&self.paths)?)?;
+        self.dirty = false;
+        Ok(true)
+    }
+
+    /// The patch to report for `state`: blocked paths left out, and listed under
+    /// `shadow_report_errors`.
+    pub fn prepare(&self, state: &Value) -> Value {
+        let mut patch = state.clone();
+        for (path, record) in &self.paths {
+            if record.blocked {
+                remove(&mut patch, path);
+            }
+        }
+        self.annotate(&mut patch);
+        patch
+    }
+
+    /// Records the backend's rejections and fixes `patch` per the policy. Returns false if
+    /// some rejection could not be fixed, so the patch is not worth sending again.
+    pub fn reject(&mut self, patch: &mut Value, rejections: &[ShadowRejection], now: DateTime<Utc>) -> bool {
+        let mut fixed = !rejections.is_empty();
+        for rejection in rejections {
+            let path = pointer(&rejection.path);
+            let record = self.paths.entry(path.clone()).or_insert_with(|| PathRejections {
+                code: rejection.code.clone(),
+                message: String::new(),
+                count: 0,
+                blocked: false,
+                last_at: now,
+            });
+            record.code = rejection.code.clone();
+            record.message = rejection.message.clone();
+            record.count += 1;
+            record.last_at = now;
+            // The whole patch cannot be left out of itself
+            record.blocked = !path.is_empty() && record.count >= self.policy.max_rejections;
+            let applied = match (self.policy.action(&rejection.code), record.blocked) {
+                (RejectionAction::Drop, _) | (RejectionAction::Sanitize, true) => remove(patch, &path),
+                (RejectionAction::Sanitize, false) => sanitize(patch, &path),
+            };
+            fixed &= applied;
+        }
+        self.dirty = true;
+        self.annotate(patch);
+        fixed
+    }
+
+    /// Blocked paths, for operators.
+    pub fn report(&self) -> Value {
+        let blocked: BTreeMap<&String, Value> = self.paths.iter()
+            .filter(|(_, record)| record.blocked)
+            .map(|(path, record)| (path, json!({"code": record.code, "message": record.message, "rejections": record.count, "last_at": record.last_at})))
+            .collect();
+        json!(blocked)
+    }
+
+    fn annotate(&self, patch: &mut Value) {
+        if self.paths.values().any(|record| record.blocked) && patch.is_object() {
+            patch["shadow_report_errors"] = self.report();
+        }
+    }
+}
+
+/// Reports `state` to the backend through `guard`. A patch refused with 422 is retried
+/// with the rejected keys fixed, as long as every rejection could be fixed.
+pub async fn report(client: &Client, config: &Config, stats: &ApiStats, guard: &mut ShadowReportGuard, state: &Value) -> Result<()> {
+    let mut patch = guard.prepare(state);
+    let mut round = 1;
+    loop {
+        let error = match net::report_device_shadow(client, config, stats, ReportedShadowState { state: patch.clone() }).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let Some(rejected) = error.downcast_ref::<ShadowRejected>() else {
+            return Err(error);
+        };
+        for rejection in &rejected.rejections {
+            warn!(device_id = %config.device_id, path = %rejection.path, code = %rejection.code, message = %rejection.message, "Backend rejected reported shadow key");
+            stats.record_failure("shadow_report", &format!("rejected_{}", rejection.code));
+        }
+        let fixed = guard.reject(&mut patch, &rejected.rejections, Utc::now());
+        if !fixed || round == MAX_REPORT_ROUNDS {
+            return Err(error).context("reported shadow patch could not be fixed; not retrying it");
+        }
+        info!(device_id = %config.device_id, round, "Retrying shadow report without the rejected keys");
+        round += 1;
+    }
+}
+
+// A JSON pointer for a path given as a pointer or with dots; "" for the whole patch
+fn pointer(path: &str) -> String {
+    let segments: Vec<String> = match path.strip_prefix('/') {
+        Some(pointer) => pointer.split('/').map(str::to_string).collect(),
+        None => path.split('.').map(|segment| segment.replace('~', "~0").replace('/', "~1")).collect(),
+    };
+    if segments.iter().all(String::is_empty) {
+        return String::new();
+    }
+    segments.iter().map(|segment| format!("/{}", segment)).collect()
+}
+
+// Removes the value at `path`; false if there is none
+fn remove(patch: &mut Value, path: &str) -> bool {
+    let Some((parent, key)) = path.rsplit_once('/') else {
+        return false;
+    };
+    let key = key.replace("~1", "/").replace("~0", "~");
+    match patch.pointer_mut(parent) {
+        Some(Value::Object(map)) => map.remove(&key).is_some(),
+        Some(Value::Array(items)) => match key.parse::<usize>() {
+            Ok(index) if index < items.len() => {
+                items.remove(index);
+                true
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+// Replaces the value at `path` with null; false if there is none
+fn sanitize(patch: &mut Value, path: &str) -> bool {
+    if path.is_empty() {
+        return false;
+    }
+    match patch.pointer_mut(path) {
+        Some(value) => {
+            *value = Value::Null;
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::types::Measurement;
+
+/// What the sampler does while the pending backlog is above the high-water mark.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ShedPolicy {
+    #[default]
+    Decimate, // Keep 1 of every N samples
+    Aggregate, // Store one averaged measurement per N samples
+    Evict, // Keep sampling at full rate; storage limits decide what is lost
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShedConfig {
+    #[serde(default)]
+    pub policy: ShedPolicy,
+    #[serde(default = "default_high_water")]
+    pub high_water: u64, // Pending measurements at which shedding starts
+    #[serde(default = "default_low_water")]
+    pub low_water: u64, // Pending measurements below which full rate resumes
+    #[serde(default = "default_max_decimation")]
+    pub max_decimation: u32,
+}
+
+fn default_high_water() -> u64 {
+    10_000
+}
+
+fn default_low_water() -> u64 {
+    5_000
+}
+
+fn default_max_decimation() -> u32 {
+    60
+}
+
+impl Default for ShedConfig {
+    fn default() -> Self {
+        ShedConfig {
+            policy: ShedPolicy::default(),
+            high_water: default_high_water(),
+            low_water: default_low_water(),
+            max_decimation: default_max_decimation(),
+        }
+    }
+}
+
+/// Emitted when the sampler enters or leaves shed mode.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShedModeChange {
+    pub active: bool,
+    pub backlog: u64,
+    pub decimation_factor: u32,
+}
+
+// Running mean of the samples folded into one aggregate
+#[derive(Debug, Default)]
+struct Aggregate {
+    count: u32,
+    temp: f64,
+    humidity: f64,
+    battery: f64,
+}
+
+/// Backlog-driven sample shedding with hysteresis between the two water marks.
+/// The decimation factor grows by one for every high-water mark's worth of backlog.
+#[derive(Debug)]
+pub struct Shedder {
+    config: ShedConfig,
+    active: bool,
+    decimation_factor: u32,
+    position: u32, // Samples seen in the current group of decimation_factor
+    aggregate: Aggregate,
+    shed_samples: u64,
+    min_aggregation: u32, // Aggregation the network forces outside shed mode; 1 for none
+}
+
+impl Shedder {
+    pub fn new(config: ShedConfig) -> Self {
+        Shedder {
+            config,
+            active: false,
+            decimation_factor: 1,
+            position: 0,
+            aggregate: Aggregate::default(),
+            shed_samples: 0,
+            min_aggregation: 1,
+        }
+    }
+
+    /// Averages every `samples` samples into one even without a backlog, e.g. to keep
+    /// payloads small on a constrained network. 1 turns this off.
+    pub fn set_min_aggregation(&mut self, samples: u32) {
+        let samples = samples.max(1);
+        if samples != self.min_aggregation && !self.active {
+            self.position = 0;
+            self.aggregate = Aggregate::default();
+        }
+        self.min_aggregation = samples;
+    }
+
+    pub fn decimation_factor(&self) -> u32 {
+        self.decimation_factor
+    }
+
+    pub fn shed_samples(&self) -> u64 {
+        self.shed_samples
+    }
+
+    /// Re-evaluates the mode for the current backlog. Returns the change, if any.
+    pub fn update(&mut self, backlog: u64) -> Option<ShedModeChange> {
+        if self.config.policy == ShedPolicy::Evict {
+            return None;
+        }
+        let was_active = self.active;
+        if backlog > self.config.high_water {
+            self.active = true;
+        } else if backlog < self.config.low_water {
+            self.active = false;
+        }
+
+        self.decimation_factor = if self.active {
+            let steps = backlog / self.config.high_water.max(1);
+            (steps + 1).clamp(2, self.config.max_decimation.max(2) as u64) as u32
+        } else {
+            1
+        };
+        if was_active && !self.active {
+            // Partial groups are dropped on recovery rather than emitted late
+            self.position = 0;
+            self.aggregate = Aggregate::default();
+        }
+
+        (self.active != was_active).then_some(ShedModeChange {
+            active: self.active,
+            backlog,
+            decimation_factor: self.decimation_factor,
+        })
+    }
+
+    /// Applies the policy to a new sample. Returns the measurement to store, if any.
+    pub fn admit(&mut self, measurement: Measurement) -> Option<Measurement> {
+        let (policy, factor) = match (self.active, self.min_aggregation) {
+            (true, _) => (self.config.policy, self.decimation_factor),
+            (false, 1) => return Some(measurement),
+            (false, forced) => (ShedPolicy::Aggregate, forced),
+        };
+        self.position += 1;
+        match policy {
+            ShedPolicy::Evict => Some(measurement),
+            ShedPolicy::Decimate => {
+                if self.position >= factor {
+                    self.position = 0;
+                    Some(measurement)
+                } else {
+                    self.shed_samples += 1;
+                    None
+                }
+            }
+            ShedPolicy::Aggregate => {
+                let agg = &mut self.aggregate;
+                agg.count += 1;
+                let n = agg.count as f64;
+                agg.temp += (measurement.temp as f64 - agg.temp) / n;
+                agg.humidity += (measurement.humidity as f64 - agg.humidity) / n;
+                agg.battery += (measurement.battery as f64 - agg.battery) / n;
+                if self.position < factor {
+                    self.shed_samples += 1;
+                    return None;
+                }
+                // The latest sample carries the timestamp, position and flags for the group
+                let mut aggregated = measurement;
+                aggregated.temp = agg.temp as f32;
+                aggregated.humidity = agg.humidity as f32;
+                aggregated.battery = agg.battery as f32;
+                aggregated.aggregate_count = Some(agg.count);
+                self.aggregate = Aggregate::default();
+                self.position = 0;
+                Some(aggregated)
+            }
+        }
+    }
+
+    pub fn report(&self) -> Value {
+        json!({
+            "active": self.active,
+            "policy": self.config.policy,
+            "decimation_factor": self.decimation_factor,
+            "shed_samples": self.shed_samples,
+        })
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::Result;
+use chrono::Utc;
+use reqwest::Client;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::shadow_report::{self, ShadowReportGuard};
+use crate::stats::ApiStats;
+use crate::types::MeasurementSchema;
+use crate::upload;
+
+/// The process cannot subscribe to SIGTERM or SIGINT, so it could not stop cleanly.
+#[derive(Debug, thiserror::Error)]
+#[error("cannot listen for termination signals")]
+pub struct SignalsUnavailable(#[source] pub std::io::Error);
+
+/// SIGTERM (docker stop) and SIGINT (Ctrl-C). Installed once, before the main loop, so a
+/// signal that arrives while another branch is running is not lost.
+pub struct ShutdownSignals {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
+}
+
+impl ShutdownSignals {
+    pub fn install() -> Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            Ok(ShutdownSignals { terminate: signal(SignalKind::terminate()).map_err(SignalsUnavailable)?, interrupt: signal(SignalKind::interrupt()).map_err(SignalsUnavailable)? })
+        }
+        #[cfg(not(unix))]
+        Ok(ShutdownSignals {})
+    }
+
+    /// Waits for the next signal and names it.
+    pub async fn recv(&mut self) -> &'static str {
+        #[cfg(unix)]
+        {
+            tokio::select! {
+                _ = self.terminate.recv() => "SIGTERM",
+                _ = self.interrupt.recv() => "SIGINT",
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+            "ctrl_c"
+        }
+    }
+}
+
+/// How the fleet runner stops a device it suspends.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StopMode {
+    #[default]
+    Graceful, // Upload the backlog and report the device suspended, as on a signal
+    Immediate, // Stop at once; the backlog stays stored for the next start
+}
+
+/// Why a device is going down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Signal(&'static str),
+    Suspend(StopMode), // Asked by the fleet runner, which can start the device again
+}
+
+impl StopReason {
+    /// The connection state the device reports on its way down.
+    pub fn connection(&self) -> &'static str {
+        match self {
+            StopReason::Signal(_) => "offline",
+            StopReason::Suspend(_) => "suspended",
+        }
+    }
+}
+
+/// Waits for a shutdown signal or a stop request from the fleet runner.
+pub async fn next_stop(signals: &mut ShutdownSignals, stop_requests: &mut mpsc::Receiver<StopMode>) -> StopReason {
+    tokio::select! {
+        signal = signals.recv() => StopReason::Signal(signal),
+        Some(mode) = stop_requests.recv() => StopReason::Suspend(mode),
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct FlushOutcome {
+    pub uploaded: usize,
+    pub timed_out: bool, // The deadline cut the flush short; anything unsent stays stored
+    pub reported_offline: bool,
+}
+
+/// The shadow report a flush ends with: the device's reported `state`, marked with
+/// `connection`, offline or suspended.
+pub struct FinalReport<'a> {
+    pub state: &'a mut Value,
+    pub connection: &'a str,
+    pub guard: &'a mut ShadowReportGuard,
+}
+
+/// Uploads every stored measurement, then sends the final report, all within
+/// `shutdown_timeout_secs`. Rows still in flight when the deadline passes are released at
+/// the next start. The report's state is updated either way, so it can be saved.
+pub async fn flush(
+    client: &Client,
+    config: &Config,
+    stats: &ApiStats,
+    conn: &mut Connection,
+    measurement_schema: Option<&MeasurementSchema>,
+    report: FinalReport<'_>,
+) -> FlushOutcome {
+    let FinalReport { state: reported_state, connection, guard: shadow_guard } = report;
+    let deadline = Duration::from_secs(config.shutdown_timeout_secs);
+    let started = Instant::now();
+    let mut outcome = FlushOutcome::default();
+
+    let drain = async {
+        let mut uploaded = 0;
+        loop {
+            match upload::drain_once(client, config, stats, conn, measurement_schema).await {
+                // Stop once a round sends nothing: the backlog is empty or the backend is failing
+                Ok(round) if round.uploaded() > 0 => uploaded += round.uploaded(),
+                Ok(_) => return uploaded,
+                Err(e) => {
+                    error!(device_id = %config.device_id, error = %e, "Failed to read measurements for the final upload");
+                    return uploaded;
+                }
+            }
+        }
+    };
+    match tokio::time::timeout(deadline, drain).await {
+        Ok(uploaded) => outcome.uploaded = uploaded,
+        Err(_) => {
+            warn!(device_id = %config.device_id, timeout_secs = config.shutdown_timeout_secs, "Final upload did not finish before the shutdown timeout");
+            outcome.timed_out = true;
+        }
+    }
+
+    reported_state["connection"] = json!(connection);
+    reported_state["last_shutdown"] = json!(Utc::now());
+    let report = shadow_report::report(client, config, stats, shadow_guard, reported_state);
+    match tokio::time::timeout(deadline.saturating_sub(started.elapsed()), report).await {
+        Ok(Ok(())) => outcome.reported_offline = true,
+        Ok(Err(e)) => warn!(device_id = %config.device_id, error = %format!("{:#}", e), "Failed to report going offline"),
+        Err(_) => {
+            warn!(device_id = %config.device_id, "No time left to report going offline");
+            outcome.timed_out = true;
+        }
+    }
+    if let Err(e) = shadow_guard.checkpoint(conn) {
+        error!(device_id = %config.device_id, error = %e, "Failed to checkpoint shadow report rejections");
+    }
+    info!(device_id = %config.device_id, flushed = outcome.uploaded, ?outcome, elapsed_ms = started.elapsed().as_millis() as u64, "Flushed before shutdown");
+    outcome
+}
@@ -1,52 +1,468 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, Utc};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::str::FromStr;
+
+use crate::can::{CanBus, CanSignalState};
+use crate::environment::SharedEnvironment;
+use crate::firmware::FirmwareBehavior;
+use crate::reefer::{ColdChainBreach, Reefer, ReeferState};
+use crate::route::RouteFollower;
 use crate::types::Measurement;
-use chrono::Utc;
-use std::sync::atomic::{AtomicU32, Ordering};
-use lazy_static::lazy_static;
-use std::sync::Mutex;
-use rand::Rng;
-
-// A simple atomic counter for the sequence number.
-static SEQUENCE_COUNTER: AtomicU32 = AtomicU32::new(0);
-
-// Simulated device state for movement
-lazy_static! {
-    static ref CURRENT_LAT: Mutex<f32> = Mutex::new(34.052235); // Initial latitude (e.g., Los Angeles)
-    static ref CURRENT_LON: Mutex<f32> = Mutex::new(-118.243683); // Initial longitude
-    static ref CURRENT_SPEED: Mutex<f32> = Mutex::new(0.0); // Initial speed
-}
-
-pub fn generate_measurement(firmware_version: String) -> Measurement {
-    let sequence_number = SEQUENCE_COUNTER.fetch_add(1, Ordering::SeqCst);
-    let mut rng = rand::thread_rng();
-
-    // Simulate some realistic-looking sensor data
-    let temp = 20.0 + (rng.gen::<f32>() * 5.0) - 2.5; // 17.5 to 22.5
-    let humidity = 50.0 + (rng.gen::<f32>() * 10.0) - 5.0; // 45.0 to 55.0
-    let battery = 0.9 - (rng.gen::<f32>() * 0.1); // 0.8 to 0.9, slowly decreasing
-
-    // Simulate movement
-    let mut lat = CURRENT_LAT.lock().unwrap();
-    let mut lon = CURRENT_LON.lock().unwrap();
-    let mut speed = CURRENT_SPEED.lock().unwrap();
-
-    // Small random walk for latitude and longitude
-    *lat += (rng.gen::<f32>() - 0.5) * 0.001; // +/- 0.0005 degrees
-    *lon += (rng.gen::<f32>() - 0.5) * 0.001; // +/- 0.0005 degrees
-
-    // Simulate speed changes
-    *speed += (rng.gen::<f32>() - 0.5) * 5.0; // +/- 2.5 units (e.g., km/h or mph)
-    if *speed < 0.0 { *speed = 0.0; } // Speed cannot be negative
-    if *speed > 100.0 { *speed = 100.0; } // Max speed
-
-    Measurement {
-        timestamp: Utc::now(),
-        temp,
-        humidity,
-        battery,
-        sequence_number,
-        latitude: Some(*lat),
-        longitude: Some(*lon),
-        speed: Some(*speed),
-        firmware_version: Some(firmware_version),
+
+// Spread of a sensor's error around its environment's air temperature, at unit noise
+const ENVIRONMENT_SENSOR_NOISE_C: f32 = 0.3;
+
+/// Simulated hardware: which fields a sample carries and the ranges they fall in.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorProfile {
+    EnvironmentalNode, // Fixed outdoor climate station, no GPS
+    #[default]
+    AssetTracker, // Moving tracker with cargo climate sensors; the original simulated device
+    IndustrialMeter, // Mains-powered meter on hot machinery, no GPS, backup battery only
+    ReeferTrailer, // Tracker in a refrigerated trailer; temp is the cargo probe, see reefer::Reefer
+}
+
+/// A reading varies uniformly within `spread` of `center`, scaled by the firmware's noise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Band {
+    pub center: f32,
+    pub spread: f32,
+}
+
+/// Plausible values for a profile at unit noise.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProfileBounds {
+    pub temp: Band, // Celsius
+    pub humidity: Band, // Percent relative humidity
+    pub battery: (f32, f32), // Charge fraction, not affected by noise
+    pub gps: bool, // Samples carry latitude, longitude and speed
+}
+
+impl SensorProfile {
+    pub const ALL: [SensorProfile; 4] = [SensorProfile::EnvironmentalNode, SensorProfile::AssetTracker, SensorProfile::IndustrialMeter, SensorProfile::ReeferTrailer];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SensorProfile::EnvironmentalNode => "environmental_node",
+            SensorProfile::AssetTracker => "asset_tracker",
+            SensorProfile::IndustrialMeter => "industrial_meter",
+            SensorProfile::ReeferTrailer => "reefer_trailer",
+        }
+    }
+
+    /// Only the trackers ride in vehicles, so only they simulate a CAN bus.
+    pub fn has_can_bus(self) -> bool {
+        matches!(self, SensorProfile::AssetTracker | SensorProfile::ReeferTrailer)
+    }
+
+    pub fn bounds(self) -> ProfileBounds {
+        match self {
+            SensorProfile::EnvironmentalNode => ProfileBounds {
+                temp: Band { center: 15.0, spread: 10.0 },
+                humidity: Band { center: 60.0, spread: 20.0 },
+                battery: (0.6, 1.0), // Solar-charged
+                gps: false,
+            },
+            SensorProfile::AssetTracker => ProfileBounds {
+                temp: Band { center: 20.0, spread: 2.5 },
+                humidity: Band { center: 50.0, spread: 5.0 },
+                battery: (0.8, 0.9),
+                gps: true,
+            },
+            SensorProfile::IndustrialMeter => ProfileBounds {
+                temp: Band { center: 65.0, spread: 15.0 },
+                humidity: Band { center: 35.0, spread: 10.0 },
+                battery: (0.95, 1.0),
+                gps: false,
+            },
+            // Without its thermal model, a reefer holding its setpoint
+            SensorProfile::ReeferTrailer => ProfileBounds {
+                temp: Band { center: 4.0, spread: 1.0 },
+                humidity: Band { center: 85.0, spread: 5.0 },
+                battery: (0.8, 0.9),
+                gps: true,
+            },
+        }
+    }
+}
+
+impl FromStr for SensorProfile {
+    type Err = anyhow::Error;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        SensorProfile::ALL.into_iter()
+            .find(|profile| profile.as_str().eq_ignore_ascii_case(raw))
+            .ok_or_else(|| anyhow!("unknown sensor profile {:?}, expected one of environmental_node, asset_tracker, industrial_meter, reefer_trailer", raw))
+    }
+}
+
+/// Range of the `clock_skew_secs` chaos flag: a fixed offset in seconds, negative for a
+/// clock running behind, or `{"min": -600, "max": 600}` for one picked per device.
+pub fn clock_skew_range(flag: &Value) -> Result<(i64, i64), String> {
+    match flag {
+        Value::Object(range) => {
+            let bound = |key: &str| range.get(key).and_then(Value::as_i64).ok_or_else(|| format!("expected {} as whole seconds", key));
+            let (min, max) = (bound("min")?, bound("max")?);
+            if min > max {
+                return Err(format!("min {} exceeds max {}", min, max));
+            }
+            Ok((min, max))
+        }
+        _ => flag.as_i64().map(|secs| (secs, secs)).ok_or_else(|| "expected seconds or {\"min\", \"max\"}".to_string()),
+    }
+}
+
+/// Rate of the `clock_drift_secs_per_hour` chaos flag: seconds the clock gains each hour,
+/// negative for one losing time.
+pub fn clock_drift_rate(flag: &Value) -> Result<f64, String> {
+    match flag.as_f64() {
+        Some(rate) if rate.abs() <= 3600.0 => Ok(rate),
+        Some(rate) => Err(format!("{} seconds per hour is more than the clock runs", rate)),
+        None => Err("expected seconds per hour".to_string()),
+    }
+}
+
+/// Where a tracker without a route starts its random walk.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub lat: f32,
+    pub lon: f32,
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Position { lat: 34.052235, lon: -118.24368 } // Los Angeles
+    }
+}
+
+/// Seed for one device's simulator. A configured seed is offset by the device's fleet
+/// index, so fleet members differ but each repeats across runs; without one, every
+/// boot draws a fresh seed.
+pub fn device_seed(configured: Option<u64>, fleet_index: Option<usize>) -> u64 {
+    match configured {
+        Some(seed) => seed.wrapping_add(fleet_index.unwrap_or(0) as u64),
+        None => rand::random(),
+    }
+}
+
+/// Everything a simulator changes as it runs, for fleet snapshots. The profile, route and
+/// CAN signal definitions come from config and are not part of it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SimulatorState {
+    pub seed: u64, // The generator continues from here
+    pub sequence_number: u32,
+    pub lat: f32,
+    pub lon: f32,
+    pub speed: f32,
+    pub route_travelled_m: Option<f64>,
+    pub last_sample: Option<DateTime<Utc>>,
+    pub can_signals: Vec<CanSignalState>,
+    pub clock_skew_secs: i64,
+    pub clock_skew_flag: Option<Value>,
+    #[serde(default)]
+    pub clock_drift_secs_per_hour: f64,
+    #[serde(default)]
+    pub clock_drift_since: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub clock_drift_flag: Option<Value>,
+    #[serde(default)]
+    pub reefer: Option<ReeferState>,
+}
+
+/// Simulated sensors of one device: its sequence counter, position and random source.
+/// Each device in a fleet owns one, so devices sharing a process move and count
+/// independently. Two simulators built with the same seed produce the same readings.
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    profile: SensorProfile,
+    rng: StdRng,
+    sequence_number: u32,
+    lat: f32,
+    lon: f32,
+    speed: f32, // km/h
+    route: Option<RouteFollower>, // Followed instead of a random walk
+    last_sample: Option<DateTime<Utc>>, // How far the route advances depends on the time since
+    can_bus: Option<CanBus>,
+    environment: Option<SharedEnvironment>, // Air temperature shared with co-located devices
+    reefer: Option<Reefer>, // Cargo temperature and trip of a reefer trailer
+    clock_skew: Duration, // Offset of this device's clock from true time
+    clock_skew_flag: Option<Value>, // Chaos flag the skew was picked from
+    clock_drift: f64, // Seconds the clock gains per hour on top of the skew
+    clock_drift_since: Option<DateTime<Utc>>, // When the drift started accumulating
+    clock_drift_flag: Option<Value>,
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Simulator::new(SensorProfile::default(), rand::random(), Position::default())
+    }
+}
+
+impl Simulator {
+    pub fn new(profile: SensorProfile, seed: u64, initial_position: Position) -> Self {
+        Simulator {
+            profile,
+            rng: StdRng::seed_from_u64(seed),
+            sequence_number: 0,
+            lat: initial_position.lat,
+            lon: initial_position.lon,
+            speed: 0.0, // Initial speed
+            route: None,
+            last_sample: None,
+            can_bus: None,
+            environment: None,
+            reefer: None,
+            clock_skew: Duration::zero(),
+            clock_skew_flag: None,
+            clock_drift: 0.0,
+            clock_drift_since: None,
+            clock_drift_flag: None,
+        }
+    }
+
+    /// Follows the `clock_skew_secs` chaos flag. A range is resolved once, so the device
+    /// keeps one wrong clock until the flag itself changes. A malformed flag clears it.
+    /// Returns true if the flag changed.
+    pub fn set_clock_skew(&mut self, flag: Option<&Value>) -> bool {
+        if flag == self.clock_skew_flag.as_ref() {
+            return false;
+        }
+        self.clock_skew_flag = flag.cloned();
+        let secs = match flag.map(clock_skew_range) {
+            Some(Ok((min, max))) => self.rng.gen_range(min..=max),
+            _ => 0,
+        };
+        self.clock_skew = Duration::seconds(secs);
+        true
+    }
+
+    pub fn clock_skew(&self) -> Duration {
+        self.clock_skew
+    }
+
+    /// Follows the `clock_drift_secs_per_hour` chaos flag. The drift accumulates from
+    /// `now`, so a changed rate starts over as if the clock had just been set. A malformed
+    /// flag clears it. Returns true if the flag changed.
+    pub fn set_clock_drift(&mut self, flag: Option<&Value>, now: DateTime<Utc>) -> bool {
+        if flag == self.clock_drift_flag.as_ref() {
+            return false;
+        }
+        self.clock_drift_flag = flag.cloned();
+        self.clock_drift = flag.map(clock_drift_rate).and_then(Result::ok).unwrap_or(0.0);
+        self.clock_drift_since = (self.clock_drift != 0.0).then_some(now);
+        true
+    }
+
+    /// Offset of this device's clock from true time at `now`: the skew plus the drift so far.
+    pub fn clock_skew_at(&self, now: DateTime<Utc>) -> Duration {
+        let drifted_ms = self.clock_drift_since.map_or(0.0, |since| {
+            (now - since).num_milliseconds().max(0) as f64 / 3_600_000.0 * self.clock_drift * 1000.0
+        });
+        self.clock_skew + Duration::milliseconds(drifted_ms as i64)
+    }
+
+    /// What this device's clock reads at true time `now`.
+    pub fn device_time(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now + self.clock_skew_at(now)
+    }
+
+    /// The clock's error, for the reported shadow.
+    pub fn clock_report(&self, now: DateTime<Utc>) -> Value {
+        json!({
+            "skew_secs": self.clock_skew_at(now).num_milliseconds() as f64 / 1000.0,
+            "drift_secs_per_hour": self.clock_drift,
+            "drift_since": self.clock_drift_since,
+        })
+    }
+
+    /// Moves along `route` at the simulated speed instead of wandering. Devices sharing a
+    /// route start at different points along it.
+    pub fn with_route(mut self, route: RouteFollower) -> Self {
+        let route = route.starting_at(self.rng.gen());
+        let start = route.position();
+        (self.lat, self.lon) = (start.lat as f32, start.lon as f32);
+        self.route = Some(route);
+        self
+    }
+
+    /// Captures the simulator's state. StdRng keeps its internal state private, so the
+    /// generator is reseeded from itself and the seed recorded: this simulator and any
+    /// restored from the state draw the same numbers from here on.
+    pub fn snapshot(&mut self) -> SimulatorState {
+        let seed = self.rng.gen();
+        self.rng = StdRng::seed_from_u64(seed);
+        SimulatorState {
+            seed,
+            sequence_number: self.sequence_number,
+            lat: self.lat,
+            lon: self.lon,
+            speed: self.speed,
+            route_travelled_m: self.route.as_ref().map(RouteFollower::travelled_m),
+            last_sample: self.last_sample,
+            can_signals: self.can_bus.as_ref().map(CanBus::state).unwrap_or_default(),
+            clock_skew_secs: self.clock_skew.num_seconds(),
+            clock_skew_flag: self.clock_skew_flag.clone(),
+            clock_drift_secs_per_hour: self.clock_drift,
+            clock_drift_since: self.clock_drift_since,
+            clock_drift_flag: self.clock_drift_flag.clone(),
+            reefer: self.reefer.as_ref().map(|reefer| reefer.state().clone()),
+        }
+    }
+
+    /// Continues from a snapshot taken `shift` ago, moving the simulator's notion of time
+    /// forward by as much so the route and bus schedules pick up where they were.
+    pub fn restore(&mut self, state: &SimulatorState, shift: Duration) {
+        self.rng = StdRng::seed_from_u64(state.seed);
+        self.sequence_number = state.sequence_number;
+        (self.lat, self.lon, self.speed) = (state.lat, state.lon, state.speed);
+        if let (Some(route), Some(travelled_m)) = (self.route.as_mut(), state.route_travelled_m) {
+            route.set_travelled_m(travelled_m);
+        }
+        self.last_sample = state.last_sample.map(|at| at + shift);
+        if let Some(can_bus) = self.can_bus.as_mut() {
+            can_bus.restore(&state.can_signals, shift);
+        }
+        self.clock_skew = Duration::seconds(state.clock_skew_secs);
+        self.clock_skew_flag = state.clock_skew_flag.clone();
+        // Paused clocks do not drift
+        self.clock_drift = state.clock_drift_secs_per_hour;
+        self.clock_drift_since = state.clock_drift_since.map(|since| since + shift);
+        self.clock_drift_flag = state.clock_drift_flag.clone();
+        if let (Some(reefer), Some(saved)) = (self.reefer.as_mut(), &state.reefer) {
+            reefer.restore(saved, shift);
+        }
+    }
+
+    /// Adds decoded bus signals to every sample, in its extra map.
+    pub fn with_can_bus(mut self, can_bus: CanBus) -> Self {
+        self.can_bus = Some(can_bus);
+        self
+    }
+
+    /// Reads temperature from the shared `environment` model, plus this sensor's own noise,
+    /// instead of from the profile's band.
+    pub fn with_environment(mut self, environment: SharedEnvironment) -> Self {
+        self.environment = Some(environment);
+        self
+    }
+
+    /// Reads temperature from the cargo of `reefer` and drives its trip: at cruising
+    /// speed between stops, standing still at them. Takes precedence over an environment.
+    pub fn with_reefer(mut self, reefer: Reefer) -> Self {
+        self.reefer = Some(reefer);
+        self
+    }
+
+    pub fn reefer(&self) -> Option<&Reefer> {
+        self.reefer.as_ref()
+    }
+
+    /// Cold-chain breaches that started since the last call, to alert on.
+    pub fn take_breaches(&mut self) -> Vec<ColdChainBreach> {
+        self.reefer.as_mut().map(Reefer::take_breaches).unwrap_or_default()
+    }
+
+    // Samples at wall time; the device samples on its own clock, tests here
+    #[cfg(test)]
+    pub fn generate_measurement(&mut self, firmware_version: String, behavior: &FirmwareBehavior) -> Measurement {
+        self.generate_measurement_at(Utc::now(), firmware_version, behavior)
+    }
+
+    /// Samples at `now` on the device's clock, see clock::Clock.
+    pub fn generate_measurement_at(&mut self, now: DateTime<Utc>, firmware_version: String, behavior: &FirmwareBehavior) -> Measurement {
+        let sequence_number = self.sequence_number;
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        let elapsed_secs = self.last_sample.map_or(0.0, |last| (now - last).num_milliseconds().max(0) as f64 / 1000.0);
+        self.last_sample = Some(now);
+        let timestamp = self.device_time(now);
+        let rng = &mut self.rng;
+        let bounds = self.profile.bounds();
+
+        // Simulate some realistic-looking sensor data
+        let noise = behavior.noise_scale;
+        let temp_draw = rng.gen::<f32>() * 2.0 - 1.0;
+        // The reefer's cargo warms and cools with the trip as it stood since the last sample
+        let cargo_c = self.reefer.as_mut().map(|reefer| reefer.advance(now));
+        let temp = match (cargo_c, &self.environment) {
+            (Some(cargo_c), _) => cargo_c + temp_draw * ENVIRONMENT_SENSOR_NOISE_C * noise,
+            (None, Some(environment)) => environment.temperature_at(now) + temp_draw * ENVIRONMENT_SENSOR_NOISE_C * noise,
+            (None, None) => bounds.temp.center + temp_draw * bounds.temp.spread * noise,
+        };
+        let humidity = (bounds.humidity.center + (rng.gen::<f32>() * 2.0 - 1.0) * bounds.humidity.spread * noise).clamp(0.0, 100.0);
+        let (battery_min, battery_max) = bounds.battery;
+        let battery = battery_max - rng.gen::<f32>() * (battery_max - battery_min);
+
+        let (latitude, longitude, speed, heading) = if bounds.gps {
+            let mut heading = None;
+            // A reefer truck drives its trip rather than a random speed
+            let driven_m = self.reefer.as_mut().map(|reefer| reefer.drive(elapsed_secs, now));
+            let parked = self.reefer.as_ref().is_some_and(Reefer::stopped);
+            if self.route.is_none() && !parked {
+                // Small random walk for latitude and longitude
+                self.lat += (rng.gen::<f32>() - 0.5) * 0.001; // +/- 0.0005 degrees
+                self.lon += (rng.gen::<f32>() - 0.5) * 0.001; // +/- 0.0005 degrees
+            }
+
+            // Simulate speed changes
+            match &self.reefer {
+                Some(reefer) => self.speed = if parked { 0.0 } else { reefer.config().cruise_kmh },
+                None => {
+                    self.speed += (rng.gen::<f32>() - 0.5) * 5.0; // +/- 2.5 km/h
+                    self.speed = self.speed.clamp(0.0, 100.0); // Speed cannot be negative, max speed 100
+                }
+            }
+
+            if let Some(route) = self.route.as_mut() {
+                let point = route.advance(driven_m.unwrap_or(self.speed as f64 / 3.6 * elapsed_secs));
+                (self.lat, self.lon) = (point.lat as f32, point.lon as f32);
+                heading = Some(point.heading as f32);
+                // Parked at the end of the route
+                if route.finished() {
+                    self.speed = 0.0;
+                }
+            }
+            (Some(self.lat), Some(self.lon), Some(self.speed), heading)
+        } else {
+            (None, None, None, None)
+        };
+
+        let mut measurement = Measurement {
+            timestamp,
+            temp,
+            humidity,
+            battery,
+            sequence_number,
+            latitude,
+            longitude,
+            speed,
+            heading,
+            geohash: None,
+            map_tile: None,
+            firmware_version: Some(firmware_version),
+            maintenance: None,
+            device_flags: None,
+            local_timestamp: None,
+            utc_offset_minutes: None,
+            aggregate_count: None,
+            region: None,
+            network: None,
+            replay: None,
+            keyframe: None,
+            extra: self.can_bus.as_mut().map(|can_bus| can_bus.sample(now, rng)), // Bus timing follows true time
+            content_hash: None,
+            omitted: Vec::new(),
+        };
+        if let Some(reefer) = &self.reefer {
+            let sample = reefer.sample(now);
+            measurement.extra.get_or_insert_with(Default::default).extend(sample.fields());
+            measurement.device_flags = sample.breach.map(|kind| vec![kind.flag().to_string()]);
+        }
+        behavior.apply(&mut measurement);
+        measurement
     }
 }
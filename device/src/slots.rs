@@ -0,0 +1,119 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::export;
+use crate::firmware::FirmwareBehavior;
+use crate::ota::OtaError;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// What a firmware slot holds: one installed image and the behavior overlay it was
+/// delivered with. Written next to the image under FIRMWARE_DIR/slot_{A,B}/.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SlotManifest {
+    pub slot: String,
+    pub version: String,
+    pub artifact: String, // Image file name within the slot directory
+    pub checksum: String, // SHA-256 hex of the image, verified at every boot
+    #[serde(default)]
+    pub behavior: Option<FirmwareBehavior>, // Applies while this slot is active
+    pub installed_at: DateTime<Utc>,
+}
+
+/// Why a slot cannot be booted.
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum SlotFault {
+    #[error("slot {slot} has no firmware installed")]
+    Empty { slot: String },
+    #[error("slot {slot} manifest is unreadable: {reason}")]
+    BadManifest { slot: String, reason: String },
+    #[error("slot {slot} image is missing")]
+    MissingArtifact { slot: String },
+    #[error("slot {slot} image is corrupt: expected {expected}, got {actual}")]
+    Corrupt { slot: String, expected: String, actual: String },
+}
+
+impl SlotFault {
+    pub fn slot(&self) -> &str {
+        match self {
+            SlotFault::Empty { slot } | SlotFault::BadManifest { slot, .. } | SlotFault::MissingArtifact { slot } | SlotFault::Corrupt { slot, .. } => slot,
+        }
+    }
+}
+
+pub fn other(slot: &str) -> &'static str {
+    if slot == "A" { "B" } else { "A" }
+}
+
+pub fn dir(firmware_dir: &Path, slot: &str) -> PathBuf {
+    firmware_dir.join(format!("slot_{}", slot))
+}
+
+/// Replaces whatever `slot` held with the image at `source`, which is moved rather than
+/// copied and must be on the same filesystem, and returns the path of the slot's new
+/// manifest. `checksum` is the image's SHA-256 hex digest. The manifest is written last,
+/// so an interrupted install leaves no manifest behind rather than one describing a
+/// partial image.
+pub fn install_file(firmware_dir: &Path, slot: &str, version: &str, source: &Path, checksum: String, behavior: Option<FirmwareBehavior>) -> Result<PathBuf, OtaError> {
+    let slot_dir = dir(firmware_dir, slot);
+    if slot_dir.exists() {
+        fs::remove_dir_all(&slot_dir).map_err(OtaError::io("clear", &slot_dir))?;
+    }
+    fs::create_dir_all(&slot_dir).map_err(OtaError::io("create", &slot_dir))?;
+    let artifact = format!("firmware_{}.bin", version);
+    fs::rename(source, slot_dir.join(&artifact)).map_err(OtaError::io("move into the slot", source))?;
+    let manifest = SlotManifest {
+        slot: slot.to_string(),
+        version: version.to_string(),
+        artifact,
+        checksum,
+        behavior,
+        installed_at: Utc::now(),
+    };
+    let path = slot_dir.join(MANIFEST_FILE);
+    let staged = slot_dir.join(format!("{}.tmp", MANIFEST_FILE));
+    fs::write(&staged, serde_json::to_vec_pretty(&manifest).map_err(OtaError::Serialize)?).map_err(OtaError::io("write", &staged))?;
+    fs::rename(&staged, &path).map_err(OtaError::io("write", &path))?;
+    Ok(path)
+}
+
+/// Installs `data` as the image of `slot`, as install_file does.
+pub fn install(firmware_dir: &Path, slot: &str, version: &str, data: &[u8], behavior: Option<FirmwareBehavior>) -> Result<PathBuf, OtaError> {
+    fs::create_dir_all(firmware_dir).map_err(OtaError::io("create", firmware_dir))?;
+    let staged = firmware_dir.join(format!("install_{}.part", slot));
+    fs::write(&staged, data).map_err(OtaError::io("write", &staged))?;
+    install_file(firmware_dir, slot, version, &staged, export::sha256_hex(data), behavior)
+}
+
+/// SHA-256 hex digest of the file at `path`, read in chunks rather than whole.
+pub fn file_sha256(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Reads the manifest at `path` and checks the image it describes, as the bootloader
+/// would before jumping into `slot`.
+pub fn verify(slot: &str, path: Option<&Path>) -> Result<SlotManifest, SlotFault> {
+    let path = path.ok_or_else(|| SlotFault::Empty { slot: slot.to_string() })?;
+    let raw = match fs::read(path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(SlotFault::Empty { slot: slot.to_string() }),
+        Err(e) => return Err(SlotFault::BadManifest { slot: slot.to_string(), reason: e.to_string() }),
+    };
+    let manifest: SlotManifest = serde_json::from_slice(&raw).map_err(|e| SlotFault::BadManifest { slot: slot.to_string(), reason: e.to_string() })?;
+    let artifact = path.parent().unwrap_or(Path::new(".")).join(&manifest.artifact);
+    let actual = file_sha256(&artifact).map_err(|_| SlotFault::MissingArtifact { slot: slot.to_string() })?;
+    if !actual.eq_ignore_ascii_case(&manifest.checksum) {
+        return Err(SlotFault::Corrupt { slot: slot.to_string(), expected: manifest.checksum, actual });
+    }
+    Ok(manifest)
+}
+
+/// Stand-in image for firmware the device shipped with, which no download provided.
+pub fn factory_image(version: &str) -> Vec<u8> {
+    format!("factory firmware {}\n", version).into_bytes()
+}
@@ -0,0 +1,317 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{error, info, warn};
+
+use crate::config::Config;
+use crate::fleet::DevicePaths;
+use crate::simulate::SimulatorState;
+
+/// Layout version of snapshot directories; restore refuses any other.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// Where a fleet writes the snapshots it is asked for, unless SNAPSHOT_DIR says otherwise.
+pub const DEFAULT_SNAPSHOT_DIR: &str = "snapshots";
+
+const MANIFEST_FILE: &str = "manifest.json";
+const RUNTIME_FILE: &str = "runtime.json";
+const DATABASE_FILE: &str = "device_storage.db";
+
+// How long the runner waits for every device to reach a safe point
+const QUIESCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// State a device only holds in memory, captured alongside its files.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeviceRuntime {
+    pub taken_at: DateTime<Utc>,
+    pub simulator: SimulatorState,
+    pub chaos_seed: u64, // Generator deciding random_error injections
+    pub sample_interval_secs: u64, // Intervals in force, which heartbeats may have changed without saving
+    pub upload_interval_secs: u64,
+    pub heartbeat_interval_secs: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ManifestEntry {
+    pub index: Option<usize>, // Position in the fleet; None for a lone device
+    pub device_id: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub version: u32,
+    pub taken_at: DateTime<Utc>,
+    pub devices: Vec<ManifestEntry>,
+    pub complete: bool, // Every device was captured while the whole fleet was paused
+}
+
+/// Reseeds `rng` from itself and returns the seed, so a restored generator continues
+/// exactly as this one does; see Simulator::snapshot.
+pub fn reseed(rng: &mut StdRng) -> u64 {
+    let seed = rng.gen();
+    *rng = StdRng::seed_from_u64(seed);
+    seed
+}
+
+fn device_dir(root: &Path, index: Option<usize>) -> PathBuf {
+    match index {
+        Some(index) => root.join(format!("device_{}", index)),
+        None => root.join("device"),
+    }
+}
+
+// The device's files, by their name in the snapshot. Firmware images, exports and crash
+// reports are artifacts rather than state, and are left out.
+fn device_files(paths: &DevicePaths) -> [(&'static str, &Path); 5] {
+    [
+        ("device_config.json", &paths.config),
+        ("ota_state.json", &paths.ota_state),
+        ("audit.log", &paths.audit_log),
+        ("staged_txn.json", &paths.staged_txn),
+        ("schema_cache.json", &paths.schema_cache),
+    ]
+}
+
+/// Captures one paused device under `root`: its files, a consistent copy of its open
+/// database, and its in-memory runtime state.
+pub fn write_device(root: &Path, paths: &DevicePaths, conn: &Connection, runtime: &DeviceRuntime) -> Result<()> {
+    let dir = device_dir(root, paths.index);
+    std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    for (name, source) in device_files(paths) {
+        if source.exists() {
+            std::fs::copy(source, dir.join(name)).with_context(|| format!("Failed to copy {}", source.display()))?;
+        }
+    }
+    let database = dir.join(DATABASE_FILE);
+    if database.exists() {
+        std::fs::remove_file(&database)?; // VACUUM INTO does not overwrite
+    }
+    conn.execute("VACUUM INTO ?1", [database.to_string_lossy()]).context("Failed to copy the measurement database")?;
+    std::fs::write(dir.join(RUNTIME_FILE), serde_json::to_vec_pretty(runtime)?)?;
+    Ok(())
+}
+
+/// Puts a captured device's files in place under `paths`, replacing what is there, and
+/// returns its runtime state. The device must not be running.
+pub fn restore_device(root: &Path, paths: &DevicePaths) -> Result<DeviceRuntime> {
+    let dir = device_dir(root, paths.index);
+    let runtime_path = dir.join(RUNTIME_FILE);
+    let raw = std::fs::read(&runtime_path).with_context(|| format!("Failed to read {}", runtime_path.display()))?;
+    let runtime: DeviceRuntime = serde_json::from_slice(&raw).with_context(|| format!("Invalid {}", runtime_path.display()))?;
+    for (name, target) in device_files(paths) {
+        let source = dir.join(name);
+        if source.exists() {
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::copy(&source, target).with_context(|| format!("Failed to restore {}", target.display()))?;
+        } else if target.exists() {
+            // Absent when captured, such as no staged transaction, so absent after restore
+            std::fs::remove_file(target)?;
+        }
+    }
+    // A journal left by the database being replaced would be applied to the restored one
+    for suffix in ["-journal", "-wal", "-shm"] {
+        let stale = PathBuf::from(format!("{}{}", paths.database.display(), suffix));
+        if stale.exists() {
+            std::fs::remove_file(&stale)?;
+        }
+    }
+    std::fs::copy(dir.join(DATABASE_FILE), &paths.database).with_context(|| format!("Failed to restore {}", paths.database.display()))?;
+    Ok(runtime)
+}
+
+/// The manifest of the snapshot at `root`, if it can be restored.
+pub fn manifest(root: &Path) -> Result<Manifest> {
+    let manifest_path = root.join(MANIFEST_FILE);
+    let raw = std::fs::read(&manifest_path).with_context(|| format!("Failed to read {}", manifest_path.display()))?;
+    let manifest: Manifest = serde_json::from_slice(&raw).with_context(|| format!("Invalid {}", manifest_path.display()))?;
+    if manifest.version != SNAPSHOT_VERSION {
+        bail!("snapshot version {} is not supported, expected {}", manifest.version, SNAPSHOT_VERSION);
+    }
+    if !manifest.complete {
+        bail!("snapshot is incomplete: not every device was captured");
+    }
+    Ok(manifest)
+}
+
+/// Restores every device of the snapshot at `root` in place. Each picks up its runtime
+/// state when it next starts.
+pub fn restore(root: &Path) -> Result<Manifest> {
+    let manifest = manifest(root)?;
+    restore_devices(root, &manifest, |index| index.map_or_else(DevicePaths::single, DevicePaths::fleet_member))?;
+    Ok(manifest)
+}
+
+/// Restores the devices of `manifest`, read from `root`, each at the paths `paths_of`
+/// gives its fleet index. None of them may be running.
+pub fn restore_devices(root: &Path, manifest: &Manifest, paths_of: impl Fn(Option<usize>) -> DevicePaths) -> Result<()> {
+    for entry in &manifest.devices {
+        let paths = paths_of(entry.index);
+        // The captured config says where the device keeps its data
+        let captured_config = device_dir(root, entry.index).join("device_config.json");
+        let paths = match Config::load_from(&captured_config) {
+            Ok(config) => paths.in_data_dir(&config.data_dir),
+            Err(_) => paths,
+        };
+        let runtime = restore_device(root, &paths).with_context(|| format!("Failed to restore device {}", entry.device_id))?;
+        std::fs::write(&paths.restored_runtime, serde_json::to_vec_pretty(&runtime)?)?;
+    }
+    Ok(())
+}
+
+/// Runtime state left by a restore for this device, consumed so only the first boot after
+/// the restore uses it.
+pub fn take_restored(path: &Path) -> Result<Option<DeviceRuntime>> {
+    let raw = match std::fs::read(path) {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    std::fs::remove_file(path)?;
+    Ok(Some(serde_json::from_slice(&raw).with_context(|| format!("Invalid {}", path.display()))?))
+}
+
+/// Asks a device to pause at its next safe point and capture itself under `dir`. It stays
+/// paused until `resume` fires or is dropped.
+#[derive(Debug)]
+pub struct PauseRequest {
+    pub dir: PathBuf,
+    pub captured: oneshot::Sender<Result<ManifestEntry, String>>,
+    pub resume: oneshot::Receiver<()>,
+}
+
+impl PauseRequest {
+    /// Reports the capture and waits to be resumed.
+    pub async fn finish(self, captured: Result<ManifestEntry, String>) {
+        let _ = self.captured.send(captured);
+        let _ = self.resume.await;
+    }
+}
+
+/// The fleet runner's side of quiescing: one port per device, outliving its reboots.
+#[derive(Debug, Default)]
+pub struct Controller {
+    ports: Mutex<Vec<(Option<usize>, mpsc::Sender<PauseRequest>)>>,
+    idle: Mutex<HashMap<Option<usize>, mpsc::Receiver<PauseRequest>>>, // Ends of the ports of devices not running
+}
+
+impl Controller {
+    pub fn port(&self, index: Option<usize>) -> mpsc::Receiver<PauseRequest> {
+        let (sender, receiver) = mpsc::channel(1);
+        self.ports.lock().unwrap().push((index, sender));
+        receiver
+    }
+
+    /// The end of device `index`'s port for it to run with, opening the port the first
+    /// time. Handed back with `release` when the device stops, so a device stopped and
+    /// started again, or restarted by a restore, keeps its place in the snapshots.
+    pub fn acquire(&self, index: Option<usize>) -> mpsc::Receiver<PauseRequest> {
+        let idle = self.idle.lock().unwrap().remove(&index);
+        idle.unwrap_or_else(|| self.port(index))
+    }
+
+    pub fn release(&self, index: Option<usize>, receiver: mpsc::Receiver<PauseRequest>) {
+        self.idle.lock().unwrap().insert(index, receiver);
+    }
+
+    /// Pauses every device, has each capture itself under `root`, writes the manifest and
+    /// resumes them. No device resumes before all are captured, so the snapshot is one
+    /// instant of the whole fleet. A device that does not pause in time, such as one
+    /// rebooting, leaves the snapshot incomplete.
+    pub async fn take(&self, root: &Path) -> Result<Manifest> {
+        std::fs::create_dir_all(root).with_context(|| format!("Failed to create {}", root.display()))?;
+        let taken_at = Utc::now();
+        let deadline = tokio::time::Instant::now() + QUIESCE_TIMEOUT;
+        let mut pending = Vec::new();
+        let mut resumes = Vec::new();
+        let ports = self.ports.lock().unwrap().clone();
+        for (index, port) in &ports {
+            let (captured, captured_receiver) = oneshot::channel();
+            let (resume, resume_receiver) = oneshot::channel();
+            let _ = port.try_send(PauseRequest { dir: root.to_path_buf(), captured, resume: resume_receiver });
+            pending.push((*index, captured_receiver));
+            resumes.push(resume);
+        }
+        let mut devices = Vec::new();
+        let mut complete = true;
+        for (index, captured) in pending {
+            match tokio::time::timeout_at(deadline, captured).await {
+                Ok(Ok(Ok(entry))) => devices.push(entry),
+                Ok(Ok(Err(e))) => {
+                    error!(?index, error = %e, "Failed to capture device for snapshot");
+                    complete = false;
+                }
+                _ => {
+                    warn!(?index, "Device did not pause for snapshot");
+                    complete = false;
+                }
+            }
+        }
+        let manifest = Manifest { version: SNAPSHOT_VERSION, taken_at, devices, complete };
+        let written = std::fs::write(root.join(MANIFEST_FILE), serde_json::to_vec_pretty(&manifest)?);
+        for resume in resumes {
+            let _ = resume.send(());
+        }
+        written?;
+        Ok(manifest)
+    }
+
+    /// Takes a snapshot in a new timestamped directory under `root` and returns where.
+    pub async fn take_under(&self, root: &Path) -> Result<(PathBuf, Manifest)> {
+        let dir = root.join(format!("fleet-{}", Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+        let manifest = self.take(&dir).await?;
+        Ok((dir, manifest))
+    }
+
+    /// Takes a snapshot under `root` on every SIGUSR1, each in its own timestamped directory.
+    pub fn listen(self: Arc<Self>, root: PathBuf) {
+        #[cfg(unix)]
+        tokio::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut requests = match signal(SignalKind::user_defined1()) {
+                Ok(requests) => requests,
+                Err(e) => {
+                    warn!(error = %e, "Fleet snapshots disabled, cannot listen for SIGUSR1");
+                    return;
+                }
+            };
+            while requests.recv().await.is_some() {
+                match self.take_under(&root).await {
+                    Ok((dir, manifest)) if manifest.complete => info!(dir = %dir.display(), devices = manifest.devices.len(), "Took fleet snapshot"),
+                    Ok((dir, manifest)) => warn!(dir = %dir.display(), captured = manifest.devices.len(), "Took incomplete fleet snapshot"),
+                    Err(e) => error!(root = %root.display(), error = %format!("{:#}", e), "Failed to take fleet snapshot"),
+                }
+            }
+        });
+        #[cfg(not(unix))]
+        let _ = (self, root);
+    }
+}
+
+/// `device restore SNAPSHOT_DIR`: puts a snapshot's devices in place, for the fleet to
+/// resume from at its next start.
+pub fn run(args: &[String]) -> ! {
+    let [root] = args else {
+        eprintln!("usage: device restore SNAPSHOT_DIR");
+        std::process::exit(2);
+    };
+    match restore(Path::new(root)) {
+        Ok(manifest) => {
+            println!("{}", serde_json::to_string_pretty(&manifest).expect("manifest serializes"));
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("device restore failed: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+}
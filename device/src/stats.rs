@@ -0,0 +1,133 @@
+use anyhow::Result;
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::response_capture::{self, CapturedResponse};
+use crate::storage;
+
+const STATS_STATE_KEY: &str = "api_stats";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct EndpointStats {
+    pub attempts: u64,
+    pub successes: u64,
+    #[serde(default)]
+    pub failures: BTreeMap<String, u64>, // Keyed by HTTP status or transport error kind
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+impl EndpointStats {
+    fn merge(&mut self, other: &EndpointStats) {
+        self.attempts += other.attempts;
+        self.successes += other.successes;
+        for (code, count) in &other.failures {
+            *self.failures.entry(code.clone()).or_insert(0) += count;
+        }
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+    }
+}
+
+pub type StatsByEndpoint = BTreeMap<String, EndpointStats>;
+
+#[derive(Default)]
+struct StatsInner {
+    // Cumulative totals persisted before this boot
+    baseline: StatsByEndpoint,
+    since_boot: StatsByEndpoint,
+    dirty: bool,
+    failed_responses: VecDeque<CapturedResponse>, // Most recent last; not persisted
+}
+
+/// Per-endpoint API counters with separate since-boot and since-provisioning views.
+/// The cumulative view is checkpointed into the device_state store and merged on load;
+/// a crash between an increment and the next checkpoint only loses that increment.
+#[derive(Clone, Default)]
+pub struct ApiStats {
+    inner: Arc<Mutex<StatsInner>>,
+}
+
+impl ApiStats {
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let baseline = match storage::load_state(conn, STATS_STATE_KEY)? {
+            Some(value) => serde_json::from_value(value)?,
+            None => StatsByEndpoint::new(),
+        };
+        Ok(ApiStats {
+            inner: Arc::new(Mutex::new(StatsInner { baseline, ..Default::default() })),
+        })
+    }
+
+    fn update(&self, endpoint: &str, f: impl FnOnce(&mut EndpointStats)) {
+        let mut inner = self.inner.lock().unwrap();
+        f(inner.since_boot.entry(endpoint.to_string()).or_default());
+        inner.dirty = true;
+    }
+
+    pub fn record_attempt(&self, endpoint: &str, bytes_sent: u64) {
+        self.update(endpoint, |stats| {
+            stats.attempts += 1;
+            stats.bytes_sent += bytes_sent;
+        });
+    }
+
+    pub fn record_success(&self, endpoint: &str, bytes_received: u64) {
+        self.update(endpoint, |stats| {
+            stats.successes += 1;
+            stats.bytes_received += bytes_received;
+        });
+    }
+
+    pub fn record_failure(&self, endpoint: &str, code: &str) {
+        self.update(endpoint, |stats| {
+            *stats.failures.entry(code.to_string()).or_insert(0) += 1;
+        });
+    }
+
+    pub fn record_failed_response(&self, captured: CapturedResponse) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.failed_responses.len() >= response_capture::MAX_CAPTURED {
+            inner.failed_responses.pop_front();
+        }
+        inner.failed_responses.push_back(captured);
+    }
+
+    /// The last few failed responses with their bodies, oldest first.
+    pub fn failed_responses(&self) -> Vec<CapturedResponse> {
+        self.inner.lock().unwrap().failed_responses.iter().cloned().collect()
+    }
+
+    pub fn since_boot(&self) -> StatsByEndpoint {
+        self.inner.lock().unwrap().since_boot.clone()
+    }
+
+    pub fn cumulative(&self) -> StatsByEndpoint {
+        let inner = self.inner.lock().unwrap();
+        let mut totals = inner.baseline.clone();
+        for (endpoint, stats) in &inner.since_boot {
+            totals.entry(endpoint.clone()).or_default().merge(stats);
+        }
+        totals
+    }
+
+    /// Both views, as reported in heartbeats and the shadow.
+    pub fn report(&self) -> Value {
+        json!({ "since_boot": self.since_boot(), "cumulative": self.cumulative() })
+    }
+
+    /// Persists the cumulative view if anything changed since the last checkpoint.
+    /// Callers invoke this on a timer, which debounces writes. Returns true if written.
+    pub fn checkpoint(&self, conn: &Connection) -> Result<bool> {
+        if !self.inner.lock().unwrap().dirty {
+            return Ok(false);
+        }
+        let cumulative = self.cumulative();
+        storage::save_state(conn, STATS_STATE_KEY, &serde_json::to_value(&cumulative)?)?;
+        self.inner.lock().unwrap().dirty = false;
+        Ok(true)
+    }
+}
@@ -1,17 +1,67 @@
-use anyhow::Result;
+use chrono::{DateTime, Utc};
 use rusqlite::{params, Connection};
 use std::path::Path;
-use tracing::{info, error};
+use tracing::{info, warn};
 
 use crate::types::Measurement;
 
-const DB_PATH: &str = "./device_storage.db";
+pub const DB_FILE: &str = "device_storage.db";
 
-pub fn init() -> Result<Connection> {
-    let path = Path::new(DB_PATH);
+/// SQLite page cache when no budget is configured, in KiB.
+pub const DEFAULT_CACHE_KIB: u64 = 8 * 1024;
+
+/// How long a statement waits for another connection's lock before failing, when not configured.
+pub const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5_000;
+
+// Budgets from this size up get larger pages, for shallower trees over a big backlog
+const LARGE_PAGE_CACHE_KIB: u64 = 32 * 1024;
+
+// Position of the claim cursor in device_state: no unclaimed row has an id at or below it
+const CLAIM_CURSOR_KEY: &str = "claim_cursor";
+
+/// Why the measurement store or the data directory could not be used.
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("stored value unreadable: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("another connection kept the WAL checkpoint from completing")]
+    CheckpointBlocked,
+}
+
+impl StorageError {
+    /// Whether SQLite found the database busy or locked by another connection, which
+    /// trying again later may get past.
+    pub fn is_busy(&self) -> bool {
+        match self {
+            StorageError::Sqlite(error) => matches!(
+                error.sqlite_error_code(),
+                Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+            ),
+            _ => false,
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, StorageError>;
+
+// The device sizes its cache from config; tests open stores with the default
+#[cfg(test)]
+pub fn init_at(path: &Path) -> Result<Connection> {
+    init_with(path, DEFAULT_CACHE_KIB, DEFAULT_BUSY_TIMEOUT_MS)
+}
+
+/// Opens the measurement store with a page cache of `cache_kib` (0 keeps SQLite's default),
+/// waiting up to `busy_timeout_ms` for locks held by other connections, and brings its
+/// layout up to date.
+pub fn init_with(path: &Path, cache_kib: u64, busy_timeout_ms: u64) -> Result<Connection> {
     let conn = Connection::open(path)?;
 
-    info!("Initializing local database at {}", DB_PATH);
+    info!("Initializing local database at {}", path.display());
+    tune(&conn, cache_kib, busy_timeout_ms)?;
     conn.execute(
         "CREATE TABLE IF NOT EXISTS measurements (
             id INTEGER PRIMARY KEY,
@@ -27,11 +77,191 @@ pub fn init() -> Result<Connection> {
         )",
         [],
     )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS device_state (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS outbox (
+            kind TEXT PRIMARY KEY,
+            payload TEXT NOT NULL,
+            queued_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS event_outbox (
+            id INTEGER PRIMARY KEY,
+            payload TEXT NOT NULL,
+            queued_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    migrate(&conn)?;
+    // Uploads interrupted by a crash are retried
+    let released = conn.execute("UPDATE measurements SET inflight = 0 WHERE inflight = 1", [])?;
+    if released > 0 {
+        info!(released, "Released measurements left in-flight by a previous run");
+        save_claim_cursor(&conn, 0)?;
+    }
     info!("Database initialization complete.");
     Ok(conn)
 }
 
-pub fn append_measurement(conn: &Connection, measurement: &Measurement) -> Result<()> {
+// Page size only takes effect on a database with no pages yet; the cache applies to this connection
+fn tune(conn: &Connection, cache_kib: u64, busy_timeout_ms: u64) -> Result<()> {
+    conn.execute_batch(&format!("PRAGMA busy_timeout = {}", busy_timeout_ms))?;
+    let page_count: i64 = conn.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+    if page_count == 0 {
+        // Before switching to WAL, which fixes the page size
+        let page_size = if cache_kib >= LARGE_PAGE_CACHE_KIB { 8192 } else { 4096 };
+        conn.execute_batch(&format!("PRAGMA page_size = {}", page_size))?;
+    }
+    // Readers and the writer no longer block each other, so sampling and uploading can
+    // overlap; with WAL, NORMAL sync loses at most the last commits on power loss, never
+    // consistency
+    let journal_mode: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+    if !journal_mode.eq_ignore_ascii_case("wal") {
+        warn!(journal_mode, "SQLite could not switch to WAL; keeping its journal mode");
+    }
+    conn.execute_batch("PRAGMA synchronous = NORMAL")?;
+    if cache_kib > 0 {
+        // Negative sizes are in KiB rather than pages
+        conn.execute_batch(&format!("PRAGMA cache_size = -{}", cache_kib))?;
+    }
+    conn.execute_batch("PRAGMA temp_store = MEMORY")?;
+    Ok(())
+}
+
+/// One step from the original measurements layout to the current one. Steps run in order,
+/// each at most once per database, and each checks before it changes anything, so a
+/// database upgraded before steps were recorded is not altered twice.
+struct Migration {
+    version: u32,
+    description: &'static str,
+    apply: fn(&Connection) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, description: "maintenance flag", apply: |conn| add_column_if_missing(conn, "maintenance", "INTEGER") },
+    Migration { version: 2, description: "device flags", apply: |conn| add_column_if_missing(conn, "device_flags", "TEXT") },
+    Migration { version: 3, description: "local timestamp", apply: |conn| add_column_if_missing(conn, "local_timestamp", "TEXT") },
+    Migration { version: 4, description: "UTC offset", apply: |conn| add_column_if_missing(conn, "utc_offset_minutes", "INTEGER") },
+    Migration { version: 5, description: "aggregate count", apply: |conn| add_column_if_missing(conn, "aggregate_count", "INTEGER") },
+    Migration { version: 6, description: "data residency region", apply: |conn| add_column_if_missing(conn, "region", "TEXT") },
+    Migration { version: 7, description: "network type", apply: |conn| add_column_if_missing(conn, "network", "TEXT") },
+    Migration { version: 8, description: "cadence keyframe", apply: |conn| add_column_if_missing(conn, "keyframe", "INTEGER") },
+    Migration { version: 9, description: "omitted fields", apply: |conn| add_column_if_missing(conn, "omitted_fields", "TEXT") },
+    Migration { version: 10, description: "extra fields", apply: |conn| add_column_if_missing(conn, "extra", "TEXT") },
+    Migration { version: 11, description: "GPS heading", apply: |conn| add_column_if_missing(conn, "heading", "REAL") },
+    Migration { version: 12, description: "geohash", apply: |conn| add_column_if_missing(conn, "geohash", "TEXT") },
+    Migration { version: 13, description: "map tile", apply: |conn| add_column_if_missing(conn, "map_tile", "TEXT") },
+    Migration { version: 14, description: "in-flight marker", apply: |conn| add_column_if_missing(conn, "inflight", "INTEGER NOT NULL DEFAULT 0") },
+    Migration { version: 15, description: "content hash", apply: |conn| add_column_if_missing(conn, "content_hash", "TEXT") },
+    Migration { version: 16, description: "integrity flag", apply: |conn| add_column_if_missing(conn, "integrity_flagged", "INTEGER NOT NULL DEFAULT 0") },
+    Migration { version: 17, description: "pending index", apply: add_pending_index },
+    Migration { version: 18, description: "timestamp index", apply: add_timestamp_index },
+    Migration { version: 19, description: "row count", apply: add_row_count },
+];
+
+/// Layout version the device writes; see MIGRATIONS.
+pub const SCHEMA_VERSION: u32 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+/// Layout version of the database; 0 for one from before versions were recorded.
+pub fn schema_version(conn: &Connection) -> Result<u32> {
+    let version: Option<u32> = conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| row.get(0))?;
+    Ok(version.unwrap_or(0))
+}
+
+// Upgrades the layout in place, recording each step with the change it made
+fn migrate(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            applied_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    let current = schema_version(conn)?;
+    if current > SCHEMA_VERSION {
+        // Firmware rolled back over a newer layout; its columns are left alone
+        warn!(version = current, known = SCHEMA_VERSION, "Database layout is newer than this firmware");
+        return Ok(());
+    }
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        let tx = conn.unchecked_transaction()?;
+        (migration.apply)(&tx)?;
+        tx.execute("INSERT INTO schema_version (version, applied_at) VALUES (?1, ?2)", params![migration.version, Utc::now()])?;
+        tx.commit()?;
+        info!(version = migration.version, description = migration.description, "Migrated measurements table");
+    }
+    Ok(())
+}
+
+// Claims and eviction read pending rows oldest first; without this index each one walks
+// the table from its first page past every row already in flight.
+fn add_pending_index(conn: &Connection) -> Result<()> {
+    let exists: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'index' AND name = 'measurements_pending')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !exists {
+        let rows: i64 = conn.query_row("SELECT COUNT(*) FROM measurements", [], |row| row.get(0))?;
+        info!(rows, "Indexing pending measurements; this runs once and may take a while on a large backlog");
+        conn.execute("CREATE INDEX measurements_pending ON measurements (inflight, id)", [])?;
+    }
+    Ok(())
+}
+
+// Timestamps from rusqlite read like `2024-03-01 10:00:00.5+00:00`; rows written in the
+// ISO form with `T` and `Z` are brought into it so every timestamp sorts as text
+fn add_timestamp_index(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "UPDATE measurements SET timestamp = replace(replace(timestamp, 'T', ' '), 'Z', '+00:00') WHERE timestamp LIKE '____-__-__T%Z'",
+        [],
+    )?;
+    conn.execute("CREATE INDEX IF NOT EXISTS measurements_timestamp ON measurements (timestamp)", [])?;
+    Ok(())
+}
+
+// The row count the storage cap checks on every append, kept by triggers rather than
+// counted, which would read the whole table each time
+fn add_row_count(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS measurement_count (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            rows INTEGER NOT NULL
+        );
+        INSERT OR REPLACE INTO measurement_count (id, rows) VALUES (0, (SELECT COUNT(*) FROM measurements));
+        CREATE TRIGGER IF NOT EXISTS measurement_count_insert AFTER INSERT ON measurements
+            BEGIN UPDATE measurement_count SET rows = rows + 1; END;
+        CREATE TRIGGER IF NOT EXISTS measurement_count_delete AFTER DELETE ON measurements
+            BEGIN UPDATE measurement_count SET rows = rows - 1; END;",
+    )?;
+    Ok(())
+}
+
+// Adds a column to databases created before it existed
+fn add_column_if_missing(conn: &Connection, column: &str, definition: &str) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info('measurements')")?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+    if !exists {
+        info!(column = column, "Adding missing column to measurements table");
+        conn.execute(&format!("ALTER TABLE measurements ADD COLUMN {} {}", column, definition), [])?;
+    }
+    Ok(())
+}
+
+/// Stores a measurement, then evicts the oldest rows beyond `max_stored` (0 for no cap).
+/// Rows being uploaded are left alone. Returns the number of rows evicted.
+pub fn append_measurement(conn: &Connection, measurement: &Measurement, max_stored: u64) -> Result<usize> {
     info!(
         timestamp = %measurement.timestamp,
         temp = measurement.temp,
@@ -42,10 +272,28 @@ pub fn append_measurement(conn: &Connection, measurement: &Measurement) -> Resul
         longitude = measurement.longitude,
         speed = measurement.speed,
         firmware_version = measurement.firmware_version,
+        maintenance = measurement.maintenance,
+        device_flags = ?measurement.device_flags,
+        local_timestamp = measurement.local_timestamp,
+        utc_offset_minutes = measurement.utc_offset_minutes,
+        aggregate_count = measurement.aggregate_count,
+        region = measurement.region,
+        network = measurement.network.map(|network| network.as_str()),
+        keyframe = measurement.keyframe,
+        omitted = ?measurement.omitted,
+        extra = ?measurement.extra,
+        heading = measurement.heading,
+        geohash = measurement.geohash,
+        map_tile = ?measurement.map_tile,
+        content_hash = measurement.content_hash,
         "Appending measurement to local DB"
     );
+    let device_flags = measurement.device_flags.as_ref().map(serde_json::to_string).transpose()?;
+    let omitted_fields = if measurement.omitted.is_empty() { None } else { Some(serde_json::to_string(&measurement.omitted)?) };
+    let extra = measurement.extra.as_ref().map(serde_json::to_string).transpose()?;
+    let map_tile = measurement.map_tile.as_ref().map(serde_json::to_string).transpose()?;
     conn.execute(
-        "INSERT INTO measurements (timestamp, temp, humidity, battery, sequence_number, latitude, longitude, speed, firmware_version) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        "INSERT INTO measurements (timestamp, temp, humidity, battery, sequence_number, latitude, longitude, speed, firmware_version, maintenance, device_flags, local_timestamp, utc_offset_minutes, aggregate_count, region, network, keyframe, omitted_fields, extra, heading, geohash, map_tile, content_hash) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23)",
         params![
             measurement.timestamp,
             measurement.temp,
@@ -56,55 +304,344 @@ pub fn append_measurement(conn: &Connection, measurement: &Measurement) -> Resul
             measurement.longitude,
             measurement.speed,
             measurement.firmware_version,
+            measurement.maintenance,
+            device_flags,
+            measurement.local_timestamp,
+            measurement.utc_offset_minutes,
+            measurement.aggregate_count,
+            measurement.region,
+            measurement.network.map(|network| network.as_str()),
+            measurement.keyframe,
+            omitted_fields,
+            extra,
+            measurement.heading,
+            measurement.geohash,
+            map_tile,
+            measurement.content_hash,
         ],
     )?;
+    if max_stored == 0 {
+        return Ok(0);
+    }
+    let excess = count_measurements(conn)?.saturating_sub(max_stored);
+    if excess == 0 {
+        return Ok(0);
+    }
+    let evicted = conn.execute(
+        "DELETE FROM measurements WHERE id IN (SELECT id FROM measurements WHERE inflight = 0 ORDER BY id LIMIT ?)",
+        params![excess],
+    )?;
+    warn!(evicted, max_stored, "Local storage cap reached; dropped the oldest measurements");
+    Ok(evicted)
+}
+
+/// Evicts measurements taken more than `max_age_secs` before `now` (0 for no limit).
+/// Rows being uploaded are left alone. Returns the number of rows evicted. Cheap enough
+/// for a timer, not meant for every sample.
+pub fn evict_expired(conn: &Connection, max_age_secs: u64, now: DateTime<Utc>) -> Result<usize> {
+    if max_age_secs == 0 {
+        return Ok(0);
+    }
+    let cutoff = now - chrono::Duration::seconds(max_age_secs as i64);
+    // Timestamps are stored in one UTC text form, so they compare as text, on the index
+    let evicted = conn.execute(
+        "DELETE FROM measurements INDEXED BY measurements_timestamp WHERE timestamp < ?1 AND inflight = 0",
+        params![cutoff],
+    )?;
+    if evicted > 0 {
+        warn!(evicted, max_age_secs, "Dropped measurements past the storage age limit");
+    }
+    Ok(evicted)
+}
+
+// Rows in the measurements table, including those being uploaded, from the running count
+// the table's triggers keep
+pub fn count_measurements(conn: &Connection) -> Result<u64> {
+    let count: i64 = conn.query_row("SELECT rows FROM measurement_count", [], |row| row.get(0))?;
+    Ok(count as u64)
+}
+
+// Measurements stored locally and not yet uploaded
+pub fn pending_count(conn: &Connection) -> Result<u64> {
+    count_measurements(conn)
+}
+
+/// Moves every commit from the WAL into the database file and syncs it, so nothing is
+/// lost when the process goes down without closing the connection.
+pub fn sync(conn: &Connection) -> Result<()> {
+    let busy: i64 = conn.query_row("PRAGMA wal_checkpoint(FULL)", [], |row| row.get(0))?;
+    if busy != 0 {
+        return Err(StorageError::CheckpointBlocked);
+    }
     Ok(())
 }
 
-pub fn get_and_clear_measurements(conn: &mut Connection, batch_size: u32) -> Result<Vec<Measurement>> {
+/// Bytes available to the device on the filesystem holding `dir`.
+pub fn free_disk_bytes(dir: &Path) -> Result<u64> {
+    Ok(fs4::available_space(dir)?)
+}
+
+// Moves pending measurements from one region to another; returns the number moved
+pub fn retag_region(conn: &Connection, from: Option<&str>, to: &str) -> Result<usize> {
+    let moved = conn.execute("UPDATE measurements SET region = ?1 WHERE region IS ?2", params![to, from])?;
+    Ok(moved)
+}
+
+/// A stored measurement and its row id, used to confirm or release it after upload.
+#[derive(Debug, Clone)]
+pub struct StoredMeasurement {
+    pub id: i64,
+    pub measurement: Measurement,
+}
+
+/// Selects up to `batch_size` of the oldest measurements not already being uploaded and
+/// flags them in-flight. Rows stay in the table until `confirm_uploaded`, so a crash
+/// mid-upload loses nothing; `init_at` releases rows left in-flight by a previous run.
+///
+/// Claims continue from a cursor persisted with the rows, so each one seeks straight to
+/// the next unclaimed id instead of counting past everything claimed before it.
+pub fn mark_measurements_inflight(conn: &mut Connection, batch_size: u32) -> Result<Vec<StoredMeasurement>> {
     let tx = conn.transaction()?;
-    
-    let (measurements, ids_to_delete) = {
-        let mut stmt = tx.prepare("SELECT id, timestamp, temp, humidity, battery, sequence_number, latitude, longitude, speed, firmware_version FROM measurements ORDER BY id LIMIT ?")?;
-        
-        let measurements_iter = stmt.query_map(params![batch_size], |row| {
-            Ok((
-                row.get::<_, i64>(0)?,
-                Measurement {
-                    timestamp: row.get(1)?,
-                    temp: row.get(2)?,
-                    humidity: row.get(3)?,
-                    battery: row.get(4)?,
-                    sequence_number: row.get(5)?,
-                    latitude: row.get(6)?,
-                    longitude: row.get(7)?,
-                    speed: row.get(8)?,
-                    firmware_version: row.get(9)?,
-                },
-            ))
-        })?;
-
-        let mut measurements = Vec::new();
-        let mut ids_to_delete = Vec::new();
-
-        for result in measurements_iter {
-            let (id, measurement) = result?;
-            measurements.push(measurement);
-            ids_to_delete.push(id);
+    let cursor = claim_cursor(&tx)?;
+    let mut rows = claim_after(&tx, cursor, batch_size)?;
+    if rows.len() < batch_size as usize && cursor > 0 {
+        // SQLite hands out ids below the cursor again once the newest rows are deleted,
+        // so a short claim looks again from the start
+        rows = claim_after(&tx, 0, batch_size)?;
+    }
+    let ids: Vec<i64> = rows.iter().map(|row| row.id).collect();
+    execute_for_ids(&tx, "UPDATE measurements SET inflight = 1 WHERE id IN", &ids)?;
+    if let Some(last) = rows.last() {
+        save_claim_cursor(&tx, last.id)?;
+    }
+    tx.commit()?;
+    if !rows.is_empty() {
+        info!("Marked {} measurements in-flight", rows.len());
+    }
+    Ok(rows)
+}
+
+fn claim_after(conn: &Connection, cursor: i64, batch_size: u32) -> Result<Vec<StoredMeasurement>> {
+    let mut stmt = conn.prepare_cached(&format!("SELECT {} FROM measurements WHERE inflight = 0 AND id > ? ORDER BY id LIMIT ?", STORED_COLUMNS))?;
+    let rows = stmt.query_map(params![cursor, batch_size], stored_measurement)?;
+    Ok(rows.collect::<rusqlite::Result<Vec<_>>>()?)
+}
+
+fn claim_cursor(conn: &Connection) -> Result<i64> {
+    Ok(load_state(conn, CLAIM_CURSOR_KEY)?.and_then(|cursor| cursor.as_i64()).unwrap_or(0))
+}
+
+fn save_claim_cursor(conn: &Connection, cursor: i64) -> Result<()> {
+    save_state(conn, CLAIM_CURSOR_KEY, &serde_json::json!(cursor))
+}
+
+/// Every stored measurement sampled within `from..=to`, in storage order, whether or not
+/// it is being uploaded.
+pub fn measurements_between(conn: &Connection, from: DateTime<Utc>, to: DateTime<Utc>) -> Result<Vec<StoredMeasurement>> {
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM measurements ORDER BY id", STORED_COLUMNS))?;
+    let rows = stmt.query_map([], stored_measurement)?.collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows.into_iter().filter(|row| (from..=to).contains(&row.measurement.timestamp)).collect())
+}
+
+const STORED_COLUMNS: &str = "id, timestamp, temp, humidity, battery, sequence_number, latitude, longitude, speed, firmware_version, maintenance, device_flags, local_timestamp, utc_offset_minutes, aggregate_count, region, network, keyframe, omitted_fields, extra, heading, geohash, map_tile, content_hash";
+
+fn stored_measurement(row: &rusqlite::Row) -> rusqlite::Result<StoredMeasurement> {
+    Ok(StoredMeasurement {
+        id: row.get(0)?,
+        measurement: Measurement {
+            timestamp: row.get(1)?,
+            temp: row.get(2)?,
+            humidity: row.get(3)?,
+            battery: row.get(4)?,
+            sequence_number: row.get(5)?,
+            latitude: row.get(6)?,
+            longitude: row.get(7)?,
+            speed: row.get(8)?,
+            heading: row.get(20)?,
+            geohash: row.get(21)?,
+            map_tile: row
+                .get::<_, Option<String>>(22)?
+                .and_then(|raw| serde_json::from_str(&raw).ok()),
+            firmware_version: row.get(9)?,
+            maintenance: row.get(10)?,
+            device_flags: row
+                .get::<_, Option<String>>(11)?
+                .and_then(|raw| serde_json::from_str(&raw).ok()),
+            local_timestamp: row.get(12)?,
+            utc_offset_minutes: row.get(13)?,
+            aggregate_count: row.get(14)?,
+            region: row.get(15)?,
+            network: row
+                .get::<_, Option<String>>(16)?
+                .and_then(|raw| raw.parse().ok()),
+            replay: None,
+            keyframe: row.get(17)?,
+            omitted: row
+                .get::<_, Option<String>>(18)?
+                .and_then(|raw| serde_json::from_str(&raw).ok())
+                .unwrap_or_default(),
+            extra: row
+                .get::<_, Option<String>>(19)?
+                .and_then(|raw| serde_json::from_str(&raw).ok()),
+            content_hash: row.get(23)?,
+        },
+    })
+}
+
+/// Deletes uploaded measurements. Returns the number of rows removed.
+pub fn confirm_uploaded(conn: &mut Connection, ids: &[i64]) -> Result<usize> {
+    delete_measurements(conn, ids)
+}
+
+/// Deletes the given rows in one transaction, so either all go or none do. Returns the
+/// number of rows removed.
+pub fn delete_measurements(conn: &mut Connection, ids: &[i64]) -> Result<usize> {
+    update_rows(conn, "DELETE FROM measurements WHERE id IN", ids)
+}
+
+/// Returns measurements whose upload failed to the backlog, in their original order.
+pub fn release_inflight(conn: &mut Connection, ids: &[i64]) -> Result<usize> {
+    let released = update_rows(conn, "UPDATE measurements SET inflight = 0 WHERE id IN", ids)?;
+    // Rewind the cursor so the next claim starts with the released rows
+    if let Some(&first) = ids.iter().min() {
+        if claim_cursor(conn)? >= first {
+            save_claim_cursor(conn, first - 1)?;
         }
-        (measurements, ids_to_delete)
-    };
-    
-    if !ids_to_delete.is_empty() {
-        info!("Clearing {} measurements from local DB", ids_to_delete.len());
-        for id in ids_to_delete {
-            if let Err(e) = tx.execute("DELETE FROM measurements WHERE id = ?", params![id]) {
-                error!(error = %e, id = id, "Failed to delete measurement from local DB");
-            }
+    }
+    Ok(released)
+}
+
+/// Flags rows whose upload the backend acknowledged with different hashes, see
+/// integrity::verify. They stay pending and are sent again.
+pub fn flag_integrity_mismatch(conn: &mut Connection, ids: &[i64]) -> Result<usize> {
+    update_rows(conn, "UPDATE measurements SET integrity_flagged = 1 WHERE id IN", ids)
+}
+
+// Stored rows whose acknowledgement did not match at least once; only tests count them
+#[cfg(test)]
+pub fn integrity_flagged_count(conn: &Connection) -> Result<u64> {
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM measurements WHERE integrity_flagged = 1", [], |row| row.get(0))?;
+    Ok(count as u64)
+}
+
+/// Rows a drain must delete before `AnalyzeAfterDrain` refreshes the planner statistics.
+pub const LARGE_DRAIN_ROWS: u64 = 50_000;
+
+/// Refreshes the query planner's statistics once a large backlog has drained, so they
+/// describe the now small table rather than the one that was. The ANALYZE samples a
+/// bounded number of rows per index, so it stays quick however big the file still is.
+#[derive(Debug, Default)]
+pub struct AnalyzeAfterDrain {
+    deleted: u64, // Rows confirmed since the last ANALYZE
+}
+
+impl AnalyzeAfterDrain {
+    /// Counts rows confirmed by an upload round. Returns true if this round finished a
+    /// large drain and the statistics were refreshed.
+    pub fn record(&mut self, conn: &Connection, confirmed: u64) -> Result<bool> {
+        self.deleted += confirmed;
+        if self.deleted < LARGE_DRAIN_ROWS || pending_count(conn)? > 0 {
+            return Ok(false);
         }
+        conn.execute_batch("PRAGMA analysis_limit = 1000; ANALYZE measurements;")?;
+        info!(deleted = self.deleted, "Refreshed query planner statistics after a large drain");
+        self.deleted = 0;
+        Ok(true)
     }
-    
+}
+
+// Ids bound per statement, well under SQLite's limit on host parameters
+const IDS_PER_STATEMENT: usize = 500;
+
+fn update_rows(conn: &mut Connection, sql: &str, ids: &[i64]) -> Result<usize> {
+    let tx = conn.transaction()?;
+    let changed = execute_for_ids(&tx, sql, ids)?;
     tx.commit()?;
-    info!("Batch of measurements committed and cleared from local DB");
-    Ok(measurements)
-}
\ No newline at end of file
+    Ok(changed)
+}
+
+// Runs `sql`, which ends in `WHERE id IN`, for every id in `ids`: one statement per
+// IDS_PER_STATEMENT ids rather than one per row
+fn execute_for_ids(conn: &Connection, sql: &str, ids: &[i64]) -> Result<usize> {
+    let mut changed = 0;
+    for chunk in ids.chunks(IDS_PER_STATEMENT) {
+        let placeholders = vec!["?"; chunk.len()].join(", ");
+        let mut stmt = conn.prepare_cached(&format!("{} ({})", sql, placeholders))?;
+        changed += stmt.execute(rusqlite::params_from_iter(chunk))?;
+    }
+    Ok(changed)
+}
+
+// Small key/value store for runtime state that must survive restarts
+pub fn save_state(conn: &Connection, key: &str, value: &serde_json::Value) -> Result<()> {
+    conn.execute(
+        "INSERT INTO device_state (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        params![key, serde_json::to_string(value)?],
+    )?;
+    Ok(())
+}
+
+/// Keeps `payload` to send once the backend is reachable again, replacing whatever of
+/// the same kind was waiting.
+pub fn queue_outbox(conn: &Connection, kind: &str, payload: &serde_json::Value, queued_at: DateTime<Utc>) -> Result<()> {
+    conn.execute(
+        "INSERT INTO outbox (kind, payload, queued_at) VALUES (?1, ?2, ?3) ON CONFLICT(kind) DO UPDATE SET payload = excluded.payload, queued_at = excluded.queued_at",
+        params![kind, serde_json::to_string(payload)?, queued_at],
+    )?;
+    Ok(())
+}
+
+pub fn outbox_entry(conn: &Connection, kind: &str) -> Result<Option<(serde_json::Value, DateTime<Utc>)>> {
+    let mut stmt = conn.prepare("SELECT payload, queued_at FROM outbox WHERE kind = ?1")?;
+    let mut rows = stmt.query(params![kind])?;
+    match rows.next()? {
+        Some(row) => {
+            let raw: String = row.get(0)?;
+            Ok(Some((serde_json::from_str(&raw)?, row.get(1)?)))
+        }
+        None => Ok(None),
+    }
+}
+
+pub fn clear_outbox(conn: &Connection, kind: &str) -> Result<()> {
+    conn.execute("DELETE FROM outbox WHERE kind = ?1", params![kind])?;
+    Ok(())
+}
+
+/// Keeps an event to send once the backend is reachable again, after those already
+/// waiting. Past `limit` waiting events the oldest are dropped; returns how many were.
+pub fn queue_event(conn: &Connection, payload: &serde_json::Value, queued_at: DateTime<Utc>, limit: usize) -> Result<usize> {
+    conn.execute("INSERT INTO event_outbox (payload, queued_at) VALUES (?1, ?2)", params![serde_json::to_string(payload)?, queued_at])?;
+    let dropped = conn.execute(
+        "DELETE FROM event_outbox WHERE id NOT IN (SELECT id FROM event_outbox ORDER BY id DESC LIMIT ?1)",
+        params![limit as i64],
+    )?;
+    Ok(dropped)
+}
+
+/// Events waiting to be sent, oldest first, with their ids for remove_event.
+pub fn queued_events(conn: &Connection) -> Result<Vec<(i64, serde_json::Value, DateTime<Utc>)>> {
+    let mut stmt = conn.prepare("SELECT id, payload, queued_at FROM event_outbox ORDER BY id")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get(2)?)))?;
+    rows.map(|row| {
+        let (id, raw, queued_at) = row?;
+        Ok((id, serde_json::from_str(&raw)?, queued_at))
+    }).collect()
+}
+
+pub fn remove_event(conn: &Connection, id: i64) -> Result<()> {
+    conn.execute("DELETE FROM event_outbox WHERE id = ?1", params![id])?;
+    Ok(())
+}
+
+pub fn load_state(conn: &Connection, key: &str) -> Result<Option<serde_json::Value>> {
+    let mut stmt = conn.prepare("SELECT value FROM device_state WHERE key = ?1")?;
+    let mut rows = stmt.query(params![key])?;
+    match rows.next()? {
+        Some(row) => {
+            let raw: String = row.get(0)?;
+            Ok(Some(serde_json::from_str(&raw)?))
+        }
+        None => Ok(None),
+    }
+}
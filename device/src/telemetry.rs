@@ -0,0 +1,154 @@
+use anyhow::Result;
+use opentelemetry::propagation::{Injector, TextMapPropagator};
+use opentelemetry::trace::{SpanBuilder, Tracer, TracerProvider as _};
+use opentelemetry::{Context, KeyValue};
+use opentelemetry_otlp::{Protocol, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{BatchConfigBuilder, BatchSpanProcessor, SdkTracer, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use std::env;
+use std::sync::{Arc, LazyLock, OnceLock};
+use std::time::Duration;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::filter::{Filtered, LevelFilter};
+use tracing_subscriber::{reload, Layer, Registry};
+
+use crate::config::Config;
+
+// Bounds on buffered spans; when the collector is slow or down spans are dropped, never queued without limit
+const MAX_QUEUED_SPANS: usize = 1024;
+const MAX_EXPORT_BATCH: usize = 256;
+const EXPORT_DELAY: Duration = Duration::from_secs(5);
+
+// Provider installed by `init`, kept for the flush on shutdown
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+// Until export is installed the layer is filtered off, so this fallback never records anything
+static UNINSTALLED: LazyLock<SdkTracer> = LazyLock::new(|| SdkTracerProvider::builder().build().tracer("device"));
+
+/// Tracer for the OpenTelemetry layer, filled in once the exporter is set up.
+#[derive(Clone, Default)]
+pub struct DeviceTracer(Arc<OnceLock<SdkTracer>>);
+
+impl Tracer for DeviceTracer {
+    type Span = opentelemetry_sdk::trace::Span;
+
+    fn build_with_context(&self, builder: SpanBuilder, parent_cx: &Context) -> Self::Span {
+        self.0.get().unwrap_or(&UNINSTALLED).build_with_context(builder, parent_cx)
+    }
+}
+
+type OtelLayer = Filtered<OpenTelemetryLayer<Registry, DeviceTracer>, reload::Layer<LevelFilter, Registry>, Registry>;
+
+/// Switches trace export on once the device identity is known. Until then the
+/// layer is filtered off, so spans cost nothing when export is not configured.
+pub struct TelemetryHandle {
+    tracer: DeviceTracer,
+    level: reload::Handle<LevelFilter, Registry>,
+}
+
+/// Tracing layer for the OpenTelemetry bridge. Registered at startup, before the
+/// config is loaded, and enabled later through the returned handle.
+pub fn layer() -> (OtelLayer, TelemetryHandle) {
+    let tracer = DeviceTracer::default();
+    let (level, level_handle) = reload::Layer::new(LevelFilter::OFF);
+    let layer = tracing_opentelemetry::layer().with_tracer(tracer.clone()).with_filter(level);
+    (layer, TelemetryHandle { tracer, level: level_handle })
+}
+
+impl TelemetryHandle {
+    /// Exports spans at info level and above through `provider`. Only the first
+    /// provider installed takes effect.
+    pub fn install(&self, provider: &SdkTracerProvider) -> Result<()> {
+        if self.tracer.0.set(provider.tracer("device")).is_err() {
+            anyhow::bail!("trace export is already installed");
+        }
+        self.level.reload(LevelFilter::INFO)?;
+        Ok(())
+    }
+}
+
+/// Where spans go. The endpoint is a base URL like OTEL_EXPORTER_OTLP_ENDPOINT;
+/// anything left unset falls back to the standard OTEL_* variables.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExportSettings {
+    pub endpoint: Option<String>,
+    pub protocol: Option<Protocol>,
+}
+
+impl ExportSettings {
+    /// Export settings from the config and OTEL_* environment, or None when export is
+    /// not configured or OTEL_SDK_DISABLED is set.
+    pub fn resolve(config: &Config) -> Option<Self> {
+        let set = |key: &str| env::var(key).is_ok_and(|value| !value.is_empty());
+        if env::var("OTEL_SDK_DISABLED").is_ok_and(|value| value.eq_ignore_ascii_case("true")) {
+            return None;
+        }
+        let from_env = set("OTEL_EXPORTER_OTLP_ENDPOINT") || set("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT");
+        (config.otel_endpoint.is_some() || from_env).then(|| ExportSettings { endpoint: config.otel_endpoint.clone(), protocol: None })
+    }
+}
+
+/// Tracer provider with a bounded batch exporter and the device's identity as
+/// resource attributes.
+pub fn build_provider(settings: &ExportSettings, device_id: &str, firmware_version: &str) -> Result<SdkTracerProvider> {
+    let mut exporter = opentelemetry_otlp::SpanExporter::builder().with_http();
+    if let Some(endpoint) = &settings.endpoint {
+        exporter = exporter.with_endpoint(format!("{}/v1/traces", endpoint.trim_end_matches('/')));
+    }
+    if let Some(protocol) = settings.protocol {
+        exporter = exporter.with_protocol(protocol);
+    }
+    let batch_config = BatchConfigBuilder::default()
+        .with_max_queue_size(MAX_QUEUED_SPANS)
+        .with_max_export_batch_size(MAX_EXPORT_BATCH)
+        .with_scheduled_delay(EXPORT_DELAY)
+        .build();
+    let processor = BatchSpanProcessor::builder(exporter.build()?).with_batch_config(batch_config).build();
+    let resource = Resource::builder()
+        .with_service_name("device")
+        .with_attributes([
+            KeyValue::new("device_id", device_id.to_string()),
+            KeyValue::new("firmware_version", firmware_version.to_string()),
+        ])
+        .build();
+    Ok(SdkTracerProvider::builder().with_span_processor(processor).with_resource(resource).build())
+}
+
+/// Enables trace export if configured. Returns whether it was enabled.
+pub fn init(handle: &TelemetryHandle, config: &Config, firmware_version: &str) -> Result<bool> {
+    let Some(settings) = ExportSettings::resolve(config) else {
+        return Ok(false);
+    };
+    let provider = build_provider(&settings, &config.device_id, firmware_version)?;
+    handle.install(&provider)?;
+    let _ = PROVIDER.set(provider);
+    Ok(true)
+}
+
+/// Flushes buffered spans before the process exits. No-op when export is off.
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(e) = provider.shutdown() {
+            tracing::error!(error = %e, "Failed to flush trace spans");
+        }
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(key.as_bytes()), HeaderValue::from_str(&value)) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+/// Adds a W3C traceparent header for the current span. Adds nothing when the span
+/// is not being exported.
+pub fn inject_trace_context(headers: &mut HeaderMap) {
+    let context = tracing::Span::current().context();
+    TraceContextPropagator::new().inject_context(&context, &mut HeaderInjector(headers));
+}
@@ -0,0 +1,27 @@
+use crate::adaptive::apply_ingest_feedback;
+use crate::types::IngestFeedback;
+
+#[test]
+fn slower_suggestion_lengthens_interval_within_bounds() {
+    let feedback: IngestFeedback = serde_json::from_str(r#"{"suggested_sample_interval_secs": 30}"#).unwrap();
+    assert_eq!(apply_ingest_feedback(10, &feedback, 5, 60), Some(30));
+
+    // Suggestions beyond the configured maximum are clamped
+    let feedback = IngestFeedback { suggested_sample_interval_secs: Some(600), ..Default::default() };
+    assert_eq!(apply_ingest_feedback(10, &feedback, 5, 60), Some(60));
+}
+
+#[test]
+fn faster_suggestion_is_clamped_to_minimum() {
+    let feedback = IngestFeedback { suggested_sample_interval_secs: Some(1), ..Default::default() };
+    assert_eq!(apply_ingest_feedback(10, &feedback, 5, 60), Some(5));
+}
+
+#[test]
+fn empty_or_unchanged_feedback_is_ignored() {
+    let feedback: IngestFeedback = serde_json::from_str("{}").unwrap();
+    assert_eq!(apply_ingest_feedback(10, &feedback, 5, 60), None);
+
+    let feedback = IngestFeedback { suggested_sample_interval_secs: Some(10), ..Default::default() };
+    assert_eq!(apply_ingest_feedback(10, &feedback, 5, 60), None);
+}
@@ -0,0 +1,68 @@
+use chrono::{Duration, TimeZone, Utc};
+use std::collections::BTreeMap;
+
+use crate::anomaly::{FieldDetectorConfig, SelfDetectionConfig, SelfDetector};
+use crate::types::Measurement;
+
+fn detector(window: u32, threshold: f64) -> SelfDetector {
+    let mut fields = BTreeMap::new();
+    fields.insert("temp".to_string(), FieldDetectorConfig { window, threshold });
+    SelfDetector::new(SelfDetectionConfig { fields, max_gap_secs: 600 })
+}
+
+fn sample(step: i64, temp: f32) -> Measurement {
+    let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    measurement.timestamp = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap() + Duration::seconds(step * 10);
+    // Small deterministic wobble around 20C
+    measurement.temp = temp + if step % 2 == 0 { 0.1 } else { -0.1 };
+    measurement
+}
+
+#[test]
+fn injected_spike_is_flagged() {
+    let mut detector = detector(10, 3.0);
+    for step in 0..30 {
+        assert!(detector.evaluate(&sample(step, 20.0)).is_empty(), "steady sample {} flagged", step);
+    }
+
+    let flags = detector.evaluate(&sample(30, 35.0));
+    assert_eq!(flags, vec!["temp_anomaly".to_string()]);
+    assert_eq!(detector.counts().get("temp"), Some(&1));
+
+    // Back to normal readings
+    assert!(detector.evaluate(&sample(31, 20.0)).is_empty());
+}
+
+#[test]
+fn no_flags_during_warm_up() {
+    let mut detector = detector(10, 3.0);
+    for step in 0..5 {
+        detector.evaluate(&sample(step, 20.0));
+    }
+    assert!(detector.evaluate(&sample(5, 35.0)).is_empty());
+}
+
+#[test]
+fn long_gap_resets_statistics() {
+    let mut detector = detector(5, 3.0);
+    for step in 0..20 {
+        detector.evaluate(&sample(step, 20.0));
+    }
+    // After an hour offline the level shifted; the first sample restarts the baseline
+    let mut after_gap = sample(20, 35.0);
+    after_gap.timestamp += Duration::hours(1);
+    assert!(detector.evaluate(&after_gap).is_empty());
+    assert!(detector.counts().is_empty());
+}
+
+#[test]
+fn detection_is_deterministic_for_identical_inputs() {
+    let mut a = detector(8, 2.5);
+    let mut b = detector(8, 2.5);
+    for step in 0..40 {
+        let temp = if step % 13 == 12 { 30.0 } else { 20.0 };
+        assert_eq!(a.evaluate(&sample(step, temp)), b.evaluate(&sample(step, temp)));
+    }
+    assert_eq!(a.counts(), b.counts());
+    assert!(!a.counts().is_empty());
+}
@@ -0,0 +1,58 @@
+use serde_json::json;
+use std::time::Duration;
+
+use crate::apply_interval_change;
+use crate::audit::{AuditLog, AuditRecord, AuditSource};
+use crate::jitter;
+
+fn temp_log_path() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("audit_{}.log", uuid::Uuid::new_v4()))
+}
+
+fn read_records(path: &std::path::Path) -> Vec<AuditRecord> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn desired_interval_change_writes_audit_record() {
+    let path = temp_log_path();
+    let mut audit_log = AuditLog::open(&path, "dev-1").unwrap();
+    let mut sample_interval_secs = 10;
+    let mut timer = jitter::interval(sample_interval_secs, 0.0);
+
+    assert!(apply_interval_change(&mut audit_log, 0.0, AuditSource::Shadow, "sample_interval_secs", &mut sample_interval_secs, &mut timer, 30));
+    assert_eq!(sample_interval_secs, 30);
+    assert_eq!(timer.period(), Duration::from_secs(30));
+
+    // Unchanged values are not audited
+    assert!(!apply_interval_change(&mut audit_log, 0.0, AuditSource::Shadow, "sample_interval_secs", &mut sample_interval_secs, &mut timer, 30));
+
+    let records = read_records(&path);
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].sequence, 0);
+    assert_eq!(records[0].device_id, "dev-1");
+    assert_eq!(records[0].source, AuditSource::Shadow);
+    assert_eq!(records[0].action, "sample_interval_secs");
+    assert_eq!(records[0].old, json!(10));
+    assert_eq!(records[0].new, json!(30));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn sequence_continues_after_reopen() {
+    let path = temp_log_path();
+    let mut audit_log = AuditLog::open(&path, "dev-1").unwrap();
+    audit_log.record(AuditSource::Heartbeat, "upload_interval_secs", json!(60), json!(120));
+    audit_log.record(AuditSource::Ota, "ota_apply", json!("1.0.0"), json!("1.1.0"));
+
+    let mut reopened = AuditLog::open(&path, "dev-1").unwrap();
+    reopened.record(AuditSource::Shadow, "chaos_flags", json!(null), json!({"random_error": true}));
+
+    let sequences: Vec<u64> = read_records(&path).iter().map(|r| r.sequence).collect();
+    assert_eq!(sequences, vec![0, 1, 2]);
+    std::fs::remove_file(path).unwrap();
+}
@@ -0,0 +1,131 @@
+use serde_json::json;
+use std::collections::HashMap;
+use wiremock::matchers::{header, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::auth::{self, Session};
+use crate::config::Config;
+use crate::net;
+use crate::residency::DataTarget;
+use crate::stats::ApiStats;
+use crate::types::Heartbeat;
+
+fn device_config(backend_url: &str, device_id: &str) -> Config {
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), backend_url.to_string()),
+        ("AUTH_TOKEN".to_string(), "stale-token".to_string()),
+        ("DEVICE_ID".to_string(), device_id.to_string()),
+        ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()),
+    ]);
+    Config::from_env_vars(&env).0
+}
+
+fn heartbeat(config: &Config) -> Heartbeat {
+    net::heartbeat_body(config, "1.0.0", 10, 60, 30)
+}
+
+async fn mount_register(server: &MockServer, device_id: uuid::Uuid, auth_token: uuid::Uuid) {
+    Mock::given(method("POST")).and(path("/api/devices/register"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "device_id": device_id,
+            "auth_token": auth_token,
+            "desired_sample_interval_secs": 10,
+            "desired_upload_interval_secs": 60,
+            "desired_heartbeat_interval_secs": 30,
+        })))
+        .expect(1)
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn rejected_token_is_refreshed_persisted_and_the_request_retried_once() {
+    let server = MockServer::start().await;
+    let device_id = uuid::Uuid::new_v4();
+    let fresh = uuid::Uuid::new_v4();
+    mount_register(&server, device_id, fresh).await;
+    Mock::given(method("POST")).and(path("/api/devices/heartbeat")).and(header("x-auth-token", fresh.to_string()))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "desired_version": null,
+            "desired_sample_interval_secs": 10,
+            "desired_upload_interval_secs": 60,
+            "desired_heartbeat_interval_secs": 30,
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST")).and(path("/api/devices/heartbeat"))
+        .respond_with(ResponseTemplate::new(401))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let config_path = std::env::temp_dir().join(format!("auth_config_{}.json", uuid::Uuid::new_v4()));
+    let mut config = device_config(&server.uri(), &device_id.to_string());
+    config.session = Session::persisting_to(config_path.clone());
+    let background = config.clone();
+
+    net::send_heartbeat(&reqwest::Client::new(), &config, &ApiStats::default(), &heartbeat(&config)).await.unwrap();
+
+    // The registration reused the device id, so the backend handed back this device's credentials
+    let requests = server.received_requests().await.unwrap();
+    let register: serde_json::Value = serde_json::from_slice(&requests[1].body).unwrap();
+    assert_eq!(register["boot_id"], json!(device_id));
+    // Clones of the config share the refreshed token, and so does the file on disk
+    assert_eq!(auth::current_token(&background).unwrap(), fresh.to_string());
+    let persisted = Config::load_from(&config_path).unwrap();
+    assert_eq!(persisted.auth_token, Some(fresh.to_string()));
+    assert_eq!(persisted.device_id, device_id.to_string());
+    let _ = std::fs::remove_file(&config_path);
+}
+
+#[tokio::test]
+async fn a_token_still_rejected_after_refreshing_is_returned_as_an_error() {
+    let server = MockServer::start().await;
+    let device_id = uuid::Uuid::new_v4();
+    let new_identity = uuid::Uuid::new_v4();
+    mount_register(&server, new_identity, uuid::Uuid::new_v4()).await;
+    Mock::given(method("GET")).and(path(format!("/api/devices/{}/shadow", device_id)))
+        .respond_with(ResponseTemplate::new(403))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let config_path = std::env::temp_dir().join(format!("auth_config_{}.json", uuid::Uuid::new_v4()));
+    let mut config = device_config(&server.uri(), &device_id.to_string());
+    config.session = Session::persisting_to(config_path.clone());
+    let error = net::fetch_device_shadow(&reqwest::Client::new(), &config, &ApiStats::default()).await.unwrap_err();
+    assert!(error.to_string().contains("403"), "{}", error);
+
+    // The backend registered a new identity; it is stored for the next start
+    let persisted = Config::load_from(&config_path).unwrap();
+    assert_eq!(persisted.device_id, new_identity.to_string());
+    assert_eq!(persisted.reported_shadow_state, Some(json!({})));
+    let _ = std::fs::remove_file(&config_path);
+}
+
+#[tokio::test]
+async fn an_upload_rejected_for_an_expired_token_succeeds_after_the_refresh() {
+    let server = MockServer::start().await;
+    let device_id = uuid::Uuid::new_v4();
+    let fresh = uuid::Uuid::new_v4();
+    mount_register(&server, device_id, fresh).await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest")).and(header("x-auth-token", fresh.to_string()))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(401))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let config = device_config(&server.uri(), &device_id.to_string());
+    let target = DataTarget { region: None, endpoint: server.uri() };
+    let measurements = [super::generate_measurement("1.0.0".to_string(), &Default::default())];
+    net::send_ingest(&reqwest::Client::new(), &config, &ApiStats::default(), &target, &measurements, None).await.unwrap();
+
+    // Without a config file to persist to, the new token is kept for the rest of the run
+    assert_eq!(auth::current_token(&config).unwrap(), fresh.to_string());
+}
@@ -0,0 +1,141 @@
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::baseline::{BaselineFaults, BaselineOverrides, FaultBaseline, UploadFault};
+use crate::config::Config;
+use crate::simulate::SensorProfile;
+use crate::validation::{self, Severity};
+
+const RUNS: u64 = 200_000;
+
+// Within five standard deviations of the expected count of a binomial with `probability`
+fn assert_rate(observed: u64, trials: u64, probability: f64, what: &str) {
+    let expected = trials as f64 * probability;
+    let tolerance = 5.0 * (expected * (1.0 - probability)).sqrt();
+    assert!((observed as f64 - expected).abs() <= tolerance, "{}: observed {} of {}, expected {:.0} ± {:.0}", what, observed, trials, expected, tolerance);
+}
+
+#[test]
+fn every_profile_has_a_valid_nonzero_baseline() {
+    for profile in SensorProfile::ALL {
+        let baseline = FaultBaseline::for_profile(profile);
+        assert!(baseline.validate().is_ok(), "{:?}", profile);
+        assert!(baseline.upload_timeout > 0.0 && baseline.restarts_per_day > 0.0, "{:?}", profile);
+        // Only profiles with GPS lose fixes
+        assert_eq!(baseline.gps_dropout > 0.0, profile.bounds().gps, "{:?}", profile);
+    }
+    assert!(FaultBaseline::for_profile(SensorProfile::AssetTracker).dead_zone > 0.0);
+    assert!(FaultBaseline::for_profile(SensorProfile::EnvironmentalNode).low_battery_below > 0.0);
+    assert_eq!(FaultBaseline::for_profile(SensorProfile::IndustrialMeter).low_battery_below, 0.0);
+}
+
+#[test]
+fn overrides_replace_single_rates_and_a_disabled_baseline_has_none() {
+    let overrides: BaselineOverrides = serde_json::from_value(json!({"gps_dropout": 0.5})).unwrap();
+    let baseline = FaultBaseline::effective(SensorProfile::AssetTracker, &overrides, true);
+    assert_eq!(baseline, FaultBaseline { gps_dropout: 0.5, ..FaultBaseline::for_profile(SensorProfile::AssetTracker) });
+    assert_eq!(FaultBaseline::effective(SensorProfile::AssetTracker, &overrides, false), FaultBaseline::NONE);
+
+    assert!(serde_json::from_value::<BaselineOverrides>(json!({"gps_drop": 0.5})).is_err());
+    let invalid = BaselineOverrides { dead_zone: Some(1.5), ..Default::default() };
+    assert!(FaultBaseline::effective(SensorProfile::AssetTracker, &invalid, true).validate().is_err());
+    let no_throttle = BaselineOverrides { low_battery_throttle: Some(0), ..Default::default() };
+    assert!(FaultBaseline::effective(SensorProfile::EnvironmentalNode, &no_throttle, true).validate().is_err());
+}
+
+#[test]
+fn a_device_without_background_faults_never_faults() {
+    let mut faults = BaselineFaults::new(SensorProfile::AssetTracker, &BaselineOverrides::default(), false, Some(1));
+    let mut measurement = super::generate_measurement("1.0.0".to_string(), &Default::default());
+    for _ in 0..10_000 {
+        assert!(!faults.gps_dropout(&mut measurement));
+        assert_eq!(faults.upload_fault(), None);
+        assert!(!faults.restart(3600.0));
+        assert!(faults.admit(0.0));
+    }
+    assert_eq!(faults.report()["rates"], json!(FaultBaseline::NONE));
+    assert_eq!(faults.report()["enabled"], false);
+}
+
+#[test]
+fn observed_fault_rates_match_the_configured_baseline() {
+    let baseline = FaultBaseline::for_profile(SensorProfile::AssetTracker);
+    let mut faults = BaselineFaults::new(SensorProfile::AssetTracker, &BaselineOverrides::default(), true, Some(42));
+    let sample = super::generate_measurement("1.0.0".to_string(), &Default::default());
+    assert!(sample.latitude.is_some());
+
+    let mut dropouts = 0;
+    for _ in 0..RUNS {
+        let mut measurement = sample.clone();
+        if faults.gps_dropout(&mut measurement) {
+            assert_eq!((measurement.latitude, measurement.longitude, measurement.speed), (None, None, None));
+            dropouts += 1;
+        }
+    }
+    assert_rate(dropouts, RUNS, baseline.gps_dropout, "gps_dropout");
+
+    let (mut dead_zones, mut timeouts) = (0, 0);
+    for _ in 0..RUNS {
+        match faults.upload_fault() {
+            Some(UploadFault::DeadZone) => dead_zones += 1,
+            Some(UploadFault::Timeout) => timeouts += 1,
+            None => {}
+        }
+    }
+    assert_rate(dead_zones, RUNS, baseline.dead_zone, "dead_zone");
+    // Timeouts only happen on ticks with coverage
+    assert_rate(timeouts, RUNS - dead_zones, baseline.upload_timeout, "upload_timeout");
+
+    // Hourly checks over about 23 years
+    let restarts = (0..RUNS).filter(|_| faults.restart(3600.0)).count() as u64;
+    assert_rate(restarts, RUNS, 1.0 - (-baseline.restarts_per_day / 24.0).exp(), "restarts_per_day");
+
+    let injected = faults.injected();
+    assert_eq!((injected.gps_dropouts, injected.dead_zones, injected.upload_timeouts), (dropouts, dead_zones, timeouts));
+}
+
+#[test]
+fn a_battery_near_end_of_life_is_throttled() {
+    let baseline = FaultBaseline::for_profile(SensorProfile::EnvironmentalNode);
+    let mut faults = BaselineFaults::new(SensorProfile::EnvironmentalNode, &BaselineOverrides::default(), true, Some(7));
+    assert!((0..100).all(|_| faults.admit(0.5)));
+    let admitted = (0..400).filter(|_| faults.admit(0.1)).count() as u32;
+    assert_eq!(admitted, 400 / baseline.low_battery_throttle);
+    assert_eq!(faults.injected().throttled_samples, 300);
+    // Mains-powered profiles never throttle
+    let mut meter = BaselineFaults::new(SensorProfile::IndustrialMeter, &BaselineOverrides::default(), true, Some(7));
+    assert!((0..100).all(|_| meter.admit(0.0)));
+}
+
+#[test]
+fn the_baseline_is_off_by_default_and_configured_from_the_environment() {
+    let (config, report) = Config::from_env_vars(&HashMap::from([("SENSOR_PROFILE".to_string(), "asset_tracker".to_string())]));
+    assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    assert!(!config.background_faults);
+    let faults = BaselineFaults::new(config.sensor_profile, &config.fault_baseline, config.background_faults, None);
+    assert_eq!(faults.report()["rates"], json!(FaultBaseline::NONE));
+
+    let env = HashMap::from([
+        ("SENSOR_PROFILE".to_string(), "asset_tracker".to_string()),
+        ("BACKGROUND_FAULTS".to_string(), "true".to_string()),
+        ("FAULT_BASELINE".to_string(), json!({"dead_zone": 0.2}).to_string()),
+    ]);
+    let (config, report) = Config::from_env_vars(&env);
+    assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    assert!(config.background_faults);
+    assert_eq!(config.fault_baseline.dead_zone, Some(0.2));
+    let faults = BaselineFaults::new(config.sensor_profile, &config.fault_baseline, config.background_faults, None);
+    assert_eq!(faults.report()["rates"]["dead_zone"], 0.2);
+    assert_eq!(faults.report()["profile"], "asset_tracker");
+
+    // Rates without the baseline turned on do nothing, which validation points out
+    let mut disabled = config.clone();
+    disabled.background_faults = false;
+    let findings = validation::check_config(&disabled).findings;
+    assert!(findings.iter().any(|finding| finding.key == "fault_baseline" && finding.severity == Severity::Warning), "{:?}", findings);
+
+    let mut invalid = config.clone();
+    invalid.fault_baseline.upload_timeout = Some(-0.1);
+    let findings = validation::check_config(&invalid).findings;
+    assert!(findings.iter().any(|finding| finding.key == "fault_baseline" && finding.severity == Severity::Error), "{:?}", findings);
+}
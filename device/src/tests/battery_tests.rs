@@ -0,0 +1,173 @@
+use chrono::{Duration, TimeZone, Utc};
+use serde_json::json;
+
+use crate::battery::{self, Battery, BatteryDrain, BatteryPhase};
+use crate::stats::{EndpointStats, StatsByEndpoint};
+use crate::storage;
+
+fn temp_db() -> (std::path::PathBuf, rusqlite::Connection) {
+    let db_path = std::env::temp_dir().join(format!("battery_{}.db", uuid::Uuid::new_v4()));
+    let conn = storage::init_at(&db_path).unwrap();
+    (db_path, conn)
+}
+
+fn attempts(ingest: u64, firmware_download: u64) -> StatsByEndpoint {
+    StatsByEndpoint::from([
+        ("ingest".to_string(), EndpointStats { attempts: ingest, ..Default::default() }),
+        ("firmware_download".to_string(), EndpointStats { attempts: firmware_download, ..Default::default() }),
+    ])
+}
+
+#[test]
+fn the_battery_drains_to_low_then_charges_back_to_full() {
+    let (db_path, conn) = temp_db();
+    let mut battery = Battery::load(0.01, 0.6, &conn).unwrap();
+    let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    let start = Utc.with_ymd_and_hms(2026, 5, 1, 0, 0, 0).unwrap();
+    let mut levels = Vec::new();
+    let mut phases = Vec::new();
+    // One sample a minute for four hours
+    for minute in 0..240 {
+        if let Some(phase) = battery.apply(&mut measurement, start + Duration::minutes(minute), &StatsByEndpoint::new(), 1.0) {
+            phases.push((minute, phase));
+        }
+        levels.push(measurement.battery);
+    }
+
+    let (charging_at, _) = phases[0];
+    assert_eq!(phases[0].1, BatteryPhase::Charging);
+    assert!(levels[..charging_at as usize].windows(2).all(|pair| pair[1] < pair[0]), "{:?}", levels);
+    // Low enough for low-battery alerts
+    assert!(levels[charging_at as usize] < 0.15);
+    // 0.9 of charge at 0.6 an hour takes 90 minutes
+    let (charged_at, phase) = phases[1];
+    assert_eq!(phase, BatteryPhase::Draining);
+    assert!((charged_at - charging_at - 90).abs() <= 1, "{:?}", phases);
+    assert_eq!(levels[charged_at as usize], 1.0);
+    assert!(levels[charged_at as usize + 1] < 1.0);
+    assert!(levels.iter().all(|level| (0.0..=1.0).contains(level)));
+    let _ = std::fs::remove_file(&db_path);
+}
+
+#[test]
+fn uploads_downloads_and_the_chaos_flag_drain_faster() {
+    let (db_path, conn) = temp_db();
+    let now = Utc::now();
+    let measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    let drained = |stats: &[StatsByEndpoint], factor: f32| {
+        let mut battery = Battery::load(0.001, 0.5, &conn).unwrap();
+        for stats in stats {
+            battery.apply(&mut measurement.clone(), now, stats, factor);
+        }
+        1.0 - battery.level()
+    };
+
+    let idle = drained(&[attempts(0, 0), attempts(0, 0)], 1.0);
+    assert!((idle - 0.002).abs() < 1e-6, "{}", idle);
+    // Each request is paid for once, however often the counters are seen
+    let uploading = drained(&[attempts(4, 0), attempts(4, 0)], 1.0);
+    assert!((uploading - idle - 4.0 * 0.0005).abs() < 1e-6, "{}", uploading);
+    let updating = drained(&[attempts(0, 1), attempts(0, 1)], 1.0);
+    assert!((updating - idle - 0.01).abs() < 1e-6, "{}", updating);
+    let accelerated = drained(&[attempts(0, 0), attempts(0, 0)], 5.0);
+    assert!((accelerated - 5.0 * idle).abs() < 1e-6, "{}", accelerated);
+
+    assert_eq!(battery::drain_factor(&json!(5)), Ok(5.0));
+    assert!(battery::drain_factor(&json!(0.5)).is_err());
+    assert!(battery::drain_factor(&json!("fast")).is_err());
+    let _ = std::fs::remove_file(&db_path);
+}
+
+#[test]
+fn the_charging_cycle_survives_a_restart() {
+    let (db_path, conn) = temp_db();
+    let mut battery = Battery::load(0.2, 0.5, &conn).unwrap();
+    let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    let start = Utc::now();
+    let phases: Vec<_> = (0..5).filter_map(|step| battery.apply(&mut measurement, start + Duration::minutes(step), &StatsByEndpoint::new(), 1.0)).collect();
+    assert_eq!(phases, [BatteryPhase::Charging]);
+    battery.checkpoint(&conn).unwrap();
+    drop(conn);
+
+    let conn = storage::init_at(&db_path).unwrap();
+    let mut restored = Battery::load(0.2, 0.5, &conn).unwrap();
+    assert_eq!(restored.report(), battery.report());
+    // Still charging, by the time since the last sample before the restart
+    let level = restored.level();
+    assert_eq!(restored.apply(&mut measurement, start + Duration::minutes(64), &StatsByEndpoint::new(), 1.0), None);
+    assert!((measurement.battery - level - 0.5).abs() < 1e-4, "{} from {}", measurement.battery, level);
+    let _ = std::fs::remove_file(&db_path);
+}
+
+#[test]
+fn the_level_never_increases_while_draining() {
+    let mut drain = BatteryDrain::default();
+    assert!(drain.set_flag(Some(&json!({"start": 0.5, "rate": 0.01, "low_threshold": 0.2, "power_save_factor": 3}))));
+    let mut levels = Vec::new();
+    let mut entered_power_saving = 0;
+    for _ in 0..100 {
+        let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+        if drain.apply(&mut measurement) {
+            entered_power_saving += 1;
+        }
+        levels.push(measurement.battery);
+    }
+    assert_eq!(levels[0], 0.5);
+    assert!(levels.windows(2).all(|pair| pair[1] <= pair[0]), "{:?}", levels);
+    assert_eq!(*levels.last().unwrap(), 0.0);
+    assert_eq!(entered_power_saving, 1);
+
+    // The same flag seen again does not recharge the cell
+    assert!(!drain.set_flag(Some(&json!({"start": 0.5, "rate": 0.01, "low_threshold": 0.2, "power_save_factor": 3}))));
+    assert_eq!(drain.level(), Some(0.0));
+}
+
+#[test]
+fn power_saving_takes_one_sample_tick_in_factor() {
+    let mut drain = BatteryDrain::default();
+    drain.set_flag(Some(&json!({"start": 0.3, "rate": 0.1, "low_threshold": 0.15, "power_save_factor": 4})));
+    let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    let admitted: Vec<bool> = (0..3).map(|_| drain.admit()).collect();
+    assert_eq!(admitted, vec![true; 3]);
+
+    drain.apply(&mut measurement);
+    assert!(!drain.power_saving());
+    assert!(drain.apply(&mut measurement));
+    let admitted: Vec<bool> = (0..8).map(|_| drain.admit()).collect();
+    assert_eq!(admitted, vec![false, false, false, true, false, false, false, true]);
+
+    // Without the flag the device samples on every tick again
+    assert!(drain.set_flag(None));
+    assert!(drain.admit() && drain.level().is_none());
+}
+
+#[test]
+fn the_level_survives_a_restart() {
+    let db_path = std::env::temp_dir().join(format!("battery_{}.db", uuid::Uuid::new_v4()));
+    let conn = storage::init_at(&db_path).unwrap();
+    let flag = json!(true);
+    let mut drain = BatteryDrain::load(&conn).unwrap();
+    drain.set_flag(Some(&flag));
+    let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    for _ in 0..10 {
+        drain.apply(&mut measurement);
+    }
+    let level = drain.level().unwrap();
+    assert!(level < 1.0);
+    drain.checkpoint(&conn).unwrap();
+    drop(conn);
+
+    let conn = storage::init_at(&db_path).unwrap();
+    let mut restored = BatteryDrain::load(&conn).unwrap();
+    assert!(!restored.set_flag(Some(&flag)));
+    assert_eq!(restored.level(), Some(level));
+    let _ = std::fs::remove_file(&db_path);
+}
+
+#[test]
+fn malformed_flags_are_rejected() {
+    for flag in [json!("fast"), json!({"start": 1.5}), json!({"power_save_factor": 0}), json!({"rate": -0.1}), json!({"speed": 1})] {
+        assert!(battery::drain_config(&flag).is_err(), "{}", flag);
+    }
+    assert_eq!(battery::drain_config(&json!(false)), Ok(None));
+}
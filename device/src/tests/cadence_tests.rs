@@ -0,0 +1,169 @@
+use chrono::{Duration, TimeZone, Utc};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+
+use crate::cadence::{self, CadenceConfig, CadenceEngine, FieldCadence};
+use crate::types::Measurement;
+use crate::storage;
+
+fn cadence_config(fields: &[(&str, u32, Option<f64>)], keyframe_interval_secs: u64) -> CadenceConfig {
+    let fields = fields.iter()
+        .map(|&(field, every, delta)| (field.to_string(), FieldCadence { every, delta }))
+        .collect::<BTreeMap<_, _>>();
+    CadenceConfig { fields, keyframe_interval_secs }
+}
+
+fn every_field_on_change() -> Vec<(&'static str, u32, Option<f64>)> {
+    cadence::FIELDS.iter().map(|&field| (field, 0, None)).collect()
+}
+
+// Ten seconds apart, with steady values unless a test changes them
+fn sample(step: i64) -> Measurement {
+    let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    measurement.timestamp = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap() + Duration::seconds(step * 10);
+    measurement.temp = 20.0;
+    measurement.humidity = 50.0;
+    measurement.battery = 0.9;
+    measurement.latitude = Some(34.05);
+    measurement.longitude = Some(-118.24);
+    measurement.speed = Some(0.0);
+    measurement
+}
+
+// Two hours of a vehicle parked for the first half, then driving: a slow daily temperature
+// curve with sensor jitter, humidity tracking it, a draining battery and one firmware update
+fn realistic_trace() -> Vec<Measurement> {
+    (0..720)
+        .map(|step| {
+            let mut measurement = sample(step);
+            let jitter = if step % 2 == 0 { 0.05 } else { -0.05 };
+            let hours = step as f32 / 360.0;
+            measurement.temp = 18.0 + 3.0 * (hours * 0.8).sin() + jitter;
+            measurement.humidity = 60.0 - 2.0 * (measurement.temp - 18.0) + jitter;
+            measurement.battery = 0.95 - 0.0001 * step as f32;
+            let driving = step.saturating_sub(360) as f32;
+            measurement.latitude = Some(34.05 + driving * 0.0002);
+            measurement.longitude = Some(-118.24 + driving * 0.0001);
+            measurement.speed = Some(if step < 360 { 0.0 } else { 40.0 + (step % 10) as f32 });
+            measurement.firmware_version = Some(if step < 500 { "0.1.0" } else { "0.2.0" }.to_string());
+            measurement
+        })
+        .collect()
+}
+
+fn thinned_row(measurement: &Measurement) -> Value {
+    let mut payload = json!({"measurements": [measurement]});
+    cadence::strip_omitted(std::slice::from_ref(measurement), &mut payload);
+    payload["measurements"][0].clone()
+}
+
+// What the backend does with a thinned upload: absent fields take the last value received
+fn reconstruct(rows: &[Value]) -> Vec<Map<String, Value>> {
+    let mut last_known = Map::new();
+    rows.iter()
+        .map(|row| {
+            for (field, value) in row.as_object().unwrap() {
+                last_known.insert(field.clone(), value.clone());
+            }
+            last_known.clone()
+        })
+        .collect()
+}
+
+#[test]
+fn keyframes_carry_every_field_on_schedule_and_after_a_change() {
+    let mut engine = CadenceEngine::new(Some(cadence_config(&every_field_on_change(), 60)));
+    let keyframes: Vec<i64> = (0..20)
+        .filter(|&step| {
+            let mut measurement = sample(step);
+            engine.apply(&mut measurement);
+            let keyframe = measurement.keyframe == Some(true);
+            // Between keyframes steady values are all left out
+            assert_eq!(measurement.omitted.is_empty(), keyframe, "step {}", step);
+            keyframe
+        })
+        .collect();
+    assert_eq!(keyframes, [0, 6, 12, 18]);
+
+    // New cadences start with a keyframe, so nothing older than the change is relied on
+    engine.reconfigure(Some(cadence_config(&every_field_on_change(), 600)));
+    let mut measurement = sample(20);
+    engine.apply(&mut measurement);
+    assert_eq!((measurement.keyframe, measurement.omitted.len()), (Some(true), 0));
+    assert_eq!(engine.report()["keyframes"], json!(5));
+
+    // Without cadences nothing is marked or left out
+    let mut measurement = sample(21);
+    CadenceEngine::new(None).apply(&mut measurement);
+    assert_eq!((measurement.keyframe, measurement.omitted.len()), (None, 0));
+}
+
+#[test]
+fn changes_are_sent_between_cadence_ticks_and_drift_is_not_suppressed_forever() {
+    let config = cadence_config(&[("temp", 0, Some(1.0)), ("firmware_version", 0, None), ("battery", 5, None)], 3600);
+    let mut engine = CadenceEngine::new(Some(config));
+    let mut sent: BTreeMap<&str, Vec<i64>> = BTreeMap::new();
+    for step in 0..13 {
+        let mut measurement = sample(step);
+        // A quarter degree per sample never moves far from the previous sample, but does
+        // from the last value sent
+        measurement.temp = 20.0 + 0.25 * step as f32;
+        if step >= 7 {
+            measurement.firmware_version = Some("0.2.0".to_string());
+        }
+        engine.apply(&mut measurement);
+        for field in ["temp", "firmware_version", "battery"] {
+            if !measurement.omitted.iter().any(|omitted| omitted == field) {
+                sent.entry(field).or_default().push(step);
+            }
+        }
+    }
+    assert_eq!(sent["temp"], [0, 4, 8, 12]);
+    assert_eq!(sent["firmware_version"], [0, 7]);
+    assert_eq!(sent["battery"], [0, 5, 10]);
+}
+
+#[test]
+fn thinned_uploads_are_smaller_and_reconstruct_within_the_deltas() {
+    let config = cadence_config(&[
+        ("temp", 30, Some(0.5)),
+        ("humidity", 30, Some(1.0)),
+        ("battery", 60, Some(0.005)),
+        ("latitude", 0, Some(0.0005)),
+        ("longitude", 0, Some(0.0005)),
+        ("speed", 0, Some(5.0)),
+        ("firmware_version", 0, None),
+    ], 600);
+    let mut engine = CadenceEngine::new(Some(config));
+    let trace = realistic_trace();
+
+    // Omitted fields survive storage, so a batch uploaded later is thinned the same way
+    let db_path = std::env::temp_dir().join(format!("cadence_{}.db", uuid::Uuid::new_v4()));
+    let mut conn = storage::init_at(&db_path).unwrap();
+    for measurement in &trace {
+        let mut measurement = measurement.clone();
+        engine.apply(&mut measurement);
+        storage::append_measurement(&conn, &measurement, 0).unwrap();
+    }
+    let stored: Vec<Measurement> = storage::mark_measurements_inflight(&mut conn, 1000).unwrap()
+        .into_iter()
+        .map(|row| row.measurement)
+        .collect();
+    let _ = std::fs::remove_file(&db_path);
+
+    let full = serde_json::to_vec(&json!({"measurements": trace})).unwrap().len();
+    let mut payload = json!({"measurements": stored});
+    cadence::strip_omitted(&stored, &mut payload);
+    let thinned = serde_json::to_vec(&payload).unwrap().len();
+    assert!(thinned * 2 < full, "thinned {} bytes vs {} full", thinned, full);
+
+    let rows: Vec<Value> = stored.iter().map(thinned_row).collect();
+    for (original, rebuilt) in trace.iter().zip(reconstruct(&rows)) {
+        let original = serde_json::to_value(original).unwrap();
+        for (field, delta) in [("temp", 0.5), ("humidity", 1.0), ("battery", 0.005), ("latitude", 0.0005), ("longitude", 0.0005), ("speed", 5.0)] {
+            let error = (original[field].as_f64().unwrap() - rebuilt[field].as_f64().unwrap()).abs();
+            assert!(error < delta, "{} off by {} at {}", field, error, original["timestamp"]);
+        }
+        assert_eq!(original["firmware_version"], rebuilt["firmware_version"]);
+    }
+}
@@ -0,0 +1,93 @@
+use chrono::{Duration, TimeZone, Utc};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde_json::json;
+use std::path::PathBuf;
+
+use crate::can::{CanBus, SignalSet};
+
+fn fixture() -> SignalSet {
+    SignalSet::load(&PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/tests/fixtures/can/vehicle.dbc")).unwrap()
+}
+
+#[test]
+fn the_fixture_parses_and_broken_lines_name_their_problem() {
+    let set = fixture();
+    let names: Vec<&str> = set.signals.iter().map(|signal| signal.name.as_str()).collect();
+    assert_eq!(names, ["EngineSpeed", "CoolantTemp", "FuelLevel", "Odometer"]);
+    let coolant = &set.signals[1];
+    assert_eq!((coolant.bits, coolant.scale, coolant.offset), (8, 1.0, -40.0));
+    assert_eq!((coolant.min, coolant.max, coolant.unit.as_str(), coolant.cycle_ms), (-40.0, 150.0, "degC", 1000));
+
+    for (line, expected) in [
+        ("SG_ Speed : 8 (1,0) [0|100] \"km/h\"", "cycle time"),
+        ("SG_ Speed : 8 (1,0) [0|300] \"km/h\" 100", "does not fit 8 raw bits"),
+        ("SG_ Speed : 8 (0,0) [0|100] \"km/h\" 100", "scale must be positive"),
+        ("SG_ Speed : 8 1,0 [0|100] \"km/h\" 100", "expected '('"),
+        ("SG_ Speed : 0 (1,0) [0|1] \"km/h\" 100", "1 to 64 bits"),
+        ("CM_ \"a comment\"", "expected an SG_ signal"),
+    ] {
+        let error = format!("{:#}", SignalSet::parse(line).unwrap_err());
+        assert!(error.contains(expected) && error.contains("line 1"), "{:?}: {}", line, error);
+    }
+    let twice = "SG_ A : 8 (1,0) [0|1] \"\" 10\nSG_ A : 8 (1,0) [0|1] \"\" 10";
+    assert!(format!("{:#}", SignalSet::parse(twice).unwrap_err()).contains("line 2: signal A is defined twice"));
+}
+
+#[test]
+fn values_round_trip_through_raw_frames_within_one_step() {
+    for signal in fixture().signals {
+        for step in 0..=100 {
+            let value = signal.min + (signal.max - signal.min) * step as f64 / 100.0;
+            let raw = signal.encode(value);
+            assert!((signal.decode(raw) - value).abs() <= signal.scale / 2.0 + 1e-9, "{} at {}", signal.name, value);
+            // Decoded values are exact multiples of the resolution, so they encode back unchanged
+            assert_eq!(signal.encode(signal.decode(raw)), raw);
+            assert_eq!(signal.frame_bytes(raw).len(), signal.bits.div_ceil(8) as usize);
+        }
+    }
+    let set = fixture();
+    let engine = &set.signals[0];
+    assert_eq!(engine.encode(1000.0), 4000);
+    assert_eq!(engine.frame_bytes(4000), [0xa0, 0x0f]);
+    // Out of range values clamp to what the raw width carries
+    assert_eq!(engine.encode(-5.0), 0);
+    assert_eq!(set.signals[1].encode(500.0), 255);
+}
+
+#[test]
+fn fast_signals_are_summarized_and_slow_ones_sent_at_their_own_cycle() {
+    let mut bus = CanBus::new(fixture(), true);
+    let mut rng = StdRng::seed_from_u64(7);
+    let start = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+
+    // First sample: one frame of each signal
+    let first = bus.sample(start, &mut rng);
+    assert!(first["EngineSpeed"].is_number() && first["FuelLevel"].is_number());
+    assert_eq!(first["EngineSpeed_raw"].as_str().unwrap().len(), 4);
+    assert_eq!(first["Odometer_raw"].as_str().unwrap().len(), 8);
+
+    // An accelerated minute of two-second samples
+    let mut frames = json!({"EngineSpeed": 1, "CoolantTemp": 1, "Odometer": 1});
+    let mut fuel_values = Vec::new();
+    for sample in 1..=30 {
+        let values = bus.sample(start + Duration::seconds(sample * 2), &mut rng);
+        for (name, per_sample) in [("EngineSpeed", 200), ("CoolantTemp", 2), ("Odometer", 20)] {
+            let summary = &values[name];
+            assert_eq!(summary["frames"], json!(per_sample), "{} at sample {}", name, sample);
+            let (min, max, last) = (summary["min"].as_f64().unwrap(), summary["max"].as_f64().unwrap(), summary["last"].as_f64().unwrap());
+            assert!(min <= last && last <= max, "{}: {}", name, summary);
+            frames[name] = json!(frames[name].as_u64().unwrap() + per_sample);
+        }
+        // Slower than the sample interval: the held value of the last frame
+        fuel_values.push(values["FuelLevel"].as_f64().unwrap());
+        let engine_raw = u16::from_str_radix(values["EngineSpeed_raw"].as_str().unwrap(), 16).unwrap().swap_bytes();
+        assert_eq!(engine_raw as f64 * 0.25, values["EngineSpeed"]["last"].as_f64().unwrap());
+    }
+    assert_eq!(frames, json!({"EngineSpeed": 6001, "CoolantTemp": 61, "Odometer": 601}));
+    // A new 5 s fuel frame lands on every second or third sample
+    let changes = fuel_values.windows(2).filter(|pair| pair[0] != pair[1]).count();
+    assert!(changes <= 12, "{:?}", fuel_values);
+    // Schedules carry across samples at any spacing
+    assert_eq!(bus.sample(start + Duration::seconds(61), &mut rng)["EngineSpeed"]["frames"], json!(100));
+}
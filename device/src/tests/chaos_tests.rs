@@ -0,0 +1,170 @@
+use chrono::Utc;
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::chaos::ChaosEngine;
+use crate::config::Config;
+use crate::dry_run::{self, Runtime};
+use crate::features::Features;
+use crate::net;
+use crate::types::{ChaosFlags, ChaosTarget, RandomError, RandomErrorInEffect};
+
+#[test]
+fn flags_without_a_field_are_kept_and_round_trip() {
+    let flags = ChaosFlags::parse(&json!({"random_error": true, "latency_ms": {"min": 100, "max": 200}, "packet_loss": 0.1})).unwrap();
+    assert_eq!(flags.random_error, RandomError::Everywhere(true));
+    assert_eq!(flags.error_probability, 0.1);
+    assert_eq!(flags.get("latency_ms"), Some(&json!({"min": 100, "max": 200})));
+    assert_eq!(json!(flags), json!({"random_error": true, "latency_ms": {"min": 100, "max": 200}, "packet_loss": 0.1}));
+    assert_eq!(json!(ChaosFlags::default()), json!({}));
+}
+
+#[test]
+fn payloads_no_flag_can_take_are_rejected() {
+    for malformed in [json!({"random_error": "yes"}), json!({"error_probability": 1.5}), json!({"error_probability": -0.1}), json!([true])] {
+        assert!(ChaosFlags::parse(&malformed).is_err(), "{}", malformed);
+    }
+}
+
+#[test]
+fn errors_are_injected_at_the_configured_probability() {
+    let mut engine = ChaosEngine::new(Some(7));
+    assert!(!engine.inject_error(None, ChaosTarget::Ingest));
+    assert!(!(0..100).any(|_| engine.inject_error(Some(&ChaosFlags::default()), ChaosTarget::Ingest)));
+
+    let always = ChaosFlags::parse(&json!({"random_error": true, "error_probability": 1.0})).unwrap();
+    assert!((0..100).all(|_| engine.inject_error(Some(&always), ChaosTarget::Ingest)));
+    let never = ChaosFlags { error_probability: 0.0, ..always.clone() };
+    assert!(!(0..100).any(|_| engine.inject_error(Some(&never), ChaosTarget::Ingest)));
+    // Flags built in code are never trusted to be in range
+    let invalid = ChaosFlags { error_probability: f64::NAN, ..always };
+    assert!(!engine.inject_error(Some(&invalid), ChaosTarget::Ingest));
+
+    let default_rate = ChaosFlags::parse(&json!({"random_error": true})).unwrap();
+    let injected = (0..2000).filter(|_| engine.inject_error(Some(&default_rate), ChaosTarget::Ingest)).count();
+    assert!((120..280).contains(&injected), "{}", injected);
+}
+
+#[test]
+fn duplicated_and_shuffled_batches_follow_their_flags() {
+    let flags = ChaosFlags::parse(&json!({"duplicate_probability": 1.0, "shuffle_batch": true})).unwrap();
+    assert_eq!(json!(flags), json!({"duplicate_probability": 1.0, "shuffle_batch": true}));
+    assert!(ChaosFlags::parse(&json!({"duplicate_probability": 1.5})).is_err());
+
+    let mut engine = ChaosEngine::new(Some(5));
+    assert!((0..50).all(|_| engine.duplicate_batch(Some(&flags))));
+    assert!(!(0..50).any(|_| engine.duplicate_batch(Some(&ChaosFlags::default()))));
+    assert_eq!(engine.batch_order(None, 5), None);
+    let mut order = engine.batch_order(Some(&flags), 5).unwrap();
+    order.sort_unstable();
+    assert_eq!(order, [0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn targeted_random_errors_fail_only_the_listed_operations_at_their_own_rate() {
+    let flags = ChaosFlags::parse(&json!({"random_error": {"probability": 1.0, "targets": ["ingest", "ota"]}})).unwrap();
+    assert_eq!(flags.random_error(), Some(RandomErrorInEffect { probability: 1.0, targets: vec![ChaosTarget::Ingest, ChaosTarget::Ota] }));
+    let mut engine = ChaosEngine::new(Some(3));
+    for target in ChaosTarget::ALL {
+        let expected = matches!(target, ChaosTarget::Ingest | ChaosTarget::Ota);
+        assert!((0..50).all(|_| engine.inject_error(Some(&flags), target) == expected), "{:?}", target);
+    }
+
+    // Without targets every operation fails; without a probability, error_probability applies
+    let soak = ChaosFlags::parse(&json!({"random_error": {"probability": 0.5}})).unwrap();
+    assert_eq!(soak.random_error().unwrap().targets, ChaosTarget::ALL.to_vec());
+    let shadow_only = ChaosFlags::parse(&json!({"random_error": {"targets": ["shadow"]}, "error_probability": 0.3})).unwrap();
+    assert_eq!(shadow_only.error_probability_for(ChaosTarget::Shadow), Some(0.3));
+    assert_eq!(shadow_only.error_probability_for(ChaosTarget::Heartbeat), None);
+
+    // Plain true is the original 10% on everything, reported as such
+    let legacy = ChaosFlags::parse(&json!({"random_error": true})).unwrap();
+    assert_eq!(json!(legacy.random_error()), json!({"probability": 0.1, "targets": ["ingest", "heartbeat", "shadow", "ota"]}));
+    assert_eq!(ChaosFlags::default().random_error(), None);
+
+    for malformed in [json!({"probability": 1.2}), json!({"targets": ["upload"]}), json!({"rate": 0.3})] {
+        assert!(ChaosFlags::parse(&json!({"random_error": malformed})).is_err(), "{}", malformed);
+    }
+    let round_trip = json!({"random_error": {"probability": 0.3, "targets": ["heartbeat"]}});
+    assert_eq!(json!(ChaosFlags::parse(&round_trip).unwrap()), round_trip);
+}
+
+#[test]
+fn a_reseeded_engine_and_its_restored_copy_decide_alike() {
+    let flags = ChaosFlags::parse(&json!({"random_error": true, "error_probability": 0.5})).unwrap();
+    let mut engine = ChaosEngine::new(None);
+    let mut restored = ChaosEngine::new(Some(engine.reseed()));
+    let decisions: Vec<bool> = (0..50).map(|_| engine.inject_error(Some(&flags), ChaosTarget::Ingest)).collect();
+    let restored_decisions: Vec<bool> = (0..50).map(|_| restored.inject_error(Some(&flags), ChaosTarget::Ingest)).collect();
+    assert_eq!(decisions, restored_decisions);
+}
+
+#[test]
+fn unparseable_flags_are_rejected_by_a_dry_run_and_dropped_from_a_config_file() {
+    let mut config = Config::from_env_vars(&HashMap::new()).0;
+    config.chaos_flags = Some(ChaosFlags::parse(&json!({"random_error": true})).unwrap());
+    let features = Features::resolve(&config.features);
+    let subject_exporter = super::dry_run_tests::exporter();
+    let runtime = Runtime {
+        config: &config,
+        features: &features,
+        sample_interval_secs: 10,
+        upload_interval_secs: 60,
+        heartbeat_interval_secs: 30,
+        degradation: None,
+        subject_exporter: &subject_exporter,
+    };
+    let result = dry_run::preview(runtime, &json!({"chaos_flags": {"random_error": 1}}), Utc::now());
+    assert!(result.reject.contains_key("chaos_flags"), "{:?}", result);
+    assert!(!result.apply.contains_key("chaos_flags"));
+
+    let mut file = serde_json::to_value(&config).unwrap();
+    file["chaos_flags"] = json!({"error_probability": "often"});
+    let loaded: Config = serde_json::from_value(file).unwrap();
+    assert_eq!(loaded.chaos_flags, None);
+}
+
+#[test]
+fn crashes_arrive_at_the_configured_rate() {
+    let flags = ChaosFlags::parse(&json!({"crash": {"mean_uptime_secs": 600, "dirty": true}})).unwrap();
+    assert_eq!(json!(flags), json!({"crash": {"mean_uptime_secs": 600.0, "dirty": true}}));
+    let crash = flags.crash.as_ref().unwrap();
+    assert!((crash.probability(600.0) - (1.0 - (-1.0f64).exp())).abs() < 1e-9);
+    let hourly = ChaosFlags::parse(&json!({"crash": {"probability_per_hour": 0.5}})).unwrap().crash.unwrap();
+    assert!((hourly.probability(3600.0) - 0.5).abs() < 1e-9);
+    assert!(!hourly.dirty);
+
+    let mut engine = ChaosEngine::new(Some(11));
+    assert!(!(0..100).any(|_| engine.crash(Some(&ChaosFlags::default()), 1.0)));
+    assert!(!engine.crash(None, 1.0));
+    let certain = ChaosFlags::parse(&json!({"crash": {"probability_per_hour": 1.0}})).unwrap();
+    assert!(engine.crash(Some(&certain), 1.0));
+    // One-second checks against a 100 s mean uptime
+    let frequent = ChaosFlags::parse(&json!({"crash": {"mean_uptime_secs": 100}})).unwrap();
+    let crashes = (0..10_000).filter(|_| engine.crash(Some(&frequent), 1.0)).count();
+    assert!((60..140).contains(&crashes), "{}", crashes);
+
+    for malformed in [
+        json!({}),
+        json!({"probability_per_hour": 0.1, "mean_uptime_secs": 60}),
+        json!({"probability_per_hour": 1.5}),
+        json!({"mean_uptime_secs": 0}),
+        json!({"mean_uptime_secs": 60, "graceful": true}),
+    ] {
+        assert!(ChaosFlags::parse(&json!({"crash": malformed})).is_err(), "{}", malformed);
+    }
+}
+
+#[test]
+fn the_crash_count_is_persisted_and_reported_in_heartbeats() {
+    let mut config = Config::from_env_vars(&HashMap::new()).0;
+    assert!(json!(net::heartbeat_body(&config, "1.0.0", 10, 60, 30)).get("chaos_crashes").is_none());
+    config.chaos_crashes = 2;
+    assert_eq!(json!(net::heartbeat_body(&config, "1.0.0", 10, 60, 30))["chaos_crashes"], 2);
+
+    let mut file = serde_json::to_value(&config).unwrap();
+    assert_eq!(serde_json::from_value::<Config>(file.clone()).unwrap().chaos_crashes, 2);
+    // Config files from before the flag have no count
+    file.as_object_mut().unwrap().remove("chaos_crashes");
+    assert_eq!(serde_json::from_value::<Config>(file).unwrap().chaos_crashes, 0);
+}
@@ -0,0 +1,159 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use super::net_tests;
+use crate::codec::{self, Codec, CodecSetting, CompressionConfig};
+use crate::config::Config;
+use crate::stats::ApiStats;
+use crate::types::IngestPayload;
+use crate::{init, net};
+
+const CODECS: [Codec; 3] = [Codec::Gzip, Codec::Zstd, Codec::Brotli];
+
+fn payload_json() -> Vec<u8> {
+    let measurements = (0..100).map(|_| super::generate_measurement("1.0.0".to_string(), &Default::default())).collect();
+    serde_json::to_vec(&IngestPayload { device_id: "device-1".to_string(), measurements, region: None, data_endpoint: None }).unwrap()
+}
+
+fn device_config(backend_url: &str) -> Config {
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), backend_url.to_string()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("DEVICE_ID".to_string(), "device-1".to_string()),
+        ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()),
+    ]);
+    Config::from_env_vars(&env).0
+}
+
+#[test]
+fn each_codec_round_trips_and_shrinks_a_batch() {
+    let json = payload_json();
+    for codec in CODECS {
+        let encoded = codec.encode(&json).unwrap();
+        assert!(encoded.len() * 3 < json.len(), "{:?}: {} bytes encoded to {}", codec, json.len(), encoded.len());
+        assert_eq!(codec.decode(&encoded).unwrap(), json, "{:?}", codec);
+        assert_eq!(codec.as_str().parse::<Codec>().unwrap(), codec);
+    }
+    assert_eq!(Codec::None.decode(&json).unwrap(), json);
+}
+
+#[test]
+fn small_or_incompressible_bodies_are_sent_as_is() {
+    let small = br#"{"device_id":"device-1"}"#.to_vec();
+    assert_eq!(codec::encode_body(Codec::Zstd, 512, small.clone()).unwrap(), (Codec::None, small));
+
+    // Random bytes do not shrink, so the encoding is dropped even above the threshold
+    let noise: Vec<u8> = (0..4096).map(|_| rand::random::<u8>()).collect();
+    for codec in CODECS {
+        assert_eq!(codec::encode_body(codec, 0, noise.clone()).unwrap().0, Codec::None, "{:?}", codec);
+    }
+    let json = payload_json();
+    let (applied, encoded) = codec::encode_body(Codec::Brotli, 512, json.clone()).unwrap();
+    assert_eq!(applied, Codec::Brotli);
+    assert_eq!(Codec::Brotli.decode(&encoded).unwrap(), json);
+}
+
+#[test]
+fn negotiation_picks_the_best_mutual_codec() {
+    let preference = CompressionConfig::default().preference;
+    let advertised = |tokens: &[&str]| tokens.iter().map(|token| token.to_string()).collect::<Vec<_>>();
+    assert_eq!(codec::negotiate(&preference, &advertised(&["gzip", "br"])), Some(Codec::Brotli));
+    assert_eq!(codec::negotiate(&preference, &advertised(&["GZIP", "lz4"])), Some(Codec::Gzip));
+    // Advertised, but nothing in common: send uncompressed
+    assert_eq!(codec::negotiate(&preference, &advertised(&["identity"])), Some(Codec::None));
+
+    // A backend that advertises nothing, or only unknown codecs, gets the fallback
+    assert_eq!(codec::negotiate(&preference, &[]), None);
+    assert_eq!(codec::negotiate(&preference, &advertised(&["lz4"])), None);
+    assert_eq!(CodecSetting::Auto.resolve(None), codec::FALLBACK);
+    assert_eq!(CodecSetting::Fixed(Codec::Zstd).resolve(Some(Codec::Gzip)), Codec::Zstd);
+}
+
+#[test]
+fn compression_settings_parse_from_the_environment() {
+    let env = HashMap::from([("COMPRESSION".to_string(), r#"{"ingest": "zstd", "heartbeat": "auto", "min_bytes": 64}"#.to_string())]);
+    let (config, report) = Config::from_env_vars(&env);
+    assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    assert_eq!(config.compression.ingest, CodecSetting::Fixed(Codec::Zstd));
+    assert_eq!(config.compression.heartbeat, CodecSetting::Auto);
+    assert_eq!(config.compression.shadow, CodecSetting::Fixed(Codec::None));
+    assert_eq!(config.compression.min_bytes, 64);
+    assert_eq!(config.compression.accept_encoding(), "zstd, br, gzip, identity");
+
+    let env = HashMap::from([("COMPRESSION".to_string(), r#"{"ingest": "lz4"}"#.to_string())]);
+    let (config, report) = Config::from_env_vars(&env);
+    assert!(report.warnings[0].contains("Invalid COMPRESSION"));
+    assert_eq!(config.compression, CompressionConfig::default());
+}
+
+async fn register_against(advertised: Option<Value>) -> (Config, MockServer) {
+    let server = MockServer::start().await;
+    let mut response = json!({
+        "device_id": uuid::Uuid::new_v4(),
+        "auth_token": uuid::Uuid::new_v4(),
+        "desired_sample_interval_secs": 10,
+        "desired_upload_interval_secs": 60,
+        "desired_heartbeat_interval_secs": 30,
+    });
+    if let Some(advertised) = advertised {
+        response["supported_encodings"] = advertised;
+    }
+    Mock::given(method("POST")).and(path("/api/devices/register"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(response))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&server)
+        .await;
+    let mut config = device_config(&server.uri());
+    init::register(&reqwest::Client::new(), &mut config, None).await.unwrap();
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests[0].headers.get("accept-encoding").unwrap(), "zstd, br, gzip, identity");
+    (config, server)
+}
+
+#[tokio::test]
+async fn registration_negotiates_and_persists_the_ingest_codec() {
+    let (config, server) = register_against(Some(json!(["gzip", "zstd"]))).await;
+    assert_eq!(config.negotiated_encoding, Some(Codec::Zstd));
+    let path = std::env::temp_dir().join(format!("codec_config_{}.json", uuid::Uuid::new_v4()));
+    std::fs::write(&path, serde_json::to_string(&config).unwrap()).unwrap();
+    assert_eq!(Config::load_from(&path).unwrap().negotiated_encoding, Some(Codec::Zstd));
+    let _ = std::fs::remove_file(&path);
+
+    let measurements: Vec<_> = (0..20).map(|_| super::generate_measurement("1.0.0".to_string(), &Default::default())).collect();
+    let target = crate::residency::target_for(&config, None).unwrap();
+    net::send_ingest(&reqwest::Client::new(), &config, &ApiStats::default(), &target, &measurements, None).await.unwrap();
+    let requests = server.received_requests().await.unwrap();
+    let ingest = requests.iter().find(|request| request.url.path() == "/api/devices/ingest").unwrap();
+    assert_eq!(ingest.headers.get("content-encoding").unwrap(), "zstd");
+    assert_eq!(net_tests::ingest_payload(ingest).measurements.len(), 20);
+}
+
+#[tokio::test]
+async fn backend_advertising_nothing_gets_the_fallback() {
+    let (config, _server) = register_against(None).await;
+    assert_eq!(config.negotiated_encoding, None);
+    assert_eq!(config.compression.ingest.resolve(config.negotiated_encoding), Codec::Gzip);
+}
+
+#[tokio::test]
+async fn compressed_responses_are_decoded() {
+    let server = MockServer::start().await;
+    let shadow = json!({"desired": {"sample_interval_secs": 15}, "reported": {"note": "x".repeat(2000)}});
+    for codec in CODECS {
+        server.reset().await;
+        Mock::given(method("GET")).and(path("/api/devices/device-1/shadow"))
+            .respond_with(ResponseTemplate::new(200)
+                .insert_header("content-encoding", codec.as_str())
+                .set_body_raw(codec.encode(shadow.to_string().as_bytes()).unwrap(), "application/json"))
+            .mount(&server)
+            .await;
+        let fetched = net::fetch_device_shadow(&reqwest::Client::new(), &device_config(&server.uri()), &ApiStats::default()).await.unwrap();
+        assert_eq!(fetched.desired, Some(shadow["desired"].clone()), "{:?}", codec);
+        assert_eq!(fetched.reported, Some(shadow["reported"].clone()), "{:?}", codec);
+    }
+}
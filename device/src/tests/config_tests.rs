@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+use crate::config::{unrecognized_env_vars, Config, ConfigSource};
+
+fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+}
+
+fn source_of(sources: &[(String, ConfigSource)], key: &str) -> Option<ConfigSource> {
+    sources.iter().find(|(k, _)| k == key).map(|(_, source)| *source)
+}
+
+#[test]
+fn unrecognized_prefixed_var_produces_warning() {
+    let env = vars(&[("VF_SAMPEL_INTERVAL_SECS", "5"), ("VF_SAMPLE_INTERVAL_SECS", "7"), ("PATH", "/usr/bin")]);
+    assert_eq!(unrecognized_env_vars(&env), vec!["VF_SAMPEL_INTERVAL_SECS".to_string()]);
+
+    let (_, report) = Config::from_env_vars(&env);
+    assert_eq!(report.warnings.len(), 1);
+    assert!(report.warnings[0].contains("VF_SAMPEL_INTERVAL_SECS"));
+}
+
+#[test]
+fn known_overrides_are_reported_as_env_sourced() {
+    let env = vars(&[("VF_SAMPLE_INTERVAL_SECS", "7"), ("BACKEND_URL", "http://backend:8000")]);
+    let (config, report) = Config::from_env_vars(&env);
+
+    assert_eq!(config.sample_interval_secs, 7);
+    assert_eq!(config.backend_url, "http://backend:8000");
+    assert_eq!(source_of(&report.sources, "SAMPLE_INTERVAL_SECS"), Some(ConfigSource::Env));
+    assert_eq!(source_of(&report.sources, "BACKEND_URL"), Some(ConfigSource::Env));
+    assert_eq!(source_of(&report.sources, "UPLOAD_INTERVAL_SECS"), Some(ConfigSource::Default));
+    assert!(report.warnings.is_empty());
+}
+
+#[test]
+fn unparseable_value_falls_back_to_default_with_warning() {
+    let env = vars(&[("UPLOAD_INTERVAL_SECS", "sixty")]);
+    let (config, report) = Config::from_env_vars(&env);
+
+    assert_eq!(config.upload_interval_secs, 60);
+    assert_eq!(source_of(&report.sources, "UPLOAD_INTERVAL_SECS"), Some(ConfigSource::Default));
+    assert_eq!(report.warnings.len(), 1);
+}
+
+#[test]
+fn firmware_behavior_table_parses_from_env() {
+    let env = vars(&[("FIRMWARE_BEHAVIORS", r#"{"1.2.0": {"clock_skew_secs": 30, "omitted_fields": ["speed"]}}"#)]);
+    let (config, report) = Config::from_env_vars(&env);
+
+    let behavior = &config.firmware_behaviors["1.2.0"];
+    assert_eq!(behavior.clock_skew_secs, 30);
+    assert_eq!(behavior.noise_scale, 1.0);
+    assert_eq!(behavior.omitted_fields, vec!["speed".to_string()]);
+    assert!(report.warnings.is_empty());
+}
+
+#[test]
+fn an_interrupted_save_leaves_the_saved_config_intact() {
+    let dir = std::env::temp_dir().join(format!("config_save_{}", uuid::Uuid::new_v4()));
+    let path = dir.join("device_config.json");
+    let config = Config { device_id: "device-1".to_string(), ..Config::from_env_vars(&HashMap::new()).0 };
+    config.save_to(&path).unwrap();
+    assert!(!dir.join("device_config.json.tmp").exists());
+
+    // A crash halfway through the next save leaves only its staged half behind
+    std::fs::write(dir.join("device_config.json.tmp"), "{\"device_id\": \"dev").unwrap();
+    assert_eq!(Config::load_from(&path).unwrap().device_id, "device-1");
+    Config { device_id: "device-2".to_string(), ..config }.save_to(&path).unwrap();
+    assert_eq!(Config::load_from(&path).unwrap().device_id, "device-2");
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,183 @@
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use wiremock::matchers::{header, method, path, path_regex, query_param};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+use super::net_tests;
+use crate::conformance::{self, ConformanceArgs, ConformanceReport, Outcome};
+
+// The shadow endpoint of the mock backend, merging reported patches or replacing the
+// reported state with each
+struct Shadow {
+    reported: Mutex<Value>,
+    merge: bool,
+}
+
+impl Respond for Shadow {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let mut reported = self.reported.lock().unwrap();
+        if request.method.as_str() == "PATCH" {
+            let patch = serde_json::from_slice::<Value>(&net_tests::decoded_body(request)).unwrap()["state"].clone();
+            match (self.merge, reported.as_object_mut()) {
+                (true, Some(current)) => current.extend(patch.as_object().unwrap().clone()),
+                _ => *reported = patch,
+            }
+            return ResponseTemplate::new(200);
+        }
+        ResponseTemplate::new(200).set_body_json(json!({"desired": {}, "reported": *reported}))
+    }
+}
+
+// Answers the first `allowed` heartbeats, then throttles
+struct Throttle {
+    count: AtomicUsize,
+    allowed: usize,
+}
+
+impl Respond for Throttle {
+    fn respond(&self, _: &Request) -> ResponseTemplate {
+        if self.count.fetch_add(1, Ordering::SeqCst) < self.allowed {
+            return ResponseTemplate::new(200).set_body_json(json!({
+                "desired_version": null,
+                "desired_sample_interval_secs": 10,
+                "desired_upload_interval_secs": 60,
+                "desired_heartbeat_interval_secs": 30,
+            }));
+        }
+        ResponseTemplate::new(429).insert_header("Retry-After", "1")
+    }
+}
+
+async fn mock_backend(merge: bool, heartbeats_allowed: usize) -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/register"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "device_id": uuid::Uuid::new_v4(),
+            "auth_token": uuid::Uuid::new_v4(),
+            "desired_sample_interval_secs": 10,
+            "desired_upload_interval_secs": 60,
+            "desired_heartbeat_interval_secs": 30,
+            "supported_encodings": ["gzip"],
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST")).and(path("/api/devices/heartbeat"))
+        .respond_with(Throttle { count: AtomicUsize::new(0), allowed: heartbeats_allowed })
+        .mount(&server)
+        .await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+    Mock::given(path_regex(r"^/api/devices/[^/]+/shadow$"))
+        .respond_with(Shadow { reported: Mutex::new(json!({})), merge })
+        .mount(&server)
+        .await;
+    Mock::given(method("GET")).and(path("/api/firmware/latest")).and(query_param("current_version", "999999.0.0"))
+        .respond_with(ResponseTemplate::new(204))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET")).and(path("/api/firmware/latest"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "version": "1.2.0",
+            "checksum": "a".repeat(64),
+            "url": format!("{}/firmware/1.2.0.bin", server.uri()),
+        })))
+        .mount(&server)
+        .await;
+    server
+}
+
+fn outcome(report: &ConformanceReport, capability: &str, check: &str) -> Outcome {
+    report.capabilities.iter()
+        .find(|report| report.capability == capability)
+        .and_then(|report| report.checks.iter().find(|result| result.name == check))
+        .unwrap_or_else(|| panic!("no {}.{} in {:?}", capability, check, report))
+        .outcome
+}
+
+#[test]
+fn arguments_parse() {
+    let args: Vec<String> = ["--backend-url", "http://backend", "--burst", "5"].iter().map(|arg| arg.to_string()).collect();
+    let parsed = ConformanceArgs::parse(&args).unwrap();
+    assert_eq!(parsed.backend_url.as_deref(), Some("http://backend"));
+    assert_eq!(parsed.burst, Some(5));
+    assert!(ConformanceArgs::parse(&["--burst".to_string(), "many".to_string()]).is_err());
+    assert!(ConformanceArgs::parse(&["--backend-url".to_string()]).is_err());
+    assert!(ConformanceArgs::parse(&["--verbose".to_string()]).is_err());
+}
+
+#[tokio::test]
+async fn a_conforming_backend_passes_every_check() {
+    // Two heartbeats and the gzip one get through, the burst is throttled
+    let server = mock_backend(true, 3).await;
+    let report = conformance::run_suite(&reqwest::Client::new(), &server.uri(), None, 5).await;
+    assert!(report.passed, "{:#?}", report);
+    for capability in &report.capabilities {
+        assert_eq!(capability.outcome, Outcome::Pass, "{:#?}", capability);
+    }
+
+    // The schema the report is printed with
+    let printed = serde_json::to_value(&report).unwrap();
+    assert_eq!(printed["backend_url"], server.uri());
+    assert_eq!(printed["passed"], true);
+    assert!(printed["device_id"].is_string());
+    let capabilities: Vec<&str> = printed["capabilities"].as_array().unwrap().iter().map(|capability| capability["capability"].as_str().unwrap()).collect();
+    assert_eq!(capabilities, ["registration", "heartbeat", "ingest", "shadow", "firmware", "error_handling"]);
+    let retry_after = &printed["capabilities"][5]["checks"][1];
+    assert_eq!(retry_after["name"], "retry_after");
+    assert_eq!(retry_after["outcome"], "pass");
+    assert_eq!(retry_after["observed"][0], json!({"status": 429, "retry_after": "1"}));
+    assert_eq!(printed["capabilities"][4]["checks"][1]["observed"]["version"], "1.2.0");
+
+    // The gzip probe really went compressed
+    let requests = server.received_requests().await.unwrap();
+    assert!(requests.iter().any(|request| request.headers.get("content-encoding").is_some_and(|encoding| encoding == "gzip")));
+}
+
+#[tokio::test]
+async fn deviations_fail_or_skip_their_checks() {
+    // Replaces the reported state, never throttles, refuses compressed heartbeats and publishes no firmware
+    let server = mock_backend(false, usize::MAX).await;
+    Mock::given(method("POST")).and(path("/api/devices/heartbeat")).and(header("content-encoding", "gzip"))
+        .respond_with(ResponseTemplate::new(415).set_body_string("unsupported encoding"))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET")).and(path("/api/firmware/latest"))
+        .respond_with(ResponseTemplate::new(204))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+
+    let report = conformance::run_suite(&reqwest::Client::new(), &server.uri(), None, 5).await;
+    assert!(!report.passed);
+    assert_eq!(outcome(&report, "shadow", "round_trip"), Outcome::Pass);
+    assert_eq!(outcome(&report, "shadow", "partial_patch_merges"), Outcome::Fail);
+    assert_eq!(outcome(&report, "error_handling", "gzip_body"), Outcome::Fail);
+    assert_eq!(outcome(&report, "error_handling", "retry_after"), Outcome::Skipped);
+    assert_eq!(outcome(&report, "firmware", "no_update"), Outcome::Pass);
+    assert_eq!(outcome(&report, "firmware", "metadata"), Outcome::Skipped);
+    assert_eq!(outcome(&report, "heartbeat", "all_fields"), Outcome::Pass);
+
+    let gzip = serde_json::to_value(&report).unwrap()["capabilities"][5]["checks"][0].clone();
+    assert_eq!(gzip["observed"]["status"], 415);
+    assert_eq!(gzip["observed"]["body"], "unsupported encoding");
+}
+
+#[tokio::test]
+async fn a_refused_registration_skips_the_rest() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/register"))
+        .respond_with(ResponseTemplate::new(403))
+        .mount(&server)
+        .await;
+    let report = conformance::run_suite(&reqwest::Client::new(), &server.uri(), Some("wrong".to_string()), 5).await;
+    assert!(!report.passed);
+    assert_eq!(report.device_id, None);
+    assert_eq!(outcome(&report, "registration", "register"), Outcome::Fail);
+    assert_eq!(report.capabilities[0].checks[0].observed, json!({"status": 403}));
+    assert!(report.capabilities[1..].iter().all(|capability| capability.outcome == Outcome::Skipped));
+}
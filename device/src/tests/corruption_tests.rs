@@ -0,0 +1,65 @@
+use serde_json::{json, Value};
+
+use crate::chaos::ChaosEngine;
+use crate::corruption::{self, CorruptPayload, CorruptionMode};
+use crate::types::ChaosFlags;
+
+fn payload() -> Value {
+    json!({
+        "device_id": "device-1",
+        "measurements": [
+            {"timestamp": "2024-05-01T12:00:00Z", "temp": 21.5, "humidity": 40.0, "battery": 0.9, "sequence_number": 1},
+            {"timestamp": "2024-05-01T12:00:10Z", "temp": 21.6, "humidity": 40.1, "battery": 0.9, "sequence_number": 2},
+        ],
+    })
+}
+
+fn first_measurement(body: &[u8]) -> Value {
+    serde_json::from_slice::<Value>(body).unwrap()["measurements"][0].clone()
+}
+
+#[test]
+fn each_mode_mangles_the_payload_its_own_way() {
+    let truncated = corruption::corrupt(&payload(), CorruptionMode::Truncate);
+    assert!(serde_json::from_slice::<Value>(&truncated).is_err());
+    assert!(truncated.starts_with(br#"{"device_id""#));
+
+    assert_eq!(first_measurement(&corruption::corrupt(&payload(), CorruptionMode::Type))["temp"], "warm");
+    assert_eq!(first_measurement(&corruption::corrupt(&payload(), CorruptionMode::Absurd))["temp"], 9999.0);
+    let missing = first_measurement(&corruption::corrupt(&payload(), CorruptionMode::MissingField));
+    assert!(missing.get("timestamp").is_none());
+    assert_eq!(missing["sequence_number"], 1);
+
+    // Only the first measurement is touched
+    let body: Value = serde_json::from_slice(&corruption::corrupt(&payload(), CorruptionMode::Type)).unwrap();
+    assert_eq!(body["measurements"][1], payload()["measurements"][1]);
+}
+
+#[test]
+fn the_flag_is_parsed_and_range_checked() {
+    let flags = ChaosFlags::parse(&json!({"corrupt_payload": {"probability": 0.5}})).unwrap();
+    assert_eq!(flags.corrupt_payload, Some(CorruptPayload { probability: 0.5, mode: CorruptionMode::Random }));
+    assert_eq!(json!(flags), json!({"corrupt_payload": {"probability": 0.5, "mode": "random"}}));
+    for malformed in [
+        json!({"corrupt_payload": {"probability": 1.5}}),
+        json!({"corrupt_payload": {"probability": 0.5, "mode": "scramble"}}),
+        json!({"corrupt_payload": {"mode": "truncate"}}),
+    ] {
+        assert!(ChaosFlags::parse(&malformed).is_err(), "{}", malformed);
+    }
+}
+
+#[test]
+fn random_corruption_draws_every_mode() {
+    let flags = ChaosFlags::parse(&json!({"corrupt_payload": {"probability": 1.0, "mode": "random"}})).unwrap();
+    let mut engine = ChaosEngine::new(Some(5));
+    let mut drawn: Vec<CorruptionMode> = (0..100).filter_map(|_| engine.corrupt_payload(Some(&flags))).collect();
+    assert_eq!(drawn.len(), 100);
+    drawn.sort_by_key(|mode| mode.as_str());
+    drawn.dedup();
+    assert_eq!(drawn, [CorruptionMode::Absurd, CorruptionMode::MissingField, CorruptionMode::Truncate, CorruptionMode::Type]);
+
+    let flags = ChaosFlags::parse(&json!({"corrupt_payload": {"probability": 0.0, "mode": "truncate"}})).unwrap();
+    assert!((0..100).all(|_| engine.corrupt_payload(Some(&flags)).is_none()));
+    assert_eq!(engine.corrupt_payload(None), None);
+}
@@ -0,0 +1,92 @@
+use chrono::{DateTime, Utc};
+
+use crate::cost::{self, CostConfig, CostModel};
+use crate::stats::ApiStats;
+use crate::storage;
+
+fn at(timestamp: &str) -> DateTime<Utc> {
+    timestamp.parse().unwrap()
+}
+
+fn prices() -> CostConfig {
+    CostConfig {
+        currency: "USD".to_string(),
+        per_mb_uploaded: 0.5,
+        per_request: 0.0001,
+        per_firmware_download: 0.02,
+        per_measurement_day: 0.0024,
+    }
+}
+
+fn temp_db() -> rusqlite::Connection {
+    storage::init_at(&std::env::temp_dir().join(format!("cost_{}.db", uuid::Uuid::new_v4()))).unwrap()
+}
+
+#[test]
+fn scripted_traffic_is_priced_per_month() {
+    let conn = temp_db();
+    let stats = ApiStats::default();
+    // History from before the model was enabled is not billed
+    stats.record_attempt("ingest", 5_000);
+    let mut model = CostModel::load(prices(), &conn, &stats.cumulative()).unwrap();
+    model.accrue(at("2026-01-31T23:00:00Z"), &stats.cumulative(), 100);
+
+    for _ in 0..10 {
+        stats.record_attempt("ingest", 100_000);
+        stats.record_success("ingest", 0);
+    }
+    stats.record_attempt("firmware_download", 200);
+    stats.record_success("firmware_download", 4_096);
+    // Two hours across the month boundary with 100 measurements held throughout
+    model.accrue(at("2026-02-01T01:00:00Z"), &stats.cumulative(), 100);
+
+    let report = model.report();
+    let january = &report["months"]["2026-01"];
+    assert_eq!(january["usage"]["measurement_secs"], 360_000);
+    assert_eq!(january["usage"]["requests"], 0);
+    assert_eq!(january["cost_micros"]["storage"], 10_000);
+    assert_eq!(january["cost"]["total"], "$0.01");
+
+    let february = &report["months"]["2026-02"];
+    assert_eq!(february["usage"]["bytes_uploaded"], 1_000_200);
+    assert_eq!(february["usage"]["requests"], 11);
+    assert_eq!(february["usage"]["firmware_downloads"], 1);
+    assert_eq!(february["cost_micros"]["upload"], 500_100);
+    assert_eq!(february["cost_micros"]["requests"], 1_100);
+    assert_eq!(february["cost_micros"]["firmware_downloads"], 20_000);
+    assert_eq!(february["cost_micros"]["storage"], 10_000);
+    assert_eq!(february["cost_micros"]["total"], 531_200);
+    assert_eq!(february["cost"]["total"], "$0.53");
+
+    assert_eq!(report["total"]["cost_micros"]["total"], 541_200);
+    assert_eq!(report["total"]["cost"]["upload"], "$0.50");
+}
+
+#[test]
+fn usage_survives_a_restart_without_double_billing() {
+    let conn = temp_db();
+    let stats = ApiStats::default();
+    let mut model = CostModel::load(prices(), &conn, &stats.cumulative()).unwrap();
+    model.accrue(at("2026-12-31T12:00:00Z"), &stats.cumulative(), 1);
+    stats.record_attempt("heartbeat", 300);
+    model.accrue(at("2027-01-01T12:00:00Z"), &stats.cumulative(), 1);
+    model.checkpoint(&conn).unwrap();
+
+    let mut restored = CostModel::load(prices(), &conn, &stats.cumulative()).unwrap();
+    restored.accrue(at("2027-01-01T12:00:00Z"), &stats.cumulative(), 1);
+    let report = restored.report();
+    // The year boundary splits storage evenly; the request is only charged once
+    assert_eq!(report["months"]["2026-12"]["usage"]["measurement_secs"], 43_200);
+    assert_eq!(report["months"]["2027-01"]["usage"]["measurement_secs"], 43_200);
+    assert_eq!(report["total"]["usage"]["requests"], 1);
+    assert_eq!(report["total"]["usage"]["bytes_uploaded"], 300);
+}
+
+#[test]
+fn amounts_are_formatted_to_cents() {
+    assert_eq!(cost::format_amount(1_234_567_890, "USD"), "$1,234.57");
+    assert_eq!(cost::format_amount(1_000_000_000_000, "eur"), "€1,000,000.00");
+    assert_eq!(cost::format_amount(5_000, "GBP"), "£0.01");
+    assert_eq!(cost::format_amount(4_999, "USD"), "$0.00");
+    assert_eq!(cost::format_amount(120_000, "CHF"), "0.12 CHF");
+}
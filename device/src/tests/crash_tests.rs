@@ -0,0 +1,73 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use tracing_subscriber::prelude::*;
+use wiremock::matchers::{method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::config::Config;
+use crate::crash;
+use crate::stats::ApiStats;
+
+fn device_config(backend_url: &str) -> Config {
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), backend_url.to_string()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("DEVICE_ID".to_string(), "device-1".to_string()),
+        ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()),
+    ]);
+    Config::from_env_vars(&env).0
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn a_panicking_worker_leaves_a_snapshot_that_the_next_start_reports_and_archives() {
+    let dir = std::env::temp_dir().join(format!("crash_{}", uuid::Uuid::new_v4()));
+    crash::install(&dir, "device-1").unwrap();
+    tracing::subscriber::with_default(tracing_subscriber::registry().with(crash::EventLayer), || {
+        tracing::info!(backlog = 42, "Uploading \"batch\"");
+    });
+    let state = json!({"backlog": {"pending": 42}, "state": {"ota_status": "Idle"}});
+    crash::update_state(&state);
+
+    let worker = tokio::spawn(async { panic!("deliberate worker panic") });
+    assert!(worker.await.unwrap_err().is_panic());
+
+    let snapshots = crash::pending_snapshots(&dir).unwrap();
+    assert_eq!(snapshots.len(), 1);
+    let snapshot: Value = serde_json::from_slice(&std::fs::read(&snapshots[0]).unwrap()).unwrap();
+    assert_eq!(snapshot["schema_version"], json!(crash::SNAPSHOT_SCHEMA_VERSION));
+    assert_eq!(snapshot["device_id"], json!("device-1"));
+    assert_eq!(snapshot["message"], json!("deliberate worker panic"));
+    assert!(snapshot["location"]["file"].as_str().unwrap().ends_with("crash_tests.rs"));
+    assert!(snapshot["location"]["line"].is_u64());
+    assert!(snapshot["thread"].as_str().unwrap().contains("worker"), "{}", snapshot["thread"]);
+    assert!(snapshot["panicked_at_ms"].as_i64().unwrap() >= snapshot["state_updated_at_ms"].as_i64().unwrap());
+    assert_eq!(snapshot["state"], state);
+    let events = snapshot["events"].as_array().unwrap();
+    assert_eq!(events.last().unwrap()["level"], json!("INFO"));
+    assert_eq!(events.last().unwrap()["text"], json!("Uploading \"batch\" backlog=42"));
+
+    // Next start: the snapshot is uploaded, the crash reported with it attached, and the file archived
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/device-1/files")).and(query_param("purpose", "crash_report"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"file_id": "file-9", "sha256": "", "bytes": 0, "purpose": "crash_report"})))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST")).and(path("/api/devices/device-1/errors"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let config = device_config(&server.uri());
+    let reported = crash::report_pending(&reqwest::Client::new(), &config, &ApiStats::default(), &dir, "0.1.0").await.unwrap();
+    assert_eq!(reported, 1);
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests[0].body, std::fs::read(dir.join("archived").join(snapshots[0].file_name().unwrap())).unwrap());
+    let error: Value = serde_json::from_slice(&requests[1].body).unwrap();
+    assert_eq!(error["error_code"], json!("panic"));
+    let message = error["error_message"].as_str().unwrap();
+    assert!(message.starts_with("deliberate worker panic at ") && message.contains("file-9"), "{}", message);
+    assert!(crash::pending_snapshots(&dir).unwrap().is_empty());
+    let _ = std::fs::remove_dir_all(&dir);
+}
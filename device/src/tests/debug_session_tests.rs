@@ -0,0 +1,162 @@
+use chrono::{Duration, TimeZone, Utc};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::config::Config;
+use crate::debug_session::{self, DebugEvent, DebugScope, DebugSession, DebugSink, DebugStream};
+use crate::net;
+use crate::stats::ApiStats;
+
+fn settings(sample: u64, heartbeat: u64) -> BTreeMap<String, u64> {
+    BTreeMap::from([
+        ("sample_interval_secs".to_string(), sample),
+        ("heartbeat_interval_secs".to_string(), heartbeat),
+    ])
+}
+
+fn event(message: &str) -> DebugEvent {
+    DebugEvent { at: Utc::now(), level: "DEBUG".to_string(), target: "device".to_string(), message: message.to_string() }
+}
+
+#[test]
+fn ending_a_session_restores_every_setting_it_replaced() {
+    let now = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+    let mut state = None;
+    let desired = json!({"duration_secs": 900, "scopes": ["logs", "sampling", "heartbeat"]});
+
+    let elevated = debug_session::request(&mut state, &desired, &settings(60, 30), now).unwrap();
+    assert_eq!(elevated, Some(settings(1, 10)));
+    assert!(debug_session::covers(state.as_ref(), DebugScope::Logs, now));
+    assert!(!debug_session::covers(state.as_ref(), DebugScope::Audit, now));
+
+    // A control-plane change during the session waits for its end; unheld settings go through
+    let during = now + Duration::seconds(300);
+    assert!(debug_session::defer(&mut state, "sample_interval_secs", 120, during));
+    assert!(!debug_session::defer(&mut state, "upload_interval_secs", 90, during));
+    assert_eq!(debug_session::end_if_due(&mut state, during), None);
+
+    let expiry = now + Duration::seconds(900);
+    assert_eq!(debug_session::end_if_due(&mut state, expiry), Some(settings(120, 30)));
+    assert!(!state.as_ref().is_some_and(|session| session.is_active(expiry)));
+    assert_eq!(debug_session::end_if_due(&mut state, expiry), None);
+    assert!(!debug_session::defer(&mut state, "sample_interval_secs", 5, expiry));
+
+    // The same request left in the shadow does not start it again
+    assert_eq!(debug_session::request(&mut state, &desired, &settings(120, 30), expiry).unwrap(), None);
+    assert!(!state.as_ref().is_some_and(|session| session.is_active(expiry)));
+}
+
+#[test]
+fn overlapping_requests_extend_the_session_instead_of_stacking() {
+    let now = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+    let mut state = None;
+    debug_session::request(&mut state, &json!({"duration_secs": 900, "scopes": ["sampling"]}), &settings(60, 30), now).unwrap();
+
+    // Current values are elevated now; the second request must not save them as the originals
+    let later = now + Duration::seconds(600);
+    let elevated = debug_session::request(&mut state, &json!({"duration_secs": 900, "scopes": ["heartbeat"]}), &settings(1, 30), later).unwrap();
+    assert_eq!(elevated, Some(settings(1, 10)));
+    let session = state.clone().unwrap();
+    assert_eq!(session.started_at, now);
+    assert_eq!(session.expires_at, later + Duration::seconds(900));
+    assert_eq!(session.scopes.len(), 2);
+
+    // A shorter request never cuts the session short
+    debug_session::request(&mut state, &json!({"duration_secs": 60, "id": "short"}), &settings(1, 10), later).unwrap();
+    assert_eq!(state.as_ref().unwrap().expires_at, later + Duration::seconds(900));
+
+    assert_eq!(debug_session::end_if_due(&mut state, later + Duration::seconds(900)), Some(settings(60, 30)));
+}
+
+#[test]
+fn a_session_that_expired_while_down_is_ended_at_startup() {
+    let now = Utc::now();
+    let mut state = None;
+    debug_session::request(&mut state, &json!({"duration_secs": 60}), &settings(10, 30), now).unwrap();
+    let restored: Option<DebugSession> = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+    assert_eq!(restored, state);
+
+    let mut resumed = restored.clone();
+    assert_eq!(debug_session::end_if_due(&mut resumed, now + Duration::seconds(30)), None);
+    assert_eq!(resumed.as_ref().unwrap().elevated(&settings(1, 10)), settings(1, 10));
+
+    let mut expired = restored;
+    assert_eq!(debug_session::end_if_due(&mut expired, now + Duration::hours(2)), Some(settings(10, 30)));
+}
+
+#[test]
+fn malformed_requests_are_rejected() {
+    for raw in [json!({"duration_secs": 0}), json!({"duration_secs": 999_999}), json!({"duration_secs": 60, "scopes": []}), json!({"duration_secs": 60, "scopes": ["everything"]}), json!(true)] {
+        let mut state = None;
+        assert!(debug_session::request(&mut state, &raw, &settings(10, 30), Utc::now()).is_err(), "{}", raw);
+        assert!(state.is_none());
+    }
+}
+
+#[tokio::test]
+async fn a_full_debug_queue_drops_events_without_blocking() {
+    let (sink, mut receiver) = DebugSink::new(2);
+    let offered: Vec<bool> = (0..5).map(|i| sink.offer(event(&format!("event {}", i)))).collect();
+    assert_eq!(offered, vec![true, true, false, false, false]);
+    assert_eq!(sink.report(), json!({"queued": 2, "sent": 0, "dropped": 3}));
+
+    // The oldest events are kept; room frees up as they are shipped
+    assert_eq!(receiver.recv().await.unwrap().message, "event 0");
+    assert!(sink.offer(event("event 5")));
+    assert_eq!(receiver.recv().await.unwrap().message, "event 1");
+    assert_eq!(receiver.recv().await.unwrap().message, "event 5");
+}
+
+#[tokio::test]
+async fn debug_events_are_posted_in_one_batch() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/device-1/debug"))
+        .respond_with(ResponseTemplate::new(202))
+        .mount(&server)
+        .await;
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("DEVICE_ID".to_string(), "device-1".to_string()),
+    ]);
+    let config = Config::from_env_vars(&env).0;
+
+    let events = vec![event("first"), event("second")];
+    net::send_debug_events(&reqwest::Client::new(), &config, &ApiStats::default(), &events).await.unwrap();
+    let requests = server.received_requests().await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&requests[0].body).unwrap();
+    assert_eq!(serde_json::from_value::<Vec<DebugEvent>>(body["events"].clone()).unwrap(), events);
+}
+
+#[tokio::test]
+async fn an_offline_device_drops_its_debug_events_instead_of_posting_them() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/device-offline/debug"))
+        .respond_with(ResponseTemplate::new(202))
+        .mount(&server)
+        .await;
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("DEVICE_ID".to_string(), "device-offline".to_string()),
+    ]);
+    let config = Config::from_env_vars(&env).0;
+    let _subscriber = tracing::subscriber::set_default(Registry::default().with(debug_session::EventLayer));
+
+    let stream = DebugStream::spawn(reqwest::Client::new(), config, ApiStats::default());
+    stream.set_offline(true);
+    tracing::debug!(device_id = "device-offline", "while offline");
+    for _ in 0..100 {
+        if stream.report()["dropped"] == 1 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(stream.report(), json!({"queued": 1, "sent": 0, "dropped": 1}));
+    stream.close(std::time::Duration::from_secs(5)).await;
+    assert!(server.received_requests().await.unwrap().is_empty());
+}
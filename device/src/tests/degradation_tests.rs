@@ -0,0 +1,102 @@
+use serde_json::json;
+use std::collections::BTreeMap;
+
+use crate::degradation::{Curve, Degradation, DegradationConfig, Lifetime, SensorDegradationConfig};
+use crate::storage;
+
+const HOUR: f64 = 3600.0;
+
+fn sensor(lifetime: Lifetime, curve: Curve) -> SensorDegradationConfig {
+    SensorDegradationConfig { lifetime, curve, max_noise: 0.0, max_bias: 2.0, max_dropout: 0.0 }
+}
+
+fn config(sensors: &[(&str, SensorDegradationConfig)]) -> DegradationConfig {
+    DegradationConfig { seed: 7, sensors: sensors.iter().map(|(name, s)| (name.to_string(), s.clone())).collect::<BTreeMap<_, _>>() }
+}
+
+fn round(value: f64) -> f64 {
+    (value * 1000.0).round() / 1000.0
+}
+
+#[test]
+fn health_follows_curve_and_fails_at_lifetime() {
+    let mut model = Degradation::new(config(&[
+        ("temp", sensor(Lifetime::Fixed { hours: 100.0 }, Curve::Linear)),
+        ("gps", sensor(Lifetime::Fixed { hours: 100.0 }, Curve::Exponential)),
+    ]));
+
+    let mut temp_trajectory = vec![round(model.health("temp").unwrap())];
+    let mut gps_trajectory = vec![round(model.health("gps").unwrap())];
+    let mut failures = Vec::new();
+    for step in 1..=10 {
+        failures.extend(model.advance(10.0 * HOUR).into_iter().map(|sensor| (step, sensor)));
+        temp_trajectory.push(round(model.health("temp").unwrap()));
+        gps_trajectory.push(round(model.health("gps").unwrap()));
+    }
+
+    assert_eq!(temp_trajectory, [1.0, 0.9, 0.8, 0.7, 0.6, 0.5, 0.4, 0.3, 0.2, 0.1, 0.0]);
+    // Exponential wear stays healthy for longer, then drops quickly
+    assert!(gps_trajectory[5] > 0.85 && gps_trajectory[9] < 0.5);
+    // Both fail exactly at the simulated age of 100 hours
+    assert_eq!(failures, [(10, "gps".to_string()), (10, "temp".to_string())]);
+    assert!(model.advance(HOUR).is_empty());
+}
+
+#[test]
+fn failed_sensors_emit_faulted_values_until_replaced() {
+    let mut model = Degradation::new(config(&[
+        ("temp", sensor(Lifetime::Fixed { hours: 10.0 }, Curve::Linear)),
+        ("gps", sensor(Lifetime::Fixed { hours: 10.0 }, Curve::Linear)),
+    ]));
+    let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    measurement.temp = 20.0;
+    model.apply(&mut measurement);
+    assert_eq!(measurement.temp, 20.0);
+
+    model.advance(5.0 * HOUR);
+    let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    measurement.temp = 20.0;
+    model.apply(&mut measurement);
+    // Half-worn sensor drifts by half of max_bias
+    assert_eq!((measurement.temp - 20.0).abs(), 1.0);
+    let stuck_at = measurement.temp;
+
+    model.advance(5.0 * HOUR);
+    let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    model.apply(&mut measurement);
+    assert_eq!(measurement.temp, stuck_at);
+    assert!(measurement.latitude.is_none() && measurement.speed.is_none());
+    assert_eq!(measurement.device_flags, Some(vec!["temp_faulted".to_string(), "gps_faulted".to_string()]));
+
+    let command = json!({"id": "job-1", "sensors": ["temp"]});
+    assert_eq!(model.replace(&command).unwrap(), ["temp".to_string()]);
+    assert_eq!(model.health("temp"), Some(1.0));
+    assert_eq!(model.health("gps"), Some(0.0));
+    // The same command left in the desired state is not applied twice
+    model.advance(HOUR);
+    assert!(model.replace(&command).unwrap().is_empty());
+    assert_eq!(round(model.health("temp").unwrap()), 0.9);
+}
+
+#[test]
+fn seeded_lifetimes_and_progress_survive_restart() {
+    let settings = config(&[("humidity", sensor(Lifetime::Weibull { scale_hours: 50.0, shape: 3.0 }, Curve::Linear))]);
+    let db_path = std::env::temp_dir().join(format!("degradation_{}.db", uuid::Uuid::new_v4()));
+    let conn = storage::init_at(&db_path).unwrap();
+
+    let mut model = Degradation::load(settings.clone(), &conn).unwrap();
+    model.advance(10.0 * HOUR);
+    let health = model.health("humidity").unwrap();
+    assert!(health < 1.0);
+    // Same seed, same lifetime draw
+    let mut twin = Degradation::new(settings.clone());
+    twin.advance(10.0 * HOUR);
+    assert_eq!(twin.health("humidity"), Some(health));
+
+    model.checkpoint(&conn).unwrap();
+    drop(conn);
+    let conn = storage::init_at(&db_path).unwrap();
+    let restored = Degradation::load(settings, &conn).unwrap();
+    assert_eq!(restored.health("humidity"), Some(health));
+    let _ = std::fs::remove_file(&db_path);
+}
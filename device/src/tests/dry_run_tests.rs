@@ -0,0 +1,119 @@
+use chrono::{Duration, Utc};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::config::Config;
+use crate::degradation::{Curve, Degradation, DegradationConfig, Lifetime, SensorDegradationConfig};
+use crate::dry_run::{self, Runtime};
+use crate::export::SubjectExporter;
+use crate::features::Features;
+use crate::push::{KeepaliveConfig, PushChannel};
+use crate::storage;
+use crate::types::ChaosFlags;
+
+// An exporter over a fresh store; a dry run never touches the store
+pub(super) fn exporter() -> SubjectExporter {
+    let dir = std::env::temp_dir().join(format!("dry_run_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let conn = storage::init_at(&dir.join("device.db")).unwrap();
+    SubjectExporter::load(&conn, dir.join("exports")).unwrap()
+}
+
+#[test]
+fn a_dry_run_reports_each_key_and_changes_nothing() {
+    let mut config = Config::from_env_vars(&HashMap::new()).0;
+    config.chaos_flags = Some(ChaosFlags::parse(&json!({"packet_loss": 0.1})).unwrap());
+    let features = Features::resolve(&config.features);
+    let subject_exporter = exporter();
+    let (config_before, features_before) = (serde_json::to_value(&config).unwrap(), features.report());
+    let desired = json!({
+        "dry_run": true,
+        "version": 7,
+        "chaos_flags": {"packet_loss": 0.1},
+        "sample_interval_secs": 5,
+        "upload_interval_secs": 60,
+        "heartbeat_interval_secs": 0,
+        "device_name": "  kitchen  ",
+        "network": "lte",
+        "geo_buckets": {"precision": 40},
+        "features": {"schema_filter": false, "external_source": false, "warp_drive": true},
+        "config_txn": {"id": "t1", "changes": {"upload_batch_size": 50, "upload_interval_secs": 120}},
+    });
+    assert!(dry_run::requested(&desired));
+
+    let runtime = Runtime {
+        config: &config,
+        features: &features,
+        sample_interval_secs: 10,
+        upload_interval_secs: 60,
+        heartbeat_interval_secs: 30,
+        degradation: None,
+        subject_exporter: &subject_exporter,
+    };
+    let result = dry_run::preview(runtime, &desired, Utc::now());
+
+    assert_eq!(result.apply.keys().collect::<Vec<_>>(), [
+        "config_txn.upload_batch_size", "config_txn.upload_interval_secs", "feature.schema_filter", "network", "sample_interval_secs",
+    ]);
+    assert_eq!(result.apply["sample_interval_secs"], json!(5));
+    assert_eq!(result.clamp, BTreeMap::from([("device_name".to_string(), json!("kitchen"))]));
+    assert_eq!(result.defer, BTreeMap::from([("feature.external_source".to_string(), json!(false))]));
+    assert_eq!(result.reject.keys().collect::<Vec<_>>(), ["features", "geo_buckets", "heartbeat_interval_secs"]);
+    assert!(result.reject["features"].contains("warp_drive"));
+    assert_eq!(result.unchanged.iter().collect::<Vec<_>>(), ["upload_interval_secs"]);
+    assert_eq!(result.restart.iter().collect::<Vec<_>>(), ["device", "sample_timer", "shedder", "upload_timer"]);
+
+    // Nothing real moved: not the config, not the features
+    assert_eq!(serde_json::to_value(&config).unwrap(), config_before);
+    assert_eq!(features.report(), features_before);
+}
+
+#[test]
+fn a_dry_run_replaces_sensors_and_accepts_exports_only_on_copies() {
+    let config = Config::from_env_vars(&HashMap::new()).0;
+    let features = Features::resolve(&config.features);
+    let sensor = SensorDegradationConfig { lifetime: Lifetime::Fixed { hours: 10.0 }, curve: Curve::Linear, max_noise: 0.0, max_bias: 2.0, max_dropout: 0.0 };
+    let mut model = Degradation::new(DegradationConfig { seed: 7, sensors: BTreeMap::from([("temp".to_string(), sensor)]) });
+    model.advance(5.0 * 3600.0);
+    let subject_exporter = exporter();
+    let now = Utc::now();
+    let export = json!({"id": "export-1", "from": now - Duration::hours(2), "to": now, "delete": true});
+    let desired = json!({
+        "dry_run": true,
+        "replace_sensor": {"id": "job-1", "sensors": ["temp", "gps"]},
+        "export_subject_data": export,
+    });
+    let runtime = Runtime {
+        config: &config,
+        features: &features,
+        sample_interval_secs: 10,
+        upload_interval_secs: 60,
+        heartbeat_interval_secs: 30,
+        degradation: Some(&model),
+        subject_exporter: &subject_exporter,
+    };
+
+    let result = dry_run::preview(runtime, &desired, now);
+    // Only the sensors the model simulates are replaced
+    assert_eq!(result.apply["replace_sensor"], json!(["temp"]));
+    assert_eq!(result.apply["export_subject_data"], export);
+    assert!(result.reject.is_empty(), "{:?}", result);
+    assert_eq!(model.health("temp"), Some(0.5));
+    assert!(subject_exporter.job().is_none());
+
+    // Each is checked as the shadow check would: without a model there is nothing to replace
+    let runtime = Runtime { degradation: None, ..runtime };
+    let result = dry_run::preview(runtime, &json!({"replace_sensor": {"id": "job-1", "sensors": ["temp"]}, "export_subject_data": {"id": "export-2"}}), now);
+    assert_eq!(result.reject.keys().collect::<Vec<_>>(), ["export_subject_data", "replace_sensor"]);
+}
+
+#[test]
+fn a_document_dry_run_first_is_delivered_again_without_the_marker() {
+    let mut channel = PushChannel::new(KeepaliveConfig::default(), None);
+    let preview = json!({"version": "v3", "dry_run": true, "sample_interval_secs": 5});
+    let real = json!({"version": "v3", "sample_interval_secs": 5});
+
+    assert_eq!(channel.receive(0, preview.clone()), Some(preview.clone()));
+    assert_eq!(channel.receive(60, preview.clone()), Some(preview));
+    assert_eq!(channel.receive(120, real.clone()), Some(real));
+}
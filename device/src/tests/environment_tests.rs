@@ -0,0 +1,105 @@
+use chrono::{Duration, Utc};
+use futures::FutureExt;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+use crate::environment::{EnvironmentConfig, EnvironmentEvent, Environments};
+use crate::error::DeviceError;
+use crate::fleet::DevicePaths;
+use crate::runner::{FleetRunner, Launch};
+use crate::shutdown::StopMode;
+use crate::simulate::{Position, SensorProfile, Simulator};
+
+// A cold room of devices 0-2 that warms quickly without cooling, and a truck of device 3
+fn cold_chain() -> Environments {
+    let room = EnvironmentConfig { setpoint_c: 4.0, ambient_c: 22.0, cooling_tau_secs: 60.0, warming_tau_secs: 60.0, door_tau_secs: 30.0, members: vec![0, 1, 2] };
+    let truck = EnvironmentConfig { setpoint_c: -18.0, members: vec![3], ..room.clone() };
+    Environments::new(BTreeMap::from([("warehouse-A".to_string(), room), ("truck-7".to_string(), truck)]), Utc::now()).unwrap()
+}
+
+fn member(environments: &Environments, index: usize) -> Simulator {
+    let simulator = Simulator::new(SensorProfile::EnvironmentalNode, index as u64, Position::default());
+    simulator.with_environment(environments.for_member(index).unwrap())
+}
+
+#[test]
+fn an_injected_cooling_failure_reaches_every_member_within_one_sample_interval() {
+    let environments = cold_chain();
+    let behavior = Default::default();
+    let mut room: Vec<Simulator> = (0..3).map(|index| member(&environments, index)).collect();
+    let mut truck = member(&environments, 3);
+    let sample_interval = Duration::seconds(30);
+    let start = Utc::now();
+
+    let before: Vec<f32> = room.iter_mut().map(|simulator| simulator.generate_measurement_at(start, "1.0.0".to_string(), &behavior).temp).collect();
+    assert!(before.iter().all(|temp| (temp - 4.0).abs() <= 0.3), "{:?}", before);
+
+    environments.inject("warehouse-A", &EnvironmentEvent::CoolingFailure, start).unwrap();
+    let after: Vec<f32> = room.iter_mut()
+        .map(|simulator| simulator.generate_measurement_at(start + sample_interval, "1.0.0".to_string(), &behavior).temp)
+        .collect();
+    // 18 degrees towards ambient over half the time constant is about 7
+    assert!(after.iter().all(|temp| (10.0..12.5).contains(temp)), "{:?}", after);
+    // Each sensor reads the same air with its own error
+    assert!(after[0] != after[1] && after[1] != after[2], "{:?}", after);
+    let spread = after.iter().cloned().fold(f32::MIN, f32::max) - after.iter().cloned().fold(f32::MAX, f32::min);
+    assert!(spread <= 0.6, "{:?}", after);
+
+    // The truck is somewhere else
+    let truck_temp = truck.generate_measurement_at(start + sample_interval, "1.0.0".to_string(), &behavior).temp;
+    assert!((truck_temp + 18.0).abs() <= 0.3, "{}", truck_temp);
+}
+
+#[test]
+fn an_open_door_warms_the_air_until_it_closes_and_the_cooling_catches_up() {
+    let environments = cold_chain();
+    let room = environments.for_member(0).unwrap();
+    let start = Utc::now();
+    environments.inject("warehouse-A", &EnvironmentEvent::DoorOpen { secs: 30 }, start).unwrap();
+
+    let warmest = room.temperature_at(start + Duration::seconds(30));
+    assert!((15.0..16.0).contains(&warmest), "{}", warmest);
+    // Once the door is shut the cooling pulls the air back down
+    let report = environments.report(start + Duration::seconds(90));
+    assert_eq!(report["warehouse-A"].door_open_until, None);
+    assert!(report["warehouse-A"].temp_c < 8.5, "{:?}", report);
+    assert!(room.temperature_at(start + Duration::seconds(600)) - 4.0 < 0.1);
+}
+
+#[test]
+fn a_device_belongs_to_one_environment_at_most() {
+    let vars = HashMap::from([(
+        "FLEET_ENVIRONMENTS".to_string(),
+        json!({"warehouse-A": {"setpoint_c": 4.0, "members": [0, 1]}, "truck-7": {"setpoint_c": -18.0, "members": [1]}}).to_string(),
+    )]);
+    let error = Environments::from_env_vars(&vars, Utc::now()).unwrap_err();
+    assert!(format!("{:#}", error).contains("fleet device 1 is in both"), "{:#}", error);
+
+    let environments = Environments::from_env_vars(&HashMap::new(), Utc::now()).unwrap();
+    assert!(environments.for_member(0).is_none());
+}
+
+#[tokio::test]
+async fn events_are_injected_through_the_control_api() {
+    let environments = cold_chain();
+    let room = environments.for_member(1).unwrap();
+    let launch: Launch = Arc::new(|_: DevicePaths, _: mpsc::Receiver<StopMode>| async { Ok::<_, DeviceError>(()) }.boxed());
+    let runner = FleetRunner::launch(Vec::new(), Arc::new(DevicePaths::fleet_member), launch).with_environments(environments);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let _server = crate::runner::serve_on(listener, runner, None);
+
+    let client = reqwest::Client::new();
+    let response = client.post(format!("{}/fleet/environments/warehouse-A/events", url)).json(&json!({"event": "cooling_failure"})).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    let state: Value = response.json().await.unwrap();
+    assert_eq!(state["cooling_failed"], json!(true));
+    assert!(room.temperature_at(Utc::now() + Duration::seconds(60)) > 10.0);
+
+    let response = client.post(format!("{}/fleet/environments/garage/events", url)).json(&json!({"event": "door_open", "secs": 60})).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 404);
+    let report: Value = reqwest::get(format!("{}/fleet/environments", url)).await.unwrap().json().await.unwrap();
+    assert_eq!((&report["warehouse-A"]["cooling_failed"], &report["truck-7"]["cooling_failed"]), (&json!(true), &json!(false)));
+}
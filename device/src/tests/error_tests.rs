@@ -0,0 +1,155 @@
+use reqwest::StatusCode;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error as _;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::config::{Config, ConfigError, InvalidSetting};
+use crate::error::DeviceError;
+use crate::fleet;
+use crate::init;
+use crate::net::NetError;
+use crate::ota::{ChecksumMismatch, OtaError, SignatureRejection};
+use crate::residency::ResidencyError;
+use crate::shutdown::SignalsUnavailable;
+use crate::slots::SlotFault;
+use crate::storage::{self, StorageError};
+
+fn refused(status: StatusCode) -> DeviceError {
+    anyhow::Error::from(NetError::Status { endpoint: "ingest".to_string(), status, correlation_id: None, body: String::new() })
+        .context("upload failed")
+        .into()
+}
+
+#[tokio::test]
+async fn a_rejected_registration_is_a_permanent_net_error() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/register"))
+        .respond_with(ResponseTemplate::new(403))
+        .mount(&server)
+        .await;
+    let mut config = Config::from_env_vars(&HashMap::from([("BACKEND_URL".to_string(), server.uri())])).0;
+    let error = DeviceError::from(init::register(&reqwest::Client::new(), &mut config, None).await.unwrap_err());
+    assert_eq!(error.kind(), "net");
+    assert!(!error.retryable());
+
+    // A backend that cannot be reached may be there on the next try
+    let mut config = Config::from_env_vars(&HashMap::from([("BACKEND_URL".to_string(), "http://127.0.0.1:1".to_string())])).0;
+    let error = DeviceError::from(init::register(&reqwest::Client::new(), &mut config, None).await.unwrap_err());
+    assert_eq!(error.kind(), "net");
+    assert!(error.retryable());
+}
+
+#[test]
+fn backend_refusals_are_retryable_unless_the_request_itself_is_wrong() {
+    assert!(!refused(StatusCode::UNAUTHORIZED).retryable());
+    assert!(!refused(StatusCode::UNPROCESSABLE_ENTITY).retryable());
+    assert!(refused(StatusCode::TOO_MANY_REQUESTS).retryable());
+    assert!(refused(StatusCode::REQUEST_TIMEOUT).retryable());
+    assert!(refused(StatusCode::SERVICE_UNAVAILABLE).retryable());
+
+    let unmapped = DeviceError::from(anyhow::Error::from(ResidencyError::Unmapped(Some("eu-west-1".to_string()))));
+    assert_eq!(unmapped.kind(), "net");
+    assert!(!unmapped.retryable());
+}
+
+#[test]
+fn unusable_storage_is_retryable_only_while_the_database_is_busy() {
+    let missing_dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string()).join("device.db");
+    let error = DeviceError::from(storage::init_at(&missing_dir).unwrap_err());
+    assert_eq!(error.kind(), "storage");
+    assert!(!error.retryable());
+
+    let busy = rusqlite::Error::SqliteFailure(rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY), None);
+    let error = DeviceError::from(anyhow::Error::from(busy).context("Failed to count pending measurements"));
+    assert_eq!(error.kind(), "storage");
+    assert!(error.retryable());
+
+    let io = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "read-only filesystem");
+    assert_eq!(DeviceError::from(anyhow::Error::from(io).context("Failed to create data directory")).kind(), "storage");
+}
+
+#[test]
+fn firmware_errors_are_ota_errors() {
+    let mismatch = ChecksumMismatch { expected: "a".repeat(64), actual: "b".repeat(64) };
+    let error = DeviceError::from(anyhow::Error::from(mismatch).context("Failed to download firmware"));
+    assert_eq!(error.kind(), "ota");
+    assert!(error.retryable());
+
+    for cause in [anyhow::Error::from(SignatureRejection::Invalid), anyhow::Error::from(SlotFault::Empty { slot: "a".to_string() })] {
+        let error = DeviceError::from(cause);
+        assert_eq!(error.kind(), "ota");
+        assert!(!error.retryable());
+    }
+}
+
+#[test]
+fn settings_and_signals_are_neither_retried_nor_lost() {
+    let error = DeviceError::from(fleet::control_port(&HashMap::from([("FLEET_CONTROL_PORT".to_string(), "eighty".to_string())])).unwrap_err());
+    assert_eq!(error.kind(), "config");
+    assert!(!error.retryable());
+    // The setting marks the error even over a cause that would pick another variant
+    let io = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+    assert_eq!(DeviceError::from(anyhow::Error::from(io).context(InvalidSetting("FLEET_ENVIRONMENTS"))).kind(), "config");
+
+    let signals = SignalsUnavailable(std::io::Error::new(std::io::ErrorKind::Unsupported, "no signal support"));
+    let error = DeviceError::from(anyhow::Error::from(signals));
+    assert_eq!(error.kind(), "shutdown");
+    assert!(!error.retryable());
+
+    let error = DeviceError::from(anyhow::anyhow!("something else"));
+    assert_eq!(error.kind(), "other");
+    assert!(!error.retryable());
+}
+
+#[test]
+fn the_whole_chain_stays_reachable_through_source() {
+    let error = DeviceError::from(fleet::control_port(&HashMap::from([("FLEET_CONTROL_PORT".to_string(), "eighty".to_string())])).unwrap_err());
+    assert_eq!(error.to_string(), "configuration invalid");
+    let mut chain = Vec::new();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        chain.push(cause.to_string());
+        source = cause.source();
+    }
+    assert_eq!(chain, ["invalid FLEET_CONTROL_PORT", "\"eighty\" is not a port", "invalid digit found in string"]);
+    assert_eq!(error.report(), "configuration invalid: invalid FLEET_CONTROL_PORT: \"eighty\" is not a port: invalid digit found in string");
+
+    // What the device was doing is kept as context above the module's error
+    let error = refused(StatusCode::FORBIDDEN);
+    assert_eq!(error.context(), ["upload failed"]);
+    assert!(matches!(error.source().and_then(|cause| cause.downcast_ref::<NetError>()), Some(NetError::Status { .. })));
+    assert_eq!(error.report(), "upload failed: backend request failed: ingest answered HTTP 403 Forbidden");
+    // Binaries can carry it on in anyhow and still get at it
+    let carried = anyhow::Error::from(error).context("device 3 stopped");
+    assert_eq!(carried.downcast_ref::<DeviceError>().map(DeviceError::kind), Some("net"));
+}
+
+#[test]
+fn module_errors_convert_into_their_variants() {
+    let error = DeviceError::from(StorageError::CheckpointBlocked);
+    assert_eq!((error.kind(), error.context().is_empty()), ("storage", true));
+
+    let read = Config::load_from(&std::env::temp_dir().join(uuid::Uuid::new_v4().to_string()).join("config.json")).unwrap_err();
+    assert!(matches!(read, ConfigError::Read { .. }));
+    let error = DeviceError::from(read);
+    assert_eq!(error.kind(), "config");
+    assert!(!error.retryable());
+
+    let error = DeviceError::from(OtaError::from(ChecksumMismatch { expected: "a".repeat(64), actual: "b".repeat(64) }));
+    assert_eq!(error.kind(), "ota");
+    assert!(error.retryable());
+
+    let error = DeviceError::from(NetError::Lost { endpoint: "heartbeat".to_string(), network: "lte" });
+    assert_eq!(error.kind(), "net");
+    assert!(error.retryable());
+    let malformed = serde_json::from_str::<Value>("{").unwrap_err();
+    assert!(!DeviceError::from(NetError::Decode(malformed)).retryable());
+
+    // A module error that went through anyhow is taken back out of it, under its context
+    let stored = anyhow::Error::from(StorageError::CheckpointBlocked).context("Failed to checkpoint the outbox");
+    let error = DeviceError::from(stored);
+    assert_eq!(error.context(), ["Failed to checkpoint the outbox"]);
+    assert!(matches!(error, DeviceError::Storage { source: StorageError::CheckpointBlocked, .. }));
+}
@@ -0,0 +1,183 @@
+use chrono::{Duration, Utc};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use wiremock::matchers::{method, path, query_param};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::audit::{AuditLog, AuditRecord};
+use crate::config::Config;
+use crate::export::{self, ExportPhase, SubjectExporter};
+use crate::stats::ApiStats;
+use crate::storage;
+
+const DEVICE_ID: &str = "device-1";
+
+struct Fixture {
+    dir: PathBuf,
+    config: Config,
+}
+
+impl Fixture {
+    fn new(backend_url: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("export_{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let env = HashMap::from([
+            ("BACKEND_URL".to_string(), backend_url.to_string()),
+            ("AUTH_TOKEN".to_string(), "token".to_string()),
+            ("DEVICE_ID".to_string(), DEVICE_ID.to_string()),
+            ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()),
+        ]);
+        Fixture { dir, config: Config::from_env_vars(&env).0 }
+    }
+
+    fn open(&self) -> (rusqlite::Connection, AuditLog, SubjectExporter) {
+        let conn = storage::init_at(&self.dir.join("device.db")).unwrap();
+        let audit_log = AuditLog::open(&self.audit_path(), DEVICE_ID).unwrap();
+        let exporter = SubjectExporter::load(&conn, self.dir.join("exports")).unwrap();
+        (conn, audit_log, exporter)
+    }
+
+    fn audit_path(&self) -> PathBuf {
+        self.dir.join("audit.log")
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Stores measurements sampled an hour apart, the first three hours ago; returns their sequence numbers
+fn store_hourly(conn: &rusqlite::Connection, count: i64) -> Vec<u32> {
+    let start = Utc::now() - Duration::hours(3);
+    (0..count)
+        .map(|hour| {
+            let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+            measurement.timestamp = start + Duration::hours(hour);
+            storage::append_measurement(conn, &measurement, 0).unwrap();
+            measurement.sequence_number
+        })
+        .collect()
+}
+
+// From two and a half hours ago to half an hour from now: the second to fourth rows
+fn command(id: &str, delete: bool) -> Value {
+    let now = Utc::now();
+    json!({"id": id, "from": now - Duration::minutes(150), "to": now + Duration::minutes(30), "delete": delete})
+}
+
+fn unpack(archive: &[u8]) -> BTreeMap<String, Vec<u8>> {
+    let mut entries = BTreeMap::new();
+    let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(archive));
+    for entry in tar.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let mut body = Vec::new();
+        entry.read_to_end(&mut body).unwrap();
+        entries.insert(entry.path().unwrap().to_string_lossy().into_owned(), body);
+    }
+    entries
+}
+
+fn audit_actions(path: &Path) -> Vec<(String, Value)> {
+    std::fs::read_to_string(path).unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<AuditRecord>(line).unwrap())
+        .map(|record| (record.action, record.new))
+        .filter(|(action, _)| action.starts_with("subject_"))
+        .collect()
+}
+
+async fn mount_upload(server: &MockServer, status: u16, times: u64) {
+    Mock::given(method("POST")).and(path(format!("/api/devices/{}/files", DEVICE_ID))).and(query_param("purpose", "compliance"))
+        .respond_with(ResponseTemplate::new(status).set_body_json(json!({"file_id": "file-1", "sha256": "", "bytes": 0, "purpose": "compliance"})))
+        .up_to_n_times(times)
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn archive_holds_the_range_and_is_signed_and_checksummed() {
+    let server = MockServer::start().await;
+    mount_upload(&server, 200, 1).await;
+    let fixture = Fixture::new(&server.uri());
+    let (mut conn, mut audit_log, mut exporter) = fixture.open();
+    let stored = store_hourly(&conn, 6);
+
+    assert!(exporter.request(&command("export-1", false), Some((&conn, &mut audit_log))).unwrap());
+    exporter.advance(&reqwest::Client::new(), &fixture.config, &ApiStats::default(), &mut conn, &mut audit_log).await.unwrap();
+    assert!(matches!(exporter.job().unwrap().phase, ExportPhase::Completed { deleted: false, .. }));
+
+    let requests = server.received_requests().await.unwrap();
+    let upload = &requests[0];
+    assert_eq!(upload.headers.get("x-content-sha256").unwrap().to_str().unwrap(), export::sha256_hex(&upload.body));
+    let entries = unpack(&upload.body);
+    assert_eq!(entries.keys().collect::<Vec<_>>(), ["audit.json", "manifest.json", "manifest.sig", "measurements.json"]);
+
+    let manifest: Value = serde_json::from_slice(&entries["manifest.json"]).unwrap();
+    assert_eq!(String::from_utf8(entries["manifest.sig"].clone()).unwrap(), export::sign("token", &entries["manifest.json"]));
+    for entry in manifest["entries"].as_array().unwrap() {
+        let body = &entries[entry["name"].as_str().unwrap()];
+        assert_eq!(entry["sha256"], json!(export::sha256_hex(body)));
+    }
+    let measurements: Vec<Value> = serde_json::from_slice(&entries["measurements.json"]).unwrap();
+    let exported: Vec<u32> = measurements.iter().map(|row| row["measurement"]["sequence_number"].as_u64().unwrap() as u32).collect();
+    assert_eq!(exported, stored[1..4]);
+    let audit: Vec<AuditRecord> = serde_json::from_slice(&entries["audit.json"]).unwrap();
+    assert_eq!(audit.last().unwrap().action, "subject_export_requested");
+
+    // Without delete the local rows stay; the uploaded archive does not
+    assert_eq!(storage::count_measurements(&conn).unwrap(), 6);
+    assert!(std::fs::read_dir(fixture.dir.join("exports")).unwrap().next().is_none());
+}
+
+#[tokio::test]
+async fn export_interrupted_by_a_restart_resumes_and_attests_the_deletion() {
+    let server = MockServer::start().await;
+    mount_upload(&server, 500, 1).await;
+    let fixture = Fixture::new(&server.uri());
+    let client = reqwest::Client::new();
+    let stats = ApiStats::default();
+
+    let (mut conn, mut audit_log, mut exporter) = fixture.open();
+    let stored = store_hourly(&conn, 6);
+    exporter.request(&command("export-2", true), Some((&conn, &mut audit_log))).unwrap();
+    exporter.advance(&client, &fixture.config, &stats, &mut conn, &mut audit_log).await.unwrap();
+    let ExportPhase::Packaged { archive } = exporter.job().unwrap().phase.clone() else {
+        panic!("upload failure should leave the export packaged");
+    };
+    assert_eq!(storage::count_measurements(&conn).unwrap(), 6);
+    drop((conn, audit_log, exporter)); // Device restarts before the upload succeeds
+
+    mount_upload(&server, 200, 1).await;
+    let (mut conn, mut audit_log, mut exporter) = fixture.open();
+    // The command is still in the desired shadow; it is not started a second time
+    assert!(!exporter.request(&command("export-2", true), Some((&conn, &mut audit_log))).unwrap());
+    exporter.advance(&client, &fixture.config, &stats, &mut conn, &mut audit_log).await.unwrap();
+    assert!(matches!(exporter.job().unwrap().phase, ExportPhase::Completed { deleted: true, .. }));
+
+    // The archive packaged before the restart is the one uploaded
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(export::sha256_hex(&requests[1].body), archive.sha256);
+    // Exactly the exported rows are gone
+    let remaining = storage::mark_measurements_inflight(&mut conn, 10).unwrap();
+    let remaining: Vec<u32> = remaining.iter().map(|row| row.measurement.sequence_number).collect();
+    assert_eq!(remaining, [stored[0], stored[4], stored[5]]);
+
+    let actions = audit_actions(&fixture.audit_path());
+    let names: Vec<&str> = actions.iter().map(|(action, _)| action.as_str()).collect();
+    assert_eq!(names, [
+        "subject_export_requested",
+        "subject_export_packaged",
+        "subject_export_upload_failed",
+        "subject_export_uploaded",
+        "subject_data_deleted",
+        "subject_export_completed",
+    ]);
+    let attestation = &actions[4].1;
+    assert_eq!(attestation["measurement_ids"], json!(archive.measurement_ids));
+    assert_eq!(attestation["archive_sha256"], json!(archive.sha256));
+    assert_eq!(attestation["removed_now"], json!(3));
+}
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::UnixStream;
+
+use crate::external::{self, Backpressure, ExternalFeed, ExternalSourceConfig, ExternalSourceKind};
+
+fn feed_config(backpressure: Backpressure) -> ExternalSourceConfig {
+    let mut field_map = HashMap::new();
+    field_map.insert("cargo_temp".to_string(), "temp".to_string());
+    ExternalSourceConfig {
+        kind: ExternalSourceKind::Stdin,
+        stall_timeout_secs: 5,
+        backpressure,
+        buffer_size: 10,
+        field_map,
+    }
+}
+
+// Waits until the reader task has consumed what the feeder wrote
+async fn settle() {
+    tokio::time::sleep(Duration::from_millis(50)).await;
+}
+
+#[tokio::test]
+async fn socketpair_feed_maps_fields_into_measurement() {
+    let (mut feeder, device_end) = UnixStream::pair().unwrap();
+    let feed = ExternalFeed::from_reader(feed_config(Backpressure::Latest), device_end);
+
+    feeder.write_all(b"{\"cargo_temp\": -18.5, \"humidity\": 80.0}\n").await.unwrap();
+    settle().await;
+
+    let record = feed.next_record(Instant::now()).expect("record available");
+    let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    let sequence_number = measurement.sequence_number;
+    feed.apply(&record, &mut measurement);
+
+    assert_eq!(measurement.temp, -18.5);
+    assert_eq!(measurement.humidity, 80.0);
+    assert_eq!(measurement.sequence_number, sequence_number);
+}
+
+#[tokio::test]
+async fn latest_mode_samples_newest_and_buffer_mode_keeps_order() {
+    let (mut feeder, device_end) = UnixStream::pair().unwrap();
+    let latest = ExternalFeed::from_reader(feed_config(Backpressure::Latest), device_end);
+    feeder.write_all(b"{\"cargo_temp\": 1}\n{\"cargo_temp\": 2}\n{\"cargo_temp\": 3}\n").await.unwrap();
+    settle().await;
+    assert_eq!(latest.next_record(Instant::now()).unwrap()["cargo_temp"], 3);
+    // Nothing new arrived: hold the last value
+    assert_eq!(latest.next_record(Instant::now()).unwrap()["cargo_temp"], 3);
+
+    let (mut feeder, device_end) = UnixStream::pair().unwrap();
+    let buffered = ExternalFeed::from_reader(feed_config(Backpressure::Buffer), device_end);
+    feeder.write_all(b"{\"cargo_temp\": 1}\n{\"cargo_temp\": 2}\n").await.unwrap();
+    settle().await;
+    assert_eq!(buffered.next_record(Instant::now()).unwrap()["cargo_temp"], 1);
+    assert_eq!(buffered.next_record(Instant::now()).unwrap()["cargo_temp"], 2);
+}
+
+#[tokio::test]
+async fn stalled_feed_falls_back_to_synthetic_model() {
+    let (mut feeder, device_end) = UnixStream::pair().unwrap();
+    let feed = ExternalFeed::from_reader(feed_config(Backpressure::Latest), device_end);
+
+    // Nothing received yet
+    assert!(feed.next_record(Instant::now()).is_none());
+
+    feeder.write_all(b"{\"cargo_temp\": 4}\nnot json\n").await.unwrap();
+    settle().await;
+    assert!(feed.next_record(Instant::now()).is_some());
+    assert!(feed.next_record(Instant::now() + Duration::from_secs(6)).is_none());
+}
+
+#[test]
+fn parses_env_source_and_field_map() {
+    assert_eq!(external::parse_source_kind("stdin").unwrap(), ExternalSourceKind::Stdin);
+    assert_eq!(
+        external::parse_source_kind("unix:/tmp/cosim.sock").unwrap(),
+        ExternalSourceKind::Unix { socket_path: "/tmp/cosim.sock".to_string() }
+    );
+    assert!(external::parse_source_kind("tcp:1234").is_err());
+
+    let map = external::parse_field_map("cargo_temp=temp, rh=humidity");
+    assert_eq!(map.get("rh").map(String::as_str), Some("humidity"));
+}
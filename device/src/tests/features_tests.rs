@@ -0,0 +1,69 @@
+use serde_json::json;
+use std::collections::BTreeMap;
+
+use crate::features::{ApplyMode, FeatureValue, Features};
+
+#[test]
+fn defaults_resolve_without_overrides() {
+    let features = Features::resolve(&BTreeMap::new());
+    assert!(features.adaptive_sampling());
+    assert!(features.schema_filter());
+    assert!(features.external_source());
+    assert!(features.full_heartbeat_telemetry());
+    assert!(features.warnings().is_empty());
+    assert_eq!(features.report()["effective"]["heartbeat_telemetry"], json!("full"));
+}
+
+#[test]
+fn shadow_override_applies_immediately_and_persists() {
+    let mut overrides = BTreeMap::new();
+    let mut features = Features::resolve(&overrides);
+
+    let changes = features.apply_desired(&mut overrides, &json!({"adaptive_sampling": false, "heartbeat_telemetry": "minimal"}));
+    assert_eq!(changes.len(), 2);
+    assert!(changes.iter().all(|change| change.apply == ApplyMode::Immediate));
+    assert!(!features.adaptive_sampling());
+    assert!(!features.full_heartbeat_telemetry());
+    assert_eq!(overrides["adaptive_sampling"], FeatureValue::Bool(false));
+
+    // Re-applying the same desired state is a no-op
+    assert!(features.apply_desired(&mut overrides, &json!({"adaptive_sampling": false})).is_empty());
+}
+
+#[test]
+fn unknown_names_and_invalid_values_are_warnings() {
+    let mut overrides = BTreeMap::new();
+    let mut features = Features::resolve(&overrides);
+
+    let changes = features.apply_desired(&mut overrides, &json!({"delta_encodng": true, "heartbeat_telemetry": "verbose", "schema_filter": 1}));
+    assert!(changes.is_empty());
+    assert!(overrides.is_empty());
+    assert_eq!(features.warnings().len(), 3);
+    assert!(features.full_heartbeat_telemetry());
+
+    // Persisted overrides are validated the same way at startup
+    let persisted = BTreeMap::from([("bogus".to_string(), FeatureValue::Bool(true))]);
+    assert_eq!(Features::resolve(&persisted).warnings(), ["Unknown feature bogus".to_string()]);
+}
+
+#[test]
+fn restart_bound_feature_waits_for_next_boot() {
+    let mut overrides = BTreeMap::new();
+    let mut features = Features::resolve(&overrides);
+
+    let changes = features.apply_desired(&mut overrides, &json!({"external_source": false}));
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].apply, ApplyMode::Restart);
+    // Still running with the value the feed was started with
+    assert!(features.external_source());
+    assert_eq!(features.report()["pending_restart"]["external_source"], json!(false));
+
+    // After a restart the persisted override takes effect
+    let restarted = Features::resolve(&overrides);
+    assert!(!restarted.external_source());
+    assert_eq!(restarted.report()["pending_restart"], json!({}));
+
+    // Reverting before a restart clears the pending change
+    features.apply_desired(&mut overrides, &json!({"external_source": true}));
+    assert_eq!(features.report()["pending_restart"], json!({}));
+}
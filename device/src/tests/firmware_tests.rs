@@ -0,0 +1,73 @@
+use chrono::Utc;
+use std::collections::BTreeMap;
+
+use crate::firmware::FirmwareBehavior;
+use crate::ota::OtaState;
+
+// 1.2.0 ships with a clock-skew and missing-latitude bug; 1.3.0 fixes both
+fn bundled_behaviors() -> BTreeMap<String, FirmwareBehavior> {
+    let buggy = FirmwareBehavior {
+        noise_scale: 0.0,
+        clock_skew_secs: 3600,
+        omitted_fields: vec!["latitude".to_string()],
+        capabilities: Vec::new(),
+    };
+    BTreeMap::from([("1.2.0".to_string(), buggy)])
+}
+
+fn state_at(version: &str) -> OtaState {
+    OtaState {
+        current_version: version.to_string(),
+        active_slot: "A".to_string(),
+        previous_version: None,
+        previous_slot: None,
+        pending_confirmation: false,
+        boot_count: 0,
+        trial_heartbeats: 0,
+        trial_ingested: false,
+        trial_started_at: None,
+        installed_behaviors: BTreeMap::new(),
+        last_rollback: None,
+        last_update: None,
+        ota_status: Default::default(),
+        manifests: BTreeMap::new(),
+        history: Vec::new(),
+        staged: None,
+    }
+}
+
+fn assert_buggy(state: &OtaState, bundled: &BTreeMap<String, FirmwareBehavior>) {
+    let measurement = super::generate_measurement(state.current_version.clone(), &state.behavior(bundled));
+    assert!(measurement.timestamp > Utc::now() + chrono::Duration::minutes(59));
+    assert!(measurement.latitude.is_none());
+    assert_eq!(measurement.temp, 20.0);
+}
+
+#[test]
+fn update_fixes_behavior_and_rollback_restores_it() {
+    let bundled = bundled_behaviors();
+    let mut state = state_at("1.2.0");
+    assert_buggy(&state, &bundled);
+
+    // Metadata-delivered behavior for the new version
+    let fixed = FirmwareBehavior { capabilities: vec!["gps_v2".to_string()], ..Default::default() };
+    state.installed_behaviors.insert("1.3.0".to_string(), fixed.clone());
+    state.begin_trial("1.3.0".to_string());
+    assert_eq!(state.behavior(&bundled), fixed);
+    let measurement = super::generate_measurement(state.current_version.clone(), &state.behavior(&bundled));
+    assert!(measurement.timestamp <= Utc::now());
+    assert!(measurement.latitude.is_some());
+
+    assert!(state.rollback());
+    assert_buggy(&state, &bundled);
+}
+
+#[test]
+fn installed_behavior_overrides_bundled_table() {
+    let bundled = bundled_behaviors();
+    let mut state = state_at("1.2.0");
+    state.installed_behaviors.insert("1.2.0".to_string(), FirmwareBehavior::default());
+    assert_eq!(state.behavior(&bundled), FirmwareBehavior::default());
+    // Unknown versions behave normally
+    assert_eq!(state_at("9.9.9").behavior(&bundled), FirmwareBehavior::default());
+}
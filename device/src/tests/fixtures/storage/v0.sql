@@ -0,0 +1,22 @@
+-- A measurement store as the first firmware created it: no schema_version table and
+-- none of the columns added since
+CREATE TABLE measurements (
+    id INTEGER PRIMARY KEY,
+    timestamp TEXT NOT NULL,
+    temp REAL NOT NULL,
+    humidity REAL NOT NULL,
+    battery REAL NOT NULL,
+    sequence_number INTEGER NOT NULL,
+    latitude REAL,
+    longitude REAL,
+    speed REAL,
+    firmware_version TEXT
+);
+CREATE TABLE device_state (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+INSERT INTO measurements (timestamp, temp, humidity, battery, sequence_number, latitude, longitude, speed, firmware_version) VALUES
+    ('2024-03-01T10:00:00Z', 21.5, 40.0, 0.9, 1, 34.05, -118.24, 12.5, '0.1.0'),
+    ('2024-03-01T10:00:10Z', 21.6, 40.5, 0.9, 2, NULL, NULL, NULL, '0.1.0'),
+    ('2024-03-01T10:00:20Z', 21.4, 41.0, 0.89, 3, 34.06, -118.25, 0.0, NULL);
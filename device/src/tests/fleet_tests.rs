@@ -0,0 +1,259 @@
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+use crate::config::Config;
+use crate::fleet::{self, DevicePaths, Registration};
+use crate::simulate::Simulator;
+
+fn all_paths(paths: &DevicePaths) -> Vec<PathBuf> {
+    vec![
+        paths.config.clone(),
+        paths.suspended.clone(),
+        paths.database.clone(),
+        paths.ota_state.clone(),
+        paths.firmware_dir.clone(),
+        paths.audit_log.clone(),
+        paths.staged_txn.clone(),
+        paths.schema_cache.clone(),
+        paths.exports_dir.clone(),
+        paths.crash_dir.clone(),
+        paths.restored_runtime.clone(),
+    ]
+}
+
+fn args(raw: &[&str]) -> Vec<String> {
+    raw.iter().map(|arg| arg.to_string()).collect()
+}
+
+#[test]
+fn a_lone_device_keeps_its_file_names_and_fleet_members_share_none() {
+    let single = DevicePaths::single();
+    assert_eq!(single.database, Path::new("./device_storage.db"));
+    assert_eq!(single.ota_state, Path::new("./ota_state.json"));
+    assert_eq!(single.config.file_name().unwrap(), "device_config.json");
+    assert!(single.owns_process());
+
+    let third = DevicePaths::fleet_member(3);
+    assert_eq!(third.config.file_name().unwrap(), "device_config_3.json");
+    assert_eq!(third.database, Path::new("./device_storage_3.db"));
+    assert_eq!(third.firmware_dir, Path::new("./firmware_3"));
+    assert!(DevicePaths::fleet_member(0).owns_process() && !third.owns_process());
+
+    let fleet: Vec<PathBuf> = (0..4).flat_map(|index| all_paths(&DevicePaths::fleet_member(index))).collect();
+    let distinct: HashSet<&PathBuf> = fleet.iter().collect();
+    assert_eq!(distinct.len(), fleet.len());
+}
+
+#[test]
+fn the_data_dir_holds_everything_but_the_config() {
+    let env = HashMap::from([("DATA_DIR".to_string(), "/var/lib/fleet".to_string())]);
+    let config = Config::from_env_vars(&env).0;
+    assert_eq!(Config::from_env_vars(&HashMap::new()).0.data_dir, Path::new("."));
+
+    let paths = DevicePaths::fleet_member(2).in_data_dir(&config.data_dir);
+    assert_eq!(paths.database, Path::new("/var/lib/fleet/device_storage_2.db"));
+    assert_eq!(paths.ota_state, Path::new("/var/lib/fleet/ota_state_2.json"));
+    assert_eq!(paths.firmware_dir, Path::new("/var/lib/fleet/firmware_2"));
+    assert_eq!(paths.audit_log.parent(), Some(Path::new("/var/lib/fleet")));
+    assert_eq!(paths.crash_dir, Path::new("/var/lib/fleet/crash_2"));
+    assert!(all_paths(&paths)[2..].iter().all(|path| path.starts_with("/var/lib/fleet")), "{:?}", paths);
+    assert_eq!(paths.config, DevicePaths::fleet_member(2).config);
+    assert_eq!(paths.suspended, DevicePaths::fleet_member(2).suspended);
+
+    // The default leaves what CONFIG_DIR holds in place
+    let paths = DevicePaths::fleet_member(2).in_data_dir(Path::new("."));
+    assert_eq!(paths.audit_log, DevicePaths::fleet_member(2).audit_log);
+    assert_eq!(paths.restored_runtime, DevicePaths::fleet_member(2).restored_runtime);
+}
+
+#[test]
+fn state_left_outside_the_data_dir_moves_into_it() {
+    let root = std::env::temp_dir().join(format!("data_dir_{}", uuid::Uuid::new_v4()));
+    let previous = member_under(&root.join("old"), 0);
+    std::fs::create_dir_all(&previous.crash_dir).unwrap();
+    std::fs::write(&previous.database, "rows").unwrap();
+    std::fs::write(format!("{}-wal", previous.database.display()), "journal").unwrap();
+    std::fs::write(&previous.audit_log, "old audit").unwrap();
+    std::fs::write(previous.crash_dir.join("panic.json"), "{}").unwrap();
+
+    let data_dir = root.join("data");
+    let paths = previous.clone().in_data_dir(&data_dir);
+    std::fs::create_dir_all(&data_dir).unwrap();
+    std::fs::write(&paths.audit_log, "new audit").unwrap();
+    paths.adopt(&previous);
+
+    assert_eq!(std::fs::read_to_string(&paths.database).unwrap(), "rows");
+    assert_eq!(std::fs::read_to_string(format!("{}-wal", paths.database.display())).unwrap(), "journal");
+    assert!(paths.crash_dir.join("panic.json").exists());
+    assert!(!previous.database.exists() && !previous.crash_dir.exists());
+    // What the data directory already holds wins; the old file is left for the operator
+    assert_eq!(std::fs::read_to_string(&paths.audit_log).unwrap(), "new audit");
+    assert_eq!(std::fs::read_to_string(&previous.audit_log).unwrap(), "old audit");
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[test]
+fn fleet_size_comes_from_the_flag_then_the_environment() {
+    let vars = HashMap::from([("NUM_DEVICES".to_string(), "5".to_string())]);
+    assert_eq!(fleet::fleet_size(&args(&["--devices", "3"]), &vars).unwrap(), 3);
+    assert_eq!(fleet::fleet_size(&args(&["--devices=4"]), &vars).unwrap(), 4);
+    assert_eq!(fleet::fleet_size(&[], &vars).unwrap(), 5);
+    assert_eq!(fleet::fleet_size(&[], &HashMap::new()).unwrap(), 1);
+    assert!(fleet::fleet_size(&args(&["--devices", "0"]), &vars).is_err());
+    assert!(fleet::fleet_size(&args(&["--devices"]), &vars).is_err());
+    assert!(fleet::fleet_size(&args(&["--devices=many"]), &vars).is_err());
+}
+
+#[test]
+fn the_control_api_stays_on_loopback_unless_a_token_guards_it() {
+    let vars = |pairs: &[(&str, &str)]| pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect::<HashMap<_, _>>();
+    assert_eq!(fleet::control_api(&HashMap::new()).unwrap(), None);
+
+    let api = fleet::control_api(&vars(&[("FLEET_CONTROL_PORT", "8090")])).unwrap().unwrap();
+    assert_eq!((api.address.to_string(), api.token), ("127.0.0.1:8090".to_string(), None));
+
+    let everywhere = [("FLEET_CONTROL_PORT", "8090"), ("FLEET_CONTROL_ADDRESS", "0.0.0.0")];
+    assert!(fleet::control_api(&vars(&everywhere)).is_err());
+    let api = fleet::control_api(&vars(&[everywhere[0], everywhere[1], ("FLEET_CONTROL_TOKEN", "s3cret")])).unwrap().unwrap();
+    assert_eq!((api.address.to_string(), api.token.as_deref()), ("0.0.0.0:8090".to_string(), Some("s3cret")));
+}
+
+#[test]
+fn each_simulator_counts_and_moves_on_its_own() {
+    let mut first = Simulator::default();
+    let mut second = Simulator::default();
+    let behavior = Default::default();
+    let from_first: Vec<u32> = (0..3).map(|_| first.generate_measurement("0.1.0".to_string(), &behavior).sequence_number).collect();
+    assert_eq!(from_first, [0, 1, 2]);
+    assert_eq!(second.generate_measurement("0.1.0".to_string(), &behavior).sequence_number, 0);
+}
+
+/// Paths of fleet member `index` with every file under `dir`.
+pub(super) fn member_under(dir: &Path, index: usize) -> DevicePaths {
+    let file = |name: &str| dir.join(format!("{}_{}", name, index));
+    DevicePaths {
+        index: Some(index),
+        config: dir.join(format!("device_config_{}.json", index)),
+        database: file("device_storage.db"),
+        ota_state: file("ota_state.json"),
+        firmware_dir: file("firmware"),
+        audit_log: file("audit.log"),
+        staged_txn: file("staged_txn.json"),
+        schema_cache: file("schema_cache.json"),
+        exports_dir: file("exports"),
+        crash_dir: file("crash"),
+        restored_runtime: file("restored_runtime.json"),
+        suspended: file("suspended"),
+    }
+}
+
+fn members_under(dir: &Path, devices: usize) -> Vec<DevicePaths> {
+    (0..devices).map(|index| member_under(dir, index)).collect()
+}
+
+pub(super) fn identity() -> Value {
+    json!({
+        "device_id": uuid::Uuid::new_v4(),
+        "auth_token": uuid::Uuid::new_v4(),
+        "desired_sample_interval_secs": 10,
+        "desired_upload_interval_secs": 60,
+        "desired_heartbeat_interval_secs": 30,
+    })
+}
+
+// Registers every device in the request except those with a rejected name
+struct BulkRegistration(Vec<&'static str>);
+
+impl Respond for BulkRegistration {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let body: Value = serde_json::from_slice(&request.body).unwrap();
+        let results: Vec<Value> = body["devices"].as_array().unwrap().iter().map(|device| {
+            if self.0.contains(&device["device_name"].as_str().unwrap()) {
+                json!({"boot_id": device["boot_id"], "error": "duplicate serial"})
+            } else {
+                json!({"boot_id": device["boot_id"], "registration": identity()})
+            }
+        }).collect();
+        ResponseTemplate::new(200).set_body_json(results)
+    }
+}
+
+async fn backend(bulk: Option<BulkRegistration>) -> MockServer {
+    let server = MockServer::start().await;
+    let bulk_response = Mock::given(method("POST")).and(path("/api/devices/register/bulk"));
+    match bulk {
+        Some(responder) => bulk_response.respond_with(responder).mount(&server).await,
+        None => bulk_response.respond_with(ResponseTemplate::new(404)).mount(&server).await,
+    }
+    Mock::given(method("POST")).and(path("/api/devices/register"))
+        .respond_with(|_: &Request| ResponseTemplate::new(200).set_body_json(identity()))
+        .mount(&server)
+        .await;
+    server
+}
+
+async fn register(server: &MockServer, members: &[DevicePaths]) -> Registration {
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("DEVICE_NAME".to_string(), "sensor".to_string()),
+    ]);
+    fleet::register_members(&reqwest::Client::new(), &Config::from_env_vars(&env).0, members).await.unwrap()
+}
+
+async fn requests_to(server: &MockServer, endpoint: &str) -> usize {
+    server.received_requests().await.unwrap().iter().filter(|request| request.url.path() == endpoint).count()
+}
+
+#[tokio::test]
+async fn a_fleet_is_registered_in_one_bulk_request() {
+    let dir = std::env::temp_dir().join(format!("fleet_{}", uuid::Uuid::new_v4()));
+    let members = members_under(&dir, 3);
+    let server = backend(Some(BulkRegistration(Vec::new()))).await;
+
+    assert_eq!(register(&server, &members).await, Registration { bulk: 3, individually: 0, failed: 0 });
+    assert_eq!((requests_to(&server, "/api/devices/register/bulk").await, requests_to(&server, "/api/devices/register").await), (1, 0));
+    let configs: Vec<Config> = members.iter().map(|paths| Config::load_from(&paths.config).unwrap()).collect();
+    assert_eq!(configs[2].device_name.as_deref(), Some("sensor-2"));
+    assert!(configs.iter().all(|config| config.auth_token.is_some() && config.reported_shadow_state == Some(json!({}))));
+    let ids: HashSet<&String> = configs.iter().map(|config| &config.device_id).collect();
+    assert_eq!(ids.len(), 3);
+
+    // Registered devices are left alone on the next start
+    assert_eq!(register(&server, &members).await, Registration::default());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn devices_rejected_in_bulk_are_registered_individually() {
+    let dir = std::env::temp_dir().join(format!("fleet_{}", uuid::Uuid::new_v4()));
+    let members = members_under(&dir, 4);
+    let server = backend(Some(BulkRegistration(vec!["sensor-1", "sensor-3"]))).await;
+
+    assert_eq!(register(&server, &members).await, Registration { bulk: 2, individually: 2, failed: 0 });
+    assert_eq!(requests_to(&server, "/api/devices/register").await, 2);
+    let retried: Value = serde_json::from_slice(&server.received_requests().await.unwrap().last().unwrap().body).unwrap();
+    assert_eq!(retried["device_name"], json!("sensor-3"));
+    assert!(members.iter().all(|paths| Config::load_from(&paths.config).is_ok()));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn a_backend_without_bulk_registration_registers_each_device() {
+    let dir = std::env::temp_dir().join(format!("fleet_{}", uuid::Uuid::new_v4()));
+    let members = members_under(&dir, 3);
+    // One device already has an identity
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut registered = Config::from_env_vars(&HashMap::new()).0;
+    registered.device_id = "existing".to_string();
+    registered.save_to(&members[0].config).unwrap();
+    let server = backend(None).await;
+
+    assert_eq!(register(&server, &members).await, Registration { bulk: 0, individually: 2, failed: 0 });
+    assert_eq!(requests_to(&server, "/api/devices/register").await, 2);
+    assert_eq!(Config::load_from(&members[0].config).unwrap().device_id, "existing");
+    assert!(Config::load_from(&members[2].config).unwrap().auth_token.is_some());
+    let _ = std::fs::remove_dir_all(&dir);
+}
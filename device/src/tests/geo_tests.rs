@@ -0,0 +1,120 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde_json::json;
+
+use crate::firmware::FirmwareBehavior;
+use crate::geo::{self, GeoBucketConfig, GeoBuckets, MapTile};
+use crate::storage;
+use crate::types::Measurement;
+
+fn fix(latitude: f32, longitude: f32, timestamp: DateTime<Utc>) -> Measurement {
+    let mut measurement = super::generate_measurement("1.0.0".to_string(), &FirmwareBehavior::default());
+    measurement.latitude = Some(latitude);
+    measurement.longitude = Some(longitude);
+    measurement.timestamp = timestamp;
+    measurement
+}
+
+fn buckets(precision: u8, tile_zoom: Option<u8>) -> GeoBuckets {
+    let db_path = std::env::temp_dir().join(format!("geo_{}.db", uuid::Uuid::new_v4()));
+    let buckets = GeoBuckets::load(Some(GeoBucketConfig { precision, tile_zoom }), &storage::init_at(&db_path).unwrap()).unwrap();
+    let _ = std::fs::remove_file(&db_path);
+    buckets
+}
+
+#[test]
+fn geohashes_match_the_reference_vectors() {
+    assert_eq!(geo::geohash(42.6, -5.6, 5), "ezs42");
+    assert_eq!(geo::geohash(57.64911, 10.40744, 11), "u4pruydqqvj");
+    assert_eq!(geo::geohash(-25.382708, -49.265506, 12), "6gkzwgjzn820");
+    assert_eq!(geo::geohash(0.0, 0.0, 1), "s");
+    // Longer hashes are not more precise than a double
+    assert_eq!(geo::geohash(42.6, -5.6, 20).len(), 12);
+}
+
+#[test]
+fn map_tiles_match_the_slippy_map_scheme() {
+    assert_eq!(geo::map_tile(51.5074, -0.1278, 10), MapTile { x: 511, y: 340, z: 10 });
+    assert_eq!(geo::map_tile(34.05, -118.24, 12), MapTile { x: 702, y: 1635, z: 12 });
+    assert_eq!(geo::map_tile(0.0, 0.0, 0), MapTile { x: 0, y: 0, z: 0 });
+    // Beyond the projection's edges positions fall into the outermost tiles
+    assert_eq!(geo::map_tile(89.9, 180.0, 2), MapTile { x: 3, y: 0, z: 2 });
+}
+
+#[test]
+fn fixes_are_stamped_and_transitions_reported() {
+    let mut buckets = buckets(6, Some(12));
+    let start = Utc.with_ymd_and_hms(2026, 3, 2, 12, 0, 0).unwrap();
+
+    let mut first = fix(34.05, -118.24, start);
+    assert_eq!(buckets.apply(&mut first), None);
+    assert_eq!(first.geohash.as_deref(), Some("9q5ctq"));
+    assert_eq!(first.map_tile, Some(MapTile { x: 702, y: 1635, z: 12 }));
+
+    assert_eq!(buckets.apply(&mut fix(34.0501, -118.2401, start + Duration::seconds(60))), None);
+    let transition = buckets.apply(&mut fix(34.06, -118.24, start + Duration::seconds(90))).unwrap();
+    assert_eq!((transition.from.as_str(), transition.to.as_str()), ("9q5ctq", "9q5cv2"));
+
+    let mut no_fix = fix(0.0, 0.0, start + Duration::seconds(120));
+    no_fix.latitude = None;
+    assert_eq!(buckets.apply(&mut no_fix), None);
+    assert_eq!(no_fix.geohash, None);
+
+    let report = buckets.report();
+    assert_eq!(report["cell"], json!("9q5cv2"));
+    assert_eq!(report["today"]["dwell_secs"], json!({"9q5ctq": 90}));
+    assert_eq!(report["transitions"].as_array().unwrap().len(), 1);
+}
+
+#[test]
+fn dwell_skips_long_gaps_and_rolls_over_at_midnight() {
+    let mut buckets = buckets(5, None);
+    let evening = Utc.with_ymd_and_hms(2026, 3, 2, 23, 50, 0).unwrap();
+    buckets.apply(&mut fix(42.6, -5.6, evening));
+    buckets.apply(&mut fix(42.6, -5.6, evening + Duration::seconds(300)));
+    // Off for an hour; the gap is not credited
+    buckets.apply(&mut fix(42.6, -5.6, evening + Duration::seconds(300 + 3600)));
+    buckets.apply(&mut fix(42.6, -5.6, evening + Duration::seconds(300 + 3600 + 120)));
+
+    let report = buckets.report();
+    assert_eq!(report["yesterday"]["day"], json!("2026-03-02"));
+    assert_eq!(report["yesterday"]["dwell_secs"], json!({"ezs42": 300}));
+    assert_eq!(report["today"]["day"], json!("2026-03-03"));
+    assert_eq!(report["today"]["dwell_secs"], json!({"ezs42": 120}));
+}
+
+#[test]
+fn changing_precision_starts_afresh_without_a_transition() {
+    let mut buckets = buckets(6, None);
+    let start = Utc.with_ymd_and_hms(2026, 3, 2, 12, 0, 0).unwrap();
+    buckets.apply(&mut fix(34.05, -118.24, start));
+
+    buckets.reconfigure(Some(GeoBucketConfig { precision: 4, tile_zoom: None }));
+    let mut coarser = fix(34.05, -118.24, start + Duration::seconds(30));
+    assert_eq!(buckets.apply(&mut coarser), None);
+    assert_eq!(coarser.geohash.as_deref(), Some("9q5c"));
+    assert_eq!(buckets.report()["precision"], json!(4));
+
+    buckets.reconfigure(None);
+    let mut off = fix(34.05, -118.24, start + Duration::seconds(60));
+    assert_eq!(buckets.apply(&mut off), None);
+    assert_eq!(off.geohash, None);
+    assert!(buckets.report().is_null());
+}
+
+#[test]
+fn dwell_times_survive_a_restart() {
+    let db_path = std::env::temp_dir().join(format!("geo_{}.db", uuid::Uuid::new_v4()));
+    let conn = storage::init_at(&db_path).unwrap();
+    let config = GeoBucketConfig { precision: 6, tile_zoom: None };
+    let mut buckets = GeoBuckets::load(Some(config.clone()), &conn).unwrap();
+    let start = Utc.with_ymd_and_hms(2026, 3, 2, 12, 0, 0).unwrap();
+    buckets.apply(&mut fix(34.05, -118.24, start));
+    buckets.apply(&mut fix(34.05, -118.24, start + Duration::seconds(45)));
+    buckets.checkpoint(&conn).unwrap();
+
+    let mut restarted = GeoBuckets::load(Some(config), &conn).unwrap();
+    assert_eq!(restarted.report(), buckets.report());
+    restarted.apply(&mut fix(34.05, -118.24, start + Duration::seconds(60)));
+    assert_eq!(restarted.report()["today"]["dwell_secs"], json!({"9q5ctq": 60}));
+    let _ = std::fs::remove_file(&db_path);
+}
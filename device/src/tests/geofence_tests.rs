@@ -0,0 +1,110 @@
+use chrono::Utc;
+use serde_json::json;
+use std::collections::HashMap;
+use wiremock::matchers::{body_json, header, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::config::Config;
+use crate::geofence::{self, Geofence, GeofenceCrossing, GeofenceMonitor};
+use crate::net;
+use crate::route::Waypoint;
+use crate::stats::ApiStats;
+use crate::types::{DeviceEvent, Measurement};
+
+const DEPOT: Waypoint = Waypoint { lat: 52.5200, lon: 13.4050 };
+
+fn depot_fence() -> Geofence {
+    Geofence::Circle { lat: DEPOT.lat, lon: DEPOT.lon, radius_m: 500.0 }
+}
+
+fn at(lat: f64, lon: f64) -> Measurement {
+    let mut measurement = super::generate_measurement("1.0.0".to_string(), &Default::default());
+    (measurement.latitude, measurement.longitude) = (Some(lat as f32), Some(lon as f32));
+    measurement
+}
+
+#[test]
+fn haversine_distance_matches_known_distances() {
+    // Berlin to Paris is about 878 km
+    let paris = Waypoint { lat: 48.8566, lon: 2.3522 };
+    let distance = geofence::haversine_m(DEPOT, paris);
+    assert!((877_000.0..879_000.0).contains(&distance), "{}", distance);
+    assert_eq!(geofence::haversine_m(DEPOT, DEPOT), 0.0);
+    // A thousandth of a degree of latitude is about 111 m anywhere
+    let north = geofence::haversine_m(DEPOT, Waypoint { lat: DEPOT.lat + 0.001, ..DEPOT });
+    assert!((110.0..112.5).contains(&north), "{}", north);
+}
+
+#[test]
+fn positions_inside_and_outside_a_circular_fence() {
+    let fence = depot_fence();
+    assert!(fence.contains(DEPOT));
+    assert!(fence.contains(Waypoint { lat: DEPOT.lat + 0.004, ..DEPOT })); // About 445 m north
+    assert!(!fence.contains(Waypoint { lat: DEPOT.lat + 0.005, ..DEPOT })); // About 556 m north
+    assert!(!fence.contains(Waypoint { lat: 48.8566, lon: 2.3522 }));
+}
+
+#[test]
+fn positions_inside_and_outside_a_polygon_fence() {
+    // An L-shaped yard: the notch in its top right corner is outside
+    let corners = [(0.0, 0.0), (0.0, 2.0), (1.0, 2.0), (1.0, 1.0), (2.0, 1.0), (2.0, 0.0)];
+    let fence = Geofence::Polygon { points: corners.iter().map(|&(lat, lon)| Waypoint { lat, lon }).collect() };
+    assert!(fence.contains(Waypoint { lat: 0.5, lon: 1.5 }));
+    assert!(fence.contains(Waypoint { lat: 1.5, lon: 0.5 }));
+    assert!(!fence.contains(Waypoint { lat: 1.5, lon: 1.5 }));
+    assert!(!fence.contains(Waypoint { lat: -0.5, lon: 0.5 }));
+
+    assert!(Geofence::Polygon { points: vec![DEPOT, DEPOT] }.validate().is_err());
+    assert!(Geofence::Circle { lat: 91.0, lon: 0.0, radius_m: 10.0 }.validate().is_err());
+    assert!(Geofence::Circle { lat: 0.0, lon: 0.0, radius_m: 0.0 }.validate().is_err());
+    assert!(depot_fence().validate().is_ok());
+}
+
+#[test]
+fn leaving_and_reentering_are_reported_once_each_time() {
+    let mut monitor = GeofenceMonitor::new(depot_fence());
+    assert_eq!(monitor.check(&at(DEPOT.lat, DEPOT.lon)), None);
+    let outside = at(DEPOT.lat + 0.01, DEPOT.lon);
+    let Some(DeviceEvent::GeofenceBreach(breach)) = monitor.check(&outside) else { panic!("no breach") };
+    assert_eq!(breach.timestamp, outside.timestamp);
+    assert!((breach.latitude - (DEPOT.lat + 0.01)).abs() < 1e-4, "{:?}", breach);
+    // Still outside is the same breach
+    assert_eq!(monitor.check(&at(DEPOT.lat + 0.02, DEPOT.lon)), None);
+    // Samples without a fix neither end nor start one
+    let mut no_fix = at(DEPOT.lat, DEPOT.lon);
+    (no_fix.latitude, no_fix.longitude) = (None, None);
+    assert_eq!(monitor.check(&no_fix), None);
+    let back = at(DEPOT.lat, DEPOT.lon);
+    assert_eq!(monitor.check(&back), Some(DeviceEvent::GeofenceReentry(GeofenceCrossing { timestamp: back.timestamp, latitude: DEPOT.lat as f32 as f64, longitude: DEPOT.lon as f32 as f64 })));
+    // Still inside is the same re-entry
+    assert_eq!(monitor.check(&at(DEPOT.lat + 0.001, DEPOT.lon)), None);
+    assert!(matches!(monitor.check(&at(DEPOT.lat - 0.01, DEPOT.lon)), Some(DeviceEvent::GeofenceBreach(_))));
+
+    // A device that boots outside its fence is in breach from its first fix, and one that
+    // boots inside has nothing to report
+    assert!(matches!(GeofenceMonitor::new(depot_fence()).check(&outside), Some(DeviceEvent::GeofenceBreach(_))));
+    assert_eq!(GeofenceMonitor::new(depot_fence()).check(&back), None);
+}
+
+#[tokio::test]
+async fn a_breach_is_sent_to_the_events_endpoint() {
+    let server = MockServer::start().await;
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("GEOFENCE".to_string(), json!({"shape": "circle", "lat": DEPOT.lat, "lon": DEPOT.lon, "radius_m": 500.0}).to_string()),
+    ]);
+    let (config, report) = Config::from_env_vars(&env);
+    assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    assert_eq!(config.geofence, Some(depot_fence()));
+
+    let breach = GeofenceCrossing { timestamp: Utc::now(), latitude: 52.53, longitude: 13.405 };
+    Mock::given(method("POST")).and(path(format!("/api/devices/{}/events", config.device_id)))
+        .and(header("X-Auth-Token", "token"))
+        .and(body_json(json!({"event_type": "geofence_breach", "timestamp": breach.timestamp, "latitude": 52.53, "longitude": 13.405})))
+        .respond_with(ResponseTemplate::new(202))
+        .expect(1)
+        .mount(&server)
+        .await;
+    net::send_event(&reqwest::Client::new(), &config, &ApiStats::default(), &DeviceEvent::GeofenceBreach(breach)).await.unwrap();
+}
@@ -0,0 +1,123 @@
+use chrono::{TimeZone, Utc};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use crate::config::Config;
+use crate::health::{HealthServer, HealthState, HealthStatus};
+
+fn device_config() -> Config {
+    let env = HashMap::from([
+        ("DEVICE_ID".to_string(), "device-1".to_string()),
+        ("AUTH_TOKEN".to_string(), "secret-token".to_string()),
+        ("HEALTH_PORT".to_string(), "9100".to_string()),
+    ]);
+    Config::from_env_vars(&env).0
+}
+
+async fn serve(state: HealthState) -> (String, HealthServer) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    (url, HealthServer::serve(listener, state))
+}
+
+async fn get(url: String) -> Value {
+    let response = reqwest::get(url).await.unwrap();
+    assert_eq!(response.status(), 200);
+    response.json().await.unwrap()
+}
+
+#[tokio::test]
+async fn healthz_reports_the_running_device() {
+    let config = device_config();
+    assert_eq!(config.health_port, Some(9100));
+    let state = HealthState::new(HealthStatus {
+        device_id: config.device_id.clone(),
+        firmware_version: "1.2.0".to_string(),
+        active_slot: "B".to_string(),
+        buffered_measurements: 0,
+        last_upload_at: None,
+    }, &config);
+    let (url, _server) = serve(state.clone()).await;
+    assert_eq!(get(format!("{}/healthz", url)).await["last_upload_at"], Value::Null);
+
+    // Served as the main loop updates it
+    let uploaded_at = Utc.with_ymd_and_hms(2026, 3, 1, 8, 0, 0).unwrap();
+    state.update(|status| {
+        status.buffered_measurements = 42;
+        status.last_upload_at = Some(uploaded_at);
+    });
+    assert_eq!(get(format!("{}/healthz", url)).await, json!({
+        "device_id": "device-1",
+        "firmware_version": "1.2.0",
+        "active_slot": "B",
+        "buffered_measurements": 42,
+        "last_upload_at": "2026-03-01T08:00:00Z",
+    }));
+}
+
+#[tokio::test]
+async fn metrics_count_what_the_device_did() {
+    let config = device_config();
+    let state = HealthState::new(HealthStatus {
+        device_id: config.device_id.clone(),
+        firmware_version: "1.0.0".to_string(),
+        active_slot: "A".to_string(),
+        buffered_measurements: 2,
+        last_upload_at: None,
+    }, &config);
+    let (url, _server) = serve(state.clone()).await;
+
+    // As the sample and upload ticks count them
+    let mut last_battery = 0.0;
+    for _ in 0..3 {
+        let measurement = super::generate_measurement("1.0.0".to_string(), &Default::default());
+        state.metrics().measurements_generated.inc();
+        state.metrics().battery_level.set(measurement.battery as f64);
+        last_battery = measurement.battery as f64;
+    }
+    state.update(|status| status.buffered_measurements += 3);
+    state.metrics().measurements_uploaded.inc_by(2);
+    state.metrics().upload_failures.inc();
+    state.metrics().heartbeats_sent.inc();
+
+    let response = reqwest::get(format!("{}/metrics", url)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/plain"));
+    let scraped = response.text().await.unwrap();
+    let sample = |name: &str| scraped.lines()
+        .find_map(|line| line.strip_prefix(&format!("{}{{device_id=\"device-1\"}} ", name)))
+        .unwrap_or_else(|| panic!("{} missing from {}", name, scraped))
+        .parse::<f64>()
+        .unwrap();
+    assert_eq!(sample("device_measurements_generated_total"), 3.0);
+    assert_eq!(sample("device_measurements_uploaded_total"), 2.0);
+    assert_eq!(sample("device_upload_failures_total"), 1.0);
+    assert_eq!(sample("device_heartbeats_sent_total"), 1.0);
+    assert_eq!(sample("device_ota_checks_total"), 0.0);
+    assert_eq!(sample("device_buffered_measurements"), 5.0);
+    assert_eq!(sample("device_battery_level"), last_battery);
+}
+
+#[tokio::test]
+async fn config_is_served_without_credentials() {
+    let config = device_config();
+    let state = HealthState::new(HealthStatus {
+        device_id: config.device_id.clone(),
+        firmware_version: "1.0.0".to_string(),
+        active_slot: "A".to_string(),
+        buffered_measurements: 0,
+        last_upload_at: None,
+    }, &config);
+    let (url, server) = serve(state).await;
+
+    let served = get(format!("{}/config", url)).await;
+    assert_eq!(served["device_id"], json!("device-1"));
+    assert_eq!(served["auth_token"], json!("<redacted>"));
+    assert!(!served.to_string().contains("secret-token"));
+    assert_eq!(served["health_port"], json!(9100));
+
+    // Stopping the server frees the port for the next boot
+    drop(server);
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(reqwest::get(format!("{}/healthz", url)).await.is_err());
+}
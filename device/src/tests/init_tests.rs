@@ -0,0 +1,167 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::PathBuf;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::config::{self, Config};
+use crate::init::{self, InitArgs};
+use crate::network::NetworkType;
+
+async fn backend() -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/health"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"status": "ok"})))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST")).and(path("/api/devices/register"))
+        .respond_with(move |_: &wiremock::Request| ResponseTemplate::new(200).set_body_json(json!({
+            "device_id": uuid::Uuid::new_v4(),
+            "auth_token": uuid::Uuid::new_v4(),
+            "desired_sample_interval_secs": 10,
+            "desired_upload_interval_secs": 60,
+            "desired_heartbeat_interval_secs": 30,
+        })))
+        .mount(&server)
+        .await;
+    server
+}
+
+fn config_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("init_{}", uuid::Uuid::new_v4()))
+}
+
+fn args(flags: &[&str]) -> InitArgs {
+    InitArgs::parse(&flags.iter().map(|flag| flag.to_string()).collect::<Vec<_>>()).unwrap()
+}
+
+async fn run(args: &InitArgs, vars: &HashMap<String, String>, input: &str) -> (anyhow::Result<init::Identity>, String) {
+    let mut output = Vec::new();
+    let result = init::init(args, vars, &mut Cursor::new(input.as_bytes()), &mut output).await;
+    (result, String::from_utf8(output).unwrap())
+}
+
+#[tokio::test]
+async fn non_interactive_init_registers_and_writes_config() {
+    let server = backend().await;
+    let dir = config_dir();
+    let dir_arg = dir.display().to_string();
+    let uri = server.uri();
+    let args = args(&[
+        "--non-interactive", "--config-dir", &dir_arg, "--backend-url", &uri, "--profile", "low_power",
+        "--device-name", "pump-7", "--region", "eu-west", "--provisioning-token", "enroll-123", "--heartbeat-interval-secs", "600",
+    ]);
+    let (identity, output) = run(&args, &HashMap::new(), "").await;
+    let identity = identity.unwrap();
+    assert!(output.is_empty(), "no prompts: {}", output);
+
+    let config = Config::load_from(&dir.join(config::CONFIG_FILE)).unwrap();
+    assert_eq!(identity.device_id, config.device_id);
+    assert_eq!(identity.device_name, "pump-7");
+    assert_eq!(identity.config_file, dir.join(config::CONFIG_FILE));
+    assert!(config.auth_token.is_some());
+    assert_eq!(config.backend_url, uri);
+    assert_eq!(config.region.as_deref(), Some("eu-west"));
+    assert_eq!(config.network, Some(NetworkType::NbIot));
+    // Preset intervals, with the explicit answer taking precedence
+    assert_eq!((config.sample_interval_secs, config.upload_interval_secs, config.heartbeat_interval_secs), (300, 3600, 600));
+    assert_eq!(config.desired_shadow_state, Some(json!({})));
+
+    let requests = server.received_requests().await.unwrap();
+    let register = requests.iter().find(|request| request.url.path() == "/api/devices/register").unwrap();
+    let body: Value = serde_json::from_slice(&register.body).unwrap();
+    assert_eq!(body["provisioning_token"], "enroll-123");
+    assert_eq!(body["device_name"], "pump-7");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn existing_identity_is_only_replaced_with_force() {
+    let server = backend().await;
+    let dir = config_dir();
+    let dir_arg = dir.display().to_string();
+    let uri = server.uri();
+    let flags = ["--non-interactive", "--config-dir", &dir_arg, "--backend-url", &uri];
+    let first = run(&args(&flags), &HashMap::new(), "").await.0.unwrap();
+
+    let refused = run(&args(&flags), &HashMap::new(), "").await.0.unwrap_err();
+    assert!(refused.to_string().contains("--force"), "{}", refused);
+    assert_eq!(Config::load_from(&dir.join(config::CONFIG_FILE)).unwrap().device_id, first.device_id);
+
+    let forced = run(&args(&[&flags[..], &["--force"]].concat()), &HashMap::new(), "").await.0.unwrap();
+    assert_ne!(forced.device_id, first.device_id);
+    assert_eq!(Config::load_from(&dir.join(config::CONFIG_FILE)).unwrap().device_id, forced.device_id);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn values_from_env_skip_their_prompts() {
+    let server = backend().await;
+    let dir = config_dir();
+    let vars = HashMap::from([
+        ("CONFIG_DIR".to_string(), dir.display().to_string()),
+        ("VF_BACKEND_URL".to_string(), server.uri()),
+        ("PROVISIONING_TOKEN".to_string(), "from-env".to_string()),
+        ("DEVICE_NAME".to_string(), "env-name".to_string()),
+        ("REGION".to_string(), "us-east".to_string()),
+        ("SAMPLE_INTERVAL_SECS".to_string(), "20".to_string()),
+        ("UPLOAD_INTERVAL_SECS".to_string(), "120".to_string()),
+        ("HEARTBEAT_INTERVAL_SECS".to_string(), "45".to_string()),
+    ]);
+    // Interactive, but the only open question is answered by a flag: any prompt would hit end of input
+    let (identity, output) = run(&args(&["--profile", "cellular"]), &vars, "").await;
+    identity.unwrap();
+    assert!(output.is_empty(), "{}", output);
+
+    let config = Config::load_from(&dir.join(config::CONFIG_FILE)).unwrap();
+    assert_eq!(config.device_name.as_deref(), Some("env-name"));
+    assert_eq!((config.sample_interval_secs, config.upload_interval_secs, config.heartbeat_interval_secs), (20, 120, 45));
+    assert_eq!(config.network, Some(NetworkType::Lte));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn prompts_take_defaults_and_reask_invalid_intervals() {
+    let server = backend().await;
+    let dir = config_dir();
+    let dir_arg = dir.display().to_string();
+    // Backend URL, token, profile, name, region, then an out-of-bounds sample interval before a valid one
+    let input = format!("{}\n\n\n\n\n0\n5\n\n\n", server.uri());
+    let (identity, output) = run(&args(&["--config-dir", &dir_arg]), &HashMap::new(), &input).await;
+    identity.unwrap();
+    assert!(output.contains("Backend URL [http://localhost:8000]: "), "{}", output);
+    assert!(output.contains("sample_interval_secs must be between 1 and 3600"), "{}", output);
+
+    let config = Config::load_from(&dir.join(config::CONFIG_FILE)).unwrap();
+    assert_eq!(config.backend_url, server.uri());
+    assert_eq!((config.sample_interval_secs, config.upload_interval_secs, config.heartbeat_interval_secs), (5, 60, 30));
+    assert_eq!((config.device_name, config.region, config.network), (None, None, None));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn unreachable_backend_or_bad_answers_write_nothing() {
+    let dir = config_dir();
+    let dir_arg = dir.display().to_string();
+    let unreachable = run(&args(&["--non-interactive", "--config-dir", &dir_arg, "--backend-url", "http://127.0.0.1:9"]), &HashMap::new(), "").await.0;
+    assert!(unreachable.unwrap_err().to_string().contains("not reachable"));
+
+    let server = backend().await;
+    let uri = server.uri();
+    let bad_profile = run(&args(&["--non-interactive", "--config-dir", &dir_arg, "--backend-url", &uri, "--profile", "rocket"]), &HashMap::new(), "").await.0;
+    assert!(bad_profile.unwrap_err().to_string().contains("unknown profile"));
+    let bad_interval = run(&args(&["--non-interactive", "--config-dir", &dir_arg, "--backend-url", &uri, "--upload-interval-secs", "0"]), &HashMap::new(), "").await.0;
+    assert!(bad_interval.unwrap_err().to_string().contains("upload_interval_secs must be greater than zero"));
+    assert!(!dir.exists());
+}
+
+#[test]
+fn parses_arguments() {
+    let parsed = args(&["--non-interactive", "--force", "--sample-interval-secs", "15"]);
+    assert!(parsed.non_interactive && parsed.force);
+    assert_eq!(parsed.answers.sample_interval_secs, Some(15));
+    assert!(InitArgs::parse(&["--sample-interval-secs".to_string(), "often".to_string()]).is_err());
+    assert!(InitArgs::parse(&["--region".to_string()]).is_err());
+    assert!(InitArgs::parse(&["--yes".to_string()]).is_err());
+}
@@ -1,6 +1,22 @@
-// This is a placeholder for integration tests.
-// In a real project, you would have tests that perhaps run the device binary
-// and mock the backend API to verify end-to-end device behavior.
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use wiremock::matchers::{method, path, path_regex};
+use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+use super::{fleet_tests, net_tests};
+use crate::config::Config;
+use crate::error::DeviceError;
+use crate::fleet::{self, DevicePaths};
+use crate::runner::{FleetRunner, Launch, MemberStatus, ScaleDownSelection};
+use crate::shadow_report::ShadowReportGuard;
+use crate::shutdown::{self, FinalReport, StopMode};
+use crate::stats::ApiStats;
+use crate::{init, storage};
 
 #[test]
 fn sample_integration_test() {
@@ -11,3 +27,138 @@ fn sample_integration_test() {
     // 4. Assert that the mock server received the expected requests.
     assert_eq!(2 + 2, 4);
 }
+
+// Registers as it first boots, samples every few milliseconds, and stops as the main loop does
+fn sampling_devices(base: Config, produced: Arc<Mutex<HashSet<u32>>>) -> Launch {
+    Arc::new(move |paths: DevicePaths, mut stop_requests: mpsc::Receiver<StopMode>| -> BoxFuture<'static, Result<(), DeviceError>> {
+        let (base, produced) = (base.clone(), produced.clone());
+        async move {
+            let client = reqwest::Client::new();
+            let config = match Config::load_from(&paths.config) {
+                Ok(config) => config,
+                Err(_) => {
+                    let mut config = fleet::boot_config(&base, paths.index);
+                    init::register(&client, &mut config, None).await?;
+                    config.save_to(&paths.config)?;
+                    config
+                }
+            };
+            let mut conn = storage::init_at(&paths.database)?;
+            let mut sampling = tokio::time::interval(Duration::from_millis(5));
+            loop {
+                tokio::select! {
+                    _ = sampling.tick() => {
+                        let measurement = super::generate_measurement("1.0.0".to_string(), &Default::default());
+                        storage::append_measurement(&conn, &measurement, 0)?;
+                        produced.lock().unwrap().insert(measurement.sequence_number);
+                    }
+                    Some(mode) = stop_requests.recv() => {
+                        if mode == StopMode::Graceful {
+                            let mut reported = json!({});
+                            let report = FinalReport { state: &mut reported, connection: "suspended", guard: &mut ShadowReportGuard::default() };
+                            shutdown::flush(&client, &config, &ApiStats::default(), &mut conn, None, report).await;
+                        }
+                        return Ok(());
+                    }
+                }
+            }
+        }
+        .boxed()
+    })
+}
+
+async fn backend() -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/register"))
+        .respond_with(|_: &Request| ResponseTemplate::new(200).set_body_json(fleet_tests::identity()))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest")).respond_with(ResponseTemplate::new(204)).mount(&server).await;
+    Mock::given(method("PATCH")).and(path_regex("^/api/devices/[^/]+/shadow$")).respond_with(ResponseTemplate::new(200)).mount(&server).await;
+    server
+}
+
+fn pending(paths: &DevicePaths) -> u64 {
+    storage::pending_count(&storage::init_at(&paths.database).unwrap()).unwrap()
+}
+
+fn device_ids(runner: &FleetRunner) -> Vec<Option<String>> {
+    runner.summary().devices.into_iter().map(|device| device.device_id).collect()
+}
+
+// Waits for every device to register, which a loaded test run can take a while to do
+async fn registered(runner: &FleetRunner) -> Vec<Option<String>> {
+    for _ in 0..100 {
+        if device_ids(runner).iter().all(Option::is_some) {
+            break;
+        }
+        sample_for(50).await;
+    }
+    device_ids(runner)
+}
+
+async fn sample_for(millis: u64) {
+    tokio::time::sleep(Duration::from_millis(millis)).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn scaling_down_and_back_up_loses_no_measurement_and_keeps_every_identity() {
+    let server = backend().await;
+    let dir = std::env::temp_dir().join(format!("fleet_scaling_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("DEVICE_NAME".to_string(), "sensor".to_string()),
+        ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()),
+        ("UPLOAD_BATCH_SIZE".to_string(), "20".to_string()),
+    ]);
+    let produced = Arc::new(Mutex::new(HashSet::new()));
+    let members: Vec<DevicePaths> = (0..6).map(|index| fleet_tests::member_under(&dir, index)).collect();
+    let under = dir.clone();
+    let runner = FleetRunner::launch(
+        members.clone(),
+        Arc::new(move |index| fleet_tests::member_under(&under, index)),
+        sampling_devices(Config::from_env_vars(&env).0, produced.clone()),
+    );
+    let identities = registered(&runner).await;
+    assert!(identities.iter().all(Option::is_some), "{:?}", identities);
+
+    let summary = runner.scale(4, &ScaleDownSelection::NewestFirst, StopMode::Graceful).await;
+    assert_eq!((summary.running, summary.suspended, summary.failed), (4, 2, 0));
+    assert_eq!(summary.devices[4].status, MemberStatus::Suspended);
+    // The suspended devices drained their backlog and kept their state directory
+    assert_eq!((pending(&members[4]), pending(&members[5])), (0, 0));
+    assert!(members[5].config.exists());
+    sample_for(50).await;
+
+    let summary = runner.scale(6, &ScaleDownSelection::NewestFirst, StopMode::Graceful).await;
+    assert_eq!(summary.running, 6);
+    // An immediate stop keeps the backlog for the next start instead of uploading it
+    runner.stop(0, StopMode::Immediate).await;
+    assert!(pending(&members[0]) > 0);
+    runner.start(0);
+    sample_for(50).await;
+
+    runner.scale(0, &ScaleDownSelection::NewestFirst, StopMode::Graceful).await;
+    runner.stopped().await;
+    assert_eq!(device_ids(&runner), identities);
+
+    let requests = server.received_requests().await.unwrap();
+    let registrations = requests.iter().filter(|request| request.url.path() == "/api/devices/register").count();
+    assert_eq!(registrations, 6);
+    let ingested: Vec<u32> = requests.iter()
+        .filter(|request| request.url.path() == "/api/devices/ingest")
+        .flat_map(|request| net_tests::ingest_payload(request).measurements)
+        .map(|measurement| measurement.sequence_number)
+        .collect();
+    let uploaded: HashSet<u32> = ingested.iter().copied().collect();
+    assert_eq!(uploaded.len(), ingested.len(), "a measurement was uploaded twice");
+    assert_eq!(uploaded, *produced.lock().unwrap());
+    assert!(members.iter().all(|paths| pending(paths) == 0));
+    let suspended = requests.iter()
+        .filter(|request| request.method.as_str() == "PATCH")
+        .filter(|request| serde_json::from_slice::<Value>(&net_tests::decoded_body(request)).unwrap()["state"]["connection"] == json!("suspended"))
+        .count();
+    assert!(suspended >= 8, "{} suspended reports", suspended);
+    let _ = std::fs::remove_dir_all(&dir);
+}
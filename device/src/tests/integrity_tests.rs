@@ -0,0 +1,168 @@
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+use super::net_tests;
+use crate::config::Config;
+use crate::export;
+use crate::integrity::{self, Verification};
+use crate::ota;
+use crate::stats::ApiStats;
+use crate::storage;
+use crate::types::{IngestFeedback, Measurement};
+use crate::upload;
+
+fn hashed() -> Measurement {
+    let mut measurement = super::generate_measurement("1.0.0".to_string(), &Default::default());
+    measurement.content_hash = Some(integrity::content_hash(&measurement));
+    measurement
+}
+
+#[test]
+fn canonical_json_sorts_keys_at_every_level() {
+    let value = json!({"b": 1, "a": {"d": [true, null], "c": "x y"}});
+    assert_eq!(integrity::canonical_json(&value), r#"{"a":{"c":"x y","d":[true,null]},"b":1}"#);
+    let reordered: Value = serde_json::from_str(r#"{"a": {"d": [true, null], "c": "x y"}, "b": 1}"#).unwrap();
+    assert_eq!(integrity::canonical_json(&reordered), integrity::canonical_json(&value));
+}
+
+#[test]
+fn the_content_hash_covers_what_is_sent_and_nothing_else() {
+    let measurement = hashed();
+    let hash = measurement.content_hash.clone().unwrap();
+    assert_eq!(hash.len(), 64);
+    // Unchanged by the hash being set, or by the copy being marked as a replay
+    assert_eq!(integrity::content_hash(&measurement), hash);
+    assert_eq!(integrity::content_hash(&Measurement { replay: Some(true), ..measurement.clone() }), hash);
+    // A field its cadence leaves out is not sent, so it is not hashed either
+    let omitted = Measurement { omitted: vec!["humidity".to_string()], ..measurement.clone() };
+    assert_eq!(integrity::content_hash(&Measurement { humidity: omitted.humidity + 5.0, ..omitted.clone() }), integrity::content_hash(&omitted));
+    assert_ne!(integrity::content_hash(&Measurement { temp: measurement.temp + 0.1, ..measurement.clone() }), hash);
+    // The hash travels with the measurement through a round trip of its serialization
+    let received: Measurement = serde_json::from_value(json!(measurement)).unwrap();
+    assert_eq!(integrity::content_hash(&received), hash);
+}
+
+#[test]
+fn merkle_roots_pair_leaves_and_carry_an_odd_one_up() {
+    let leaves: Vec<String> = ["a", "b", "c"].iter().map(|leaf| export::sha256_hex(leaf.as_bytes())).collect();
+    let parent = |left: &str, right: &str| {
+        let digest = Sha256::new().chain_update(ota::decode_hex(left).unwrap()).chain_update(ota::decode_hex(right).unwrap()).finalize();
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()
+    };
+    assert_eq!(integrity::merkle_root(&[]), None);
+    assert_eq!(integrity::merkle_root(&[&leaves[0]]), Some(leaves[0].clone()));
+    let ab = parent(&leaves[0], &leaves[1]);
+    assert_eq!(integrity::merkle_root(&[&leaves[0], &leaves[1]]), Some(ab.clone()));
+    assert_eq!(integrity::merkle_root(&[&leaves[0], &leaves[1], &leaves[2]]), Some(parent(&ab, &leaves[2])));
+    assert_eq!(integrity::merkle_root(&["not hex"]), None);
+}
+
+#[test]
+fn acknowledgements_are_checked_against_the_hashes_sent() {
+    let batch = vec![hashed(), hashed()];
+    let hashes: Vec<String> = batch.iter().map(|measurement| measurement.content_hash.clone().unwrap()).collect();
+    let echo = |accepted_hashes: Option<Vec<String>>, merkle_root: Option<String>| IngestFeedback { accepted_hashes, merkle_root, ..Default::default() };
+
+    assert_eq!(integrity::verify(&batch, None), Verification::Unsupported);
+    assert_eq!(integrity::verify(&batch, Some(&IngestFeedback::default())), Verification::Unsupported);
+    let uppercase = hashes.iter().map(|hash| hash.to_uppercase()).collect();
+    assert_eq!(integrity::verify(&batch, Some(&echo(Some(uppercase), None))), Verification::Verified);
+    let root = integrity::merkle_root(&[&hashes[0], &hashes[1]]);
+    assert_eq!(integrity::verify(&batch, Some(&echo(None, root))), Verification::Verified);
+
+    let only_first = integrity::verify(&batch, Some(&echo(Some(vec![hashes[0].clone()]), None)));
+    assert!(matches!(only_first, Verification::Mismatch { ref suspect, .. } if suspect == &vec![1]), "{:?}", only_first);
+    let swapped = integrity::merkle_root(&[&hashes[1], &hashes[0]]);
+    assert!(matches!(integrity::verify(&batch, Some(&echo(None, swapped))), Verification::Mismatch { ref suspect, .. } if suspect.len() == 2));
+}
+
+// Acknowledges each batch with the hashes of what it received, recomputed on its side,
+// optionally altering one measurement on the way in
+struct HashEcho {
+    tamper: Option<u32>, // Sequence number of the measurement to alter
+}
+
+impl Respond for HashEcho {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let accepted: Vec<String> = net_tests::ingest_payload(request).measurements.into_iter().map(|mut measurement| {
+            if Some(measurement.sequence_number) == self.tamper {
+                measurement.temp += 1.0;
+            }
+            integrity::content_hash(&measurement)
+        }).collect();
+        ResponseTemplate::new(200).set_body_json(json!({"accepted_hashes": accepted}))
+    }
+}
+
+// Drains three hashed rows against the backend `respond` builds for them; returns the
+// round and the rows left pending and flagged
+async fn drain_against<R: Respond + 'static>(respond: impl FnOnce(&[Measurement]) -> R) -> (upload::UploadRound, u64, u64) {
+    let server = MockServer::start().await;
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+    ]);
+    let (config, _) = Config::from_env_vars(&env);
+    let db_path = std::env::temp_dir().join(format!("integrity_{}.db", uuid::Uuid::new_v4()));
+    let mut conn = storage::init_at(&db_path).unwrap();
+    let batch: Vec<Measurement> = (0..3).map(|_| hashed()).collect();
+    for measurement in &batch {
+        storage::append_measurement(&conn, measurement, 0).unwrap();
+    }
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(respond(&batch))
+        .mount(&server)
+        .await;
+
+    let round = upload::drain_once(&reqwest::Client::new(), &config, &ApiStats::default(), &mut conn, None).await.unwrap();
+    let (pending, flagged) = (storage::pending_count(&conn).unwrap(), storage::integrity_flagged_count(&conn).unwrap());
+    let _ = std::fs::remove_file(&db_path);
+    (round, pending, flagged)
+}
+
+#[tokio::test]
+async fn a_tampered_echo_nacks_the_batch_and_flags_the_row() {
+    let mut tampered = 0;
+    let (round, pending, flagged) = drain_against(|batch| {
+        tampered = batch[1].sequence_number;
+        HashEcho { tamper: Some(tampered) }
+    }).await;
+    assert_eq!(round.uploaded(), 0);
+    assert!(round.batches[0].error.as_deref().unwrap().starts_with("integrity mismatch"), "{:?}", round.batches);
+    // Nothing is deleted, and only the altered row is under suspicion
+    assert_eq!((pending, flagged), (3, 1));
+    assert_eq!(round.integrity_mismatches.len(), 1);
+    assert_eq!(round.integrity_mismatches[0].sequence_numbers, vec![tampered]);
+    assert_eq!(json!(crate::types::DeviceEvent::IntegrityMismatch(round.integrity_mismatches[0].clone()))["event_type"], "integrity_mismatch");
+}
+
+#[tokio::test]
+async fn a_matching_echo_confirms_the_batch() {
+    let (round, pending, flagged) = drain_against(|_| HashEcho { tamper: None }).await;
+    assert_eq!(round.uploaded(), 3);
+    assert!(round.integrity_mismatches.is_empty());
+    assert_eq!((pending, flagged), (0, 0));
+}
+
+#[tokio::test]
+async fn a_backend_that_echoes_nothing_is_trusted_as_before() {
+    let (round, pending, flagged) = drain_against(|_| ResponseTemplate::new(204)).await;
+    assert_eq!(round.uploaded(), 3);
+    assert!(round.integrity_mismatches.is_empty());
+    assert_eq!((pending, flagged), (0, 0));
+}
+
+// Same measurement and hash as the backend's test_ingest_echoes_the_hashes_of_what_it_stored,
+// so the two canonical serializations cannot drift apart unnoticed
+#[test]
+fn the_content_hash_of_a_fixed_measurement_matches_the_backend() {
+    let measurement: Measurement = serde_json::from_value(json!({
+        "timestamp": "2026-01-08T12:00:00Z", "temp": 21.3, "humidity": 48.5, "battery": 0.87, "sequence_number": 7,
+        "latitude": 52.52, "longitude": 13.405, "speed": null, "firmware_version": "1.0.0",
+        "device_flags": ["spike"], "extra": {"can": {"rpm": 1800, "label": "\u{e9}\u{1}"}},
+    })).unwrap();
+    assert_eq!(integrity::content_hash(&measurement), "9ce2046835c03ac2338e80fc08dff8c5d03a781573adcb1a33590b114f5dd38e");
+}
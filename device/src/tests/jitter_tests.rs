@@ -0,0 +1,76 @@
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+use crate::config::Config;
+use crate::jitter;
+use crate::validation;
+
+#[test]
+fn jittered_periods_stay_within_the_bounds() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let period = Duration::from_secs(60);
+    let periods: Vec<Duration> = (0..10_000).map(|_| jitter::jittered(period, 0.1, &mut rng)).collect();
+    assert!(periods.iter().all(|jittered| (Duration::from_secs(54)..=Duration::from_secs(66)).contains(jittered)));
+    // Spread over the whole range rather than bunched at the period
+    assert!(periods.iter().any(|jittered| *jittered < Duration::from_secs(55)));
+    assert!(periods.iter().any(|jittered| *jittered > Duration::from_secs(65)));
+}
+
+#[test]
+fn no_jitter_keeps_the_period_and_too_much_is_capped() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let period = Duration::from_secs(10);
+    assert_eq!(jitter::jittered(period, 0.0, &mut rng), period);
+    for _ in 0..1000 {
+        let jittered = jitter::jittered(period, 5.0, &mut rng);
+        assert!((Duration::from_secs(5)..=Duration::from_secs(15)).contains(&jittered), "{:?}", jittered);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn every_tick_draws_its_own_period_and_the_first_is_delayed() {
+    let (mut firsts, mut gaps) = (Vec::new(), Vec::new());
+    for _ in 0..20 {
+        let start = Instant::now();
+        let mut timer = jitter::interval(60, 0.1);
+        let mut last = timer.tick().await;
+        firsts.push(last - start);
+        for _ in 0..10 {
+            let tick = timer.tick().await;
+            gaps.push(tick - last);
+            last = tick;
+        }
+    }
+    assert!(firsts.iter().all(|first| *first <= Duration::from_secs(6)), "{:?}", firsts);
+    assert!(firsts.iter().any(|first| *first != firsts[0]));
+    assert!(gaps.iter().all(|gap| (Duration::from_secs(54)..=Duration::from_secs(66)).contains(gap)), "{:?}", gaps);
+    assert!(gaps.iter().any(|gap| *gap != gaps[0]));
+}
+
+#[tokio::test(start_paused = true)]
+async fn without_jitter_an_interval_ticks_at_once_and_then_on_the_period() {
+    let start = Instant::now();
+    let mut timer = jitter::interval(10, 0.0);
+    assert_eq!(timer.tick().await - start, Duration::ZERO);
+    assert_eq!(timer.tick().await - start, Duration::from_secs(10));
+    // A loop that fell behind is not paid back in a burst of ticks
+    tokio::time::sleep(Duration::from_secs(35)).await;
+    assert_eq!(timer.tick().await - start, Duration::from_secs(45));
+    assert_eq!(timer.tick().await - start, Duration::from_secs(55));
+    timer.reset_immediately();
+    assert_eq!(timer.tick().await - start, Duration::from_secs(55));
+}
+
+#[test]
+fn jitter_outside_the_accepted_range_fails_validation() {
+    let env = HashMap::from([("INTERVAL_JITTER".to_string(), "0.8".to_string())]);
+    let config = Config::from_env_vars(&env).0;
+    assert_eq!(config.interval_jitter, 0.8);
+    assert!(validation::check_config(&config).findings.iter().any(|finding| finding.key == "interval_jitter"));
+    let config = Config::from_env_vars(&HashMap::new()).0;
+    assert_eq!(config.interval_jitter, 0.1);
+    assert!(!validation::check_config(&config).findings.iter().any(|finding| finding.key == "interval_jitter"));
+}
@@ -0,0 +1,59 @@
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+
+use crate::localtime;
+
+// Local wall-clock readings every 30 minutes for three hours from `start`
+fn wall_clock(tz: &str, start: &str, broken_dst: bool) -> Vec<String> {
+    let tz: Tz = localtime::parse_timezone(tz).unwrap();
+    let start: DateTime<Utc> = start.parse().unwrap();
+    (0..6)
+        .map(|step| localtime::local_time(tz, start + Duration::minutes(30 * step), broken_dst).format("%H:%M%:z").to_string())
+        .collect()
+}
+
+#[test]
+fn new_york_spring_forward() {
+    let correct = wall_clock("America/New_York", "2024-03-10T06:00:00Z", false);
+    assert_eq!(correct, ["01:00-05:00", "01:30-05:00", "03:00-04:00", "03:30-04:00", "04:00-04:00", "04:30-04:00"]);
+    // Broken: emits the non-existent 02:xx hour, then skips 03:xx
+    let broken = wall_clock("America/New_York", "2024-03-10T06:00:00Z", true);
+    assert_eq!(broken, ["01:00-05:00", "01:30-05:00", "02:00-05:00", "02:30-05:00", "04:00-04:00", "04:30-04:00"]);
+}
+
+#[test]
+fn new_york_fall_back() {
+    let correct = wall_clock("America/New_York", "2024-11-03T05:00:00Z", false);
+    assert_eq!(correct, ["01:00-04:00", "01:30-04:00", "01:00-05:00", "01:30-05:00", "02:00-05:00", "02:30-05:00"]);
+    // Broken: 02:xx is emitted twice and the repeated 01:xx is lost
+    let broken = wall_clock("America/New_York", "2024-11-03T05:00:00Z", true);
+    assert_eq!(broken, ["01:00-04:00", "01:30-04:00", "02:00-04:00", "02:30-04:00", "02:00-05:00", "02:30-05:00"]);
+}
+
+#[test]
+fn berlin_spring_forward() {
+    let correct = wall_clock("Europe/Berlin", "2024-03-31T00:00:00Z", false);
+    assert_eq!(correct, ["01:00+01:00", "01:30+01:00", "03:00+02:00", "03:30+02:00", "04:00+02:00", "04:30+02:00"]);
+    let broken = wall_clock("Europe/Berlin", "2024-03-31T00:00:00Z", true);
+    assert_eq!(broken, ["01:00+01:00", "01:30+01:00", "02:00+01:00", "02:30+01:00", "04:00+02:00", "04:30+02:00"]);
+}
+
+#[test]
+fn berlin_fall_back() {
+    let correct = wall_clock("Europe/Berlin", "2024-10-27T00:00:00Z", false);
+    assert_eq!(correct, ["02:00+02:00", "02:30+02:00", "02:00+01:00", "02:30+01:00", "03:00+01:00", "03:30+01:00"]);
+    let broken = wall_clock("Europe/Berlin", "2024-10-27T00:00:00Z", true);
+    assert_eq!(broken, ["02:00+02:00", "02:30+02:00", "03:00+02:00", "03:30+02:00", "03:00+01:00", "03:30+01:00"]);
+}
+
+#[test]
+fn stamp_keeps_utc_and_adds_local_fields() {
+    let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    measurement.timestamp = "2024-07-01T12:00:00Z".parse().unwrap();
+    localtime::stamp(&mut measurement, localtime::parse_timezone("Europe/Berlin").unwrap(), false);
+
+    assert_eq!(measurement.timestamp.to_rfc3339(), "2024-07-01T12:00:00+00:00");
+    assert_eq!(measurement.local_timestamp.as_deref(), Some("2024-07-01T14:00:00+02:00"));
+    assert_eq!(measurement.utc_offset_minutes, Some(120));
+    assert!(localtime::parse_timezone("Mars/Olympus_Mons").is_err());
+}
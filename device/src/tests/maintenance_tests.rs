@@ -0,0 +1,61 @@
+use chrono::{Duration, TimeZone, Utc};
+use serde_json::json;
+
+use crate::maintenance::{self, MaintenanceState};
+
+#[test]
+fn desired_request_enables_with_expiry() {
+    let now = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+    let desired = json!({"enabled": true, "duration_secs": 900});
+
+    let state = maintenance::reconcile(None, Some(&desired), now).unwrap();
+    assert_eq!(state.expires_at, Some(now + Duration::seconds(900)));
+    assert!(state.is_active(now + Duration::seconds(899)));
+    assert!(!state.is_active(now + Duration::seconds(900)));
+}
+
+#[test]
+fn expiry_clears_mode_even_if_backend_forgets() {
+    let now = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+    let desired = json!({"enabled": true, "duration_secs": 60});
+    let state = maintenance::reconcile(None, Some(&desired), now);
+
+    // The same desired document is still present after expiry; it must not re-arm the window
+    let later = now + Duration::seconds(120);
+    let state = maintenance::reconcile(state, Some(&desired), later);
+    assert!(!maintenance::is_active(state.as_ref(), later));
+
+    // A new request does re-enable it
+    let desired = json!({"enabled": true, "duration_secs": 60, "ticket": "T-2"});
+    let state = maintenance::reconcile(state, Some(&desired), later);
+    assert!(maintenance::is_active(state.as_ref(), later));
+}
+
+#[test]
+fn disabled_or_absent_request_clears_mode() {
+    let now = Utc::now();
+    let state = maintenance::reconcile(None, Some(&json!(true)), now);
+    assert!(maintenance::is_active(state.as_ref(), now));
+    assert!(maintenance::reconcile(state.clone(), Some(&json!({"enabled": false})), now).is_none());
+    assert!(maintenance::reconcile(state, None, now).is_none());
+}
+
+#[test]
+fn persisted_state_restores_after_restart() {
+    let now = Utc::now();
+    let state = maintenance::reconcile(None, Some(&json!({"enabled": true, "duration_secs": 3600})), now).unwrap();
+    let restored: MaintenanceState = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+    assert_eq!(restored, state);
+    assert!(restored.is_active(now + Duration::seconds(10)));
+}
+
+#[test]
+fn measurement_flag_serializes_only_when_set() {
+    let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    let value = serde_json::to_value(&measurement).unwrap();
+    assert!(value.get("maintenance").is_none());
+
+    measurement.maintenance = Some(true);
+    let value = serde_json::to_value(&measurement).unwrap();
+    assert_eq!(value["maintenance"], json!(true));
+}
@@ -0,0 +1,75 @@
+mod adaptive_tests;
+mod anomaly_tests;
+mod audit_tests;
+mod auth_tests;
+mod baseline_tests;
+mod battery_tests;
+mod cadence_tests;
+mod can_tests;
+mod chaos_tests;
+mod codec_tests;
+mod config_tests;
+mod conformance_tests;
+mod corruption_tests;
+mod cost_tests;
+mod crash_tests;
+mod debug_session_tests;
+mod degradation_tests;
+mod dry_run_tests;
+mod environment_tests;
+mod error_tests;
+mod export_tests;
+mod external_tests;
+mod features_tests;
+mod firmware_tests;
+mod fleet_tests;
+mod geo_tests;
+mod geofence_tests;
+mod health_tests;
+mod init_tests;
+mod integrity_tests;
+mod jitter_tests;
+mod integration_tests;
+mod localtime_tests;
+mod maintenance_tests;
+mod naming_tests;
+mod net_tests;
+mod network_tests;
+mod offline_tests;
+mod ota_tests;
+mod outbox_tests;
+mod push_tests;
+mod replay_tests;
+mod reefer_tests;
+mod reported_tests;
+mod residency_tests;
+mod response_capture_tests;
+mod route_tests;
+mod runner_tests;
+mod schema_tests;
+mod shadow_report_tests;
+mod shed_tests;
+mod shutdown_tests;
+mod simulate_tests;
+mod slots_tests;
+mod snapshot_tests;
+mod stats_tests;
+mod storage_tests;
+mod telemetry_tests;
+mod txn_tests;
+mod types_tests;
+mod upload_tests;
+mod validation_tests;
+
+use std::sync::{LazyLock, Mutex};
+
+use crate::firmware::FirmwareBehavior;
+use crate::simulate::Simulator;
+use crate::types::Measurement;
+
+// One simulated device for the whole test run, so sequence numbers differ across tests
+static SIMULATOR: LazyLock<Mutex<Simulator>> = LazyLock::new(Mutex::default);
+
+pub fn generate_measurement(firmware_version: String, behavior: &FirmwareBehavior) -> Measurement {
+    SIMULATOR.lock().unwrap().generate_measurement(firmware_version, behavior)
+}
@@ -0,0 +1,67 @@
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::config::Config;
+use crate::{naming, net, storage};
+
+fn device_config() -> Config {
+    let env = HashMap::from([
+        ("DEVICE_ID".to_string(), "3f9c41d2-7a10-4c55-9e0b-2f6d1a8b9c01".to_string()),
+        ("AUTH_TOKEN".to_string(), "token-1".to_string()),
+    ]);
+    Config::from_env_vars(&env).0
+}
+
+#[test]
+fn generated_name_is_deterministic() {
+    let name = naming::generated_name("3f9c41d2-7a10");
+    assert_eq!(name, naming::generated_name("3f9c41d2-7a10"));
+    assert!(name.ends_with("-3f9c"));
+    assert_eq!(name.split('-').count(), 3);
+    assert_ne!(name, naming::generated_name("8acd02e1-0000"));
+}
+
+#[test]
+fn configured_name_wins_over_generated() {
+    let mut config = device_config();
+    assert_eq!(naming::display_name(&config), naming::generated_name(&config.device_id));
+    config.device_name = Some("eu-truck-001".to_string());
+    assert_eq!(naming::display_name(&config), "eu-truck-001");
+}
+
+#[test]
+fn invalid_desired_names_are_rejected() {
+    let mut config = device_config();
+    assert!(naming::rename(&mut config, &json!(42)).is_err());
+    assert!(naming::rename(&mut config, &json!("   ")).is_err());
+    assert!(naming::rename(&mut config, &json!("x".repeat(65))).is_err());
+    assert!(config.device_name.is_none());
+}
+
+#[test]
+fn shadow_rename_reaches_heartbeats_and_survives_restart() {
+    let config_path = std::env::temp_dir().join(format!("naming_{}.json", uuid::Uuid::new_v4()));
+    let db_path = std::env::temp_dir().join(format!("naming_{}.db", uuid::Uuid::new_v4()));
+    let mut config = device_config();
+    let conn = storage::init_at(&db_path).unwrap();
+    storage::append_measurement(&conn, &super::generate_measurement("0.1.0".to_string(), &Default::default()), 0).unwrap();
+
+    let previous = naming::rename(&mut config, &json!("eu-truck-007")).unwrap();
+    assert_eq!(previous, Some(naming::generated_name(&config.device_id)));
+    assert_eq!(naming::rename(&mut config, &json!("eu-truck-007")).unwrap(), None);
+    assert_eq!(net::heartbeat_body(&config, "0.1.0", 10, 60, 30).device_name, "eu-truck-007");
+    config.save_to(&config_path).unwrap();
+    drop(conn);
+
+    // Simulated restart
+    let restarted = Config::load_from(&config_path).unwrap();
+    let heartbeat = net::heartbeat_body(&restarted, "0.1.0", 10, 60, 30);
+    assert_eq!(heartbeat.device_name, "eu-truck-007");
+    assert_eq!(heartbeat.device_id, "3f9c41d2-7a10-4c55-9e0b-2f6d1a8b9c01");
+    assert_eq!(restarted.auth_token.as_deref(), Some("token-1"));
+    let conn = storage::init_at(&db_path).unwrap();
+    assert_eq!(storage::pending_count(&conn).unwrap(), 1);
+
+    let _ = std::fs::remove_file(&config_path);
+    let _ = std::fs::remove_file(&db_path);
+}
@@ -0,0 +1,515 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::codec::Codec;
+use crate::config::Config;
+use crate::export;
+use crate::net;
+use crate::residency::DataTarget;
+use crate::stats::ApiStats;
+use crate::types::{ChaosFlags, ChaosTarget, IngestPayload, ReportedShadowState};
+
+/// Request body as the backend sees it, decoded per its Content-Encoding.
+pub(super) fn decoded_body(request: &wiremock::Request) -> Vec<u8> {
+    let encoding = request.headers.get("content-encoding").map_or(Codec::None, |encoding| encoding.to_str().unwrap().parse().unwrap());
+    encoding.decode(&request.body).unwrap()
+}
+
+pub(super) fn ingest_payload(request: &wiremock::Request) -> IngestPayload {
+    serde_json::from_slice(&decoded_body(request)).unwrap()
+}
+
+fn device_config(backend_url: &str, max_attempts: u32) -> Config {
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), backend_url.to_string()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("RETRY_MAX_ATTEMPTS".to_string(), max_attempts.to_string()),
+        ("RETRY_BASE_DELAY_MS".to_string(), "20".to_string()),
+    ]);
+    Config::from_env_vars(&env).0
+}
+
+fn desired_state() -> serde_json::Value {
+    json!({
+        "desired_version": null,
+        "desired_sample_interval_secs": 10,
+        "desired_upload_interval_secs": 60,
+        "desired_heartbeat_interval_secs": 30,
+    })
+}
+
+#[test]
+fn backoff_doubles_with_jitter_and_is_capped() {
+    let base = Duration::from_millis(100);
+    let bounds: Vec<(Duration, Duration)> = (0..5)
+        .map(|retry| (net::backoff_delay(base, retry, 0.0), net::backoff_delay(base, retry, 1.0)))
+        .collect();
+    let millis = |ms| Duration::from_millis(ms);
+    assert_eq!(bounds, vec![
+        (millis(50), millis(100)),
+        (millis(100), millis(200)),
+        (millis(200), millis(400)),
+        (millis(400), millis(800)),
+        (millis(800), millis(1_600)),
+    ]);
+    let jittered = net::backoff_delay(base, 2, 0.5);
+    assert!(jittered > bounds[2].0 && jittered < bounds[2].1);
+    assert_eq!(net::backoff_delay(base, 40, 1.0), Duration::from_secs(30));
+}
+
+#[tokio::test]
+async fn transient_server_errors_are_retried_with_backoff() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/heartbeat"))
+        .respond_with(ResponseTemplate::new(503))
+        .up_to_n_times(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST")).and(path("/api/devices/heartbeat"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(desired_state()))
+        .mount(&server)
+        .await;
+
+    let config = device_config(&server.uri(), 3);
+    let stats = ApiStats::default();
+    let body = net::heartbeat_body(&config, "1.0.0", 10, 60, 30);
+    let started = Instant::now();
+    let desired = net::send_heartbeat(&reqwest::Client::new(), &config, &stats, &body).await.unwrap();
+
+    assert_eq!(desired.desired_heartbeat_interval_secs, 30);
+    // At least the lower bounds of the first two backoff delays
+    assert!(started.elapsed() >= Duration::from_millis(10 + 20));
+    let heartbeat = &stats.since_boot()["heartbeat"];
+    assert_eq!((heartbeat.attempts, heartbeat.successes, heartbeat.failures["503"]), (3, 1, 2));
+}
+
+#[tokio::test]
+async fn client_errors_fail_fast() {
+    let server = MockServer::start().await;
+    // 401 and 403 refresh the token first; see auth_tests
+    Mock::given(method("GET")).and(path("/api/devices/device-1/shadow"))
+        .respond_with(ResponseTemplate::new(400))
+        .mount(&server)
+        .await;
+
+    let mut config = device_config(&server.uri(), 5);
+    config.device_id = "device-1".to_string();
+    let stats = ApiStats::default();
+    assert!(net::fetch_device_shadow(&reqwest::Client::new(), &config, &stats).await.is_err());
+    assert_eq!(server.received_requests().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn persistent_failures_give_up_after_max_attempts() {
+    let server = MockServer::start().await;
+    Mock::given(method("PATCH")).and(path("/api/devices/device-1/shadow"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+
+    let mut config = device_config(&server.uri(), 4);
+    config.device_id = "device-1".to_string();
+    let stats = ApiStats::default();
+    let reported = ReportedShadowState { state: json!({}) };
+    assert!(net::report_device_shadow(&reqwest::Client::new(), &config, &stats, reported).await.is_err());
+    assert_eq!(server.received_requests().await.unwrap().len(), 4);
+}
+
+#[tokio::test]
+async fn connection_errors_are_retried() {
+    // Nothing listens on the discard port
+    let config = device_config("http://127.0.0.1:9", 3);
+    let stats = ApiStats::default();
+    let body = net::heartbeat_body(&config, "1.0.0", 10, 60, 30);
+    assert!(net::send_heartbeat(&reqwest::Client::new(), &config, &stats, &body).await.is_err());
+    assert_eq!(stats.since_boot()["heartbeat"].attempts, 3);
+    assert_eq!(stats.since_boot()["heartbeat"].successes, 0);
+}
+
+#[tokio::test]
+async fn a_hung_backend_times_out_instead_of_stalling() {
+    // Accepts connections and never answers
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((socket, _)) = listener.accept().await {
+            held.push(socket);
+        }
+    });
+    let mut config = device_config(&url, 1);
+    config.http_request_timeout_secs = 1;
+    let client = net::build_client(&config).unwrap();
+    let stats = ApiStats::default();
+    let body = net::heartbeat_body(&config, "1.0.0", 10, 60, 30);
+
+    let started = Instant::now();
+    assert!(net::send_heartbeat(&client, &config, &stats, &body).await.is_err());
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_secs(1) && elapsed < Duration::from_secs(3), "took {:?}", elapsed);
+    assert_eq!(stats.since_boot()["heartbeat"].failures["timeout"], 1);
+}
+
+#[test]
+fn gzip_round_trips_an_ingest_payload() {
+    let measurements: Vec<_> = (0..100).map(|_| super::generate_measurement("1.0.0".to_string(), &Default::default())).collect();
+    let payload = IngestPayload { device_id: "device-1".to_string(), measurements, region: None, data_endpoint: None };
+    let json = serde_json::to_vec(&payload).unwrap();
+    let compressed = Codec::Gzip.encode(&json).unwrap();
+    assert!(compressed.len() * 3 < json.len(), "{} bytes compressed to {}", json.len(), compressed.len());
+    let decompressed = Codec::Gzip.decode(&compressed).unwrap();
+    assert_eq!(decompressed, json);
+    let original: Value = serde_json::from_slice(&json).unwrap();
+    assert_eq!(serde_json::from_slice::<Value>(&decompressed).unwrap(), original);
+}
+
+#[tokio::test]
+async fn ingest_is_gzipped_unless_disabled() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&server)
+        .await;
+    let measurements: Vec<_> = (0..20).map(|_| super::generate_measurement("1.0.0".to_string(), &Default::default())).collect();
+    let target = DataTarget { region: None, endpoint: server.uri() };
+
+    let mut config = device_config(&server.uri(), 1);
+    let stats = ApiStats::default();
+    net::send_ingest(&reqwest::Client::new(), &config, &stats, &target, &measurements, None).await.unwrap();
+    config.compress_uploads = false;
+    net::send_ingest(&reqwest::Client::new(), &config, &stats, &target, &measurements, None).await.unwrap();
+
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests[0].headers.get("content-encoding").unwrap(), "gzip");
+    assert!(requests[1].headers.get("content-encoding").is_none());
+    assert!(requests[0].body.len() < requests[1].body.len());
+    for request in &requests {
+        assert_eq!(request.headers.get("content-type").unwrap(), "application/json");
+        assert_eq!(ingest_payload(request).measurements.len(), 20);
+    }
+    // Statistics count the bytes actually sent
+    assert_eq!(stats.since_boot()["ingest"].bytes_sent, (requests[0].body.len() + requests[1].body.len()) as u64);
+}
+
+#[tokio::test]
+async fn firmware_download_reports_progress() {
+    let server = MockServer::start().await;
+    let image = vec![7u8; 200_000];
+    Mock::given(method("GET")).and(path("/firmware/1.1.0.bin"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(image.clone()))
+        .mount(&server)
+        .await;
+
+    let config = device_config(&server.uri(), 1);
+    let mut progress = Vec::new();
+    let url = format!("{}/firmware/1.1.0.bin", server.uri());
+    let destination = std::env::temp_dir().join(format!("download_{}.part", uuid::Uuid::new_v4()));
+    let download = net::download_firmware(&reqwest::Client::new(), &config, &ApiStats::default(), &url, &destination, |bytes, total| progress.push((bytes, total)))
+        .await
+        .unwrap();
+    assert_eq!(download.path, destination);
+    assert_eq!(download.bytes, 200_000);
+    assert_eq!(download.sha256_hex(), export::sha256_hex(&image));
+    assert_eq!(std::fs::read(&destination).unwrap(), image);
+    assert!(progress.windows(2).all(|pair| pair[0].0 < pair[1].0), "{:?}", progress);
+    assert_eq!(progress.last(), Some(&(200_000, Some(200_000))));
+    let _ = std::fs::remove_file(&destination);
+}
+
+#[tokio::test]
+async fn failed_firmware_download_leaves_no_file() {
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/firmware/1.1.0.bin")).respond_with(ResponseTemplate::new(404)).mount(&server).await;
+
+    let destination = std::env::temp_dir().join(format!("download_{}.part", uuid::Uuid::new_v4()));
+    std::fs::write(&destination, b"left over from an earlier attempt").unwrap();
+    let url = format!("{}/firmware/1.1.0.bin", server.uri());
+    let result = net::download_firmware(&reqwest::Client::new(), &device_config(&server.uri(), 1), &ApiStats::default(), &url, &destination, |_, _| {}).await;
+    assert!(result.is_err());
+    assert!(!destination.exists());
+}
+
+// Serves `image`, cutting the connection halfway through unless the request resumes with
+// a Range header, or with `stall` going quiet there instead, as a download does in a
+// process about to be killed. Returns the base URL and the Range header of each request.
+async fn interrupting_server(image: Vec<u8>, stall: bool) -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let ranges = Arc::new(Mutex::new(Vec::new()));
+    let seen = ranges.clone();
+    let image = Arc::new(image);
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            // Each on its own, so a stalled connection does not hold up the next
+            let (image, seen) = (image.clone(), seen.clone());
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0; 1];
+                    if socket.read(&mut byte).await.unwrap() == 0 {
+                        break;
+                    }
+                    head.push(byte[0]);
+                }
+                let head = String::from_utf8_lossy(&head).to_lowercase();
+                let range = head.lines().find_map(|line| line.strip_prefix("range: bytes=")).map(|range| range.trim_end_matches('-').to_string());
+                seen.lock().unwrap().push(range.clone());
+                match range.and_then(|start| start.parse::<usize>().ok()) {
+                    Some(start) => {
+                        let headers = format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                            image.len() - start, start, image.len() - 1, image.len(),
+                        );
+                        socket.write_all(headers.as_bytes()).await.unwrap();
+                        socket.write_all(&image[start..]).await.unwrap();
+                    }
+                    None => {
+                        let headers = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", image.len());
+                        socket.write_all(headers.as_bytes()).await.unwrap();
+                        socket.write_all(&image[..image.len() / 2]).await.unwrap();
+                        if stall {
+                            tokio::time::sleep(Duration::from_secs(3600)).await;
+                        }
+                    }
+                }
+                socket.shutdown().await.unwrap();
+            });
+        }
+    });
+    (url, ranges)
+}
+
+#[tokio::test]
+async fn an_interrupted_firmware_download_resumes_where_it_stopped() {
+    let image: Vec<u8> = (0..100_000u32).map(|byte| (byte % 251) as u8).collect();
+    let (server, ranges) = interrupting_server(image.clone(), false).await;
+    let config = device_config(&server, 1);
+    let url = format!("{}/firmware/1.1.0.bin", server);
+    let destination = std::env::temp_dir().join(format!("download_{}.part", uuid::Uuid::new_v4()));
+
+    assert!(net::download_firmware(&reqwest::Client::new(), &config, &ApiStats::default(), &url, &destination, |_, _| {}).await.is_err());
+    let partial: net::PartialDownload = serde_json::from_slice(&std::fs::read(net::resume_path(&destination)).unwrap()).unwrap();
+    assert_eq!(partial.offset, 50_000);
+    assert_eq!(std::fs::read(&destination).unwrap(), image[..50_000]);
+
+    let mut progress = Vec::new();
+    let download = net::download_firmware(&reqwest::Client::new(), &config, &ApiStats::default(), &url, &destination, |bytes, total| progress.push((bytes, total)))
+        .await
+        .unwrap();
+    assert_eq!(ranges.lock().unwrap().clone(), [None, Some("50000".to_string())]);
+    assert_eq!((download.resumed_from, download.bytes), (50_000, 100_000));
+    // The digest covers the bytes kept from the first attempt as well
+    assert_eq!(download.sha256_hex(), export::sha256_hex(&image));
+    assert_eq!(std::fs::read(&destination).unwrap(), image);
+    assert_eq!(progress.last(), Some(&(100_000, Some(100_000))));
+    assert!(!net::resume_path(&destination).exists());
+    let _ = std::fs::remove_file(&destination);
+}
+
+#[tokio::test]
+async fn a_download_killed_midway_resumes_from_its_last_recorded_progress() {
+    let image: Vec<u8> = (0..4 * net::RESUME_CHECKPOINT_BYTES as u32).map(|byte| (byte % 251) as u8).collect();
+    let (server, ranges) = interrupting_server(image.clone(), true).await;
+    let config = device_config(&server, 1);
+    let url = format!("{}/firmware/1.1.0.bin", server);
+    let destination = std::env::temp_dir().join(format!("download_{}.part", uuid::Uuid::new_v4()));
+
+    // Aborting the task drops the download where it stands, as killing the process would
+    let download = tokio::spawn({
+        let (config, url, destination) = (config.clone(), url.clone(), destination.clone());
+        async move { net::download_firmware(&reqwest::Client::new(), &config, &ApiStats::default(), &url, &destination, |_, _| {}).await }
+    });
+    let recorded = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(partial) = std::fs::read(net::resume_path(&destination)).ok().and_then(|raw| serde_json::from_slice::<net::PartialDownload>(&raw).ok()) {
+                if partial.offset >= net::RESUME_CHECKPOINT_BYTES {
+                    return partial.offset;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.unwrap();
+    download.abort();
+    let _ = download.await;
+    assert!(recorded <= image.len() as u64 / 2);
+    assert_eq!(net::resume_offset(&destination, &url), recorded);
+
+    let download = net::download_firmware(&reqwest::Client::new(), &config, &ApiStats::default(), &url, &destination, |_, _| {}).await.unwrap();
+    assert_eq!(ranges.lock().unwrap().last().unwrap().as_deref(), Some(recorded.to_string().as_str()));
+    assert_eq!((download.resumed_from, download.bytes), (recorded, image.len() as u64));
+    assert_eq!(download.sha256_hex(), export::sha256_hex(&image));
+    assert_eq!(std::fs::read(&destination).unwrap(), image);
+    let _ = std::fs::remove_file(&destination);
+}
+
+#[tokio::test]
+async fn a_server_ignoring_the_range_restarts_the_download() {
+    let server = MockServer::start().await;
+    let image = vec![9u8; 10_000];
+    Mock::given(method("GET")).and(path("/firmware/1.1.0.bin"))
+        .respond_with(ResponseTemplate::new(200).set_body_bytes(image.clone()))
+        .mount(&server)
+        .await;
+    let url = format!("{}/firmware/1.1.0.bin", server.uri());
+    let destination = std::env::temp_dir().join(format!("download_{}.part", uuid::Uuid::new_v4()));
+    std::fs::write(&destination, vec![1u8; 4_000]).unwrap();
+    std::fs::write(net::resume_path(&destination), serde_json::to_vec(&net::PartialDownload { url: url.clone(), offset: 4_000 }).unwrap()).unwrap();
+
+    let download = net::download_firmware(&reqwest::Client::new(), &device_config(&server.uri(), 1), &ApiStats::default(), &url, &destination, |_, _| {})
+        .await
+        .unwrap();
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests[0].headers.get("range").unwrap(), "bytes=4000-");
+    assert_eq!((download.resumed_from, download.bytes), (0, 10_000));
+    assert_eq!(download.sha256_hex(), export::sha256_hex(&image));
+    assert_eq!(std::fs::read(&destination).unwrap(), image);
+    let _ = std::fs::remove_file(&destination);
+}
+
+fn signed_by(names: &[&str], usage: rcgen::ExtendedKeyUsagePurpose, ca: &rcgen::Certificate, ca_key: &rcgen::KeyPair) -> (rcgen::Certificate, rcgen::KeyPair) {
+    let key = rcgen::KeyPair::generate().unwrap();
+    let mut params = rcgen::CertificateParams::new(names.iter().map(|name| name.to_string()).collect::<Vec<_>>()).unwrap();
+    params.extended_key_usages = vec![usage];
+    (params.signed_by(&key, ca, ca_key).unwrap(), key)
+}
+
+// An HTTPS backend that only completes handshakes with clients presenting a certificate
+// from its CA. Returns its URL and a directory with ca.pem, client.pem and client.key.
+async fn mutual_tls_server() -> (String, std::path::PathBuf) {
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let (server, server_key) = signed_by(&["127.0.0.1"], ExtendedKeyUsagePurpose::ServerAuth, &ca, &ca_key);
+    let (client, client_key) = signed_by(&["device"], ExtendedKeyUsagePurpose::ClientAuth, &ca, &ca_key);
+
+    let dir = std::env::temp_dir().join(format!("mtls_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+    std::fs::write(dir.join("client.pem"), client.pem()).unwrap();
+    std::fs::write(dir.join("client.key"), client_key.serialize_pem()).unwrap();
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(ca.der().clone()).unwrap();
+    let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build().unwrap();
+    let server_config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(vec![CertificateDer::from(server.der().to_vec())], PrivateKeyDer::Pkcs8(server_key.serialize_der().into()))
+        .unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("https://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(mut stream) = acceptor.accept(socket).await else {
+                    return;
+                };
+                let mut request = [0; 4096];
+                let _ = stream.read(&mut request).await;
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok").await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+    (url, dir)
+}
+
+#[tokio::test]
+async fn a_client_certificate_is_presented_to_a_backend_requiring_mutual_tls() {
+    let (url, dir) = mutual_tls_server().await;
+    let mut config = device_config(&url, 1);
+    config.tls_ca_bundle_path = Some(dir.join("ca.pem"));
+    config.tls_client_cert_path = Some(dir.join("client.pem"));
+    config.tls_client_key_path = Some(dir.join("client.key"));
+
+    let client = net::build_client(&config).unwrap();
+    let response = client.get(format!("{}/healthz", url)).send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "ok");
+
+    // Trusting the backend is not enough; without its own certificate the device is refused
+    config.tls_client_cert_path = None;
+    config.tls_client_key_path = None;
+    let anonymous = net::build_client(&config).unwrap();
+    assert!(anonymous.get(format!("{}/healthz", url)).send().await.is_err());
+
+    config.tls_client_cert_path = Some(dir.join("client.pem"));
+    assert!(net::build_client(&config).is_err());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn a_combined_sync_brings_back_intervals_and_the_shadow_in_one_round_trip() {
+    let server = MockServer::start().await;
+    let desired_state = json!({
+        "desired_version": null,
+        "desired_sample_interval_secs": 15,
+        "desired_upload_interval_secs": 120,
+        "desired_heartbeat_interval_secs": 45,
+    });
+    Mock::given(method("POST")).and(path("/api/devices/sync"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "desired_state": desired_state,
+            "shadow": {"desired": {"chaos_flags": {"random_error": true, "error_probability": 0.5}}, "reported": {}},
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST")).and(path("/api/devices/heartbeat"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(desired_state.clone()))
+        .expect(0)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET")).and(path("/api/devices/device-1/shadow"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"desired": {}, "reported": {}})))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let mut config = device_config(&server.uri(), 1);
+    config.device_id = "device-1".to_string();
+    config.combined_sync = true;
+    let stats = ApiStats::default();
+    let body = net::heartbeat_body(&config, "1.0.0", 10, 60, 30);
+    let synced = net::sync(&reqwest::Client::new(), &config, &stats, &body).await.unwrap().unwrap();
+
+    let intervals = &synced.desired_state;
+    assert_eq!((intervals.desired_sample_interval_secs, intervals.desired_upload_interval_secs, intervals.desired_heartbeat_interval_secs), (15, 120, 45));
+    let chaos_flags = ChaosFlags::parse(&synced.shadow.desired.unwrap()["chaos_flags"]).unwrap();
+    assert_eq!(chaos_flags.error_probability_for(ChaosTarget::Shadow), Some(0.5));
+    assert_eq!(stats.since_boot()["sync"].successes, 1);
+    assert!(!stats.since_boot().contains_key("heartbeat"));
+    // The heartbeat went out as the request body
+    let sent: Value = serde_json::from_slice(&decoded_body(&server.received_requests().await.unwrap()[0])).unwrap();
+    assert_eq!(sent["device_id"], "device-1");
+}
+
+#[tokio::test]
+async fn a_backend_without_the_sync_endpoint_is_told_apart_from_a_failed_sync() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/sync"))
+        .respond_with(ResponseTemplate::new(404))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut config = device_config(&server.uri(), 1);
+    config.combined_sync = true;
+    let body = net::heartbeat_body(&config, "1.0.0", 10, 60, 30);
+    let synced = net::sync(&reqwest::Client::new(), &config, &ApiStats::default(), &body).await.unwrap();
+    assert!(synced.is_none());
+}
@@ -0,0 +1,195 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use super::net_tests;
+use crate::config::Config;
+use crate::network::{self, NetworkProfile, NetworkType, RoamStep, Roaming, RoamingConfig};
+use crate::shed::{ShedConfig, Shedder};
+use crate::stats::ApiStats;
+use crate::types::ChaosFlags;
+use crate::{storage, upload};
+
+// Bundled payload and aggregation limits, without the delays and loss that would slow or flake a test
+fn instant_profile(network: NetworkType) -> NetworkProfile {
+    NetworkProfile { latency_ms: 0, jitter_ms: 0, bandwidth_bytes_per_sec: u64::MAX, loss: 0.0, ..network.bundled_profile() }
+}
+
+async fn backend() -> MockServer {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&server)
+        .await;
+    server
+}
+
+fn device_on(server: &MockServer, network: NetworkType, profile: NetworkProfile) -> (Config, rusqlite::Connection) {
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("NETWORK".to_string(), network.to_string()),
+    ]);
+    let (mut config, report) = Config::from_env_vars(&env);
+    assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    assert_eq!(config.network, Some(network));
+    config.network_profiles = BTreeMap::from([(network, profile)]);
+    let db_path = std::env::temp_dir().join(format!("network_{}.db", uuid::Uuid::new_v4()));
+    (config, storage::init_at(&db_path).unwrap())
+}
+
+// Samples through the shedder as the main loop does, then drains the store
+async fn sample_and_drain(config: &Config, conn: &mut rusqlite::Connection, samples: usize) -> ApiStats {
+    let mut shedder = Shedder::new(ShedConfig::default());
+    shedder.set_min_aggregation(network::active_profile(config).unwrap().min_aggregation);
+    for _ in 0..samples {
+        let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+        measurement.network = config.network;
+        if let Some(measurement) = shedder.admit(measurement) {
+            storage::append_measurement(conn, &measurement, 0).unwrap();
+        }
+    }
+    let client = reqwest::Client::new();
+    let stats = ApiStats::default();
+    while storage::pending_count(conn).unwrap() > 0 {
+        upload::drain_once(&client, config, &stats, conn, None).await.unwrap();
+    }
+    stats
+}
+
+#[tokio::test]
+async fn every_profile_enforces_its_payload_limit_end_to_end() {
+    for network in NetworkType::ALL {
+        let server = backend().await;
+        let profile = instant_profile(network);
+        let (config, mut conn) = device_on(&server, network, profile.clone());
+        sample_and_drain(&config, &mut conn, 120).await;
+
+        let requests = server.received_requests().await.unwrap();
+        let mut delivered = 0;
+        for request in &requests {
+            if let Some(limit) = profile.max_payload_bytes {
+                assert!(request.body.len() as u64 <= limit, "{}: {} byte body over {} limit", network, request.body.len(), limit);
+            }
+            let payload = net_tests::ingest_payload(request);
+            assert!(payload.measurements.iter().all(|m| m.network == Some(network)));
+            delivered += payload.measurements.iter().map(|m| m.aggregate_count.unwrap_or(1) as usize).sum::<usize>();
+        }
+        assert_eq!(delivered, 120, "{}: every sample is delivered or folded into an aggregate", network);
+        if network == NetworkType::Wifi {
+            assert_eq!(requests.len(), 2, "wifi only splits on batch size");
+        }
+    }
+}
+
+#[tokio::test]
+async fn nbiot_forces_aggregation_and_tiny_payloads() {
+    let server = backend().await;
+    let (config, mut conn) = device_on(&server, NetworkType::NbIot, instant_profile(NetworkType::NbIot));
+    sample_and_drain(&config, &mut conn, 60).await;
+
+    let requests = server.received_requests().await.unwrap();
+    assert!(requests.len() > 1, "payload limit splits the upload");
+    let measurements: Vec<_> = requests.iter()
+        .flat_map(|request| net_tests::ingest_payload(request).measurements)
+        .collect();
+    assert_eq!(measurements.len(), 10);
+    assert!(measurements.iter().all(|m| m.aggregate_count == Some(6)));
+}
+
+#[tokio::test]
+async fn lost_requests_are_recorded_and_retried() {
+    let server = backend().await;
+    let profile = NetworkProfile { loss: 1.0, ..instant_profile(NetworkType::TwoG) };
+    let (mut config, mut conn) = device_on(&server, NetworkType::TwoG, profile);
+    config.retry_base_delay_ms = 1;
+    storage::append_measurement(&conn, &super::generate_measurement("0.1.0".to_string(), &Default::default()), 0).unwrap();
+
+    let stats = ApiStats::default();
+    let round = upload::drain_once(&reqwest::Client::new(), &config, &stats, &mut conn, None).await.unwrap();
+    assert_eq!(round.uploaded(), 0);
+    assert_eq!(storage::pending_count(&conn).unwrap(), 1);
+    // Every attempt is lost, so the upload gives up after the configured retries
+    assert_eq!(stats.since_boot()["ingest"].failures["packet_loss"], config.retry_max_attempts as u64);
+    assert!(server.received_requests().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn latency_and_bandwidth_delay_requests() {
+    let server = backend().await;
+    let profile = NetworkProfile { latency_ms: 100, bandwidth_bytes_per_sec: 10_000, ..instant_profile(NetworkType::ThreeG) };
+    let (config, mut conn) = device_on(&server, NetworkType::ThreeG, profile);
+    for _ in 0..5 {
+        storage::append_measurement(&conn, &super::generate_measurement("0.1.0".to_string(), &Default::default()), 0).unwrap();
+    }
+
+    let started = Instant::now();
+    upload::drain_once(&reqwest::Client::new(), &config, &ApiStats::default(), &mut conn, None).await.unwrap();
+    let body_len = server.received_requests().await.unwrap()[0].body.len() as u64;
+    assert!(started.elapsed() >= Duration::from_millis(100 + body_len * 1000 / 10_000));
+}
+
+#[test]
+fn roaming_follows_schedule_and_prefers_region_map() {
+    let mut roaming = Roaming::new(RoamingConfig {
+        schedule: vec![
+            RoamStep { network: NetworkType::Lte, duration_secs: 60 },
+            RoamStep { network: NetworkType::TwoG, duration_secs: 30 },
+        ],
+        by_region: BTreeMap::from([("rural".to_string(), NetworkType::NbIot)]),
+    });
+    assert_eq!(roaming.advance(0, None), Some(NetworkType::Lte));
+    assert_eq!(roaming.advance(30, None), None);
+    assert_eq!(roaming.advance(30, None), Some(NetworkType::TwoG));
+    assert_eq!(roaming.advance(10, Some("rural")), Some(NetworkType::NbIot));
+    assert_eq!(roaming.advance(10, Some("rural")), None);
+    // Schedule wraps around once the device leaves the mapped region
+    assert_eq!(roaming.advance(10, Some("city")), Some(NetworkType::Lte));
+}
+
+#[test]
+fn unknown_network_is_a_config_warning() {
+    let env = HashMap::from([("NETWORK".to_string(), "5g".to_string())]);
+    let (config, report) = Config::from_env_vars(&env);
+    assert_eq!(config.network, None);
+    assert!(report.warnings.iter().any(|warning| warning.contains("5g")));
+}
+
+#[test]
+fn chaos_latency_is_fixed_or_picked_within_its_range() {
+    let mut config = Config::from_env_vars(&HashMap::new()).0;
+    assert_eq!(network::chaos_latency(&config), None);
+
+    config.chaos_flags = Some(ChaosFlags::parse(&serde_json::json!({"latency_ms": 250})).unwrap());
+    assert_eq!(network::chaos_latency(&config), Some(Duration::from_millis(250)));
+
+    config.chaos_flags = Some(ChaosFlags::parse(&serde_json::json!({"latency_ms": {"min": 100, "max": 2000}})).unwrap());
+    let delays: Vec<Duration> = (0..200).map(|_| network::chaos_latency(&config).unwrap()).collect();
+    assert!(delays.iter().all(|delay| (Duration::from_millis(100)..=Duration::from_millis(2000)).contains(delay)), "{:?}", delays);
+    assert!(delays.iter().any(|delay| *delay != delays[0]), "every request got the same delay");
+
+    for malformed in [serde_json::json!({"min": 500, "max": 100}), serde_json::json!({"min": 100}), serde_json::json!("slow"), serde_json::json!(-5)] {
+        assert!(network::latency_range(&malformed).is_err(), "{}", malformed);
+        config.chaos_flags = Some(ChaosFlags::parse(&serde_json::json!({"latency_ms": malformed})).unwrap());
+        assert_eq!(network::chaos_latency(&config), None);
+    }
+}
+
+#[tokio::test]
+async fn chaos_latency_delays_each_upload() {
+    let server = backend().await;
+    let (mut config, mut conn) = device_on(&server, NetworkType::Lte, instant_profile(NetworkType::Lte));
+    config.upload_batch_size = 2;
+    config.chaos_flags = Some(ChaosFlags::parse(&serde_json::json!({"latency_ms": {"min": 60, "max": 120}})).unwrap());
+    for _ in 0..4 {
+        storage::append_measurement(&conn, &super::generate_measurement("0.1.0".to_string(), &Default::default()), 0).unwrap();
+    }
+
+    let started = Instant::now();
+    while storage::pending_count(&conn).unwrap() > 0 {
+        upload::drain_once(&reqwest::Client::new(), &config, &ApiStats::default(), &mut conn, None).await.unwrap();
+    }
+    assert_eq!(server.received_requests().await.unwrap().len(), 2);
+    assert!(started.elapsed() >= Duration::from_millis(2 * 60), "{:?}", started.elapsed());
+}
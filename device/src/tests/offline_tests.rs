@@ -0,0 +1,69 @@
+use chrono::{Duration, TimeZone, Utc};
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::config::Config;
+use crate::offline;
+use crate::validation::{self, Severity};
+
+#[test]
+fn a_duration_or_an_end_time_opens_the_window() {
+    let now = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+    let window = offline::reconcile(None, Some(&json!({"duration_secs": 600})), now).unwrap();
+    assert_eq!(window.until, now + Duration::seconds(600));
+    assert!(offline::is_active(Some(&window), now + Duration::seconds(599)));
+    assert!(!offline::is_active(Some(&window), now + Duration::seconds(600)));
+    assert_eq!(offline::remaining(Some(&window), now + Duration::seconds(590)), Some(std::time::Duration::from_secs(10)));
+    assert_eq!(offline::remaining(Some(&window), now + Duration::seconds(600)), None);
+
+    let window = offline::reconcile(None, Some(&json!({"until": "2024-05-01T13:00:00Z"})), now).unwrap();
+    assert_eq!(window.until, now + Duration::hours(1));
+}
+
+#[test]
+fn invalid_requests_open_nothing() {
+    let now = Utc::now();
+    for request in [json!({}), json!({"duration_secs": 0}), json!({"duration_secs": "600"}), json!({"until": "tomorrow"}), json!({"duration_secs": 60, "until": "2024-05-01T13:00:00Z"})] {
+        assert!(offline::parse_request(&request, now).is_err(), "{}", request);
+        assert_eq!(offline::reconcile(None, Some(&request), now), None, "{}", request);
+    }
+}
+
+#[test]
+fn a_flag_left_in_the_shadow_does_not_reopen_the_window() {
+    let now = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+    let request = json!({"duration_secs": 60});
+    let window = offline::reconcile(None, Some(&request), now);
+
+    let later = now + Duration::seconds(120);
+    let window = offline::reconcile(window, Some(&request), later);
+    assert!(!offline::is_active(window.as_ref(), later));
+
+    // A different request opens a new one, and clearing the flag forgets it
+    let window = offline::reconcile(window, Some(&json!({"duration_secs": 300})), later);
+    assert!(offline::is_active(window.as_ref(), later));
+    assert_eq!(offline::reconcile(window, None, later), None);
+}
+
+#[test]
+fn a_restart_mid_window_stays_offline_for_the_rest_of_it() {
+    let now = Utc::now();
+    let mut config = Config::from_env_vars(&HashMap::new()).0;
+    config.offline_window = offline::reconcile(None, Some(&json!({"duration_secs": 600})), now);
+    let path = std::env::temp_dir().join(format!("offline_{}.json", uuid::Uuid::new_v4()));
+    config.save_to(&path).unwrap();
+    let restored = Config::load_from(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+
+    assert_eq!(restored.offline_window, config.offline_window);
+    let remaining = offline::remaining(restored.offline_window.as_ref(), now + Duration::seconds(200)).unwrap();
+    assert_eq!(remaining.as_secs(), 400);
+}
+
+#[test]
+fn an_invalid_offline_flag_fails_validation() {
+    let config = Config::from_env_vars(&HashMap::new()).0;
+    let findings = validation::check_desired(&config, &json!({"chaos_flags": {"offline": {"duration_secs": -5}}})).findings;
+    assert!(findings.iter().any(|finding| finding.key == "desired.chaos_flags.offline" && finding.severity == Severity::Error), "{:?}", findings);
+    assert!(validation::check_desired(&config, &json!({"chaos_flags": {"offline": {"duration_secs": 600}}})).findings.is_empty());
+}
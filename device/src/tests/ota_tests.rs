@@ -0,0 +1,742 @@
+use chrono::{Duration, Utc};
+use ed25519_dalek::{Signer, SigningKey};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use tokio::sync::mpsc;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+use super::net_tests;
+
+use crate::audit::AuditLog;
+use crate::config::Config;
+use crate::export;
+use crate::fleet::DevicePaths;
+use crate::net::{self, DownloadedFirmware};
+use crate::ota::{self, MetadataRejection, OtaPhase, OtaState, OtaStatus, OtaStatusReporter, SignatureRejection, TrialPolicy};
+use crate::ota_history::{self, AttemptOutcome};
+use crate::slots;
+use crate::stats::ApiStats;
+use crate::types::{ChaosFlags, FirmwareMetadata};
+use crate::validation::{self, Severity};
+
+fn installed_state() -> OtaState {
+    let mut state = OtaState {
+        current_version: "1.0.0".to_string(),
+        active_slot: "A".to_string(),
+        previous_version: None,
+        previous_slot: None,
+        pending_confirmation: false,
+        boot_count: 0,
+        trial_heartbeats: 0,
+        trial_ingested: false,
+        trial_started_at: None,
+        installed_behaviors: Default::default(),
+        last_rollback: None,
+        last_update: None,
+        ota_status: Default::default(),
+        manifests: Default::default(),
+        history: Vec::new(),
+        staged: None,
+    };
+    state.begin_trial("1.1.0".to_string());
+    state
+}
+
+fn policy() -> TrialPolicy {
+    TrialPolicy { heartbeats: 3, window_secs: 600, max_boots: 3, require_ingest: false }
+}
+
+#[test]
+fn begin_trial_switches_slot_and_keeps_previous() {
+    let state = installed_state();
+    assert_eq!(state.current_version, "1.1.0");
+    assert_eq!(state.active_slot, "B");
+    assert_eq!(state.previous_version.as_deref(), Some("1.0.0"));
+    assert!(state.pending_confirmation);
+}
+
+#[test]
+fn unconfirmed_boot_under_chaos_rolls_back() {
+    let mut state = installed_state();
+    assert!(ota::handle_trial_boot(&mut state, &policy(), true, Utc::now()));
+    assert_eq!(state.current_version, "1.0.0");
+    assert_eq!(state.active_slot, "A");
+    assert!(!state.pending_confirmation);
+}
+
+#[test]
+fn trial_boot_without_chaos_awaits_confirmation() {
+    let mut state = installed_state();
+    assert!(!ota::handle_trial_boot(&mut state, &policy(), false, Utc::now()));
+    assert!(state.pending_confirmation);
+
+    state.confirm_boot();
+    assert!(!state.pending_confirmation);
+    assert_eq!(state.current_version, "1.1.0");
+
+    // A confirmed boot is not affected by the chaos flag
+    assert!(!ota::handle_trial_boot(&mut state, &policy(), true, Utc::now()));
+    assert_eq!(state.current_version, "1.1.0");
+}
+
+#[test]
+fn enough_heartbeats_within_window_confirm_the_boot() {
+    let mut state = installed_state();
+    let booted_at = Utc::now();
+    assert!(!ota::handle_trial_boot(&mut state, &policy(), false, booted_at));
+    assert_eq!(state.boot_count, 1);
+
+    assert!(!state.record_trial_heartbeat(&policy()));
+    assert!(!state.record_trial_heartbeat(&policy()));
+    assert!(!ota::check_trial(&mut state, &policy(), booted_at + Duration::seconds(300)));
+    assert!(state.record_trial_heartbeat(&policy()));
+    assert!(!state.pending_confirmation);
+    assert_eq!(state.trial_heartbeats, 0);
+
+    // Confirmed firmware is never rolled back, however long it runs
+    assert!(!ota::check_trial(&mut state, &policy(), booted_at + Duration::days(30)));
+    assert_eq!(state.current_version, "1.1.0");
+    assert_eq!(state.active_slot, "B");
+}
+
+#[test]
+fn missing_heartbeats_within_window_revert_to_previous_slot() {
+    let mut state = installed_state();
+    let booted_at = Utc::now();
+    ota::handle_trial_boot(&mut state, &policy(), false, booted_at);
+    state.record_trial_heartbeat(&policy());
+
+    // The window runs from the first boot, so a restart does not extend it
+    assert!(!ota::handle_trial_boot(&mut state, &policy(), false, booted_at + Duration::seconds(500)));
+    assert_eq!(state.trial_started_at, Some(booted_at));
+    assert!(ota::check_trial(&mut state, &policy(), booted_at + Duration::seconds(601)));
+    assert_eq!(state.current_version, "1.0.0");
+    assert_eq!(state.active_slot, "A");
+    assert!(!state.pending_confirmation);
+    assert_eq!(state.previous_version, None);
+}
+
+#[test]
+fn health_check_needs_an_ingest_as_well_as_heartbeats() {
+    let policy = TrialPolicy { heartbeats: 1, require_ingest: true, ..policy() };
+    let mut state = installed_state();
+    ota::handle_trial_boot(&mut state, &policy, false, Utc::now());
+    assert!(!state.record_trial_heartbeat(&policy));
+    assert!(state.pending_confirmation);
+    assert!(state.record_trial_ingest(&policy));
+    assert!(!state.pending_confirmation);
+
+    // Either order works
+    let mut state = installed_state();
+    ota::handle_trial_boot(&mut state, &policy, false, Utc::now());
+    assert!(!state.record_trial_ingest(&policy));
+    assert!(state.record_trial_heartbeat(&policy));
+}
+
+#[test]
+fn rollback_is_kept_for_the_next_heartbeat() {
+    let mut state = installed_state();
+    let booted_at = Utc::now();
+    ota::handle_trial_boot(&mut state, &policy(), false, booted_at);
+    state.record_trial_heartbeat(&policy());
+    state.record_trial_ingest(&policy());
+    assert!(ota::check_trial(&mut state, &policy(), booted_at + Duration::seconds(601)));
+
+    let report = state.last_rollback.clone().unwrap();
+    assert_eq!(report.failed_version, "1.1.0");
+    assert_eq!(report.version, "1.0.0");
+    assert_eq!(report.slot, "A");
+    assert_eq!(report.reason, "health_check_window");
+    assert_eq!((report.boot_count, report.heartbeats, report.ingested), (1, 1, true));
+
+    // Survives the reboot into the previous slot
+    let path = std::env::temp_dir().join(format!("ota_state_{}.json", uuid::Uuid::new_v4()));
+    state.save_to(&path).unwrap();
+    assert_eq!(OtaState::load_from(&path).unwrap().last_rollback, Some(report));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn crash_looping_firmware_reverts_after_boot_limit() {
+    let mut state = installed_state();
+    let booted_at = Utc::now();
+    for boot in 1..=3 {
+        assert!(!ota::handle_trial_boot(&mut state, &policy(), false, booted_at + Duration::seconds(boot)), "boot {}", boot);
+    }
+    assert!(ota::handle_trial_boot(&mut state, &policy(), false, booted_at + Duration::seconds(4)));
+    assert_eq!(state.current_version, "1.0.0");
+    assert_eq!(state.boot_count, 0);
+}
+
+#[test]
+fn state_round_trips_through_file() {
+    let path = std::env::temp_dir().join(format!("ota_state_{}.json", uuid::Uuid::new_v4()));
+    let state = installed_state();
+    state.save_to(&path).unwrap();
+    let loaded = OtaState::load_from(&path).unwrap();
+    assert_eq!(loaded.current_version, "1.1.0");
+    assert_eq!(loaded.previous_slot.as_deref(), Some("A"));
+    assert!(loaded.pending_confirmation);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn failed_status_survives_restart() {
+    let mut state = installed_state();
+    ota::handle_trial_boot(&mut state, &policy(), true, Utc::now());
+    assert_eq!(state.ota_status, OtaStatus::Failed { version: "1.1.0".to_string(), error: "rolled back: fail_boot_confirmation".to_string() });
+    assert_eq!(json!(state.ota_status), json!({"state": "failed", "version": "1.1.0", "error": "rolled back: fail_boot_confirmation"}));
+
+    let path = std::env::temp_dir().join(format!("ota_state_{}.json", uuid::Uuid::new_v4()));
+    state.save_to(&path).unwrap();
+    let mut loaded = OtaState::load_from(&path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(loaded.ota_status, state.ota_status);
+    // Only a finished reboot clears the status; a failure stays until the next update
+    assert!(!loaded.finish_reboot());
+
+    loaded.ota_status = OtaStatus::Rebooting { version: "1.0.0".to_string() };
+    assert!(loaded.finish_reboot());
+    assert_eq!(loaded.ota_status, OtaStatus::Idle);
+}
+
+#[tokio::test]
+async fn status_reporter_pushes_transitions_to_the_shadow() {
+    let server = MockServer::start().await;
+    Mock::given(method("PATCH")).and(path("/api/devices/device-1/shadow"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&server)
+        .await;
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("DEVICE_ID".to_string(), "device-1".to_string()),
+    ]);
+    let (config, _) = Config::from_env_vars(&env);
+    let reporter = OtaStatusReporter::spawn(reqwest::Client::new(), config, ApiStats::default());
+    let version = "1.1.0".to_string();
+    reporter.sender().send(OtaStatus::Downloading { version: version.clone(), bytes: 512, total_bytes: Some(1024), percent: Some(50) }).unwrap();
+    reporter.sender().send(OtaStatus::Verifying { version: version.clone() }).unwrap();
+    reporter.sender().send(OtaStatus::Rebooting { version }).unwrap();
+    reporter.flush(std::time::Duration::from_secs(5)).await;
+
+    let requests = server.received_requests().await.unwrap();
+    assert!(!requests.is_empty());
+    let last: Value = serde_json::from_slice(&requests.last().unwrap().body).unwrap();
+    assert_eq!(last["state"]["ota_status"], json!({"state": "rebooting", "version": "1.1.0"}));
+}
+
+fn metadata(nonce: Option<&str>, issued_secs_ago: i64) -> FirmwareMetadata {
+    FirmwareMetadata {
+        version: "1.1.0".to_string(),
+        checksum: "abc".to_string(),
+        url: "/f".to_string(),
+        behavior: None,
+        nonce: nonce.map(str::to_string),
+        issued_at: Some(Utc::now() - Duration::seconds(issued_secs_ago)),
+        signature: None,
+        metadata_signature: None,
+    }
+}
+
+#[test]
+fn fresh_metadata_with_matching_nonce_is_accepted() {
+    assert_eq!(ota::verify_metadata(&metadata(Some("n-1"), 5), "n-1", Utc::now(), 300, None), Ok(()));
+}
+
+#[test]
+fn replayed_metadata_is_rejected() {
+    // Captured response from an earlier check, replayed against a new nonce
+    let rejection = ota::verify_metadata(&metadata(Some("n-old"), 5), "n-new", Utc::now(), 300, None).unwrap_err();
+    assert_eq!(rejection, MetadataRejection::NonceMismatch);
+    assert_eq!(rejection.code(), "OTA_METADATA_REPLAY");
+
+    let rejection = ota::verify_metadata(&metadata(None, 5), "n-new", Utc::now(), 300, None).unwrap_err();
+    assert_eq!(rejection, MetadataRejection::MissingNonce);
+    assert_eq!(rejection.code(), "OTA_METADATA_REPLAY");
+}
+
+#[test]
+fn stale_metadata_is_rejected() {
+    let rejection = ota::verify_metadata(&metadata(Some("n-1"), 600), "n-1", Utc::now(), 300, None).unwrap_err();
+    assert!(matches!(rejection, MetadataRejection::Stale { max_age_secs: 300, .. }));
+    assert_eq!(rejection.code(), "OTA_METADATA_STALE");
+
+    let mut undated = metadata(Some("n-1"), 0);
+    undated.issued_at = None;
+    assert_eq!(ota::verify_metadata(&undated, "n-1", Utc::now(), 300, None), Err(MetadataRejection::MissingTimestamp));
+}
+
+// Signed by the backend with the test key, see
+// test_latest_firmware_metadata_is_signed_with_the_nonce
+const SIGNED_METADATA: &str = r#"{
+    "version": "1.2.0",
+    "checksum": "sha256:abc",
+    "url": "https://fleet.example/firmware/1.2.0.bin",
+    "nonce": "n-1",
+    "issued_at": "2026-01-08T12:00:00Z",
+    "metadata_signature": "18c4e73a722b2ccc800588658ff4f44b9a592f108491b670cba8484668ec14275fcb8bbe2e50b2b6f1fda1757d9c0921fc6477571a272b0bf9ba18b5af15ac02"
+}"#;
+
+#[test]
+fn the_metadata_message_is_the_one_the_backend_signs() {
+    let metadata: FirmwareMetadata = serde_json::from_str(SIGNED_METADATA).unwrap();
+    assert_eq!(
+        String::from_utf8(ota::metadata_message(&metadata)).unwrap(),
+        r#"["vf-ota-metadata/1","1.2.0","sha256:abc","https://fleet.example/firmware/1.2.0.bin","n-1",1767873600]"#
+    );
+    assert_eq!(hex(&signing_key().sign(&ota::metadata_message(&metadata)).to_bytes()), metadata.metadata_signature.unwrap());
+}
+
+#[test]
+fn signed_metadata_is_trusted_only_once_its_signature_verifies() {
+    let key = signing_key().verifying_key();
+    let signed: FirmwareMetadata = serde_json::from_str(SIGNED_METADATA).unwrap();
+    let now = signed.issued_at.unwrap() + Duration::seconds(5);
+    assert_eq!(ota::verify_metadata(&signed, "n-1", now, 300, Some(&key)), Ok(()));
+
+    // A replay with the nonce rewritten to the new request's no longer verifies
+    let mut replayed = signed.clone();
+    replayed.nonce = Some("n-2".to_string());
+    let rejection = ota::verify_metadata(&replayed, "n-2", now, 300, Some(&key)).unwrap_err();
+    assert_eq!((rejection.clone(), rejection.code()), (MetadataRejection::BadSignature, "OTA_METADATA_SIGNATURE"));
+    let mut redated = signed.clone();
+    redated.issued_at = Some(Utc::now());
+    assert_eq!(ota::verify_metadata(&redated, "n-1", Utc::now(), 300, Some(&key)), Err(MetadataRejection::BadSignature));
+
+    // Signed, but for another request or too long ago
+    assert_eq!(ota::verify_metadata(&signed, "n-2", now, 300, Some(&key)), Err(MetadataRejection::NonceMismatch));
+    assert!(matches!(ota::verify_metadata(&signed, "n-1", now + Duration::seconds(600), 300, Some(&key)), Err(MetadataRejection::Stale { .. })));
+
+    let mut unsigned = signed;
+    unsigned.metadata_signature = None;
+    assert_eq!(ota::verify_metadata(&unsigned, "n-1", now, 300, Some(&key)), Err(MetadataRejection::Unsigned));
+    assert_eq!(ota::verify_metadata(&unsigned, "n-1", now, 300, None), Ok(()));
+}
+
+fn firmware_dir() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("firmware_{}", uuid::Uuid::new_v4()))
+}
+
+// `image` as net::download_firmware leaves it, under `dir`
+fn downloaded(dir: &std::path::Path, image: &[u8]) -> DownloadedFirmware {
+    std::fs::create_dir_all(dir).unwrap();
+    let path = dir.join("download.part");
+    std::fs::write(&path, image).unwrap();
+    DownloadedFirmware { path, digest: Sha256::digest(image).into(), bytes: image.len() as u64, resumed_from: 0 }
+}
+
+#[test]
+fn mismatched_firmware_is_not_installed() {
+    let dir = firmware_dir();
+    let mut state = installed_state();
+    state.confirm_boot();
+    // Slot A holds the rollback target, which a bad download must not replace
+    let rollback_target = slots::install(&dir, "A", "1.0.0", b"firmware 1.0.0", None).unwrap();
+    state.manifests.insert("A".to_string(), rollback_target.clone());
+    let mut offered = metadata(Some("n-1"), 0);
+    offered.version = "1.2.0".to_string();
+    offered.checksum = "00".repeat(32);
+
+    let error = ota::install_firmware(&mut state, &offered, &downloaded(&dir, b"tampered image"), &dir).unwrap_err();
+    assert!(matches!(error, ota::OtaError::Checksum(_)), "{}", error);
+    assert_eq!(state.current_version, "1.1.0");
+    assert_eq!(state.active_slot, "B");
+    assert!(!state.pending_confirmation);
+    assert_eq!(slots::verify("A", Some(&rollback_target)).unwrap().version, "1.0.0");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn verified_firmware_switches_slot() {
+    let dir = firmware_dir();
+    let image = b"firmware image";
+    let mut offered = metadata(Some("n-1"), 0);
+    offered.checksum = "1DF2F3853D10A305AA52D36FD4A03F5721D7CE7DAEF6F7E5E8D51074D31361F1".to_string();
+    let download = downloaded(&dir, image);
+    assert_eq!(ota::verify_checksum(&download.sha256_hex(), &offered.checksum), Ok(()));
+
+    let mut state = installed_state();
+    state.rollback();
+    let manifest = ota::install_firmware(&mut state, &offered, &download, &dir).unwrap();
+    assert_eq!(manifest, dir.join("slot_B").join("manifest.json"));
+    assert_eq!(state.manifests.get("B"), Some(&manifest));
+    let installed = slots::verify("B", Some(&manifest)).unwrap();
+    assert_eq!(std::fs::read(dir.join("slot_B").join(&installed.artifact)).unwrap(), image);
+    // Moved into the slot rather than copied
+    assert!(!download.path.exists());
+    assert_eq!(state.current_version, "1.1.0");
+    assert_eq!(state.active_slot, "B");
+    assert!(state.pending_confirmation);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn checksum_accepts_bare_and_prefixed_hex() {
+    let hex = "1df2f3853d10a305aa52d36fd4a03f5721d7ce7daef6f7e5e8d51074d31361f1";
+    assert_eq!(ota::verify_checksum(hex, hex), Ok(()));
+    assert_eq!(ota::verify_checksum(hex, &format!("sha256:{}", hex)), Ok(()));
+    assert_eq!(ota::verify_checksum(hex, &format!("SHA256:{}", hex.to_uppercase())), Ok(()));
+
+    let mismatch = ota::verify_checksum(hex, &format!("md5:{}", hex)).unwrap_err();
+    assert_eq!(mismatch.actual, hex);
+    assert!(ota::verify_checksum(hex, "sha256:").is_err());
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn signing_key() -> SigningKey {
+    SigningKey::from_bytes(&[7; 32])
+}
+
+#[test]
+fn signatures_are_checked_against_the_configured_key() {
+    let image = b"firmware image";
+    let key = ota::public_key(&hex(signing_key().verifying_key().as_bytes())).unwrap();
+    let signature = hex(&signing_key().sign(image).to_bytes());
+    assert_eq!(ota::verify_signature(image, Some(&signature), &key), Ok(()));
+    assert_eq!(ota::verify_signature(image, Some(&signature.to_uppercase()), &key), Ok(()));
+
+    assert_eq!(ota::verify_signature(b"tampered image", Some(&signature), &key), Err(SignatureRejection::Invalid));
+    let other_signer = hex(&SigningKey::from_bytes(&[8; 32]).sign(image).to_bytes());
+    assert_eq!(ota::verify_signature(image, Some(&other_signer), &key), Err(SignatureRejection::Invalid));
+    assert_eq!(ota::verify_signature(image, None, &key), Err(SignatureRejection::Missing));
+    assert_eq!(ota::verify_signature(image, Some(&signature[..126]), &key), Err(SignatureRejection::Malformed));
+    assert_eq!(ota::verify_signature(image, Some("zz"), &key), Err(SignatureRejection::Malformed));
+
+    assert!(ota::public_key("abcd").is_err());
+    assert!(ota::public_key(&"g".repeat(64)).is_err());
+}
+
+// Serves the offered metadata, echoing the nonce of each check and signing the result
+// with the test key
+struct LatestFirmware(Value);
+
+impl Respond for LatestFirmware {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let nonce = request.url.query_pairs().find(|(key, _)| key == "nonce").map(|(_, value)| value.to_string());
+        let mut metadata = self.0.clone();
+        metadata["nonce"] = json!(nonce);
+        let message = ota::metadata_message(&serde_json::from_value(metadata.clone()).unwrap());
+        metadata["metadata_signature"] = json!(hex(&signing_key().sign(&message).to_bytes()));
+        ResponseTemplate::new(200).set_body_json(metadata)
+    }
+}
+
+fn paths_under(dir: &std::path::Path) -> DevicePaths {
+    std::fs::create_dir_all(dir).unwrap();
+    DevicePaths {
+        index: None,
+        config: dir.join("device_config.json"),
+        database: dir.join("device_storage.db"),
+        ota_state: dir.join("ota_state.json"),
+        firmware_dir: dir.join("firmware"),
+        audit_log: dir.join("audit.log"),
+        staged_txn: dir.join("staged_txn.json"),
+        schema_cache: dir.join("schema_cache.json"),
+        exports_dir: dir.join("exports"),
+        crash_dir: dir.join("crash"),
+        restored_runtime: dir.join("restored_runtime.json"),
+        suspended: dir.join("suspended"),
+    }
+}
+
+// Runs one OTA check against a backend offering 1.2.0 with `signature`
+async fn check_signed_update(signature: String) -> (anyhow::Result<bool>, OtaState, DevicePaths) {
+    let image = b"firmware 1.2.0";
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/api/firmware/latest"))
+        .respond_with(LatestFirmware(json!({
+            "version": "1.2.0",
+            "checksum": export::sha256_hex(image),
+            "url": format!("{}/firmware/1.2.0.bin", server.uri()),
+            "issued_at": Utc::now(),
+            "signature": signature,
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET")).and(path("/firmware/1.2.0.bin")).respond_with(ResponseTemplate::new(200).set_body_bytes(image.to_vec())).mount(&server).await;
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("DEVICE_ID".to_string(), "device-1".to_string()),
+        ("OTA_PUBLIC_KEY".to_string(), hex(signing_key().verifying_key().as_bytes())),
+        ("OTA_APPLY_MS".to_string(), "0".to_string()),
+    ]);
+    let (config, _) = Config::from_env_vars(&env);
+    let paths = paths_under(&firmware_dir());
+    let mut audit_log = AuditLog::open(&paths.audit_log, "device-1").unwrap();
+    let (status, _statuses) = mpsc::unbounded_channel();
+    let mut state = installed_state();
+    state.confirm_boot();
+
+    let result = ota::check_for_update(&reqwest::Client::new(), &config, &ApiStats::default(), &paths, &mut state, &mut audit_log, &status, &net::heartbeat_body(&config, "1.1.0", 10, 60, 30), false).await;
+    (result, state, paths)
+}
+
+#[tokio::test]
+async fn a_correctly_signed_update_is_installed() {
+    let signature = hex(&signing_key().sign(&Sha256::digest(b"firmware 1.2.0")).to_bytes());
+    let (result, state, paths) = check_signed_update(signature).await;
+    assert!(result.unwrap());
+    assert_eq!((state.current_version.as_str(), state.active_slot.as_str()), ("1.2.0", "A"));
+    assert!(paths.firmware_dir.join("slot_A/firmware_1.2.0.bin").exists());
+    let _ = std::fs::remove_dir_all(paths.firmware_dir.parent().unwrap());
+}
+
+#[tokio::test]
+async fn a_badly_signed_update_is_rejected_without_switching_slots() {
+    let signature = hex(&signing_key().sign(&Sha256::digest(b"some other image")).to_bytes());
+    let (result, state, paths) = check_signed_update(signature).await;
+    let error = result.unwrap_err();
+    assert_eq!(error.downcast_ref::<SignatureRejection>(), Some(&SignatureRejection::Invalid));
+    assert_eq!((state.current_version.as_str(), state.active_slot.as_str()), ("1.1.0", "B"));
+    assert!(matches!(&state.ota_status, OtaStatus::Failed { version, .. } if version == "1.2.0"));
+    assert!(!paths.firmware_dir.join("slot_A").exists());
+    assert!(!paths.firmware_dir.join("download_1.2.0.part").exists());
+    assert!(std::fs::read_to_string(&paths.audit_log).unwrap().contains("ota_signature_rejected"));
+    let _ = std::fs::remove_dir_all(paths.firmware_dir.parent().unwrap());
+}
+
+// Serves `image` in 4 KiB chunks, each sent `delay` after the last, so a download takes
+// at least chunks * delay
+async fn throttled_server(image: Vec<u8>, delay: std::time::Duration) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/firmware/1.2.0.bin", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let image = image.clone();
+            tokio::spawn(async move {
+                let mut request = [0; 4096];
+                let _ = socket.read(&mut request).await;
+                let headers = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", image.len());
+                socket.write_all(headers.as_bytes()).await.unwrap();
+                for chunk in image.chunks(4096) {
+                    tokio::time::sleep(delay).await;
+                    socket.write_all(chunk).await.unwrap();
+                }
+            });
+        }
+    });
+    url
+}
+
+// One device updating from 1.1.0 to 1.2.0 over a link throttled to a chunk per `delay`
+async fn throttled_update(image: Vec<u8>, delay: std::time::Duration) -> (OtaState, DevicePaths) {
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/api/firmware/latest"))
+        .respond_with(LatestFirmware(json!({
+            "version": "1.2.0",
+            "checksum": export::sha256_hex(&image),
+            "url": throttled_server(image, delay).await,
+            "issued_at": Utc::now(),
+        })))
+        .mount(&server)
+        .await;
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("OTA_APPLY_MS".to_string(), "0".to_string()),
+    ]);
+    let (config, _) = Config::from_env_vars(&env);
+    let paths = paths_under(&firmware_dir());
+    let mut audit_log = AuditLog::open(&paths.audit_log, "device-1").unwrap();
+    let (status, _statuses) = mpsc::unbounded_channel();
+    let mut state = installed_state();
+    state.confirm_boot();
+
+    let installed = ota::check_for_update(&reqwest::Client::new(), &config, &ApiStats::default(), &paths, &mut state, &mut audit_log, &status, &net::heartbeat_body(&config, "1.1.0", 10, 60, 30), false).await;
+    assert!(installed.unwrap());
+    (state, paths)
+}
+
+#[tokio::test]
+async fn a_throttled_rollout_records_the_time_and_bytes_each_update_took() {
+    let image: Vec<u8> = (0..32 * 1024).map(|i| (i % 251) as u8).collect(); // 8 chunks
+    let delays_ms = [25, 50, 100];
+    let devices = futures::future::join_all(delays_ms.map(|ms| throttled_update(image.clone(), std::time::Duration::from_millis(ms)))).await;
+
+    for ((state, _), delay_ms) in devices.iter().zip(delays_ms) {
+        let attempt = &state.history[0];
+        assert_eq!((attempt.version.as_str(), attempt.from_version.as_str(), attempt.outcome), ("1.2.0", "1.1.0", AttemptOutcome::Installed));
+        assert_eq!((attempt.bytes_downloaded, attempt.image_bytes), (image.len() as u64, Some(image.len() as u64)));
+        assert!((8 * delay_ms..8 * delay_ms + 2_000).contains(&attempt.download_ms), "{} ms at {} ms a chunk", attempt.download_ms, delay_ms);
+        assert_eq!((attempt.retries, attempt.resumed, attempt.network), (0, false, None));
+        // Carried by the first heartbeat after the reboot
+        assert_eq!(state.last_update.as_ref(), Some(attempt));
+    }
+
+    let progress = ota_history::rollout(devices.iter().map(|(state, _)| (state.current_version.as_str(), state.history.as_slice())));
+    assert_eq!(progress.devices_per_version, BTreeMap::from([("1.2.0".to_string(), 3)]));
+    assert_eq!(progress.median_download_ms["1.2.0"], devices[1].0.history[0].download_ms);
+
+    // Passing the trial settles the attempt; the day's summary adds up the downloads
+    let mut state = devices[0].0.clone();
+    state.confirm_boot();
+    assert_eq!(state.history[0].outcome, AttemptOutcome::Confirmed);
+    let daily = ota_history::daily_summary(&state.history, Utc::now());
+    assert_eq!((daily.len(), daily[0].attempts, daily[0].failed, daily[0].bytes_downloaded), (1, 1, 0, image.len() as u64));
+
+    for (_, paths) in &devices {
+        let _ = std::fs::remove_dir_all(paths.firmware_dir.parent().unwrap());
+    }
+}
+
+// Updates from 1.1.0 to 1.2.0 with an apply taking `apply_ms` plus a chaos `hang_ms`,
+// returning the OTA phases the heartbeats sent along the way announced
+async fn apply_update(apply_ms: u64, hang_ms: u64, break_install: bool) -> (anyhow::Result<bool>, OtaState, Vec<Value>) {
+    let image = b"firmware 1.2.0";
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/api/firmware/latest"))
+        .respond_with(LatestFirmware(json!({
+            "version": "1.2.0",
+            "checksum": export::sha256_hex(image),
+            "url": format!("{}/firmware/1.2.0.bin", server.uri()),
+            "issued_at": Utc::now(),
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET")).and(path("/firmware/1.2.0.bin")).respond_with(ResponseTemplate::new(200).set_body_bytes(image.to_vec())).mount(&server).await;
+    Mock::given(method("POST")).and(path("/api/devices/heartbeat"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "desired_version": null,
+            "desired_sample_interval_secs": 10,
+            "desired_upload_interval_secs": 60,
+            "desired_heartbeat_interval_secs": 30,
+        })))
+        .mount(&server)
+        .await;
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("DEVICE_ID".to_string(), "device-1".to_string()),
+        ("OTA_APPLY_MS".to_string(), apply_ms.to_string()),
+        ("OTA_APPLY_OVERDUE_FACTOR".to_string(), "2".to_string()),
+    ]);
+    let (mut config, _) = Config::from_env_vars(&env);
+    if hang_ms > 0 {
+        config.chaos_flags = Some(ChaosFlags::parse(&json!({"ota_apply_hang_ms": hang_ms})).unwrap());
+    }
+    let paths = paths_under(&firmware_dir());
+    if break_install {
+        // A file where slot A's directory should go
+        std::fs::create_dir_all(&paths.firmware_dir).unwrap();
+        std::fs::write(slots::dir(&paths.firmware_dir, "A"), b"").unwrap();
+    }
+    let mut audit_log = AuditLog::open(&paths.audit_log, "device-1").unwrap();
+    let (status, _statuses) = mpsc::unbounded_channel();
+    let mut state = installed_state();
+    state.confirm_boot();
+
+    let heartbeat = net::heartbeat_body(&config, "1.1.0", 10, 60, 30);
+    let result = ota::check_for_update(&reqwest::Client::new(), &config, &ApiStats::default(), &paths, &mut state, &mut audit_log, &status, &heartbeat, false).await;
+    let phases = server.received_requests().await.unwrap().iter()
+        .filter(|request| request.url.path() == "/api/devices/heartbeat")
+        .map(|request| serde_json::from_slice::<Value>(&net_tests::decoded_body(request)).unwrap()["ota_phase"].clone())
+        .collect();
+    let _ = std::fs::remove_dir_all(paths.firmware_dir.parent().unwrap());
+    (result, state, phases)
+}
+
+#[tokio::test]
+async fn the_apply_phase_is_announced_before_going_dark() {
+    let (result, state, phases) = apply_update(300, 0, false).await;
+    assert!(result.unwrap());
+    assert_eq!(phases, [json!({"phase": "applying", "version": "1.2.0", "expected_downtime_ms": 300})]);
+    let applied = state.last_update.as_ref().unwrap();
+    assert_eq!(applied.outcome, AttemptOutcome::Installed);
+    assert!(applied.apply_ms >= 300, "{} ms", applied.apply_ms);
+
+    // Applies that took longer than configured raise the next estimate
+    assert_eq!(ota::apply_estimate(100, &state.history), applied.apply_ms);
+    assert_eq!(ota::apply_estimate(60_000, &state.history), 60_000);
+    assert_eq!(ota::apply_estimate(100, &[]), 100);
+}
+
+#[tokio::test]
+async fn a_failed_apply_is_announced_right_away() {
+    let (result, state, phases) = apply_update(0, 0, true).await;
+    assert!(result.is_err());
+    assert_eq!((state.current_version.as_str(), state.active_slot.as_str()), ("1.1.0", "B"));
+    assert_eq!(phases.len(), 2, "{:?}", phases);
+    assert_eq!(phases[0]["phase"], "applying");
+    let failed: OtaPhase = serde_json::from_value(phases[1].clone()).unwrap();
+    assert!(matches!(&failed, OtaPhase::Failed { version, error } if version == "1.2.0" && !error.is_empty()), "{:?}", failed);
+}
+
+#[tokio::test]
+async fn a_hung_apply_sends_progress_heartbeats() {
+    // Announced as 600 ms, overdue at twice that, done after 3 s
+    let (result, _, phases) = apply_update(600, 2_400, false).await;
+    assert!(result.unwrap());
+    assert_eq!(phases.len(), 3, "{:?}", phases);
+    assert_eq!(phases[0], json!({"phase": "applying", "version": "1.2.0", "expected_downtime_ms": 600}));
+    for (progress, at_least_ms) in phases[1..].iter().zip([1_200, 2_400]) {
+        let overdue: OtaPhase = serde_json::from_value(progress.clone()).unwrap();
+        assert!(matches!(overdue, OtaPhase::Overdue { elapsed_ms, expected_downtime_ms: 600, .. } if elapsed_ms >= at_least_ms), "{:?}", overdue);
+    }
+}
+
+#[tokio::test]
+async fn maintenance_holds_back_only_the_apply() {
+    let image = b"firmware 1.2.0";
+    let server = MockServer::start().await;
+    Mock::given(method("GET")).and(path("/api/firmware/latest"))
+        .respond_with(LatestFirmware(json!({
+            "version": "1.2.0",
+            "checksum": export::sha256_hex(image),
+            "url": format!("{}/firmware/1.2.0.bin", server.uri()),
+            "issued_at": Utc::now(),
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET")).and(path("/firmware/1.2.0.bin")).respond_with(ResponseTemplate::new(200).set_body_bytes(image.to_vec())).expect(1).mount(&server).await;
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("OTA_APPLY_MS".to_string(), "0".to_string()),
+    ]);
+    let (config, _) = Config::from_env_vars(&env);
+    let paths = paths_under(&firmware_dir());
+    let mut audit_log = AuditLog::open(&paths.audit_log, "device-1").unwrap();
+    let (status, _statuses) = mpsc::unbounded_channel();
+    let mut state = installed_state();
+    state.confirm_boot();
+    let heartbeat = net::heartbeat_body(&config, "1.1.0", 10, 60, 30);
+
+    // Fetched and verified during the window, but not installed; a second check in it
+    // does not download again
+    for _ in 0..2 {
+        let installed = ota::check_for_update(&reqwest::Client::new(), &config, &ApiStats::default(), &paths, &mut state, &mut audit_log, &status, &heartbeat, true).await;
+        assert!(!installed.unwrap());
+    }
+    assert_eq!((state.current_version.as_str(), state.active_slot.as_str()), ("1.1.0", "B"));
+    assert_eq!(state.ota_status, OtaStatus::Staged { version: "1.2.0".to_string() });
+    assert!(!paths.firmware_dir.join("slot_A").exists());
+
+    // Staged across a restart, and applied by the first check after the window
+    let mut state = OtaState::load_from(&paths.ota_state).unwrap();
+    let staged = state.staged.as_ref().unwrap();
+    assert_eq!((staged.metadata.version.as_str(), staged.download.bytes), ("1.2.0", image.len() as u64));
+    let installed = ota::check_for_update(&reqwest::Client::new(), &config, &ApiStats::default(), &paths, &mut state, &mut audit_log, &status, &heartbeat, false).await;
+    assert!(installed.unwrap());
+    assert_eq!((state.current_version.as_str(), state.active_slot.as_str(), state.staged.is_none()), ("1.2.0", "A", true));
+    assert_eq!(state.history.last().unwrap().outcome, AttemptOutcome::Installed);
+    server.verify().await;
+    let _ = std::fs::remove_dir_all(paths.firmware_dir.parent().unwrap());
+}
+
+#[test]
+fn apply_settings_are_validated() {
+    let (mut config, _) = Config::from_env_vars(&HashMap::new());
+    config.ota_apply_overdue_factor = 0.5;
+    let findings = validation::check_config(&config).findings;
+    assert!(findings.iter().any(|finding| finding.key == "ota_apply_overdue_factor" && finding.severity == Severity::Error), "{:?}", findings);
+    let findings = validation::check_desired(&config, &json!({"chaos_flags": {"ota_apply_hang_ms": "forever"}})).findings;
+    assert!(findings.iter().any(|finding| finding.key == "desired.chaos_flags.ota_apply_hang_ms"), "{:?}", findings);
+}
@@ -0,0 +1,163 @@
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::TcpListener;
+use wiremock::matchers::{body_partial_json, method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use super::net_tests;
+use crate::config::Config;
+use crate::geofence::GeofenceCrossing;
+use crate::net;
+use crate::outbox::{self, OutboxKind};
+use crate::shadow_report::{self, ShadowReportGuard};
+use crate::stats::ApiStats;
+use crate::storage;
+use crate::types::DeviceEvent;
+
+fn device_config(backend_url: &str) -> Config {
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), backend_url.to_string()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("DEVICE_ID".to_string(), "device-1".to_string()),
+        ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()),
+    ]);
+    Config::from_env_vars(&env).0
+}
+
+fn temp_db() -> (std::path::PathBuf, rusqlite::Connection) {
+    let db_path = std::env::temp_dir().join(format!("outbox_{}.db", uuid::Uuid::new_v4()));
+    let conn = storage::init_at(&db_path).unwrap();
+    (db_path, conn)
+}
+
+#[test]
+fn each_kind_keeps_only_its_latest_message() {
+    let (db_path, conn) = temp_db();
+    let config = device_config("http://localhost:1");
+    outbox::queue_heartbeat(&conn, &net::heartbeat_body(&config, "1.0.0", 10, 60, 30), Utc::now()).unwrap();
+    outbox::queue_heartbeat(&conn, &net::heartbeat_body(&config, "1.1.0", 10, 60, 30), Utc::now()).unwrap();
+    outbox::queue_shadow_report(&conn, &json!({"sample_interval_secs": 10}), Utc::now()).unwrap();
+    outbox::queue_shadow_report(&conn, &json!({"sample_interval_secs": 20}), Utc::now()).unwrap();
+
+    let (heartbeat, _) = storage::outbox_entry(&conn, OutboxKind::Heartbeat.as_str()).unwrap().unwrap();
+    assert_eq!(heartbeat["firmware_version"], "1.1.0");
+    let (report, _) = storage::outbox_entry(&conn, OutboxKind::ShadowReport.as_str()).unwrap().unwrap();
+    assert_eq!(report, json!({"sample_interval_secs": 20}));
+
+    outbox::clear(&conn, OutboxKind::Heartbeat).unwrap();
+    assert!(storage::outbox_entry(&conn, OutboxKind::Heartbeat.as_str()).unwrap().is_none());
+    assert!(storage::outbox_entry(&conn, OutboxKind::ShadowReport.as_str()).unwrap().is_some());
+    drop(conn);
+    // Survives a restart
+    assert!(storage::outbox_entry(&storage::init_at(&db_path).unwrap(), OutboxKind::ShadowReport.as_str()).unwrap().is_some());
+    let _ = std::fs::remove_file(&db_path);
+}
+
+async fn flush(client: &reqwest::Client, config: &Config, stats: &ApiStats, conn: &rusqlite::Connection, guard: &mut ShadowReportGuard) -> anyhow::Result<usize> {
+    let pending = outbox::pending(conn).unwrap();
+    pending.send(client, config, stats, guard).await.settle(conn)
+}
+
+#[tokio::test]
+async fn a_report_queued_while_offline_is_flushed_once_the_backend_is_reachable() {
+    // Reserve an address, then leave nothing listening on it
+    let address = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let config = device_config(&format!("http://{}", address));
+    let (db_path, conn) = temp_db();
+    let client = reqwest::Client::new();
+    let stats = ApiStats::default();
+    let mut guard = ShadowReportGuard::default();
+
+    let state = json!({"connection": "online", "sample_interval_secs": 15});
+    assert!(shadow_report::report(&client, &config, &stats, &mut guard, &state).await.is_err());
+    outbox::queue_shadow_report(&conn, &state, Utc::now()).unwrap();
+    outbox::queue_heartbeat(&conn, &net::heartbeat_body(&config, "1.0.0", 15, 60, 30), Utc::now()).unwrap();
+    // Still offline: nothing is lost
+    assert!(flush(&client, &config, &stats, &conn, &mut guard).await.is_err());
+    assert!(storage::outbox_entry(&conn, OutboxKind::ShadowReport.as_str()).unwrap().is_some());
+    assert!(storage::outbox_entry(&conn, OutboxKind::Heartbeat.as_str()).unwrap().is_some());
+
+    // The backend comes up at the same address
+    let server = MockServer::builder().listener(TcpListener::bind(address).unwrap()).start().await;
+    Mock::given(method("POST")).and(path("/api/devices/heartbeat"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "desired_version": null,
+            "desired_sample_interval_secs": 15,
+            "desired_upload_interval_secs": 60,
+            "desired_heartbeat_interval_secs": 30,
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PATCH")).and(path("/api/devices/device-1/shadow"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut flushed = outbox::pending(&conn).unwrap().send(&client, &config, &stats, &mut guard).await;
+    // The answer to the queued heartbeat comes back for the device to apply
+    assert_eq!(flushed.take_desired_state().map(|desired| desired.desired_sample_interval_secs), Some(15));
+    assert_eq!(flushed.settle(&conn).unwrap(), 2);
+    let reported: Vec<Value> = server.received_requests().await.unwrap().iter()
+        .filter(|request| request.method.as_str() == "PATCH")
+        .map(|request| serde_json::from_slice::<Value>(&net_tests::decoded_body(request)).unwrap()["state"].clone())
+        .collect();
+    assert_eq!(reported, [state]);
+    assert!(storage::outbox_entry(&conn, OutboxKind::ShadowReport.as_str()).unwrap().is_none());
+    assert!(storage::outbox_entry(&conn, OutboxKind::Heartbeat.as_str()).unwrap().is_none());
+    // Nothing left to send
+    assert_eq!(flush(&client, &config, &stats, &conn, &mut guard).await.unwrap(), 0);
+    let _ = std::fs::remove_file(&db_path);
+}
+
+fn crossing(latitude: f64) -> GeofenceCrossing {
+    GeofenceCrossing { timestamp: Utc::now(), latitude, longitude: 13.405 }
+}
+
+#[tokio::test]
+async fn queued_events_go_out_oldest_first_and_wait_until_they_do() {
+    let server = MockServer::start().await;
+    let config = device_config(&server.uri());
+    let (db_path, conn) = temp_db();
+    let client = reqwest::Client::new();
+    let stats = ApiStats::default();
+    let mut guard = ShadowReportGuard::default();
+    let events = [DeviceEvent::GeofenceBreach(crossing(52.53)), DeviceEvent::GeofenceReentry(crossing(52.52)), DeviceEvent::GeofenceBreach(crossing(52.54))];
+    for event in &events {
+        outbox::queue_event(&conn, event, Utc::now()).unwrap();
+    }
+
+    // The backend is up but failing: every event stays queued
+    Mock::given(method("POST")).and(path("/api/devices/device-1/events")).respond_with(ResponseTemplate::new(503)).mount(&server).await;
+    assert!(flush(&client, &config, &stats, &conn, &mut guard).await.is_err());
+    assert_eq!(storage::queued_events(&conn).unwrap().len(), 3);
+
+    // Recovered, except that it refuses the re-entry, which is dropped rather than retried
+    server.reset().await;
+    Mock::given(method("POST")).and(path("/api/devices/device-1/events")).and(body_partial_json(json!({"event_type": "geofence_reentry"})))
+        .respond_with(ResponseTemplate::new(422))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST")).and(path("/api/devices/device-1/events")).respond_with(ResponseTemplate::new(202)).mount(&server).await;
+    assert_eq!(flush(&client, &config, &stats, &conn, &mut guard).await.unwrap(), 2);
+    let sent: Vec<DeviceEvent> = server.received_requests().await.unwrap().iter()
+        .map(|request| serde_json::from_slice(&net_tests::decoded_body(request)).unwrap())
+        .collect();
+    assert_eq!(sent, events);
+    assert!(storage::queued_events(&conn).unwrap().is_empty());
+    let _ = std::fs::remove_file(&db_path);
+}
+
+#[test]
+fn a_full_event_outbox_drops_its_oldest_events() {
+    let (db_path, conn) = temp_db();
+    for i in 0..=outbox::MAX_QUEUED_EVENTS {
+        outbox::queue_event(&conn, &DeviceEvent::GeofenceBreach(crossing(i as f64 / 1000.0)), Utc::now()).unwrap();
+    }
+    let queued = storage::queued_events(&conn).unwrap();
+    assert_eq!(queued.len(), outbox::MAX_QUEUED_EVENTS);
+    assert_eq!(queued[0].1["latitude"], json!(0.001));
+    let _ = std::fs::remove_file(&db_path);
+}
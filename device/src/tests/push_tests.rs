@@ -0,0 +1,113 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+use crate::config::Config;
+use crate::network::NetworkType;
+use crate::push::{self, KeepaliveAction, KeepaliveConfig, PushChannel};
+use crate::types::ChaosFlags;
+use crate::validation;
+
+const SHADOW_CHECK_SECS: u64 = 60;
+
+// Drives the channel the way the main loop does: keepalive every second, a shadow
+// check every minute, and an immediate handoff fetch after a reconnect. Returns the
+// second at which the device first holds `target`.
+fn run_until_applied(channel: &mut PushChannel, backend: impl Fn(u64) -> Value, target: &Value, until: u64) -> Option<u64> {
+    let mut handoff = false;
+    for now in 0..=until {
+        if channel.poll(now) == KeepaliveAction::Reconnect {
+            channel.reconnect(now);
+            handoff = true;
+        }
+        if handoff || now % SHADOW_CHECK_SECS == 0 {
+            handoff = false;
+            if channel.receive(now, backend(now)).as_ref() == Some(target) {
+                return Some(now);
+            }
+        }
+    }
+    None
+}
+
+fn desired_at(change_at: u64) -> impl Fn(u64) -> Value {
+    move |now| if now < change_at { json!({"version": 1, "sample_interval_secs": 10}) } else { json!({"version": 2, "sample_interval_secs": 5}) }
+}
+
+#[test]
+fn idle_connection_dies_silently_behind_nat() {
+    let keepalive = KeepaliveConfig { ping_interval_secs: 0, ..Default::default() };
+    let mut channel = PushChannel::new(keepalive, Some(90));
+    let first = json!({"version": 1});
+    assert_eq!(channel.receive(0, first.clone()), Some(first.clone()));
+
+    // The change is pushed after the mapping expired: nothing errors, it just never arrives
+    assert_eq!(channel.receive(120, json!({"version": 2})), Some(first.clone()));
+    assert_eq!(channel.poll(600), KeepaliveAction::Idle);
+    assert_eq!(channel.receive(600, json!({"version": 2})), Some(first));
+    assert_eq!(channel.report()["lost_deliveries"], 1);
+    assert_eq!(channel.report()["reconnects"], 0);
+}
+
+#[test]
+fn keepalive_inside_nat_timeout_keeps_pushes_flowing() {
+    let mut channel = PushChannel::new(KeepaliveConfig::default(), Some(60));
+    let target = desired_at(150)(150);
+    // Delivered on the first shadow check after the change, no reconnect needed
+    assert_eq!(run_until_applied(&mut channel, desired_at(150), &target, 600), Some(180));
+    assert_eq!(channel.report()["missed_pongs"], 0);
+    assert_eq!(channel.report()["lost_deliveries"], 0);
+}
+
+#[test]
+fn change_during_silent_dead_window_is_applied_after_missed_pong() {
+    // Pings are too sparse to hold the mapping open, so the connection dies at 60s
+    let keepalive = KeepaliveConfig { ping_interval_secs: 90, pong_timeout_secs: 10 };
+    let mut channel = PushChannel::new(keepalive.clone(), Some(60));
+    let change_at = 70;
+    let target = desired_at(change_at)(change_at);
+
+    let applied_at = run_until_applied(&mut channel, desired_at(change_at), &target, 600).unwrap();
+    // The ping at 90s goes unanswered, the reconnect at 100s hands off from version 1
+    assert_eq!(applied_at, 100);
+    assert!(applied_at - change_at <= keepalive.ping_interval_secs + keepalive.pong_timeout_secs);
+    let report = channel.report();
+    assert_eq!(report["missed_pongs"], 1);
+    assert_eq!(report["reconnects"], 1);
+    assert_eq!(report["anchor"], "2");
+}
+
+#[test]
+fn without_keepalive_the_change_is_stuck() {
+    let keepalive = KeepaliveConfig { ping_interval_secs: 0, ..Default::default() };
+    let mut channel = PushChannel::new(keepalive, Some(60));
+    let target = desired_at(70)(70);
+    assert_eq!(run_until_applied(&mut channel, desired_at(70), &target, 3600), None);
+}
+
+#[test]
+fn handoff_on_unchanged_version_keeps_the_anchor() {
+    let mut channel = PushChannel::new(KeepaliveConfig::default(), Some(60));
+    let document = json!({"sample_interval_secs": 10});
+    channel.receive(0, document.clone());
+    channel.reconnect(30);
+    assert_eq!(channel.receive(30, document.clone()), Some(document.clone()));
+    assert_eq!(channel.report()["anchor"], push::desired_version(&document));
+    assert_ne!(push::desired_version(&document), push::desired_version(&json!({"sample_interval_secs": 5})));
+}
+
+#[test]
+fn nat_timeout_comes_from_chaos_flag_then_profile() {
+    let env = HashMap::from([("NETWORK".to_string(), "nbiot".to_string()), ("PUSH_PING_INTERVAL_SECS".to_string(), "120".to_string())]);
+    let (mut config, report) = Config::from_env_vars(&env);
+    assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    assert_eq!(push::nat_idle_timeout(&config), NetworkType::NbIot.bundled_profile().nat_idle_timeout_secs);
+    config.chaos_flags = Some(ChaosFlags::parse(&json!({"nat_idle_timeout_secs": 15})).unwrap());
+    assert_eq!(push::nat_idle_timeout(&config), Some(15));
+    config.network = Some(NetworkType::Wifi);
+    config.chaos_flags = None;
+    assert_eq!(push::nat_idle_timeout(&config), None);
+
+    config.network = Some(NetworkType::NbIot);
+    let findings = validation::check_config(&config).findings;
+    assert!(findings.iter().any(|finding| finding.key == "push_keepalive.ping_interval_secs"), "{:?}", findings);
+}
@@ -0,0 +1,212 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::config::Config;
+use crate::firmware::FirmwareBehavior;
+use crate::reefer::{BreachKind, ColdChainBreach, DeliveryStop, Reefer, ReeferConfig};
+use crate::simulate::{Position, SensorProfile, Simulator};
+use crate::types::{DeviceEvent, Measurement};
+use crate::validation::Severity;
+
+const SAMPLE_SECS: i64 = 60;
+
+fn t0() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, 6, 1, 6, 0, 0).unwrap()
+}
+
+// A hot day without a daily swing, and three deliveries: a short one, a long one on
+// which the driver switches the engine off, and a short one again
+fn delivery() -> ReeferConfig {
+    ReeferConfig {
+        ambient_mean_c: 25.0,
+        ambient_swing_c: 0.0,
+        door_open_secs: 60,
+        engine_idle_secs: 600,
+        stops: vec![
+            DeliveryStop { distance_m: 10_000.0, dwell_secs: 600 },
+            DeliveryStop { distance_m: 25_000.0, dwell_secs: 3000 },
+            DeliveryStop { distance_m: 40_000.0, dwell_secs: 900 },
+        ],
+        ..ReeferConfig::default()
+    }
+}
+
+struct Trip {
+    simulator: Simulator,
+    samples: Vec<(DateTime<Utc>, Measurement)>,
+    breaches: Vec<ColdChainBreach>,
+}
+
+impl Trip {
+    fn drive(secs: i64) -> Self {
+        let behavior = FirmwareBehavior { noise_scale: 0.0, ..Default::default() };
+        let mut simulator = Simulator::new(SensorProfile::ReeferTrailer, 7, Position::default()).with_reefer(Reefer::new(delivery(), t0()));
+        let mut samples = Vec::new();
+        let mut breaches = Vec::new();
+        for step in 0..=secs / SAMPLE_SECS {
+            let now = t0() + Duration::seconds(step * SAMPLE_SECS);
+            samples.push((now, simulator.generate_measurement_at(now, "1.0.0".to_string(), &behavior)));
+            breaches.extend(simulator.take_breaches());
+        }
+        Trip { simulator, samples, breaches }
+    }
+
+    // Arrival and departure sample times of each stop
+    fn stops(&self) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        let mut stops = Vec::new();
+        let mut arrived = None;
+        for (at, measurement) in &self.samples {
+            match (arrived, measurement.speed == Some(0.0)) {
+                (None, true) => arrived = Some(*at),
+                (Some(since), false) => {
+                    stops.push((since, *at));
+                    arrived = None;
+                }
+                _ => {}
+            }
+        }
+        stops
+    }
+
+    fn at(&self, at: DateTime<Utc>) -> &Measurement {
+        &self.samples.iter().find(|(sampled, _)| *sampled == at).expect("sampled then").1
+    }
+}
+
+fn field<'a>(measurement: &'a Measurement, key: &str) -> &'a serde_json::Value {
+    &measurement.extra.as_ref().expect("reefer fields")[key]
+}
+
+#[test]
+fn the_cargo_holds_its_setpoint_while_driving() {
+    let trip = Trip::drive(8400);
+    let config = delivery();
+    let (first_arrival, _) = trip.stops()[0];
+    for (at, measurement) in trip.samples.iter().filter(|(at, _)| *at < first_arrival) {
+        // The thermostat switches between steps, so allow a little overshoot
+        assert!((measurement.temp - config.setpoint_c).abs() <= config.hysteresis_c + 0.5, "{} at {}", measurement.temp, at);
+        assert_eq!(measurement.speed, Some(config.cruise_kmh));
+        assert_eq!(field(measurement, "door"), "closed");
+        assert_eq!(field(measurement, "engine_on"), true);
+        assert_ne!(field(measurement, "reefer"), "unpowered");
+    }
+    // Cooling kicked in at least once against the warm outside air
+    assert!(trip.samples.iter().any(|(at, measurement)| *at < first_arrival && field(measurement, "reefer") == "cooling"));
+}
+
+#[test]
+fn the_truck_stands_still_at_each_stop_and_opens_its_door() {
+    let trip = Trip::drive(8400);
+    let stops = trip.stops();
+    assert_eq!(stops.len(), 3, "{:?}", stops);
+    for ((arrived, departed), stop) in stops.iter().zip(delivery().stops) {
+        assert!(*departed - *arrived >= Duration::seconds(stop.dwell_secs as i64), "{} to {}", arrived, departed);
+        let (on_arrival, after) = (trip.at(*arrived), trip.at(*arrived + Duration::seconds(SAMPLE_SECS)));
+        assert_eq!(field(on_arrival, "door"), "open");
+        assert_eq!(field(after, "door"), "closed");
+        // The door was open for the whole minute in between
+        assert!(after.temp > on_arrival.temp + 1.0, "{} then {}", on_arrival.temp, after.temp);
+        // Parked without a route: no random walk
+        assert_eq!((after.latitude, after.longitude), (on_arrival.latitude, on_arrival.longitude));
+    }
+}
+
+#[test]
+fn a_long_stop_with_the_engine_off_breaches_the_cold_chain() {
+    let trip = Trip::drive(8400);
+    let config = delivery();
+    let (arrived, departed) = trip.stops()[1];
+
+    assert_eq!(trip.breaches.len(), 1, "{:?}", trip.breaches);
+    let breach = &trip.breaches[0];
+    assert_eq!(breach.kind, BreachKind::High);
+    assert_eq!(breach.limit_c, config.max_c);
+    // Only once the engine, and with it the unit, was off
+    assert!(breach.started_at > arrived + Duration::seconds(config.engine_idle_secs as i64), "{}", breach.started_at);
+    assert!(breach.started_at < departed, "{}", breach.started_at);
+
+    for (at, measurement) in &trip.samples {
+        let flagged = measurement.device_flags.as_ref().is_some_and(|flags| flags.iter().any(|flag| flag == "cargo_temp_high"));
+        assert_eq!(flagged, measurement.temp > config.max_c, "{} at {}", measurement.temp, at);
+        if flagged && *at < departed {
+            assert_eq!(field(measurement, "reefer"), "unpowered");
+            assert_eq!(field(measurement, "engine_on"), false);
+        }
+    }
+
+    // Back on the road, the unit pulls the cargo into the band within minutes
+    let report = trip.simulator.reefer().unwrap().report();
+    assert_eq!(report["breach"], json!(null));
+    let ended: DateTime<Utc> = serde_json::from_value(report["last_breach"]["ended_at"].clone()).unwrap();
+    assert!(ended > departed - Duration::seconds(SAMPLE_SECS) && ended < departed + Duration::minutes(5), "{}", ended);
+    assert!(report["last_breach"]["peak_c"].as_f64().unwrap() > config.max_c as f64);
+    assert_eq!(field(trip.at(departed), "reefer"), "cooling");
+}
+
+#[test]
+fn the_daily_summary_counts_breaches_and_door_openings() {
+    let trip = Trip::drive(8400);
+    let report = trip.simulator.reefer().unwrap().report();
+    assert_eq!(report["band"], json!({"min_c": 2.0, "max_c": 8.0}));
+    let daily = report["daily"].as_array().unwrap();
+    assert_eq!(daily.len(), 1);
+    assert_eq!(daily[0]["day"], "2024-06-01");
+    assert_eq!(daily[0]["breaches"], 1);
+    assert_eq!(daily[0]["door_openings"], 3);
+    assert!(daily[0]["breach_secs"].as_u64().unwrap() > 0);
+    assert!(daily[0]["cooling_secs"].as_u64().unwrap() > 0);
+    assert!(daily[0]["max_c"].as_f64().unwrap() > 8.0);
+}
+
+#[test]
+fn the_trip_survives_a_snapshot() {
+    let mut trip = Trip::drive(3000);
+    let state = trip.simulator.snapshot();
+    let saved = state.reefer.clone().expect("reefer state");
+    assert!(saved.arrived_at.is_some());
+
+    let mut restored = Simulator::new(SensorProfile::ReeferTrailer, 7, Position::default()).with_reefer(Reefer::new(delivery(), t0()));
+    restored.restore(&state, Duration::hours(1));
+    let reefer = restored.reefer().unwrap().state();
+    assert_eq!((reefer.next_stop, reefer.odometer_m, reefer.cargo_c), (saved.next_stop, saved.odometer_m, saved.cargo_c));
+    assert_eq!(reefer.arrived_at, saved.arrived_at.map(|at| at + Duration::hours(1)));
+}
+
+#[test]
+fn a_breach_is_sent_as_an_event() {
+    let breach = ColdChainBreach { kind: BreachKind::High, started_at: t0(), ended_at: None, peak_c: 8.2, limit_c: 8.0 };
+    assert_eq!(
+        serde_json::to_value(DeviceEvent::ColdChainBreach(breach)).unwrap(),
+        json!({"event_type": "cold_chain_breach", "kind": "high", "started_at": t0(), "ended_at": null, "peak_c": 8.2f32, "limit_c": 8.0}),
+    );
+}
+
+#[test]
+fn reefer_settings_are_validated() {
+    assert!(ReeferConfig::default().validate().is_ok());
+    assert!(delivery().validate().is_ok());
+    assert!(ReeferConfig { min_c: 8.0, max_c: 2.0, ..Default::default() }.validate().is_err());
+    assert!(ReeferConfig { setpoint_c: 10.0, ..Default::default() }.validate().is_err());
+    assert!(ReeferConfig { supply_c: 3.5, ..Default::default() }.validate().is_err());
+    assert!(ReeferConfig { cooling_tau_secs: 0.0, ..Default::default() }.validate().is_err());
+    let unordered = vec![DeliveryStop { distance_m: 5000.0, dwell_secs: 60 }, DeliveryStop { distance_m: 1000.0, dwell_secs: 60 }];
+    assert!(ReeferConfig { stops: unordered, ..Default::default() }.validate().is_err());
+
+    let env = |profile: &str, reefer: serde_json::Value| HashMap::from([
+        ("SENSOR_PROFILE".to_string(), profile.to_string()),
+        ("REEFER".to_string(), reefer.to_string()),
+    ]);
+    let (config, report) = Config::from_env_vars(&env("reefer_trailer", json!({"setpoint_c": -18, "min_c": -25, "max_c": -15, "supply_c": -30})));
+    assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+    assert_eq!(config.sensor_profile, SensorProfile::ReeferTrailer);
+    assert_eq!(config.reefer.as_ref().map(|reefer| reefer.setpoint_c), Some(-18.0));
+    assert!(!config.validate().findings.iter().any(|finding| finding.key == "reefer"));
+
+    let (config, _) = Config::from_env_vars(&env("reefer_trailer", json!({"setpoint_c": 12})));
+    assert!(config.validate().findings.iter().any(|finding| finding.key == "reefer" && finding.severity == Severity::Error));
+    let (config, _) = Config::from_env_vars(&env("industrial_meter", json!({})));
+    assert!(config.validate().findings.iter().any(|finding| finding.key == "reefer" && finding.severity == Severity::Warning));
+    let (_, report) = Config::from_env_vars(&env("reefer_trailer", json!({"setpont_c": 4})));
+    assert!(report.warnings.iter().any(|warning| warning.contains("Invalid REEFER")), "{:?}", report.warnings);
+}
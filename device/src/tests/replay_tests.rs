@@ -0,0 +1,125 @@
+use chrono::{Duration, Utc};
+use std::collections::HashMap;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use super::net_tests;
+use crate::config::Config;
+use crate::replay::ReconnectReplay;
+use crate::stats::ApiStats;
+use crate::types::Measurement;
+use crate::upload;
+use crate::storage;
+
+fn measurement_at(secs_ago: i64) -> Measurement {
+    let measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+    Measurement { timestamp: Utc::now() - Duration::seconds(secs_ago), ..measurement }
+}
+
+#[test]
+fn ring_keeps_only_the_window_and_row_limit() {
+    let mut replay = ReconnectReplay::new(60, 3);
+    for secs_ago in [120, 50, 40, 30, 20] {
+        replay.remember(&measurement_at(secs_ago));
+    }
+    assert_eq!(replay.report()["buffered_rows"], 3);
+
+    // Only rows within the window before the reconnect are replayed
+    let batch = replay.batch(Utc::now() + Duration::seconds(25));
+    assert_eq!(batch.len(), 2);
+    assert!(batch.iter().all(|measurement| measurement.replay == Some(true)));
+}
+
+#[test]
+fn replay_is_scheduled_on_offline_to_online_transition() {
+    let mut replay = ReconnectReplay::new(60, 10);
+    replay.remember(&measurement_at(5));
+    assert!(!replay.due());
+    replay.observe(false);
+    assert!(replay.due());
+    replay.observe(true);
+    assert!(replay.due(), "a heartbeat noticing the reconnect leaves the replay pending");
+    replay.delivered(1);
+    assert!(!replay.due());
+
+    let mut disabled = ReconnectReplay::new(0, 10);
+    disabled.remember(&measurement_at(5));
+    disabled.observe(false);
+    disabled.observe(true);
+    assert!(!disabled.due());
+}
+
+// Sampler path: store for upload and keep for a later replay
+fn sample(conn: &rusqlite::Connection, replay: &mut ReconnectReplay) {
+    let measurement = measurement_at(0);
+    storage::append_measurement(conn, &measurement, 0).unwrap();
+    replay.remember(&measurement);
+}
+
+fn ingested(requests: &[wiremock::Request]) -> Vec<Vec<Measurement>> {
+    requests.iter().map(|request| net_tests::ingest_payload(request).measurements).collect()
+}
+
+async fn respond_with(server: &MockServer, status: u16) {
+    server.reset().await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(status))
+        .mount(server)
+        .await;
+}
+
+#[tokio::test]
+async fn replay_after_outage_arrives_first_and_flagged() {
+    let server = MockServer::start().await;
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()),
+        ("RECONNECT_REPLAY_SECS".to_string(), "300".to_string()),
+    ]);
+    let (config, _) = Config::from_env_vars(&env);
+    let db_path = std::env::temp_dir().join(format!("replay_{}.db", uuid::Uuid::new_v4()));
+    let mut conn = storage::init_at(&db_path).unwrap();
+    let client = reqwest::Client::new();
+    let stats = ApiStats::default();
+    let mut replay = ReconnectReplay::new(config.reconnect_replay_secs, config.reconnect_replay_max_rows);
+
+    // Online: rows are uploaded and deleted, and no replay is due
+    respond_with(&server, 204).await;
+    for _ in 0..3 {
+        sample(&conn, &mut replay);
+    }
+    assert!(upload::send_replay(&client, &config, &stats, &mut replay, None, Utc::now()).await.is_none());
+    let round = upload::drain_once(&client, &config, &stats, &mut conn, None).await.unwrap();
+    replay.observe(round.uploaded() > 0);
+    assert_eq!(storage::pending_count(&conn).unwrap(), 0);
+
+    // Outage: the backlog builds up
+    respond_with(&server, 503).await;
+    for _ in 0..2 {
+        sample(&conn, &mut replay);
+    }
+    let round = upload::drain_once(&client, &config, &stats, &mut conn, None).await.unwrap();
+    replay.observe(round.uploaded() > 0);
+    assert_eq!(storage::pending_count(&conn).unwrap(), 2);
+
+    // Back online: the replay goes out before the backlog drain
+    respond_with(&server, 204).await;
+    let batch = upload::send_replay(&client, &config, &stats, &mut replay, None, Utc::now()).await.unwrap();
+    assert!(batch.uploaded);
+    upload::drain_once(&client, &config, &stats, &mut conn, None).await.unwrap();
+    let _ = std::fs::remove_file(&db_path);
+
+    let requests = ingested(&server.received_requests().await.unwrap());
+    assert_eq!(requests.len(), 2);
+    let (replayed, backlog) = (&requests[0], &requests[1]);
+    assert_eq!(replayed.len(), 5);
+    assert!(replayed.iter().all(|measurement| measurement.replay == Some(true)));
+    assert!(backlog.iter().all(|measurement| measurement.replay.is_none()));
+    // Backlog rows repeat in the replay under the same identity, so the backend can drop them
+    for row in backlog {
+        assert!(replayed.iter().any(|copy| copy.sequence_number == row.sequence_number && copy.timestamp == row.timestamp));
+    }
+    assert!(upload::send_replay(&client, &config, &stats, &mut replay, None, Utc::now()).await.is_none());
+    assert_eq!(replay.report()["replays"], 1);
+}
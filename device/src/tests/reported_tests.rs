@@ -0,0 +1,214 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use super::net_tests;
+use crate::config::Config;
+use crate::reported::{Priority, ReportedState, SectionProvider, SectionWriter, Sections, MAX_DOCUMENT_BYTES};
+use crate::shadow_report::ShadowReportGuard;
+use crate::stats::ApiStats;
+
+fn device_config(backend_url: &str) -> Config {
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), backend_url.to_string()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("DEVICE_ID".to_string(), "device-1".to_string()),
+        ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()),
+    ]);
+    Config::from_env_vars(&env).0
+}
+
+async fn reported_patches(server: &MockServer) -> Vec<Value> {
+    server.received_requests().await.unwrap().iter()
+        .filter(|request| request.method.as_str() == "PATCH")
+        .map(|request| serde_json::from_slice::<Value>(&net_tests::decoded_body(request)).unwrap()["state"].clone())
+        .collect()
+}
+
+#[test]
+fn a_section_has_exactly_one_owner() {
+    let state = ReportedState::new(None, MAX_DOCUMENT_BYTES);
+    let _ota = state.register("ota_status", Priority::High, None).unwrap();
+    assert!(state.register("ota_status", Priority::Low, None).is_err());
+    // Every section of the device registers once
+    assert!(Sections::register(&ReportedState::new(None, MAX_DOCUMENT_BYTES)).is_ok());
+    assert!(Sections::register(&state).is_err());
+}
+
+#[test]
+fn only_changed_sections_are_pending() {
+    let state = ReportedState::new(None, MAX_DOCUMENT_BYTES);
+    let network = state.register("network", Priority::Normal, None).unwrap();
+    let ota = state.register("ota_status", Priority::High, None).unwrap();
+    assert_eq!(state.pending(), None);
+
+    assert!(network.set(json!({"profile": "lte"})));
+    assert!(ota.set(json!("idle")));
+    let pending = state.pending().unwrap();
+    assert_eq!(pending.patch, json!({"network": {"profile": "lte"}, "ota_status": "idle"}));
+    state.mark_reported(&pending);
+    assert_eq!(state.pending(), None);
+
+    // Setting a section to what it already holds changes nothing
+    assert!(!network.set(json!({"profile": "lte"})));
+    assert_eq!(state.pending(), None);
+    assert!(ota.set(json!("downloading")));
+    assert_eq!(state.pending().unwrap().patch, json!({"ota_status": "downloading"}));
+    assert_eq!(state.document(), json!({"network": {"profile": "lte"}, "ota_status": "downloading"}));
+}
+
+#[test]
+fn a_change_while_a_report_is_in_flight_stays_pending() {
+    let state = ReportedState::new(None, MAX_DOCUMENT_BYTES);
+    let ota = state.register("ota_status", Priority::High, None).unwrap();
+    ota.set(json!("downloading"));
+    let in_flight = state.pending().unwrap();
+    ota.set(json!("installing"));
+    state.mark_reported(&in_flight);
+    assert_eq!(state.pending().unwrap().patch, json!({"ota_status": "installing"}));
+}
+
+struct Counter {
+    count: u64,
+    dirty: bool,
+}
+
+impl SectionProvider for Counter {
+    fn section(&self) -> Value {
+        json!({"count": self.count})
+    }
+
+    fn dirty(&self) -> bool {
+        self.dirty
+    }
+}
+
+#[test]
+fn providers_that_are_not_dirty_are_not_read() {
+    let state = ReportedState::new(None, MAX_DOCUMENT_BYTES);
+    let shed = state.register("shed", Priority::Normal, None).unwrap();
+    assert!(shed.publish(&Counter { count: 1, dirty: true }));
+    assert!(!shed.publish(&Counter { count: 2, dirty: false }));
+    assert_eq!(state.document(), json!({"shed": {"count": 1}}));
+    assert!(shed.publish(&|| json!({"count": 3})));
+    assert_eq!(state.document(), json!({"shed": {"count": 3}}));
+}
+
+#[test]
+fn oversized_sections_are_truncated_and_low_priorities_pruned_first() {
+    let state = ReportedState::new(None, 200);
+    let history = state.register("ota_history", Priority::Low, Some(1000)).unwrap();
+    let stats = state.register("api_stats", Priority::Low, Some(50)).unwrap();
+    let name = state.register("device_name", Priority::High, None).unwrap();
+
+    // Over its own limit: replaced whatever the rest of the document holds
+    stats.set(json!({"endpoints": "x".repeat(100)}));
+    assert_eq!(state.document()["api_stats"], json!({"truncated": true, "bytes": 116}));
+
+    name.set(json!("n".repeat(100)));
+    history.set(json!(["h".repeat(80)]));
+    // The document is over 200 bytes; the low priority history goes, the name stays
+    let document = state.document();
+    assert_eq!(document["ota_history"]["truncated"], true);
+    assert_eq!(document["device_name"], json!("n".repeat(100)));
+    assert_eq!(state.pending().unwrap().patch["ota_history"]["truncated"], true);
+
+    // Room again once the history shrinks
+    history.set(json!(["h"]));
+    assert_eq!(state.document()["ota_history"], json!(["h"]));
+}
+
+#[test]
+fn restored_sections_are_reported_again_and_orphans_dropped() {
+    let previous = json!({"last_shutdown": "2026-01-01T00:00:00Z", "connection": "offline", "retired": 1});
+    let state = ReportedState::new(Some(&previous), MAX_DOCUMENT_BYTES);
+    let connection = state.register("connection", Priority::High, None).unwrap();
+    state.register("last_shutdown", Priority::High, None).unwrap();
+    assert_eq!(state.pending().unwrap().patch, json!({"last_shutdown": "2026-01-01T00:00:00Z", "connection": "offline"}));
+    connection.set(json!("online"));
+    assert_eq!(state.document(), json!({"last_shutdown": "2026-01-01T00:00:00Z", "connection": "online"}));
+}
+
+#[tokio::test]
+async fn concurrent_owners_never_lose_each_others_updates() {
+    let state = ReportedState::new(None, MAX_DOCUMENT_BYTES);
+    let ota = state.register("ota_status", Priority::High, None).unwrap();
+    let network = state.register("network", Priority::Normal, None).unwrap();
+    let writer = |section: SectionWriter| tokio::spawn(async move {
+        for count in 1..=500 {
+            section.set(json!({"count": count}));
+            tokio::task::yield_now().await;
+        }
+    });
+    let tasks = [writer(ota), writer(network)];
+
+    // Meanwhile the sync task reports whatever changed, as a backend would merge it
+    let mut backend = serde_json::Map::new();
+    while tasks.iter().any(|task| !task.is_finished()) {
+        if let Some(pending) = state.pending() {
+            backend.extend(pending.patch.as_object().unwrap().clone());
+            state.mark_reported(&pending);
+        }
+        tokio::task::yield_now().await;
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+    if let Some(pending) = state.pending() {
+        backend.extend(pending.patch.as_object().unwrap().clone());
+        state.mark_reported(&pending);
+    }
+    let expected = json!({"ota_status": {"count": 500}, "network": {"count": 500}});
+    assert_eq!(Value::Object(backend), expected);
+    assert_eq!(state.document(), expected);
+}
+
+#[tokio::test]
+async fn unchanged_sections_send_no_patch() {
+    let server = MockServer::start().await;
+    Mock::given(method("PATCH")).and(path("/api/devices/device-1/shadow"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(2)
+        .mount(&server)
+        .await;
+    let config = device_config(&server.uri());
+    let client = reqwest::Client::new();
+    let stats = ApiStats::default();
+    let mut guard = ShadowReportGuard::default();
+    let state = ReportedState::new(None, MAX_DOCUMENT_BYTES);
+    let sections = Sections::register(&state).unwrap();
+
+    sections.sample_interval_secs.set(json!(10));
+    sections.connection.set(json!("online"));
+    assert!(state.report(&client, &config, &stats, &mut guard).await.unwrap());
+    // The next sync publishes the same values: nothing goes out
+    sections.sample_interval_secs.set(json!(10));
+    sections.connection.set(json!("online"));
+    assert!(!state.report(&client, &config, &stats, &mut guard).await.unwrap());
+    // One section changes: only it goes out
+    sections.sample_interval_secs.set(json!(20));
+    assert!(state.report(&client, &config, &stats, &mut guard).await.unwrap());
+    assert!(!state.report(&client, &config, &stats, &mut guard).await.unwrap());
+
+    assert_eq!(reported_patches(&server).await, [
+        json!({"sample_interval_secs": 10, "connection": "online"}),
+        json!({"sample_interval_secs": 20}),
+    ]);
+}
+
+#[tokio::test]
+async fn a_failed_report_stays_pending() {
+    let server = MockServer::start().await;
+    Mock::given(method("PATCH")).and(path("/api/devices/device-1/shadow"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&server)
+        .await;
+    let config = device_config(&server.uri());
+    let mut guard = ShadowReportGuard::default();
+    let state = ReportedState::new(None, MAX_DOCUMENT_BYTES);
+    let ota = state.register("ota_status", Priority::High, None).unwrap();
+    ota.set(json!("idle"));
+    assert!(state.report(&reqwest::Client::new(), &config, &ApiStats::default(), &mut guard).await.is_err());
+    assert_eq!(state.pending().unwrap().patch, json!({"ota_status": "idle"}));
+}
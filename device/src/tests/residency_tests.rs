@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use super::net_tests;
+use crate::config::Config;
+use crate::residency::{self, RegionDrainPolicy, ResidencyError};
+use crate::stats::ApiStats;
+use crate::{storage, upload};
+
+fn config_with(pairs: &[(&str, &str)]) -> Config {
+    let env: HashMap<String, String> = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+    Config::from_env_vars(&env).0
+}
+
+#[test]
+fn selects_endpoint_for_region() {
+    let config = config_with(&[
+        ("BACKEND_URL", "http://global"),
+        ("REGION", "eu-west-1"),
+        ("REGION_ENDPOINTS", r#"{"eu-west-1": "http://eu", "us-east-1": "http://us"}"#),
+    ]);
+    assert_eq!(residency::target_for(&config, Some("eu-west-1")).unwrap().endpoint, "http://eu");
+    assert_eq!(residency::target_for(&config, Some("us-east-1")).unwrap().endpoint, "http://us");
+    // Without strict residency unmapped regions fall back to the global backend
+    assert_eq!(residency::target_for(&config, Some("ap-south-1")).unwrap().endpoint, "http://global");
+    assert_eq!(residency::report(&config)["status"], "ok");
+
+    let unrestricted = config_with(&[("BACKEND_URL", "http://global"), ("REGION", "eu-west-1")]);
+    assert_eq!(residency::target_for(&unrestricted, Some("eu-west-1")).unwrap().endpoint, "http://global");
+    assert_eq!(residency::report(&unrestricted)["status"], "unrestricted");
+}
+
+#[test]
+fn strict_residency_rejects_unmapped_region() {
+    let config = config_with(&[
+        ("REGION", "ap-south-1"),
+        ("REGION_ENDPOINTS", r#"{"eu-west-1": "http://eu"}"#),
+        ("STRICT_RESIDENCY", "true"),
+    ]);
+    assert_eq!(
+        residency::target_for(&config, config.region.as_deref()),
+        Err(ResidencyError::Unmapped(Some("ap-south-1".to_string())))
+    );
+    assert_eq!(residency::report(&config)["status"], "misconfigured");
+}
+
+// Stores rows from eu, switches to us under `policy`, stores more, drains; returns row counts received per endpoint
+async fn region_change(policy: &str) -> (usize, usize) {
+    let eu = MockServer::start().await;
+    let us = MockServer::start().await;
+    for server in [&eu, &us] {
+        Mock::given(method("POST")).and(path("/api/devices/ingest"))
+            .respond_with(ResponseTemplate::new(204))
+            .mount(server)
+            .await;
+    }
+    let endpoints = format!(r#"{{"eu-west-1": "{}", "us-east-1": "{}"}}"#, eu.uri(), us.uri());
+    let mut config = config_with(&[
+        ("AUTH_TOKEN", "token"),
+        ("REGION", "eu-west-1"),
+        ("REGION_ENDPOINTS", &endpoints),
+        ("STRICT_RESIDENCY", "true"),
+        ("RESIDENCY_DRAIN", policy),
+    ]);
+    let db_path = std::env::temp_dir().join(format!("residency_{}.db", uuid::Uuid::new_v4()));
+    let mut conn = storage::init_at(&db_path).unwrap();
+    let store = |config: &Config, conn: &rusqlite::Connection, count: usize| {
+        for _ in 0..count {
+            let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+            measurement.region = config.region.clone();
+            storage::append_measurement(conn, &measurement, 0).unwrap();
+        }
+    };
+
+    store(&config, &conn, 3);
+    assert_eq!(residency::change_region(Some(&conn), &mut config, "us-east-1").unwrap().as_deref(), Some("eu-west-1"));
+    store(&config, &conn, 2);
+    upload::drain_once(&reqwest::Client::new(), &config, &ApiStats::default(), &mut conn, None).await.unwrap();
+    assert_eq!(storage::pending_count(&conn).unwrap(), 0);
+
+    let received = |payloads: Vec<wiremock::Request>, region: &str, endpoint: &str| {
+        payloads.iter()
+            .map(net_tests::ingest_payload)
+            .inspect(|payload| {
+                // Each upload is annotated with the region and endpoint used
+                assert_eq!(payload.region.as_deref(), Some(region));
+                assert_eq!(payload.data_endpoint.as_deref(), Some(endpoint));
+            })
+            .map(|payload| payload.measurements.len())
+            .sum::<usize>()
+    };
+    let eu_rows = received(eu.received_requests().await.unwrap(), "eu-west-1", &eu.uri());
+    let us_rows = received(us.received_requests().await.unwrap(), "us-east-1", &us.uri());
+    let _ = std::fs::remove_file(&db_path);
+    (eu_rows, us_rows)
+}
+
+#[tokio::test]
+async fn flush_to_old_keeps_pending_rows_in_old_region() {
+    assert_eq!(config_with(&[]).residency_drain, RegionDrainPolicy::FlushToOld);
+    assert_eq!(region_change("flush_to_old").await, (3, 2));
+}
+
+#[tokio::test]
+async fn hold_for_new_moves_pending_rows_to_new_region() {
+    assert_eq!(region_change("hold_for_new").await, (0, 5));
+}
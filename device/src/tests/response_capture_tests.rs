@@ -0,0 +1,93 @@
+use serde_json::json;
+use std::collections::HashMap;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::config::Config;
+use crate::net::{self, NetError};
+use crate::response_capture::{self, CaptureConfig};
+use crate::stats::ApiStats;
+
+fn device_config(backend_url: &str) -> Config {
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), backend_url.to_string()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()),
+        ("ERROR_BODY_LIMIT_BYTES".to_string(), "256".to_string()),
+    ]);
+    Config::from_env_vars(&env).0
+}
+
+// Fetches the shadow from a backend answering with `response`
+async fn refused(response: ResponseTemplate) -> (NetError, ApiStats) {
+    let server = MockServer::start().await;
+    let config = device_config(&server.uri());
+    Mock::given(method("GET")).and(path(format!("/api/devices/{}/shadow", config.device_id)))
+        .respond_with(response)
+        .mount(&server)
+        .await;
+    let stats = ApiStats::default();
+    let error = net::fetch_device_shadow(&reqwest::Client::new(), &config, &stats).await.unwrap_err();
+    (error.downcast::<NetError>().unwrap(), stats)
+}
+
+#[tokio::test]
+async fn a_json_error_body_is_kept_with_its_credentials_redacted() {
+    let body = json!({"detail": "database unavailable", "auth_token": "3f2a9c", "retry": true});
+    let (error, stats) = refused(ResponseTemplate::new(500).set_body_json(&body).insert_header("x-request-id", "req-42")).await;
+
+    let NetError::Status { endpoint, status, correlation_id, body } = &error else { panic!("{}", error) };
+    assert_eq!((endpoint.as_str(), status.as_u16(), correlation_id.as_deref()), ("shadow_fetch", 500, Some("req-42")));
+    assert!(body.contains("database unavailable") && body.contains("<redacted>"), "{}", body);
+    assert!(!body.contains("3f2a9c"));
+    assert!(error.to_string().contains("database unavailable"), "{}", error);
+
+    let captured = stats.failed_responses();
+    assert_eq!(captured.len(), 1);
+    assert_eq!((captured[0].status, captured[0].truncated), (500, false));
+    assert_eq!(captured[0].body, *body);
+}
+
+#[tokio::test]
+async fn html_pages_and_huge_bodies_are_truncated_to_the_limit() {
+    let page = format!("<html><body><h1>502 Bad Gateway</h1>{}</body></html>", "<p>upstream</p>".repeat(100_000));
+    let (error, stats) = refused(ResponseTemplate::new(502).set_body_raw(page, "text/html; charset=utf-8")).await;
+
+    let NetError::Status { body, .. } = &error else { panic!("{}", error) };
+    assert_eq!(body.len(), 256);
+    assert!(body.starts_with("<html><body><h1>502 Bad Gateway</h1>"));
+    assert!(stats.failed_responses()[0].truncated);
+}
+
+#[tokio::test]
+async fn binary_bodies_are_summarized_not_dumped() {
+    let (error, _) = refused(ResponseTemplate::new(503).set_body_raw(vec![0xff, 0x00, 0xfe, 0x01], "application/octet-stream")).await;
+    let NetError::Status { body, .. } = &error else { panic!("{}", error) };
+    assert_eq!(body, "<4 bytes of application/octet-stream>");
+
+    // Without a content type, bytes that are not UTF-8 are binary too
+    let config = CaptureConfig::default();
+    assert_eq!(response_capture::summarize(&config, None, &[0xc3, 0x28], false), "<2 bytes of binary data>");
+    assert_eq!(response_capture::summarize(&config, None, "token=abc&page=2".as_bytes(), false), "<redacted>&page=2");
+}
+
+#[tokio::test]
+async fn successful_requests_capture_nothing() {
+    let server = MockServer::start().await;
+    let config = device_config(&server.uri());
+    Mock::given(method("POST")).and(path("/api/devices/heartbeat"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "desired_version": null,
+            "desired_sample_interval_secs": 10,
+            "desired_upload_interval_secs": 60,
+            "desired_heartbeat_interval_secs": 30,
+        })))
+        .mount(&server)
+        .await;
+
+    let stats = ApiStats::default();
+    let heartbeat = net::heartbeat_body(&config, "1.0.0", 10, 60, 30);
+    let desired = net::send_heartbeat(&reqwest::Client::new(), &config, &stats, &heartbeat).await.unwrap();
+    assert_eq!(desired.desired_sample_interval_secs, 10);
+    assert!(stats.failed_responses().is_empty());
+}
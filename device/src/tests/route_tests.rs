@@ -0,0 +1,136 @@
+use std::path::PathBuf;
+
+use crate::route::{Route, RouteEnd, RouteFollower, Waypoint};
+use crate::simulate::{Position, SensorProfile, Simulator};
+
+fn fixture(name: &str) -> Route {
+    Route::load(&PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/tests/fixtures/route").join(name)).unwrap()
+}
+
+fn close(a: f64, b: f64, tolerance: f64) -> bool {
+    (a - b).abs() <= tolerance
+}
+
+#[test]
+fn gpx_tracks_and_geojson_line_strings_read_the_same() {
+    let gpx = fixture("block.gpx");
+    assert_eq!(gpx, fixture("block.geojson"));
+    assert_eq!(gpx.waypoints(), [
+        Waypoint { lat: 34.0, lon: -118.0 },
+        Waypoint { lat: 34.0, lon: -117.99 },
+        Waypoint { lat: 34.01, lon: -117.99 },
+    ]);
+    // 0.01 degrees east at this latitude, then 0.01 degrees north
+    assert!(close(gpx.length_m(), 921.9 + 1112.0, 1.0), "{}", gpx.length_m());
+
+    for (text, expected) in [
+        ("<gpx><trk><trkpt lat=\"34\"/></trk></gpx>", "line 1: <trkpt without a lon attribute"),
+        ("<gpx>\n<trkpt lat=\"north\" lon=\"1\"/></gpx>", "line 2: lat \"north\" is not a number"),
+        ("<gpx><trkpt lat=\"34\" lon=\"1\"/></gpx>", "at least two distinct waypoints"),
+        ("{\"type\": \"LineString\", \"coordinates\": [[1, 2], [1]]}", "coordinate 1 is not a [longitude, latitude] pair"),
+        ("{\"type\": \"LineString\", \"coordinates\": [[1, 95], [1, 2]]}", "not a valid latitude and longitude"),
+        ("{\"type\": \"Point\", \"coordinates\": [1, 2]}", "no LineString geometry found"),
+        ("lat,lon\n34,-118", "expected a GPX or GeoJSON document"),
+    ] {
+        let error = format!("{:#}", Route::parse(text).unwrap_err());
+        assert!(error.contains(expected), "{:?}: {}", text, error);
+    }
+}
+
+#[test]
+fn positions_are_interpolated_between_waypoints_with_a_heading() {
+    let route = fixture("block.gpx");
+    let start = route.at(0.0);
+    assert_eq!((start.lat, start.lon), (34.0, -118.0));
+    assert!(close(start.heading, 90.0, 0.01), "{:?}", start);
+
+    let halfway = route.at(route.length_m() / 2.0);
+    assert!(close(halfway.lon, -117.99, 1e-9) && halfway.lat > 34.0 && halfway.lat < 34.01, "{:?}", halfway);
+    assert!(close(halfway.heading, 0.0, 0.01), "{:?}", halfway);
+    // Beyond either end the route stops at it
+    assert_eq!(route.at(-5.0), start);
+    let end = route.at(route.length_m() + 5.0);
+    assert_eq!((end.lat, end.lon), (34.01, -117.99));
+}
+
+#[test]
+fn a_looping_route_drives_back_to_its_start() {
+    let route = fixture("block.gpx");
+    let length = route.length_m();
+    let mut follower = RouteFollower::new(route, RouteEnd::Loop);
+
+    // The closing leg runs south-west from the last waypoint back to the first
+    let closing = follower.advance(length + 100.0);
+    assert!(closing.heading > 180.0 && closing.heading < 270.0, "{:?}", closing);
+    assert!(closing.lat < 34.01 && closing.lon < -117.99, "{:?}", closing);
+
+    let lap = length + Waypoint { lat: 34.01, lon: -117.99 }.distance_to(Waypoint { lat: 34.0, lon: -118.0 });
+    let again = follower.advance(lap);
+    assert!(close(again.lat, closing.lat, 1e-9) && close(again.lon, closing.lon, 1e-9), "{:?} vs {:?}", again, closing);
+}
+
+#[test]
+fn a_reversing_route_turns_around_at_each_end() {
+    let route = fixture("block.geojson");
+    let length = route.length_m();
+    let mut follower = RouteFollower::new(route, RouteEnd::Reverse);
+
+    let outbound = follower.advance(100.0);
+    assert!(close(outbound.heading, 90.0, 0.01), "{:?}", outbound);
+    // 100 m past the far end is 100 m back along the northbound leg, facing south
+    let back = follower.advance(length);
+    assert!(close(back.heading, 180.0, 0.01), "{:?}", back);
+    assert!(close(back.lon, -117.99, 1e-9) && back.lat < 34.01, "{:?}", back);
+    // And past the start it heads out again
+    let out_again = follower.advance(length);
+    assert!(close(out_again.heading, 90.0, 0.01), "{:?}", out_again);
+    assert!(close(out_again.lat, outbound.lat, 1e-9) && close(out_again.lon, outbound.lon, 1e-9));
+}
+
+#[test]
+fn trackers_on_a_route_stay_on_it_and_others_keep_wandering() {
+    let behavior = Default::default();
+    let route = fixture("block.gpx");
+    let mut on_route = Simulator::new(SensorProfile::AssetTracker, 4, Position::default()).with_route(RouteFollower::new(route.clone(), RouteEnd::Reverse));
+    let mut wandering = Simulator::new(SensorProfile::AssetTracker, 4, Position::default());
+    for _ in 0..50 {
+        let measurement = on_route.generate_measurement("0.1.0".to_string(), &behavior);
+        let (lat, lon) = (measurement.latitude.unwrap() as f64, measurement.longitude.unwrap() as f64);
+        // On one of the two legs, within f32 precision
+        assert!(close(lat, 34.0, 1e-5) || close(lon, -117.99, 1e-5), "{},{}", lat, lon);
+        assert!(measurement.heading.is_some());
+
+        let measurement = wandering.generate_measurement("0.1.0".to_string(), &behavior);
+        assert!(measurement.latitude.is_some() && measurement.heading.is_none());
+    }
+}
+
+#[test]
+fn a_stopping_route_drives_from_start_to_destination_and_parks() {
+    let (start, destination) = (Waypoint { lat: 34.0, lon: -118.0 }, Waypoint { lat: 34.0, lon: -117.98 });
+    let route = Route::from_waypoints(vec![start, destination]).unwrap();
+    let mut simulator = Simulator::new(SensorProfile::AssetTracker, 4, Position::default()).with_route(RouteFollower::new(route, RouteEnd::Stop));
+    let behavior = Default::default();
+    let started = chrono::Utc::now();
+
+    let mut previous_lon = f64::MIN;
+    let mut parked = None;
+    for minute in 0..240 {
+        let measurement = simulator.generate_measurement_at(started + chrono::Duration::minutes(minute), "0.1.0".to_string(), &behavior);
+        let (lat, lon) = (measurement.latitude.unwrap() as f64, measurement.longitude.unwrap() as f64);
+        if minute == 0 {
+            assert!(close(lon, start.lon, 1e-5), "starts at the first waypoint, not part way: {}", lon);
+        }
+        // Heading east along the one leg, never back
+        assert!(close(lat, 34.0, 1e-5) && lon >= previous_lon, "{},{} after {}", lat, lon, previous_lon);
+        previous_lon = lon;
+        if close(lon, destination.lon, 1e-5) {
+            parked = Some(measurement.speed.unwrap());
+            break;
+        }
+    }
+    assert_eq!(parked, Some(0.0), "never reached the destination, last at {}", previous_lon);
+
+    let later = simulator.generate_measurement_at(started + chrono::Duration::hours(6), "0.1.0".to_string(), &behavior);
+    assert!(close(later.longitude.unwrap() as f64, destination.lon, 1e-5));
+}
@@ -0,0 +1,192 @@
+use futures::FutureExt;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::fleet_tests;
+use crate::config::Config;
+use crate::fleet::DevicePaths;
+use crate::runner::{FleetRunner, FleetSummary, Launch, MemberStatus, ScaleDownSelection};
+use crate::runner::MemberStatus::{Failed, Running, Suspended};
+use crate::shutdown::StopMode;
+
+fn write_identity(paths: &DevicePaths, region: &str) {
+    let env = HashMap::from([
+        ("DEVICE_ID".to_string(), format!("device-{}", paths.index.unwrap())),
+        ("REGION".to_string(), region.to_string()),
+    ]);
+    Config::from_env_vars(&env).0.save_to(&paths.config).unwrap();
+}
+
+// Devices that run until asked to stop, except in region "broken", where they fail at once
+fn idle_devices() -> Launch {
+    Arc::new(|paths: DevicePaths, mut stop_requests: mpsc::Receiver<StopMode>| {
+        async move {
+            let region = Config::load_from(&paths.config).ok().and_then(|config| config.region);
+            if region.as_deref() == Some("broken") {
+                return Err(anyhow::anyhow!("sensor bus unavailable").into());
+            }
+            stop_requests.recv().await;
+            Ok(())
+        }
+        .boxed()
+    })
+}
+
+fn fleet(regions: &[&str]) -> (PathBuf, FleetRunner) {
+    let dir = std::env::temp_dir().join(format!("runner_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let members: Vec<DevicePaths> = regions.iter().enumerate().map(|(index, region)| {
+        let paths = fleet_tests::member_under(&dir, index);
+        write_identity(&paths, region);
+        paths
+    }).collect();
+    let under = dir.clone();
+    let runner = FleetRunner::launch(members, Arc::new(move |index| fleet_tests::member_under(&under, index)), idle_devices());
+    (dir, runner)
+}
+
+fn statuses(summary: &FleetSummary) -> Vec<MemberStatus> {
+    summary.devices.iter().map(|device| device.status).collect()
+}
+
+async fn until(runner: &FleetRunner, done: impl Fn(&FleetSummary) -> bool) -> FleetSummary {
+    for _ in 0..200 {
+        let summary = runner.summary();
+        if done(&summary) {
+            return summary;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("fleet never settled: {:?}", runner.summary());
+}
+
+#[tokio::test]
+async fn scaling_down_suspends_the_newest_devices_and_scaling_up_resumes_them_first() {
+    let (dir, runner) = fleet(&["eu"; 5]);
+
+    let summary = runner.scale(3, &ScaleDownSelection::NewestFirst, StopMode::Graceful).await;
+    assert_eq!(statuses(&summary), [Running, Running, Running, Suspended, Suspended]);
+    assert_eq!((summary.running, summary.suspended, summary.failed), (3, 2, 0));
+
+    let summary = runner.scale(6, &ScaleDownSelection::NewestFirst, StopMode::Graceful).await;
+    assert_eq!(statuses(&summary), [Running; 6]);
+    // The suspended devices came back as themselves; only the sixth is new
+    let ids: Vec<Option<&str>> = summary.devices.iter().map(|device| device.device_id.as_deref()).collect();
+    assert_eq!(ids, [Some("device-0"), Some("device-1"), Some("device-2"), Some("device-3"), Some("device-4"), None]);
+
+    runner.scale(0, &ScaleDownSelection::NewestFirst, StopMode::Immediate).await;
+    runner.stopped().await;
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn scale_down_picks_devices_by_region_or_from_a_list() {
+    let (dir, runner) = fleet(&["eu", "us", "eu", "us", "eu"]);
+
+    let by_region = ScaleDownSelection::Region { region: "eu".to_string() };
+    assert_eq!(statuses(&runner.scale(3, &by_region, StopMode::Graceful).await), [Running, Running, Suspended, Running, Suspended]);
+    // The region runs out, so the newest device outside it makes up the target
+    assert_eq!(statuses(&runner.scale(1, &by_region, StopMode::Graceful).await), [Suspended, Running, Suspended, Suspended, Suspended]);
+
+    runner.scale(5, &by_region, StopMode::Graceful).await;
+    let explicit: ScaleDownSelection = serde_json::from_value(json!({"by": "explicit", "devices": ["device-1", "7", "device-1"]})).unwrap();
+    assert_eq!(statuses(&runner.scale(3, &explicit, StopMode::Graceful).await), [Running, Suspended, Running, Running, Suspended]);
+
+    runner.scale(0, &ScaleDownSelection::default(), StopMode::Graceful).await;
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn a_failed_device_is_reported_and_can_be_started_again() {
+    let (dir, runner) = fleet(&["eu", "broken"]);
+
+    let summary = until(&runner, |summary| summary.failed == 1).await;
+    assert_eq!(statuses(&summary), [Running, Failed]);
+    assert_eq!(summary.devices[1].error.as_deref(), Some("device failed: sensor bus unavailable"));
+
+    write_identity(&fleet_tests::member_under(&dir, 1), "eu");
+    let restarted = runner.start(runner.find("device-1").unwrap());
+    assert_eq!((restarted.status, restarted.error), (Running, None));
+    assert_eq!(runner.summary().running, 2);
+
+    runner.scale(0, &ScaleDownSelection::default(), StopMode::Graceful).await;
+    runner.stopped().await;
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn devices_suspended_through_the_runner_stay_suspended_when_it_restarts() {
+    let (dir, runner) = fleet(&["eu"; 3]);
+    runner.stop(1, StopMode::Graceful).await;
+
+    // A second runner over the same state directories, as after a restart
+    let members: Vec<DevicePaths> = (0..3).map(|index| fleet_tests::member_under(&dir, index)).collect();
+    let under = dir.clone();
+    let restarted = FleetRunner::launch(members, Arc::new(move |index| fleet_tests::member_under(&under, index)), idle_devices());
+    assert_eq!(statuses(&restarted.summary()), [Running, Suspended, Running]);
+
+    // Starting it clears the marker for the next restart
+    restarted.start(1);
+    assert!(!fleet_tests::member_under(&dir, 1).suspended.exists());
+
+    runner.scale(0, &ScaleDownSelection::default(), StopMode::Graceful).await;
+    restarted.scale(0, &ScaleDownSelection::default(), StopMode::Graceful).await;
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+async fn post(url: String, body: Option<Value>) -> (u16, Value) {
+    let request = reqwest::Client::new().post(url);
+    let request = match body {
+        Some(body) => request.json(&body),
+        None => request,
+    };
+    let response = request.send().await.unwrap();
+    let status = response.status().as_u16();
+    (status, response.json().await.unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn the_control_api_stops_starts_and_scales_devices() {
+    let (dir, runner) = fleet(&["eu", "us", "eu"]);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let _server = crate::runner::serve_on(listener, runner.clone(), None);
+
+    let (status, stopped) = post(format!("{}/fleet/devices/device-1/stop", url), Some(json!({"mode": "immediate"}))).await;
+    assert_eq!((status, &stopped["status"]), (200, &json!("suspended")));
+    let (status, started) = post(format!("{}/fleet/devices/1/start", url), None).await;
+    assert_eq!((status, &started["device_id"], &started["status"]), (200, &json!("device-1"), &json!("running")));
+
+    assert_eq!(post(format!("{}/fleet/devices/device-9/stop", url), None).await.0, 404);
+    assert_eq!(post(format!("{}/fleet/devices/device-1/stop", url), Some(json!({"mode": "eventually"}))).await.0, 400);
+    assert_eq!(runner.summary().running, 3);
+
+    let (status, scaled) = post(format!("{}/fleet/scale", url), Some(json!({"target": 1, "selection": {"by": "region", "region": "eu"}}))).await;
+    assert_eq!(status, 200);
+    assert_eq!((&scaled["running"], &scaled["suspended"]), (&json!(1), &json!(2)));
+    let summary: Value = reqwest::get(format!("{}/fleet", url)).await.unwrap().json().await.unwrap();
+    assert_eq!(summary, scaled);
+
+    runner.scale(0, &ScaleDownSelection::default(), StopMode::Graceful).await;
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn a_control_api_with_a_token_turns_away_requests_without_it() {
+    let (dir, runner) = fleet(&["eu"]);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/fleet", listener.local_addr().unwrap());
+    let _server = crate::runner::serve_on(listener, runner.clone(), Some("s3cret".to_string()));
+
+    let client = reqwest::Client::new();
+    assert_eq!(client.get(&url).send().await.unwrap().status(), 401);
+    assert_eq!(client.get(&url).bearer_auth("guess").send().await.unwrap().status(), 401);
+    assert_eq!(client.get(&url).bearer_auth("s3cret").send().await.unwrap().status(), 200);
+
+    runner.scale(0, &ScaleDownSelection::default(), StopMode::Graceful).await;
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,101 @@
+use chrono::Utc;
+use serde_json::json;
+
+use crate::schema;
+use crate::types::{FieldRange, Measurement, MeasurementSchema};
+
+fn measurement(temp: f32) -> Measurement {
+    Measurement {
+        timestamp: Utc::now(),
+        temp,
+        humidity: 50.0,
+        battery: 0.9,
+        sequence_number: 1,
+        latitude: Some(34.05),
+        longitude: Some(-118.24),
+        speed: None,
+        heading: None,
+        geohash: None,
+        map_tile: None,
+        firmware_version: Some("0.1.0".to_string()),
+        maintenance: None,
+        device_flags: None,
+        local_timestamp: None,
+        utc_offset_minutes: None,
+        aggregate_count: None,
+        region: None,
+        network: None,
+        replay: None,
+        keyframe: None,
+        extra: None,
+        content_hash: None,
+        omitted: Vec::new(),
+    }
+}
+
+fn payload(m: &Measurement) -> serde_json::Value {
+    json!({"device_id": "dev-1", "measurements": [m]})
+}
+
+#[test]
+fn refreshes_narrow_then_widen_accepted_fields() {
+    let m = measurement(21.0);
+
+    let narrow: MeasurementSchema = serde_json::from_value(json!({
+        "accepted_fields": ["timestamp", "temp", "sequence_number"]
+    })).unwrap();
+    let mut body = payload(&m);
+    schema::filter_payload(&narrow, &mut body);
+    let fields = body["measurements"][0].as_object().unwrap();
+    assert_eq!(fields.len(), 3);
+    assert!(fields.get("latitude").is_none());
+
+    let wide: MeasurementSchema = serde_json::from_value(json!({
+        "accepted_fields": ["timestamp", "temp", "sequence_number", "latitude", "longitude"]
+    })).unwrap();
+    let mut body = payload(&m);
+    schema::filter_payload(&wide, &mut body);
+    let fields = body["measurements"][0].as_object().unwrap();
+    assert_eq!(fields.len(), 5);
+    assert!(fields.get("latitude").is_some());
+    assert!(fields.get("humidity").is_none());
+}
+
+#[test]
+fn empty_accepted_list_sends_everything() {
+    let m = measurement(21.0);
+    let mut body = payload(&m);
+    let original = body.clone();
+    schema::filter_payload(&MeasurementSchema::default(), &mut body);
+    assert_eq!(body, original);
+}
+
+#[test]
+fn counts_out_of_range_and_missing_required_values() {
+    let mut ranges = std::collections::HashMap::new();
+    ranges.insert("temp".to_string(), FieldRange { min: Some(-40.0), max: Some(20.0) });
+    let measurement_schema = MeasurementSchema {
+        accepted_fields: vec![],
+        required_fields: vec!["speed".to_string(), "battery".to_string()],
+        ranges,
+    };
+
+    assert_eq!(schema::count_rejections(&measurement_schema, &measurement(10.0)), 1); // speed missing
+    assert_eq!(schema::count_rejections(&measurement_schema, &measurement(25.0)), 2); // plus temp too high
+}
+
+#[test]
+fn cache_persists_and_clears() {
+    let path = std::env::temp_dir().join(format!("schema_cache_{}.json", uuid::Uuid::new_v4()));
+    let measurement_schema = MeasurementSchema {
+        accepted_fields: vec!["temp".to_string()],
+        ..Default::default()
+    };
+
+    schema::save_cached(&path, Some(&measurement_schema)).unwrap();
+    assert_eq!(schema::load_cached(&path), Some(measurement_schema));
+
+    // A 404 from the backend clears the cache so the next boot sends everything
+    schema::save_cached(&path, None).unwrap();
+    assert_eq!(schema::load_cached(&path), None);
+}
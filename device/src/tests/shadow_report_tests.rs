@@ -0,0 +1,151 @@
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use super::net_tests;
+use crate::config::Config;
+use crate::shadow_report::{self, RejectionAction, ShadowRejected, ShadowRejectionPolicy, ShadowReportGuard};
+use crate::stats::ApiStats;
+use crate::storage;
+use crate::types::ShadowRejection;
+
+fn device_config(backend_url: &str) -> Config {
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), backend_url.to_string()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("DEVICE_ID".to_string(), "device-1".to_string()),
+        ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()),
+    ]);
+    Config::from_env_vars(&env).0
+}
+
+fn rejection(path: &str, code: &str) -> ShadowRejection {
+    ShadowRejection { path: path.to_string(), code: code.to_string(), message: format!("{} refused", path) }
+}
+
+async fn mount_rejection(server: &MockServer, body: Value) {
+    Mock::given(method("PATCH")).and(path("/api/devices/device-1/shadow"))
+        .respond_with(ResponseTemplate::new(422).set_body_json(body))
+        .up_to_n_times(1)
+        .mount(server)
+        .await;
+}
+
+async fn mount_acceptance(server: &MockServer) {
+    Mock::given(method("PATCH")).and(path("/api/devices/device-1/shadow")).respond_with(ResponseTemplate::new(200)).mount(server).await;
+}
+
+async fn reported_states(server: &MockServer) -> Vec<Value> {
+    server.received_requests().await.unwrap().iter()
+        .map(|request| serde_json::from_slice::<Value>(&net_tests::decoded_body(request)).unwrap()["state"].clone())
+        .collect()
+}
+
+#[tokio::test]
+async fn a_rejected_key_is_dropped_and_the_rest_reported_again() {
+    let server = MockServer::start().await;
+    mount_rejection(&server, json!([{"path": "features.beta", "code": "schema", "message": "expected a bool"}])).await;
+    mount_acceptance(&server).await;
+    let stats = ApiStats::default();
+    let mut guard = ShadowReportGuard::default();
+
+    let state = json!({"connection": "online", "features": {"beta": "yes", "gamma": true}});
+    shadow_report::report(&reqwest::Client::new(), &device_config(&server.uri()), &stats, &mut guard, &state).await.unwrap();
+
+    let reported = reported_states(&server).await;
+    assert_eq!(reported, [state, json!({"connection": "online", "features": {"gamma": true}})]);
+    assert_eq!(stats.since_boot()["shadow_report"].failures["rejected_schema"], 1);
+    // Once is not enough to stop reporting the key
+    assert_eq!(guard.report(), json!({}));
+}
+
+#[tokio::test]
+async fn several_rejections_are_fixed_in_one_retry_per_the_policy() {
+    let server = MockServer::start().await;
+    mount_rejection(&server, json!({"errors": [
+        {"path": "/api_stats", "code": "too_large"},
+        {"path": "/firmware_behavior", "code": "schema"},
+        {"path": "debug_session", "code": "forbidden_key"},
+    ]})).await;
+    mount_acceptance(&server).await;
+    let policy = ShadowRejectionPolicy { actions: BTreeMap::from([("schema".to_string(), RejectionAction::Sanitize)]), ..Default::default() };
+    let stats = ApiStats::default();
+    let db_path = std::env::temp_dir().join(format!("shadow_report_{}.db", uuid::Uuid::new_v4()));
+    let mut guard = ShadowReportGuard::load(policy, &storage::init_at(&db_path).unwrap()).unwrap();
+
+    let state = json!({"connection": "online", "api_stats": {"ingest": {}}, "firmware_behavior": "bad", "debug_session": {"active": true}});
+    shadow_report::report(&reqwest::Client::new(), &device_config(&server.uri()), &stats, &mut guard, &state).await.unwrap();
+
+    let reported = reported_states(&server).await;
+    assert_eq!(reported.len(), 2);
+    assert_eq!(reported[1], json!({"connection": "online", "firmware_behavior": null}));
+    let failures = &stats.since_boot()["shadow_report"].failures;
+    assert_eq!((failures["rejected_too_large"], failures["rejected_schema"], failures["rejected_forbidden_key"]), (1, 1, 1));
+    let _ = std::fs::remove_file(&db_path);
+}
+
+#[tokio::test]
+async fn unfixable_rejections_are_not_retried() {
+    for body in [
+        json!([{"path": "", "code": "too_large"}]),
+        json!([{"path": "no_such_key", "code": "schema"}]),
+        json!({"detail": "unprocessable"}),
+    ] {
+        let server = MockServer::start().await;
+        mount_rejection(&server, body.clone()).await;
+        mount_acceptance(&server).await;
+        let mut guard = ShadowReportGuard::default();
+
+        let error = shadow_report::report(&reqwest::Client::new(), &device_config(&server.uri()), &ApiStats::default(), &mut guard, &json!({"connection": "online"}))
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<ShadowRejected>().is_some(), "{}: {:#}", body, error);
+        assert_eq!(reported_states(&server).await.len(), 1, "{}", body);
+    }
+}
+
+#[tokio::test]
+async fn a_path_rejected_repeatedly_is_no_longer_reported() {
+    let server = MockServer::start().await;
+    mount_acceptance(&server).await;
+    let mut guard = ShadowReportGuard::default();
+    let (mut patch, now) = (json!({"connection": "online", "network": {"rssi": "weak"}}), Utc::now());
+    for _ in 0..3 {
+        assert!(guard.reject(&mut patch.clone(), &[rejection("network.rssi", "schema")], now));
+    }
+    assert!(guard.reject(&mut patch, &[rejection("/connection", "schema")], now));
+    assert_eq!(guard.report()["/network/rssi"]["rejections"], json!(3));
+
+    let state = json!({"connection": "online", "network": {"rssi": "weak", "type": "lte"}});
+    shadow_report::report(&reqwest::Client::new(), &device_config(&server.uri()), &ApiStats::default(), &mut guard, &state).await.unwrap();
+
+    let reported = reported_states(&server).await;
+    assert_eq!(reported.len(), 1);
+    assert_eq!(reported[0]["network"], json!({"type": "lte"}));
+    assert_eq!(reported[0]["connection"], json!("online"));
+    assert_eq!(reported[0]["shadow_report_errors"]["/network/rssi"]["code"], json!("schema"));
+    assert_eq!(reported[0]["shadow_report_errors"]["/network/rssi"]["message"], json!("network.rssi refused"));
+}
+
+#[test]
+fn blocked_paths_survive_a_restart() {
+    let db_path = std::env::temp_dir().join(format!("shadow_report_{}.db", uuid::Uuid::new_v4()));
+    let conn = storage::init_at(&db_path).unwrap();
+    let policy = ShadowRejectionPolicy { max_rejections: 1, ..Default::default() };
+    let mut guard = ShadowReportGuard::load(policy.clone(), &conn).unwrap();
+    assert!(!guard.checkpoint(&conn).unwrap());
+
+    let mut patch = json!({"battery": {"level": -1}});
+    assert!(guard.reject(&mut patch, &[rejection("battery.level", "range")], Utc::now()));
+    assert!(guard.checkpoint(&conn).unwrap());
+    assert!(!guard.checkpoint(&conn).unwrap());
+
+    let restarted = ShadowReportGuard::load(policy, &conn).unwrap();
+    assert_eq!(restarted.report(), guard.report());
+    let prepared = restarted.prepare(&json!({"battery": {"level": -1, "charging": false}}));
+    assert_eq!(prepared["battery"], json!({"charging": false}));
+    assert!(prepared["shadow_report_errors"]["/battery/level"].is_object());
+    let _ = std::fs::remove_file(&db_path);
+}
@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::config::Config;
+use crate::shed::{ShedConfig, ShedPolicy, Shedder};
+use crate::stats::ApiStats;
+use crate::{net, residency, storage};
+
+fn shed_config(policy: ShedPolicy) -> ShedConfig {
+    ShedConfig { policy, high_water: 20, low_water: 10, max_decimation: 60 }
+}
+
+fn sample() -> crate::types::Measurement {
+    super::generate_measurement("0.1.0".to_string(), &Default::default())
+}
+
+// Takes the next upload batch off the store, as the upload path does
+fn mark_batch(conn: &mut rusqlite::Connection) -> (Vec<i64>, Vec<crate::types::Measurement>) {
+    storage::mark_measurements_inflight(conn, 100).unwrap().into_iter().map(|row| (row.id, row.measurement)).unzip()
+}
+
+#[test]
+fn aggregate_policy_averages_each_group() {
+    let mut shedder = Shedder::new(shed_config(ShedPolicy::Aggregate));
+    shedder.update(45); // Factor 3
+    assert_eq!(shedder.decimation_factor(), 3);
+
+    let temps = [10.0, 20.0, 30.0];
+    let mut stored = Vec::new();
+    for temp in temps {
+        let mut measurement = sample();
+        measurement.temp = temp;
+        stored.extend(shedder.admit(measurement));
+    }
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].temp, 20.0);
+    assert_eq!(stored[0].aggregate_count, Some(3));
+    assert_eq!(shedder.shed_samples(), 2);
+}
+
+#[test]
+fn evict_policy_never_sheds() {
+    let mut shedder = Shedder::new(shed_config(ShedPolicy::Evict));
+    assert!(shedder.update(10_000).is_none());
+    assert!(shedder.admit(sample()).is_some());
+    assert_eq!(shedder.decimation_factor(), 1);
+}
+
+#[tokio::test]
+async fn blocked_backend_drives_decimation_and_recovery() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(503))
+        .mount(&server)
+        .await;
+
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        // One attempt per upload tick keeps the backlog trajectory independent of retries
+        ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()),
+    ]);
+    let (config, _) = Config::from_env_vars(&env);
+    let target = residency::target_for(&config, None).unwrap();
+    let stats = ApiStats::default();
+    let db_path = std::env::temp_dir().join(format!("shed_{}.db", uuid::Uuid::new_v4()));
+    let mut conn = storage::init_at(&db_path).unwrap();
+    let mut shedder = Shedder::new(shed_config(ShedPolicy::Decimate));
+
+    // Sample once per tick, upload every tenth tick; failed uploads are released like the main loop does
+    let mut trajectory = Vec::new();
+    let mut changes = Vec::new();
+    for tick in 1..=200 {
+        changes.extend(shedder.update(storage::pending_count(&conn).unwrap()));
+        if let Some(measurement) = shedder.admit(sample()) {
+            storage::append_measurement(&conn, &measurement, 0).unwrap();
+        }
+        trajectory.push(shedder.decimation_factor());
+        if tick % 10 == 0 {
+            let (ids, batch) = mark_batch(&mut conn);
+            assert!(net::send_ingest(&reqwest::Client::new(), &config, &stats, &target, &batch, None).await.is_err());
+            storage::release_inflight(&mut conn, &ids).unwrap();
+        }
+    }
+
+    // Full rate until the high-water mark, then a factor that only grows while blocked
+    let first_shed = trajectory.iter().position(|&factor| factor > 1).unwrap();
+    assert_eq!(first_shed, 21);
+    assert!(trajectory.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert_eq!(*trajectory.last().unwrap(), 5);
+    let backlog = storage::pending_count(&conn).unwrap();
+    assert_eq!(shedder.shed_samples(), 200 - backlog);
+    assert_eq!(changes.len(), 1);
+    assert!(changes[0].active);
+
+    // Unblock the backend and drain the backlog
+    server.reset().await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&server)
+        .await;
+    while storage::pending_count(&conn).unwrap() > 0 {
+        let (ids, batch) = mark_batch(&mut conn);
+        net::send_ingest(&reqwest::Client::new(), &config, &stats, &target, &batch, None).await.unwrap();
+        storage::confirm_uploaded(&mut conn, &ids).unwrap();
+    }
+
+    let change = shedder.update(storage::pending_count(&conn).unwrap()).unwrap();
+    assert!(!change.active);
+    assert_eq!(shedder.decimation_factor(), 1);
+    assert!(shedder.admit(sample()).is_some());
+
+    let _ = std::fs::remove_file(&db_path);
+}
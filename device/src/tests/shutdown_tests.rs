@@ -0,0 +1,83 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use super::net_tests;
+use crate::config::Config;
+use crate::shadow_report::ShadowReportGuard;
+use crate::shutdown::{self, FinalReport, FlushOutcome};
+use crate::stats::ApiStats;
+use crate::storage;
+
+fn device_config(backend_url: &str, timeout_secs: u64) -> Config {
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), backend_url.to_string()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("DEVICE_ID".to_string(), "device-1".to_string()),
+        ("UPLOAD_BATCH_SIZE".to_string(), "10".to_string()),
+        ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()),
+        ("SHUTDOWN_TIMEOUT_SECS".to_string(), timeout_secs.to_string()),
+    ]);
+    Config::from_env_vars(&env).0
+}
+
+fn stored_db(rows: usize) -> (PathBuf, rusqlite::Connection) {
+    let path = std::env::temp_dir().join(format!("shutdown_{}.db", uuid::Uuid::new_v4()));
+    let conn = storage::init_at(&path).unwrap();
+    for _ in 0..rows {
+        storage::append_measurement(&conn, &super::generate_measurement("0.1.0".to_string(), &Default::default()), 0).unwrap();
+    }
+    (path, conn)
+}
+
+#[tokio::test]
+async fn flush_uploads_the_whole_backlog_then_reports_offline() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest")).respond_with(ResponseTemplate::new(204)).mount(&server).await;
+    Mock::given(method("PATCH")).and(path("/api/devices/device-1/shadow")).respond_with(ResponseTemplate::new(200)).mount(&server).await;
+    let (db_path, mut conn) = stored_db(25);
+
+    let mut reported = json!({"connection": "online", "sample_interval_secs": 10});
+    let config = device_config(&server.uri(), 5);
+    let outcome = shutdown::flush(&reqwest::Client::new(), &config, &ApiStats::default(), &mut conn, None, FinalReport { state: &mut reported, connection: "offline", guard: &mut ShadowReportGuard::default() }).await;
+
+    assert_eq!(outcome, FlushOutcome { uploaded: 25, timed_out: false, reported_offline: true });
+    assert_eq!(storage::pending_count(&conn).unwrap(), 0);
+    let requests = server.received_requests().await.unwrap();
+    let uploaded: usize = requests.iter()
+        .filter(|request| request.url.path() == "/api/devices/ingest")
+        .map(|request| net_tests::ingest_payload(request).measurements.len())
+        .sum();
+    assert_eq!(uploaded, 25);
+    // The offline report is the last request, and carries the rest of the reported state
+    let report: Value = serde_json::from_slice(&net_tests::decoded_body(requests.last().unwrap())).unwrap();
+    assert_eq!(report["state"]["connection"], json!("offline"));
+    assert_eq!(report["state"]["sample_interval_secs"], json!(10));
+    assert_eq!(reported["connection"], json!("offline"));
+    let _ = std::fs::remove_file(&db_path);
+}
+
+#[tokio::test]
+async fn a_dead_backend_cannot_hang_the_stop() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(204).set_delay(Duration::from_secs(30)))
+        .mount(&server)
+        .await;
+    let (db_path, mut conn) = stored_db(5);
+
+    let started = Instant::now();
+    let config = device_config(&server.uri(), 1);
+    let outcome = shutdown::flush(&reqwest::Client::new(), &config, &ApiStats::default(), &mut conn, None, FinalReport { state: &mut json!({}), connection: "offline", guard: &mut ShadowReportGuard::default() }).await;
+
+    assert!(started.elapsed() < Duration::from_secs(3), "flush took {:?}", started.elapsed());
+    assert_eq!(outcome, FlushOutcome { uploaded: 0, timed_out: true, reported_offline: false });
+    // The interrupted batch was left in flight and is retried after the restart
+    drop(conn);
+    let mut conn = storage::init_at(&db_path).unwrap();
+    assert_eq!(storage::mark_measurements_inflight(&mut conn, 10).unwrap().len(), 5);
+    let _ = std::fs::remove_file(&db_path);
+}
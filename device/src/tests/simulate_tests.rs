@@ -0,0 +1,136 @@
+use chrono::{Duration, Utc};
+use serde_json::json;
+use std::collections::HashMap;
+
+use crate::config::Config;
+use crate::simulate::{self, Band, Position, SensorProfile, Simulator};
+
+fn within(band: Band, value: f32) -> bool {
+    (band.center - band.spread..=band.center + band.spread).contains(&value)
+}
+
+#[test]
+fn only_asset_trackers_report_a_position() {
+    let behavior = Default::default();
+    let mut environmental = Simulator::new(SensorProfile::EnvironmentalNode, 1, Position::default());
+    let mut tracker = Simulator::new(SensorProfile::AssetTracker, 2, Position::default());
+    for _ in 0..200 {
+        let measurement = environmental.generate_measurement("0.1.0".to_string(), &behavior);
+        assert_eq!((measurement.latitude, measurement.longitude, measurement.speed), (None, None, None));
+        let measurement = tracker.generate_measurement("0.1.0".to_string(), &behavior);
+        assert!(measurement.latitude.is_some() && measurement.longitude.is_some() && measurement.speed.is_some());
+    }
+}
+
+#[test]
+fn each_profile_stays_within_its_own_bounds() {
+    let behavior = Default::default();
+    for profile in SensorProfile::ALL {
+        let bounds = profile.bounds();
+        let mut simulator = Simulator::new(profile, 3, Position::default());
+        for _ in 0..500 {
+            let measurement = simulator.generate_measurement("0.1.0".to_string(), &behavior);
+            assert!(within(bounds.temp, measurement.temp), "{:?} temp {}", profile, measurement.temp);
+            assert!(within(bounds.humidity, measurement.humidity), "{:?} humidity {}", profile, measurement.humidity);
+            assert!((bounds.battery.0..=bounds.battery.1).contains(&measurement.battery), "{:?} battery {}", profile, measurement.battery);
+        }
+    }
+    // Machinery runs hotter than tracked cargo ever should
+    let (meter, tracker) = (SensorProfile::IndustrialMeter.bounds().temp, SensorProfile::AssetTracker.bounds().temp);
+    assert!(meter.center - meter.spread > tracker.center + tracker.spread);
+}
+
+#[test]
+fn the_profile_is_selected_by_sensor_profile_and_defaults_to_a_tracker() {
+    let env = |profile: &str| HashMap::from([("SENSOR_PROFILE".to_string(), profile.to_string())]);
+    assert_eq!(Config::from_env_vars(&env("industrial_meter")).0.sensor_profile, SensorProfile::IndustrialMeter);
+    assert_eq!(Config::from_env_vars(&HashMap::new()).0.sensor_profile, SensorProfile::AssetTracker);
+
+    let (config, report) = Config::from_env_vars(&env("weather_balloon"));
+    assert_eq!(config.sensor_profile, SensorProfile::AssetTracker);
+    assert!(report.warnings.iter().any(|warning| warning.contains("weather_balloon")), "{:?}", report.warnings);
+}
+
+#[test]
+fn clock_skew_shifts_every_timestamp_by_one_offset_per_device() {
+    let behavior = Default::default();
+    let mut simulator = Simulator::default();
+    assert!(simulator.set_clock_skew(Some(&json!(3600))));
+    let before = Utc::now();
+    let measurement = simulator.generate_measurement("0.1.0".to_string(), &behavior);
+    let ahead = measurement.timestamp - before;
+    assert!(ahead >= Duration::seconds(3600) && ahead < Duration::seconds(3601), "{}", ahead);
+
+    // A range is picked once and kept while the flag stays the same
+    let range = json!({"min": -600, "max": 600});
+    simulator.set_clock_skew(Some(&range));
+    let skew = simulator.clock_skew();
+    assert!((Duration::seconds(-600)..=Duration::seconds(600)).contains(&skew));
+    assert!(!simulator.set_clock_skew(Some(&range)));
+    assert_eq!(simulator.clock_skew(), skew);
+
+    for malformed in [json!("fast"), json!({"min": 10, "max": -10}), json!({"max": 5})] {
+        assert!(simulate::clock_skew_range(&malformed).is_err(), "{}", malformed);
+    }
+    simulator.set_clock_skew(None);
+    let drift = Utc::now() - simulator.generate_measurement("0.1.0".to_string(), &behavior).timestamp;
+    assert!(drift.abs() < Duration::seconds(1));
+}
+
+#[test]
+fn clock_drift_grows_the_skew_from_when_it_was_set() {
+    let behavior = Default::default();
+    let start = Utc::now();
+    let mut simulator = Simulator::default();
+    simulator.set_clock_skew(Some(&json!(-60)));
+    assert!(simulator.set_clock_drift(Some(&json!(36)), start));
+    assert!(!simulator.set_clock_drift(Some(&json!(36)), start + Duration::hours(1)));
+    assert_eq!(simulator.clock_skew_at(start), Duration::seconds(-60));
+    assert_eq!(simulator.clock_skew_at(start + Duration::minutes(30)), Duration::seconds(-42));
+    let measurement = simulator.generate_measurement_at(start + Duration::hours(2), "0.1.0".to_string(), &behavior);
+    assert_eq!(measurement.timestamp, start + Duration::hours(2) + Duration::seconds(12));
+    assert_eq!(simulator.device_time(start + Duration::hours(2)), measurement.timestamp);
+    assert_eq!(simulator.clock_report(start + Duration::hours(2))["skew_secs"], 12.0);
+    assert_eq!(simulator.clock_report(start)["drift_secs_per_hour"], 36.0);
+
+    // The drift survives a snapshot, without drifting while paused
+    let state = simulator.snapshot();
+    let mut restored = Simulator::default();
+    restored.restore(&state, Duration::hours(5));
+    assert_eq!(restored.clock_skew_at(start + Duration::hours(7)), Duration::seconds(12));
+
+    // A new rate starts over; a malformed or cleared flag stops drifting
+    assert!(simulator.set_clock_drift(Some(&json!(-3.6)), start + Duration::hours(2)));
+    assert_eq!(simulator.clock_skew_at(start + Duration::hours(3)), Duration::seconds(-60) - Duration::milliseconds(3600));
+    for malformed in [json!("fast"), json!(7200)] {
+        assert!(simulate::clock_drift_rate(&malformed).is_err(), "{}", malformed);
+        simulator.set_clock_drift(Some(&malformed), start);
+        assert_eq!(simulator.clock_skew_at(start + Duration::hours(9)), Duration::seconds(-60));
+    }
+    simulator.set_clock_drift(None, start);
+    assert_eq!(simulator.clock_skew_at(start + Duration::hours(9)), Duration::seconds(-60));
+}
+
+#[test]
+fn the_same_seed_produces_the_same_measurement_stream() {
+    let behavior = Default::default();
+    let start = Position { lat: 51.5072, lon: -0.1276 };
+    let stream = |seed| {
+        let mut simulator = Simulator::new(SensorProfile::AssetTracker, seed, start);
+        simulator.set_clock_skew(Some(&json!({"min": -600, "max": 600})));
+        (0..100)
+            .map(|_| {
+                let measurement = simulator.generate_measurement("0.1.0".to_string(), &behavior);
+                (measurement.sequence_number, measurement.temp, measurement.humidity, measurement.battery, measurement.latitude, measurement.longitude, measurement.speed)
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(stream(42), stream(42));
+    assert_ne!(stream(42), stream(43));
+    assert!((stream(42)[0].4.unwrap() - start.lat).abs() < 0.001);
+
+    // Fleet members get distinct seeds that repeat across runs
+    assert_eq!(simulate::device_seed(Some(42), Some(3)), simulate::device_seed(Some(42), Some(3)));
+    assert_ne!(simulate::device_seed(Some(42), Some(3)), simulate::device_seed(Some(42), Some(4)));
+    assert_eq!(simulate::device_seed(Some(42), None), 42);
+}
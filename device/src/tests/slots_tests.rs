@@ -0,0 +1,141 @@
+use chrono::Utc;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::firmware::FirmwareBehavior;
+use crate::ota::{self, OtaState, OtaStatus};
+use crate::slots::{self, SlotFault};
+
+fn firmware_dir() -> PathBuf {
+    std::env::temp_dir().join(format!("slots_{}", uuid::Uuid::new_v4()))
+}
+
+fn state_at(version: &str, slot: &str) -> OtaState {
+    OtaState {
+        current_version: version.to_string(),
+        active_slot: slot.to_string(),
+        previous_version: None,
+        previous_slot: None,
+        pending_confirmation: false,
+        boot_count: 0,
+        trial_heartbeats: 0,
+        trial_ingested: false,
+        trial_started_at: None,
+        installed_behaviors: BTreeMap::new(),
+        last_rollback: None,
+        last_update: None,
+        ota_status: Default::default(),
+        manifests: BTreeMap::new(),
+        history: Vec::new(),
+        staged: None,
+    }
+}
+
+// 1.0.0 stamps timestamps an hour ahead; 1.1.0 fixed that
+fn skewed() -> FirmwareBehavior {
+    FirmwareBehavior { clock_skew_secs: 3600, ..Default::default() }
+}
+
+// Slot A holds 1.0.0, slot B holds 1.1.0 and is active
+fn two_slots(dir: &std::path::Path) -> OtaState {
+    let mut state = state_at("1.1.0", "B");
+    state.previous_version = Some("1.0.0".to_string());
+    state.previous_slot = Some("A".to_string());
+    state.manifests.insert("A".to_string(), slots::install(dir, "A", "1.0.0", b"firmware 1.0.0", Some(skewed())).unwrap());
+    state.manifests.insert("B".to_string(), slots::install(dir, "B", "1.1.0", b"firmware 1.1.0", Some(FirmwareBehavior::default())).unwrap());
+    state
+}
+
+fn corrupt(dir: &std::path::Path, slot: &str, version: &str) {
+    std::fs::write(slots::dir(dir, slot).join(format!("firmware_{}.bin", version)), b"bit rot").unwrap();
+}
+
+#[test]
+fn flat_firmware_moves_into_slots_with_manifests() {
+    let dir = firmware_dir();
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("firmware_1.0.0.bin"), b"firmware 1.0.0").unwrap();
+    std::fs::write(dir.join("firmware_1.1.0.bin"), b"firmware 1.1.0").unwrap();
+    let mut state = state_at("1.1.0", "B");
+    state.previous_version = Some("1.0.0".to_string());
+    state.previous_slot = Some("A".to_string());
+    state.installed_behaviors.insert("1.0.0".to_string(), skewed());
+
+    assert!(ota::migrate_slots(&mut state, &dir).unwrap());
+    assert!(!dir.join("firmware_1.0.0.bin").exists() && !dir.join("firmware_1.1.0.bin").exists());
+    let a = slots::verify("A", state.manifests.get("A").map(PathBuf::as_path)).unwrap();
+    assert_eq!((a.version.as_str(), a.behavior), ("1.0.0", Some(skewed())));
+    let b = slots::verify("B", state.manifests.get("B").map(PathBuf::as_path)).unwrap();
+    assert_eq!((b.version.as_str(), b.behavior), ("1.1.0", None));
+    assert_eq!(std::fs::read(slots::dir(&dir, "B").join(&b.artifact)).unwrap(), b"firmware 1.1.0");
+    // Already migrated
+    assert!(!ota::migrate_slots(&mut state, &dir).unwrap());
+
+    // A device that never updated boots its factory image
+    let mut fresh = state_at("0.1.0", "A");
+    let fresh_dir = firmware_dir();
+    assert!(ota::migrate_slots(&mut fresh, &fresh_dir).unwrap());
+    assert_eq!(fresh.manifests.keys().collect::<Vec<_>>(), ["A"]);
+    assert_eq!(ota::boot_slot(&mut fresh, Utc::now()).manifest.unwrap().version, "0.1.0");
+    let _ = std::fs::remove_dir_all(&dir);
+    let _ = std::fs::remove_dir_all(&fresh_dir);
+}
+
+#[test]
+fn a_corrupt_active_slot_boots_the_other_one() {
+    let dir = firmware_dir();
+    let mut state = two_slots(&dir);
+    assert_eq!(ota::boot_slot(&mut state, Utc::now()).fault, None);
+
+    corrupt(&dir, "B", "1.1.0");
+    let boot = ota::boot_slot(&mut state, Utc::now());
+    assert!(matches!(&boot.fault, Some(SlotFault::Corrupt { slot, .. }) if slot == "B"), "{:?}", boot.fault);
+    let manifest = boot.manifest.unwrap();
+    assert_eq!((manifest.slot.as_str(), manifest.version.as_str()), ("A", "1.0.0"));
+    // The other slot's behavior overlay applies, not the one of the version that failed
+    let behavior = manifest.behavior.unwrap();
+    assert_eq!(behavior, skewed());
+    let measurement = super::generate_measurement(state.current_version.clone(), &behavior);
+    assert!(measurement.timestamp > Utc::now() + chrono::Duration::minutes(59));
+
+    assert_eq!((state.current_version.as_str(), state.active_slot.as_str()), ("1.0.0", "A"));
+    assert_eq!((state.previous_version.as_ref(), state.previous_slot.as_ref()), (None, None));
+    let rollback = state.last_rollback.as_ref().unwrap();
+    assert_eq!((rollback.failed_version.as_str(), rollback.version.as_str(), rollback.slot.as_str()), ("1.1.0", "1.0.0", "A"));
+    assert_eq!(rollback.reason, "slot_verification");
+    assert!(matches!(&state.ota_status, OtaStatus::Failed { version, error } if version == "1.1.0" && error.contains("slot B image is corrupt")));
+    // Booting again stays on the good slot
+    assert_eq!(ota::boot_slot(&mut state, Utc::now()).fault, None);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn rollback_boots_the_previous_slot_and_its_behavior() {
+    let dir = firmware_dir();
+    let mut state = two_slots(&dir);
+    state.pending_confirmation = true;
+    assert!(state.rollback());
+
+    let boot = ota::boot_slot(&mut state, Utc::now());
+    assert_eq!(boot.fault, None);
+    let manifest = boot.manifest.unwrap();
+    assert_eq!((manifest.slot.as_str(), manifest.version.as_str(), manifest.behavior), ("A", "1.0.0", Some(skewed())));
+    assert_eq!(state.current_version, manifest.version);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn with_no_bootable_slot_the_device_runs_on() {
+    let dir = firmware_dir();
+    let mut state = two_slots(&dir);
+    corrupt(&dir, "B", "1.1.0");
+    std::fs::remove_file(state.manifests["A"].clone()).unwrap();
+
+    let boot = ota::boot_slot(&mut state, Utc::now());
+    assert_eq!(boot.manifest, None);
+    assert!(matches!(boot.fault, Some(SlotFault::Corrupt { .. })));
+    assert_eq!((state.current_version.as_str(), state.active_slot.as_str()), ("1.1.0", "B"));
+    assert_eq!(slots::verify("A", state.manifests.get("A").map(PathBuf::as_path)), Err(SlotFault::Empty { slot: "A".to_string() }));
+    assert_eq!(slots::verify("B", None), Err(SlotFault::Empty { slot: "B".to_string() }));
+    let _ = std::fs::remove_dir_all(&dir);
+}
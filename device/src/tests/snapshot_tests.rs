@@ -0,0 +1,235 @@
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use futures::FutureExt;
+use tokio::sync::mpsc;
+
+use super::fleet_tests;
+use crate::battery::BatteryDrain;
+use crate::can::{CanBus, SignalSet};
+use crate::config::Config;
+use crate::fleet::DevicePaths;
+use crate::offline::OfflineWindow;
+use crate::route::{Route, RouteEnd, RouteFollower};
+use crate::runner::{FleetRunner, Launch};
+use crate::shutdown::StopMode;
+use crate::simulate::{Position, SensorProfile, Simulator};
+use crate::runner::ScaleDownSelection;
+use crate::snapshot::{self, Controller, DeviceRuntime, Manifest, ManifestEntry};
+use crate::storage;
+use crate::types::Measurement;
+
+fn fixture(path: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/tests/fixtures").join(path)
+}
+
+fn paths_under(dir: &Path) -> DevicePaths {
+    std::fs::create_dir_all(dir).unwrap();
+    DevicePaths {
+        index: Some(0),
+        config: dir.join("device_config_0.json"),
+        database: dir.join("device_storage_0.db"),
+        ota_state: dir.join("ota_state_0.json"),
+        firmware_dir: dir.join("firmware_0"),
+        audit_log: dir.join("audit_0.log"),
+        staged_txn: dir.join("staged_txn_0.json"),
+        schema_cache: dir.join("schema_cache_0.json"),
+        exports_dir: dir.join("exports_0"),
+        crash_dir: dir.join("crash_0"),
+        restored_runtime: dir.join("restored_runtime_0.json"),
+        suspended: dir.join("suspended_0"),
+    }
+}
+
+// A tracker exercising every stateful part of the simulator
+fn tracker(seed: u64) -> Simulator {
+    let route = Route::load(&fixture("route/block.gpx")).unwrap();
+    let signals = SignalSet::load(&fixture("can/vehicle.dbc")).unwrap();
+    let mut simulator = Simulator::new(SensorProfile::AssetTracker, seed, Position::default())
+        .with_route(RouteFollower::new(route, RouteEnd::Loop))
+        .with_can_bus(CanBus::new(signals, true));
+    simulator.set_clock_skew(Some(&json!({"min": -600, "max": 600})));
+    simulator
+}
+
+// Samples every 5 simulated seconds, storing each measurement as the device would
+fn run(simulator: &mut Simulator, battery: &mut BatteryDrain, conn: &rusqlite::Connection, from: DateTime<Utc>, minutes: i64) -> Vec<Measurement> {
+    (0..minutes * 12).map(|step| {
+        let mut measurement = simulator.generate_measurement_at(from + Duration::seconds(step * 5), "1.0.0".to_string(), &Default::default());
+        battery.apply(&mut measurement);
+        storage::append_measurement(conn, &measurement, 0).unwrap();
+        measurement
+    }).collect()
+}
+
+fn stored(conn: &rusqlite::Connection) -> Vec<Measurement> {
+    let (from, to) = (Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap(), Utc.with_ymd_and_hms(2100, 1, 1, 0, 0, 0).unwrap());
+    storage::measurements_between(conn, from, to).unwrap().into_iter().map(|row| row.measurement).collect()
+}
+
+#[test]
+fn ten_minutes_from_the_same_snapshot_are_identical() {
+    let root = std::env::temp_dir().join(format!("snapshot_{}", uuid::Uuid::new_v4()));
+    let live_paths = paths_under(&root.join("live"));
+    std::fs::write(&live_paths.config, r#"{"device_id": "device-0"}"#).unwrap();
+    std::fs::write(&live_paths.staged_txn, "{}").unwrap();
+    let conn = storage::init_at(&live_paths.database).unwrap();
+    let mut battery = BatteryDrain::default();
+    battery.set_flag(Some(&json!({"rate": 0.0005})));
+    let mut simulator = tracker(42);
+
+    let start = Utc.with_ymd_and_hms(2026, 3, 1, 8, 0, 0).unwrap();
+    run(&mut simulator, &mut battery, &conn, start, 2);
+    battery.checkpoint(&conn).unwrap();
+    let taken_at = start + Duration::minutes(2);
+    let runtime = DeviceRuntime {
+        taken_at,
+        simulator: simulator.snapshot(),
+        chaos_seed: 7,
+        sample_interval_secs: 5,
+        upload_interval_secs: 60,
+        heartbeat_interval_secs: 30,
+    };
+    snapshot::write_device(&root.join("snapshot"), &live_paths, &conn, &runtime).unwrap();
+    // Taking the snapshot does not disturb the device it was taken from
+    let live = run(&mut simulator, &mut battery, &conn, taken_at, 10);
+
+    let mut replays = Vec::new();
+    for attempt in 1..=2 {
+        let paths = paths_under(&root.join(format!("replay_{}", attempt)));
+        std::fs::write(&paths.staged_txn, "{\"left\": \"over\"}").unwrap();
+        let restored = snapshot::restore_device(&root.join("snapshot"), &paths).unwrap();
+        assert_eq!(restored, runtime);
+        assert_eq!(std::fs::read_to_string(&paths.config).unwrap(), r#"{"device_id": "device-0"}"#);
+        assert_eq!(std::fs::read_to_string(&paths.staged_txn).unwrap(), "{}");
+
+        let conn = storage::init_at(&paths.database).unwrap();
+        let mut battery = BatteryDrain::load(&conn).unwrap();
+        // Built with another seed: the snapshot decides everything from here on
+        let mut simulator = tracker(attempt);
+        simulator.restore(&restored.simulator, Duration::zero());
+        let produced = run(&mut simulator, &mut battery, &conn, restored.taken_at, 10);
+        replays.push((produced, stored(&conn)));
+    }
+
+    let (first, second) = (&replays[0], &replays[1]);
+    assert_eq!(first.0.len(), 120);
+    assert_eq!(first.0, second.0);
+    assert_eq!(first.1, second.1);
+    assert_eq!(first.0, live);
+    // The backlog came along: the 24 warm-up samples precede the replayed ones
+    assert_eq!(first.1.len(), 24 + 120);
+    assert_eq!(first.1, stored(&conn));
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[test]
+fn restore_refuses_unknown_versions_and_incomplete_snapshots() {
+    let root = std::env::temp_dir().join(format!("snapshot_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&root).unwrap();
+    for (version, complete, expected) in [(2, true, "version 2 is not supported"), (1, false, "incomplete")] {
+        let manifest = Manifest { version, taken_at: Utc::now(), devices: Vec::new(), complete };
+        std::fs::write(root.join("manifest.json"), serde_json::to_vec(&manifest).unwrap()).unwrap();
+        let error = format!("{:#}", snapshot::restore(&root).unwrap_err());
+        assert!(error.contains(expected), "{}", error);
+    }
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[tokio::test]
+async fn no_device_resumes_before_the_whole_fleet_is_captured() {
+    let root = std::env::temp_dir().join(format!("snapshot_{}", uuid::Uuid::new_v4()));
+    let controller = Controller::default();
+    let captured = Arc::new(AtomicUsize::new(0));
+    let (resumed, mut resumes) = mpsc::unbounded_channel();
+    for index in 0..3 {
+        let mut port = controller.port(Some(index));
+        let (captured, resumed) = (captured.clone(), resumed.clone());
+        tokio::spawn(async move {
+            let pause = port.recv().await.unwrap();
+            if index == 2 {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+            captured.fetch_add(1, Ordering::SeqCst);
+            pause.finish(Ok(ManifestEntry { index: Some(index), device_id: format!("device-{}", index) })).await;
+            resumed.send(captured.load(Ordering::SeqCst)).unwrap();
+        });
+    }
+
+    let manifest = controller.take(&root).await.unwrap();
+    assert!(manifest.complete);
+    assert_eq!(manifest.devices.iter().map(|entry| entry.index).collect::<Vec<_>>(), [Some(0), Some(1), Some(2)]);
+    let written: Manifest = serde_json::from_slice(&std::fs::read(root.join("manifest.json")).unwrap()).unwrap();
+    assert_eq!(written, manifest);
+    for _ in 0..3 {
+        assert_eq!(resumes.recv().await, Some(3));
+    }
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+// Stored measurements of the fleet member at `paths`, read beside the running device
+fn stored_by(paths: &DevicePaths) -> Vec<Measurement> {
+    stored(&storage::init_at(&paths.database).unwrap())
+}
+
+// Time is paused, so the devices run on tokio's clock alone and every replay takes the
+// same course; the backend is unreachable and the device offline throughout, and ticks
+// are not jittered, as jitter is drawn from entropy.
+#[tokio::test(start_paused = true)]
+async fn two_full_ten_minute_replays_of_a_running_fleet_are_identical() {
+    let dir = std::env::temp_dir().join(format!("snapshot_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // Not member 0, which would install the process-wide crash hook
+    let paths = fleet_tests::member_under(&dir, 1);
+    let vars = [("DEVICE_ID", "device-1"), ("AUTH_TOKEN", "token"), ("BACKEND_URL", "http://127.0.0.1:9"), ("SAMPLE_INTERVAL_SECS", "5"), ("INTERVAL_JITTER", "0"), ("SIMULATION_SEED", "42")];
+    let (mut config, _) = Config::from_env_vars(&vars.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect());
+    config.data_dir = dir.clone();
+    let (started_at, until) = (Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap(), Utc.with_ymd_and_hms(2100, 1, 1, 0, 0, 0).unwrap());
+    config.offline_window = Some(OfflineWindow { started_at, until, request: json!({"until": until}) });
+    config.save_to(&paths.config).unwrap();
+
+    let controller = Arc::new(Controller::default());
+    let ports = controller.clone();
+    let launch: Launch = Arc::new(move |paths: DevicePaths, mut stop_requests: mpsc::Receiver<StopMode>| {
+        let ports = ports.clone();
+        async move {
+            let mut pause_requests = ports.acquire(paths.index);
+            let stopped = crate::supervise(&paths, None, None, &mut pause_requests, &mut stop_requests).await;
+            ports.release(paths.index, pause_requests);
+            stopped
+        }
+        .boxed()
+    });
+    let under = dir.clone();
+    let runner = FleetRunner::launch(vec![paths.clone()], Arc::new(move |index| fleet_tests::member_under(&under, index)), launch)
+        .with_snapshots(controller, dir.join("snapshots"));
+
+    tokio::time::sleep(std::time::Duration::from_secs(120)).await;
+    let (snapshot, manifest) = runner.snapshot().await.unwrap();
+    assert!(manifest.complete);
+    assert_eq!(manifest.devices, [ManifestEntry { index: Some(1), device_id: "device-1".to_string() }]);
+    let warm_up = stored_by(&paths).len();
+    assert!(warm_up >= 24, "{}", warm_up);
+
+    let mut replays = Vec::new();
+    for _ in 0..2 {
+        // Restored while it runs: the device is stopped, put back and started again
+        assert_eq!(runner.restore(&snapshot).await.unwrap(), manifest);
+        tokio::time::sleep(std::time::Duration::from_millis(602_500)).await;
+        replays.push(stored_by(&paths));
+    }
+    runner.scale(0, &ScaleDownSelection::default(), StopMode::Immediate).await;
+
+    let (first, second) = (&replays[0], &replays[1]);
+    assert!(first.len() >= warm_up + 120, "{} after {}", first.len(), warm_up);
+    assert_eq!(first, second);
+    // Each replay continued from the snapshot's instant, not from when it was restored.
+    // Like any boot it samples at once, at that instant, which is at most a sample
+    // interval after the warm-up's last sample
+    let (last_kept, resumed_at) = (first[warm_up - 1].timestamp, first[warm_up].timestamp);
+    assert!((last_kept..=last_kept + Duration::seconds(5)).contains(&resumed_at), "{} after {}", resumed_at, last_kept);
+    assert!(first[warm_up..].iter().all(|measurement| (resumed_at..=resumed_at + Duration::milliseconds(602_500)).contains(&measurement.timestamp)));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,52 @@
+use crate::stats::ApiStats;
+use crate::storage;
+
+fn temp_db_path() -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("stats_{}.db", uuid::Uuid::new_v4()))
+}
+
+#[test]
+fn records_attempts_successes_and_failures_per_endpoint() {
+    let stats = ApiStats::default();
+    stats.record_attempt("ingest", 120);
+    stats.record_success("ingest", 0);
+    stats.record_attempt("ingest", 80);
+    stats.record_failure("ingest", "503");
+    stats.record_attempt("heartbeat", 40);
+    stats.record_failure("heartbeat", "timeout");
+
+    let since_boot = stats.since_boot();
+    let ingest = &since_boot["ingest"];
+    assert_eq!(ingest.attempts, 2);
+    assert_eq!(ingest.successes, 1);
+    assert_eq!(ingest.failures["503"], 1);
+    assert_eq!(ingest.bytes_sent, 200);
+    assert_eq!(since_boot["heartbeat"].failures["timeout"], 1);
+}
+
+#[test]
+fn cumulative_counters_survive_restart() {
+    let path = temp_db_path();
+    let conn = storage::init_at(&path).unwrap();
+    let stats = ApiStats::load(&conn).unwrap();
+    stats.record_attempt("heartbeat", 40);
+    stats.record_success("heartbeat", 10);
+    assert!(stats.checkpoint(&conn).unwrap());
+    // Nothing new to persist
+    assert!(!stats.checkpoint(&conn).unwrap());
+    drop(conn);
+
+    // Simulated restart: since-boot resets, cumulative continues
+    let conn = storage::init_at(&path).unwrap();
+    let stats = ApiStats::load(&conn).unwrap();
+    assert!(stats.since_boot().is_empty());
+    stats.record_attempt("heartbeat", 40);
+
+    let cumulative = stats.cumulative();
+    assert_eq!(cumulative["heartbeat"].attempts, 2);
+    assert_eq!(cumulative["heartbeat"].successes, 1);
+    assert_eq!(cumulative["heartbeat"].bytes_sent, 80);
+    assert_eq!(stats.since_boot()["heartbeat"].attempts, 1);
+
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,465 @@
+use std::cell::Cell;
+use std::path::PathBuf;
+
+use crate::storage;
+
+fn temp_db() -> PathBuf {
+    std::env::temp_dir().join(format!("storage_{}.db", uuid::Uuid::new_v4()))
+}
+
+fn store(conn: &rusqlite::Connection, count: usize) -> Vec<u32> {
+    (0..count)
+        .map(|_| {
+            let measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+            storage::append_measurement(conn, &measurement, 0).unwrap();
+            measurement.sequence_number
+        })
+        .collect()
+}
+
+fn sequence_numbers(rows: &[storage::StoredMeasurement]) -> Vec<u32> {
+    rows.iter().map(|row| row.measurement.sequence_number).collect()
+}
+
+fn ids(rows: &[storage::StoredMeasurement]) -> Vec<i64> {
+    rows.iter().map(|row| row.id).collect()
+}
+
+#[test]
+fn confirmed_rows_are_deleted_and_in_flight_rows_are_not_selected_twice() {
+    let path = temp_db();
+    let mut conn = storage::init_at(&path).unwrap();
+    let stored = store(&conn, 5);
+
+    let first = storage::mark_measurements_inflight(&mut conn, 3).unwrap();
+    assert_eq!(sequence_numbers(&first), stored[..3]);
+    // In-flight rows still count towards the backlog but are not handed out again
+    assert_eq!(storage::pending_count(&conn).unwrap(), 5);
+    let second = storage::mark_measurements_inflight(&mut conn, 3).unwrap();
+    assert_eq!(sequence_numbers(&second), stored[3..]);
+
+    assert_eq!(storage::confirm_uploaded(&mut conn, &ids(&first)).unwrap(), 3);
+    assert_eq!(storage::pending_count(&conn).unwrap(), 2);
+    assert!(storage::mark_measurements_inflight(&mut conn, 3).unwrap().is_empty());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn released_rows_keep_their_order() {
+    let path = temp_db();
+    let mut conn = storage::init_at(&path).unwrap();
+    let stored = store(&conn, 4);
+
+    let failed = storage::mark_measurements_inflight(&mut conn, 2).unwrap();
+    let later = storage::mark_measurements_inflight(&mut conn, 2).unwrap();
+    storage::confirm_uploaded(&mut conn, &ids(&later)).unwrap();
+    assert_eq!(storage::release_inflight(&mut conn, &ids(&failed)).unwrap(), 2);
+    store(&conn, 1);
+
+    // The failed batch is retried ahead of anything sampled since
+    let retry = storage::mark_measurements_inflight(&mut conn, 10).unwrap();
+    assert_eq!(sequence_numbers(&retry)[..2], stored[..2]);
+    assert_eq!(retry.len(), 3);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn rows_in_flight_at_a_crash_are_retried_after_restart() {
+    let path = temp_db();
+    let mut conn = storage::init_at(&path).unwrap();
+    let stored = store(&conn, 3);
+    storage::mark_measurements_inflight(&mut conn, 3).unwrap();
+    drop(conn); // Process dies before the upload completes
+
+    let mut conn = storage::init_at(&path).unwrap();
+    assert_eq!(storage::pending_count(&conn).unwrap(), 3);
+    assert_eq!(sequence_numbers(&storage::mark_measurements_inflight(&mut conn, 10).unwrap()), stored);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn a_crash_between_upload_and_confirmation_resends_only_the_unconfirmed_batch() {
+    let path = temp_db();
+    let mut conn = storage::init_at(&path).unwrap();
+    let stored = store(&conn, 4);
+    let confirmed = storage::mark_measurements_inflight(&mut conn, 2).unwrap();
+    storage::confirm_uploaded(&mut conn, &ids(&confirmed)).unwrap();
+    // The backend accepted this batch, but the process dies before deleting it
+    storage::mark_measurements_inflight(&mut conn, 2).unwrap();
+    drop(conn);
+
+    let mut conn = storage::init_at(&path).unwrap();
+    assert_eq!(sequence_numbers(&storage::mark_measurements_inflight(&mut conn, 10).unwrap()), stored[2..]);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn storage_cap_evicts_the_oldest_rows_first() {
+    let path = temp_db();
+    let mut conn = storage::init_at(&path).unwrap();
+    let stored = store(&conn, 3);
+    let uploading = storage::mark_measurements_inflight(&mut conn, 1).unwrap();
+
+    let mut evicted = 0;
+    let mut newest = Vec::new();
+    for _ in 0..5 {
+        let measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+        evicted += storage::append_measurement(&conn, &measurement, 4).unwrap();
+        newest.push(measurement.sequence_number);
+    }
+    assert_eq!(evicted, 4);
+    assert_eq!(storage::count_measurements(&conn).unwrap(), 4);
+
+    // The row being uploaded survives; everything else that remains is the newest
+    storage::release_inflight(&mut conn, &ids(&uploading)).unwrap();
+    let kept = sequence_numbers(&storage::mark_measurements_inflight(&mut conn, 10).unwrap());
+    assert_eq!(kept[0], stored[0]);
+    assert_eq!(kept[1..], newest[2..]);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn the_age_limit_evicts_old_rows_alongside_the_row_cap() {
+    let path = temp_db();
+    let mut conn = storage::init_at(&path).unwrap();
+    let now = chrono::Utc::now();
+    let taken_ago = |secs: i64| {
+        let mut measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+        measurement.timestamp = now - chrono::Duration::seconds(secs);
+        measurement
+    };
+    let oldest = taken_ago(600);
+    storage::append_measurement(&conn, &oldest, 0).unwrap();
+    let uploading = storage::mark_measurements_inflight(&mut conn, 1).unwrap();
+    for secs in [500, 400, 30, 20] {
+        storage::append_measurement(&conn, &taken_ago(secs), 0).unwrap();
+    }
+
+    // Rows past five minutes go, except the one being uploaded
+    assert_eq!(storage::evict_expired(&conn, 300, now).unwrap(), 2);
+    assert_eq!(storage::count_measurements(&conn).unwrap(), 3);
+    assert_eq!(storage::evict_expired(&conn, 0, now).unwrap(), 0);
+
+    // With both limits, the cap still makes room for the newest row
+    let newest = taken_ago(10);
+    assert_eq!(storage::append_measurement(&conn, &newest, 3).unwrap(), 1);
+    assert_eq!(storage::evict_expired(&conn, 300, now).unwrap(), 0);
+    storage::release_inflight(&mut conn, &ids(&uploading)).unwrap();
+    let kept = sequence_numbers(&storage::mark_measurements_inflight(&mut conn, 10).unwrap());
+    assert_eq!(kept.len(), 3);
+    assert_eq!(kept[0], oldest.sequence_number);
+    assert_eq!(kept[2], newest.sequence_number);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn the_age_limit_seeks_the_timestamp_index_and_the_row_count_follows_every_change() {
+    let path = temp_db();
+    let mut conn = storage::init_at(&path).unwrap();
+    let plan: String = conn
+        .query_row("EXPLAIN QUERY PLAN DELETE FROM measurements INDEXED BY measurements_timestamp WHERE timestamp < ?1 AND inflight = 0", [chrono::Utc::now()], |row| row.get(3))
+        .unwrap();
+    assert!(plan.contains("measurements_timestamp"), "{}", plan);
+
+    let counted = |conn: &rusqlite::Connection| conn.query_row("SELECT COUNT(*) FROM measurements", [], |row| row.get::<_, i64>(0)).unwrap() as u64;
+    store(&conn, 5);
+    let claimed = storage::mark_measurements_inflight(&mut conn, 2).unwrap();
+    storage::confirm_uploaded(&mut conn, &ids(&claimed)).unwrap();
+    storage::append_measurement(&conn, &super::generate_measurement("0.1.0".to_string(), &Default::default()), 2).unwrap();
+    assert_eq!(storage::count_measurements(&conn).unwrap(), 2);
+    assert_eq!(storage::count_measurements(&conn).unwrap(), counted(&conn));
+    let _ = std::fs::remove_file(&path);
+
+    // Rows from before the index, stored in the ISO form, are evicted by age as well
+    let path = old_database();
+    let conn = storage::init_at(&path).unwrap();
+    assert_eq!(storage::count_measurements(&conn).unwrap(), 3);
+    let now = "2024-03-01T10:00:15Z".parse::<chrono::DateTime<chrono::Utc>>().unwrap();
+    assert_eq!(storage::evict_expired(&conn, 10, now).unwrap(), 1);
+    assert_eq!(storage::count_measurements(&conn).unwrap(), 2);
+    let _ = std::fs::remove_file(&path);
+}
+
+// Benchmark fixture: `rows` deterministic measurements in one transaction, far faster than
+// sampling them one by one
+fn bulk_fill(conn: &mut rusqlite::Connection, rows: u64) {
+    let start = chrono::Utc::now() - chrono::Duration::seconds(rows as i64);
+    let tx = conn.transaction().unwrap();
+    {
+        let mut insert = tx.prepare("INSERT INTO measurements (timestamp, temp, humidity, battery, sequence_number, firmware_version) VALUES (?1, ?2, ?3, ?4, ?5, '0.1.0')").unwrap();
+        for row in 0..rows {
+            let step = (row % 100) as f32;
+            insert.execute(rusqlite::params![start + chrono::Duration::seconds(row as i64), 20.0 + step / 50.0, 50.0 - step / 20.0, 0.9, row as u32]).unwrap();
+        }
+    }
+    tx.commit().unwrap();
+}
+
+// Slowest claim-and-confirm of `rounds` batches of 100
+fn worst_claim_and_ack(conn: &mut rusqlite::Connection, rounds: usize) -> std::time::Duration {
+    (0..rounds)
+        .map(|_| {
+            let started = std::time::Instant::now();
+            let claimed = storage::mark_measurements_inflight(conn, 100).unwrap();
+            storage::confirm_uploaded(conn, &ids(&claimed)).unwrap();
+            started.elapsed()
+        })
+        .max()
+        .unwrap()
+}
+
+thread_local! {
+    // Claiming and deleting statements run on this thread, as (claims, deletes)
+    static ROW_STATEMENTS: Cell<(usize, usize)> = const { Cell::new((0, 0)) };
+}
+
+fn count_row_statements(sql: &str) {
+    ROW_STATEMENTS.with(|counts| {
+        let (claims, deletes) = counts.get();
+        if sql.starts_with("UPDATE measurements SET inflight = 1") {
+            counts.set((claims + 1, deletes));
+        } else if sql.starts_with("DELETE FROM measurements") {
+            counts.set((claims, deletes + 1));
+        }
+    });
+}
+
+#[test]
+fn ten_thousand_rows_are_claimed_and_confirmed_in_batches() {
+    let path = temp_db();
+    let mut conn = storage::init_at(&path).unwrap();
+    bulk_fill(&mut conn, 10_000);
+
+    conn.trace(Some(count_row_statements));
+    let claimed = storage::mark_measurements_inflight(&mut conn, 10_000).unwrap();
+    assert_eq!(storage::confirm_uploaded(&mut conn, &ids(&claimed)).unwrap(), 10_000);
+    conn.trace(None);
+    // 500 ids per statement rather than one statement per row
+    assert_eq!(ROW_STATEMENTS.with(Cell::get), (20, 20));
+    assert_eq!(storage::pending_count(&conn).unwrap(), 0);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn a_failed_delete_keeps_every_row() {
+    let path = temp_db();
+    let mut conn = storage::init_at(&path).unwrap();
+    bulk_fill(&mut conn, 1200);
+    let claimed = storage::mark_measurements_inflight(&mut conn, 1200).unwrap();
+    // Fails in the last statement, after earlier ones have deleted their rows
+    let last = claimed.last().unwrap().id;
+    conn.execute_batch(&format!("CREATE TEMP TRIGGER refuse BEFORE DELETE ON measurements WHEN old.id = {} BEGIN SELECT RAISE(ABORT, 'refused'); END;", last)).unwrap();
+
+    assert!(storage::confirm_uploaded(&mut conn, &ids(&claimed)).is_err());
+    assert_eq!(storage::count_measurements(&conn).unwrap(), 1200);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn claims_seek_the_pending_index_from_a_cursor_that_survives_restarts() {
+    let path = temp_db();
+    let mut conn = storage::init_at(&path).unwrap();
+    let plan: String = conn
+        .query_row("EXPLAIN QUERY PLAN SELECT * FROM measurements WHERE inflight = 0 AND id > 0 ORDER BY id LIMIT 10", [], |row| row.get(3))
+        .unwrap();
+    assert!(plan.contains("measurements_pending"), "{}", plan);
+
+    let stored = store(&conn, 5);
+    let first = storage::mark_measurements_inflight(&mut conn, 2).unwrap();
+    storage::confirm_uploaded(&mut conn, &ids(&first)).unwrap();
+    drop(conn);
+    let mut conn = storage::init_at(&path).unwrap();
+    assert_eq!(sequence_numbers(&storage::mark_measurements_inflight(&mut conn, 2).unwrap()), stored[2..4]);
+
+    // Emptying the table lets SQLite reuse ids below the cursor; they are still claimed
+    let rest = storage::mark_measurements_inflight(&mut conn, 10).unwrap();
+    storage::confirm_uploaded(&mut conn, &(1..=5).collect::<Vec<i64>>()).unwrap();
+    assert_eq!(rest.len(), 1);
+    let reused = store(&conn, 2);
+    assert_eq!(sequence_numbers(&storage::mark_measurements_inflight(&mut conn, 10).unwrap()), reused);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn the_page_cache_follows_the_memory_budget_and_new_databases_get_larger_pages() {
+    let path = temp_db();
+    let conn = storage::init_with(&path, 64 * 1024, storage::DEFAULT_BUSY_TIMEOUT_MS).unwrap();
+    let pragma = |conn: &rusqlite::Connection, name: &str| conn.query_row(&format!("PRAGMA {}", name), [], |row| row.get::<_, i64>(0)).unwrap();
+    assert_eq!((pragma(&conn, "page_size"), pragma(&conn, "cache_size")), (8192, -64 * 1024));
+    drop(conn);
+
+    // Pages are fixed once the file exists; the cache is set per connection
+    let conn = storage::init_with(&path, 1024, storage::DEFAULT_BUSY_TIMEOUT_MS).unwrap();
+    assert_eq!((pragma(&conn, "page_size"), pragma(&conn, "cache_size")), (8192, -1024));
+    let _ = std::fs::remove_file(&path);
+    let small = temp_db();
+    assert_eq!(pragma(&storage::init_at(&small).unwrap(), "page_size"), 4096);
+    let _ = std::fs::remove_file(&small);
+}
+
+#[test]
+fn planner_statistics_are_refreshed_once_a_large_backlog_has_drained() {
+    let path = temp_db();
+    let mut conn = storage::init_at(&path).unwrap();
+    bulk_fill(&mut conn, 10);
+    let mut analyze = storage::AnalyzeAfterDrain::default();
+    let statistics = |conn: &rusqlite::Connection| conn.query_row("SELECT COUNT(*) FROM sqlite_master WHERE name = 'sqlite_stat1'", [], |row| row.get::<_, i64>(0)).unwrap();
+
+    assert!(!analyze.record(&conn, storage::LARGE_DRAIN_ROWS).unwrap(), "rows still pending");
+    let claimed = storage::mark_measurements_inflight(&mut conn, 10).unwrap();
+    storage::confirm_uploaded(&mut conn, &ids(&claimed)).unwrap();
+    assert!(analyze.record(&conn, 10).unwrap());
+    assert_eq!(statistics(&conn), 1);
+    // The count starts over afterwards
+    assert!(!analyze.record(&conn, 10).unwrap());
+    let _ = std::fs::remove_file(&path);
+}
+
+// Two million rows take a while to generate; run with
+// `cargo test --release -- --ignored two_million_row_backlog`
+#[test]
+#[ignore]
+fn two_million_row_backlog_claims_and_acks_within_target() {
+    let target = std::time::Duration::from_millis(50);
+    let path = temp_db();
+    let mut conn = storage::init_at(&path).unwrap();
+    // A database from before the pending index
+    conn.execute("DROP INDEX measurements_pending", []).unwrap();
+    bulk_fill(&mut conn, 2_000_000);
+    drop(conn);
+
+    let mut conn = storage::init_at(&path).unwrap();
+    let before = worst_claim_and_ack(&mut conn, 50);
+    assert!(before < target, "claim and ack took {:?} with a full backlog", before);
+
+    while storage::pending_count(&conn).unwrap() > 1000 {
+        let claimed = storage::mark_measurements_inflight(&mut conn, 10_000).unwrap();
+        storage::confirm_uploaded(&mut conn, &ids(&claimed)).unwrap();
+    }
+    let after = worst_claim_and_ack(&mut conn, 5);
+    assert!(after < target, "claim and ack took {:?} after draining", after);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn appends_and_reads_on_separate_connections_do_not_lock_each_other_out() {
+    let path = temp_db();
+    let writer = storage::init_at(&path).unwrap();
+    let journal_mode: String = writer.query_row("PRAGMA journal_mode", [], |row| row.get(0)).unwrap();
+    assert_eq!(journal_mode, "wal");
+
+    // The sampler appends on one connection while another reads, as the upload path does
+    let (finished, done) = std::sync::mpsc::channel();
+    let sampler = {
+        let finished = finished.clone();
+        std::thread::spawn(move || {
+            store(&writer, 300);
+            finished.send("sampler").unwrap();
+        })
+    };
+    let reader_path = path.clone();
+    let uploader = std::thread::spawn(move || {
+        let reader = storage::init_at(&reader_path).unwrap();
+        let mut seen = 0;
+        while seen < 300 {
+            let pending = storage::pending_count(&reader).unwrap();
+            assert!(pending >= seen, "pending went from {} to {}", seen, pending);
+            seen = pending;
+        }
+        finished.send("uploader").unwrap();
+    });
+    // A deadlock, or a lock error panicking either side, leaves one of them unfinished
+    for _ in 0..2 {
+        done.recv_timeout(std::time::Duration::from_secs(30)).expect("both connections finish");
+    }
+    sampler.join().unwrap();
+    uploader.join().unwrap();
+    let _ = std::fs::remove_file(&path);
+}
+
+fn columns(conn: &rusqlite::Connection) -> Vec<String> {
+    let mut stmt = conn.prepare("SELECT name FROM pragma_table_info('measurements')").unwrap();
+    stmt.query_map([], |row| row.get(0)).unwrap().map(Result::unwrap).collect()
+}
+
+fn old_database() -> PathBuf {
+    let path = temp_db();
+    let fixture = std::fs::read_to_string(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/tests/fixtures/storage/v0.sql")).unwrap();
+    rusqlite::Connection::open(&path).unwrap().execute_batch(&fixture).unwrap();
+    path
+}
+
+#[test]
+fn an_old_database_is_migrated_in_place_without_losing_rows() {
+    let path = old_database();
+    let mut conn = storage::init_at(&path).unwrap();
+    assert_eq!(storage::schema_version(&conn).unwrap(), storage::SCHEMA_VERSION);
+    let migrated = columns(&conn);
+    for column in ["maintenance", "heading", "geohash", "inflight", "content_hash", "integrity_flagged"] {
+        assert!(migrated.iter().any(|name| name == column), "{} missing from {:?}", column, migrated);
+    }
+
+    // The old rows read back as they were, and new ones are stored next to them
+    let rows = storage::mark_measurements_inflight(&mut conn, 10).unwrap();
+    assert_eq!(sequence_numbers(&rows), [1, 2, 3]);
+    assert_eq!((rows[0].measurement.temp, rows[0].measurement.latitude, rows[0].measurement.speed), (21.5, Some(34.05), Some(12.5)));
+    assert_eq!(rows[1].measurement.latitude, None);
+    assert_eq!(rows[2].measurement.firmware_version, None);
+    assert_eq!(rows[0].measurement.heading, None);
+    store(&conn, 1);
+    assert_eq!(storage::pending_count(&conn).unwrap(), 4);
+    drop(conn);
+
+    // Opening it again changes nothing
+    let conn = storage::init_at(&path).unwrap();
+    assert_eq!(columns(&conn), migrated);
+    assert_eq!(storage::count_measurements(&conn).unwrap(), 4);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn a_database_upgraded_before_versions_were_recorded_is_not_altered_twice() {
+    let path = temp_db();
+    let conn = storage::init_at(&path).unwrap();
+    let current = columns(&conn);
+    store(&conn, 2);
+    // As earlier firmware left it: every column, but no record of the steps
+    conn.execute("DROP TABLE schema_version", []).unwrap();
+    drop(conn);
+
+    let conn = storage::init_at(&path).unwrap();
+    assert_eq!(storage::schema_version(&conn).unwrap(), storage::SCHEMA_VERSION);
+    assert_eq!(columns(&conn), current);
+    assert_eq!(storage::pending_count(&conn).unwrap(), 2);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn a_layout_newer_than_the_firmware_is_left_alone() {
+    let path = temp_db();
+    let conn = storage::init_at(&path).unwrap();
+    conn.execute("INSERT INTO schema_version (version, applied_at) VALUES (?1, '2030-01-01T00:00:00Z')", [storage::SCHEMA_VERSION + 1]).unwrap();
+    drop(conn);
+    // As after a rollback to older firmware
+    let conn = storage::init_at(&path).unwrap();
+    assert_eq!(storage::schema_version(&conn).unwrap(), storage::SCHEMA_VERSION + 1);
+    store(&conn, 1);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn a_synced_database_file_holds_every_commit_without_its_wal() {
+    let path = temp_db();
+    let conn = storage::init_at(&path).unwrap();
+    store(&conn, 5);
+    storage::sync(&conn).unwrap();
+
+    // As if the process died and only the main file made it to disk
+    let copy = temp_db();
+    std::fs::copy(&path, &copy).unwrap();
+    assert_eq!(storage::count_measurements(&rusqlite::Connection::open(&copy).unwrap()).unwrap(), 5);
+    drop(conn);
+    for file in [&path, &copy] {
+        let _ = std::fs::remove_file(file);
+    }
+}
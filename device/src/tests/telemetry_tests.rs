@@ -0,0 +1,100 @@
+use opentelemetry_otlp::Protocol;
+use serde_json::Value;
+use std::collections::HashMap;
+use tracing::{info_span, Instrument};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::Registry;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use crate::config::Config;
+use crate::stats::ApiStats;
+use crate::telemetry::{self, ExportSettings};
+use crate::{storage, upload};
+
+async fn backend_and_store() -> (MockServer, Config, rusqlite::Connection) {
+    let backend = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&backend)
+        .await;
+    let env = HashMap::from([
+        ("DEVICE_ID".to_string(), "trace-device".to_string()),
+        ("BACKEND_URL".to_string(), backend.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+    ]);
+    let (config, _) = Config::from_env_vars(&env);
+    let db_path = std::env::temp_dir().join(format!("telemetry_{}.db", uuid::Uuid::new_v4()));
+    let conn = storage::init_at(&db_path).unwrap();
+    for _ in 0..3 {
+        storage::append_measurement(&conn, &super::generate_measurement("0.1.0".to_string(), &Default::default()), 0).unwrap();
+    }
+    (backend, config, conn)
+}
+
+fn traceparent(server_requests: &[wiremock::Request]) -> Option<String> {
+    server_requests[0].headers.get("traceparent").map(|value| value.to_str().unwrap().to_string())
+}
+
+#[tokio::test]
+async fn exports_upload_spans_and_propagates_traceparent() {
+    let collector = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/v1/traces"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&collector)
+        .await;
+    let (backend, config, mut conn) = backend_and_store().await;
+
+    let (layer, handle) = telemetry::layer();
+    let _subscriber = tracing::subscriber::set_default(Registry::default().with(layer));
+    let settings = ExportSettings { endpoint: Some(collector.uri()), protocol: Some(Protocol::HttpJson) };
+    let provider = telemetry::build_provider(&settings, &config.device_id, "0.1.0").unwrap();
+    handle.install(&provider).unwrap();
+
+    let client = reqwest::Client::new();
+    upload::drain_once(&client, &config, &ApiStats::default(), &mut conn, None)
+        .instrument(info_span!("upload_cycle"))
+        .await
+        .unwrap();
+    // Shutdown flushes the batch exporter
+    tokio::task::spawn_blocking(move || provider.shutdown()).await.unwrap().unwrap();
+
+    let exports = collector.received_requests().await.unwrap();
+    assert!(!exports.is_empty());
+    let export: Value = serde_json::from_slice(&exports[0].body).unwrap();
+    let resource_spans = &export["resourceSpans"][0];
+    let attributes: HashMap<&str, &str> = resource_spans["resource"]["attributes"].as_array().unwrap().iter()
+        .map(|attribute| (attribute["key"].as_str().unwrap(), attribute["value"]["stringValue"].as_str().unwrap_or_default()))
+        .collect();
+    assert_eq!(attributes["device_id"], "trace-device");
+    assert_eq!(attributes["firmware_version"], "0.1.0");
+    assert_eq!(attributes["service.name"], "device");
+
+    let spans: Vec<&Value> = resource_spans["scopeSpans"].as_array().unwrap().iter()
+        .flat_map(|scope| scope["spans"].as_array().unwrap())
+        .collect();
+    let span = |name: &str| *spans.iter().find(|span| span["name"] == name).unwrap_or_else(|| panic!("no {} span", name));
+    let cycle = span("upload_cycle");
+    let batch = span("upload_batch");
+    assert_eq!(batch["traceId"], cycle["traceId"]);
+    assert_eq!(batch["parentSpanId"], cycle["spanId"]);
+
+    // The ingest request carries the batch span as its parent
+    let header = traceparent(&backend.received_requests().await.unwrap()).expect("traceparent header");
+    let expected = format!("00-{}-{}-01", batch["traceId"].as_str().unwrap(), batch["spanId"].as_str().unwrap());
+    assert_eq!(header, expected);
+}
+
+#[tokio::test]
+async fn no_traceparent_when_export_is_off() {
+    let (backend, config, mut conn) = backend_and_store().await;
+    let (layer, _handle) = telemetry::layer();
+    let _subscriber = tracing::subscriber::set_default(Registry::default().with(layer));
+
+    let client = reqwest::Client::new();
+    upload::drain_once(&client, &config, &ApiStats::default(), &mut conn, None)
+        .instrument(info_span!("upload_cycle"))
+        .await
+        .unwrap();
+    assert_eq!(traceparent(&backend.received_requests().await.unwrap()), None);
+}
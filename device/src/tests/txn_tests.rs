@@ -0,0 +1,80 @@
+use serde_json::json;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::txn::{self, TxnStatus};
+
+fn temp_paths() -> (PathBuf, PathBuf) {
+    let dir = std::env::temp_dir().join(format!("txn_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    (dir.join("config_txn_staged.json"), dir.join("device_config.json"))
+}
+
+fn default_config() -> Config {
+    Config::from_env_vars(&HashMap::new()).0
+}
+
+#[test]
+fn accepts_all_changes_together() {
+    let (staged_path, config_path) = temp_paths();
+    let mut config = default_config();
+    let txn_value = json!({"id": "txn-1", "changes": {"sample_interval_secs": 20, "upload_batch_size": 50}});
+
+    let outcome = txn::apply(&mut config, &txn_value, Some((&staged_path, &config_path))).unwrap().unwrap();
+    assert_eq!(outcome.status, TxnStatus::Applied);
+    assert_eq!(config.sample_interval_secs, 20);
+    assert_eq!(config.upload_batch_size, 50);
+    assert!(!staged_path.exists());
+
+    let persisted = Config::load_from(&config_path).unwrap();
+    assert_eq!(persisted.sample_interval_secs, 20);
+    assert_eq!(persisted.last_config_txn, Some(outcome));
+
+    // The same transaction left in the desired shadow is not re-applied
+    assert!(txn::apply(&mut config, &txn_value, Some((&staged_path, &config_path))).unwrap().is_none());
+}
+
+#[test]
+fn one_rejected_key_rejects_all() {
+    let (staged_path, config_path) = temp_paths();
+    let mut config = default_config();
+    let txn_value = json!({"id": "txn-2", "changes": {"sample_interval_secs": 20, "upload_batch_size": 0, "encoding": "cbor"}});
+
+    let outcome = txn::apply(&mut config, &txn_value, Some((&staged_path, &config_path))).unwrap().unwrap();
+    assert_eq!(outcome.status, TxnStatus::Rejected);
+    assert!(outcome.applied.is_empty());
+    assert_eq!(outcome.errors.len(), 2);
+    assert!(outcome.errors.contains_key("upload_batch_size"));
+    assert_eq!(outcome.errors.get("encoding").map(String::as_str), Some("unknown key"));
+    assert_eq!(config.sample_interval_secs, 10);
+    assert_eq!(config.upload_batch_size, 100);
+}
+
+#[test]
+fn crash_during_commit_is_rolled_forward_on_boot() {
+    let (staged_path, config_path) = temp_paths();
+    let mut config = default_config();
+    config.save_to(&config_path).unwrap();
+
+    // Simulate a crash after staging: the config on disk still has the old values
+    std::fs::write(
+        &staged_path,
+        r#"{"id":"txn-3","changes":[{"UploadIntervalSecs":120},{"HeartbeatIntervalSecs":15}]}"#,
+    ).unwrap();
+
+    let mut rebooted = Config::load_from(&config_path).unwrap();
+    assert_eq!(rebooted.upload_interval_secs, 60);
+    let outcome = txn::recover(&mut rebooted, &staged_path, &config_path).unwrap().unwrap();
+    assert_eq!(outcome.id, "txn-3");
+    assert_eq!(rebooted.upload_interval_secs, 120);
+    assert_eq!(rebooted.heartbeat_interval_secs, 15);
+    assert!(!staged_path.exists());
+    assert_eq!(Config::load_from(&config_path).unwrap().upload_interval_secs, 120);
+
+    // A torn staging write means nothing was committed
+    std::fs::write(&staged_path, r#"{"id":"txn-4","chan"#).unwrap();
+    assert!(txn::recover(&mut config, &staged_path, &config_path).unwrap().is_none());
+    assert_eq!(config.upload_interval_secs, 60);
+    assert!(!staged_path.exists());
+}
@@ -0,0 +1,91 @@
+use chrono::{TimeZone, Utc};
+use serde_json::json;
+
+use crate::types::Measurement;
+use crate::storage;
+
+fn minimal_json() -> serde_json::Value {
+    json!({
+        "timestamp": "2026-01-08T12:00:00Z",
+        "temp": 25.5,
+        "humidity": 60.0,
+        "battery": 0.95,
+        "sequence_number": 7,
+    })
+}
+
+#[test]
+fn gps_and_firmware_fields_round_trip_when_present() {
+    let mut raw = minimal_json();
+    raw["latitude"] = json!(34.05);
+    raw["longitude"] = json!(-118.25);
+    raw["speed"] = json!(12.5);
+    raw["firmware_version"] = json!("1.2.0");
+    let measurement: Measurement = serde_json::from_value(raw).unwrap();
+    assert_eq!(measurement.timestamp, Utc.with_ymd_and_hms(2026, 1, 8, 12, 0, 0).unwrap());
+    assert_eq!((measurement.latitude, measurement.longitude, measurement.speed), (Some(34.05), Some(-118.25), Some(12.5)));
+    assert_eq!(measurement.firmware_version.as_deref(), Some("1.2.0"));
+
+    let reparsed: Measurement = serde_json::from_str(&serde_json::to_string(&measurement).unwrap()).unwrap();
+    assert_eq!(reparsed, measurement);
+}
+
+#[test]
+fn gps_and_firmware_fields_may_be_absent() {
+    let measurement: Measurement = serde_json::from_value(minimal_json()).unwrap();
+    assert_eq!((measurement.latitude, measurement.longitude, measurement.speed), (None, None, None));
+    assert_eq!(measurement.firmware_version, None);
+
+    // Absent values are sent as null and read back as absent
+    let serialized = serde_json::to_value(&measurement).unwrap();
+    assert_eq!(serialized["latitude"], json!(null));
+    assert_eq!(serialized["firmware_version"], json!(null));
+    assert_eq!(serde_json::from_value::<Measurement>(serialized).unwrap(), measurement);
+}
+
+#[test]
+fn stored_measurements_keep_gps_and_firmware_fields() {
+    let path = std::env::temp_dir().join(format!("types_{}.db", uuid::Uuid::new_v4()));
+    let mut conn = storage::init_at(&path).unwrap();
+    let sampled = super::generate_measurement("2.0.0".to_string(), &Default::default());
+    let without_gps = Measurement { latitude: None, longitude: None, speed: None, firmware_version: None, ..sampled.clone() };
+    storage::append_measurement(&conn, &sampled, 0).unwrap();
+    storage::append_measurement(&conn, &without_gps, 0).unwrap();
+
+    let stored: Vec<Measurement> = storage::mark_measurements_inflight(&mut conn, 10).unwrap().into_iter().map(|row| row.measurement).collect();
+    assert_eq!(stored[0].firmware_version.as_deref(), Some("2.0.0"));
+    assert_eq!((stored[0].latitude, stored[0].longitude, stored[0].speed), (sampled.latitude, sampled.longitude, sampled.speed));
+    assert_eq!((stored[1].latitude, stored[1].firmware_version.as_deref()), (None, None));
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn a_fully_populated_measurement_reads_back_unchanged() {
+    let path = std::env::temp_dir().join(format!("types_{}.db", uuid::Uuid::new_v4()));
+    let mut conn = storage::init_at(&path).unwrap();
+    // Everything but replay, which is only set on the in-memory copy being re-sent
+    let full = Measurement {
+        timestamp: Utc.with_ymd_and_hms(2026, 1, 8, 12, 0, 0).unwrap(),
+        maintenance: Some(true),
+        device_flags: Some(vec!["temp_spike".to_string()]),
+        local_timestamp: Some("2026-01-08T04:00:00-08:00".to_string()),
+        utc_offset_minutes: Some(-480),
+        aggregate_count: Some(3),
+        region: Some("eu".to_string()),
+        network: Some(crate::network::NetworkType::Lte),
+        heading: Some(87.5),
+        geohash: Some("9q5ctq".to_string()),
+        map_tile: Some(crate::geo::MapTile { x: 702, y: 1635, z: 12 }),
+        keyframe: Some(true),
+        extra: Some([("EngineSpeed".to_string(), json!({"last": 1250.5, "min": 1200.0, "max": 1300.25, "frames": 100}))].into()),
+        omitted: vec!["humidity".to_string()],
+        content_hash: Some("9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08".to_string()),
+        ..super::generate_measurement("2.0.0".to_string(), &Default::default())
+    };
+    storage::append_measurement(&conn, &full, 0).unwrap();
+
+    let stored = storage::mark_measurements_inflight(&mut conn, 10).unwrap();
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].measurement, full);
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,210 @@
+use serde_json::json;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use super::net_tests;
+use crate::chaos::ChaosEngine;
+use crate::config::Config;
+use crate::stats::ApiStats;
+use crate::upload::{self, UploadMetrics};
+use crate::storage;
+use crate::types::ChaosFlags;
+
+#[test]
+fn concurrency_only_above_drain_threshold() {
+    assert_eq!(upload::batches_in_flight(500, 100, 4, 1000), 1);
+    assert_eq!(upload::batches_in_flight(5000, 100, 4, 1000), 4);
+    assert_eq!(upload::batches_in_flight(1200, 1000, 4, 1000), 2);
+    // Default of one in flight preserves sequential uploads
+    assert_eq!(upload::batches_in_flight(500_000, 100, 1, 1000), 1);
+}
+
+// Drains `rows` measurements against a backend with fixed latency; returns elapsed time and uploaded sequence numbers
+async fn drain(rows: u32, max_in_flight: u32) -> (Duration, Vec<u32>, Vec<u32>, UploadMetrics) {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(204).set_delay(Duration::from_millis(150)))
+        .mount(&server)
+        .await;
+
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("UPLOAD_BATCH_SIZE".to_string(), "10".to_string()),
+        ("UPLOAD_MAX_IN_FLIGHT".to_string(), max_in_flight.to_string()),
+        ("UPLOAD_DRAIN_THRESHOLD".to_string(), "0".to_string()),
+    ]);
+    let (config, _) = Config::from_env_vars(&env);
+    let db_path = std::env::temp_dir().join(format!("upload_{}.db", uuid::Uuid::new_v4()));
+    let mut conn = storage::init_at(&db_path).unwrap();
+    let mut stored = Vec::new();
+    for _ in 0..rows {
+        let measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+        stored.push(measurement.sequence_number);
+        storage::append_measurement(&conn, &measurement, 0).unwrap();
+    }
+
+    let client = reqwest::Client::new();
+    let stats = ApiStats::default();
+    let mut metrics = UploadMetrics::default();
+    let started = Instant::now();
+    while storage::pending_count(&conn).unwrap() > 0 {
+        let round = upload::drain_once(&client, &config, &stats, &mut conn, None).await.unwrap();
+        metrics.record(&round);
+    }
+    let elapsed = started.elapsed();
+
+    let mut uploaded: Vec<u32> = server.received_requests().await.unwrap().iter()
+        .flat_map(|request| net_tests::ingest_payload(request).measurements)
+        .map(|measurement| measurement.sequence_number)
+        .collect();
+    uploaded.sort_unstable();
+    stored.sort_unstable();
+    let _ = std::fs::remove_file(&db_path);
+    (elapsed, stored, uploaded, metrics)
+}
+
+#[tokio::test]
+async fn concurrent_drain_speeds_up_without_losing_rows() {
+    let (sequential, stored, uploaded, _) = drain(80, 1).await;
+    assert_eq!(uploaded, stored);
+
+    let (concurrent, stored, uploaded, metrics) = drain(80, 4).await;
+    // Every row exactly once
+    assert_eq!(uploaded, stored);
+    // 8 sequential round trips versus 2 rounds of 4; allow for scheduling overhead
+    assert!(concurrent * 3 < sequential, "sequential {:?}, concurrent {:?}", sequential, concurrent);
+
+    let report = metrics.report();
+    assert_eq!(report["in_flight"], 4);
+    assert_eq!(report["batches"], 8);
+    assert!(report["avg_batch_latency_ms"].as_u64().unwrap() >= 150);
+}
+
+#[tokio::test]
+async fn upload_failures_are_counted_until_a_batch_goes_through() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(500).set_body_json(serde_json::json!({"detail": "ingest queue full"})))
+        .up_to_n_times(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&server)
+        .await;
+
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("RETRY_MAX_ATTEMPTS".to_string(), "1".to_string()),
+    ]);
+    let (config, _) = Config::from_env_vars(&env);
+    let db_path = std::env::temp_dir().join(format!("upload_{}.db", uuid::Uuid::new_v4()));
+    let mut conn = storage::init_at(&db_path).unwrap();
+    storage::append_measurement(&conn, &super::generate_measurement("0.1.0".to_string(), &Default::default()), 0).unwrap();
+
+    let client = reqwest::Client::new();
+    let stats = ApiStats::default();
+    let mut metrics = UploadMetrics::default();
+    for _ in 0..2 {
+        metrics.record(&upload::drain_once(&client, &config, &stats, &mut conn, None).await.unwrap());
+    }
+    assert_eq!(metrics.consecutive_failures(), 2);
+    assert!(metrics.last_error().unwrap().contains("ingest queue full"), "{:?}", metrics.last_error());
+    assert_eq!(storage::pending_count(&conn).unwrap(), 1);
+
+    metrics.record(&upload::drain_once(&client, &config, &stats, &mut conn, None).await.unwrap());
+    assert_eq!(metrics.consecutive_failures(), 0);
+    assert_eq!(metrics.report()["consecutive_failures"], 0);
+    assert_eq!(storage::pending_count(&conn).unwrap(), 0);
+    let _ = std::fs::remove_file(&db_path);
+}
+
+#[tokio::test]
+async fn chaos_duplicates_and_shuffles_what_goes_out_but_not_what_is_stored() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&server)
+        .await;
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("UPLOAD_BATCH_SIZE".to_string(), "10".to_string()),
+    ]);
+    let (mut config, _) = Config::from_env_vars(&env);
+    config.chaos_flags = Some(ChaosFlags::parse(&json!({"duplicate_probability": 1.0, "shuffle_batch": true})).unwrap());
+    let db_path = std::env::temp_dir().join(format!("upload_{}.db", uuid::Uuid::new_v4()));
+    let mut conn = storage::init_at(&db_path).unwrap();
+    let mut stored = Vec::new();
+    for _ in 0..20 {
+        let measurement = super::generate_measurement("0.1.0".to_string(), &Default::default());
+        stored.push(measurement.sequence_number);
+        storage::append_measurement(&conn, &measurement, 0).unwrap();
+    }
+
+    let mut chaos = ChaosEngine::new(Some(11));
+    while storage::pending_count(&conn).unwrap() > 0 {
+        let round = upload::drain_once_with_chaos(&reqwest::Client::new(), &config, &ApiStats::default(), &mut conn, None, &mut chaos).await.unwrap();
+        assert!(round.batches.iter().all(|batch| batch.uploaded));
+    }
+
+    let sent: Vec<Vec<u32>> = server.received_requests().await.unwrap().iter()
+        .map(|request| net_tests::ingest_payload(request).measurements.iter().map(|measurement| measurement.sequence_number).collect())
+        .collect();
+    // Each batch went out twice, the same way both times
+    assert_eq!(sent.len(), 4);
+    assert_eq!(sent[0], sent[1]);
+    assert_eq!(sent[2], sent[3]);
+    assert!(sent.iter().any(|batch| !batch.windows(2).all(|pair| pair[0] < pair[1])), "{:?}", sent);
+    let mut uploaded: Vec<u32> = sent.iter().step_by(2).flatten().copied().collect();
+    uploaded.sort_unstable();
+    assert_eq!(uploaded, stored);
+    let _ = std::fs::remove_file(&db_path);
+}
+
+#[tokio::test]
+async fn a_corrupted_batch_is_sent_but_its_rows_are_kept() {
+    let server = MockServer::start().await;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(400))
+        .mount(&server)
+        .await;
+    let env = HashMap::from([
+        ("BACKEND_URL".to_string(), server.uri()),
+        ("AUTH_TOKEN".to_string(), "token".to_string()),
+        ("UPLOAD_BATCH_SIZE".to_string(), "10".to_string()),
+    ]);
+    let (mut config, _) = Config::from_env_vars(&env);
+    config.chaos_flags = Some(ChaosFlags::parse(&json!({"corrupt_payload": {"probability": 1.0, "mode": "truncate"}})).unwrap());
+    let db_path = std::env::temp_dir().join(format!("upload_{}.db", uuid::Uuid::new_v4()));
+    let mut conn = storage::init_at(&db_path).unwrap();
+    for _ in 0..5 {
+        storage::append_measurement(&conn, &super::generate_measurement("0.1.0".to_string(), &Default::default()), 0).unwrap();
+    }
+
+    let mut chaos = ChaosEngine::new(Some(3));
+    let round = upload::drain_once_with_chaos(&reqwest::Client::new(), &config, &ApiStats::default(), &mut conn, None, &mut chaos).await.unwrap();
+    assert_eq!(round.uploaded(), 0);
+    assert!(round.batches[0].error.as_deref().unwrap().contains("corrupted payload (truncate)"), "{:?}", round.batches);
+    assert_eq!(storage::pending_count(&conn).unwrap(), 5);
+    let requests = server.received_requests().await.unwrap();
+    assert_eq!(requests.len(), 1);
+    assert!(serde_json::from_slice::<serde_json::Value>(&requests[0].body).is_err());
+
+    // Once the flag is cleared the same rows go out intact
+    config.chaos_flags = None;
+    Mock::given(method("POST")).and(path("/api/devices/ingest"))
+        .respond_with(ResponseTemplate::new(204))
+        .with_priority(1)
+        .mount(&server)
+        .await;
+    let round = upload::drain_once_with_chaos(&reqwest::Client::new(), &config, &ApiStats::default(), &mut conn, None, &mut chaos).await.unwrap();
+    assert_eq!(round.uploaded(), 5);
+    assert_eq!(net_tests::ingest_payload(&server.received_requests().await.unwrap()[1]).measurements.len(), 5);
+    assert_eq!(storage::pending_count(&conn).unwrap(), 0);
+    let _ = std::fs::remove_file(&db_path);
+}
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::config::Config;
+use crate::validation::{self, Severity, ValidateArgs};
+
+fn fixture(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/tests/fixtures/validation").join(format!("{}.json", name))
+}
+
+fn validate_fixture(name: &str) -> validation::Report {
+    let args = ValidateArgs { config: Some(fixture(name)), shadows: Vec::new() };
+    validation::validate(&args, &HashMap::new())
+}
+
+#[test]
+fn valid_fixture_has_no_findings() {
+    let report = validate_fixture("valid");
+    assert!(report.findings.is_empty(), "{:?}", report.findings);
+    assert_eq!(report.to_json()["valid"], true);
+}
+
+#[test]
+fn each_broken_fixture_reports_its_error() {
+    let corpus = [
+        ("sample_interval_out_of_bounds", "sample_interval_secs", "between 1 and 3600"),
+        ("zero_heartbeat_interval", "heartbeat_interval_secs", "greater than zero"),
+        ("inverted_sample_bounds", "min_sample_interval_secs", "exceeds max_sample_interval_secs"),
+        ("bad_backend_url", "backend_url", "unsupported scheme"),
+        ("unknown_timezone", "timezone", "Mars/Olympus_Mons"),
+        ("unknown_feature", "features", "Unknown feature teleport"),
+        ("shed_water_marks_inverted", "shed.low_water", "must be below high_water"),
+        ("unmapped_strict_region", "region", "strict residency"),
+        ("zero_roaming_step", "network_roaming.schedule", "at least one second"),
+        ("unknown_degradation_sensor", "degradation.sensors.pressure", "unknown sensor"),
+        ("desired_feature_variant", "desired.features", "heartbeat_telemetry"),
+        ("desired_txn_batch_size", "desired.config_txn.upload_batch_size", "between 1 and 1000"),
+    ];
+    for (name, key, message) in corpus {
+        let report = validate_fixture(name);
+        let errors: Vec<_> = report.findings.iter().filter(|finding| finding.severity == Severity::Error).collect();
+        assert_eq!(errors.len(), 1, "{}: {:?}", name, errors);
+        assert_eq!(errors[0].key, key, "{}", name);
+        assert!(errors[0].message.contains(message), "{}: {}", name, errors[0].message);
+        assert_eq!(errors[0].file.as_deref(), Some(fixture(name).display().to_string().as_str()));
+        assert!(errors[0].line.is_some(), "{}: error is located in the file", name);
+    }
+}
+
+#[test]
+fn malformed_json_reports_line_and_column() {
+    let report = validate_fixture("malformed");
+    assert!(report.has_errors());
+    let contents = std::fs::read_to_string(fixture("malformed")).unwrap();
+    let broken_line = contents.lines().position(|line| line.ends_with(": ,")).unwrap() + 1;
+    assert_eq!(report.findings[0].key, "config");
+    assert_eq!(report.findings[0].line, Some(broken_line));
+    assert!(report.findings[0].column.is_some());
+}
+
+#[test]
+fn shadow_seed_documents_are_checked_against_the_config() {
+    let args = ValidateArgs { config: Some(fixture("valid")), shadows: vec![fixture("shadow_seed_bad_network")] };
+    let report = validation::validate(&args, &HashMap::new());
+    assert_eq!(report.findings.len(), 1, "{:?}", report.findings);
+    assert_eq!(report.findings[0].key, "desired.network");
+    assert_eq!(report.findings[0].line, Some(2));
+}
+
+#[test]
+fn missing_config_file_falls_back_to_environment_like_startup() {
+    let args = ValidateArgs::default();
+    let vars = HashMap::from([
+        ("CONFIG_DIR".to_string(), format!("/nonexistent/{}", uuid::Uuid::new_v4())),
+        ("VF_HEARTBEAT_INTERVAL_SECS".to_string(), "0".to_string()),
+        ("VF_NETWORK".to_string(), "5g".to_string()),
+    ]);
+    let report = validation::validate(&args, &vars);
+    let find = |key: &str| report.findings.iter().find(|finding| finding.key == key);
+    assert_eq!(find("heartbeat_interval_secs").unwrap().severity, Severity::Error);
+    assert!(find("env").unwrap().message.contains("5g"));
+    assert!(find("config").is_none());
+}
+
+#[test]
+fn parses_arguments() {
+    let args: Vec<String> = ["--config", "a.json", "--shadow", "b.json", "--shadow", "c.json"].iter().map(|arg| arg.to_string()).collect();
+    let parsed = ValidateArgs::parse(&args).unwrap();
+    assert_eq!(parsed.config, Some(PathBuf::from("a.json")));
+    assert_eq!(parsed.shadows.len(), 2);
+    assert!(ValidateArgs::parse(&["--config".to_string()]).is_err());
+    assert!(ValidateArgs::parse(&["--force".to_string()]).is_err());
+}
+
+#[test]
+fn data_dir_must_be_writable() {
+    let (mut config, _) = Config::from_env_vars(&HashMap::new());
+    let dir = std::env::temp_dir().join(format!("validation_{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Not created yet: startup creates it under a writable parent
+    config.data_dir = dir.join("data/device-1");
+    assert!(config.validate().findings.iter().all(|finding| finding.key != "data_dir"));
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0, "the probe is removed");
+
+    std::fs::write(dir.join("file"), b"").unwrap();
+    config.data_dir = dir.join("file/data");
+    let findings = config.validate().findings;
+    let finding = findings.iter().find(|finding| finding.key == "data_dir").unwrap();
+    assert_eq!(finding.severity, Severity::Error);
+    assert!(finding.message.contains("not a directory"), "{}", finding.message);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn config_validate_lists_every_problem() {
+    let vars = HashMap::from([
+        ("BACKEND_URL".to_string(), "ftp://backend".to_string()),
+        ("UPLOAD_INTERVAL_SECS".to_string(), "0".to_string()),
+        ("HEARTBEAT_INTERVAL_SECS".to_string(), "0".to_string()),
+    ]);
+    let (config, _) = Config::from_env_vars(&vars);
+    let report = config.validate();
+    let errors: Vec<&str> = report.findings.iter().filter(|finding| finding.severity == Severity::Error).map(|finding| finding.key.as_str()).collect();
+    assert_eq!(errors, ["backend_url", "upload_interval_secs", "heartbeat_interval_secs"]);
+
+    let (config, _) = Config::from_env_vars(&HashMap::from([("UPLOAD_INTERVAL_SECS".to_string(), "5".to_string())]));
+    assert!(!config.validate().has_errors(), "{:?}", config.validate().findings);
+}
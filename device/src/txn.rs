@@ -0,0 +1,196 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::validation;
+
+pub const STAGED_TXN_FILE: &str = "config_txn_staged.json";
+
+/// A desired-shadow `config_txn` object: every change is applied, or none is.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConfigTxn {
+    pub id: String,
+    pub changes: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TxnStatus {
+    Applied,
+    Rejected,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TxnOutcome {
+    pub id: String,
+    pub status: TxnStatus,
+    // Keys changed by an applied transaction
+    #[serde(default)]
+    pub applied: Vec<String>,
+    // Per-key rejection reasons; empty when applied
+    #[serde(default)]
+    pub errors: BTreeMap<String, String>,
+}
+
+/// A validated change ready to be committed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum TxnChange {
+    SampleIntervalSecs(u64),
+    UploadIntervalSecs(u64),
+    HeartbeatIntervalSecs(u64),
+    UploadBatchSize(u32),
+}
+
+impl TxnChange {
+    pub fn key(&self) -> &'static str {
+        match self {
+            TxnChange::SampleIntervalSecs(_) => "sample_interval_secs",
+            TxnChange::UploadIntervalSecs(_) => "upload_interval_secs",
+            TxnChange::HeartbeatIntervalSecs(_) => "heartbeat_interval_secs",
+            TxnChange::UploadBatchSize(_) => "upload_batch_size",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct StagedTxn {
+    id: String,
+    changes: Vec<TxnChange>,
+}
+
+fn as_number(value: &Value) -> Result<u64, String> {
+    value.as_u64().ok_or_else(|| "expected a non-negative integer".to_string())
+}
+
+fn validate_change(config: &Config, key: &str, value: &Value) -> Result<TxnChange, String> {
+    match key {
+        "sample_interval_secs" | "upload_interval_secs" | "heartbeat_interval_secs" => {
+            let secs = as_number(value)?;
+            validation::interval(config, key, secs)?;
+            Ok(match key {
+                "sample_interval_secs" => TxnChange::SampleIntervalSecs(secs),
+                "upload_interval_secs" => TxnChange::UploadIntervalSecs(secs),
+                _ => TxnChange::HeartbeatIntervalSecs(secs),
+            })
+        }
+        "upload_batch_size" => {
+            let size = as_number(value)?;
+            validation::upload_batch_size(size)?;
+            Ok(TxnChange::UploadBatchSize(size as u32))
+        }
+        _ => Err("unknown key".to_string()),
+    }
+}
+
+/// Validates every change in the transaction. Any error rejects the whole transaction.
+pub fn validate(config: &Config, txn: &ConfigTxn) -> Result<Vec<TxnChange>, BTreeMap<String, String>> {
+    let mut changes = Vec::new();
+    let mut errors = BTreeMap::new();
+    for (key, value) in &txn.changes {
+        match validate_change(config, key, value) {
+            Ok(change) => changes.push(change),
+            Err(reason) => {
+                errors.insert(key.clone(), reason);
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(changes)
+    } else {
+        Err(errors)
+    }
+}
+
+fn commit(config: &mut Config, staged: &StagedTxn) {
+    for change in &staged.changes {
+        match *change {
+            TxnChange::SampleIntervalSecs(secs) => config.sample_interval_secs = secs,
+            TxnChange::UploadIntervalSecs(secs) => config.upload_interval_secs = secs,
+            TxnChange::HeartbeatIntervalSecs(secs) => config.heartbeat_interval_secs = secs,
+            TxnChange::UploadBatchSize(size) => config.upload_batch_size = size,
+        }
+    }
+    config.last_config_txn = Some(TxnOutcome {
+        id: staged.id.clone(),
+        status: TxnStatus::Applied,
+        applied: staged.changes.iter().map(|change| change.key().to_string()).collect(),
+        errors: BTreeMap::new(),
+    });
+}
+
+fn write_staged(path: &Path, staged: &StagedTxn) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::File::create(path)?;
+    file.write_all(serde_json::to_string(staged)?.as_bytes())?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Applies a desired `config_txn` to the config atomically. The validated changes are
+/// staged to disk before the config is rewritten, so a crash mid-commit is rolled
+/// forward by `recover` on the next boot instead of leaving a mixed state. Without
+/// `files` (the staged transaction and config paths) the config only changes in memory.
+/// Transactions already processed (same id as the last outcome) are skipped.
+pub fn apply(config: &mut Config, txn_value: &Value, files: Option<(&Path, &Path)>) -> Result<Option<TxnOutcome>> {
+    let txn: ConfigTxn = serde_json::from_value(txn_value.clone()).context("malformed config_txn")?;
+    if config.last_config_txn.as_ref().is_some_and(|last| last.id == txn.id) {
+        return Ok(None);
+    }
+
+    let outcome = match validate(config, &txn) {
+        Ok(changes) => {
+            let staged = StagedTxn { id: txn.id.clone(), changes };
+            match files {
+                Some((staged_path, config_path)) => {
+                    write_staged(staged_path, &staged)?;
+                    commit(config, &staged);
+                    config.save_to(config_path)?;
+                    fs::remove_file(staged_path)?;
+                    info!(txn_id = %txn.id, "Applied config transaction");
+                }
+                None => commit(config, &staged),
+            }
+            config.last_config_txn.clone().expect("commit records the outcome")
+        }
+        Err(errors) => {
+            let outcome = TxnOutcome { id: txn.id, status: TxnStatus::Rejected, applied: Vec::new(), errors };
+            config.last_config_txn = Some(outcome.clone());
+            if let Some((_, config_path)) = files {
+                warn!(txn_id = %outcome.id, errors = ?outcome.errors, "Rejected config transaction");
+                config.save_to(config_path)?;
+            }
+            outcome
+        }
+    };
+    Ok(Some(outcome))
+}
+
+/// Finishes a transaction that was staged but not fully committed before a crash.
+pub fn recover(config: &mut Config, staged_path: &Path, config_path: &Path) -> Result<Option<TxnOutcome>> {
+    let contents = match fs::read_to_string(staged_path) {
+        Ok(contents) => contents,
+        Err(_) => return Ok(None),
+    };
+    let staged: StagedTxn = match serde_json::from_str(&contents) {
+        Ok(staged) => staged,
+        Err(e) => {
+            // Staging was interrupted before the file was complete; nothing was committed
+            warn!(error = %e, "Discarding incomplete staged config transaction");
+            fs::remove_file(staged_path)?;
+            return Ok(None);
+        }
+    };
+    commit(config, &staged);
+    config.save_to(config_path)?;
+    fs::remove_file(staged_path)?;
+    info!(txn_id = %staged.id, "Recovered staged config transaction");
+    Ok(config.last_config_txn.clone())
+}
@@ -1,8 +1,20 @@
 use serde::{Serialize, Deserialize};
 use chrono::{DateTime, Utc};
 use serde_json::Value; // Import Value for generic JSON
+use std::collections::{BTreeMap, HashMap};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+use crate::chaos_crash::ChaosCrash;
+use crate::corruption::CorruptPayload;
+use crate::firmware::FirmwareBehavior;
+use crate::geofence::GeofenceCrossing;
+use crate::integrity::IntegrityMismatch;
+use crate::reefer::ColdChainBreach;
+use crate::geo::MapTile;
+use crate::network::NetworkType;
+use crate::ota::OtaPhase;
+use crate::ota_history::OtaAttempt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Measurement {
     pub timestamp: DateTime<Utc>,
     pub temp: f32,
@@ -12,18 +24,100 @@ pub struct Measurement {
     pub latitude: Option<f32>,
     pub longitude: Option<f32>,
     pub speed: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heading: Option<f32>, // Degrees clockwise from north, while following a route
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geohash: Option<String>, // Cell of the GPS fix when geo bucketing is on, see geo::GeoBuckets
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub map_tile: Option<MapTile>,
     pub firmware_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub maintenance: Option<bool>, // Set while the device is in maintenance mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_flags: Option<Vec<String>>, // On-device anomaly detections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_timestamp: Option<String>, // Wall-clock time in the device's timezone, RFC 3339 with offset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub utc_offset_minutes: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub aggregate_count: Option<u32>, // Samples averaged into this one while shedding
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>, // Region the sample was collected in; decides its data endpoint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkType>, // Simulated network the device was on when sampling
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay: Option<bool>, // Re-sent after reconnecting; timestamp and sequence_number identify the original
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keyframe: Option<bool>, // Set while field cadences are active; a keyframe carries every field
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub extra: Option<BTreeMap<String, Value>>, // Profile-specific values, such as decoded CAN signals
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>, // Taken when the sample is stored, see integrity::content_hash
+    #[serde(skip)]
+    pub omitted: Vec<String>, // Fields the cadence leaves out of the upload; kept locally
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Heartbeat {
     pub device_id: String,
+    pub device_name: String, // Display only, devices are identified by device_id
     pub firmware_version: String,
     pub reported_sample_interval_secs: u64,
     pub reported_upload_interval_secs: u64,
     pub reported_heartbeat_interval_secs: u64,
     pub region: Option<String>,
     pub hardware_rev: Option<String>,
+    pub maintenance: bool,
+    pub maintenance_expires_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anomaly_counts: Option<BTreeMap<String, u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub api_stats: Option<Value>, // Per-endpoint counters, since boot and cumulative
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub features: Option<Value>, // Effective feature set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shed_samples: Option<u64>, // Samples dropped or folded into aggregates since boot
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub decimation_factor: Option<u32>, // 1 when sampling at full rate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rollback: Option<RollbackReport>, // Firmware rollback not yet reported to the backend
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uptime_secs: Option<u64>, // Since this boot
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pending_measurements: Option<u64>, // Stored and not yet uploaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub consecutive_upload_failures: Option<u32>, // 0 once a batch goes through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_upload_error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery_level: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub free_disk_bytes: Option<u64>, // Available on the data directory's filesystem
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dropped_measurements: Option<u64>, // Evicted by the storage limits since the last heartbeat
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ota_update: Option<OtaAttempt>, // The update the running firmware came from, until reported once
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_time: Option<DateTime<Utc>>, // The device's own clock when sent, skew and drift included
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ota_phase: Option<OtaPhase>, // Set only on the heartbeats around an update's apply phase
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chaos_crashes: Option<u32>, // Crashes the crash chaos flag caused, once there has been one
+}
+
+/// A firmware trial that failed and was reverted to the previous slot.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RollbackReport {
+    pub failed_version: String,
+    pub version: String, // Version running after the rollback
+    pub slot: String,
+    pub reason: String, // boot_limit, health_check_window, fail_boot_confirmation or slot_verification
+    pub boot_count: u32,
+    pub heartbeats: u32, // Successful heartbeats the failed version sent
+    pub ingested: bool,
+    pub at: DateTime<Utc>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -34,11 +128,226 @@ pub struct DesiredState {
     pub desired_heartbeat_interval_secs: u64,
 }
 
+/// Faults the backend asks the device to simulate: the `chaos_flags` object of its desired
+/// shadow. Flags without a field of their own are kept in `other` for the modules that
+/// interpret them, so unknown keys survive a round trip.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ChaosFlags {
+    #[serde(skip_serializing_if = "RandomError::is_off")]
+    pub random_error: RandomError, // Fail operations at random
+    #[serde(skip_serializing_if = "is_default_error_probability")]
+    pub error_probability: f64, // Chance of each failing, unless random_error gives its own
+    #[serde(skip_serializing_if = "is_zero")]
+    pub duplicate_probability: f64, // Chance of an uploaded batch being sent a second time
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub shuffle_batch: bool, // Send the measurements of each upload batch in random order
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub corrupt_payload: Option<CorruptPayload>, // Send upload batches mangled, see corruption::CorruptPayload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub crash: Option<ChaosCrash>, // Exit at a random moment, see chaos_crash::ChaosCrash
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, Value>,
+}
+
+fn default_error_probability() -> f64 {
+    0.1
+}
+
+fn is_default_error_probability(probability: &f64) -> bool {
+    *probability == default_error_probability()
+}
+
+fn is_zero(probability: &f64) -> bool {
+    *probability == 0.0
+}
+
+impl Default for ChaosFlags {
+    fn default() -> Self {
+        ChaosFlags {
+            random_error: RandomError::default(),
+            error_probability: default_error_probability(),
+            duplicate_probability: 0.0,
+            shuffle_batch: false,
+            corrupt_payload: None,
+            crash: None,
+            other: serde_json::Map::new(),
+        }
+    }
+}
+
+impl ChaosFlags {
+    /// Parses a `chaos_flags` object, rejecting values no flag can take.
+    pub fn parse(value: &Value) -> anyhow::Result<Self> {
+        let flags: ChaosFlags = serde_json::from_value(value.clone())?;
+        let probabilities = [Some(flags.error_probability), flags.random_error.targeted().and_then(|targeted| targeted.probability)];
+        if let Some(probability) = probabilities.into_iter().flatten().find(|probability| !(0.0..=1.0).contains(probability)) {
+            anyhow::bail!("error probability {} is not between 0 and 1", probability);
+        }
+        if !(0.0..=1.0).contains(&flags.duplicate_probability) {
+            anyhow::bail!("duplicate probability {} is not between 0 and 1", flags.duplicate_probability);
+        }
+        if let Some(corrupt) = flags.corrupt_payload.as_ref().filter(|corrupt| !(0.0..=1.0).contains(&corrupt.probability)) {
+            anyhow::bail!("corrupt_payload probability {} is not between 0 and 1", corrupt.probability);
+        }
+        if let Some(Err(e)) = flags.crash.as_ref().map(ChaosCrash::validate) {
+            anyhow::bail!("crash: {}", e);
+        }
+        Ok(flags)
+    }
+
+    /// A flag without a field of its own, such as `latency_ms`.
+    pub fn get(&self, flag: &str) -> Option<&Value> {
+        self.other.get(flag)
+    }
+
+    /// What random_error does with these flags; None while it is off.
+    pub fn random_error(&self) -> Option<RandomErrorInEffect> {
+        match &self.random_error {
+            RandomError::Everywhere(false) => None,
+            RandomError::Everywhere(true) => Some(RandomErrorInEffect { probability: self.error_probability, targets: ChaosTarget::ALL.to_vec() }),
+            RandomError::Targeted(targeted) => Some(RandomErrorInEffect {
+                probability: targeted.probability.unwrap_or(self.error_probability),
+                targets: targeted.targets.clone(),
+            }),
+        }
+    }
+
+    /// Chance of `target` failing; None when random_error leaves it alone.
+    pub fn error_probability_for(&self, target: ChaosTarget) -> Option<f64> {
+        self.random_error().filter(|in_effect| in_effect.targets.contains(&target)).map(|in_effect| in_effect.probability)
+    }
+}
+
+/// An operation random_error can fail.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChaosTarget {
+    Ingest,
+    Heartbeat,
+    Shadow,
+    Ota,
+}
+
+impl ChaosTarget {
+    pub const ALL: [ChaosTarget; 4] = [ChaosTarget::Ingest, ChaosTarget::Heartbeat, ChaosTarget::Shadow, ChaosTarget::Ota];
+}
+
+/// The `random_error` flag: `true` fails every operation at `error_probability`, as the
+/// flag always has; an object such as `{"probability": 0.3, "targets": ["ingest"]}` picks
+/// the rate and the operations.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum RandomError {
+    Everywhere(bool),
+    Targeted(TargetedRandomError),
+}
+
+impl Default for RandomError {
+    fn default() -> Self {
+        RandomError::Everywhere(false)
+    }
+}
+
+impl RandomError {
+    fn is_off(&self) -> bool {
+        *self == RandomError::Everywhere(false)
+    }
+
+    fn targeted(&self) -> Option<&TargetedRandomError> {
+        match self {
+            RandomError::Targeted(targeted) => Some(targeted),
+            RandomError::Everywhere(_) => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TargetedRandomError {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probability: Option<f64>, // error_probability when absent
+    #[serde(default = "all_chaos_targets")]
+    pub targets: Vec<ChaosTarget>,
+}
+
+fn all_chaos_targets() -> Vec<ChaosTarget> {
+    ChaosTarget::ALL.to_vec()
+}
+
+/// The rate and operations random_error is failing, reported in the shadow so an operator
+/// can confirm what the device does with the flags it was sent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RandomErrorInEffect {
+    pub probability: f64,
+    pub targets: Vec<ChaosTarget>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FirmwareMetadata {
     pub version: String,
     pub checksum: String,
     pub url: String,
+    #[serde(default)]
+    pub behavior: Option<FirmwareBehavior>, // Simulated behavior the version carries once installed
+    #[serde(default)]
+    pub nonce: Option<String>, // Echo of the request nonce, for replay protection
+    #[serde(default)]
+    pub issued_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub signature: Option<String>, // Hex ed25519 signature over the image's SHA-256 digest, checked against ota_public_key
+    #[serde(default)]
+    pub metadata_signature: Option<String>, // Hex ed25519 signature over ota::metadata_message, checked against ota_public_key
+}
+
+/// One reported shadow key the backend's policy refused, from a 422 response.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShadowRejection {
+    pub path: String, // JSON pointer, or dotted, into the reported patch
+    pub code: String, // Why, e.g. schema, too_large or forbidden_key
+    #[serde(default)]
+    pub message: String,
+}
+
+/// Body of a 422 response to a shadow report: the rejections, bare or under `errors`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ShadowRejections {
+    List(Vec<ShadowRejection>),
+    Wrapped { errors: Vec<ShadowRejection> },
+}
+
+impl ShadowRejections {
+    pub fn into_vec(self) -> Vec<ShadowRejection> {
+        match self {
+            ShadowRejections::List(rejections) | ShadowRejections::Wrapped { errors: rejections } => rejections,
+        }
+    }
+}
+
+// Device error report, keyed by a stable error code
+#[derive(Serialize, Debug)]
+pub struct DeviceErrorPayload {
+    pub firmware_version: String,
+    pub error_code: String,
+    pub error_message: String,
+}
+
+/// Something that happened to a device, sent as it happens rather than with measurements.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event_type", rename_all = "snake_case")]
+pub enum DeviceEvent {
+    GeofenceBreach(GeofenceCrossing),
+    GeofenceReentry(GeofenceCrossing),
+    IntegrityMismatch(IntegrityMismatch),
+    ColdChainBreach(ColdChainBreach),
+}
+
+// Backend acknowledgement of an uploaded file
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct UploadedFile {
+    pub file_id: String,
+    pub sha256: String,
 }
 
 // For sending to the backend ingest API
@@ -46,8 +355,41 @@ pub struct FirmwareMetadata {
 pub struct IngestPayload {
     pub device_id: String,
     pub measurements: Vec<Measurement>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>, // Residency annotations: region and endpoint the batch was sent for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_endpoint: Option<String>,
 }
 
+// Optional body of the ingest response, used by the backend to steer sampling
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct IngestFeedback {
+    #[serde(default)]
+    pub suggested_sample_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub accepted_hashes: Option<Vec<String>>, // Content hashes of the rows the backend stored, see integrity::verify
+    #[serde(default)]
+    pub merkle_root: Option<String>, // Or the root of the batch's hashes instead
+}
+
+// Measurement schema published by the backend at /api/schema/measurements
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct MeasurementSchema {
+    #[serde(default)]
+    pub accepted_fields: Vec<String>,
+    #[serde(default)]
+    pub required_fields: Vec<String>,
+    #[serde(default)]
+    pub ranges: HashMap<String, FieldRange>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FieldRange {
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+#[allow(dead_code)] // Mirrors the backend fleet settings schema
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FleetSettings {
     pub num_devices: u64,
@@ -59,6 +401,11 @@ pub struct FleetSettings {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RegisterPayload {
     pub boot_id: uuid::Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>, // Configured name; generated names need the assigned id
+    pub features: Value, // Effective feature set at registration
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provisioning_token: Option<String>, // Fleet enrollment secret from device init
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -68,6 +415,23 @@ pub struct RegisterResponse {
     pub desired_sample_interval_secs: u64,
     pub desired_upload_interval_secs: u64,
     pub desired_heartbeat_interval_secs: u64,
+    #[serde(default)]
+    pub supported_encodings: Vec<String>, // Request Content-Encodings the backend accepts; empty if it does not say
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BulkRegisterPayload {
+    pub devices: Vec<RegisterPayload>,
+}
+
+/// One device's outcome in a bulk registration, matched to its request by boot id.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BulkRegisterResult {
+    pub boot_id: uuid::Uuid,
+    #[serde(default)]
+    pub registration: Option<RegisterResponse>, // None if the backend rejected this device
+    #[serde(default)]
+    pub error: Option<String>,
 }
 
 // New structs for Device Shadow
@@ -77,6 +441,14 @@ pub struct DeviceShadow {
     pub reported: Option<Value>,
 }
 
+// Response of the combined heartbeat and shadow sync, see net::sync
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SyncResponse {
+    pub desired_state: DesiredState,
+    pub shadow: DeviceShadow,
+}
+
+#[allow(dead_code)] // Mirrors the backend desired shadow schema
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct DesiredShadowState {
     pub state: Value,
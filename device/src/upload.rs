@@ -0,0 +1,354 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use futures::stream::{self, StreamExt};
+use reqwest::Client;
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, warn, Instrument};
+
+use crate::chaos::ChaosEngine;
+use crate::config::Config;
+use crate::corruption::{self, CorruptionMode};
+use crate::integrity::{self, IntegrityMismatch, Verification};
+use crate::net;
+use crate::network;
+use crate::replay::ReconnectReplay;
+use crate::residency::{self, DataTarget};
+use crate::stats::ApiStats;
+use crate::storage;
+use crate::types::{IngestFeedback, Measurement, MeasurementSchema};
+
+// Wait before a batch duplicated by chaos goes out again
+const DUPLICATE_DELAY: Duration = Duration::from_millis(500);
+
+// What chaos does to one upload batch on the wire
+#[derive(Debug, Clone, Copy, Default)]
+struct BatchChaos {
+    duplicate: bool,
+    corruption: Option<CorruptionMode>,
+}
+
+// A batch's row ids and measurements, reordered if chaos shuffled them, with what else chaos does to it
+type PlannedBatch<'a> = (Cow<'a, [i64]>, Cow<'a, [Measurement]>, BatchChaos);
+
+/// Number of batches to send at once. Concurrency only kicks in for a backlog above
+/// the drain threshold, so steady-state uploads keep one request in flight.
+pub fn batches_in_flight(backlog: u64, batch_size: u32, max_in_flight: u32, drain_threshold: u64) -> u32 {
+    if backlog <= drain_threshold || max_in_flight <= 1 {
+        return 1;
+    }
+    let batches_needed = backlog.div_ceil(batch_size.max(1) as u64);
+    batches_needed.clamp(1, max_in_flight as u64) as u32
+}
+
+#[derive(Debug, Clone)]
+pub struct BatchResult {
+    pub count: usize,
+    pub latency: Duration,
+    pub uploaded: bool,
+    pub error: Option<String>, // Why the batch failed
+}
+
+#[derive(Debug, Default)]
+pub struct UploadRound {
+    pub in_flight: u32, // Most batches sent at once
+    pub batches: Vec<BatchResult>,
+    pub feedback: Option<IngestFeedback>, // Most recent suggestion from any batch in the round
+    pub integrity_mismatches: Vec<IntegrityMismatch>, // Acknowledgements that did not match what was sent
+}
+
+impl UploadRound {
+    pub fn uploaded(&self) -> usize {
+        self.batches.iter().filter(|batch| batch.uploaded).map(|batch| batch.count).sum()
+    }
+}
+
+/// Upload concurrency and per-batch latency, reported in the shadow.
+#[derive(Debug, Default)]
+pub struct UploadMetrics {
+    in_flight: u32, // Batches in flight during the most recent round
+    batches: u64,
+    failed_batches: u64,
+    consecutive_failures: u32, // Failed batches and upload ticks since the last batch went through
+    last_error: Option<String>,
+    last_latency_ms: u64,
+    max_latency_ms: u64,
+    total_latency_ms: u64,
+}
+
+impl UploadMetrics {
+    pub fn record(&mut self, round: &UploadRound) {
+        self.in_flight = round.in_flight;
+        for batch in &round.batches {
+            let latency_ms = batch.latency.as_millis() as u64;
+            self.batches += 1;
+            self.failed_batches += u64::from(!batch.uploaded);
+            match &batch.error {
+                Some(error) => self.record_failure(error),
+                None if batch.uploaded => self.consecutive_failures = 0,
+                None => {}
+            }
+            self.last_latency_ms = latency_ms;
+            self.max_latency_ms = self.max_latency_ms.max(latency_ms);
+            self.total_latency_ms += latency_ms;
+        }
+    }
+
+    /// Counts an upload that failed before any batch was sent.
+    pub fn record_failure(&mut self, error: &str) {
+        self.consecutive_failures += 1;
+        self.last_error = Some(error.to_string());
+    }
+
+    pub fn consecutive_failures(&self) -> u32 {
+        self.consecutive_failures
+    }
+
+    /// Most recent upload error; kept after uploads recover, see consecutive_failures.
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    pub fn report(&self) -> Value {
+        json!({
+            "in_flight": self.in_flight,
+            "batches": self.batches,
+            "failed_batches": self.failed_batches,
+            "consecutive_failures": self.consecutive_failures,
+            "last_error": self.last_error,
+            "last_batch_latency_ms": self.last_latency_ms,
+            "max_batch_latency_ms": self.max_latency_ms,
+            "avg_batch_latency_ms": self.total_latency_ms.checked_div(self.batches).unwrap_or(0),
+        })
+    }
+}
+
+/// One upload tick: flags up to `batches_in_flight` batches' worth of measurements
+/// in-flight and sends them with at most that many in flight. Each batch succeeds or fails on its own:
+/// uploaded rows are deleted, and failed batches, including those for an unmapped
+/// region under strict residency, are released in place for the next tick. A batch
+/// whose acknowledgement does not match its content hashes counts as failed, and the
+/// rows it cannot vouch for are flagged.
+pub async fn drain_once(
+    client: &Client,
+    config: &Config,
+    stats: &ApiStats,
+    conn: &mut Connection,
+    measurement_schema: Option<&MeasurementSchema>,
+) -> Result<UploadRound> {
+    drain(client, config, stats, conn, measurement_schema, None).await
+}
+
+/// An upload tick as drain_once, with the batches duplicated, shuffled and corrupted as
+/// the `duplicate_probability`, `shuffle_batch` and `corrupt_payload` chaos flags ask.
+/// Only what goes over the wire changes; the stored rows are left alone, and those of a
+/// corrupted batch are released for the next tick.
+pub async fn drain_once_with_chaos(
+    client: &Client,
+    config: &Config,
+    stats: &ApiStats,
+    conn: &mut Connection,
+    measurement_schema: Option<&MeasurementSchema>,
+    chaos: &mut ChaosEngine,
+) -> Result<UploadRound> {
+    drain(client, config, stats, conn, measurement_schema, Some(chaos)).await
+}
+
+async fn drain(
+    client: &Client,
+    config: &Config,
+    stats: &ApiStats,
+    conn: &mut Connection,
+    measurement_schema: Option<&MeasurementSchema>,
+    mut chaos: Option<&mut ChaosEngine>,
+) -> Result<UploadRound> {
+    let backlog = storage::pending_count(conn)?;
+    let in_flight = batches_in_flight(backlog, config.upload_batch_size, config.upload_max_in_flight, config.upload_drain_threshold);
+    let rows = storage::mark_measurements_inflight(conn, config.upload_batch_size.saturating_mul(in_flight))?;
+    let (ids, measurements): (Vec<i64>, Vec<Measurement>) = rows.into_iter().map(|row| (row.id, row.measurement)).unzip();
+    if measurements.is_empty() {
+        return Ok(UploadRound::default());
+    }
+    if in_flight > 1 {
+        info!(device_id = %config.device_id, backlog, in_flight, "Draining backlog with concurrent batches");
+    }
+
+    // Batches never mix regions, so each one goes to its own region's data endpoint,
+    // and stay within the payload limit of the simulated network
+    let max_payload_bytes = network::active_profile(config).and_then(|profile| profile.max_payload_bytes);
+    let mut batches: Vec<&[Measurement]> = Vec::new();
+    for group in measurements.chunk_by(|a, b| a.region == b.region) {
+        for batch in group.chunks(config.upload_batch_size.max(1) as usize) {
+            batches.extend(network::split_to_fit(batch, &config.device_id, max_payload_bytes));
+        }
+    }
+
+    // Batches are consecutive slices of `measurements`, so row ids line up by offset.
+    // A shuffled batch keeps its ids in the same order as its measurements.
+    let mut offset = 0;
+    let flags = config.chaos_flags.as_ref();
+    let batches: Vec<PlannedBatch> = batches.into_iter().map(|batch| {
+        let batch_ids = &ids[offset..offset + batch.len()];
+        offset += batch.len();
+        let Some(chaos) = chaos.as_deref_mut() else {
+            return (Cow::Borrowed(batch_ids), Cow::Borrowed(batch), BatchChaos::default());
+        };
+        let batch_chaos = BatchChaos { duplicate: chaos.duplicate_batch(flags), corruption: chaos.corrupt_payload(flags) };
+        match chaos.batch_order(flags, batch.len()) {
+            Some(order) => {
+                warn!(device_id = %config.device_id, chaos_type = "shuffle_batch", count = batch.len(), "Shuffled batch of {}", batch.len());
+                let shuffled_ids = order.iter().map(|&i| batch_ids[i]).collect();
+                let shuffled = order.iter().map(|&i| batch[i].clone()).collect();
+                (Cow::Owned(shuffled_ids), Cow::Owned(shuffled), batch_chaos)
+            }
+            None => (Cow::Borrowed(batch_ids), Cow::Borrowed(batch), batch_chaos),
+        }
+    }).collect();
+
+    let batch_count = batches.len();
+    // Collected rather than left as a lazy map, which would keep the drain from being Send
+    let sends: Vec<_> = batches.into_iter().map(|(batch_ids, batch, batch_chaos)| {
+        let span = info_span!("upload_batch", count = batch.len(), region = ?batch[0].region);
+        async move {
+            let started = Instant::now();
+            let result = match residency::target_for(config, batch[0].region.as_deref()) {
+                Ok(target) => send_batch(client, config, stats, &target, &batch, measurement_schema, batch_chaos).await,
+                Err(e) => Err(e.into()), // Held locally until the region is mapped
+            };
+            (batch_ids, batch, started.elapsed(), result)
+        }
+        .instrument(span)
+    }).collect();
+
+    let mut round = UploadRound { in_flight: in_flight.min(batch_count as u32), ..Default::default() };
+    // Splitting to fit a small payload limit can yield more batches than may be in flight
+    for (batch_ids, batch, latency, result) in stream::iter(sends).buffered(in_flight as usize).collect::<Vec<_>>().await {
+        let (uploaded, error) = match result {
+            Ok(feedback) => match integrity::verify(&batch, feedback.as_ref()) {
+                Verification::Mismatch { reason, suspect } => {
+                    error!(device_id = %config.device_id, %reason, count = batch.len(), suspect = suspect.len(), "Ingest acknowledgement does not match the batch. Releasing it for retry.");
+                    if let Err(e) = storage::release_inflight(conn, &batch_ids) {
+                        error!(device_id = %config.device_id, error = %e, "Failed to release in-flight measurements");
+                    }
+                    let suspect_ids: Vec<i64> = suspect.iter().map(|&i| batch_ids[i]).collect();
+                    if let Err(e) = storage::flag_integrity_mismatch(conn, &suspect_ids) {
+                        error!(device_id = %config.device_id, error = %e, "Failed to flag measurements with mismatched hashes");
+                    }
+                    round.integrity_mismatches.push(IntegrityMismatch {
+                        timestamp: Utc::now(),
+                        reason: reason.clone(),
+                        sequence_numbers: suspect.iter().map(|&i| batch[i].sequence_number).collect(),
+                    });
+                    (false, Some(format!("integrity mismatch: {}", reason)))
+                }
+                Verification::Verified | Verification::Unsupported => {
+                    round.feedback = feedback.or(round.feedback.take());
+                    // Left in-flight on error, so the rows are retried after a restart
+                    if let Err(e) = storage::confirm_uploaded(conn, &batch_ids) {
+                        error!(device_id = %config.device_id, error = %e, count = batch.len(), "Failed to delete uploaded measurements");
+                    }
+                    (true, None)
+                }
+            },
+            Err(e) => {
+                error!(device_id = %config.device_id, error = %e, count = batch.len(), "Failed to ingest batch. Releasing it for retry.");
+                if let Err(e_release) = storage::release_inflight(conn, &batch_ids) {
+                    error!(device_id = %config.device_id, error = %e_release, "Failed to release in-flight measurements");
+                }
+                (false, Some(e.to_string()))
+            }
+        };
+        round.batches.push(BatchResult { count: batch.len(), latency, uploaded, error });
+    }
+    Ok(round)
+}
+
+/// Sends a batch, and once more after DUPLICATE_DELAY if chaos duplicates it. The first
+/// send decides the batch's fate; the duplicate's outcome is only logged. A batch chaos
+/// corrupts goes out mangled instead, and always fails.
+async fn send_batch(
+    client: &Client,
+    config: &Config,
+    stats: &ApiStats,
+    target: &DataTarget,
+    batch: &[Measurement],
+    measurement_schema: Option<&MeasurementSchema>,
+    chaos: BatchChaos,
+) -> Result<Option<IngestFeedback>> {
+    if let Some(mode) = chaos.corruption {
+        return send_corrupted(client, config, stats, target, batch, measurement_schema, mode).await;
+    }
+    let result = net::send_ingest(client, config, stats, target, batch, measurement_schema).await;
+    if chaos.duplicate && result.is_ok() {
+        tokio::time::sleep(DUPLICATE_DELAY).await;
+        match net::send_ingest(client, config, stats, target, batch, measurement_schema).await {
+            Ok(_) => warn!(device_id = %config.device_id, chaos_type = "duplicate_probability", count = batch.len(), "Resent batch of {}", batch.len()),
+            Err(e) => warn!(device_id = %config.device_id, chaos_type = "duplicate_probability", error = %e, count = batch.len(), "Failed to resend duplicated batch"),
+        }
+    }
+    result
+}
+
+// Sends `batch` mangled per `mode` in place of the real upload. Whatever the backend
+// answers, the batch counts as failed, so its rows are kept and sent intact later.
+async fn send_corrupted(
+    client: &Client,
+    config: &Config,
+    stats: &ApiStats,
+    target: &DataTarget,
+    batch: &[Measurement],
+    measurement_schema: Option<&MeasurementSchema>,
+    mode: CorruptionMode,
+) -> Result<Option<IngestFeedback>> {
+    let body = corruption::corrupt(&net::ingest_body(config, target, batch, measurement_schema)?, mode);
+    let status = net::send_raw_ingest(client, config, stats, target, &body).await?;
+    if status.is_success() {
+        error!(device_id = %config.device_id, chaos_type = "corrupt_payload", mode = mode.as_str(), status = %status, count = batch.len(), "Backend accepted a corrupted payload");
+    } else {
+        warn!(device_id = %config.device_id, chaos_type = "corrupt_payload", mode = mode.as_str(), status = %status, count = batch.len(), "Backend refused a corrupted payload");
+    }
+    anyhow::bail!("sent a corrupted payload ({}), backend answered {}", mode.as_str(), status)
+}
+
+/// Sends the reconnect replay if one is due, ahead of the backlog. Replayed rows are
+/// copies, so a failed replay is not put back; it is tried again on the next tick.
+/// Returns None when no replay was due.
+pub async fn send_replay(
+    client: &Client,
+    config: &Config,
+    stats: &ApiStats,
+    replay: &mut ReconnectReplay,
+    measurement_schema: Option<&MeasurementSchema>,
+    now: DateTime<Utc>,
+) -> Option<BatchResult> {
+    if !replay.due() {
+        return None;
+    }
+    let measurements = replay.batch(now);
+    if measurements.is_empty() {
+        return None;
+    }
+    let started = Instant::now();
+    let mut error = None;
+    for group in measurements.chunk_by(|a, b| a.region == b.region) {
+        let result = match residency::target_for(config, group[0].region.as_deref()) {
+            Ok(target) => net::send_ingest(client, config, stats, &target, group, measurement_schema).await.map(|_| ()),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!(device_id = %config.device_id, error = %e, count = group.len(), "Failed to send reconnect replay");
+            error = Some(e.to_string());
+            break;
+        }
+    }
+    let uploaded = error.is_none();
+    if uploaded {
+        info!(device_id = %config.device_id, count = measurements.len(), "Replayed recent measurements after reconnecting");
+        replay.delivered(measurements.len());
+    } else {
+        replay.observe(false);
+    }
+    Some(BatchResult { count: measurements.len(), latency: started.elapsed(), uploaded, error })
+}
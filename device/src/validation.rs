@@ -0,0 +1,621 @@
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::anomaly::SelfDetectionConfig;
+use crate::baseline::{BaselineOverrides, FaultBaseline};
+use crate::battery;
+use crate::cadence::{self, CadenceConfig};
+use crate::can::SignalSet;
+use crate::config::{self, Config};
+use crate::debug_session;
+use crate::degradation::{self, Lifetime};
+use crate::features::Features;
+use crate::geo::{self, GeoBucketConfig};
+use crate::jitter;
+use crate::localtime;
+use crate::naming;
+use crate::offline;
+use crate::network::{self, NetworkType};
+use crate::ota;
+use crate::push;
+use crate::residency;
+use crate::route;
+use crate::simulate;
+use crate::txn::{self, ConfigTxn};
+use crate::types::ChaosFlags;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// One problem found in a config or shadow document, keyed by the setting it concerns.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub key: String, // Dotted path, e.g. shed.low_water or desired.features
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+}
+
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    fn push(&mut self, severity: Severity, key: &str, message: impl Into<String>) {
+        self.findings.push(Finding { severity, key: key.to_string(), message: message.into(), file: None, line: None, column: None });
+    }
+
+    pub fn error(&mut self, key: &str, message: impl Into<String>) {
+        self.push(Severity::Error, key, message);
+    }
+
+    pub fn warning(&mut self, key: &str, message: impl Into<String>) {
+        self.push(Severity::Warning, key, message);
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.findings.iter().any(|finding| finding.severity == Severity::Error)
+    }
+
+    fn extend(&mut self, other: Report) {
+        self.findings.extend(other.findings);
+    }
+
+    // Attributes findings without a location to `file`, at the first line naming their key
+    fn locate(&mut self, file: &Path, contents: &str) {
+        for finding in self.findings.iter_mut().filter(|finding| finding.file.is_none()) {
+            finding.file = Some(file.display().to_string());
+            let leaf = finding.key.rsplit('.').next().unwrap_or_default();
+            let needle = format!("\"{}\"", leaf);
+            finding.line = contents.lines().position(|line| line.contains(&needle)).map(|index| index + 1);
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let count = |severity| self.findings.iter().filter(|finding| finding.severity == severity).count();
+        json!({
+            "valid": !self.has_errors(),
+            "errors": count(Severity::Error),
+            "warnings": count(Severity::Warning),
+            "findings": self.findings,
+        })
+    }
+}
+
+// --- Checks shared with the runtime ---
+
+/// Bounds for an interval set from the control plane. Sample intervals must stay
+/// within the configured bounds; every interval must be non-zero.
+pub fn interval(config: &Config, key: &str, secs: u64) -> Result<(), String> {
+    if key == "sample_interval_secs" && !(config.min_sample_interval_secs..=config.max_sample_interval_secs).contains(&secs) {
+        return Err(format!("must be between {} and {}", config.min_sample_interval_secs, config.max_sample_interval_secs));
+    }
+    if secs == 0 {
+        return Err("must be greater than zero".to_string());
+    }
+    Ok(())
+}
+
+pub fn upload_batch_size(size: u64) -> Result<(), String> {
+    if !(1..=1000).contains(&size) {
+        return Err("must be between 1 and 1000".to_string());
+    }
+    Ok(())
+}
+
+pub fn url(raw: &str) -> Result<(), String> {
+    match reqwest::Url::parse(raw) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        Ok(url) => Err(format!("unsupported scheme {:?} in {}", url.scheme(), raw)),
+        Err(e) => Err(format!("invalid URL {:?}: {}", raw, e)),
+    }
+}
+
+/// Whether the device can create files under `dir`. A directory that does not exist yet
+/// is created at startup, so its nearest existing ancestor is checked instead, by
+/// creating and removing a probe file in it.
+pub fn writable_dir(dir: &Path) -> Result<(), String> {
+    let existing = dir.ancestors()
+        .map(|ancestor| if ancestor.as_os_str().is_empty() { Path::new(".") } else { ancestor })
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("."));
+    if !existing.is_dir() {
+        return Err(format!("{} is not a directory", existing.display()));
+    }
+    let probe = existing.join(format!(".write_probe_{}", uuid::Uuid::new_v4()));
+    match fs::OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            Ok(())
+        }
+        Err(e) => Err(format!("{} is not writable: {}", existing.display(), e)),
+    }
+}
+
+pub fn cadence(cadence: &CadenceConfig) -> Result<(), String> {
+    if let Some(unknown) = cadence.fields.keys().find(|field| !cadence::FIELDS.contains(&field.as_str())) {
+        return Err(format!("{:?} cannot be thinned; expected one of {}", unknown, cadence::FIELDS.join(", ")));
+    }
+    if cadence.keyframe_interval_secs == 0 {
+        return Err("keyframe_interval_secs must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+pub fn geo_buckets(settings: &GeoBucketConfig) -> Result<(), String> {
+    if !(1..=geo::MAX_PRECISION).contains(&settings.precision) {
+        return Err(format!("precision must be between 1 and {}", geo::MAX_PRECISION));
+    }
+    if settings.tile_zoom.is_some_and(|zoom| zoom > geo::MAX_ZOOM) {
+        return Err(format!("tile_zoom must be at most {}", geo::MAX_ZOOM));
+    }
+    Ok(())
+}
+
+/// Every check that applies to a loaded config. Run at startup, where findings are
+/// logged, and by the validate command, where errors fail the run. See Config::validate.
+pub fn check_config(config: &Config) -> Report {
+    let mut report = Report::default();
+
+    if let Err(e) = url(&config.backend_url) {
+        report.error("backend_url", e);
+    }
+    if config.min_sample_interval_secs > config.max_sample_interval_secs {
+        report.error("min_sample_interval_secs", format!("exceeds max_sample_interval_secs ({} > {})", config.min_sample_interval_secs, config.max_sample_interval_secs));
+    } else if let Err(e) = interval(config, "sample_interval_secs", config.sample_interval_secs) {
+        report.error("sample_interval_secs", e);
+    }
+    for (key, secs) in [
+        ("upload_interval_secs", config.upload_interval_secs),
+        ("heartbeat_interval_secs", config.heartbeat_interval_secs),
+        ("ota_check_interval_secs", config.ota_check_interval_secs),
+    ] {
+        if let Err(e) = interval(config, key, secs) {
+            report.error(key, e);
+        }
+    }
+    if let Err(e) = upload_batch_size(config.upload_batch_size as u64) {
+        report.error("upload_batch_size", e);
+    }
+    if config.upload_max_in_flight == 0 {
+        report.warning("upload_max_in_flight", "0 is treated as 1");
+    }
+    if config.retry_max_attempts == 0 {
+        report.warning("retry_max_attempts", "0 is treated as 1");
+    }
+    if config.shutdown_timeout_secs == 0 {
+        report.warning("shutdown_timeout_secs", "0 skips the final upload when stopping");
+    }
+    if config.http_connect_timeout_ms == 0 {
+        report.warning("http_connect_timeout_ms", "0 lets slow DNS or an unreachable backend stall the device");
+    }
+    if config.http_request_timeout_secs == 0 {
+        report.warning("http_request_timeout_secs", "0 lets a hung backend request stall the device");
+    }
+    match (&config.tls_client_cert_path, &config.tls_client_key_path) {
+        (Some(_), None) => report.error("tls_client_key_path", "required with tls_client_cert_path"),
+        (None, Some(_)) => report.error("tls_client_cert_path", "required with tls_client_key_path"),
+        _ => {}
+    }
+    for (key, path) in [
+        ("tls_client_cert_path", &config.tls_client_cert_path),
+        ("tls_client_key_path", &config.tls_client_key_path),
+        ("tls_ca_bundle_path", &config.tls_ca_bundle_path),
+    ] {
+        if let Some(path) = path.as_ref().filter(|path| !path.is_file()) {
+            report.error(key, format!("{} is not a readable file", path.display()));
+        }
+    }
+    if let Err(e) = writable_dir(&config.data_dir) {
+        report.error("data_dir", e);
+    }
+    for pattern in &config.error_body_capture.redact_patterns {
+        if let Err(e) = regex::Regex::new(pattern) {
+            report.error("error_body_capture.redact_patterns", format!("{:?} is not a valid pattern: {}", pattern, e));
+        }
+    }
+    if let Some(Err(e)) = config.cadence.as_ref().map(cadence) {
+        report.error("cadence", e);
+    }
+    if let Some(Err(e)) = config.geo_buckets.as_ref().map(geo_buckets) {
+        report.error("geo_buckets", e);
+    }
+    if let Some(fence) = &config.geofence {
+        if !config.sensor_profile.bounds().gps {
+            report.warning("geofence", format!("ignored for the {} profile, which has no GPS", config.sensor_profile.as_str()));
+        } else if let Err(e) = fence.validate() {
+            report.error("geofence", e);
+        }
+    }
+    if let Some(reefer) = &config.reefer {
+        if config.sensor_profile != simulate::SensorProfile::ReeferTrailer {
+            report.warning("reefer", format!("ignored for the {} profile; only reefer_trailer models its cargo", config.sensor_profile.as_str()));
+        } else if let Err(e) = reefer.validate() {
+            report.error("reefer", e);
+        }
+    }
+    if let Err(e) = FaultBaseline::effective(config.sensor_profile, &config.fault_baseline, true).validate() {
+        report.error("fault_baseline", e);
+    } else if !config.background_faults && config.fault_baseline != BaselineOverrides::default() {
+        report.warning("fault_baseline", "ignored while background_faults is off");
+    }
+    if let Some(path) = &config.can_signals {
+        if !config.sensor_profile.has_can_bus() {
+            report.warning("can_signals", format!("ignored for the {} profile; only the vehicle profiles simulate a CAN bus", config.sensor_profile.as_str()));
+        } else if let Err(e) = SignalSet::load(std::path::Path::new(path)) {
+            report.error("can_signals", format!("{:#}", e));
+        }
+    }
+    let route_key = if config.route_file.is_some() { "route_file" } else { "route_waypoints" };
+    if config.route_file.is_some() && !config.route_waypoints.is_empty() {
+        report.warning("route_waypoints", "ignored in favour of route_file");
+    }
+    if let Some(route) = route::configured(config) {
+        if !config.sensor_profile.bounds().gps {
+            report.warning(route_key, format!("ignored for the {} profile, which has no GPS", config.sensor_profile.as_str()));
+        } else if let Err(e) = route {
+            report.error(route_key, format!("{:#}", e));
+        }
+    }
+    if config.route_stop_at_end && config.route_reverse {
+        report.warning("route_reverse", "ignored when route_stop_at_end is set");
+    }
+    if config.reconnect_replay_secs > 0 && config.reconnect_replay_max_rows == 0 {
+        report.warning("reconnect_replay_max_rows", "0 turns reconnect replay off");
+    }
+    if config.ota_trial_window_secs < config.heartbeat_interval_secs.saturating_mul(config.ota_trial_heartbeats as u64) {
+        report.warning("ota_trial_window_secs", format!(
+            "too short for {} heartbeats every {}s; new firmware will be rolled back",
+            config.ota_trial_heartbeats, config.heartbeat_interval_secs
+        ));
+    }
+    if config.ota_trial_require_ingest && config.ota_trial_window_secs < config.upload_interval_secs {
+        report.warning("ota_trial_window_secs", format!(
+            "shorter than upload_interval_secs ({}s); new firmware will be rolled back before its first ingest",
+            config.upload_interval_secs
+        ));
+    }
+    if config.ota_apply_overdue_factor < 1.0 {
+        report.error("ota_apply_overdue_factor", "must be at least 1, or progress heartbeats start before the announced downtime is over");
+    }
+
+    for (region, endpoint) in &config.region_endpoints {
+        if let Err(e) = url(endpoint) {
+            report.error(&format!("region_endpoints.{}", region), e);
+        }
+    }
+    if let Err(e) = residency::target_for(config, config.region.as_deref()) {
+        report.error("region", e.to_string());
+    }
+    if let Some(Err(e)) = config.timezone.as_deref().map(localtime::parse_timezone) {
+        report.error("timezone", e.to_string());
+    }
+    for warning in Features::resolve(&config.features).warnings() {
+        report.error("features", warning.clone());
+    }
+
+    if config.shed.low_water >= config.shed.high_water {
+        report.error("shed.low_water", format!("must be below high_water ({} >= {})", config.shed.low_water, config.shed.high_water));
+    }
+    if let Some(Err(e)) = config.ota_public_key.as_deref().map(ota::public_key) {
+        report.error("ota_public_key", e);
+    }
+    if !(0.0..=1.0).contains(&config.battery_drain_rate) {
+        report.error("battery_drain_rate", "must be between 0 and 1");
+    }
+    if config.charge_rate < 0.0 {
+        report.error("charge_rate", "must not be negative");
+    } else if config.charge_rate == 0.0 && config.battery_drain_rate > 0.0 {
+        report.warning("charge_rate", "0 leaves a drained battery empty for good");
+    }
+    if !(0.0..=jitter::MAX_FRACTION).contains(&config.interval_jitter) {
+        report.error("interval_jitter", format!("must be between 0 and {}", jitter::MAX_FRACTION));
+    }
+    if config.storage_busy_timeout_ms == 0 {
+        report.warning("storage_busy_timeout_ms", "0 fails a database statement at once while another connection holds a lock");
+    }
+    if config.max_stored_measurements > 0 && config.max_stored_measurements <= config.shed.high_water {
+        report.warning("max_stored_measurements", format!("rows are evicted before shedding starts at {}", config.shed.high_water));
+    }
+    if config.max_storage_age_secs > 0 && config.max_storage_age_secs < config.upload_interval_secs {
+        report.warning("max_storage_age_secs", format!("rows expire before the next upload every {}s", config.upload_interval_secs));
+    }
+
+    if let Some(roaming) = &config.network_roaming {
+        if roaming.schedule.iter().any(|step| step.duration_secs == 0) {
+            report.error("network_roaming.schedule", "steps must last at least one second");
+        }
+    }
+    for (network, profile) in &config.network_profiles {
+        if profile.bandwidth_bytes_per_sec == 0 {
+            report.error(&format!("network_profiles.{}", network), "bandwidth_bytes_per_sec must be greater than zero");
+        }
+        if !(0.0..=1.0).contains(&profile.loss) {
+            report.error(&format!("network_profiles.{}", network), format!("loss must be between 0 and 1, got {}", profile.loss));
+        }
+    }
+
+    let keepalive = &config.push_keepalive;
+    if let Some(nat_idle_timeout_secs) = push::nat_idle_timeout(config) {
+        if keepalive.ping_interval_secs == 0 || keepalive.ping_interval_secs >= nat_idle_timeout_secs {
+            report.warning("push_keepalive.ping_interval_secs", format!("pings do not keep the push connection inside the {}s NAT idle timeout", nat_idle_timeout_secs));
+        }
+    }
+    if keepalive.ping_interval_secs > 0 && keepalive.pong_timeout_secs == 0 {
+        report.error("push_keepalive.pong_timeout_secs", "must be greater than zero while pings are enabled");
+    }
+
+    if let Some(prices) = &config.cost_model {
+        for (key, price) in [
+            ("per_mb_uploaded", prices.per_mb_uploaded),
+            ("per_request", prices.per_request),
+            ("per_firmware_download", prices.per_firmware_download),
+            ("per_measurement_day", prices.per_measurement_day),
+        ] {
+            if !(price >= 0.0 && price.is_finite()) {
+                report.error(&format!("cost_model.{}", key), format!("price must be zero or positive, got {}", price));
+            }
+        }
+    }
+
+    if let Some(settings) = &config.degradation {
+        for (sensor, sensor_config) in &settings.sensors {
+            if !degradation::SENSORS.contains(&sensor.as_str()) {
+                report.error(&format!("degradation.sensors.{}", sensor), format!("unknown sensor, expected one of {}", degradation::SENSORS.join(", ")));
+            }
+            match sensor_config.lifetime {
+                Lifetime::Uniform { min_hours, max_hours } if min_hours > max_hours => {
+                    report.error(&format!("degradation.sensors.{}", sensor), "lifetime min_hours exceeds max_hours");
+                }
+                Lifetime::Weibull { shape, scale_hours } if shape <= 0.0 || scale_hours <= 0.0 => {
+                    report.error(&format!("degradation.sensors.{}", sensor), "Weibull lifetime needs a positive scale and shape");
+                }
+                _ => {}
+            }
+        }
+    }
+
+    if let Some(desired) = &config.desired_shadow_state {
+        report.extend(check_desired(config, desired));
+    }
+    report
+}
+
+/// Checks a desired shadow document the way the shadow sync would apply it.
+pub fn check_desired(config: &Config, desired: &Value) -> Report {
+    let mut report = Report::default();
+    let Some(fields) = desired.as_object() else {
+        report.error("desired", "must be a JSON object");
+        return report;
+    };
+    for (key, value) in fields {
+        let path = format!("desired.{}", key);
+        match key.as_str() {
+            "sample_interval_secs" | "upload_interval_secs" | "heartbeat_interval_secs" => {
+                match value.as_u64() {
+                    Some(secs) => {
+                        if let Err(e) = interval(config, key, secs) {
+                            report.error(&path, e);
+                        }
+                    }
+                    None => report.error(&path, "expected a non-negative integer"),
+                }
+            }
+            "device_name" => {
+                if let Err(e) = naming::rename(&mut config.clone(), value) {
+                    report.error(&path, e.to_string());
+                }
+            }
+            "network" => {
+                if let Err(e) = value.as_str().ok_or_else(|| "expected a string".to_string()).and_then(|raw| raw.parse::<NetworkType>().map_err(|e| e.to_string())) {
+                    report.error(&path, e);
+                }
+            }
+            "features" => {
+                let mut features = Features::resolve(&config.features);
+                features.apply_desired(&mut config.features.clone(), value);
+                for warning in features.warnings() {
+                    report.error(&path, warning.clone());
+                }
+            }
+            "self_detection" => {
+                if let Err(e) = serde_json::from_value::<SelfDetectionConfig>(value.clone()) {
+                    report.error(&path, e.to_string());
+                }
+            }
+            "cadence" => match serde_json::from_value::<CadenceConfig>(value.clone()) {
+                Ok(desired) => {
+                    if let Err(e) = cadence(&desired) {
+                        report.error(&path, e);
+                    }
+                }
+                Err(e) => report.error(&path, e.to_string()),
+            },
+            "geo_buckets" if value.is_null() => {}
+            "geo_buckets" => match serde_json::from_value::<GeoBucketConfig>(value.clone()) {
+                Ok(desired) => {
+                    if let Err(e) = geo_buckets(&desired) {
+                        report.error(&path, e);
+                    }
+                }
+                Err(e) => report.error(&path, e.to_string()),
+            },
+            "config_txn" => match serde_json::from_value::<ConfigTxn>(value.clone()) {
+                Ok(txn) => {
+                    if let Err(errors) = txn::validate(config, &txn) {
+                        for (change, reason) in errors {
+                            report.error(&format!("{}.{}", path, change), reason);
+                        }
+                    }
+                }
+                Err(e) => report.error(&path, e.to_string()),
+            },
+            "region" => {
+                if !value.is_string() {
+                    report.error(&path, "expected a string");
+                }
+            }
+            "chaos_flags" if value.is_null() => {}
+            "chaos_flags" => {
+                if let Err(e) = ChaosFlags::parse(value) {
+                    report.error(&path, e.to_string());
+                }
+                if let Some(Err(e)) = value.get("latency_ms").map(network::latency_range) {
+                    report.error(&format!("{}.latency_ms", path), e);
+                }
+                if let Some(Err(e)) = value.get("clock_skew_secs").map(simulate::clock_skew_range) {
+                    report.error(&format!("{}.clock_skew_secs", path), e);
+                }
+                if let Some(Err(e)) = value.get("clock_drift_secs_per_hour").map(simulate::clock_drift_rate) {
+                    report.error(&format!("{}.clock_drift_secs_per_hour", path), e);
+                }
+                if let Some(Err(e)) = value.get("battery_drain").map(battery::drain_config) {
+                    report.error(&format!("{}.battery_drain", path), e);
+                }
+                if let Some(Err(e)) = value.get("battery_drain_factor").map(battery::drain_factor) {
+                    report.error(&format!("{}.battery_drain_factor", path), e);
+                }
+                if value.get("ota_apply_hang_ms").is_some_and(|hang| hang.as_u64().is_none()) {
+                    report.error(&format!("{}.ota_apply_hang_ms", path), "expected a non-negative number of milliseconds");
+                }
+                if let Some(Err(e)) = value.get("offline").map(|request| offline::parse_request(request, Utc::now())) {
+                    report.error(&format!("{}.offline", path), e);
+                }
+            }
+            "debug_session" => {
+                if let Err(e) = debug_session::parse_request(value) {
+                    report.error(&path, e);
+                }
+            }
+            "maintenance" | "replace_sensor" => {}
+            _ => report.warning(&path, "not handled by the device"),
+        }
+    }
+    report
+}
+
+// --- The validate command ---
+
+/// Options for `device validate [--config PATH] [--shadow PATH]...`, also spelled
+/// `device --validate`.
+#[derive(Debug, Default)]
+pub struct ValidateArgs {
+    pub config: Option<PathBuf>,
+    pub shadows: Vec<PathBuf>,
+}
+
+impl ValidateArgs {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut parsed = ValidateArgs::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().map(PathBuf::from).ok_or_else(|| format!("{} needs a path", arg));
+            match arg.as_str() {
+                "--config" => parsed.config = Some(value()?),
+                "--shadow" => parsed.shadows.push(value()?),
+                other => return Err(format!("unknown argument {}", other)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn parse_error(report: &mut Report, file: &Path, key: &str, e: &serde_json::Error) {
+    report.findings.push(Finding {
+        severity: Severity::Error,
+        key: key.to_string(),
+        message: e.to_string(),
+        file: Some(file.display().to_string()),
+        line: Some(e.line()),
+        column: Some(e.column()),
+    });
+}
+
+/// Loads the config the way startup does, without registering or touching the
+/// network, and checks it along with any shadow seed documents. Writes nothing but the
+/// probe that checks data_dir is writable.
+pub fn validate(args: &ValidateArgs, vars: &HashMap<String, String>) -> Report {
+    let mut report = Report::default();
+    let config_dir = vars.get("CONFIG_DIR").map_or(Path::new("."), Path::new);
+    let config_path = args.config.clone().unwrap_or_else(|| config_dir.join(config::CONFIG_FILE));
+
+    // Same layering as startup: a config file wins; without one the device boots from the environment
+    let config = match fs::read_to_string(&config_path) {
+        Ok(contents) => match serde_json::from_str::<Config>(&contents) {
+            Ok(config) => {
+                let mut file_report = config.validate();
+                file_report.locate(&config_path, &contents);
+                report.extend(file_report);
+                for key in config::unrecognized_env_vars(vars) {
+                    report.warning(&key, "unrecognized environment variable");
+                }
+                Some(config)
+            }
+            Err(e) => {
+                parse_error(&mut report, &config_path, "config", &e);
+                None
+            }
+        },
+        Err(_) if args.config.is_some() => {
+            report.error("config", format!("cannot read {}", config_path.display()));
+            None
+        }
+        Err(_) => {
+            let (config, env_report) = Config::from_env_vars(vars);
+            for warning in env_report.warnings {
+                report.warning("env", warning);
+            }
+            report.extend(config.validate());
+            Some(config)
+        }
+    };
+
+    for shadow_path in &args.shadows {
+        let contents = match fs::read_to_string(shadow_path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                report.error("shadow", format!("cannot read {}: {}", shadow_path.display(), e));
+                continue;
+            }
+        };
+        match (serde_json::from_str::<Value>(&contents), &config) {
+            (Ok(desired), Some(config)) => {
+                let mut shadow_report = check_desired(config, &desired);
+                shadow_report.locate(shadow_path, &contents);
+                report.extend(shadow_report);
+            }
+            (Ok(_), None) => report.warning("shadow", format!("{} not checked, the config did not load", shadow_path.display())),
+            (Err(e), _) => parse_error(&mut report, shadow_path, "shadow", &e),
+        }
+    }
+    report
+}
+
+/// Entry point for `device validate`: prints the report as JSON and exits non-zero
+/// on any error.
+pub fn run(args: &[String]) -> ! {
+    let args = match ValidateArgs::parse(args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\nusage: device validate [--config PATH] [--shadow PATH]...", e);
+            std::process::exit(2);
+        }
+    };
+    let report = validate(&args, &std::env::vars().collect());
+    println!("{}", serde_json::to_string_pretty(&report.to_json()).expect("report serializes"));
+    std::process::exit(if report.has_errors() { 1 } else { 0 });
+}
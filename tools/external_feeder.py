@@ -0,0 +1,55 @@
+import argparse
+import json
+import math
+import os
+import socket
+import sys
+import time
+
+# Example co-simulator feeding a device's external measurement source.
+# Emits NDJSON refrigeration-unit readings either on stdout (pipe into a device
+# started with EXTERNAL_SOURCE=stdin) or on a Unix socket the device connects to
+# (EXTERNAL_SOURCE=unix:<path>). Use EXTERNAL_FIELD_MAP=cargo_temp=temp to map fields.
+
+def readings(interval: float):
+    start = time.time()
+    while True:
+        elapsed = time.time() - start
+        # Compressor cycling around a -18C setpoint
+        cargo_temp = -18.0 + 1.5 * math.sin(elapsed / 120.0)
+        yield {"cargo_temp": round(cargo_temp, 2), "humidity": 85.0}
+        time.sleep(interval)
+
+def feed_stdout(interval: float):
+    for record in readings(interval):
+        sys.stdout.write(json.dumps(record) + "\n")
+        sys.stdout.flush()
+
+def feed_socket(path: str, interval: float):
+    if os.path.exists(path):
+        os.remove(path)
+    server = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+    server.bind(path)
+    server.listen(1)
+    print(f"Waiting for device on {path}", file=sys.stderr)
+    while True:
+        conn, _ = server.accept()
+        print("Device connected", file=sys.stderr)
+        try:
+            for record in readings(interval):
+                conn.sendall((json.dumps(record) + "\n").encode())
+        except (BrokenPipeError, ConnectionResetError):
+            print("Device disconnected", file=sys.stderr)
+        finally:
+            conn.close()
+
+if __name__ == "__main__":
+    parser = argparse.ArgumentParser(description="Feed external measurements to a virtual device.")
+    parser.add_argument("--socket", help="Serve records on this Unix socket path instead of stdout")
+    parser.add_argument("--interval", type=float, default=1.0, help="Seconds between records")
+    args = parser.parse_args()
+
+    if args.socket:
+        feed_socket(args.socket, args.interval)
+    else:
+        feed_stdout(args.interval)
@@ -1,5 +1,6 @@
 import argparse
 import hashlib
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
 import os
 import sqlite3
 import datetime
@@ -10,19 +11,16 @@ def get_db_connection():
     conn.row_factory = sqlite3.Row
     return conn
 
-def calculate_checksum(file_path):
+def calculate_digest(file_path):
     sha256_hash = hashlib.sha256()
     with open(file_path, "rb") as f:
         for byte_block in iter(lambda: f.read(4096), b""):
             sha256_hash.update(byte_block)
-    return sha256_hash.hexdigest()
+    return sha256_hash.digest()
 
-def generate_signature(firmware_data: bytes, private_key: str) -> str:
-    """Generates a dummy signature for the firmware data."""
-    signer = hashlib.sha256()
-    signer.update(firmware_data)
-    signer.update(private_key.encode('utf-8')) # Incorporate private key
-    return signer.hexdigest()
+def generate_signature(digest: bytes, private_key: str) -> str:
+    """Signs the image's SHA-256 digest with a hex ed25519 seed, as devices verify it."""
+    return Ed25519PrivateKey.from_private_bytes(bytes.fromhex(private_key.strip())).sign(digest).hex()
 
 def main():
     parser = argparse.ArgumentParser(description="Publish new firmware to the backend.")
@@ -33,7 +31,7 @@ def main():
     parser.add_argument("--group", default="default", help="Rollout group (e.g., default, green)")
     parser.add_argument("--required-region", help="Required device region for this firmware.")
     parser.add_argument("--required-hardware-rev", help="Required device hardware revision for this firmware.")
-    parser.add_argument("--private-key", default="super-secret-key", help="Private key for signing firmware.")
+    parser.add_argument("--private-key", default=os.environ.get("OTA_SIGNING_KEY"), help="Hex ed25519 seed to sign firmware with (default: OTA_SIGNING_KEY); unsigned without one.")
     parser.add_argument("--status", default="active", help="Rollout status (e.g., active, paused, rolled_back).")
     args = parser.parse_args()
 
@@ -41,15 +39,10 @@ def main():
         print(f"Error: File not found at {args.file}")
         return
 
-    # Calculate checksum of the firmware file
-    checksum = calculate_checksum(args.file)
-
-    # Read the firmware file content for signing
-    with open(args.file, "rb") as f:
-        firmware_file_content = f.read()
-    
-    # Generate signature
-    signature = generate_signature(firmware_file_content, args.private_key)
+    # Calculate checksum of the firmware file; the signature covers the same digest
+    digest = calculate_digest(args.file)
+    checksum = digest.hex()
+    signature = generate_signature(digest, args.private_key) if args.private_key else None
 
     # In a real system, the URL would point to a cloud storage location.
     # Here, we'll use a placeholder URL that points back to the backend.